@@ -0,0 +1,46 @@
+//! Throughput of the length-preserving sector modes: XEX (XTS without ciphertext stealing),
+//! HCTR2 and Adiantum, on 4 KiB sectors.
+//!
+//! Run with `cargo bench --features adiantum`. To see how they compare on hardware without AES
+//! instructions, force the software AES implementation:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg aes_force_soft" cargo bench --features adiantum
+//! ```
+
+use std::{hint::black_box, time::Instant};
+
+use aes_modes::{adiantum::Adiantum, hctr2::Hctr2, tweakable::Xex};
+
+const SECTOR_SIZE: usize = 4096;
+const SECTORS: u64 = 4096;
+
+fn bench(name: &str, mut encrypt: impl FnMut(u64, &mut [u8])) {
+    let mut sector = vec![0u8; SECTOR_SIZE];
+    let start = Instant::now();
+    for number in 0..SECTORS {
+        encrypt(number, black_box(&mut sector));
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let megabytes = (SECTORS as usize * SECTOR_SIZE) as f64 / 1e6;
+    println!("{:<10} {:>8.1} MB/s", name, megabytes / elapsed);
+}
+
+fn main() {
+    let xex = Xex::new([1u8; 16], [2u8; 16]);
+    bench("aes-xex", |number, sector| {
+        let mut data_unit = [0u8; 16];
+        data_unit[..8].copy_from_slice(&number.to_le_bytes());
+        xex.encrypt_unit(&data_unit, sector);
+    });
+
+    let hctr2 = Hctr2::new([1u8; 16]);
+    bench("hctr2", |number, sector| {
+        hctr2.encrypt(&number.to_le_bytes(), sector)
+    });
+
+    let adiantum = Adiantum::new([1u8; 32]);
+    bench("adiantum", |number, sector| {
+        adiantum.encrypt(&number.to_le_bytes(), sector)
+    });
+}
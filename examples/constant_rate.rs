@@ -0,0 +1,63 @@
+//! Measuring what message timings give away, with and without constant-rate encryption.
+//!
+//! ```text
+//! cargo run --release --example constant_rate
+//! ```
+//!
+//! Seals messages of 16 bytes to 64 KiB many times each, and prints the median time and the
+//! ciphertext size for every length. Plain GCM envelopes take longer and grow with the
+//! message, so either column tells the lengths apart. Under a
+//! [`ConstantRate`](aes_modes::constant_rate::ConstantRate) with a 64 KiB size quantum and a
+//! time quantum above the slowest seal, every row is the same.
+
+use std::time::{Duration, Instant};
+
+use aes_modes::{
+    constant_rate::ConstantRate,
+    envelope::{Envelope, EnvelopeMode},
+    BLOCK_SIZE,
+};
+
+const KEY: [u8; BLOCK_SIZE] = *b"constant rate ex";
+const LENGTHS: [usize; 5] = [16, 256, 4096, 32768, 65000];
+const RUNS: usize = 25;
+
+/// The median time `seal` takes, and the size of what it returns.
+fn measure(seal: impl Fn(Vec<u8>) -> Envelope, len: usize) -> (Duration, usize) {
+    let mut times = Vec::with_capacity(RUNS);
+    let mut size = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let envelope = seal(vec![b'm'; len]);
+        times.push(start.elapsed());
+        size = envelope.to_bytes().len();
+    }
+    times.sort();
+    (times[RUNS / 2], size)
+}
+
+fn print_table(name: &str, seal: impl Fn(Vec<u8>) -> Envelope) {
+    println!("{}:", name);
+    println!("{:>10} {:>12} {:>10}", "length", "median", "size");
+    for len in LENGTHS {
+        let (time, size) = measure(&seal, len);
+        println!("{:>10} {:>12.1?} {:>10}", len, time, size);
+    }
+    println!();
+}
+
+fn main() {
+    print_table("plain GCM envelopes", |plain_text| {
+        Envelope::seal(EnvelopeMode::Gcm, 1, KEY, plain_text)
+    });
+
+    // A time quantum several times the slowest plain seal, so no message overruns it.
+    let slowest = measure(
+        |plain_text| Envelope::seal(EnvelopeMode::Gcm, 1, KEY, plain_text),
+        65535,
+    )
+    .0;
+    let rate = ConstantRate::new(65536, (slowest * 4).max(Duration::from_millis(1)));
+    println!("time quantum: {:?}", rate.time_quantum());
+    print_table("constant rate", |plain_text| rate.seal(1, KEY, plain_text));
+}
@@ -0,0 +1,101 @@
+//! Decoding an ECB-encrypted database dump without decrypting anything.
+//!
+//! ```text
+//! cargo run --example ecb_dictionary
+//! ```
+//!
+//! The dump is a table of fixed-width records, a 16-byte user name and a 16-byte role, each
+//! field exactly one block, encrypted with ECB. The key is never used to decrypt. ECB is a
+//! codebook, so the same field value always encrypts to the same block, and two attacks
+//! recover the roles:
+//!
+//! 1. With a way to encrypt chosen values, such as a key that leaked into an encrypt-only
+//!    service, or a sign-up form that stores whatever role it is sent, the attacker encrypts
+//!    every role they can guess and looks the dump's blocks up in that dictionary.
+//! 2. With nothing but the dump, the attacker ranks the role column's blocks by frequency and
+//!    matches them against the known share of each role, say from a published head count.
+//!
+//! Neither reads a single user name, yet both give who the administrators are. A mode with an
+//! IV or nonce, or a GCM [envelope](aes_modes::envelope) per record, encrypts equal values to
+//! unrelated blocks and defeats both.
+
+use std::collections::HashMap;
+
+use aes_modes::{analysis::codebook, ecb_encrypt, BLOCK_SIZE};
+
+const KEY: [u8; BLOCK_SIZE] = *b"the dump's key!!";
+const ROLES: [&str; 3] = ["viewer", "editor", "admin"];
+
+fn field(value: &str) -> [u8; BLOCK_SIZE] {
+    let mut block = [b' '; BLOCK_SIZE];
+    block[..value.len()].copy_from_slice(value.as_bytes());
+    block
+}
+
+/// The role of user `i`: mostly viewers, some editors and a few admins.
+fn role(i: usize) -> &'static str {
+    match i % 20 {
+        0 => "admin",
+        1..=6 => "editor",
+        _ => "viewer",
+    }
+}
+
+fn main() {
+    let mut table = Vec::new();
+    for i in 0..200 {
+        table.extend_from_slice(&field(&format!("user{:04}", i)));
+        table.extend_from_slice(&field(role(i)));
+    }
+    let dump = ecb_encrypt(table, KEY);
+    let records: Vec<&[u8]> = dump.chunks_exact(2 * BLOCK_SIZE).collect();
+    let role_block =
+        |record: &[u8]| -> [u8; BLOCK_SIZE] { record[BLOCK_SIZE..].try_into().unwrap() };
+
+    // 1. A dictionary of guessed values, encrypted the way the database encrypts them.
+    let dictionary: HashMap<[u8; BLOCK_SIZE], &str> = ROLES
+        .iter()
+        .map(|&role| {
+            let block: [u8; BLOCK_SIZE] = ecb_encrypt(field(role).to_vec(), KEY)[..BLOCK_SIZE]
+                .try_into()
+                .unwrap();
+            (block, role)
+        })
+        .collect();
+    let decoded: Vec<&str> = records
+        .iter()
+        .map(|record| dictionary.get(&role_block(record)).copied().unwrap_or("?"))
+        .collect();
+    let admins: Vec<usize> = (0..decoded.len())
+        .filter(|&i| decoded[i] == "admin")
+        .collect();
+    println!("dictionary: admins are the users at rows {:?}", admins);
+    assert!(decoded
+        .iter()
+        .enumerate()
+        .all(|(i, &found)| found == role(i)));
+
+    // 2. Frequency analysis of the role column, against the known shares 65%, 30% and 5%.
+    let column: Vec<u8> = records
+        .iter()
+        .flat_map(|record| role_block(record))
+        .collect();
+    let by_frequency = codebook(&column).top(ROLES.len());
+    println!("{}", codebook(&column));
+    let ranked: HashMap<[u8; BLOCK_SIZE], &str> = by_frequency
+        .iter()
+        .zip(ROLES)
+        .map(|((block, _), role)| (*block, role))
+        .collect();
+    let matched = records
+        .iter()
+        .enumerate()
+        .filter(|&(i, record)| ranked[&role_block(record)] == role(i))
+        .count();
+    println!(
+        "frequency: {} of {} roles matched without encrypting anything",
+        matched,
+        records.len()
+    );
+    assert_eq!(matched, records.len());
+}
@@ -0,0 +1,311 @@
+//! The classic misuses of the modes, next to their correct use, with what the crate reports.
+//!
+//! ```text
+//! cargo run --example misuse_gallery
+//! ```
+//!
+//! Each entry encrypts the same data twice, once as the crate intends and once the way real
+//! systems keep getting it wrong, and prints for both the [warnings](aes_modes::warnings) the
+//! encryption raised and the [analysis](aes_modes::analysis) of what came out:
+//!
+//! 1. CBC with a static IV: messages that start alike give ciphertexts that start alike, and
+//!    [`scan_nonces`] finds the repeated IV.
+//! 2. CTR with a reused nonce: the XOR of two ciphertexts is the XOR of their plaintexts, so
+//!    knowing one message gives the other. [`scan_nonces`] calls it catastrophic.
+//! 3. ECB on structured data: a table's repeated fields become repeated ciphertext blocks,
+//!    which the [`codebook`] counts.
+//! 4. Truncated tags: chunked streams allow tags down to 12 bytes, and warn about them; a
+//!    homemade 1-byte GCM tag is forged here in at most 256 attempts, after which the forger
+//!    changes an amount without the key.
+//!
+//! No warning flags the repeats in the first two, since a single call can't tell that its IV
+//! or nonce was used before; only scanning what was stored does. Every claim printed is also
+//! asserted, so the gallery fails to run if it ever stops being true.
+
+use std::{
+    io::Write,
+    sync::{Mutex, Once},
+};
+
+use aes_modes::{
+    analysis::{codebook, scan_nonces},
+    backend::Backend,
+    chunked::{StreamEncryptor, StreamOptions},
+    ctr_encrypt_with_nonce, ecb_encrypt,
+    envelope::{Envelope, EnvelopeMode, Validity},
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE, TAG_SIZE},
+    invocations::max_invocations,
+    length_padding::LengthPadding,
+    warnings::{self, Warning},
+    BLOCK_SIZE, NONCE_SIZE,
+};
+
+const KEY: [u8; BLOCK_SIZE] = *b"gallery key 0001";
+
+static RAISED: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+/// Runs `f`, and returns what it returned with the warnings it raised.
+fn with_warnings<T>(f: impl FnOnce() -> T) -> (T, Vec<Warning>) {
+    static SINK: Once = Once::new();
+    SINK.call_once(|| {
+        warnings::set_warning_sink(|warning: &Warning| RAISED.lock().unwrap().push(*warning))
+    });
+    RAISED.lock().unwrap().clear();
+    let result = f();
+    (result, RAISED.lock().unwrap().drain(..).collect())
+}
+
+fn show(label: &str, warnings: &[Warning], report: &str) {
+    println!("{}", label);
+    if warnings.is_empty() {
+        println!("  warnings: none");
+    }
+    let mut distinct = warnings.to_vec();
+    distinct.dedup();
+    for warning in distinct {
+        let count = warnings.iter().filter(|&&w| w == warning).count();
+        println!("  warning: {} (x{})", warning, count);
+    }
+    for line in report.lines() {
+        println!("  {}", line);
+    }
+}
+
+/// An envelope around a ciphertext made outside of [`Envelope::seal`], as a system with its
+/// own IV or nonce handling would store it.
+fn envelope(mode: EnvelopeMode, nonce: &[u8], body: &[u8]) -> Envelope {
+    Envelope {
+        mode,
+        key_id: 1,
+        nonce: nonce.to_vec(),
+        chunks: vec![body.to_vec()],
+        tag: Vec::new(),
+        wrapped_key: Vec::new(),
+        check_value: Vec::new(),
+        padding: LengthPadding::None,
+        validity: Validity::default(),
+    }
+}
+
+/// The first ciphertext block of each envelope, one after another.
+fn first_blocks(envelopes: &[Envelope]) -> Vec<u8> {
+    envelopes
+        .iter()
+        .flat_map(|envelope| envelope.chunks[0][..BLOCK_SIZE].to_vec())
+        .collect()
+}
+
+fn static_iv_cbc() {
+    println!("== 1. CBC with a static IV ==");
+    let messages: Vec<Vec<u8>> = (0..8)
+        .map(|i| format!("To: payroll@example.com; pay {} to account {}", 100 * i, i).into_bytes())
+        .collect();
+
+    let (correct, raised) = with_warnings(|| {
+        messages
+            .iter()
+            .map(|message| Envelope::seal(EnvelopeMode::Cbc, 1, KEY, message.clone()))
+            .collect::<Vec<_>>()
+    });
+    let nonces = scan_nonces(correct.iter().map(Envelope::to_bytes));
+    let blocks = codebook(&first_blocks(&correct));
+    show(
+        "correct: a random IV for every message (Envelope::seal)",
+        &raised,
+        &format!("{}\nfirst blocks: {}", nonces, blocks),
+    );
+    assert!(nonces.is_clean() && blocks.repeated() == 0);
+
+    let iv = [0u8; BLOCK_SIZE];
+    let context = Backend::RustCryptoAes.context(KEY);
+    let (misused, raised) = with_warnings(|| {
+        messages
+            .iter()
+            .map(|message| {
+                let cipher_text = context.cbc_encrypt_with_iv(message.clone(), iv);
+                envelope(EnvelopeMode::Cbc, &iv, &cipher_text[BLOCK_SIZE..])
+            })
+            .collect::<Vec<_>>()
+    });
+    let nonces = scan_nonces(misused.iter().map(Envelope::to_bytes));
+    let blocks = codebook(&first_blocks(&misused));
+    show(
+        "misuse: the same all-zero IV for every message",
+        &raised,
+        &format!("{}\nfirst blocks: {}", nonces, blocks),
+    );
+    assert!(!nonces.is_clean() && !nonces.reuses[0].is_catastrophic());
+    assert_eq!(blocks.distinct(), 1);
+    assert_eq!(misused[3].open(KEY).unwrap(), messages[3]);
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+fn reused_ctr_nonce() {
+    println!("\n== 2. CTR with a reused nonce ==");
+    let known = b"Meeting moved to Thursday, 10:00 in room 4.".to_vec();
+    let secret = b"The new door code is 7291, tell no one else".to_vec();
+
+    // What the attacker does with two ciphertexts and the plaintext of the first.
+    let recover = |first: &Envelope, second: &Envelope| {
+        xor(&xor(&first.chunks[0], &second.chunks[0]), &known)
+    };
+
+    let (correct, raised) = with_warnings(|| {
+        [&known, &secret].map(|message| Envelope::seal(EnvelopeMode::Ctr, 1, KEY, message.clone()))
+    });
+    let nonces = scan_nonces(correct.iter().map(Envelope::to_bytes));
+    let recovered = recover(&correct[0], &correct[1]);
+    show(
+        "correct: a random nonce for every message (Envelope::seal)",
+        &raised,
+        &format!(
+            "{}\nknown plaintext XOR both ciphertexts: {} random bytes",
+            nonces,
+            recovered.len()
+        ),
+    );
+    assert!(nonces.is_clean() && recovered != secret);
+
+    let nonce = [7u8; NONCE_SIZE];
+    let (misused, raised) = with_warnings(|| {
+        [&known, &secret].map(|message| {
+            let cipher_text = ctr_encrypt_with_nonce(message.clone(), KEY, nonce);
+            envelope(EnvelopeMode::Ctr, &nonce, &cipher_text[NONCE_SIZE..])
+        })
+    });
+    let nonces = scan_nonces(misused.iter().map(Envelope::to_bytes));
+    let recovered = recover(&misused[0], &misused[1]);
+    show(
+        "misuse: one fixed nonce for both messages",
+        &raised,
+        &format!(
+            "{}\nknown plaintext XOR both ciphertexts: {:?}",
+            nonces,
+            String::from_utf8_lossy(&recovered)
+        ),
+    );
+    assert!(nonces.reuses[0].is_catastrophic());
+    assert_eq!(recovered, secret);
+}
+
+fn ecb_on_structured_data() {
+    println!("\n== 3. ECB on structured data ==");
+    // Fixed-width records of one block each: a status and a country, mostly the same few.
+    let table: Vec<u8> = (0..64)
+        .flat_map(|i| {
+            let status = ["status=active   ", "status=suspended"][usize::from(i % 7 == 0)];
+            let country = ["country=PT      ", "country=ES      ", "country=FR      "][i % 3];
+            [status.as_bytes(), country.as_bytes()].concat()
+        })
+        .collect();
+
+    let (correct, raised) =
+        with_warnings(|| Envelope::seal(EnvelopeMode::Gcm, 1, KEY, table.clone()));
+    let blocks = codebook(&correct.chunks[0]);
+    show(
+        "correct: GCM, which hides equal blocks",
+        &raised,
+        &blocks.to_string(),
+    );
+    assert_eq!(blocks.repeated(), 0);
+
+    let (misused, raised) = with_warnings(|| ecb_encrypt(table.clone(), KEY));
+    let blocks = codebook(&misused);
+    show("misuse: ECB", &raised, &blocks.to_string());
+    // Five values stand for 128 fields, plus the padding block.
+    assert_eq!(blocks.distinct(), 6);
+}
+
+/// A homemade check of a GCM tag cut down to `tag.len()` bytes: decrypt, recompute the tag,
+/// and compare as many bytes as were kept.
+fn truncated_tag_matches(
+    nonce: [u8; GCM_NONCE_SIZE],
+    aad: &[u8],
+    cipher_text: &[u8],
+    tag: &[u8],
+) -> bool {
+    let keystream = gcm_encrypt(vec![0; cipher_text.len()], KEY, nonce, aad);
+    let plain_text = xor(cipher_text, &keystream);
+    let recomputed = gcm_encrypt(plain_text, KEY, nonce, aad);
+    recomputed[cipher_text.len()..].starts_with(tag)
+}
+
+fn truncated_tags() {
+    println!("\n== 4. Truncated tags ==");
+    let data = vec![0x42; 4096];
+    let stream = |options: StreamOptions| {
+        let mut encryptor = StreamEncryptor::with_options(b"stream secret", options, Vec::new())
+            .expect("valid options");
+        encryptor.write_all(&data).unwrap();
+        encryptor.finish().unwrap()
+    };
+    let budget = |tag_len: u8| match max_invocations(tag_len, 1500) {
+        Some(invocations) => format!("{}-byte tags: 2^{}", tag_len, invocations.ilog2()),
+        None => format!("{}-byte tags: not allowed", tag_len),
+    };
+
+    let (full, raised) = with_warnings(|| stream(StreamOptions::new()));
+    show(
+        "correct: chunked stream with full tags",
+        &raised,
+        &format!(
+            "{} bytes; SP 800-38D decryption budget for 1500-byte packets: {}",
+            full.len(),
+            budget(16)
+        ),
+    );
+    assert!(raised.is_empty());
+
+    let (short, raised) = with_warnings(|| stream(StreamOptions::new().with_tag_len(12)));
+    show(
+        "borderline: chunked stream with 12-byte tags, the shortest it allows",
+        &raised,
+        &format!("{} bytes; {}", short.len(), budget(12)),
+    );
+    assert_eq!(raised, [Warning::ShortTag { len: 12 }]);
+
+    // A protocol that keeps only the first byte of each GCM tag.
+    let nonce = [9u8; GCM_NONCE_SIZE];
+    let aad = b"ledger v1";
+    let sealed = gcm_encrypt(b"pay $10 to bob".to_vec(), KEY, nonce, aad);
+    let (cipher_text, full_tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+    // CTR is malleable: whoever knows the plaintext can flip it to anything of the same length.
+    let forged = xor(cipher_text, &xor(b"pay $10 to bob", b"pay $99 to eve"));
+    let attempts = (0..=255u8)
+        .position(|guess| truncated_tag_matches(nonce, aad, &forged, &[guess]))
+        .expect("one of 256 guesses is the tag")
+        + 1;
+    let with_full_tag = gcm_decrypt(
+        [forged.clone(), full_tag.to_vec()].concat(),
+        KEY,
+        nonce,
+        aad,
+    );
+    show(
+        "misuse: a homemade check of the first tag byte only",
+        &[],
+        &format!(
+            "{}, {}, {}\n\"pay $99 to eve\" forged in {} attempts; with the full tag: {:?}",
+            budget(8),
+            budget(4),
+            budget(1),
+            attempts,
+            with_full_tag
+                .as_ref()
+                .map(|_| ())
+                .map_err(|error| error.to_string())
+        ),
+    );
+    assert!(with_full_tag.is_err());
+}
+
+fn main() {
+    static_iv_cbc();
+    reused_ctr_nonce();
+    ecb_on_structured_data();
+    truncated_tags();
+    warnings::clear_warning_sink();
+}
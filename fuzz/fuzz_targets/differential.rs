@@ -0,0 +1,118 @@
+//! Feeds the same inputs to this crate's CBC, CTR and GCM and to the RustCrypto `cbc`, `ctr`
+//! and `aes-gcm` crates, and fails on any difference in output, or in what is rejected.
+//!
+//! Run with `cargo +nightly fuzz run differential` from this directory.
+
+#![no_main]
+
+use aes::Aes128;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm,
+};
+use aes_modes::{
+    backend::Backend,
+    ctr::CtrParams,
+    gcm::{GCM_NONCE_SIZE, TAG_SIZE},
+    BLOCK_SIZE,
+};
+use cbc::cipher::{
+    block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit, StreamCipher,
+};
+use libfuzzer_sys::fuzz_target;
+
+/// The input is a key, an IV, a GCM nonce, the length of the associated data, and then the
+/// associated data and the message.
+const HEADER: usize = BLOCK_SIZE + BLOCK_SIZE + GCM_NONCE_SIZE + 1;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < HEADER {
+        return;
+    }
+    let key: [u8; BLOCK_SIZE] = data[..16].try_into().unwrap();
+    let iv: [u8; BLOCK_SIZE] = data[16..32].try_into().unwrap();
+    let nonce: [u8; GCM_NONCE_SIZE] = data[32..44].try_into().unwrap();
+    let aad_len = (data[44] as usize).min(data.len() - HEADER);
+    let (aad, message) = data[HEADER..].split_at(aad_len);
+
+    for backend in Backend::available() {
+        check(backend, key, iv, nonce, aad, message);
+    }
+    check_ctr(key, iv, message);
+});
+
+fn check(
+    backend: Backend,
+    key: [u8; BLOCK_SIZE],
+    iv: [u8; BLOCK_SIZE],
+    nonce: [u8; GCM_NONCE_SIZE],
+    aad: &[u8],
+    message: &[u8],
+) {
+    let context = backend.context(key);
+
+    // CBC with PKCS#7 padding, after the IV.
+    let ours = context.cbc_encrypt_with_iv(message.to_vec(), iv);
+    let theirs = cbc::Encryptor::<Aes128>::new(&key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(message);
+    assert_eq!(ours[BLOCK_SIZE..], theirs, "{} CBC", backend.name());
+    assert_eq!(context.cbc_decrypt(ours).as_deref(), Ok(message));
+
+    // The message as a CBC ciphertext. Our padding check is more lenient than the `cbc`
+    // crate's, so only a ciphertext that it accepts has to decrypt the same.
+    let cipher_text = [&iv[..], message].concat();
+    if let Ok(plain_text) = cbc::Decryptor::<Aes128>::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(message)
+    {
+        assert_eq!(context.cbc_decrypt(cipher_text), Ok(plain_text));
+    }
+
+    // GCM both ways, and the message as a forged ciphertext, which both must reject alike.
+    let gcm = Aes128Gcm::new(&key.into());
+    let ours = context.gcm_encrypt(message.to_vec(), nonce, aad);
+    assert_eq!(
+        ours,
+        gcm.encrypt(&nonce.into(), Payload { msg: message, aad })
+            .unwrap(),
+        "{} GCM",
+        backend.name()
+    );
+    assert_eq!(
+        context.gcm_decrypt(ours, nonce, aad).as_deref(),
+        Ok(message)
+    );
+    if message.len() >= TAG_SIZE {
+        assert_eq!(
+            context.gcm_decrypt(message.to_vec(), nonce, aad).ok(),
+            gcm.decrypt(&nonce.into(), Payload { msg: message, aad })
+                .ok(),
+            "{} GCM open",
+            backend.name()
+        );
+    }
+}
+
+/// The `ctr` crate has no flavor with the crate root's layout, a little-endian counter after
+/// the nonce, so this checks the big-endian layouts of `CtrParams` instead. The last bytes of
+/// the IV pick the initial counter, kept low enough not to run out.
+fn check_ctr(key: [u8; BLOCK_SIZE], iv: [u8; BLOCK_SIZE], message: &[u8]) {
+    let initial = (u32::from_be_bytes(iv[12..].try_into().unwrap()) >> 1) as u128;
+    for nonce_size in [8, 12] {
+        let params = CtrParams::new()
+            .with_big_endian_counter()
+            .with_nonce_size(nonce_size)
+            .with_initial_counter(initial);
+        let nonce = &iv[..nonce_size];
+        let mut ours = message.to_vec();
+        params.apply_keystream(key, nonce, &mut ours);
+
+        let counter_block = params.counter_block(nonce, initial).into();
+        let mut theirs = message.to_vec();
+        if nonce_size == 8 {
+            ctr::Ctr64BE::<Aes128>::new(&key.into(), &counter_block).apply_keystream(&mut theirs);
+        } else {
+            ctr::Ctr32BE::<Aes128>::new(&key.into(), &counter_block).apply_keystream(&mut theirs);
+        }
+        assert_eq!(ours, theirs, "CTR with a {}-byte nonce", nonce_size);
+    }
+}
@@ -0,0 +1,932 @@
+//! Audits of data that is already encrypted.
+//!
+//! [`scan_nonces`] reads a whole store of [envelopes](crate::envelope) and reports every IV or
+//! nonce used more than once under the same key. Under CTR and GCM that is catastrophic: two
+//! messages with the same nonce share a keystream, so XORing their ciphertexts gives the XOR
+//! of their plaintexts, and a repeated GCM nonce also leaks the authentication key. A repeated
+//! CBC IV is milder, but still shows which messages start with the same blocks.
+//!
+//! Keys are told apart by their ID and, when the envelope records one, their
+//! [check value](crate::keys::check_value), so two keys that were given the same ID by
+//! mistake aren't mixed up. Envelopes with a [wrapped key](crate::envelope::seal_with_ephemeral_key)
+//! each have a key of their own, and ECB has no nonce; both are skipped.
+//!
+//! [`diagnose`] looks at one envelope that won't open and says why, as precisely as the
+//! envelope and a keyring allow: not an envelope at all, cut short or with bytes left over, a
+//! newer format, a key the keyring doesn't have, the wrong key by its check value or because
+//! another key of the keyring opens it, or a ciphertext that was modified. It only ever
+//! reports what it found, never any plaintext, so its output can go into logs and support
+//! tickets.
+//!
+//! [`codebook`] measures the weakness of ECB instead of asserting it. ECB is a codebook: every
+//! plaintext block has one ciphertext block, so a ciphertext repeats wherever its plaintext
+//! does. The [`Codebook`] of a ciphertext counts its distinct blocks, the most common ones and
+//! the entropy of their distribution, and draws it as a bitmap in which the repeated blocks
+//! keep the shapes of the plaintext. A ciphertext from a mode with an IV or nonce has no
+//! repeats, and draws as black.
+//!
+//! [`error_propagation`] flips every bit of a ciphertext in turn, decrypts, and counts what it
+//! did to the plaintext, block by block from the one holding the bit. Run over the five
+//! [`PropagationMode`]s it gives the textbook table:
+//!
+//! | Mode | Block holding the bit | Next block | Later blocks |
+//! |------|-----------------------|------------|--------------|
+//! | ECB  | garbled               | intact     | intact       |
+//! | CBC  | garbled               | same bit   | intact       |
+//! | CFB  | same bit              | garbled    | intact       |
+//! | OFB  | same bit              | intact     | intact       |
+//! | CTR  | same bit              | intact     | intact       |
+
+use std::{collections::HashMap, fmt};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{
+    envelope::{open_with_ephemeral_key, Envelope, EnvelopeError, EnvelopeMode, MAGIC, VERSION},
+    generic,
+    keys::KeyManager,
+    BLOCK_SIZE,
+};
+
+/// Which key an envelope was sealed under, as far as the envelope tells.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyFingerprint {
+    pub key_id: u32,
+    /// The key's check value, or empty if the envelope didn't record it.
+    pub check_value: Vec<u8>,
+}
+
+impl fmt::Display for KeyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key {}", self.key_id)?;
+        if !self.check_value.is_empty() {
+            f.write_str(" (check value ")?;
+            for byte in &self.check_value {
+                write!(f, "{:02x}", byte)?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+/// One nonce found more than once under one key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonceReuse {
+    pub key: KeyFingerprint,
+    pub mode: EnvelopeMode,
+    pub nonce: Vec<u8>,
+    /// The positions of the envelopes in the scanned sequence, in order.
+    pub indices: Vec<usize>,
+}
+
+impl NonceReuse {
+    /// Whether the reuse exposes plaintext outright, as it does under CTR and GCM.
+    pub fn is_catastrophic(&self) -> bool {
+        matches!(self.mode, EnvelopeMode::Ctr | EnvelopeMode::Gcm)
+    }
+}
+
+/// The findings of [`scan_nonces`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NonceReport {
+    pub scanned: usize,
+    /// The positions of the inputs that weren't valid envelopes.
+    pub malformed: Vec<usize>,
+    /// How many envelopes had no nonce to check: ECB, or a key of their own.
+    pub skipped: usize,
+    /// Ordered by key, then by where the nonce was first seen.
+    pub reuses: Vec<NonceReuse>,
+}
+
+impl NonceReport {
+    /// Whether no nonce was reused.
+    pub fn is_clean(&self) -> bool {
+        self.reuses.is_empty()
+    }
+}
+
+impl fmt::Display for NonceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scanned {} envelopes ({} malformed, {} without a nonce to check): ",
+            self.scanned,
+            self.malformed.len(),
+            self.skipped
+        )?;
+        if self.is_clean() {
+            return f.write_str("no nonce reuse");
+        }
+        write!(f, "{} reused nonces", self.reuses.len())?;
+        for reuse in &self.reuses {
+            write!(f, "\n{}: {:?} nonce ", reuse.key, reuse.mode)?;
+            for byte in &reuse.nonce {
+                write!(f, "{:02x}", byte)?;
+            }
+            write!(f, " in envelopes {:?}", reuse.indices)?;
+            if reuse.is_catastrophic() {
+                f.write_str(", which exposes their plaintexts")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scans encoded envelopes for nonces used more than once under the same key. Only the
+/// headers are read, so no key is needed.
+pub fn scan_nonces<I>(envelopes: I) -> NonceReport
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut report = NonceReport::default();
+    let mut seen: HashMap<(KeyFingerprint, EnvelopeMode, Vec<u8>), Vec<usize>> = HashMap::new();
+    for (index, bytes) in envelopes.into_iter().enumerate() {
+        report.scanned += 1;
+        let Ok(envelope) = Envelope::from_bytes(bytes.as_ref()) else {
+            report.malformed.push(index);
+            continue;
+        };
+        if envelope.nonce.is_empty() || !envelope.wrapped_key.is_empty() {
+            report.skipped += 1;
+            continue;
+        }
+        let key = KeyFingerprint {
+            key_id: envelope.key_id,
+            check_value: envelope.check_value,
+        };
+        seen.entry((key, envelope.mode, envelope.nonce))
+            .or_default()
+            .push(index);
+    }
+    report.reuses = seen
+        .into_iter()
+        .filter(|(_, indices)| indices.len() > 1)
+        .map(|((key, mode, nonce), indices)| NonceReuse {
+            key,
+            mode,
+            nonce,
+            indices,
+        })
+        .collect();
+    report
+        .reuses
+        .sort_by(|a, b| (&a.key, a.indices[0]).cmp(&(&b.key, b.indices[0])));
+    report
+}
+
+/// The most likely reason an envelope won't open, from [`diagnose`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Diagnosis {
+    /// It opens and authenticates, or has a check value that matched, so the envelope and the
+    /// keyring are fine.
+    Opens { key_id: u32 },
+    /// It decrypts, but the mode doesn't authenticate and there is no check value, so a wrong
+    /// key would have decrypted to garbage just the same.
+    Unverifiable { key_id: u32 },
+    /// It doesn't start with the envelope magic.
+    NotAnEnvelope,
+    /// It was written by a newer format version than this build reads.
+    UnsupportedVersion(u8),
+    /// The header's lengths need at least `needed` bytes, and there are only `len`.
+    Truncated { needed: usize, len: usize },
+    /// The envelope ends before the data does, by this many bytes.
+    TrailingBytes(usize),
+    /// A header field is invalid, or doesn't fit the mode.
+    Malformed,
+    /// The keyring has no key `key_id`. `matching_version` is a key of the keyring that
+    /// matches the envelope's check value, if any.
+    UnknownKey {
+        key_id: u32,
+        matching_version: Option<u32>,
+    },
+    /// Key `key_id` isn't the key the envelope was sealed with, by its check value or because
+    /// the tag only matches under `matching_version`. The key ID was probably recorded wrong.
+    WrongKey {
+        key_id: u32,
+        matching_version: Option<u32>,
+    },
+    /// The GCM tag matches under no key of the keyring: the envelope was modified, or sealed
+    /// under a key the keyring doesn't have.
+    TagMismatch { key_id: u32 },
+    /// The tag matched but the length padding is invalid, so the envelope was modified.
+    BadPadding { key_id: u32 },
+    /// It authenticates with key `key_id`, but is opened outside its validity period.
+    OutsideValidity { key_id: u32 },
+    /// Key `key_id` doesn't unwrap the data key: it isn't the key-encryption key, or the
+    /// envelope was modified.
+    KeyUnwrapFailed { key_id: u32 },
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let matching = |f: &mut fmt::Formatter<'_>, version: &Option<u32>| match version {
+            Some(version) => write!(f, "; key {} matches it", version),
+            None => Ok(()),
+        };
+        match self {
+            Diagnosis::Opens { key_id } => write!(f, "opens and checks out with key {}", key_id),
+            Diagnosis::Unverifiable { key_id } => write!(
+                f,
+                "decrypts with key {}, but has no tag or check value to confirm the key",
+                key_id
+            ),
+            Diagnosis::NotAnEnvelope => f.write_str("not an envelope"),
+            Diagnosis::UnsupportedVersion(version) => write!(
+                f,
+                "written by envelope version {}, and this build reads up to {}",
+                version, VERSION
+            ),
+            Diagnosis::Truncated { needed, len } => write!(
+                f,
+                "truncated: the header needs at least {} bytes, and there are {}",
+                needed, len
+            ),
+            Diagnosis::TrailingBytes(extra) => {
+                write!(f, "{} bytes follow the end of the envelope", extra)
+            }
+            Diagnosis::Malformed => f.write_str("a header field is invalid for the mode"),
+            Diagnosis::UnknownKey {
+                key_id,
+                matching_version,
+            } => {
+                write!(f, "the keyring has no key {}", key_id)?;
+                matching(f, matching_version)
+            }
+            Diagnosis::WrongKey {
+                key_id,
+                matching_version,
+            } => {
+                write!(
+                    f,
+                    "key {} is not the key the envelope was sealed with",
+                    key_id
+                )?;
+                matching(f, matching_version)
+            }
+            Diagnosis::TagMismatch { key_id } => write!(
+                f,
+                "the tag matches under no key: modified, or sealed under a key other than {}",
+                key_id
+            ),
+            Diagnosis::BadPadding { key_id } => write!(
+                f,
+                "authenticates with key {}, but its length padding is invalid",
+                key_id
+            ),
+            Diagnosis::OutsideValidity { key_id } => write!(
+                f,
+                "authenticates with key {}, but is not valid at this time",
+                key_id
+            ),
+            Diagnosis::KeyUnwrapFailed { key_id } => write!(
+                f,
+                "key {} doesn't unwrap the data key: the wrong key-encryption key, or modified",
+                key_id
+            ),
+        }
+    }
+}
+
+/// How many bytes the envelope encoding in `bytes` says it takes, or at least takes if the
+/// lengths run past the end. Only the lengths are read.
+fn encoded_len(bytes: &[u8]) -> usize {
+    let version = bytes[MAGIC.len()];
+    let length_prefixed_fields = match version {
+        1 => 2,
+        2 => 3,
+        _ => 4,
+    };
+    // The magic, version, mode and key ID.
+    let mut pos = MAGIC.len() + 1 + 1 + 4;
+    for _ in 0..length_prefixed_fields {
+        let Some(&len) = bytes.get(pos) else {
+            return pos + 1;
+        };
+        pos += 1 + len as usize;
+    }
+    if version >= 4 {
+        pos += 1;
+    }
+    if version >= 5 {
+        let Some(&flags) = bytes.get(pos) else {
+            return pos + 1;
+        };
+        pos += 1 + 8 * flags.count_ones() as usize;
+    }
+    let read_u32 = |pos: usize| -> Option<usize> {
+        let bytes = bytes.get(pos..pos + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    let Some(count) = read_u32(pos) else {
+        return pos + 4;
+    };
+    pos += 4;
+    for _ in 0..count {
+        let Some(len) = read_u32(pos) else {
+            return pos + 4;
+        };
+        pos += 4 + len;
+        if pos > bytes.len() {
+            break;
+        }
+    }
+    pos
+}
+
+/// The frequency table of a ciphertext's blocks, from [`codebook`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Codebook {
+    /// Each distinct block and how often it occurs, in the order of first occurrence.
+    entries: Vec<([u8; BLOCK_SIZE], usize)>,
+    /// For each block of the ciphertext, its index in `entries`.
+    sequence: Vec<usize>,
+}
+
+/// Builds the codebook of `cipher_text`'s 16-byte blocks. A trailing partial block is ignored.
+pub fn codebook(cipher_text: &[u8]) -> Codebook {
+    let mut entries = Vec::new();
+    let mut index: HashMap<[u8; BLOCK_SIZE], usize> = HashMap::new();
+    let sequence = cipher_text
+        .chunks_exact(BLOCK_SIZE)
+        .map(|block| {
+            let block: [u8; BLOCK_SIZE] = block.try_into().unwrap();
+            let entry = *index.entry(block).or_insert_with(|| {
+                entries.push((block, 0));
+                entries.len() - 1
+            });
+            entries[entry].1 += 1;
+            entry
+        })
+        .collect();
+    Codebook { entries, sequence }
+}
+
+impl Codebook {
+    pub fn blocks(&self) -> usize {
+        self.sequence.len()
+    }
+
+    pub fn distinct(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// How many blocks have the same value as another block.
+    pub fn repeated(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The share of blocks that are repeats of an earlier block, from 0 for a ciphertext
+    /// indistinguishable from random to nearly 1 for a constant plaintext.
+    pub fn repetition_rate(&self) -> f64 {
+        if self.sequence.is_empty() {
+            return 0.0;
+        }
+        1.0 - self.distinct() as f64 / self.blocks() as f64
+    }
+
+    /// The Shannon entropy of the block distribution, in bits per block. All distinct blocks
+    /// give `log2(blocks)`, the most there can be; every bit short of that is structure the
+    /// ciphertext shows.
+    pub fn entropy_bits(&self) -> f64 {
+        let blocks = self.blocks() as f64;
+        self.entries
+            .iter()
+            .map(|(_, count)| {
+                let p = *count as f64 / blocks;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// The `k` most common blocks and their counts, most common first, and in order of first
+    /// occurrence among equal counts.
+    pub fn top(&self, k: usize) -> Vec<([u8; BLOCK_SIZE], usize)> {
+        let mut entries = self.entries.clone();
+        entries.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        entries.truncate(k);
+        entries
+    }
+
+    /// Renders the ciphertext as a binary PPM image, one pixel per block and `width` pixels
+    /// per row. Repeated blocks take a color from their first three bytes, and blocks that
+    /// occur once are black. Encrypting an uncompressed bitmap with ECB and rendering it at the
+    /// bitmap's width in blocks shows the picture.
+    ///
+    /// # Panics
+    ///
+    /// If `width` is zero.
+    pub fn to_ppm(&self, width: usize) -> Vec<u8> {
+        assert!(width > 0, "a bitmap needs at least one column");
+        let height = self.sequence.len().div_ceil(width);
+        let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for row in 0..height {
+            for column in 0..width {
+                let pixel = match self.sequence.get(row * width + column) {
+                    Some(&entry) if self.entries[entry].1 > 1 => {
+                        let block = &self.entries[entry].0;
+                        [block[0] | 0x40, block[1] | 0x40, block[2] | 0x40]
+                    }
+                    _ => [0; 3],
+                };
+                image.extend_from_slice(&pixel);
+            }
+        }
+        image
+    }
+}
+
+impl fmt::Display for Codebook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks, {} distinct, {:.1}% repeats, {:.2} of at most {:.2} bits of entropy",
+            self.blocks(),
+            self.distinct(),
+            100.0 * self.repetition_rate(),
+            self.entropy_bits(),
+            (self.blocks().max(1) as f64).log2()
+        )?;
+        for (block, count) in self.top(3).into_iter().filter(|(_, count)| *count > 1) {
+            f.write_str("\n")?;
+            for byte in block {
+                write!(f, "{:02x}", byte)?;
+            }
+            write!(f, " x{}", count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The modes [`error_propagation`] compares.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PropagationMode {
+    Ecb,
+    Cbc,
+    Cfb,
+    Ofb,
+    Ctr,
+}
+
+impl PropagationMode {
+    pub const ALL: [PropagationMode; 5] = [
+        PropagationMode::Ecb,
+        PropagationMode::Cbc,
+        PropagationMode::Cfb,
+        PropagationMode::Ofb,
+        PropagationMode::Ctr,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PropagationMode::Ecb => "ecb",
+            PropagationMode::Cbc => "cbc",
+            PropagationMode::Cfb => "cfb",
+            PropagationMode::Ofb => "ofb",
+            PropagationMode::Ctr => "ctr",
+        }
+    }
+
+    /// Encrypts whole blocks without padding, so that a padding check can't hide the damage
+    /// of a flipped bit. The IV or counter block is fixed, since only the damage matters.
+    fn encrypt(self, cipher: &Aes128, plain_text: &[u8]) -> Vec<u8> {
+        let iv = [0x5a; BLOCK_SIZE];
+        match self {
+            PropagationMode::Ecb | PropagationMode::Cbc => {
+                let mut previous = iv;
+                let mut cipher_text = Vec::with_capacity(plain_text.len());
+                for block in plain_text.chunks(BLOCK_SIZE) {
+                    let mut block = GenericArray::clone_from_slice(block);
+                    if self == PropagationMode::Cbc {
+                        block.iter_mut().zip(previous).for_each(|(x, y)| *x ^= y);
+                    }
+                    cipher.encrypt_block(&mut block);
+                    previous = block.into();
+                    cipher_text.extend_from_slice(&block);
+                }
+                cipher_text
+            }
+            PropagationMode::Cfb => {
+                generic::cfb_encrypt_with_iv(cipher, &iv, plain_text)[BLOCK_SIZE..].to_vec()
+            }
+            PropagationMode::Ofb => generic::ofb_apply(cipher, &iv, plain_text),
+            PropagationMode::Ctr => generic::ctr_apply(cipher, &iv, plain_text),
+        }
+    }
+
+    fn decrypt(self, cipher: &Aes128, cipher_text: &[u8]) -> Vec<u8> {
+        let iv = [0x5a; BLOCK_SIZE];
+        match self {
+            PropagationMode::Ecb | PropagationMode::Cbc => {
+                let mut previous = iv;
+                let mut plain_text = Vec::with_capacity(cipher_text.len());
+                for block in cipher_text.chunks(BLOCK_SIZE) {
+                    let mut decrypted = GenericArray::clone_from_slice(block);
+                    cipher.decrypt_block(&mut decrypted);
+                    if self == PropagationMode::Cbc {
+                        decrypted
+                            .iter_mut()
+                            .zip(previous)
+                            .for_each(|(x, y)| *x ^= y);
+                    }
+                    previous = block.try_into().unwrap();
+                    plain_text.extend_from_slice(&decrypted);
+                }
+                plain_text
+            }
+            PropagationMode::Cfb => {
+                let mut with_iv = iv.to_vec();
+                with_iv.extend_from_slice(cipher_text);
+                generic::cfb_decrypt(cipher, &with_iv).expect("the IV is there")
+            }
+            PropagationMode::Ofb => generic::ofb_apply(cipher, &iv, cipher_text),
+            PropagationMode::Ctr => generic::ctr_apply(cipher, &iv, cipher_text),
+        }
+    }
+}
+
+/// What the flipped bits did to the plaintext block at one offset from the bit's block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockEffect {
+    /// How many flips had a plaintext block at this offset to look at.
+    pub observed: usize,
+    /// How many changed more than one bit of it.
+    pub garbled: usize,
+    /// How many flipped exactly the same bit of it, and nothing else.
+    pub same_bit: usize,
+}
+
+impl BlockEffect {
+    /// What happens to this block for most flips: "garbled", "same bit" or "intact".
+    pub fn typical(&self) -> &'static str {
+        if 2 * self.garbled > self.observed {
+            "garbled"
+        } else if 2 * self.same_bit > self.observed {
+            "same bit"
+        } else {
+            "intact"
+        }
+    }
+}
+
+/// The findings of [`error_propagation`] for one mode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorPropagation {
+    pub mode: PropagationMode,
+    /// How many bits were flipped, one at a time.
+    pub flips: usize,
+    /// Indexed by the distance from the block holding the flipped bit, up to the last block
+    /// that was ever changed. Earlier blocks are never changed by any of the modes.
+    pub effects: Vec<BlockEffect>,
+    /// The most plaintext bytes one flipped bit changed.
+    pub max_bytes_changed: usize,
+}
+
+impl fmt::Display for ErrorPropagation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.mode.name())?;
+        for (offset, effect) in self.effects.iter().enumerate() {
+            write!(f, "block +{} {}, ", offset, effect.typical())?;
+        }
+        write!(f, "at most {} bytes changed", self.max_bytes_changed)
+    }
+}
+
+/// Encrypts `plain_text`, zero-padded to whole blocks, with `mode`, flips each ciphertext bit
+/// in turn and decrypts, and counts what each flip changed. The IV isn't flipped.
+pub fn error_propagation(
+    mode: PropagationMode,
+    key: [u8; BLOCK_SIZE],
+    plain_text: &[u8],
+) -> ErrorPropagation {
+    let cipher = Aes128::new(&key.into());
+    let mut plain_text = plain_text.to_vec();
+    plain_text.resize(plain_text.len().next_multiple_of(BLOCK_SIZE), 0);
+    let cipher_text = mode.encrypt(&cipher, &plain_text);
+    let blocks = plain_text.len() / BLOCK_SIZE;
+
+    let mut effects = vec![BlockEffect::default(); blocks];
+    let mut max_bytes_changed = 0;
+    let mut reach = 0;
+    for bit in 0..8 * cipher_text.len() {
+        let (byte, mask) = (bit / 8, 1u8 << (bit % 8));
+        let mut tampered = cipher_text.clone();
+        tampered[byte] ^= mask;
+        let decrypted = mode.decrypt(&cipher, &tampered);
+        let origin = byte / BLOCK_SIZE;
+        let mut changed_bytes = 0;
+        for (offset, effect) in effects[..blocks - origin].iter_mut().enumerate() {
+            let range = (origin + offset) * BLOCK_SIZE..(origin + offset + 1) * BLOCK_SIZE;
+            let difference: Vec<u8> = plain_text[range.clone()]
+                .iter()
+                .zip(&decrypted[range])
+                .map(|(x, y)| x ^ y)
+                .collect();
+            let changed = difference.iter().filter(|&&x| x != 0).count();
+            effect.observed += 1;
+            if changed == 0 {
+                continue;
+            }
+            reach = reach.max(offset + 1);
+            changed_bytes += changed;
+            let mut same_bit = [0; BLOCK_SIZE];
+            same_bit[byte % BLOCK_SIZE] = mask;
+            if difference == same_bit {
+                effect.same_bit += 1;
+            } else {
+                effect.garbled += 1;
+            }
+        }
+        max_bytes_changed = max_bytes_changed.max(changed_bytes);
+    }
+    effects.truncate(reach);
+    ErrorPropagation {
+        mode,
+        flips: 8 * cipher_text.len(),
+        effects,
+        max_bytes_changed,
+    }
+}
+
+/// The version of the key in `keyring` that opens `envelope`, judging by its check value or,
+/// if it has none, by which key its tag matches under.
+fn find_key(envelope: &Envelope, keyring: &KeyManager) -> Option<u32> {
+    keyring
+        .keys()
+        .iter()
+        .find(|managed| {
+            if envelope.check_value.is_empty() {
+                envelope.mode == EnvelopeMode::Gcm && envelope.open(*managed.key).is_ok()
+            } else {
+                envelope.check_value == managed.check_value()
+            }
+        })
+        .map(|managed| managed.version)
+}
+
+/// Works out why the encoded envelope `blob` doesn't open with the keys in `keyring`. See
+/// the [module documentation](self).
+pub fn diagnose(blob: &[u8], keyring: &KeyManager) -> Diagnosis {
+    if !blob.starts_with(MAGIC) {
+        return Diagnosis::NotAnEnvelope;
+    }
+    let envelope = match Envelope::from_bytes(blob) {
+        Ok(envelope) => envelope,
+        Err(EnvelopeError::UnsupportedVersion(version)) => {
+            return Diagnosis::UnsupportedVersion(version)
+        }
+        Err(_) if blob.len() == MAGIC.len() => {
+            return Diagnosis::Truncated {
+                needed: MAGIC.len() + 1,
+                len: blob.len(),
+            }
+        }
+        Err(_) => {
+            let needed = encoded_len(blob);
+            return if needed > blob.len() {
+                Diagnosis::Truncated {
+                    needed,
+                    len: blob.len(),
+                }
+            } else if needed < blob.len() {
+                Diagnosis::TrailingBytes(blob.len() - needed)
+            } else {
+                Diagnosis::Malformed
+            };
+        }
+    };
+
+    let key_id = envelope.key_id;
+    let Some(managed) = keyring.get(key_id) else {
+        return Diagnosis::UnknownKey {
+            key_id,
+            matching_version: find_key(&envelope, keyring),
+        };
+    };
+    if !envelope.wrapped_key.is_empty() {
+        // The check value is the data key's, so only unwrapping tells.
+        return match open_with_ephemeral_key(&managed.key, &envelope) {
+            Ok(_) => Diagnosis::Opens { key_id },
+            Err(_) => Diagnosis::KeyUnwrapFailed { key_id },
+        };
+    }
+    let wrong_key = || Diagnosis::WrongKey {
+        key_id,
+        matching_version: find_key(&envelope, keyring),
+    };
+    match envelope.open(*managed.key) {
+        Ok(_) if envelope.mode == EnvelopeMode::Gcm || !envelope.check_value.is_empty() => {
+            Diagnosis::Opens { key_id }
+        }
+        Ok(_) => Diagnosis::Unverifiable { key_id },
+        Err(EnvelopeError::WrongKey) => wrong_key(),
+        Err(EnvelopeError::Authentication) => match wrong_key() {
+            Diagnosis::WrongKey {
+                matching_version: None,
+                ..
+            } => Diagnosis::TagMismatch { key_id },
+            diagnosis => diagnosis,
+        },
+        Err(EnvelopeError::NotYetValid { .. } | EnvelopeError::Expired { .. }) => {
+            Diagnosis::OutsideValidity { key_id }
+        }
+        // Only a bad length padding gets past the header checks.
+        Err(_) if envelope.mode == EnvelopeMode::Gcm || !envelope.check_value.is_empty() => {
+            Diagnosis::BadPadding { key_id }
+        }
+        // Without a tag or check value, the wrong key garbles the padding too.
+        Err(_) => Diagnosis::Unverifiable { key_id },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cbc_encrypt, ecb_encrypt, keys::KeyPolicy};
+
+    const KEY: [u8; BLOCK_SIZE] = [5; BLOCK_SIZE];
+
+    #[test]
+    fn test_finds_reuse_per_key() {
+        let fresh = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, b"first".to_vec());
+        let mut reused = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, b"second".to_vec());
+        reused.nonce = fresh.nonce.clone();
+        // The same nonce under another key is fine.
+        let mut other_key = Envelope::seal(EnvelopeMode::Gcm, 2, [6; 16], b"third".to_vec());
+        other_key.nonce = fresh.nonce.clone();
+        let mut cbc = Envelope::seal(EnvelopeMode::Cbc, 1, KEY, b"cbc".to_vec());
+        cbc.nonce = vec![0; BLOCK_SIZE];
+
+        let store = vec![
+            fresh.to_bytes(),
+            other_key.to_bytes(),
+            Envelope::seal(EnvelopeMode::Ecb, 1, KEY, b"ecb".to_vec()).to_bytes(),
+            cbc.to_bytes(),
+            b"not an envelope".to_vec(),
+            reused.to_bytes(),
+            cbc.to_bytes(),
+        ];
+        let report = scan_nonces(&store);
+        assert_eq!(report.scanned, 7);
+        assert_eq!(report.malformed, [4]);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.reuses.len(), 2);
+        assert_eq!(report.reuses[0].indices, [0, 5]);
+        assert!(report.reuses[0].is_catastrophic());
+        assert_eq!(report.reuses[1].indices, [3, 6]);
+        assert!(!report.reuses[1].is_catastrophic());
+        assert!(report
+            .to_string()
+            .contains("key 1: Cbc nonce 00000000000000000000000000000000 in envelopes [3, 6]"));
+    }
+
+    #[test]
+    fn test_check_values_separate_keys() {
+        let first = Envelope::seal(EnvelopeMode::Ctr, 1, KEY, vec![1]).with_check_value(&KEY);
+        let mut second =
+            Envelope::seal(EnvelopeMode::Ctr, 1, [6; 16], vec![2]).with_check_value(&[6; 16]);
+        second.nonce = first.nonce.clone();
+        let report = scan_nonces([first.to_bytes(), second.to_bytes()]);
+        assert!(report.is_clean(), "{}", report);
+        assert!(report.to_string().ends_with("no nonce reuse"));
+    }
+
+    #[test]
+    fn test_diagnoses_damaged_envelopes() {
+        let mut keyring = KeyManager::new();
+        let version = keyring.add(KEY, KeyPolicy::default());
+        let bytes = Envelope::seal(EnvelopeMode::Gcm, version, KEY, b"secret".to_vec()).to_bytes();
+        assert_eq!(
+            diagnose(&bytes, &keyring),
+            Diagnosis::Opens { key_id: version }
+        );
+
+        assert_eq!(diagnose(b"secret", &keyring), Diagnosis::NotAnEnvelope);
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(
+            diagnose(&newer, &keyring),
+            Diagnosis::UnsupportedVersion(VERSION + 1)
+        );
+        assert_eq!(
+            diagnose(&bytes[..bytes.len() - 2], &keyring),
+            Diagnosis::Truncated {
+                needed: bytes.len(),
+                len: bytes.len() - 2
+            }
+        );
+        let mut longer = bytes.clone();
+        longer.extend_from_slice(b"!!!");
+        assert_eq!(diagnose(&longer, &keyring), Diagnosis::TrailingBytes(3));
+
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            diagnose(&tampered, &keyring),
+            Diagnosis::TagMismatch { key_id: version }
+        );
+    }
+
+    #[test]
+    fn test_diagnoses_key_mixups() {
+        let mut keyring = KeyManager::new();
+        let first = keyring.add(KEY, KeyPolicy::default());
+        let second = keyring.add([6; BLOCK_SIZE], KeyPolicy::default());
+
+        // Sealed under the first key, but recorded as the second.
+        let gcm = Envelope::seal(EnvelopeMode::Gcm, second, KEY, b"secret".to_vec());
+        let cbc = Envelope::seal(EnvelopeMode::Cbc, second, KEY, b"secret".to_vec())
+            .with_check_value(&KEY);
+        for envelope in [&gcm, &cbc] {
+            assert_eq!(
+                diagnose(&envelope.to_bytes(), &keyring),
+                Diagnosis::WrongKey {
+                    key_id: second,
+                    matching_version: Some(first)
+                }
+            );
+        }
+
+        let unknown =
+            Envelope::seal(EnvelopeMode::Ctr, 99, KEY, b"secret".to_vec()).with_check_value(&KEY);
+        let diagnosis = diagnose(&unknown.to_bytes(), &keyring);
+        assert_eq!(
+            diagnosis,
+            Diagnosis::UnknownKey {
+                key_id: 99,
+                matching_version: Some(first)
+            }
+        );
+        assert_eq!(
+            diagnosis.to_string(),
+            format!("the keyring has no key 99; key {} matches it", first)
+        );
+        let unchecked = Envelope::seal(EnvelopeMode::Ctr, first, KEY, b"secret".to_vec());
+        assert_eq!(
+            diagnose(&unchecked.to_bytes(), &keyring),
+            Diagnosis::Unverifiable { key_id: first }
+        );
+    }
+
+    #[test]
+    fn test_codebook() {
+        // Two kinds of 16-byte rows, as in a two-color image.
+        let mut plain_text = Vec::new();
+        for row in 0..64 {
+            plain_text.extend_from_slice(&[(row % 4 == 0) as u8; BLOCK_SIZE]);
+        }
+        let ecb = codebook(&ecb_encrypt(plain_text.clone(), KEY));
+        // The padding block is the only one of its kind.
+        assert_eq!((ecb.blocks(), ecb.distinct(), ecb.repeated()), (65, 3, 64));
+        assert_eq!(ecb.top(1)[0].1, 48);
+        assert!(ecb.entropy_bits() < 1.0);
+        assert!(ecb.to_string().starts_with("65 blocks, 3 distinct"));
+
+        let cbc = codebook(&cbc_encrypt(plain_text, KEY));
+        assert_eq!(cbc.repeated(), 0);
+        assert_eq!(cbc.repetition_rate(), 0.0);
+        assert!((cbc.entropy_bits() - 66f64.log2()).abs() < 1e-9);
+
+        let image = ecb.to_ppm(8);
+        let header = b"P6\n8 9\n255\n";
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(image.len(), header.len() + 8 * 9 * 3);
+        assert_ne!(&image[header.len()..header.len() + 3], [0; 3]);
+        assert!(cbc.to_ppm(8)[header.len()..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        let plain_text = b"four blocks of plaintext, so each mode has room to spread damage";
+        let table: Vec<ErrorPropagation> = PropagationMode::ALL
+            .into_iter()
+            .map(|mode| error_propagation(mode, KEY, plain_text))
+            .collect();
+        let typical = |row: &ErrorPropagation| -> Vec<&str> {
+            row.effects.iter().map(BlockEffect::typical).collect()
+        };
+        assert_eq!(typical(&table[0]), ["garbled"]);
+        assert_eq!(typical(&table[1]), ["garbled", "same bit"]);
+        assert_eq!(typical(&table[2]), ["same bit", "garbled"]);
+        assert_eq!(typical(&table[3]), ["same bit"]);
+        assert_eq!(typical(&table[4]), ["same bit"]);
+        assert_eq!(table[4].max_bytes_changed, 1);
+        // The last block has no next block, so 128 of the 512 CBC flips can't reach one.
+        assert_eq!(table[1].flips, 512);
+        assert_eq!(table[1].effects[1].observed, 384);
+        assert_eq!(table[1].effects[1].same_bit, 384);
+        assert_eq!(table[1].max_bytes_changed, BLOCK_SIZE + 1);
+        assert_eq!(
+            table[2].to_string(),
+            "cfb: block +0 same bit, block +1 garbled, at most 17 bytes changed"
+        );
+    }
+}
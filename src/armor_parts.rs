@@ -0,0 +1,310 @@
+//! Armored text split into numbered, checksummed parts, for QR codes and SMS.
+//!
+//! Moving a secret to an air-gapped machine often means a phone camera or a text message, and a
+//! QR code holds a couple of kilobytes at most, an SMS 160 characters. [`split`] cuts armored
+//! ciphertext, such as a [data URL](crate::mime) or [`transcode`](crate::transcode) output, into
+//! parts no longer than a given length:
+//!
+//! ```text
+//! AMP:<set>:<index>/<total>:<text>:<check>
+//! ```
+//!
+//! `set` is the first four bytes of the SHA-256 of the whole text, in hex, so parts of two
+//! transfers can't be mixed, and it checks the reassembled text once more. The first part has
+//! `index` 1. `check` is the first four bytes of the SHA-256 of everything before it, and
+//! catches a part mangled by a bad scan or a carrier that rewrote it.
+//!
+//! The parts may arrive in any order. A [`Reassembler`] takes them as they are scanned, says
+//! which are [`missing`](Reassembler::missing), and shrugs off a part scanned twice; [`join`]
+//! does the same for a batch that should be complete, and rejects one with a part missing or
+//! repeated. Neither keeps the text secret: that is the ciphertext's job, and the checksums are
+//! for accidents, not attackers, who are stopped by the ciphertext's own tag.
+
+use std::{error::Error, fmt};
+
+use sha2::{Digest, Sha256};
+
+const PREFIX: &str = "AMP:";
+/// Hex digits in the set ID and in a part's check.
+const HASH_DIGITS: usize = 8;
+
+/// Why a part, or a set of them, was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartError {
+    /// It isn't laid out as a part, or its index is out of range.
+    Malformed,
+    /// The part was damaged in transit.
+    Checksum {
+        index: u16,
+    },
+    /// The part belongs to another set, or says the set has a different number of parts.
+    OtherSet,
+    /// Two different parts claim the same index.
+    Conflict {
+        index: u16,
+    },
+    /// [`join`] was given the same part twice.
+    Duplicate {
+        index: u16,
+    },
+    Missing {
+        index: u16,
+    },
+    /// Every part checks, but together they don't give the text the set ID was made from.
+    Corrupt,
+}
+
+impl fmt::Display for PartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartError::Malformed => f.write_str("malformed armor part"),
+            PartError::Checksum { index } => write!(f, "part {} is damaged", index),
+            PartError::OtherSet => f.write_str("part belongs to another set"),
+            PartError::Conflict { index } => write!(f, "two different parts {}", index),
+            PartError::Duplicate { index } => write!(f, "part {} given twice", index),
+            PartError::Missing { index } => write!(f, "part {} is missing", index),
+            PartError::Corrupt => f.write_str("reassembled text doesn't match its set ID"),
+        }
+    }
+}
+
+impl Error for PartError {}
+
+fn hash_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes())[..HASH_DIGITS / 2]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The characters of a part other than its text, when the set has `total` parts.
+fn overhead(total: usize) -> usize {
+    let digits = total.to_string().len();
+    PREFIX.len() + HASH_DIGITS + 1 + 2 * digits + 1 + 1 + 1 + HASH_DIGITS
+}
+
+/// Splits `text` into parts of at most `max_len` characters.
+///
+/// # Panics
+///
+/// If `max_len` leaves no room for text after a part's header and check, about 30
+/// characters, or the text would need more than 65535 parts.
+pub fn split(text: &str, max_len: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    // The header grows with the number of parts, which depends on the room it leaves.
+    let mut total = 1;
+    let room = loop {
+        let room = max_len.saturating_sub(overhead(total));
+        assert!(
+            room > 0,
+            "parts of {} characters leave no room for text",
+            max_len
+        );
+        let needed = chars.len().div_ceil(room).max(1);
+        if needed <= total {
+            break room;
+        }
+        total = needed;
+    };
+    assert!(total <= usize::from(u16::MAX), "too many parts");
+
+    let set = hash_hex(text);
+    let pieces: Vec<String> = if chars.is_empty() {
+        vec![String::new()]
+    } else {
+        chars
+            .chunks(room)
+            .map(|piece| piece.iter().collect())
+            .collect()
+    };
+    pieces
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| {
+            let body = format!("{}{}:{}/{}:{}", PREFIX, set, i + 1, pieces.len(), piece);
+            let check = hash_hex(&body);
+            format!("{}:{}", body, check)
+        })
+        .collect()
+}
+
+/// A part, parsed and checked.
+struct Parsed<'a> {
+    set: &'a str,
+    index: u16,
+    total: u16,
+    text: &'a str,
+}
+
+fn parse(part: &str) -> Result<Parsed<'_>, PartError> {
+    let part = part.trim();
+    let (body, check) = part.rsplit_once(':').ok_or(PartError::Malformed)?;
+    let rest = body.strip_prefix(PREFIX).ok_or(PartError::Malformed)?;
+    let (set, rest) = rest.split_once(':').ok_or(PartError::Malformed)?;
+    let (position, text) = rest.split_once(':').ok_or(PartError::Malformed)?;
+    let (index, total) = position.split_once('/').ok_or(PartError::Malformed)?;
+    let index: u16 = index.parse().map_err(|_| PartError::Malformed)?;
+    let total: u16 = total.parse().map_err(|_| PartError::Malformed)?;
+    if set.len() != HASH_DIGITS || index == 0 || index > total {
+        return Err(PartError::Malformed);
+    }
+    if !check.eq_ignore_ascii_case(&hash_hex(body)) {
+        return Err(PartError::Checksum { index });
+    }
+    Ok(Parsed {
+        set,
+        index,
+        total,
+        text,
+    })
+}
+
+/// What [`Reassembler::add`] made of a part.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Added {
+    New,
+    /// The part was already there, as when the same QR code is scanned twice.
+    Duplicate,
+}
+
+/// Collects the parts of one set as they arrive, in any order.
+#[derive(Clone, Debug, Default)]
+pub struct Reassembler {
+    set: Option<String>,
+    parts: Vec<Option<String>>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a part. The first one fixes the set, and any part of another set is rejected.
+    pub fn add(&mut self, part: &str) -> Result<Added, PartError> {
+        let parsed = parse(part)?;
+        match &self.set {
+            None => {
+                self.set = Some(parsed.set.to_ascii_lowercase());
+                self.parts = vec![None; usize::from(parsed.total)];
+            }
+            Some(set) => {
+                if !set.eq_ignore_ascii_case(parsed.set) || self.parts.len() != parsed.total.into()
+                {
+                    return Err(PartError::OtherSet);
+                }
+            }
+        }
+        let slot = &mut self.parts[usize::from(parsed.index) - 1];
+        match slot {
+            Some(text) if text == parsed.text => Ok(Added::Duplicate),
+            Some(_) => Err(PartError::Conflict {
+                index: parsed.index,
+            }),
+            None => {
+                *slot = Some(parsed.text.to_string());
+                Ok(Added::New)
+            }
+        }
+    }
+
+    /// The number of parts in the set, once one has been added.
+    pub fn total(&self) -> Option<u16> {
+        self.set.as_ref().map(|_| self.parts.len() as u16)
+    }
+
+    /// The indexes of the parts still to come, or all of them if none has been added yet.
+    pub fn missing(&self) -> Vec<u16> {
+        (1..)
+            .zip(&self.parts)
+            .filter(|(_, part)| part.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.set.is_some() && self.parts.iter().all(Option::is_some)
+    }
+
+    /// The reassembled text, once every part is in.
+    pub fn finish(self) -> Result<String, PartError> {
+        let Some(set) = self.set else {
+            return Err(PartError::Missing { index: 1 });
+        };
+        let mut text = String::new();
+        for (index, part) in (1..).zip(self.parts) {
+            text.push_str(&part.ok_or(PartError::Missing { index })?);
+        }
+        if hash_hex(&text) != set {
+            return Err(PartError::Corrupt);
+        }
+        Ok(text)
+    }
+}
+
+/// Reassembles a complete set of parts, in any order. Unlike a [`Reassembler`], it rejects a
+/// part given twice.
+pub fn join<S: AsRef<str>>(parts: impl IntoIterator<Item = S>) -> Result<String, PartError> {
+    let mut reassembler = Reassembler::new();
+    for part in parts {
+        if reassembler.add(part.as_ref())? == Added::Duplicate {
+            let index = parse(part.as_ref())?.index;
+            return Err(PartError::Duplicate { index });
+        }
+    }
+    reassembler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "data:application/vnd.aes-modes.envelope;mode=gcm;version=5;base64,\
+                        AU1BRQUDAAAAAwAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==";
+
+    #[test]
+    fn test_round_trip() {
+        for max_len in [40, 60, 160, 2000] {
+            let parts = split(TEXT, max_len);
+            assert!(parts.iter().all(|part| part.chars().count() <= max_len));
+            assert_eq!(join(&parts), Ok(TEXT.to_string()));
+            assert_eq!(join(parts.iter().rev()), Ok(TEXT.to_string()));
+        }
+        assert_eq!(split(TEXT, 2000).len(), 1);
+        assert_eq!(join(split("", 40)), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_reassembler() {
+        let parts = split(TEXT, 50);
+        assert!(parts.len() > 3);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add(&parts[2]), Ok(Added::New));
+        assert_eq!(reassembler.add(&parts[2]), Ok(Added::Duplicate));
+        assert_eq!(reassembler.total(), Some(parts.len() as u16));
+        assert!(reassembler.missing().contains(&1));
+        assert!(!reassembler.missing().contains(&3));
+        assert_eq!(
+            reassembler.add(&split("another transfer", 50)[0]),
+            Err(PartError::OtherSet)
+        );
+        for part in &parts {
+            reassembler.add(part).unwrap();
+        }
+        assert!(reassembler.is_complete());
+        assert_eq!(reassembler.finish(), Ok(TEXT.to_string()));
+    }
+
+    #[test]
+    fn test_detects_bad_sets() {
+        let parts = split(TEXT, 50);
+        assert_eq!(join(&parts[1..]), Err(PartError::Missing { index: 1 }));
+        let mut twice = parts.clone();
+        twice.push(parts[1].clone());
+        assert_eq!(join(&twice), Err(PartError::Duplicate { index: 2 }));
+
+        let mut damaged = parts.clone();
+        damaged[0] = damaged[0].replacen("data", "dada", 1);
+        assert_eq!(join(&damaged), Err(PartError::Checksum { index: 1 }));
+        assert_eq!(join(["not a part"]), Err(PartError::Malformed));
+    }
+}
@@ -0,0 +1,252 @@
+//! A structured audit trail of encryptions and decryptions.
+//!
+//! Some environments have to be able to prove, after the fact, what was encrypted or decrypted,
+//! by whom, and with which key. An [`Auditor`] wraps the high-level [`Mode`] API and reports an
+//! [`AuditEvent`] to an [`AuditSink`] for every call. The events never contain key material or
+//! data, only a one-way key fingerprint and the number of bytes processed.
+//!
+//! Auditing fails closed: if the sink can't record the event, the output is not returned.
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{aes_encrypt, Mode, BLOCK_SIZE};
+
+/// The fixed block that gets encrypted to fingerprint a key. It is deliberately not the all-zero
+/// block, so the fingerprint can't be confused with (or used to compute) a key check value.
+const FINGERPRINT_BLOCK: [u8; BLOCK_SIZE] = *b"aes-modes key fp";
+
+/// Identifies a key without revealing it: the first 8 bytes of the key's encryption of a fixed
+/// block, in hex. Finding a key that matches a fingerprint is as hard as breaking AES.
+pub fn key_fingerprint(key: &[u8; BLOCK_SIZE]) -> String {
+    aes_encrypt(FINGERPRINT_BLOCK, key)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// One audited operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// Who asked for the operation, in whatever form the application identifies callers.
+    pub actor: String,
+    pub key_fingerprint: String,
+    /// The caller's version number for the key, if it keeps track of them.
+    pub key_version: Option<u32>,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Either `"encrypt"` or `"decrypt"`.
+    pub operation: &'static str,
+    pub mode: Mode,
+    /// Length of the input that was processed.
+    pub bytes: usize,
+}
+
+impl AuditEvent {
+    /// Renders the event as a single line of JSON, without the trailing newline.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        write!(json, "\"actor\":\"{}\"", escape_json(&self.actor)).unwrap();
+        write!(json, ",\"key_fingerprint\":\"{}\"", self.key_fingerprint).unwrap();
+        match self.key_version {
+            Some(version) => write!(json, ",\"key_version\":{}", version).unwrap(),
+            None => json.push_str(",\"key_version\":null"),
+        }
+        write!(json, ",\"timestamp\":{}", self.timestamp).unwrap();
+        write!(json, ",\"operation\":\"{}\"", self.operation).unwrap();
+        write!(json, ",\"mode\":\"{}\"", self.mode.name()).unwrap();
+        write!(json, ",\"bytes\":{}", self.bytes).unwrap();
+        json.push('}');
+        json
+    }
+}
+
+/// Somewhere audit events are sent to.
+pub trait AuditSink {
+    fn record(&self, event: &AuditEvent) -> io::Result<()>;
+}
+
+/// Appends each event as one line of JSON to a file.
+pub struct JsonLinesSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesSink {
+    /// Opens (or creates) the log file for appending. Existing entries are never touched.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, event: &AuditEvent) -> io::Result<()> {
+        let mut line = event.to_json();
+        line.push('\n');
+
+        // A single write per event keeps lines whole even with several writers on one file.
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+/// Runs encryptions and decryptions on behalf of one actor, reporting each of them to a sink.
+pub struct Auditor<'a, S: AuditSink> {
+    sink: &'a S,
+    actor: String,
+    key_version: Option<u32>,
+}
+
+impl<'a, S: AuditSink> Auditor<'a, S> {
+    pub fn new(sink: &'a S, actor: impl Into<String>) -> Self {
+        Auditor {
+            sink,
+            actor: actor.into(),
+            key_version: None,
+        }
+    }
+
+    /// Records the given key version in every event from now on.
+    pub fn with_key_version(mut self, version: u32) -> Self {
+        self.key_version = Some(version);
+        self
+    }
+
+    pub fn encrypt(
+        &self,
+        mode: Mode,
+        plain_text: Vec<u8>,
+        key: [u8; BLOCK_SIZE],
+    ) -> io::Result<Vec<u8>> {
+        let event = self.event("encrypt", mode, plain_text.len(), &key);
+        let cipher_text = mode.encrypt(plain_text, key);
+        self.sink.record(&event)?;
+        Ok(cipher_text)
+    }
+
+    pub fn decrypt(
+        &self,
+        mode: Mode,
+        cipher_text: Vec<u8>,
+        key: [u8; BLOCK_SIZE],
+    ) -> io::Result<Vec<u8>> {
+        let event = self.event("decrypt", mode, cipher_text.len(), &key);
+        let plain_text = mode.decrypt(cipher_text, key);
+        self.sink.record(&event)?;
+        Ok(plain_text)
+    }
+
+    fn event(
+        &self,
+        operation: &'static str,
+        mode: Mode,
+        bytes: usize,
+        key: &[u8; BLOCK_SIZE],
+    ) -> AuditEvent {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+
+        AuditEvent {
+            actor: self.actor.clone(),
+            key_fingerprint: key_fingerprint(key),
+            key_version: self.key_version,
+            timestamp,
+            operation,
+            mode,
+            bytes,
+        }
+    }
+}
+
+/// Escapes a string for use inside a JSON string literal.
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+
+    #[derive(Default)]
+    struct MemorySink(Mutex<Vec<AuditEvent>>);
+
+    impl AuditSink for MemorySink {
+        fn record(&self, event: &AuditEvent) -> io::Result<()> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_are_recorded() {
+        let sink = MemorySink::default();
+        let auditor = Auditor::new(&sink, "alice").with_key_version(3);
+
+        let cipher_text = auditor
+            .encrypt(Mode::Cbc, b"Payroll".to_vec(), KEY)
+            .unwrap();
+        let plain_text = auditor.decrypt(Mode::Cbc, cipher_text, KEY).unwrap();
+        assert_eq!(plain_text, b"Payroll");
+
+        let events = sink.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].operation, "encrypt");
+        assert_eq!(events[0].bytes, 7);
+        assert_eq!(events[1].operation, "decrypt");
+        assert_eq!(events[1].bytes, 32);
+        assert_eq!(events[1].key_version, Some(3));
+        assert_eq!(events[1].key_fingerprint, key_fingerprint(&KEY));
+        assert_ne!(key_fingerprint(&KEY), key_fingerprint(&[1u8; BLOCK_SIZE]));
+    }
+
+    #[test]
+    fn test_json_lines_sink() {
+        let path =
+            std::env::temp_dir().join(format!("aes-modes-audit-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let sink = JsonLinesSink::open(&path).unwrap();
+        let auditor = Auditor::new(&sink, "bob \"the builder\"");
+        auditor.encrypt(Mode::Ctr, b"one".to_vec(), KEY).unwrap();
+        auditor.encrypt(Mode::Ecb, b"two".to_vec(), KEY).unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"actor\":\"bob \\\"the builder\\\"\""));
+        assert!(lines[0].contains("\"key_version\":null"));
+        assert!(lines[0].contains("\"mode\":\"ctr\""));
+        assert!(lines[1].contains("\"mode\":\"ecb\",\"bytes\":3}"));
+    }
+}
@@ -0,0 +1,210 @@
+//! Decrypting anything this crate wrote, without being told how it was written.
+//!
+//! Every format here describes itself: an [`Envelope`] records its mode, key ID and wrapped
+//! data key, a [`chunked`](crate::chunked) stream records its chunk size and compression in
+//! its header and derives its keys with HKDF from the stream ID, and a [`KeyManager`]
+//! ciphertext starts with its key version and mode. [`decrypt_auto`] looks at the first bytes
+//! to tell them apart and finds the key in the keyring:
+//!
+//! | Starts with | Format                 | Key                                          |
+//! |-------------|------------------------|----------------------------------------------|
+//! | `AMEV`      | [`Envelope`]           | the key ID, possibly unwrapping a data key   |
+//! | `AMCS`      | chunked stream         | each key in turn, newest first (see below)   |
+//! | otherwise   | [`KeyManager`] output  | the key version                              |
+//!
+//! A chunked stream doesn't name its key, since it is keyed by an arbitrary master secret. The
+//! keyring's keys are tried as that secret from the newest to the oldest; a wrong key fails on
+//! the first chunk, so this costs one chunk decryption per key tried.
+
+use std::{error::Error, fmt, io::Read};
+
+use crate::{
+    chunked::{self, StreamDecryptor, StreamHeader},
+    envelope::{self, open_with_ephemeral_key, Envelope, EnvelopeError},
+    keys::{KeyManager, PolicyError},
+    keywrap::KeyWrapError,
+    policy::PolicyViolation,
+};
+
+/// Why [`decrypt_auto`] couldn't decrypt a blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoDecryptError {
+    /// The keyring has no key with this ID.
+    UnknownKey { key_id: u32 },
+    /// The blob isn't in any format this crate writes, or is damaged.
+    Malformed,
+    /// The blob was written by a newer version of its format.
+    UnsupportedVersion(u8),
+    /// The keyring's policy doesn't allow decrypting it.
+    Policy(PolicyError),
+    /// Authentication failed: the wrong key, or the blob was modified.
+    Authentication,
+    /// A [`Policy`](crate::policy::Policy) doesn't allow the blob's algorithm or parameters.
+    PolicyViolation(PolicyViolation),
+    /// The blob is authentic, but opened outside its validity period.
+    OutsideValidity,
+}
+
+impl fmt::Display for AutoDecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AutoDecryptError::UnknownKey { key_id } => write!(f, "no key with ID {}", key_id),
+            AutoDecryptError::Malformed => f.write_str("unrecognized or malformed ciphertext"),
+            AutoDecryptError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            AutoDecryptError::Policy(error) => error.fmt(f),
+            AutoDecryptError::Authentication => f.write_str("authentication failed"),
+            AutoDecryptError::PolicyViolation(violation) => violation.fmt(f),
+            AutoDecryptError::OutsideValidity => f.write_str("not valid at this time"),
+        }
+    }
+}
+
+impl Error for AutoDecryptError {}
+
+impl From<EnvelopeError> for AutoDecryptError {
+    fn from(error: EnvelopeError) -> Self {
+        match error {
+            EnvelopeError::Malformed | EnvelopeError::TooLarge => AutoDecryptError::Malformed,
+            EnvelopeError::UnsupportedVersion(version) => {
+                AutoDecryptError::UnsupportedVersion(version)
+            }
+            EnvelopeError::Authentication | EnvelopeError::WrongKey => {
+                AutoDecryptError::Authentication
+            }
+            EnvelopeError::Policy(violation) => AutoDecryptError::PolicyViolation(violation),
+            EnvelopeError::NotYetValid { .. } | EnvelopeError::Expired { .. } => {
+                AutoDecryptError::OutsideValidity
+            }
+        }
+    }
+}
+
+/// Works out how `blob` was encrypted and decrypts it with the right key from `keyring`.
+pub fn decrypt_auto(keyring: &KeyManager, blob: &[u8]) -> Result<Vec<u8>, AutoDecryptError> {
+    if blob.starts_with(envelope::MAGIC) {
+        decrypt_envelope(keyring, blob)
+    } else if blob.starts_with(chunked::MAGIC) {
+        decrypt_stream(keyring, blob)
+    } else {
+        keyring.decrypt(blob).map_err(|error| match error {
+            PolicyError::UnknownVersion { version } => {
+                AutoDecryptError::UnknownKey { key_id: version }
+            }
+            PolicyError::Malformed => AutoDecryptError::Malformed,
+            error => AutoDecryptError::Policy(error),
+        })
+    }
+}
+
+fn decrypt_envelope(keyring: &KeyManager, blob: &[u8]) -> Result<Vec<u8>, AutoDecryptError> {
+    let envelope = Envelope::from_bytes(blob)?;
+    let key_id = envelope.key_id;
+    let key = *keyring
+        .get(key_id)
+        .ok_or(AutoDecryptError::UnknownKey { key_id })?
+        .key;
+
+    if envelope.wrapped_key.is_empty() {
+        Ok(envelope.open(key)?)
+    } else {
+        open_with_ephemeral_key(&key, &envelope).map_err(|error| match error {
+            KeyWrapError::Truncated => AutoDecryptError::Malformed,
+            KeyWrapError::Integrity => AutoDecryptError::Authentication,
+            KeyWrapError::Backend(never) => match never {},
+        })
+    }
+}
+
+fn decrypt_stream(keyring: &KeyManager, blob: &[u8]) -> Result<Vec<u8>, AutoDecryptError> {
+    let header = blob
+        .get(..chunked::HEADER_SIZE)
+        .ok_or(AutoDecryptError::Malformed)?;
+    StreamHeader::from_bytes(header.try_into().unwrap())
+        .map_err(|_| AutoDecryptError::Malformed)?;
+
+    for managed in keyring.keys().iter().rev() {
+        let mut plain_text = Vec::new();
+        let result = StreamDecryptor::new(&managed.key[..], blob)
+            .and_then(|mut decryptor| decryptor.read_to_end(&mut plain_text));
+        if result.is_ok() {
+            return Ok(plain_text);
+        }
+    }
+    Err(AutoDecryptError::Authentication)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{
+        chunked::StreamEncryptor,
+        envelope::{seal_with_ephemeral_key, EnvelopeMode},
+        keys::KeyPolicy,
+        Mode,
+    };
+
+    use super::*;
+
+    fn keyring() -> KeyManager {
+        let mut keyring = KeyManager::new();
+        keyring.generate(KeyPolicy::default());
+        keyring.generate(KeyPolicy::default());
+        keyring
+    }
+
+    #[test]
+    fn test_every_format() {
+        let mut keyring = keyring();
+        let old_key = *keyring.get(1).unwrap().key;
+        let message = b"no out-of-band knowledge needed".to_vec();
+
+        let envelope = Envelope::seal(EnvelopeMode::Cbc, 1, old_key, message.clone());
+        assert_eq!(
+            decrypt_auto(&keyring, &envelope.to_bytes()),
+            Ok(message.clone())
+        );
+
+        let wrapped = seal_with_ephemeral_key(&old_key, 1, message.clone()).unwrap();
+        assert_eq!(
+            decrypt_auto(&keyring, &wrapped.to_bytes()),
+            Ok(message.clone())
+        );
+
+        let mut encryptor = StreamEncryptor::new(&old_key, Vec::new()).unwrap();
+        encryptor.write_all(&message).unwrap();
+        let stream = encryptor.finish().unwrap();
+        assert_eq!(decrypt_auto(&keyring, &stream), Ok(message.clone()));
+
+        let managed = keyring.encrypt(Mode::Ctr, message.clone()).unwrap();
+        assert_eq!(decrypt_auto(&keyring, &managed), Ok(message));
+    }
+
+    #[test]
+    fn test_errors() {
+        let keyring = keyring();
+        let envelope = Envelope::seal(EnvelopeMode::Gcm, 9, [0u8; 16], b"hi".to_vec());
+        assert_eq!(
+            decrypt_auto(&keyring, &envelope.to_bytes()),
+            Err(AutoDecryptError::UnknownKey { key_id: 9 })
+        );
+
+        let mut encryptor = StreamEncryptor::new(b"not in the keyring", Vec::new()).unwrap();
+        encryptor.write_all(b"hi").unwrap();
+        let stream = encryptor.finish().unwrap();
+        assert_eq!(
+            decrypt_auto(&keyring, &stream),
+            Err(AutoDecryptError::Authentication)
+        );
+        assert_eq!(
+            decrypt_auto(&keyring, &stream[..10]),
+            Err(AutoDecryptError::Malformed)
+        );
+        assert_eq!(
+            decrypt_auto(&keyring, b"??"),
+            Err(AutoDecryptError::Malformed)
+        );
+    }
+}
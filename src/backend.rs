@@ -0,0 +1,610 @@
+//! A choice of AES implementation behind the ECB, CBC, CTR and GCM modes, made at runtime.
+//!
+//! The modes at the crate root always use the `aes` crate. A [`Backend`] names an AES
+//! implementation instead, and [`Backend::context`] gives a [`CipherContext`] that runs the
+//! same modes, in the same formats, on that implementation. Different contexts in one program
+//! can use different backends.
+//!
+//! All backends must give identical output, and the tests here check that they do, so a new
+//! backend is validated by adding it to [`Backend::available`]. Another implementation of AES
+//! is mostly useful for exactly that kind of cross-check, and later for delegating the block
+//! cipher to a certified module while keeping this crate's formats.
+//!
+//! - [`Backend::RustCryptoAes`] is the `aes` crate, the default and the one to use.
+//! - `Backend::TextbookAes`, with the `textbook` feature, is the
+//!   step-by-step [`TextbookAes`](crate::textbook::TextbookAes). It is slow and its timing
+//!   depends on the key, so it is only for testing.
+//! - `Backend::OpenSsl`, with the `openssl` feature, runs every block operation through
+//!   OpenSSL's EVP interface, and GCM as a whole in OpenSSL too. Only the modes and formats
+//!   are this crate's, so with OpenSSL's FIPS provider configured as the default (in
+//!   `openssl.cnf`), the cryptography happens in the validated module.
+//!
+//! # Sharing between threads
+//!
+//! A [`CipherContext`] is `Send` and `Sync` on every backend, and a server can set one up
+//! once and share it, in an `Arc` or a `static`, across all its request handlers without a
+//! lock. The key schedule is fixed when the context is made and never written again, every
+//! method takes `&self`, and whatever state a call needs (chaining values, counters, the GHASH
+//! accumulator) lives on that call's stack. The random IVs and nonces come from a per-thread
+//! RNG, so concurrent calls neither contend for it nor share its output.
+//!
+//! Sharing is checked when the crate is compiled: a backend whose state isn't `Send + Sync`
+//! doesn't build.
+
+use std::fmt;
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+#[cfg(feature = "textbook")]
+use crate::textbook::TextbookAes;
+use crate::{
+    gcm::{AuthenticationError, GcmKey, GCM_NONCE_SIZE},
+    generic::MalformedCiphertext,
+    group, pad, un_group, un_pad, util, utils,
+    warnings::{self, Warning},
+    Mode, BLOCK_SIZE, NONCE_SIZE,
+};
+
+/// An implementation of AES-128. See the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Backend {
+    #[default]
+    RustCryptoAes,
+    #[cfg(feature = "textbook")]
+    TextbookAes,
+    #[cfg(feature = "openssl")]
+    OpenSsl,
+}
+
+impl Backend {
+    /// Every backend compiled in, the default first.
+    pub fn available() -> Vec<Backend> {
+        vec![
+            Backend::RustCryptoAes,
+            #[cfg(feature = "textbook")]
+            Backend::TextbookAes,
+            #[cfg(feature = "openssl")]
+            Backend::OpenSsl,
+        ]
+    }
+
+    /// Short lowercase name, as used in logs and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::RustCryptoAes => "rustcrypto",
+            #[cfg(feature = "textbook")]
+            Backend::TextbookAes => "textbook",
+            #[cfg(feature = "openssl")]
+            Backend::OpenSsl => "openssl",
+        }
+    }
+
+    /// Sets up `key` on this backend.
+    pub fn context(self, key: [u8; BLOCK_SIZE]) -> CipherContext {
+        let cipher = match self {
+            Backend::RustCryptoAes => {
+                Cipher::RustCrypto(Box::new(Aes128::new(&GenericArray::from(key))))
+            }
+            #[cfg(feature = "textbook")]
+            Backend::TextbookAes => Cipher::Textbook(TextbookAes::new(&key)),
+            #[cfg(feature = "openssl")]
+            Backend::OpenSsl => Cipher::OpenSsl(openssl_backend::Key::new(key)),
+        };
+        CipherContext {
+            backend: self,
+            cipher,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Cipher {
+    RustCrypto(Box<Aes128>),
+    #[cfg(feature = "textbook")]
+    Textbook(TextbookAes),
+    #[cfg(feature = "openssl")]
+    OpenSsl(openssl_backend::Key),
+}
+
+/// A key set up on one [`Backend`], with the modes of the crate root.
+///
+/// The ciphertexts are in the same formats as [`Mode::encrypt`], so either side can decrypt
+/// what the other encrypted.
+#[derive(Clone)]
+pub struct CipherContext {
+    backend: Backend,
+    cipher: Cipher,
+}
+
+/// Fails to compile if a backend makes the context unsafe to share.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CipherContext>();
+};
+
+/// Shows the backend only: the key schedule is as good as the key.
+impl fmt::Debug for CipherContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherContext")
+            .field("backend", &self.backend)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CipherContext {
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+        match &self.cipher {
+            Cipher::RustCrypto(cipher) => {
+                let mut block = GenericArray::from(block);
+                cipher.encrypt_block(&mut block);
+                block.into()
+            }
+            #[cfg(feature = "textbook")]
+            Cipher::Textbook(cipher) => cipher.encrypt_block(block),
+            #[cfg(feature = "openssl")]
+            Cipher::OpenSsl(key) => key.block(openssl::symm::Mode::Encrypt, block),
+        }
+    }
+
+    pub fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+        match &self.cipher {
+            Cipher::RustCrypto(cipher) => {
+                let mut block = GenericArray::from(block);
+                cipher.decrypt_block(&mut block);
+                block.into()
+            }
+            #[cfg(feature = "textbook")]
+            Cipher::Textbook(cipher) => cipher.decrypt_block(block),
+            #[cfg(feature = "openssl")]
+            Cipher::OpenSsl(key) => key.block(openssl::symm::Mode::Decrypt, block),
+        }
+    }
+
+    /// Encrypts with `mode` and a random IV or nonce, like [`Mode::encrypt`].
+    pub fn encrypt(&self, mode: Mode, plain_text: Vec<u8>) -> Vec<u8> {
+        match mode {
+            Mode::Ecb => self.ecb_encrypt(plain_text),
+            Mode::Cbc => self.cbc_encrypt_with_iv(plain_text, utils::create_rand_init_vector()),
+            Mode::Ctr => self.ctr_encrypt_with_nonce(plain_text, utils::create_rand_nonce()),
+        }
+    }
+
+    /// Like [`Mode::decrypt`], but returning an error for a ciphertext of the wrong length
+    /// instead of panicking.
+    pub fn decrypt(
+        &self,
+        mode: Mode,
+        cipher_text: Vec<u8>,
+    ) -> Result<Vec<u8>, MalformedCiphertext> {
+        match mode {
+            Mode::Ecb => self.ecb_decrypt(cipher_text),
+            Mode::Cbc => self.cbc_decrypt(cipher_text),
+            Mode::Ctr => self.ctr_decrypt(cipher_text),
+        }
+    }
+
+    pub fn ecb_encrypt(&self, plain_text: Vec<u8>) -> Vec<u8> {
+        warnings::warn(Warning::Ecb);
+        let blocks = group(pad(plain_text));
+        un_group(
+            blocks
+                .into_iter()
+                .map(|block| self.encrypt_block(block))
+                .collect(),
+        )
+    }
+
+    pub fn ecb_decrypt(&self, cipher_text: Vec<u8>) -> Result<Vec<u8>, MalformedCiphertext> {
+        warnings::warn(Warning::Ecb);
+        if cipher_text.is_empty() || !cipher_text.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(MalformedCiphertext);
+        }
+        let blocks = group(cipher_text);
+        let plain_text = un_group(
+            blocks
+                .into_iter()
+                .map(|block| self.decrypt_block(block))
+                .collect(),
+        );
+        Ok(un_pad(plain_text))
+    }
+
+    /// CBC with the IV chosen by the caller, which must be unpredictable.
+    pub fn cbc_encrypt_with_iv(&self, plain_text: Vec<u8>, iv: [u8; BLOCK_SIZE]) -> Vec<u8> {
+        warnings::warn(Warning::UnauthenticatedCbc);
+        let mut previous = iv;
+        let mut blocks = vec![iv];
+        for block in group(pad(plain_text)) {
+            previous = self.encrypt_block(util::xor(&block, &previous));
+            blocks.push(previous);
+        }
+        un_group(blocks)
+    }
+
+    pub fn cbc_decrypt(&self, cipher_text: Vec<u8>) -> Result<Vec<u8>, MalformedCiphertext> {
+        warnings::warn(Warning::UnauthenticatedCbc);
+        if cipher_text.len() < 2 * BLOCK_SIZE || !cipher_text.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(MalformedCiphertext);
+        }
+        let blocks: Vec<[u8; BLOCK_SIZE]> = group(cipher_text);
+        let plain_text = blocks
+            .windows(2)
+            .map(|pair| util::xor(&self.decrypt_block(pair[1]), &pair[0]))
+            .collect();
+        Ok(un_pad(un_group(plain_text)))
+    }
+
+    /// CTR with the nonce chosen by the caller, which must never repeat under one key.
+    pub fn ctr_encrypt_with_nonce(
+        &self,
+        mut plain_text: Vec<u8>,
+        nonce: [u8; NONCE_SIZE],
+    ) -> Vec<u8> {
+        self.apply_keystream(&nonce, &mut plain_text);
+        [&nonce[..], &plain_text].concat()
+    }
+
+    pub fn ctr_decrypt(&self, mut cipher_text: Vec<u8>) -> Result<Vec<u8>, MalformedCiphertext> {
+        if cipher_text.len() < NONCE_SIZE {
+            return Err(MalformedCiphertext);
+        }
+        let mut body = cipher_text.split_off(NONCE_SIZE);
+        self.apply_keystream(&cipher_text.try_into().unwrap(), &mut body);
+        Ok(body)
+    }
+
+    /// GCM, as [`gcm_encrypt`](crate::gcm::gcm_encrypt) does it.
+    pub fn gcm_encrypt(
+        &self,
+        plain_text: Vec<u8>,
+        nonce: [u8; GCM_NONCE_SIZE],
+        aad: &[u8],
+    ) -> Vec<u8> {
+        #[cfg(feature = "openssl")]
+        if let Cipher::OpenSsl(key) = &self.cipher {
+            return key.gcm_encrypt(&plain_text, nonce, aad);
+        }
+        let mut cipher_text = Vec::new();
+        GcmKey::with_context(self.clone()).seal_into(nonce, &plain_text, aad, &mut cipher_text);
+        cipher_text
+    }
+
+    pub fn gcm_decrypt(
+        &self,
+        cipher_text: Vec<u8>,
+        nonce: [u8; GCM_NONCE_SIZE],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, AuthenticationError> {
+        #[cfg(feature = "openssl")]
+        if let Cipher::OpenSsl(key) = &self.cipher {
+            return key.gcm_decrypt(&cipher_text, nonce, aad);
+        }
+        GcmKey::with_context(self.clone()).open(nonce, &cipher_text, aad)
+    }
+
+    fn apply_keystream(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let keystream = self.encrypt_block(util::ctr_counter_block(nonce, i as u64));
+            util::xor_in_place(chunk, &keystream[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(feature = "openssl")]
+mod openssl_backend {
+    use openssl::symm::{self, Cipher, Crypter};
+
+    use crate::{
+        gcm::{AuthenticationError, GCM_NONCE_SIZE, TAG_SIZE},
+        locked::LockedBox,
+        BLOCK_SIZE,
+    };
+
+    /// OpenSSL keeps no key schedule between calls here, so the key itself is kept, on
+    /// [locked](crate::locked) pages.
+    #[derive(Clone)]
+    pub(super) struct Key(LockedBox<[u8; BLOCK_SIZE]>);
+
+    impl Key {
+        pub(super) fn new(key: [u8; BLOCK_SIZE]) -> Self {
+            Key(LockedBox::new(key))
+        }
+
+        /// One block through AES-128 in ECB mode without padding, which is the raw block
+        /// cipher.
+        pub(super) fn block(&self, mode: symm::Mode, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+            let mut crypter = Crypter::new(Cipher::aes_128_ecb(), mode, &self.0[..], None)
+                .expect("OpenSSL refused AES-128");
+            crypter.pad(false);
+            // OpenSSL wants room for an extra block, even though none is produced.
+            let mut output = [0u8; 2 * BLOCK_SIZE];
+            let len = crypter
+                .update(&block, &mut output)
+                .expect("OpenSSL AES-128 failed");
+            assert_eq!(len, BLOCK_SIZE);
+            output[..BLOCK_SIZE].try_into().unwrap()
+        }
+
+        pub(super) fn gcm_encrypt(
+            &self,
+            plain_text: &[u8],
+            nonce: [u8; GCM_NONCE_SIZE],
+            aad: &[u8],
+        ) -> Vec<u8> {
+            let mut tag = [0u8; TAG_SIZE];
+            let mut cipher_text = symm::encrypt_aead(
+                Cipher::aes_128_gcm(),
+                &self.0[..],
+                Some(&nonce),
+                aad,
+                plain_text,
+                &mut tag,
+            )
+            .expect("OpenSSL AES-128-GCM failed");
+            cipher_text.extend_from_slice(&tag);
+            cipher_text
+        }
+
+        pub(super) fn gcm_decrypt(
+            &self,
+            cipher_text: &[u8],
+            nonce: [u8; GCM_NONCE_SIZE],
+            aad: &[u8],
+        ) -> Result<Vec<u8>, AuthenticationError> {
+            if cipher_text.len() < TAG_SIZE {
+                return Err(AuthenticationError);
+            }
+            let (body, tag) = cipher_text.split_at(cipher_text.len() - TAG_SIZE);
+            symm::decrypt_aead(
+                Cipher::aes_128_gcm(),
+                &self.0[..],
+                Some(&nonce),
+                aad,
+                body,
+                tag,
+            )
+            .map_err(|_| AuthenticationError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [6u8; BLOCK_SIZE];
+
+    #[test]
+    fn test_debug_hides_the_key() {
+        let context = Backend::RustCryptoAes.context(KEY);
+        let debug = format!("{:?} {:?}", context, GcmKey::new(KEY));
+        assert_eq!(
+            debug,
+            "CipherContext { backend: RustCryptoAes, .. } GcmKey(..)"
+        );
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let iv = [7u8; BLOCK_SIZE];
+        let nonce = [8u8; NONCE_SIZE];
+        let reference = Backend::RustCryptoAes.context(KEY);
+        for backend in Backend::available() {
+            let context = backend.context(KEY);
+            assert_eq!(context.backend(), backend);
+            for len in [0, 1, 15, 16, 17, 64] {
+                let plain_text: Vec<u8> = (0..len as u8).collect();
+                assert_eq!(
+                    context.ecb_encrypt(plain_text.clone()),
+                    crate::ecb_encrypt(plain_text.clone(), KEY),
+                    "{} ECB",
+                    backend.name()
+                );
+                assert_eq!(
+                    context.cbc_encrypt_with_iv(plain_text.clone(), iv),
+                    reference.cbc_encrypt_with_iv(plain_text.clone(), iv),
+                    "{} CBC",
+                    backend.name()
+                );
+                assert_eq!(
+                    context.ctr_encrypt_with_nonce(plain_text.clone(), nonce),
+                    crate::ctr_encrypt_with_nonce(plain_text.clone(), KEY, nonce),
+                    "{} CTR",
+                    backend.name()
+                );
+
+                // Each side decrypts what the other encrypted.
+                for mode in [Mode::Ecb, Mode::Cbc, Mode::Ctr] {
+                    let cipher_text = context.encrypt(mode, plain_text.clone());
+                    assert_eq!(mode.decrypt(cipher_text, KEY), plain_text);
+                    let cipher_text = mode.encrypt(plain_text.clone(), KEY);
+                    assert_eq!(context.decrypt(mode, cipher_text), Ok(plain_text.clone()));
+                }
+            }
+            assert_eq!(context.cbc_decrypt(vec![0u8; 16]), Err(MalformedCiphertext));
+        }
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let plain_text: Vec<u8> = (0..100).collect();
+        for backend in Backend::available() {
+            let context = backend.context(KEY);
+            let expected = context.cbc_encrypt_with_iv(plain_text.clone(), [1; BLOCK_SIZE]);
+            let mut nonces: Vec<Vec<u8>> = std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..8)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            let mut nonces = Vec::new();
+                            for _ in 0..50 {
+                                let cbc = context
+                                    .cbc_encrypt_with_iv(plain_text.clone(), [1; BLOCK_SIZE]);
+                                assert_eq!(cbc, expected, "{}", backend.name());
+                                let ctr = context.encrypt(Mode::Ctr, plain_text.clone());
+                                assert_eq!(
+                                    context.decrypt(Mode::Ctr, ctr.clone()),
+                                    Ok(plain_text.clone())
+                                );
+                                nonces.push(ctr[..NONCE_SIZE].to_vec());
+                            }
+                            nonces
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+            // No two calls, on the same thread or not, drew the same nonce.
+            nonces.sort();
+            nonces.dedup();
+            assert_eq!(nonces.len(), 8 * 50, "{}", backend.name());
+        }
+    }
+
+    #[test]
+    fn test_cbc_vector() {
+        // NIST SP 800-38A F.2.1, the first block. Padding adds a second one.
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let iv = core::array::from_fn(|i| i as u8);
+        let plain_text = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        for backend in Backend::available() {
+            let cipher_text = backend
+                .context(key)
+                .cbc_encrypt_with_iv(plain_text.to_vec(), iv);
+            assert_eq!(
+                cipher_text[16..32],
+                [
+                    0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12,
+                    0xe9, 0x19, 0x7d,
+                ],
+                "{}",
+                backend.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_gcm_backends_agree() {
+        let nonce = [9u8; GCM_NONCE_SIZE];
+        for backend in Backend::available() {
+            let context = backend.context(KEY);
+            for len in [0, 1, 16, 33] {
+                let plain_text: Vec<u8> = (0..len as u8).collect();
+                let cipher_text = context.gcm_encrypt(plain_text.clone(), nonce, b"aad");
+                assert_eq!(
+                    cipher_text,
+                    crate::gcm::gcm_encrypt(plain_text.clone(), KEY, nonce, b"aad"),
+                    "{}",
+                    backend.name()
+                );
+                assert_eq!(
+                    context.gcm_decrypt(cipher_text.clone(), nonce, b"aad"),
+                    Ok(plain_text)
+                );
+                assert_eq!(
+                    context.gcm_decrypt(cipher_text, nonce, b"other aad"),
+                    Err(AuthenticationError)
+                );
+            }
+            assert_eq!(
+                context.gcm_decrypt(vec![0u8; 15], nonce, b""),
+                Err(AuthenticationError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_matches_rustcrypto_mode_crates() {
+        // The same comparisons as the differential fuzz target in `fuzz/`, on a fixed set of
+        // pseudo-random inputs.
+        use aes_gcm::{
+            aead::{Aead, KeyInit, Payload},
+            Aes128Gcm,
+        };
+        use cbc::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit, StreamCipher};
+
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut bytes = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+        for round in 0..40 {
+            let key: [u8; BLOCK_SIZE] = bytes(16).try_into().unwrap();
+            let iv: [u8; BLOCK_SIZE] = bytes(16).try_into().unwrap();
+            let nonce: [u8; GCM_NONCE_SIZE] = bytes(12).try_into().unwrap();
+            let aad = bytes(round % 20);
+            let message = bytes(round * 3);
+
+            for backend in Backend::available() {
+                let context = backend.context(key);
+                let theirs = cbc::Encryptor::<Aes128>::new(&key.into(), &iv.into())
+                    .encrypt_padded_vec_mut::<Pkcs7>(&message);
+                assert_eq!(
+                    context.cbc_encrypt_with_iv(message.clone(), iv)[BLOCK_SIZE..],
+                    theirs,
+                    "{} CBC",
+                    backend.name()
+                );
+
+                let payload = Payload {
+                    msg: &message,
+                    aad: &aad,
+                };
+                assert_eq!(
+                    context.gcm_encrypt(message.clone(), nonce, &aad),
+                    Aes128Gcm::new(&key.into())
+                        .encrypt(&nonce.into(), payload)
+                        .unwrap(),
+                    "{} GCM",
+                    backend.name()
+                );
+            }
+
+            // The `ctr` crate has no flavor with the crate root's layout, a little-endian
+            // counter after the nonce, so this checks the big-endian layouts of `CtrParams`
+            // instead, from a random initial counter.
+            let initial = (u32::from_be_bytes(iv[12..].try_into().unwrap()) >> 1) as u128;
+            for nonce_size in [8, 12] {
+                let params = crate::ctr::CtrParams::new()
+                    .with_big_endian_counter()
+                    .with_nonce_size(nonce_size)
+                    .with_initial_counter(initial);
+                let ctr_nonce = &iv[..nonce_size];
+                let mut ours = message.clone();
+                params.apply_keystream(key, ctr_nonce, &mut ours);
+
+                let counter_block = params.counter_block(ctr_nonce, initial).into();
+                let mut theirs = message.clone();
+                if nonce_size == 8 {
+                    ctr::Ctr64BE::<Aes128>::new(&key.into(), &counter_block)
+                        .apply_keystream(&mut theirs);
+                } else {
+                    ctr::Ctr32BE::<Aes128>::new(&key.into(), &counter_block)
+                        .apply_keystream(&mut theirs);
+                }
+                assert_eq!(ours, theirs, "CTR with a {}-byte nonce", nonce_size);
+            }
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! Encrypted backups of whole directory trees, with a manifest to check them against.
+//!
+//! Encrypting every file on its own protects each file, but not the backup as a whole. Someone
+//! with access to the backup could delete a file, roll one back to an older backup, or swap two
+//! files around, and every file would still decrypt without complaint.
+//!
+//! [`encrypt_dir`] therefore also writes a manifest that lists every file: its original path,
+//! size, chunk count, the SHA-256 of its contents, and the ID of its encrypted stream. The
+//! manifest is itself encrypted and authenticated (it contains the plaintext paths), and
+//! carries a version number so that successive backups can be told apart, and an old backup
+//! can't be passed off as the latest one (see [`BackupManifest::load_since`]). [`verify`]
+//! checks the backup directory against it, and [`restore`] brings back all files or a
+//! selection.
+//!
+//! The files themselves are stored flat in the backup directory, under random file IDs. Only
+//! the manifest maps them back to paths, so the backup doesn't reveal the directory structure,
+//! and paths of any length can be backed up.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    chunked::{StreamDecryptor, StreamEncryptor, StreamHeader, DEFAULT_CHUNK_SIZE, STREAM_ID_SIZE},
+    files::{write_atomically, Durability},
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+/// The name of the manifest inside a backup directory.
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+const MANIFEST_KEY_LABEL: &[u8] = b"aes-modes backup manifest";
+const FILE_ID_SIZE: usize = 16;
+
+/// Everything the manifest records about one file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The path relative to the backed-up directory, with `/` as the separator.
+    pub path: String,
+    /// The file's name inside the backup directory, a random ID.
+    pub stored_name: String,
+    pub size: u64,
+    pub chunk_count: u64,
+    pub stream_id: [u8; STREAM_ID_SIZE],
+    pub sha256: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub version: u64,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A problem [`verify`] found in a backup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyFailure {
+    /// The file is listed in the manifest but missing from the backup.
+    Missing { path: String },
+    /// The file is there but doesn't decrypt to what the manifest says: it was modified,
+    /// swapped with another file, or replaced with an older version.
+    Mismatch { path: String },
+}
+
+fn manifest_key(master_secret: &[u8]) -> [u8; BLOCK_SIZE] {
+    let mut key = [0u8; BLOCK_SIZE];
+    Hkdf::<Sha256>::new(None, master_secret)
+        .expand(MANIFEST_KEY_LABEL, &mut key)
+        .expect("16 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl BackupManifest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.version.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            for field in [&entry.path, &entry.stored_name] {
+                bytes.extend_from_slice(&(field.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(field.as_bytes());
+            }
+            bytes.extend_from_slice(&entry.size.to_be_bytes());
+            bytes.extend_from_slice(&entry.chunk_count.to_be_bytes());
+            bytes.extend_from_slice(&entry.stream_id);
+            bytes.extend_from_slice(&entry.sha256);
+        }
+        bytes
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Option<Self> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if bytes.len() < n {
+                return None;
+            }
+            let (taken, rest) = bytes.split_at(n);
+            *bytes = rest;
+            Some(taken)
+        }
+        fn take_string(bytes: &mut &[u8]) -> Option<String> {
+            let len = u16::from_be_bytes(take(bytes, 2)?.try_into().unwrap());
+            String::from_utf8(take(bytes, len as usize)?.to_vec()).ok()
+        }
+        let take_u64 =
+            |bytes: &mut &[u8]| Some(u64::from_be_bytes(take(bytes, 8)?.try_into().unwrap()));
+
+        let version = take_u64(&mut bytes)?;
+        let count = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(ManifestEntry {
+                path: take_string(&mut bytes)?,
+                stored_name: take_string(&mut bytes)?,
+                size: take_u64(&mut bytes)?,
+                chunk_count: take_u64(&mut bytes)?,
+                stream_id: take(&mut bytes, STREAM_ID_SIZE)?.try_into().unwrap(),
+                sha256: take(&mut bytes, 32)?.try_into().unwrap(),
+            });
+        }
+        bytes
+            .is_empty()
+            .then_some(BackupManifest { version, entries })
+    }
+
+    /// Encrypts the manifest as `nonce | ciphertext | tag`.
+    pub fn seal(&self, master_secret: &[u8]) -> Vec<u8> {
+        let nonce = utils::create_rand_gcm_nonce();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(gcm_encrypt(
+            self.to_bytes(),
+            manifest_key(master_secret),
+            nonce,
+            MANIFEST_KEY_LABEL,
+        ));
+        sealed
+    }
+
+    pub fn open(master_secret: &[u8], sealed: &[u8]) -> io::Result<Self> {
+        if sealed.len() < GCM_NONCE_SIZE {
+            return Err(invalid_data("truncated backup manifest"));
+        }
+        let (nonce, cipher_text) = sealed.split_at(GCM_NONCE_SIZE);
+        let bytes = gcm_decrypt(
+            cipher_text.to_vec(),
+            manifest_key(master_secret),
+            nonce.try_into().unwrap(),
+            MANIFEST_KEY_LABEL,
+        )
+        .map_err(|_| invalid_data("backup manifest failed authentication"))?;
+        Self::from_bytes(&bytes).ok_or_else(|| invalid_data("malformed backup manifest"))
+    }
+
+    /// Reads and authenticates the manifest of a backup directory.
+    pub fn load(master_secret: &[u8], backup: &Path) -> io::Result<Self> {
+        Self::open(master_secret, &fs::read(backup.join(MANIFEST_FILE))?)
+    }
+
+    /// Like [`load`](Self::load), but fails if the manifest is older than `last_seen`, the
+    /// version of the newest backup the caller knows about. An authentic manifest can't be
+    /// forged, but an old one can be put back in place of the current one.
+    pub fn load_since(master_secret: &[u8], backup: &Path, last_seen: u64) -> io::Result<Self> {
+        let manifest = Self::load(master_secret, backup)?;
+        if manifest.version < last_seen {
+            return Err(invalid_data("backup manifest was rolled back"));
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest already in `backup`, if there is one.
+    fn load_previous(master_secret: &[u8], backup: &Path) -> io::Result<Option<Self>> {
+        match Self::load(master_secret, backup) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// All files below `directory`, as `/`-separated relative paths, in a stable order.
+fn list_files(root: &Path, directory: &Path, files: &mut Vec<String>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            list_files(root, &path, files)?;
+        } else {
+            let relative = path.strip_prefix(root).unwrap();
+            let components: Vec<_> = relative
+                .iter()
+                .map(|component| component.to_string_lossy())
+                .collect();
+            files.push(components.join("/"));
+        }
+    }
+    Ok(())
+}
+
+/// Reads everything from `reader` into `sink`, returning the SHA-256 and length of the data.
+fn copy_hashing(reader: &mut impl Read, sink: &mut impl io::Write) -> io::Result<([u8; 32], u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok((hasher.finalize().into(), len));
+        }
+        hasher.update(&buffer[..n]);
+        sink.write_all(&buffer[..n])?;
+        len += n as u64;
+    }
+}
+
+/// Encrypts every file below `source` into `backup`, and writes a manifest with the given
+/// version number. Returns the manifest.
+///
+/// If `backup` already holds a backup, `version` must be newer than its version, and the
+/// files of the old backup are removed once the new manifest is in place.
+pub fn encrypt_dir(
+    master_secret: &[u8],
+    source: &Path,
+    backup: &Path,
+    version: u64,
+) -> io::Result<BackupManifest> {
+    let previous = BackupManifest::load_previous(master_secret, backup)?;
+    if previous
+        .as_ref()
+        .is_some_and(|previous| version <= previous.version)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "backup version is not newer than the existing backup",
+        ));
+    }
+    let mut paths = Vec::new();
+    list_files(source, source, &mut paths)?;
+    fs::create_dir_all(backup)?;
+
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut file_id = [0u8; FILE_ID_SIZE];
+        utils::fill_random(&mut file_id);
+        let stored_name = utils::base64url_encode(&file_id);
+        let header = StreamHeader::new(DEFAULT_CHUNK_SIZE);
+        let mut input = File::open(source.join(&path))?;
+
+        let mut summary = None;
+        write_atomically(&backup.join(&stored_name), Durability::SyncData, |output| {
+            let mut encryptor = StreamEncryptor::with_header(master_secret, header, output)?;
+            summary = Some(copy_hashing(&mut input, &mut encryptor)?);
+            encryptor.finish()?;
+            Ok(())
+        })?;
+
+        let (sha256, size) = summary.unwrap();
+        entries.push(ManifestEntry {
+            path,
+            stored_name,
+            size,
+            chunk_count: size / DEFAULT_CHUNK_SIZE as u64 + 1,
+            stream_id: header.stream_id,
+            sha256,
+        });
+    }
+
+    let manifest = BackupManifest { version, entries };
+    let sealed = manifest.seal(master_secret);
+    write_atomically(&backup.join(MANIFEST_FILE), Durability::Full, |output| {
+        io::Write::write_all(output, &sealed)
+    })?;
+    for entry in previous.iter().flat_map(|previous| &previous.entries) {
+        match fs::remove_file(backup.join(&entry.stored_name)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+    Ok(manifest)
+}
+
+/// Decrypts one stored file into `sink`, failing unless it matches its manifest entry.
+fn check_entry(
+    master_secret: &[u8],
+    backup: &Path,
+    entry: &ManifestEntry,
+    sink: &mut impl io::Write,
+) -> io::Result<()> {
+    let file = File::open(backup.join(&entry.stored_name))?;
+    let mut decryptor = StreamDecryptor::new(master_secret, file)?;
+    let header = *decryptor.header();
+    let (sha256, size) = copy_hashing(&mut decryptor, sink)?;
+
+    if header.stream_id != entry.stream_id
+        || sha256 != entry.sha256
+        || size != entry.size
+        || decryptor.chunks_read() != entry.chunk_count
+    {
+        return Err(invalid_data("file does not match the backup manifest"));
+    }
+    Ok(())
+}
+
+/// Checks every file in the manifest, returning the problems found. An empty list means the
+/// backup is complete and intact.
+pub fn verify(master_secret: &[u8], backup: &Path) -> io::Result<Vec<VerifyFailure>> {
+    let manifest = BackupManifest::load(master_secret, backup)?;
+    let mut failures = Vec::new();
+    for entry in &manifest.entries {
+        let path = entry.path.clone();
+        if !backup.join(&entry.stored_name).is_file() {
+            failures.push(VerifyFailure::Missing { path });
+        } else if check_entry(master_secret, backup, entry, &mut io::sink()).is_err() {
+            failures.push(VerifyFailure::Mismatch { path });
+        }
+    }
+    Ok(failures)
+}
+
+/// Restores the files whose paths `select` accepts into `target`, recreating directories as
+/// needed. Returns the restored paths. Each file is checked against the manifest before it is
+/// moved into place, so a damaged file is never restored.
+pub fn restore(
+    master_secret: &[u8],
+    backup: &Path,
+    target: &Path,
+    select: impl Fn(&str) -> bool,
+) -> io::Result<Vec<String>> {
+    let manifest = BackupManifest::load(master_secret, backup)?;
+    let mut restored = Vec::new();
+    for entry in manifest.entries.iter().filter(|entry| select(&entry.path)) {
+        // Paths come from the authenticated manifest, but reject anything that would escape
+        // the target anyway.
+        let relative: PathBuf = entry.path.split('/').collect();
+        if entry
+            .path
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            return Err(invalid_data("unsafe path in backup manifest"));
+        }
+
+        let destination = target.join(relative);
+        fs::create_dir_all(destination.parent().unwrap())?;
+        write_atomically(&destination, Durability::SyncData, |output| {
+            check_entry(master_secret, backup, entry, output)
+        })?;
+        restored.push(entry.path.clone());
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    fn scratch_directory() -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "aes-modes-backup-{:016x}",
+            u64::from_be_bytes(utils::create_rand_nonce())
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// A source tree with a few files, and a backup of it.
+    fn backed_up_tree() -> (PathBuf, BackupManifest) {
+        let root = scratch_directory();
+        let source = root.join("source");
+        fs::create_dir_all(source.join("docs/old")).unwrap();
+        fs::write(source.join("a.txt"), b"alpha").unwrap();
+        fs::write(source.join("docs/b.txt"), vec![7u8; 100_000]).unwrap();
+        fs::write(source.join("docs/old/c.txt"), b"").unwrap();
+
+        let manifest = encrypt_dir(SECRET, &source, &root.join("backup"), 3).unwrap();
+        (root, manifest)
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let (root, manifest) = backed_up_tree();
+        let backup = root.join("backup");
+        assert_eq!(BackupManifest::load(SECRET, &backup).unwrap(), manifest);
+        assert_eq!(manifest.version, 3);
+        let paths: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|entry| &entry.path[..])
+            .collect();
+        assert_eq!(paths, ["a.txt", "docs/b.txt", "docs/old/c.txt"]);
+        assert_eq!(manifest.entries[1].chunk_count, 2);
+
+        // Nothing in the backup directory gives away a name.
+        for entry in fs::read_dir(&backup).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            assert!(!name.contains("txt") && !name.contains("docs"));
+        }
+        assert_eq!(verify(SECRET, &backup).unwrap(), []);
+
+        let target = root.join("restored");
+        let restored = restore(SECRET, &backup, &target, |path| path.starts_with("docs/")).unwrap();
+        assert_eq!(restored, ["docs/b.txt", "docs/old/c.txt"]);
+        assert_eq!(
+            fs::read(target.join("docs/b.txt")).unwrap(),
+            vec![7u8; 100_000]
+        );
+        assert!(!target.join("a.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_detects_missing_and_swapped_files() {
+        let (root, manifest) = backed_up_tree();
+        let backup = root.join("backup");
+        let stored = |i: usize| backup.join(&manifest.entries[i].stored_name);
+
+        // Swap the first and last file, and delete the middle one.
+        let first = fs::read(stored(0)).unwrap();
+        fs::copy(stored(2), stored(0)).unwrap();
+        fs::write(stored(2), first).unwrap();
+        fs::remove_file(stored(1)).unwrap();
+
+        assert_eq!(
+            verify(SECRET, &backup).unwrap(),
+            [
+                VerifyFailure::Mismatch {
+                    path: "a.txt".into()
+                },
+                VerifyFailure::Missing {
+                    path: "docs/b.txt".into()
+                },
+                VerifyFailure::Mismatch {
+                    path: "docs/old/c.txt".into()
+                },
+            ]
+        );
+        assert!(restore(SECRET, &backup, &root.join("restored"), |_| true).is_err());
+
+        let mut sealed = fs::read(backup.join(MANIFEST_FILE)).unwrap();
+        sealed[GCM_NONCE_SIZE] ^= 1;
+        assert!(BackupManifest::open(SECRET, &sealed).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_long_paths_and_rollback() {
+        let root = scratch_directory();
+        let source = root.join("source");
+        let long = ["a".repeat(200), "b".repeat(200)].join("/");
+        fs::create_dir_all(source.join(&long)).unwrap();
+        fs::write(
+            source.join(&long).join("c".repeat(200)),
+            vec![1u8; DEFAULT_CHUNK_SIZE as usize],
+        )
+        .unwrap();
+
+        let backup = root.join("backup");
+        let first = encrypt_dir(SECRET, &source, &backup, 1).unwrap();
+        assert_eq!(first.entries[0].path.len(), 602);
+        assert_eq!(verify(SECRET, &backup).unwrap(), []);
+        let old_manifest = fs::read(backup.join(MANIFEST_FILE)).unwrap();
+
+        // A new backup must be newer, and replaces the old one's files.
+        assert!(encrypt_dir(SECRET, &source, &backup, 1).is_err());
+        encrypt_dir(SECRET, &source, &backup, 2).unwrap();
+        assert_eq!(fs::read_dir(&backup).unwrap().count(), 2);
+        assert!(BackupManifest::load_since(SECRET, &backup, 2).is_ok());
+
+        // Putting the old manifest back is caught by a reader that has seen version 2.
+        fs::write(backup.join(MANIFEST_FILE), old_manifest).unwrap();
+        let error = BackupManifest::load_since(SECRET, &backup, 2).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Encrypting many small messages at once.
+//!
+//! Encrypting a record of a few dozen bytes with [`gcm_encrypt`](crate::gcm::gcm_encrypt)
+//! spends most of its time on setup rather than on the record: expanding the AES key,
+//! deriving the GHASH key, and allocating a fresh output vector. [`encrypt_batch`] does the
+//! setup once for the whole batch and writes every ciphertext into one shared buffer.
+//!
+//! Each ciphertext is `nonce | ciphertext | tag`, the same layout
+//! [`CountingGcm`](crate::invocations::CountingGcm) produces, so [`decrypt`] opens either.
+//!
+//! Every message gets its own random 96-bit nonce, as [`gcm_encrypt`](crate::gcm::gcm_encrypt)
+//! callers are told to use. NIST SP 800-38D allows 2^32 messages under one key with random
+//! nonces, counting every batch the key encrypted, so a key that encrypts batches all day
+//! still needs [rotating](crate::rotation) or [counting](crate::invocations::CountingGcm).
+//! Numbering the messages under a random per-batch prefix would look safer but isn't: a
+//! 64-bit prefix collides after around 2^32 _batches_, and then reuses every nonce of the
+//! batch at once.
+
+use std::{ops::Range, thread};
+
+use crate::{
+    gcm::{AuthenticationError, GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+/// The most messages one batch can hold, which is all SP 800-38D allows under one key with
+/// random nonces.
+pub const MAX_BATCH_SIZE: usize = u32::MAX as usize;
+
+/// The ciphertexts of a batch, stored back to back in one allocation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CiphertextBatch {
+    arena: Vec<u8>,
+    ranges: Vec<Range<usize>>,
+}
+
+impl CiphertextBatch {
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The ciphertext of the `index`th message.
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        self.ranges
+            .get(index)
+            .map(|range| &self.arena[range.clone()])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.ranges.iter().map(|range| &self.arena[range.clone()])
+    }
+
+    /// Every ciphertext in one buffer, in order, for callers that store them together anyway.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.arena
+    }
+}
+
+/// Encrypts the messages into `output`, each under a random nonce.
+fn encrypt_range(
+    key: &GcmKey,
+    messages: &[&[u8]],
+    output: &mut Vec<u8>,
+    ranges: &mut Vec<Range<usize>>,
+) {
+    for message in messages {
+        let nonce = utils::create_rand_gcm_nonce();
+        let start = output.len();
+        output.extend_from_slice(&nonce);
+        key.seal_into(nonce, message, &[], output);
+        ranges.push(start..output.len());
+    }
+}
+
+fn output_size(messages: &[&[u8]]) -> usize {
+    messages
+        .iter()
+        .map(|message| GCM_NONCE_SIZE + message.len() + TAG_SIZE)
+        .sum()
+}
+
+/// Encrypts every message with AES-GCM under `key`.
+///
+/// # Panics
+///
+/// If there are more than [`MAX_BATCH_SIZE`] messages.
+pub fn encrypt_batch(key: [u8; BLOCK_SIZE], messages: &[&[u8]]) -> CiphertextBatch {
+    assert!(
+        messages.len() <= MAX_BATCH_SIZE,
+        "too many messages in one batch"
+    );
+    let key = GcmKey::new(key);
+    let mut arena = Vec::with_capacity(output_size(messages));
+    let mut ranges = Vec::with_capacity(messages.len());
+    encrypt_range(&key, messages, &mut arena, &mut ranges);
+    CiphertextBatch { arena, ranges }
+}
+
+/// Like [`encrypt_batch`], but spreads the messages over all CPUs. Only worth it for large
+/// batches: each thread gets its own buffer, which are joined at the end.
+pub fn encrypt_batch_parallel(key: [u8; BLOCK_SIZE], messages: &[&[u8]]) -> CiphertextBatch {
+    assert!(
+        messages.len() <= MAX_BATCH_SIZE,
+        "too many messages in one batch"
+    );
+    let key = GcmKey::new(key);
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let per_thread = messages.len().div_ceil(threads).max(1);
+
+    let parts: Vec<(Vec<u8>, Vec<Range<usize>>)> = thread::scope(|scope| {
+        let handles: Vec<_> = messages
+            .chunks(per_thread)
+            .map(|part_messages| {
+                let key = &key;
+                scope.spawn(move || {
+                    let mut output = Vec::with_capacity(output_size(part_messages));
+                    let mut ranges = Vec::with_capacity(part_messages.len());
+                    encrypt_range(key, part_messages, &mut output, &mut ranges);
+                    (output, ranges)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    let mut arena = Vec::with_capacity(output_size(messages));
+    let mut ranges = Vec::with_capacity(messages.len());
+    for (output, part_ranges) in parts {
+        let offset = arena.len();
+        ranges.extend(
+            part_ranges
+                .into_iter()
+                .map(|range| range.start + offset..range.end + offset),
+        );
+        arena.extend_from_slice(&output);
+    }
+    CiphertextBatch { arena, ranges }
+}
+
+/// Decrypts one `nonce | ciphertext | tag` message.
+pub fn decrypt(key: [u8; BLOCK_SIZE], cipher_text: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+    decrypt_with(&GcmKey::new(key), cipher_text)
+}
+
+fn decrypt_with(key: &GcmKey, cipher_text: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+    if cipher_text.len() < GCM_NONCE_SIZE + TAG_SIZE {
+        return Err(AuthenticationError);
+    }
+    let (nonce, rest) = cipher_text.split_at(GCM_NONCE_SIZE);
+    key.open(nonce.try_into().unwrap(), rest, &[])
+}
+
+/// Decrypts many messages under one key, setting the key up only once. Each message
+/// succeeds or fails on its own.
+pub fn decrypt_batch<'a>(
+    key: [u8; BLOCK_SIZE],
+    cipher_texts: impl IntoIterator<Item = &'a [u8]>,
+) -> Vec<Result<Vec<u8>, AuthenticationError>> {
+    let key = GcmKey::new(key);
+    cipher_texts
+        .into_iter()
+        .map(|cipher_text| decrypt_with(&key, cipher_text))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::gcm::gcm_decrypt;
+
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [3u8; BLOCK_SIZE];
+
+    fn messages() -> Vec<Vec<u8>> {
+        (0..100).map(|i| vec![i as u8; i % 40]).collect()
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let messages = messages();
+        let messages: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+        let batch = encrypt_batch(KEY, &messages);
+        assert_eq!(batch.len(), messages.len());
+        assert_eq!(batch.as_bytes().len(), output_size(&messages));
+
+        let decrypted = decrypt_batch(KEY, batch.iter());
+        for (message, result) in messages.iter().zip(decrypted) {
+            assert_eq!(&result.unwrap(), message);
+        }
+
+        // Each ciphertext is ordinary GCM under its own random nonce.
+        let nonces: HashSet<&[u8]> = batch.iter().map(|c| &c[..GCM_NONCE_SIZE]).collect();
+        assert_eq!(nonces.len(), messages.len());
+        let third = batch.get(2).unwrap();
+        let nonce = third[..GCM_NONCE_SIZE].try_into().unwrap();
+        assert_eq!(
+            gcm_decrypt(third[GCM_NONCE_SIZE..].to_vec(), KEY, nonce, &[]).unwrap(),
+            messages[2]
+        );
+    }
+
+    #[test]
+    fn test_parallel_batch() {
+        let messages = messages();
+        let messages: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+        let batch = encrypt_batch_parallel(KEY, &messages);
+        for (i, cipher_text) in batch.iter().enumerate() {
+            assert_eq!(decrypt(KEY, cipher_text).unwrap(), messages[i]);
+        }
+        assert!(encrypt_batch_parallel(KEY, &[]).is_empty());
+
+        let mut tampered = batch.get(5).unwrap().to_vec();
+        tampered[GCM_NONCE_SIZE] ^= 1;
+        assert_eq!(decrypt(KEY, &tampered), Err(AuthenticationError));
+    }
+}
@@ -0,0 +1,453 @@
+//! Mounts an [`EncryptedDirectory`](aes_modes::encrypted_dir::EncryptedDirectory) as a
+//! plaintext view, gocryptfs-style.
+//!
+//! ```text
+//! aes-fuse [--init] <encrypted directory> <mount point>
+//! ```
+//!
+//! The master secret is read from `AES_FUSE_SECRET`. `--init` starts a new encrypted
+//! directory first, which must be empty or not exist yet. The mount stays in the foreground
+//! until it is unmounted with `fusermount3 -u <mount point>`.
+//!
+//! Every request opens the files it touches afresh and flushes what it wrote before it
+//! replies, so nothing is lost if the process is killed, at the cost of speed. Symbolic and
+//! hard links, special files and extended attributes aren't supported.
+
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime},
+};
+
+use aes_modes::{
+    encrypted_dir::{EncryptedDirectory, Entry, EntryKind},
+    file_handle::SECTOR_SIZE,
+};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+
+const USAGE: &str = "usage: aes-fuse [--init] <encrypted directory> <mount point>";
+/// How long the kernel may cache names and attributes.
+const TTL: Duration = Duration::from_secs(1);
+
+fn errno(error: &io::Error) -> i32 {
+    error.raw_os_error().unwrap_or(match error.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::AlreadyExists => libc::EEXIST,
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        io::ErrorKind::InvalidFilename => libc::ENAMETOOLONG,
+        io::ErrorKind::DirectoryNotEmpty => libc::ENOTEMPTY,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        _ => libc::EIO,
+    })
+}
+
+/// The plaintext view, with the inode numbers handed out to the kernel so far.
+struct Mount {
+    directory: EncryptedDirectory,
+    paths: HashMap<u64, PathBuf>,
+    inodes: HashMap<PathBuf, u64>,
+    next_inode: u64,
+}
+
+impl Mount {
+    fn new(directory: EncryptedDirectory) -> Self {
+        Mount {
+            directory,
+            paths: HashMap::from([(FUSE_ROOT_ID, PathBuf::new())]),
+            inodes: HashMap::from([(PathBuf::new(), FUSE_ROOT_ID)]),
+            next_inode: FUSE_ROOT_ID + 1,
+        }
+    }
+
+    fn path(&self, inode: u64) -> Result<PathBuf, i32> {
+        self.paths.get(&inode).cloned().ok_or(libc::ENOENT)
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<PathBuf, i32> {
+        Ok(self.path(parent)?.join(name))
+    }
+
+    fn inode(&mut self, path: &Path) -> u64 {
+        if let Some(&inode) = self.inodes.get(path) {
+            return inode;
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.paths.insert(inode, path.to_path_buf());
+        self.inodes.insert(path.to_path_buf(), inode);
+        inode
+    }
+
+    fn forget_path(&mut self, path: &Path) {
+        if let Some(inode) = self.inodes.remove(path) {
+            self.paths.remove(&inode);
+        }
+    }
+
+    /// Moves the inode numbers of `from` and everything below it to `to`.
+    fn move_paths(&mut self, from: &Path, to: &Path) {
+        self.forget_path(to);
+        let moved: Vec<(u64, PathBuf)> = self
+            .paths
+            .iter()
+            .filter_map(|(&inode, path)| {
+                let rest = path.strip_prefix(from).ok()?;
+                Some((inode, to.join(rest)))
+            })
+            .collect();
+        for (inode, path) in moved {
+            self.inodes.remove(&self.paths[&inode]);
+            self.inodes.insert(path.clone(), inode);
+            self.paths.insert(inode, path);
+        }
+    }
+
+    fn attr(&self, inode: u64, entry: &Entry, request: &Request<'_>) -> FileAttr {
+        let (kind, nlink) = match entry.kind {
+            EntryKind::File => (FileType::RegularFile, 1),
+            EntryKind::Directory => (FileType::Directory, 2),
+        };
+        FileAttr {
+            ino: inode,
+            size: entry.len,
+            blocks: entry.len.div_ceil(512),
+            atime: entry.modified,
+            mtime: entry.modified,
+            ctime: entry.modified,
+            crtime: entry.modified,
+            kind,
+            perm: (entry.permissions.mode() & 0o7777) as u16,
+            nlink,
+            uid: request.uid(),
+            gid: request.gid(),
+            rdev: 0,
+            blksize: SECTOR_SIZE as u32,
+            flags: 0,
+        }
+    }
+
+    /// Looks up the entry at `path`, handing out an inode number for it.
+    fn lookup_path(&mut self, path: &Path, request: &Request<'_>) -> Result<FileAttr, i32> {
+        let entry = self.directory.metadata(path).map_err(|e| errno(&e))?;
+        let inode = self.inode(path);
+        Ok(self.attr(inode, &entry, request))
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let mut file = self.directory.open_file(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(size.into()).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = self.directory.open_file(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        file.flush()
+    }
+}
+
+impl Filesystem for Mount {
+    fn lookup(&mut self, request: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .child(parent, name)
+            .and_then(|path| self.lookup_path(&path, request))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn getattr(&mut self, request: &Request<'_>, inode: u64, _: Option<u64>, reply: ReplyAttr) {
+        match self
+            .path(inode)
+            .and_then(|path| self.lookup_path(&path, request))
+        {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        request: &Request<'_>,
+        inode: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let result = self.path(inode).and_then(|path| {
+            if let Some(size) = size {
+                self.directory.set_len(&path, size).map_err(|e| errno(&e))?;
+            }
+            if let Some(mode) = mode {
+                self.directory
+                    .set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+                    .map_err(|e| errno(&e))?;
+            }
+            self.lookup_path(&path, request)
+        });
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        request: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.directory.create_dir(&path).map_err(|e| errno(&e))?;
+            self.directory
+                .set_permissions(&path, fs::Permissions::from_mode(mode & !umask & 0o7777))
+                .map_err(|e| errno(&e))?;
+            self.lookup_path(&path, request)
+        });
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn unlink(&mut self, _: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.directory.remove_file(&path).map_err(|e| errno(&e))?;
+            self.forget_path(&path);
+            Ok(())
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn rmdir(&mut self, _: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.directory.remove_dir(&path).map_err(|e| errno(&e))?;
+            self.forget_path(&path);
+            Ok(())
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // RENAME_NOREPLACE and RENAME_EXCHANGE would need an atomicity the backing rename
+        // doesn't offer.
+        if flags != 0 {
+            return reply.error(libc::EINVAL);
+        }
+        let result = self.child(parent, name).and_then(|from| {
+            let to = self.child(new_parent, new_name)?;
+            self.directory.rename(&from, &to).map_err(|e| errno(&e))?;
+            self.move_paths(&from, &to);
+            Ok(())
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn open(&mut self, _: &Request<'_>, inode: u64, flags: i32, reply: ReplyOpen) {
+        let result = self.path(inode).and_then(|path| {
+            if flags & libc::O_TRUNC != 0 {
+                self.directory.set_len(&path, 0).map_err(|e| errno(&e))?;
+            }
+            self.directory.metadata(&path).map_err(|e| errno(&e))
+        });
+        match result {
+            Ok(_) => reply.opened(0, 0),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn create(
+        &mut self,
+        request: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.directory
+                .create_file(&path)
+                .and_then(|mut file| file.flush())
+                .map_err(|e| errno(&e))?;
+            self.directory
+                .set_permissions(&path, fs::Permissions::from_mode(mode & !umask & 0o7777))
+                .map_err(|e| errno(&e))?;
+            self.lookup_path(&path, request)
+        });
+        match result {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let result = self.path(inode).and_then(|path| {
+            self.read_at(&path, offset.try_into().map_err(|_| libc::EINVAL)?, size)
+                .map_err(|e| errno(&e))
+        });
+        match result {
+            Ok(data) => reply.data(&data),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let result = self.path(inode).and_then(|path| {
+            self.write_at(&path, offset.try_into().map_err(|_| libc::EINVAL)?, data)
+                .map_err(|e| errno(&e))
+        });
+        match result {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn flush(&mut self, _: &Request<'_>, _: u64, _: u64, _: u64, reply: ReplyEmpty) {
+        // Every write was flushed before it was answered.
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _: &Request<'_>, _: u64, _: u64, _: bool, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        request: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let listed = self
+            .path(inode)
+            .and_then(|path| Ok((self.directory.list(&path).map_err(|e| errno(&e))?, path)));
+        let (entries, path) = match listed {
+            Ok(listed) => listed,
+            Err(error) => return reply.error(error),
+        };
+        let parent = path
+            .parent()
+            .map_or(FUSE_ROOT_ID, |parent| self.inode(parent));
+        let mut rows = vec![
+            (inode, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        for entry in entries {
+            let child = self.inode(&path.join(&entry.name));
+            let kind = self.attr(child, &entry, request).kind;
+            rows.push((child, kind, entry.name));
+        }
+        for (index, (child, kind, name)) in rows.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(child, index as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn run(init: bool, encrypted: &str, mount_point: &str) -> io::Result<()> {
+    let secret = env::var("AES_FUSE_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "AES_FUSE_SECRET must be set")
+        })?;
+    let directory = if init {
+        EncryptedDirectory::create(encrypted, secret.as_bytes())?
+    } else {
+        EncryptedDirectory::open(encrypted, secret.as_bytes())?
+    };
+    fuser::mount2(
+        Mount::new(directory),
+        mount_point,
+        &[
+            MountOption::FSName("aes-modes".to_string()),
+            MountOption::DefaultPermissions,
+        ],
+    )
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (init, paths) = match args.split_first() {
+        Some((flag, rest)) if flag == "--init" => (true, rest),
+        _ => (false, &args[..]),
+    };
+    let [encrypted, mount_point] = paths else {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    if let Err(error) = run(init, encrypted, mount_point) {
+        eprintln!("aes-fuse: {}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,63 @@
+//! Runs a [`KeyServer`](aes_modes::key_server::KeyServer) on a Unix socket.
+//!
+//! ```text
+//! aes-keyd <socket> <keyring>
+//! ```
+//!
+//! The keyring is sealed under a key-encryption key given in hex or base64url in
+//! `AES_KEYD_KEK`, and clients authenticate with the token in `AES_KEYD_TOKEN`. A missing
+//! keyring file starts a new one with a single key. The socket is only accessible to the user
+//! running the server.
+
+use std::{env, fs, io, os::unix::net::UnixListener, process, sync::Arc};
+
+use aes_modes::{
+    key_server::KeyServer,
+    keys::{parse_key, KeyManager, KeyPolicy},
+};
+
+fn run(socket: &str, keyring: &str) -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+    let kek = env::var("AES_KEYD_KEK")
+        .ok()
+        .and_then(|kek| parse_key(&kek))
+        .ok_or_else(|| invalid("AES_KEYD_KEK must be 32 hex digits or 22 base64url characters"))?;
+    let token = env::var("AES_KEYD_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| invalid("AES_KEYD_TOKEN must be set"))?;
+
+    let manager = match fs::read(keyring) {
+        Ok(sealed) => KeyManager::open(kek, &sealed)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let mut manager = KeyManager::new();
+            manager.generate(KeyPolicy::default());
+            manager
+        }
+        Err(error) => return Err(error),
+    };
+
+    // The socket is created with the umask's permissions, so it is narrowed before binding:
+    // changing the permissions afterwards would leave a moment in which anyone could connect.
+    // SAFETY: umask only swaps the process's file creation mask, and nothing else runs yet.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    // SAFETY: as above.
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    Arc::new(KeyServer::new(manager, token.as_bytes()).with_keyring_file(keyring, kek))
+        .serve(listener)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <socket> <keyring>", args[0]);
+        process::exit(2);
+    }
+    if let Err(error) = run(&args[1], &args[2]) {
+        eprintln!("aes-keyd: {}", error);
+        process::exit(1);
+    }
+}
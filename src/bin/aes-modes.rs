@@ -0,0 +1,436 @@
+//! Command-line tools for teaching and auditing the modes.
+//!
+//! ```text
+//! aes-modes encrypt [--armor] <in> <out>
+//! aes-modes decrypt [--armor] <in> <out>
+//! aes-modes tamper --flip-bit <N> <file.enc>
+//! aes-modes scan-nonces <path>...
+//! aes-modes gen-corpus --out <dir>
+//! aes-modes inspect <file>
+//! aes-modes migrate --from-key <file> --to-key <file> --to-mode gcm [--key-id <N>]
+//!                   [--legacy-mode ecb|cbc] [--dry-run] <path>...
+//! ```
+//!
+//! `encrypt` encrypts `in` into a [chunked stream](aes_modes::chunked) in `out`, with the key in
+//! `AES_MODES_KEY` as its master secret, and `decrypt` reads one back. Either path may be `-`
+//! for standard input or output. With `--armor`, the stream is
+//! [base64url](aes_modes::transcode) in lines of 76 characters. Both run in constant memory.
+//! `decrypt` writes a file `out` [atomically](aes_modes::files::write_atomically), so a stream
+//! that fails authentication leaves no plaintext file behind; to standard output it writes each
+//! chunk as soon as it is authenticated.
+//!
+//! `tamper` opens the [envelope](aes_modes::envelope) in `file.enc` with the key in hex or
+//! base64url in `AES_MODES_KEY`, then seals its plaintext under ECB, CBC, CTR and GCM, flips
+//! bit N of each ciphertext, and shows what decrypting each one gives.
+//!
+//! `scan-nonces` reads every file under the paths as an envelope, and reports
+//! [nonces used twice](aes_modes::analysis::scan_nonces) under one key by file name. It exits
+//! with status 1 if it finds any.
+//!
+//! `gen-corpus` writes the [interop corpus](aes_modes::corpus) to `dir`: one `<case>.bin` file
+//! per ciphertext, and `manifest.json` with the inputs of each.
+//!
+//! `inspect` prints a [hex dump](aes_modes::util::hexdump) of `file`, one block per line, with
+//! every block that repeats an earlier one marked, in red on a terminal. In an ECB ciphertext
+//! those are the places where the plaintext repeats.
+//!
+//! `migrate` [re-encrypts](aes_modes::migrate) every file under the paths, in place, from the
+//! key in one key file to the key in another, each in hex or base64url. Envelopes of any mode
+//! and bare blobs of the legacy mode (CBC by default) become GCM envelopes naming key ID N (1
+//! by default). With `--dry-run` it only decrypts the files and reports what it would do. It
+//! exits with status 1 if any file failed, leaving those files unchanged.
+
+use std::{
+    env, fs,
+    io::{self, BufReader, BufWriter, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use aes_modes::{
+    analysis::scan_nonces,
+    chunked::{StreamDecryptor, StreamEncryptor},
+    corpus,
+    envelope::Envelope,
+    files::{write_atomically, Durability},
+    keys::parse_key,
+    migrate::{LegacyMode, Migrator, Source},
+    tamper::{tamper_all_modes, Damage},
+    transcode::{DecodingReader, Encoding, EncodingWriter},
+    util, BLOCK_SIZE,
+};
+
+const USAGE: &str = "usage: aes-modes encrypt [--armor] <in> <out>
+       aes-modes decrypt [--armor] <in> <out>
+       aes-modes tamper --flip-bit <N> <file.enc>
+       aes-modes scan-nonces <path>...
+       aes-modes gen-corpus --out <dir>
+       aes-modes inspect <file>
+       aes-modes migrate --from-key <file> --to-key <file> --to-mode gcm [--key-id <N>]
+                         [--legacy-mode ecb|cbc] [--dry-run] <path>...";
+
+fn describe(damage: &[Damage]) -> String {
+    damage
+        .iter()
+        .map(|damage| match damage {
+            Damage::GarbledBlock(block) => format!("block {} garbled", block),
+            Damage::FlippedBit { byte, bit } => format!("bit {} of byte {} flipped", bit, byte),
+            Damage::Length(len) => format!("padding garbled, {} bytes out", len),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The line length of armored output, as in MIME.
+const ARMOR_LINE_LENGTH: usize = 76;
+
+fn env_key() -> io::Result<[u8; BLOCK_SIZE]> {
+    env::var("AES_MODES_KEY")
+        .ok()
+        .and_then(|key| parse_key(&key))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AES_MODES_KEY must be 32 hex digits or 22 base64url characters",
+            )
+        })
+}
+
+fn open_input(path: &str) -> io::Result<Box<dyn Read>> {
+    Ok(if path == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(fs::File::open(path)?))
+    })
+}
+
+fn create_output(path: &str) -> io::Result<Box<dyn Write>> {
+    Ok(if path == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(BufWriter::new(fs::File::create(path)?))
+    })
+}
+
+fn encrypt(armor: bool, input: &str, output: &str) -> io::Result<()> {
+    let key = env_key()?;
+    let mut input = open_input(input)?;
+    let output = create_output(output)?;
+    if armor {
+        let output =
+            EncodingWriter::new(Encoding::Base64Url, output).with_line_length(ARMOR_LINE_LENGTH);
+        let mut encryptor = StreamEncryptor::new(&key, output)?;
+        io::copy(&mut input, &mut encryptor)?;
+        encryptor.finish()?.finish()?.flush()
+    } else {
+        let mut encryptor = StreamEncryptor::new(&key, output)?;
+        io::copy(&mut input, &mut encryptor)?;
+        encryptor.finish()?.flush()
+    }
+}
+
+fn decrypt(armor: bool, input: &str, output: &str) -> io::Result<()> {
+    let key = env_key()?;
+    let mut input = open_input(input)?;
+    if armor {
+        input = Box::new(DecodingReader::new(Encoding::Base64Url, input));
+    }
+    let mut decryptor = StreamDecryptor::new(&key, input)?;
+    if output == "-" {
+        let mut output = io::stdout().lock();
+        io::copy(&mut decryptor, &mut output)?;
+        return output.flush();
+    }
+    write_atomically(Path::new(output), Durability::Full, |file| {
+        let mut output = BufWriter::new(file);
+        io::copy(&mut decryptor, &mut output)?;
+        output.flush()
+    })
+}
+
+fn tamper(bit: usize, path: &str) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let key = env_key()?;
+    let envelope = Envelope::from_bytes(&fs::read(path)?)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let plain_text = envelope
+        .open(key)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    if bit >= 8 * plain_text.len() {
+        return Err(invalid(format!(
+            "{} holds {} bytes, so the bit must be below {}",
+            path,
+            plain_text.len(),
+            8 * plain_text.len()
+        )));
+    }
+
+    println!(
+        "{} is a {:?} envelope of {} bytes.",
+        path,
+        envelope.mode,
+        plain_text.len()
+    );
+    println!(
+        "Flipping bit {} of each mode's ciphertext: bit {} of byte {}, in block {}.\n",
+        bit,
+        bit % 8,
+        bit / 8,
+        bit / 8 / BLOCK_SIZE
+    );
+    for tampered in tamper_all_modes(key, &plain_text, bit) {
+        let own = if tampered.mode == envelope.mode {
+            " (this file's mode)"
+        } else {
+            ""
+        };
+        let name = format!("{:?}", tampered.mode).to_uppercase();
+        match &tampered.result {
+            Ok(decrypted) => {
+                println!(
+                    "{:<4} {}{}",
+                    name,
+                    describe(&tampered.damage(&plain_text)),
+                    own
+                );
+                println!("     {:?}", String::from_utf8_lossy(decrypted));
+            }
+            Err(error) => println!("{:<4} rejected: {}{}", name, error, own),
+        }
+    }
+    println!(
+        "\nECB and CBC garble a block, and CBC also flips the same bit of the next block, \
+         exactly where an attacker wants it. CTR flips exactly that bit. Only GCM notices."
+    );
+    Ok(())
+}
+
+/// Adds `path` to `files`, or every file under it if it is a directory, in name order.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        collect_files(&entry, files)?;
+    }
+    Ok(())
+}
+
+fn scan(paths: &[&str]) -> io::Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        collect_files(Path::new(path), &mut files)?;
+    }
+    // An unreadable file is reported as not being an envelope.
+    let report = scan_nonces(files.iter().map(|file| fs::read(file).unwrap_or_default()));
+    for &index in &report.malformed {
+        println!("skipped {}: not an envelope", files[index].display());
+    }
+    println!(
+        "scanned {} files, {} without a nonce to check",
+        report.scanned, report.skipped
+    );
+    for reuse in &report.reuses {
+        let nonce: String = reuse
+            .nonce
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let severity = if reuse.is_catastrophic() {
+            "CATASTROPHIC, the plaintexts are exposed"
+        } else {
+            "repeated IV"
+        };
+        println!(
+            "\n{}, {:?} nonce {}: {}",
+            reuse.key, reuse.mode, nonce, severity
+        );
+        for &index in &reuse.indices {
+            println!("  {}", files[index].display());
+        }
+    }
+    if report.is_clean() {
+        println!("no nonce reuse");
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "found {} reused nonces",
+            report.reuses.len()
+        )))
+    }
+}
+
+fn gen_corpus(dir: &Path) -> io::Result<()> {
+    let cases = corpus::generate();
+    fs::create_dir_all(dir)?;
+    for case in &cases {
+        fs::write(dir.join(format!("{}.bin", case.name)), &case.cipher_text)?;
+    }
+    fs::write(dir.join("manifest.json"), corpus::manifest_json(&cases))?;
+    println!("wrote {} cases to {}", cases.len(), dir.display());
+    Ok(())
+}
+
+/// The options of `migrate`.
+struct MigrateArgs<'a> {
+    from_key: &'a str,
+    to_key: &'a str,
+    key_id: u32,
+    legacy_mode: LegacyMode,
+    dry_run: bool,
+    paths: Vec<&'a str>,
+}
+
+impl<'a> MigrateArgs<'a> {
+    /// `None` if the options don't parse, or a required one is missing.
+    fn parse(mut args: &[&'a str]) -> Option<Self> {
+        let (mut from_key, mut to_key, mut to_mode) = (None, None, None);
+        let mut parsed = MigrateArgs {
+            from_key: "",
+            to_key: "",
+            key_id: 1,
+            legacy_mode: LegacyMode::Cbc,
+            dry_run: false,
+            paths: Vec::new(),
+        };
+        loop {
+            match *args {
+                ["--dry-run", ref rest @ ..] => {
+                    parsed.dry_run = true;
+                    args = rest;
+                }
+                [option, value, ref rest @ ..] if option.starts_with("--") => {
+                    match option {
+                        "--from-key" => from_key = Some(value),
+                        "--to-key" => to_key = Some(value),
+                        "--to-mode" => to_mode = Some(value),
+                        "--key-id" => parsed.key_id = value.parse().ok()?,
+                        "--legacy-mode" => {
+                            parsed.legacy_mode = match value {
+                                "ecb" => LegacyMode::Ecb,
+                                "cbc" => LegacyMode::Cbc,
+                                _ => return None,
+                            }
+                        }
+                        _ => return None,
+                    }
+                    args = rest;
+                }
+                _ => {
+                    parsed.paths = args.to_vec();
+                    break;
+                }
+            }
+        }
+        // GCM is the only authenticated envelope mode, so the only one worth migrating to.
+        if to_mode != Some("gcm") || parsed.paths.is_empty() {
+            return None;
+        }
+        parsed.from_key = from_key?;
+        parsed.to_key = to_key?;
+        Some(parsed)
+    }
+}
+
+fn inspect(path: &str) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let dump = util::hexdump(&data, io::stdout().is_terminal());
+    io::stdout().write_all(dump.as_bytes())
+}
+
+fn read_key_file(path: &str) -> io::Result<[u8; BLOCK_SIZE]> {
+    parse_key(&fs::read_to_string(path)?).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} must hold 32 hex digits or 22 base64url characters",
+                path
+            ),
+        )
+    })
+}
+
+fn migrate(args: &MigrateArgs) -> io::Result<()> {
+    let migrator = Migrator::new(
+        read_key_file(args.from_key)?,
+        args.key_id,
+        read_key_file(args.to_key)?,
+    )
+    .with_legacy_mode(args.legacy_mode);
+    let mut files = Vec::new();
+    for path in &args.paths {
+        collect_files(Path::new(path), &mut files)?;
+    }
+
+    let report = migrator.migrate_files(&files, args.dry_run);
+    let verb = if args.dry_run {
+        "would migrate"
+    } else {
+        "migrated"
+    };
+    for (file, from) in &report.migrated {
+        let from = match from {
+            Source::Legacy(mode) => format!("bare {:?} blob", mode),
+            Source::Envelope(mode) => format!("{:?} envelope", mode),
+        };
+        println!("{} {}: {}", verb, file.display(), from);
+    }
+    for file in &report.current {
+        println!("skipped {}: already under the new key", file.display());
+    }
+    for (file, error) in &report.failed {
+        println!("failed {}: {}", file.display(), error);
+    }
+    println!(
+        "\n{} {}, {} already current, {} failed",
+        verb,
+        report.migrated.len(),
+        report.current.len(),
+        report.failed.len()
+    );
+    if report.is_complete() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{} files not migrated",
+            report.failed.len()
+        )))
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["encrypt", "--armor", input, output] => encrypt(true, input, output),
+        ["encrypt", input, output] => encrypt(false, input, output),
+        ["decrypt", "--armor", input, output] => decrypt(true, input, output),
+        ["decrypt", input, output] => decrypt(false, input, output),
+        ["tamper", "--flip-bit", bit, path] => match bit.parse() {
+            Ok(bit) => tamper(bit, path),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the bit must be a number",
+            )),
+        },
+        ["scan-nonces", ref paths @ ..] if !paths.is_empty() => scan(paths),
+        ["gen-corpus", "--out", dir] => gen_corpus(Path::new(dir)),
+        ["inspect", path] => inspect(path),
+        ["migrate", ref rest @ ..] => migrate(&MigrateArgs::parse(rest).unwrap_or_else(|| usage())),
+        _ => usage(),
+    };
+    if let Err(error) = result {
+        eprintln!("aes-modes: {}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,66 @@
+//! Runs an [`OracleServer`](aes_modes::oracle::OracleServer) on localhost, for attack
+//! exercises.
+//!
+//! ```text
+//! aes-oracle [--port <N>] [--seed <text>] [--delay-ms <N>] [--timing-only]
+//! ```
+//!
+//! The server listens on 127.0.0.1 only, port 8077 by default. The same seed gives the same
+//! keys and secret on every machine. `--delay-ms` makes valid padding N milliseconds slower to
+//! answer, and `--timing-only` leaves that delay as the only way to tell.
+
+use std::{env, net::TcpListener, process, sync::Arc, time::Duration};
+
+use aes_modes::oracle::OracleServer;
+
+const USAGE: &str =
+    "usage: aes-oracle [--port <N>] [--seed <text>] [--delay-ms <N>] [--timing-only]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (mut port, mut seed, mut delay, mut timing_only) = (8077u16, "aes-modes", 0u64, false);
+    while !args.is_empty() {
+        let taken = match args[..] {
+            ["--timing-only", ..] => {
+                timing_only = true;
+                1
+            }
+            ["--port", value, ..] => {
+                port = value.parse().unwrap_or_else(|_| usage());
+                2
+            }
+            ["--seed", value, ..] => {
+                seed = value;
+                2
+            }
+            ["--delay-ms", value, ..] => {
+                delay = value.parse().unwrap_or_else(|_| usage());
+                2
+            }
+            _ => usage(),
+        };
+        args.drain(..taken);
+    }
+
+    let mut server = OracleServer::new(seed.as_bytes()).with_delay(Duration::from_millis(delay));
+    if timing_only {
+        server = server.with_timing_only();
+    }
+    let result = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        println!(
+            "oracles for seed {:?} listening on http://127.0.0.1:{}",
+            seed, port
+        );
+        Arc::new(server).serve(listener)
+    });
+    if let Err(error) = result {
+        eprintln!("aes-oracle: {}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,316 @@
+//! ECB, CBC and CTR encryption as iterators, one block at a time.
+//!
+//! [`Mode::encrypt`] needs the whole plaintext up front and returns the whole ciphertext. An
+//! [`EncryptIter`] instead wraps an iterator of plaintext bytes and produces the ciphertext
+//! lazily, a block per call to `next`, so it runs in constant memory and combines with other
+//! iterator adapters. [`ReadEncryptIter`] does the same for an [`io::Read`].
+//!
+//! The first item is the IV (CBC) or nonce (CTR), and every later one is a ciphertext block,
+//! so the items concatenated are exactly what [`Mode::encrypt`] would have returned and
+//! [`Mode::decrypt`] reads them back. ECB and CBC pad the last block; the last CTR block is
+//! as long as what is left of the plaintext.
+//!
+//! With the `debug-internals` feature, `debug_state` shows the chaining value or counter block
+//! the next block will use, to compare step by step with another implementation when their
+//! outputs differ. It never shows the key.
+
+use std::io::{self, Read};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{
+    pad, util, utils,
+    warnings::{self, Warning},
+    Mode, BLOCK_SIZE, NONCE_SIZE,
+};
+
+/// The state a mode carries into its next block, from `debug_state`.
+#[cfg(feature = "debug-internals")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugState {
+    /// ECB carries nothing from block to block.
+    Ecb,
+    /// The value the next plaintext block is XORed with: the IV, then the last ciphertext
+    /// block.
+    Cbc { chaining_value: [u8; BLOCK_SIZE] },
+    /// The counter block the next keystream block is made from, and how many blocks came
+    /// before it.
+    Ctr {
+        counter_block: [u8; BLOCK_SIZE],
+        blocks: u64,
+    },
+}
+
+/// What the iterators have to remember between blocks.
+struct BlockState {
+    mode: Mode,
+    cipher: Aes128,
+    /// The previous ciphertext block for CBC, and the nonce in the first half for CTR.
+    chain: [u8; BLOCK_SIZE],
+    counter: u64,
+    header: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl BlockState {
+    fn new(mode: Mode, key: [u8; BLOCK_SIZE]) -> Self {
+        let mut chain = [0u8; BLOCK_SIZE];
+        let header = match mode {
+            Mode::Ecb => {
+                warnings::warn(Warning::Ecb);
+                None
+            }
+            Mode::Cbc => {
+                warnings::warn(Warning::UnauthenticatedCbc);
+                chain = utils::create_rand_init_vector();
+                Some(chain.to_vec())
+            }
+            Mode::Ctr => {
+                let nonce = utils::create_rand_nonce();
+                chain[..NONCE_SIZE].copy_from_slice(&nonce);
+                Some(nonce.to_vec())
+            }
+        };
+        BlockState {
+            mode,
+            cipher: Aes128::new(&GenericArray::from(key)),
+            chain,
+            counter: 0,
+            header,
+            finished: false,
+        }
+    }
+
+    #[cfg(feature = "debug-internals")]
+    fn debug_state(&self) -> DebugState {
+        match self.mode {
+            Mode::Ecb => DebugState::Ecb,
+            Mode::Cbc => DebugState::Cbc {
+                chaining_value: self.chain,
+            },
+            Mode::Ctr => DebugState::Ctr {
+                counter_block: util::ctr_counter_block(
+                    self.chain[..NONCE_SIZE].try_into().unwrap(),
+                    self.counter,
+                ),
+                blocks: self.counter,
+            },
+        }
+    }
+
+    fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+        let mut block = GenericArray::from(block);
+        self.cipher.encrypt_block(&mut block);
+        block.into()
+    }
+
+    /// Encrypts the next piece of plaintext, which is a whole block unless it is the last.
+    fn encrypt(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        if chunk.len() < BLOCK_SIZE {
+            self.finished = true;
+        }
+        if self.mode == Mode::Ctr {
+            return self.apply_keystream(chunk);
+        }
+
+        let block: [u8; BLOCK_SIZE] = if self.finished {
+            pad(chunk.to_vec()).try_into().unwrap()
+        } else {
+            chunk.try_into().unwrap()
+        };
+        let cipher_text = match self.mode {
+            Mode::Cbc => self.encrypt_block(util::xor(&block, &self.chain)),
+            _ => self.encrypt_block(block),
+        };
+        self.chain = cipher_text;
+        Some(cipher_text.to_vec())
+    }
+
+    fn apply_keystream(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        if chunk.is_empty() {
+            return None;
+        }
+        let nonce = self.chain[..NONCE_SIZE].try_into().unwrap();
+        let keystream = self.encrypt_block(util::ctr_counter_block(nonce, self.counter));
+        self.counter += 1;
+        let mut cipher_text = chunk.to_vec();
+        util::xor_in_place(&mut cipher_text, &keystream[..chunk.len()]);
+        Some(cipher_text)
+    }
+}
+
+/// Encrypts the bytes of an iterator lazily. See the [module documentation](self).
+pub struct EncryptIter<I> {
+    bytes: I,
+    state: BlockState,
+}
+
+impl<I: Iterator<Item = u8>> EncryptIter<I> {
+    /// Encrypts `bytes` with `mode` under `key`, with a random IV or nonce.
+    pub fn new(mode: Mode, key: [u8; BLOCK_SIZE], bytes: I) -> Self {
+        EncryptIter {
+            bytes,
+            state: BlockState::new(mode, key),
+        }
+    }
+
+    /// The state the next block will be encrypted with. See the
+    /// [module documentation](self).
+    #[cfg(feature = "debug-internals")]
+    pub fn debug_state(&self) -> DebugState {
+        self.state.debug_state()
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for EncryptIter<I> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if let Some(header) = self.state.header.take() {
+            return Some(header);
+        }
+        if self.state.finished {
+            return None;
+        }
+        let chunk: Vec<u8> = self.bytes.by_ref().take(BLOCK_SIZE).collect();
+        self.state.encrypt(&chunk)
+    }
+}
+
+/// Like [`EncryptIter`], but reading the plaintext from an [`io::Read`]. A read error is
+/// passed on, and ends the iteration.
+pub struct ReadEncryptIter<R> {
+    reader: R,
+    state: BlockState,
+}
+
+impl<R: Read> ReadEncryptIter<R> {
+    pub fn new(mode: Mode, key: [u8; BLOCK_SIZE], reader: R) -> Self {
+        ReadEncryptIter {
+            reader,
+            state: BlockState::new(mode, key),
+        }
+    }
+
+    /// The state the next block will be encrypted with. See the
+    /// [module documentation](self).
+    #[cfg(feature = "debug-internals")]
+    pub fn debug_state(&self) -> DebugState {
+        self.state.debug_state()
+    }
+
+    /// Reads a whole block, or less at the end of the input.
+    fn read_block(&mut self) -> io::Result<Vec<u8>> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut len = 0;
+        while len < BLOCK_SIZE {
+            match self.reader.read(&mut block[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(block[..len].to_vec())
+    }
+}
+
+impl<R: Read> Iterator for ReadEncryptIter<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(header) = self.state.header.take() {
+            return Some(Ok(header));
+        }
+        if self.state.finished {
+            return None;
+        }
+        match self.read_block() {
+            Ok(chunk) => self.state.encrypt(&chunk).map(Ok),
+            Err(error) => {
+                self.state.finished = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [5u8; BLOCK_SIZE];
+
+    #[test]
+    fn test_matches_the_modes() {
+        for mode in [Mode::Ecb, Mode::Cbc, Mode::Ctr] {
+            for len in [0, 1, 15, 16, 17, 48] {
+                let plain_text: Vec<u8> = (0..len as u8).collect();
+                let cipher_text: Vec<u8> = EncryptIter::new(mode, KEY, plain_text.iter().copied())
+                    .flatten()
+                    .collect();
+                assert_eq!(mode.decrypt(cipher_text.clone(), KEY), plain_text);
+                if mode == Mode::Ecb {
+                    assert_eq!(cipher_text, mode.encrypt(plain_text.clone(), KEY));
+                }
+
+                let from_reader: Vec<u8> = ReadEncryptIter::new(mode, KEY, plain_text.as_slice())
+                    .flat_map(Result::unwrap)
+                    .collect();
+                assert_eq!(mode.decrypt(from_reader, KEY), plain_text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_lazy() {
+        // An endless plaintext: only the blocks that are asked for get encrypted.
+        let mut blocks = EncryptIter::new(Mode::Ctr, KEY, std::iter::repeat(0u8));
+        let nonce = blocks.next().unwrap();
+        let first_blocks: Vec<Vec<u8>> = blocks.take(3).collect();
+        assert_eq!(first_blocks.len(), 3);
+        assert_eq!(
+            crate::ctr_decrypt([nonce, first_blocks.concat()].concat(), KEY),
+            [0u8; 3 * BLOCK_SIZE]
+        );
+    }
+
+    #[cfg(feature = "debug-internals")]
+    #[test]
+    fn test_debug_state() {
+        let mut cbc = EncryptIter::new(Mode::Cbc, KEY, [1u8; 32].into_iter());
+        let iv = cbc.next().unwrap();
+        assert_eq!(
+            cbc.debug_state(),
+            DebugState::Cbc {
+                chaining_value: iv.try_into().unwrap()
+            }
+        );
+        let first = cbc.next().unwrap();
+        assert_eq!(
+            cbc.debug_state(),
+            DebugState::Cbc {
+                chaining_value: first.try_into().unwrap()
+            }
+        );
+
+        let mut ctr = ReadEncryptIter::new(Mode::Ctr, KEY, [0u8; 40].as_slice());
+        let nonce: [u8; NONCE_SIZE] = ctr.next().unwrap().unwrap().try_into().unwrap();
+        ctr.next();
+        let DebugState::Ctr {
+            counter_block,
+            blocks,
+        } = ctr.debug_state()
+        else {
+            panic!("not a CTR state");
+        };
+        assert_eq!(blocks, 1);
+        assert_eq!(counter_block, util::ctr_counter_block(&nonce, 1));
+        // The keystream is the counter block encrypted, so the next output shows it directly.
+        let keystream = ctr.state.encrypt_block(counter_block);
+        assert_eq!(ctr.next().unwrap().unwrap(), keystream);
+    }
+}
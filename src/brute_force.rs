@@ -0,0 +1,240 @@
+//! Recovering a key by trying every candidate, when only a few of its bits are unknown.
+//!
+//! A 128-bit key can't be searched: 2^128 AES key schedules is beyond every computer there
+//! will ever be. A key with fewer unknown bits can, and the cost is easy to measure. A
+//! [`Challenge`] encrypts a message under a key and publishes all but its last
+//! `unknown_bits` bits; [`search`] tries every value of the rest on all CPUs until one
+//! encrypts the first plaintext block to the first ciphertext block, reporting its
+//! [`Progress`] as it goes.
+//!
+//! Each extra unknown bit doubles the work. Timing the search at 16, 20 and 24 bits, and
+//! extrapolating with [`SearchResult::keys_per_second`], shows what 56 bits (DES) or 128 bits
+//! would take, and why keys must come from a real random source: a key derived from a 6-digit
+//! PIN has 20 unknown bits, however long it is.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{ecb_encrypt, BLOCK_SIZE};
+
+/// How many candidates a thread tries between reports.
+const REPORT_EVERY: u64 = 1 << 14;
+
+/// A message encrypted with ECB under a key whose last `unknown_bits` bits are secret.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    /// The key with its unknown bits cleared.
+    pub known_key: [u8; BLOCK_SIZE],
+    pub unknown_bits: u32,
+    pub plain_text: Vec<u8>,
+    pub cipher_text: Vec<u8>,
+}
+
+impl Challenge {
+    /// Encrypts `message` under `key`, and publishes everything but the key's last
+    /// `unknown_bits` bits.
+    ///
+    /// # Panics
+    ///
+    /// If `unknown_bits` is over 64, which no search could finish anyway, or `message` is
+    /// shorter than a block.
+    pub fn new(key: [u8; BLOCK_SIZE], unknown_bits: u32, message: &[u8]) -> Self {
+        assert!(unknown_bits <= 64, "at most 64 bits can be unknown");
+        assert!(
+            message.len() >= BLOCK_SIZE,
+            "the message must have a whole block of known plaintext"
+        );
+        let mask = u128::MAX.checked_shr(128 - unknown_bits).unwrap_or(0);
+        Challenge {
+            known_key: (u128::from_be_bytes(key) & !mask).to_be_bytes(),
+            unknown_bits,
+            plain_text: message.to_vec(),
+            cipher_text: ecb_encrypt(message.to_vec(), key),
+        }
+    }
+
+    /// How many keys the search may have to try.
+    pub fn keyspace(&self) -> u64 {
+        // 2^64 doesn't fit, and the last key is never reached in practice anyway.
+        1u64.checked_shl(self.unknown_bits).unwrap_or(u64::MAX)
+    }
+
+    /// The key with `candidate` in its unknown bits.
+    fn key(&self, candidate: u64) -> [u8; BLOCK_SIZE] {
+        (u128::from_be_bytes(self.known_key) | u128::from(candidate)).to_be_bytes()
+    }
+}
+
+/// How far a [`search`] has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub tried: u64,
+    pub keyspace: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// The share of the keyspace tried so far, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        self.tried as f64 / self.keyspace as f64
+    }
+
+    /// How long the rest of the keyspace will take at the rate so far.
+    pub fn remaining(&self) -> Duration {
+        if self.tried == 0 {
+            return Duration::MAX;
+        }
+        let left = (self.keyspace - self.tried.min(self.keyspace)) as f64;
+        Duration::from_secs_f64(self.elapsed.as_secs_f64() * left / self.tried as f64)
+    }
+}
+
+/// What [`search`] found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchResult {
+    /// The key, or `None` if no candidate decrypts the challenge.
+    pub key: Option<[u8; BLOCK_SIZE]>,
+    pub tried: u64,
+    pub elapsed: Duration,
+}
+
+impl SearchResult {
+    pub fn keys_per_second(&self) -> f64 {
+        self.tried as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Searches `challenge`'s keyspace on every CPU, calling `on_progress` every few thousand
+/// keys from whichever thread got there.
+pub fn search(challenge: &Challenge, on_progress: impl Fn(&Progress) + Sync) -> SearchResult {
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    search_with_threads(challenge, threads, on_progress)
+}
+
+/// Like [`search`], on `threads` threads, to measure how the search scales with them.
+///
+/// # Panics
+///
+/// If `threads` is zero.
+pub fn search_with_threads(
+    challenge: &Challenge,
+    threads: usize,
+    on_progress: impl Fn(&Progress) + Sync,
+) -> SearchResult {
+    assert!(threads > 0, "the search needs at least one thread");
+    let start = Instant::now();
+    let keyspace = challenge.keyspace();
+    let target: [u8; BLOCK_SIZE] = challenge.cipher_text[..BLOCK_SIZE].try_into().unwrap();
+    let tried = AtomicU64::new(0);
+    let found = AtomicBool::new(false);
+    let per_thread = keyspace.div_ceil(threads as u64).max(1);
+
+    let key = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads as u64)
+            .map(|part| {
+                let (tried, found, on_progress) = (&tried, &found, &on_progress);
+                scope.spawn(move || {
+                    let first = part.saturating_mul(per_thread);
+                    let end = first.saturating_add(per_thread).min(keyspace);
+                    let mut block = GenericArray::default();
+                    let mut candidate = first;
+                    while candidate < end && !found.load(Ordering::Relaxed) {
+                        let batch_end = candidate.saturating_add(REPORT_EVERY).min(end);
+                        for guess in candidate..batch_end {
+                            let key = challenge.key(guess);
+                            block.copy_from_slice(&challenge.plain_text[..BLOCK_SIZE]);
+                            Aes128::new(&key.into()).encrypt_block(&mut block);
+                            if block[..] == target {
+                                found.store(true, Ordering::Relaxed);
+                                tried.fetch_add(guess - candidate + 1, Ordering::Relaxed);
+                                return Some(key);
+                            }
+                        }
+                        let total = tried.fetch_add(batch_end - candidate, Ordering::Relaxed)
+                            + batch_end
+                            - candidate;
+                        on_progress(&Progress {
+                            tried: total,
+                            keyspace,
+                            elapsed: start.elapsed(),
+                        });
+                        candidate = batch_end;
+                    }
+                    None
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().unwrap())
+            .next()
+    });
+    SearchResult {
+        key,
+        tried: tried.into_inner(),
+        elapsed: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const KEY: [u8; BLOCK_SIZE] = *b"sixteen byte key";
+    const MESSAGE: &[u8] = b"attack at dawn, bring snacks";
+
+    #[test]
+    fn test_recovers_the_key() {
+        let challenge = Challenge::new(KEY, 12, MESSAGE);
+        assert_eq!(challenge.known_key[..14], KEY[..14]);
+        assert_eq!(challenge.known_key[14], KEY[14] & 0xf0);
+        assert_eq!(challenge.known_key[15], 0);
+        assert_eq!(challenge.keyspace(), 4096);
+
+        let reports = Mutex::new(Vec::new());
+        let result = search_with_threads(&challenge, 3, |progress| {
+            reports.lock().unwrap().push(*progress)
+        });
+        assert_eq!(result.key, Some(KEY));
+        assert!(result.tried <= challenge.keyspace());
+        for progress in reports.into_inner().unwrap() {
+            assert!(progress.tried <= progress.keyspace);
+            assert!(progress.fraction() <= 1.0);
+        }
+
+        let full = Challenge::new(KEY, 0, MESSAGE);
+        assert_eq!(full.known_key, KEY);
+        assert_eq!(search(&full, |_| {}).key, Some(KEY));
+    }
+
+    #[test]
+    fn test_reports_exhausted_keyspace() {
+        let mut challenge = Challenge::new(KEY, 10, MESSAGE);
+        challenge.known_key[0] ^= 0x80;
+        let reports = AtomicU64::new(0);
+        let result = search_with_threads(&challenge, 2, |_| {
+            reports.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(result.key, None);
+        assert_eq!(result.tried, 1024);
+        assert_eq!(reports.into_inner(), 2);
+        assert_eq!(
+            Progress {
+                tried: 1,
+                keyspace: 4,
+                elapsed: Duration::from_secs(2)
+            }
+            .remaining(),
+            Duration::from_secs(6)
+        );
+    }
+}
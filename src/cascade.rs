@@ -0,0 +1,186 @@
+//! Encrypting twice, under two independent keys, in case one cipher or mode is ever broken.
+//!
+//! A [`Cascade`] runs the plaintext through an inner layer and then an outer one. As long as
+//! the two layers' keys are independent, an attacker has to break _both_ to read anything, so
+//! some standards ask for this as defence in depth. Two rules make it work:
+//!
+//! - The keys must be independent. [`Cascade::ctr_then_gcm`] derives them from one secret with
+//!   HKDF under different labels; callers building their own layers must do the equivalent.
+//!   Sharing a key between layers could let one layer's keystream cancel the other's.
+//! - Each layer chooses its own nonces, under its own key, so nonces from different layers
+//!   live in separate domains and can never collide with each other.
+//!
+//! The outer layer should be authenticated, so tampering is caught before the inner layer
+//! does anything. Both layers see the same associated data, which names the two layers, so a
+//! ciphertext made by one composition can't be opened by another.
+//!
+//! Layers are anything implementing [`CascadeLayer`], which leaves room for ciphers this crate
+//! doesn't have yet.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    ctr_decrypt, ctr_encrypt,
+    gcm::{gcm_decrypt, gcm_encrypt, AuthenticationError, GCM_NONCE_SIZE},
+    utils, BLOCK_SIZE, NONCE_SIZE,
+};
+
+const CASCADE_LABEL: &[u8] = b"aes-modes cascade";
+
+/// One cipher in a [`Cascade`].
+pub trait CascadeLayer {
+    /// A short name identifying the cipher and mode. It is authenticated, so keep it stable.
+    fn name(&self) -> &'static str;
+
+    fn seal(&self, plain_text: Vec<u8>, aad: &[u8]) -> Vec<u8>;
+
+    fn open(&self, cipher_text: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, AuthenticationError>;
+}
+
+/// AES-128-GCM with a random nonce, as `nonce | ciphertext | tag`.
+pub struct GcmLayer([u8; BLOCK_SIZE]);
+
+impl GcmLayer {
+    pub fn new(key: [u8; BLOCK_SIZE]) -> Self {
+        GcmLayer(key)
+    }
+}
+
+impl CascadeLayer for GcmLayer {
+    fn name(&self) -> &'static str {
+        "aes-128-gcm"
+    }
+
+    fn seal(&self, plain_text: Vec<u8>, aad: &[u8]) -> Vec<u8> {
+        let nonce = utils::create_rand_gcm_nonce();
+        let mut cipher_text = nonce.to_vec();
+        cipher_text.extend(gcm_encrypt(plain_text, self.0, nonce, aad));
+        cipher_text
+    }
+
+    fn open(&self, mut cipher_text: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+        if cipher_text.len() < GCM_NONCE_SIZE {
+            return Err(AuthenticationError);
+        }
+        let body = cipher_text.split_off(GCM_NONCE_SIZE);
+        gcm_decrypt(body, self.0, cipher_text.try_into().unwrap(), aad)
+    }
+}
+
+/// AES-128-CTR with a random nonce. It doesn't authenticate anything, so it only belongs on
+/// the inside of a cascade.
+pub struct CtrLayer([u8; BLOCK_SIZE]);
+
+impl CtrLayer {
+    pub fn new(key: [u8; BLOCK_SIZE]) -> Self {
+        CtrLayer(key)
+    }
+}
+
+impl CascadeLayer for CtrLayer {
+    fn name(&self) -> &'static str {
+        "aes-128-ctr"
+    }
+
+    fn seal(&self, plain_text: Vec<u8>, _aad: &[u8]) -> Vec<u8> {
+        ctr_encrypt(plain_text, self.0)
+    }
+
+    fn open(&self, cipher_text: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+        if cipher_text.len() < NONCE_SIZE {
+            return Err(AuthenticationError);
+        }
+        Ok(ctr_decrypt(cipher_text, self.0))
+    }
+}
+
+/// Two layers of encryption: `outer(inner(plaintext))`.
+pub struct Cascade<I, O> {
+    inner: I,
+    outer: O,
+}
+
+impl<I: CascadeLayer, O: CascadeLayer> Cascade<I, O> {
+    /// Composes two layers, which must use independent keys.
+    pub fn new(inner: I, outer: O) -> Self {
+        Cascade { inner, outer }
+    }
+
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = CASCADE_LABEL.to_vec();
+        for name in [self.inner.name(), self.outer.name()] {
+            aad.push(name.len() as u8);
+            aad.extend_from_slice(name.as_bytes());
+        }
+        aad
+    }
+
+    pub fn encrypt(&self, plain_text: Vec<u8>) -> Vec<u8> {
+        let aad = self.aad();
+        self.outer.seal(self.inner.seal(plain_text, &aad), &aad)
+    }
+
+    pub fn decrypt(&self, cipher_text: Vec<u8>) -> Result<Vec<u8>, AuthenticationError> {
+        let aad = self.aad();
+        self.inner.open(self.outer.open(cipher_text, &aad)?, &aad)
+    }
+}
+
+impl Cascade<CtrLayer, GcmLayer> {
+    /// AES-GCM over AES-CTR, with both keys derived from one secret.
+    pub fn ctr_then_gcm(master_secret: &[u8]) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, master_secret);
+        let mut inner = [0u8; BLOCK_SIZE];
+        let mut outer = [0u8; BLOCK_SIZE];
+        hkdf.expand(b"aes-modes cascade inner", &mut inner)
+            .expect("16 bytes is a valid output length");
+        hkdf.expand(b"aes-modes cascade outer", &mut outer)
+            .expect("16 bytes is a valid output length");
+        Cascade::new(CtrLayer::new(inner), GcmLayer::new(outer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    #[test]
+    fn test_cascade_round_trip() {
+        let cascade = Cascade::ctr_then_gcm(SECRET);
+        let cipher_text = cascade.encrypt(b"defence in depth".to_vec());
+        assert_eq!(
+            cipher_text.len(),
+            GCM_NONCE_SIZE + NONCE_SIZE + 16 + crate::gcm::TAG_SIZE
+        );
+        assert_eq!(
+            cascade.decrypt(cipher_text.clone()),
+            Ok(b"defence in depth".to_vec())
+        );
+
+        // The outer layer is exactly what GcmLayer produces, and peeling it off only reveals
+        // the inner ciphertext.
+        let inner = cascade.outer.open(cipher_text, &cascade.aad()).unwrap();
+        assert_ne!(&inner[NONCE_SIZE..], b"defence in depth");
+    }
+
+    #[test]
+    fn test_tampering_and_composition_are_detected() {
+        let cascade = Cascade::ctr_then_gcm(SECRET);
+        let mut cipher_text = cascade.encrypt(b"defence in depth".to_vec());
+        cipher_text[GCM_NONCE_SIZE] ^= 1;
+        assert_eq!(cascade.decrypt(cipher_text), Err(AuthenticationError));
+
+        // Two GCM layers with the same outer key, but a different composition.
+        let hkdf = Hkdf::<Sha256>::new(None, SECRET);
+        let mut inner = [0u8; BLOCK_SIZE];
+        let mut outer = [0u8; BLOCK_SIZE];
+        hkdf.expand(b"aes-modes cascade inner", &mut inner).unwrap();
+        hkdf.expand(b"aes-modes cascade outer", &mut outer).unwrap();
+        let other = Cascade::new(GcmLayer::new(inner), GcmLayer::new(outer));
+        let cipher_text = cascade.encrypt(b"defence in depth".to_vec());
+        assert_eq!(other.decrypt(cipher_text), Err(AuthenticationError));
+    }
+}
@@ -0,0 +1,279 @@
+//! Loading a key from components held by separate custodians, as payment HSMs do.
+//!
+//! Under split knowledge no single person ever sees a key. Two or more custodians are each
+//! given a random component, usually on paper as 32 hex digits with its KCV, and the key is
+//! the XOR of all of them. A [`Ceremony`] runs the loading:
+//!
+//! 1. each custodian in turn enters their component with
+//!    [`enter_component`](Ceremony::enter_component), and confirms that the
+//!    [KCV](crate::keys::check_value) it returns matches the one on their form;
+//! 2. once all have, [`check_value`](Ceremony::check_value) gives the combined key's KCV,
+//!    which the custodians compare with the one recorded when the key was made;
+//! 3. [`finish`](Ceremony::finish) checks the KCV they confirmed and hands over the key.
+//!
+//! The components are XORed into [locked](crate::locked) memory as they are decoded, and
+//! never stored: all that is kept of each is its KCV, to catch the same component entered
+//! twice, which would cancel out. The strings the components arrive in belong to the caller,
+//! who should wipe them as soon as they have been entered.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    keys::{check_value, KCV_SIZE},
+    locked::LockedBox,
+    BLOCK_SIZE,
+};
+
+/// Why a component or the final confirmation was rejected. None of them reveal anything about
+/// the component beyond its format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CeremonyError {
+    /// The component isn't 32 hex digits, optionally grouped with spaces or dashes.
+    InvalidComponent,
+    /// The component is all zeros, which would add nothing to the key.
+    ZeroComponent,
+    /// The component's KCV doesn't match the one the custodian expected: a typing mistake.
+    ComponentMismatch,
+    /// The same component was entered twice, which would cancel it out.
+    RepeatedComponent,
+    /// Every custodian has already entered their component.
+    AlreadyComplete,
+    /// Not every custodian has entered their component yet.
+    Incomplete { remaining: usize },
+    /// The combined key's KCV isn't the one the custodians confirmed.
+    KeyMismatch,
+}
+
+impl fmt::Display for CeremonyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CeremonyError::InvalidComponent => f.write_str("a component must be 32 hex digits"),
+            CeremonyError::ZeroComponent => f.write_str("the component is all zeros"),
+            CeremonyError::ComponentMismatch => {
+                f.write_str("the component doesn't match its check value")
+            }
+            CeremonyError::RepeatedComponent => f.write_str("the component was already entered"),
+            CeremonyError::AlreadyComplete => f.write_str("every component has been entered"),
+            CeremonyError::Incomplete { remaining } => {
+                write!(f, "{} components still to be entered", remaining)
+            }
+            CeremonyError::KeyMismatch => {
+                f.write_str("the combined key doesn't match the confirmed check value")
+            }
+        }
+    }
+}
+
+impl Error for CeremonyError {}
+
+/// A KCV as it is printed on key forms: uppercase hex.
+pub fn format_check_value(kcv: &[u8; KCV_SIZE]) -> String {
+    kcv.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Decodes a component straight into locked memory.
+fn decode_component(hex: &str) -> Result<LockedBox<[u8; BLOCK_SIZE]>, CeremonyError> {
+    let mut component = LockedBox::new([0u8; BLOCK_SIZE]);
+    let mut digits = 0;
+    for c in hex.chars().filter(|&c| !c.is_whitespace() && c != '-') {
+        let nibble = c.to_digit(16).ok_or(CeremonyError::InvalidComponent)? as u8;
+        if digits == 2 * BLOCK_SIZE {
+            return Err(CeremonyError::InvalidComponent);
+        }
+        component[digits / 2] |= nibble << (4 * (1 - digits % 2));
+        digits += 1;
+    }
+    if digits != 2 * BLOCK_SIZE {
+        return Err(CeremonyError::InvalidComponent);
+    }
+    Ok(component)
+}
+
+/// A key being loaded from its components. See the [module documentation](self).
+pub struct Ceremony {
+    custodians: usize,
+    key: LockedBox<[u8; BLOCK_SIZE]>,
+    /// The KCVs of the components entered so far.
+    entered: Vec<[u8; KCV_SIZE]>,
+}
+
+impl Ceremony {
+    /// A ceremony for a key split between `custodians` components.
+    ///
+    /// # Panics
+    ///
+    /// If `custodians` is less than two, which would not be split knowledge.
+    pub fn new(custodians: usize) -> Self {
+        assert!(
+            custodians >= 2,
+            "split knowledge needs at least two custodians"
+        );
+        Ceremony {
+            custodians,
+            key: LockedBox::new([0; BLOCK_SIZE]),
+            entered: Vec::with_capacity(custodians),
+        }
+    }
+
+    /// Adds a component, and returns its KCV for the custodian to compare with their form.
+    pub fn enter_component(&mut self, hex: &str) -> Result<[u8; KCV_SIZE], CeremonyError> {
+        self.enter(hex, None)
+    }
+
+    /// Like [`enter_component`](Self::enter_component), for a custodian who types in the KCV
+    /// from their form too. A component that doesn't match it is rejected and not added.
+    pub fn enter_component_with_check_value(
+        &mut self,
+        hex: &str,
+        expected: &[u8; KCV_SIZE],
+    ) -> Result<(), CeremonyError> {
+        self.enter(hex, Some(expected)).map(|_| ())
+    }
+
+    fn enter(
+        &mut self,
+        hex: &str,
+        expected: Option<&[u8; KCV_SIZE]>,
+    ) -> Result<[u8; KCV_SIZE], CeremonyError> {
+        if self.remaining() == 0 {
+            return Err(CeremonyError::AlreadyComplete);
+        }
+        let component = decode_component(hex)?;
+        if component.iter().all(|&byte| byte == 0) {
+            return Err(CeremonyError::ZeroComponent);
+        }
+        let kcv = check_value(&component);
+        if expected.is_some_and(|expected| *expected != kcv) {
+            return Err(CeremonyError::ComponentMismatch);
+        }
+        if self.entered.contains(&kcv) {
+            return Err(CeremonyError::RepeatedComponent);
+        }
+        for (byte, component_byte) in self.key.iter_mut().zip(component.iter()) {
+            *byte ^= component_byte;
+        }
+        self.entered.push(kcv);
+        Ok(kcv)
+    }
+
+    /// How many components are still to be entered.
+    pub fn remaining(&self) -> usize {
+        self.custodians - self.entered.len()
+    }
+
+    /// The combined key's KCV, to display once every component is in.
+    pub fn check_value(&self) -> Option<[u8; KCV_SIZE]> {
+        (self.remaining() == 0).then(|| check_value(&self.key))
+    }
+
+    /// Hands over the combined key, if every component is in and the custodians confirmed
+    /// `confirmed` as its KCV.
+    pub fn finish(
+        self,
+        confirmed: &[u8; KCV_SIZE],
+    ) -> Result<LockedBox<[u8; BLOCK_SIZE]>, CeremonyError> {
+        match self.check_value() {
+            None => Err(CeremonyError::Incomplete {
+                remaining: self.remaining(),
+            }),
+            Some(kcv) if kcv != *confirmed => Err(CeremonyError::KeyMismatch),
+            Some(_) => Ok(self.key),
+        }
+    }
+}
+
+impl fmt::Debug for Ceremony {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ceremony")
+            .field("custodians", &self.custodians)
+            .field("remaining", &self.remaining())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: &str = "0123 4567 89AB CDEF 0123 4567 89AB CDEF";
+    const SECOND: &str = "fedcba98-76543210-00112233-44556677";
+
+    fn key() -> [u8; BLOCK_SIZE] {
+        let mut key = [0; BLOCK_SIZE];
+        let first = decode_component(FIRST).unwrap();
+        let second = decode_component(SECOND).unwrap();
+        for i in 0..BLOCK_SIZE {
+            key[i] = first[i] ^ second[i];
+        }
+        key
+    }
+
+    #[test]
+    fn test_two_custodians() {
+        let mut ceremony = Ceremony::new(2);
+        let first_kcv = ceremony.enter_component(FIRST).unwrap();
+        assert_eq!(first_kcv, check_value(&decode_component(FIRST).unwrap()));
+        assert_eq!(ceremony.remaining(), 1);
+        assert_eq!(ceremony.check_value(), None);
+        assert_eq!(
+            ceremony.enter_component(&FIRST.to_lowercase()),
+            Err(CeremonyError::RepeatedComponent)
+        );
+
+        let expected = check_value(&key());
+        ceremony
+            .enter_component_with_check_value(
+                SECOND,
+                &check_value(&decode_component(SECOND).unwrap()),
+            )
+            .unwrap();
+        assert_eq!(ceremony.check_value(), Some(expected));
+        assert_eq!(format_check_value(&expected).len(), 2 * KCV_SIZE);
+        assert_eq!(*ceremony.finish(&expected).unwrap(), key());
+    }
+
+    #[test]
+    fn test_rejects_mistakes() {
+        let mut ceremony = Ceremony::new(3);
+        for (hex, error) in [
+            ("0123", CeremonyError::InvalidComponent),
+            (
+                "0123456789abcdef0123456789abcdefff",
+                CeremonyError::InvalidComponent,
+            ),
+            (
+                "0123456789abcdef0123456789abcdeg",
+                CeremonyError::InvalidComponent,
+            ),
+            (
+                "00000000000000000000000000000000",
+                CeremonyError::ZeroComponent,
+            ),
+        ] {
+            assert_eq!(ceremony.enter_component(hex), Err(error));
+        }
+        assert_eq!(
+            ceremony.enter_component_with_check_value(FIRST, &[0; KCV_SIZE]),
+            Err(CeremonyError::ComponentMismatch)
+        );
+        assert_eq!(ceremony.remaining(), 3);
+
+        ceremony.enter_component(FIRST).unwrap();
+        ceremony.enter_component(SECOND).unwrap();
+        assert_eq!(
+            Ceremony::new(2).finish(&[0; KCV_SIZE]).unwrap_err(),
+            CeremonyError::Incomplete { remaining: 2 }
+        );
+        ceremony
+            .enter_component("11111111111111111111111111111111")
+            .unwrap();
+        assert_eq!(
+            ceremony.enter_component("22222222222222222222222222222222"),
+            Err(CeremonyError::AlreadyComplete)
+        );
+        assert_eq!(
+            ceremony.finish(&[0; KCV_SIZE]).unwrap_err(),
+            CeremonyError::KeyMismatch
+        );
+    }
+}
@@ -0,0 +1,1337 @@
+//! A chunked format for encrypting streams and files too large to hold in memory.
+//!
+//! GCM needs the whole message before it can verify the tag, which rules it out for a 10 GB
+//! file. Instead, the data is split into fixed-size chunks that are each authenticated on their
+//! own, so a reader can release every chunk as soon as it has been verified.
+//!
+//! ```text
+//! header: magic "AMCS" | version | chunk size (u32) | parameters | stream ID (16 random bytes)
+//! chunks: ciphertext | tag, ciphertext | tag, ..., final ciphertext | tag
+//! ```
+//!
+//! The parameters byte holds the compression algorithm in its low three bits, in the next bit
+//! whether the plaintext is the extent map and data of a [sparse file](crate::files), how many
+//! bytes the tags are shortened by (zero for full 16-byte tags) in the next three, and in its
+//! top bit whether the stream ends with a digest of the plaintext. All but the sparse bit are
+//! chosen with [`StreamOptions`].
+//!
+//! Every chunk has its own key and nonce, derived with HKDF from the master secret, salted with
+//! the stream ID, and labelled with the chunk index. Nonces therefore can't collide, neither
+//! across chunks nor across streams, and chunks can be encrypted in parallel (see
+//! [`encrypt_parallel`]). Each chunk also authenticates the header, its index, and whether it
+//! is the final chunk, so chunks can't be reordered, moved between streams, or cut off.
+//!
+//! The final chunk is always shorter than the chunk size, possibly empty, which is how the
+//! reader recognizes it without a length field.
+//!
+//! Streams can optionally be [compressed](crate::compression) before they are encrypted.
+//!
+//! A stream can also carry the SHA-256 of its plaintext, computed in the same pass as the
+//! encryption and stored, encrypted, after the last (compressed) byte of data. The writer gets
+//! it back from [`StreamEncryptor::finish_with_digest`], for deduplication say, without reading
+//! the source twice. The reader checks it once it has decrypted everything, which catches a
+//! faulty writer or decompressor that chunk authentication can't, and hands it out from
+//! [`StreamDecryptor::plaintext_digest`] for checking the data end to end.
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem, thread,
+};
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compression::{Compression, Compressor, Decompressor, OracleRiskAcknowledged},
+    gcm::{gcm_encrypt, GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    limits::{self, Limits},
+    policy::Policy,
+    spill::{SpillBuffer, SpillReader},
+    utils,
+    warnings::{self, Warning},
+    BLOCK_SIZE,
+};
+
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Chunks are read into memory whole, so the header may not ask for anything bigger.
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The shortest tag a stream may use. Every chunk is a separate forgery attempt, so tags
+/// shorter than this are too easy to guess for data of any size (see NIST SP 800-38D, C).
+pub const MIN_TAG_LEN: u8 = 12;
+
+pub const STREAM_ID_SIZE: usize = 16;
+/// The length of the plaintext digest at the end of a stream that has one.
+pub const DIGEST_SIZE: usize = 32;
+pub const HEADER_SIZE: usize = 4 + 1 + 4 + 1 + STREAM_ID_SIZE;
+
+pub(crate) const MAGIC: &[u8; 4] = b"AMCS";
+/// The format version of the stream header.
+pub const VERSION: u8 = 1;
+const CHUNK_LABEL: &[u8] = b"aes-modes chunk";
+const MANIFEST_LABEL: &[u8] = b"aes-modes manifest";
+const PART_LABEL: &[u8] = b"aes-modes part";
+const DIGEST_FLAG: u8 = 0x80;
+const SPARSE_FLAG: u8 = 0x08;
+
+/// Why a chunked stream could not be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamError {
+    /// The header is missing, has the wrong magic or version, an invalid chunk size, or a
+    /// compression algorithm this build doesn't support.
+    BadHeader,
+    /// The stream ended before its final chunk.
+    Truncated,
+    /// The chunk with this index was modified, reordered, or belongs to another stream.
+    Authentication { index: u64 },
+    /// Every chunk was authentic, but the plaintext doesn't match the digest at the end, so
+    /// whatever wrote the stream was faulty.
+    DigestMismatch,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::BadHeader => f.write_str("invalid stream header"),
+            StreamError::Truncated => f.write_str("stream ended before its final chunk"),
+            StreamError::Authentication { index } => {
+                write!(f, "chunk {} failed authentication", index)
+            }
+            StreamError::DigestMismatch => {
+                f.write_str("plaintext does not match the stream's digest")
+            }
+        }
+    }
+}
+
+impl Error for StreamError {}
+
+impl From<StreamError> for io::Error {
+    fn from(error: StreamError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// The parameters at the start of every stream.
+///
+/// A header is either checked by [`StreamOptions::to_header`] for a new stream, or parsed by
+/// [`from_bytes`](Self::from_bytes) from an existing one, so its parameters are always valid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamHeader {
+    pub(crate) chunk_size: u32,
+    pub(crate) compression: Compression,
+    pub(crate) tag_len: u8,
+    pub(crate) plaintext_digest: bool,
+    pub(crate) sparse: bool,
+    pub(crate) stream_id: [u8; STREAM_ID_SIZE],
+}
+
+impl StreamHeader {
+    /// A header with a fresh random stream ID, for the crate's own streams and tests, which
+    /// only pass chunk sizes from 1 to [`MAX_CHUNK_SIZE`].
+    pub(crate) fn new(chunk_size: u32) -> Self {
+        debug_assert!((1..=MAX_CHUNK_SIZE).contains(&chunk_size));
+        StreamHeader {
+            chunk_size,
+            compression: Compression::None,
+            tag_len: TAG_SIZE as u8,
+            plaintext_digest: false,
+            sparse: false,
+            stream_id: utils::create_rand_key(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_plaintext_digest(mut self) -> Self {
+        self.plaintext_digest = true;
+        self
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// The length of each chunk's tag, from [`MIN_TAG_LEN`] to 16 bytes.
+    pub fn tag_len(&self) -> u8 {
+        self.tag_len
+    }
+
+    /// Whether the stream ends with a SHA-256 of the plaintext.
+    pub fn plaintext_digest(&self) -> bool {
+        self.plaintext_digest
+    }
+
+    /// Whether the plaintext is a sparse file's extent map followed by its data.
+    pub fn sparse(&self) -> bool {
+        self.sparse
+    }
+
+    pub fn stream_id(&self) -> [u8; STREAM_ID_SIZE] {
+        self.stream_id
+    }
+
+    /// The bytes the stream needs after its (compressed) data.
+    fn trailer_len(&self) -> usize {
+        if self.plaintext_digest {
+            DIGEST_SIZE
+        } else {
+            0
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = VERSION;
+        bytes[5..9].copy_from_slice(&self.chunk_size.to_be_bytes());
+        bytes[9] = self.compression.id() | ((TAG_SIZE as u8 - self.tag_len) << 4);
+        if self.plaintext_digest {
+            bytes[9] |= DIGEST_FLAG;
+        }
+        if self.sparse {
+            bytes[9] |= SPARSE_FLAG;
+        }
+        bytes[10..].copy_from_slice(&self.stream_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Result<Self, StreamError> {
+        if &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return Err(StreamError::BadHeader);
+        }
+        let chunk_size = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+        if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(StreamError::BadHeader);
+        }
+        // Readers from before the sparse flag saw it as an unknown compression algorithm.
+        let compression = Compression::from_id(bytes[9] & 0x07).ok_or(StreamError::BadHeader)?;
+        // Readers from before the digest saw its flag as tags shortened by 8 or more bytes, and
+        // rejected the header.
+        let tag_len = TAG_SIZE as u8 - ((bytes[9] & !DIGEST_FLAG) >> 4);
+        if tag_len < MIN_TAG_LEN {
+            return Err(StreamError::BadHeader);
+        }
+
+        let mut stream_id = [0u8; STREAM_ID_SIZE];
+        stream_id.copy_from_slice(&bytes[10..]);
+        Ok(StreamHeader {
+            chunk_size,
+            compression,
+            tag_len,
+            plaintext_digest: bytes[9] & DIGEST_FLAG != 0,
+            sparse: bytes[9] & SPARSE_FLAG != 0,
+            stream_id,
+        })
+    }
+}
+
+/// A stream's parameters didn't pass [`StreamOptions::to_header`]'s checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamOptionsError {
+    /// The chunk size must be between 1 byte and [`MAX_CHUNK_SIZE`].
+    ChunkSize(u32),
+    /// Tags must be between [`MIN_TAG_LEN`] and 16 bytes.
+    TagLen(u8),
+    /// No chunk size fits a whole number of encrypted chunks into a part of this size.
+    PartSize(u64),
+}
+
+impl fmt::Display for StreamOptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamOptionsError::ChunkSize(size) => write!(
+                f,
+                "chunk size {} is not between 1 and {} bytes",
+                size, MAX_CHUNK_SIZE
+            ),
+            StreamOptionsError::TagLen(len) => write!(
+                f,
+                "tag length {} is not between {} and {} bytes",
+                len, MIN_TAG_LEN, TAG_SIZE
+            ),
+            StreamOptionsError::PartSize(size) => write!(
+                f,
+                "no chunk size divides parts of {} bytes into whole chunks",
+                size
+            ),
+        }
+    }
+}
+
+impl Error for StreamOptionsError {}
+
+/// The tunable parts of a stream, checked before any data is written.
+///
+/// All of them are recorded in the header, so the reader needs no configuration. Bigger chunks
+/// mean fewer tags and fewer key derivations, hence more throughput, but the reader holds a
+/// whole chunk in memory: the default 64 KiB suits servers, and 4 KiB suits embedded devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamOptions {
+    chunk_size: u32,
+    tag_len: u8,
+    compression: Compression,
+    plaintext_digest: bool,
+    part_size: Option<u64>,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamOptions {
+    /// 64 KiB chunks, full 16-byte tags, no compression, and no plaintext digest.
+    pub fn new() -> Self {
+        StreamOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            tag_len: TAG_SIZE as u8,
+            compression: Compression::None,
+            plaintext_digest: false,
+            part_size: None,
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Shortens every chunk's tag to `tag_len` bytes, from [`MIN_TAG_LEN`] to 16, saving space
+    /// at the cost of a higher chance that a forged chunk goes unnoticed.
+    pub fn with_tag_len(mut self, tag_len: u8) -> Self {
+        self.tag_len = tag_len;
+        self
+    }
+
+    /// Compresses the stream before encrypting it. Read the [`compression`](crate::compression)
+    /// docs first: this is unsafe for data that mixes secrets with attacker-controlled input.
+    pub fn with_compression(
+        mut self,
+        compression: Compression,
+        _acknowledged: OracleRiskAcknowledged,
+    ) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Stores a SHA-256 of the plaintext at the end of the stream.
+    pub fn with_plaintext_digest(mut self, plaintext_digest: bool) -> Self {
+        self.plaintext_digest = plaintext_digest;
+        self
+    }
+
+    /// Picks the chunk size so that every [multipart upload part](crate::multipart) of
+    /// `part_size` bytes holds a whole number of encrypted chunks, instead of the chunk size
+    /// given with [`with_chunk_size`](Self::with_chunk_size).
+    pub fn with_part_size(mut self, part_size: u64) -> Self {
+        self.part_size = Some(part_size);
+        self
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub fn tag_len(&self) -> u8 {
+        self.tag_len
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn plaintext_digest(&self) -> bool {
+        self.plaintext_digest
+    }
+
+    pub fn part_size(&self) -> Option<u64> {
+        self.part_size
+    }
+
+    /// Checks the options and makes a header for a new stream, with a fresh stream ID.
+    pub fn to_header(&self) -> Result<StreamHeader, StreamOptionsError> {
+        if !(MIN_TAG_LEN..=TAG_SIZE as u8).contains(&self.tag_len) {
+            return Err(StreamOptionsError::TagLen(self.tag_len));
+        }
+        let chunk_size = match self.part_size {
+            Some(part_size) => crate::multipart::chunk_size_for_part(part_size, self.tag_len)
+                .ok_or(StreamOptionsError::PartSize(part_size))?,
+            None => self.chunk_size,
+        };
+        if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(StreamOptionsError::ChunkSize(chunk_size));
+        }
+        Ok(StreamHeader {
+            tag_len: self.tag_len,
+            compression: self.compression,
+            plaintext_digest: self.plaintext_digest,
+            ..StreamHeader::new(chunk_size)
+        })
+    }
+}
+
+/// Derives per-chunk keys and nonces for one stream.
+#[derive(Clone)]
+pub struct ChunkKeys {
+    hkdf: Hkdf<Sha256>,
+    header: StreamHeader,
+}
+
+/// Shows the header only: the key derivation is as good as the master secret.
+impl fmt::Debug for ChunkKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkKeys")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChunkKeys {
+    pub fn new(master_secret: &[u8], header: StreamHeader) -> Self {
+        if header.tag_len < TAG_SIZE as u8 {
+            warnings::warn(Warning::ShortTag {
+                len: header.tag_len as usize,
+            });
+        }
+        ChunkKeys {
+            hkdf: Hkdf::new(Some(&header.stream_id), master_secret),
+            header,
+        }
+    }
+
+    pub fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+
+    /// The key and nonce for chunk `index`: HKDF-Expand(PRK, "aes-modes chunk" | index).
+    fn derive(&self, index: u64) -> ([u8; BLOCK_SIZE], [u8; GCM_NONCE_SIZE]) {
+        let mut output = [0u8; BLOCK_SIZE + GCM_NONCE_SIZE];
+        self.hkdf
+            .expand_multi_info(&[CHUNK_LABEL, &index.to_be_bytes()], &mut output)
+            .expect("28 bytes is a valid HKDF-SHA256 output length");
+
+        let mut key = [0u8; BLOCK_SIZE];
+        let mut nonce = [0u8; GCM_NONCE_SIZE];
+        key.copy_from_slice(&output[..BLOCK_SIZE]);
+        nonce.copy_from_slice(&output[BLOCK_SIZE..]);
+        (key, nonce)
+    }
+
+    /// The key that authenticates the stream's [`Manifest`](crate::merkle::Manifest).
+    pub(crate) fn manifest_key(&self) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        self.hkdf
+            .expand(MANIFEST_LABEL, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    /// The key of the MACs over the stream's [parts](crate::multipart::Part).
+    pub(crate) fn part_key(&self) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        self.hkdf
+            .expand(PART_LABEL, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    /// Everything a chunk authenticates besides its contents.
+    fn aad(&self, index: u64, is_final: bool) -> Vec<u8> {
+        let mut aad = self.header.to_bytes().to_vec();
+        aad.extend_from_slice(&index.to_be_bytes());
+        aad.push(is_final as u8);
+        aad
+    }
+
+    /// Encrypts one chunk independently of all the others.
+    pub fn encrypt_chunk(&self, index: u64, is_final: bool, chunk: &[u8]) -> Vec<u8> {
+        let (key, nonce) = self.derive(index);
+        let mut cipher_text = gcm_encrypt(chunk.to_vec(), key, nonce, &self.aad(index, is_final));
+        cipher_text.truncate(chunk.len() + self.header.tag_len as usize);
+        cipher_text
+    }
+
+    pub fn decrypt_chunk(
+        &self,
+        index: u64,
+        is_final: bool,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, StreamError> {
+        let (key, nonce) = self.derive(index);
+        let aad = self.aad(index, is_final);
+        GcmKey::new(key)
+            .open_truncated(nonce, chunk, &aad, self.header.tag_len as usize)
+            .map_err(|_| StreamError::Authentication { index })
+    }
+}
+
+/// Encrypts everything written to it into a chunked stream. Call [`finish`](Self::finish) at
+/// the end, otherwise the stream is missing its final chunk and won't decrypt.
+pub struct StreamEncryptor<W: Write> {
+    keys: ChunkKeys,
+    compressor: Compressor,
+    hasher: Option<Sha256>,
+    writer: W,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> fmt::Debug for StreamEncryptor<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamEncryptor")
+            .field("keys", &self.keys)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    pub fn new(master_secret: &[u8], writer: W) -> io::Result<Self> {
+        Self::with_header(master_secret, StreamHeader::new(DEFAULT_CHUNK_SIZE), writer)
+    }
+
+    /// Starts a stream with the given options, failing with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) if they are out of bounds.
+    pub fn with_options(
+        master_secret: &[u8],
+        options: StreamOptions,
+        writer: W,
+    ) -> io::Result<Self> {
+        let header = options
+            .to_header()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        Self::with_header(master_secret, header, writer)
+    }
+
+    /// Like [`with_options`](Self::with_options), also failing with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) if `policy` doesn't allow the tag length
+    /// or the master secret.
+    pub fn with_policy(
+        master_secret: &[u8],
+        options: StreamOptions,
+        policy: &Policy,
+        writer: W,
+    ) -> io::Result<Self> {
+        let header = options
+            .to_header()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        policy
+            .check_stream(master_secret, &header)
+            .map_err(|violation| io::Error::new(io::ErrorKind::InvalidInput, violation))?;
+        Self::with_header(master_secret, header, writer)
+    }
+
+    pub fn with_header(
+        master_secret: &[u8],
+        header: StreamHeader,
+        mut writer: W,
+    ) -> io::Result<Self> {
+        writer.write_all(&header.to_bytes())?;
+        Ok(StreamEncryptor {
+            keys: ChunkKeys::new(master_secret, header),
+            compressor: Compressor::new(header.compression),
+            hasher: header.plaintext_digest.then(Sha256::new),
+            writer,
+            buffer: Vec::with_capacity(header.chunk_size as usize),
+            index: 0,
+        })
+    }
+
+    /// Writes the final chunk and hands back the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_with_digest().map(|(writer, _)| writer)
+    }
+
+    /// Like [`finish`](Self::finish), also handing back the SHA-256 of everything written if
+    /// the header asked for one.
+    pub fn finish_with_digest(mut self) -> io::Result<(W, Option<[u8; DIGEST_SIZE]>)> {
+        let compressor = mem::replace(&mut self.compressor, Compressor::None);
+        self.push(&compressor.finish())?;
+        let digest: Option<[u8; DIGEST_SIZE]> =
+            self.hasher.take().map(|hasher| hasher.finalize().into());
+        if let Some(digest) = &digest {
+            self.push(digest)?;
+        }
+
+        if self.buffer.len() == self.keys.header.chunk_size as usize {
+            self.write_chunk(false)?;
+        }
+        self.write_chunk(true)?;
+        self.writer.flush()?;
+        Ok((self.writer, digest))
+    }
+
+    /// Adds (compressed) data to the buffer, writing out every chunk that fills up.
+    fn push(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let chunk_size = self.keys.header.chunk_size as usize;
+        while !data.is_empty() {
+            // A full buffer is only written once more data arrives, because if nothing else
+            // comes it has to be followed by an empty final chunk rather than be final itself.
+            if self.buffer.len() == chunk_size {
+                self.write_chunk(false)?;
+            }
+            let n = data.len().min(chunk_size - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Encrypts and writes out whatever is buffered as the next chunk.
+    fn write_chunk(&mut self, is_final: bool) -> io::Result<()> {
+        let chunk = self.keys.encrypt_chunk(self.index, is_final, &self.buffer);
+        self.writer.write_all(&chunk)?;
+        self.buffer.clear();
+        self.index += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        let data = self.compressor.compress(buf);
+        self.push(&data)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts a chunked stream, releasing each chunk only after it has been authenticated.
+///
+/// If the stream was cut short, reading fails at the end instead of returning `Ok(0)`.
+pub struct StreamDecryptor<R: Read> {
+    keys: ChunkKeys,
+    decompressor: Option<Decompressor>,
+    hasher: Option<Sha256>,
+    /// The last bytes decrypted, which are the digest if the stream ends here.
+    held_back: Vec<u8>,
+    digest: Option<[u8; DIGEST_SIZE]>,
+    reader: R,
+    buffer: Vec<u8>,
+    position: usize,
+    index: u64,
+    finished: bool,
+}
+
+impl<R: Read> fmt::Debug for StreamDecryptor<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamDecryptor")
+            .field("keys", &self.keys)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    /// Reads the stream header, within the default [`Limits`].
+    pub fn new(master_secret: &[u8], reader: R) -> io::Result<Self> {
+        Self::with_limits(master_secret, &Limits::default(), reader)
+    }
+
+    /// Like [`new`](Self::new), failing with [`OutOfMemory`](io::ErrorKind::OutOfMemory) if
+    /// the header's chunk size, tag included, is over `limits`, before any chunk is read.
+    pub fn with_limits(master_secret: &[u8], limits: &Limits, mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::UnexpectedEof => StreamError::BadHeader.into(),
+                _ => error,
+            })?;
+        let header = StreamHeader::from_bytes(&header)?;
+        if header.chunk_size as usize + header.tag_len as usize > limits.max_chunk_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "stream chunk size over the limit",
+            ));
+        }
+
+        Ok(StreamDecryptor {
+            keys: ChunkKeys::new(master_secret, header),
+            decompressor: Some(Decompressor::new(
+                header.compression,
+                limits.max_decompressed_chunk_size(),
+            )),
+            hasher: header.plaintext_digest.then(Sha256::new),
+            held_back: Vec::new(),
+            digest: None,
+            reader,
+            buffer: Vec::new(),
+            position: 0,
+            index: 0,
+            finished: false,
+        })
+    }
+
+    /// Like [`new`](Self::new), failing with [`InvalidData`](io::ErrorKind::InvalidData) if
+    /// `policy` doesn't allow the stream's tag length or the master secret.
+    pub fn with_policy(master_secret: &[u8], policy: &Policy, reader: R) -> io::Result<Self> {
+        let decryptor = Self::new(master_secret, reader)?;
+        policy
+            .check_stream(master_secret, decryptor.header())
+            .map_err(|violation| io::Error::new(io::ErrorKind::InvalidData, violation))?;
+        Ok(decryptor)
+    }
+
+    pub fn header(&self) -> &StreamHeader {
+        self.keys.header()
+    }
+
+    /// The SHA-256 of the plaintext stored in the stream, once all of it has been read and
+    /// found to match. `None` before that, or if the stream has no digest.
+    pub fn plaintext_digest(&self) -> Option<[u8; DIGEST_SIZE]> {
+        self.digest
+    }
+
+    /// How many chunks have been decrypted and authenticated so far.
+    pub fn chunks_read(&self) -> u64 {
+        self.index
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let tag_len = self.keys.header.tag_len as usize;
+        let full_len = self.keys.header.chunk_size as usize + tag_len;
+        let mut chunk = limits::try_zeroed(full_len, usize::MAX)?;
+        let len = read_up_to(&mut self.reader, &mut chunk)?;
+        if len < tag_len {
+            return Err(StreamError::Truncated.into());
+        }
+
+        let is_final = len < full_len;
+        let mut plain_text = self
+            .keys
+            .decrypt_chunk(self.index, is_final, &chunk[..len])?;
+        if self.hasher.is_some() {
+            // The digest may start in the chunk before the final one.
+            plain_text = [mem::take(&mut self.held_back), plain_text].concat();
+            let data_len = plain_text.len().saturating_sub(DIGEST_SIZE);
+            self.held_back = plain_text.split_off(data_len);
+        }
+
+        let decompressor = self.decompressor.as_mut().unwrap();
+        self.buffer = decompressor.decompress(plain_text)?;
+        if is_final {
+            let rest = self.decompressor.take().unwrap().finish()?;
+            self.buffer.extend(rest);
+        }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&self.buffer);
+            if is_final {
+                let digest: [u8; DIGEST_SIZE] = self.hasher.take().unwrap().finalize().into();
+                if self.held_back != digest {
+                    return Err(StreamError::DigestMismatch.into());
+                }
+                self.digest = Some(digest);
+            }
+        }
+        self.position = 0;
+        self.index += 1;
+        self.finished = is_final;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Where [`decrypt_verified`] keeps the plaintext until the whole stream has been verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Spool {
+    /// In memory. Simple, but the whole plaintext has to fit.
+    Memory,
+    /// In a temporary file, itself encrypted as a chunked stream under a random key that never
+    /// leaves the process. The file is deleted when the [`VerifiedReader`] is dropped.
+    EncryptedTempFile,
+    /// In memory up to `threshold` bytes, and in an encrypted temporary file past that, as a
+    /// [`SpillBuffer`] does.
+    Spill { threshold: usize },
+}
+
+/// Decrypts and authenticates an entire stream, including its final chunk, before handing
+/// out a single byte of plaintext.
+///
+/// [`StreamDecryptor`] releases each chunk as soon as that chunk is verified, which is the
+/// right thing for piping a large file somewhere. But an application that _acts_ on the data
+/// as it arrives (applying a database dump, say) may have acted on the first half of a stream
+/// whose second half turns out to be forged or missing. With this, any tampering or truncation
+/// is reported here, and the returned reader only ever yields a complete, authentic stream.
+pub fn decrypt_verified<R: Read>(
+    master_secret: &[u8],
+    reader: R,
+    spool: Spool,
+) -> io::Result<VerifiedReader> {
+    let mut decryptor = StreamDecryptor::new(master_secret, reader)?;
+    let mut buffer = SpillBuffer::new(match spool {
+        Spool::Memory => usize::MAX,
+        Spool::EncryptedTempFile => 0,
+        Spool::Spill { threshold } => threshold,
+    });
+    io::copy(&mut decryptor, &mut buffer)?;
+    Ok(VerifiedReader {
+        inner: buffer.into_reader()?,
+    })
+}
+
+/// The plaintext of a stream that [`decrypt_verified`] has already authenticated in full.
+///
+/// A spool file is authenticated again as it is read back, so even someone who can write to
+/// the temporary directory can't slip in different data; reading then fails instead.
+pub struct VerifiedReader {
+    inner: SpillReader,
+}
+
+impl Read for VerifiedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Random access to a chunked stream stored in a seekable file.
+///
+/// Only the chunks overlapping a requested range are read and authenticated, so a media player
+/// seeking to the middle of a large file doesn't have to decrypt everything before it.
+pub struct EncryptedFile<R: Read + Seek> {
+    keys: ChunkKeys,
+    reader: R,
+    chunk_count: u64,
+    len: u64,
+}
+
+impl<R: Read + Seek> EncryptedFile<R> {
+    /// Reads the header and authenticates the final chunk, which proves the file wasn't cut
+    /// short and tells us the plaintext length.
+    pub fn open(master_secret: &[u8], reader: R) -> io::Result<Self> {
+        Self::open_with_limits(master_secret, &Limits::default(), reader)
+    }
+
+    /// Like [`open`](Self::open), failing with [`OutOfMemory`](io::ErrorKind::OutOfMemory) if
+    /// the header's chunk size, tag included, is over `limits`, before any chunk is read.
+    pub fn open_with_limits(
+        master_secret: &[u8],
+        limits: &Limits,
+        mut reader: R,
+    ) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::UnexpectedEof => StreamError::BadHeader.into(),
+                _ => error,
+            })?;
+        let header = StreamHeader::from_bytes(&header)?;
+        if header.compression != Compression::None {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compressed streams can't be decrypted at random offsets",
+            ));
+        }
+        if header.chunk_size as usize + header.tag_len as usize > limits.max_chunk_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "stream chunk size over the limit",
+            ));
+        }
+
+        let body_len = reader.seek(SeekFrom::End(0))? - HEADER_SIZE as u64;
+        let tag_len = header.tag_len as u64;
+        let full_len = header.chunk_size as u64 + tag_len;
+        if body_len % full_len < tag_len {
+            return Err(StreamError::Truncated.into());
+        }
+        let chunk_count = body_len / full_len + 1;
+        let len = (body_len - chunk_count * tag_len)
+            .checked_sub(header.trailer_len() as u64)
+            .ok_or(StreamError::Truncated)?;
+
+        let mut file = EncryptedFile {
+            keys: ChunkKeys::new(master_secret, header),
+            reader,
+            chunk_count,
+            len,
+        };
+        file.read_chunk(chunk_count - 1)?;
+        Ok(file)
+    }
+
+    /// The length of the plaintext.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The SHA-256 of the plaintext stored at the end of the file, if it has one. Unlike
+    /// [`StreamDecryptor::plaintext_digest`], this doesn't check it against the plaintext.
+    pub fn plaintext_digest(&mut self) -> io::Result<Option<[u8; DIGEST_SIZE]>> {
+        if !self.keys.header.plaintext_digest {
+            return Ok(None);
+        }
+        let digest = self.read_range(self.len, self.len + DIGEST_SIZE as u64)?;
+        Ok(Some(digest.try_into().unwrap()))
+    }
+
+    fn read_chunk(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let tag_len = self.keys.header.tag_len as u64;
+        let full_len = self.keys.header.chunk_size as u64 + tag_len;
+        let chunk_len = if index + 1 == self.chunk_count {
+            let stored_len = self.len + self.keys.header.trailer_len() as u64;
+            stored_len - index * self.keys.header.chunk_size as u64 + tag_len
+        } else {
+            full_len
+        };
+
+        let mut chunk = limits::try_zeroed(chunk_len as usize, usize::MAX)?;
+        self.reader
+            .seek(SeekFrom::Start(HEADER_SIZE as u64 + index * full_len))?;
+        self.reader.read_exact(&mut chunk)?;
+        Ok(self
+            .keys
+            .decrypt_chunk(index, index + 1 == self.chunk_count, &chunk)?)
+    }
+
+    /// Decrypts `len` bytes starting at `offset`. The range is clamped to the end of the file.
+    pub fn decrypt_range(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset.saturating_add(len as u64).min(self.len);
+        self.read_range(offset, end)
+    }
+
+    /// Decrypts the stored bytes from `offset` to `end`, trailer included.
+    fn read_range(&mut self, offset: u64, end: u64) -> io::Result<Vec<u8>> {
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        let chunk_size = self.keys.header.chunk_size as u64;
+        let first = offset / chunk_size;
+        let last = (end - 1) / chunk_size;
+        let mut plain_text = Vec::with_capacity((end - offset) as usize);
+        for index in first..=last {
+            let chunk = self.read_chunk(index)?;
+            let chunk_start = index * chunk_size;
+            let from = offset.saturating_sub(chunk_start) as usize;
+            let to = (end - chunk_start).min(chunk.len() as u64) as usize;
+            plain_text.extend_from_slice(&chunk[from..to]);
+        }
+        Ok(plain_text)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Splits the encrypted chunks that follow the header into the individual chunks, each one
+/// `ciphertext | tag`. Only the last one may be shorter than a full chunk.
+pub fn split_chunks<'a>(header: &StreamHeader, chunks: &'a [u8]) -> Vec<&'a [u8]> {
+    chunks
+        .chunks(header.chunk_size as usize + header.tag_len as usize)
+        .collect()
+}
+
+/// Fills as much of `buf` as the reader can provide before hitting the end.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Encrypts an in-memory buffer into a complete stream, spreading the chunks over all CPUs.
+/// The output is identical to what [`StreamEncryptor`] produces with the same header.
+pub fn encrypt_parallel(master_secret: &[u8], header: StreamHeader, data: &[u8]) -> Vec<u8> {
+    let stored;
+    let data = if header.compression == Compression::None && !header.plaintext_digest {
+        data
+    } else {
+        let mut compressor = Compressor::new(header.compression);
+        let mut compressed = [compressor.compress(data).into_owned(), compressor.finish()].concat();
+        if header.plaintext_digest {
+            compressed.extend_from_slice(&Sha256::digest(data));
+        }
+        stored = compressed;
+        &stored
+    };
+
+    let keys = ChunkKeys::new(master_secret, header);
+    let chunk_size = header.chunk_size as usize;
+
+    // The final chunk is always short, so a multiple of the chunk size gets an empty one.
+    let mut chunks: Vec<&[u8]> = data.chunks(chunk_size).collect();
+    if chunks.last().is_none_or(|chunk| chunk.len() == chunk_size) {
+        chunks.push(&[]);
+    }
+    let last = chunks.len() - 1;
+
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let per_thread = chunks.len().div_ceil(threads);
+    let encrypted: Vec<Vec<u8>> = thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .chunks(per_thread)
+            .enumerate()
+            .map(|(batch, batch_chunks)| {
+                let keys = &keys;
+                scope.spawn(move || {
+                    batch_chunks
+                        .iter()
+                        .enumerate()
+                        .map(|(i, chunk)| {
+                            let index = batch * per_thread + i;
+                            keys.encrypt_chunk(index as u64, index == last, chunk)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    let mut output = header.to_bytes().to_vec();
+    for chunk in encrypted {
+        output.extend(chunk);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    fn encrypt(header: StreamHeader, data: &[u8]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::with_header(SECRET, header, Vec::new()).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain_text = Vec::new();
+        StreamDecryptor::new(SECRET, stream)?.read_to_end(&mut plain_text)?;
+        Ok(plain_text)
+    }
+
+    #[test]
+    fn test_debug_hides_the_secret() {
+        let mut encryptor = StreamEncryptor::new(SECRET, Vec::new()).unwrap();
+        let debug = format!("{:?}", encryptor);
+        assert!(debug.starts_with("StreamEncryptor { keys: ChunkKeys { header: StreamHeader {"));
+        encryptor.write_all(b"data").unwrap();
+        let stream = encryptor.finish().unwrap();
+        let debug = format!(
+            "{:?}",
+            StreamDecryptor::new(SECRET, stream.as_slice()).unwrap()
+        );
+        assert!(debug.starts_with("StreamDecryptor { keys: ChunkKeys {"));
+        let secret = String::from_utf8_lossy(SECRET);
+        assert!(!debug.contains(&*secret) && !debug.contains(&format!("{:?}", &SECRET[..4])));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let header = StreamHeader::new(64);
+        for len in [0, 1, 63, 64, 65, 128, 1000] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let stream = encrypt(header, &data);
+
+            let chunks = len / 64 + 1;
+            assert_eq!(stream.len(), HEADER_SIZE + len + chunks * TAG_SIZE);
+            assert_eq!(decrypt(&stream).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let header = StreamHeader::new(100);
+        let data = vec![0x5Au8; 10_000];
+        let stream = encrypt_parallel(SECRET, header, &data);
+        assert_eq!(stream, encrypt(header, &data));
+        assert_eq!(decrypt(&stream).unwrap(), data);
+    }
+
+    #[test]
+    fn test_chunks_have_independent_keys() {
+        // Identical plaintext chunks encrypt differently, in the same and in different streams.
+        let data = vec![0u8; 128];
+        let first = encrypt(StreamHeader::new(64), &data);
+        let second = encrypt(StreamHeader::new(64), &data);
+
+        let chunk =
+            |stream: &[u8], i: usize| stream[HEADER_SIZE + i * (64 + TAG_SIZE)..][..64].to_vec();
+        assert_ne!(chunk(&first, 0), chunk(&first, 1));
+        assert_ne!(chunk(&first, 0), chunk(&second, 0));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let data = vec![7u8; 200];
+        let stream = encrypt(StreamHeader::new(64), &data);
+        let error_of = |stream: &[u8]| {
+            let error = decrypt(stream).unwrap_err();
+            *error
+                .into_inner()
+                .unwrap()
+                .downcast::<StreamError>()
+                .unwrap()
+        };
+
+        // Dropping the final chunk, or cutting the stream at a chunk boundary.
+        let chunk_len = 64 + TAG_SIZE;
+        assert_eq!(
+            error_of(&stream[..HEADER_SIZE + 3 * chunk_len]),
+            StreamError::Truncated
+        );
+        assert_eq!(
+            error_of(&stream[..HEADER_SIZE + 2 * chunk_len]),
+            StreamError::Truncated
+        );
+
+        // Swapping two chunks.
+        let mut swapped = stream.clone();
+        let (a, b) = (HEADER_SIZE, HEADER_SIZE + chunk_len);
+        let first: Vec<u8> = swapped[a..b].to_vec();
+        swapped.copy_within(b..b + chunk_len, a);
+        swapped[b..b + chunk_len].copy_from_slice(&first);
+        assert_eq!(error_of(&swapped), StreamError::Authentication { index: 0 });
+
+        // Changing the chunk size in the header.
+        let mut tampered = stream.clone();
+        tampered[8] ^= 1;
+        assert_eq!(
+            error_of(&tampered),
+            StreamError::Authentication { index: 0 }
+        );
+
+        let mut tampered = stream;
+        tampered[..4].copy_from_slice(b"NOPE");
+        assert_eq!(error_of(&tampered), StreamError::BadHeader);
+    }
+
+    #[test]
+    fn test_decrypt_verified() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let stream = encrypt(StreamHeader::new(64), &data);
+        for spool in [
+            Spool::Memory,
+            Spool::EncryptedTempFile,
+            Spool::Spill { threshold: 100 },
+        ] {
+            let mut plain_text = Vec::new();
+            decrypt_verified(SECRET, stream.as_slice(), spool)
+                .unwrap()
+                .read_to_end(&mut plain_text)
+                .unwrap();
+            assert_eq!(plain_text, data);
+
+            // Damage to the last chunk is caught before anything is released.
+            let mut tampered = stream.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(decrypt_verified(SECRET, tampered.as_slice(), spool).is_err());
+            assert!(decrypt_verified(SECRET, &stream[..stream.len() - 20], spool).is_err());
+        }
+    }
+
+    #[test]
+    fn test_spool_file_is_encrypted_and_removed() {
+        let data = b"never on disk in the clear ".repeat(100);
+        let stream = encrypt(StreamHeader::new(64), &data);
+        let reader = decrypt_verified(SECRET, stream.as_slice(), Spool::EncryptedTempFile).unwrap();
+        let path = reader.inner.path().unwrap().to_path_buf();
+        let on_disk = fs::read(&path).unwrap();
+        assert!(!on_disk.windows(10).any(|window| window == &data[..10]));
+        drop(reader);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_stream_options() {
+        let options = StreamOptions::new().with_chunk_size(4096).with_tag_len(12);
+        let mut encryptor = StreamEncryptor::with_options(SECRET, options, Vec::new()).unwrap();
+        let data = vec![1u8; 10_000];
+        encryptor.write_all(&data).unwrap();
+        let stream = encryptor.finish().unwrap();
+
+        // Three chunks with 12-byte tags, and the reader needs no options.
+        assert_eq!(stream.len(), HEADER_SIZE + data.len() + 3 * 12);
+        assert_eq!(decrypt(&stream).unwrap(), data);
+        let header = StreamHeader::from_bytes(stream[..HEADER_SIZE].try_into().unwrap()).unwrap();
+        assert_eq!((header.chunk_size, header.tag_len), (4096, 12));
+
+        let mut file = EncryptedFile::open(SECRET, io::Cursor::new(&stream)).unwrap();
+        assert_eq!(file.decrypt_range(4090, 10).unwrap(), &data[4090..4100]);
+        let mut tampered = stream.clone();
+        tampered[HEADER_SIZE + 4096 + 11] ^= 1;
+        assert!(decrypt(&tampered).is_err());
+
+        assert_eq!(
+            StreamOptions::new().with_tag_len(8).to_header(),
+            Err(StreamOptionsError::TagLen(8))
+        );
+        assert_eq!(
+            StreamOptions::new().with_chunk_size(0).to_header(),
+            Err(StreamOptionsError::ChunkSize(0))
+        );
+        // A header claiming shorter tags than the minimum is rejected too.
+        let mut bytes = StreamHeader::new(64).to_bytes();
+        bytes[9] |= 0x50;
+        assert_eq!(
+            StreamHeader::from_bytes(&bytes),
+            Err(StreamError::BadHeader)
+        );
+    }
+
+    #[test]
+    fn test_plaintext_digest() {
+        // 120 bytes of data put the digest across the last two 64-byte chunks.
+        for len in [0, 10, 120] {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let header = StreamHeader::new(64).with_plaintext_digest();
+            let mut encryptor = StreamEncryptor::with_header(SECRET, header, Vec::new()).unwrap();
+            encryptor.write_all(&data).unwrap();
+            let (stream, digest) = encryptor.finish_with_digest().unwrap();
+            let expected: [u8; DIGEST_SIZE] = Sha256::digest(&data).into();
+            assert_eq!(digest, Some(expected));
+            assert_eq!(encrypt_parallel(SECRET, header, &data), stream);
+
+            let mut decryptor = StreamDecryptor::new(SECRET, &stream[..]).unwrap();
+            assert_eq!(decryptor.plaintext_digest(), None);
+            let mut plain_text = Vec::new();
+            decryptor.read_to_end(&mut plain_text).unwrap();
+            assert_eq!(plain_text, data);
+            assert_eq!(decryptor.plaintext_digest(), Some(expected));
+
+            let mut file = EncryptedFile::open(SECRET, io::Cursor::new(&stream)).unwrap();
+            assert_eq!(file.len(), len);
+            assert_eq!(file.decrypt_range(0, 1000).unwrap(), data);
+            assert_eq!(file.plaintext_digest().unwrap(), Some(expected));
+        }
+
+        let options = StreamOptions::new().with_plaintext_digest(true);
+        let header = options.to_header().unwrap();
+        assert!(
+            StreamHeader::from_bytes(&header.to_bytes())
+                .unwrap()
+                .plaintext_digest
+        );
+        let (_, digest) = StreamEncryptor::new(SECRET, Vec::new())
+            .unwrap()
+            .finish_with_digest()
+            .unwrap();
+        assert_eq!(digest, None);
+    }
+
+    #[test]
+    fn test_policy() {
+        let truncated = StreamOptions::new().with_tag_len(12);
+        let error = StreamEncryptor::with_policy(SECRET, truncated, &Policy::strict(), Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // The secret is 27 bytes, enough for `compat` but not for `strict`.
+        let encryptor =
+            StreamEncryptor::with_policy(SECRET, truncated, &Policy::compat(), Vec::new()).unwrap();
+        let stream = encryptor.finish().unwrap();
+        assert!(StreamDecryptor::with_policy(SECRET, &Policy::compat(), &stream[..]).is_ok());
+        let error = StreamDecryptor::with_policy(SECRET, &Policy::strict(), &stream[..])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "12-byte tag is shorter than the policy's 16 bytes"
+        );
+    }
+
+    #[cfg(any(feature = "deflate", feature = "zstd"))]
+    #[test]
+    fn test_compressed_round_trip() {
+        let algorithms = [
+            #[cfg(feature = "deflate")]
+            Compression::Deflate,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ];
+        let data = b"the same sentence over and over again. ".repeat(500);
+        for compression in algorithms {
+            let header = StreamHeader {
+                compression,
+                ..StreamHeader::new(256)
+            };
+            let stream = encrypt(header, &data);
+            assert!(stream.len() < data.len() / 4);
+            assert_eq!(&stream[..HEADER_SIZE], &header.to_bytes());
+            assert_eq!(decrypt(&stream).unwrap(), data);
+            assert_eq!(encrypt_parallel(SECRET, header, &data), stream);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_range() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let stream = encrypt(StreamHeader::new(64), &data);
+        let mut file = EncryptedFile::open(SECRET, io::Cursor::new(&stream)).unwrap();
+        assert_eq!(file.len(), 1000);
+
+        for (offset, len) in [
+            (0, 10),
+            (60, 10),
+            (64, 64),
+            (100, 500),
+            (990, 50),
+            (2000, 1),
+        ] {
+            let start = (offset as usize).min(data.len());
+            let end = (start + len).min(data.len());
+            assert_eq!(file.decrypt_range(offset, len).unwrap(), &data[start..end]);
+        }
+
+        // Damage in one chunk only affects ranges that touch it.
+        let mut tampered = stream.clone();
+        tampered[HEADER_SIZE + 5 * (64 + TAG_SIZE)] ^= 1;
+        let mut file = EncryptedFile::open(SECRET, io::Cursor::new(&tampered)).unwrap();
+        assert_eq!(file.decrypt_range(0, 320).unwrap(), &data[..320]);
+        assert!(file.decrypt_range(300, 40).is_err());
+
+        assert!(EncryptedFile::open(SECRET, io::Cursor::new(&stream[..stream.len() - 1])).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! Where the time comes from, for anything that expires.
+//!
+//! [Envelopes with a validity period](crate::envelope::Validity) are checked against the
+//! current time when they are opened. The system clock is the right answer in production, and
+//! the wrong one in tests, in replays of old traffic, and on devices that get their time from
+//! somewhere trusted rather than from an RTC anyone can set. A [`Clock`] says what "now" is.
+
+use crate::keys;
+
+/// A source of the current time, in seconds since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// The operating system's clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        keys::now()
+    }
+}
+
+/// A clock stopped at the given time, for tests and for checking against a time from
+/// elsewhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,345 @@
+//! Optional compression of [`chunked`](crate::chunked) streams before they are encrypted.
+//!
+//! Ciphertext doesn't compress, so compression has to happen first. That has a well-known catch:
+//! the length of the ciphertext now depends on the _contents_ of the plaintext. When an attacker
+//! can put their own data next to a secret and watch the size of the result, they can guess the
+//! secret piece by piece, because a correct guess compresses better. This is how CRIME and
+//! BREACH recovered session cookies from encrypted HTTP.
+//!
+//! Compression is therefore off unless the caller passes [`OracleRiskAcknowledged`], and it is
+//! only safe when attacker-influenced data is never compressed together with secrets, or when
+//! nobody who could influence the data can also see ciphertext sizes.
+//!
+//! Each algorithm needs its feature: `deflate` or `zstd`. The choice is recorded in the stream
+//! header, and reversed automatically when the stream is decrypted.
+
+use std::{borrow::Cow, io};
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+use std::io::Write;
+
+/// How the plaintext is compressed before encryption.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Passing this is how callers confirm they've read about compression oracles (see the
+/// [module documentation](self)) and that their data is safe to compress.
+#[derive(Clone, Copy, Debug)]
+pub struct OracleRiskAcknowledged;
+
+impl Compression {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 2,
+        }
+    }
+
+    /// `None` for unknown IDs, and for algorithms this build was compiled without.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            #[cfg(feature = "deflate")]
+            1 => Some(Compression::Deflate),
+            #[cfg(feature = "zstd")]
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// The zstd default, which is a good balance between speed and size.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses a stream incrementally, handing back whatever output is ready.
+pub(crate) enum Compressor {
+    None,
+    #[cfg(feature = "deflate")]
+    Deflate(flate2::write::DeflateEncoder<Vec<u8>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Compressor {
+    pub(crate) fn new(compression: Compression) -> Self {
+        match compression {
+            Compression::None => Compressor::None,
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => Compressor::Deflate(flate2::write::DeflateEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Compressor::Zstd(
+                zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)
+                    .expect("the zstd level is valid"),
+            ),
+        }
+    }
+
+    /// Compresses `data`, returning as much compressed output as is available so far. For
+    /// [`Compression::None`] that is just `data`.
+    pub(crate) fn compress<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Compressor::None => Cow::Borrowed(data),
+            #[cfg(feature = "deflate")]
+            Compressor::Deflate(encoder) => {
+                encoder
+                    .write_all(data)
+                    .expect("writing to a Vec can't fail");
+                Cow::Owned(std::mem::take(encoder.get_mut()))
+            }
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd(encoder) => {
+                encoder
+                    .write_all(data)
+                    .expect("writing to a Vec can't fail");
+                Cow::Owned(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// The rest of the compressed output.
+    pub(crate) fn finish(self) -> Vec<u8> {
+        match self {
+            Compressor::None => Vec::new(),
+            #[cfg(feature = "deflate")]
+            Compressor::Deflate(encoder) => encoder.finish().expect("writing to a Vec can't fail"),
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd(encoder) => encoder.finish().expect("writing to a Vec can't fail"),
+        }
+    }
+}
+
+fn over_limit() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::OutOfMemory,
+        "decompressed chunk over the limit",
+    )
+}
+
+/// Collects decompressed output, refusing to hold more than `limit` bytes at a time.
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+pub(crate) struct BoundedOutput {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+impl BoundedOutput {
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+impl Write for BoundedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.limit - self.data.len() {
+            return Err(over_limit());
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Feeds `input` to `inflater`, until it is all consumed and no output is pending, or the
+/// deflate stream has ended.
+#[cfg(feature = "deflate")]
+fn inflate(
+    inflater: &mut flate2::Decompress,
+    output: &mut BoundedOutput,
+    ended: &mut bool,
+    mut input: &[u8],
+) -> io::Result<()> {
+    let mut buffer = [0u8; 32 * 1024];
+    while !*ended {
+        let (total_in, total_out) = (inflater.total_in(), inflater.total_out());
+        let status = inflater
+            .decompress(input, &mut buffer, flate2::FlushDecompress::None)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let consumed = (inflater.total_in() - total_in) as usize;
+        let produced = (inflater.total_out() - total_out) as usize;
+        input = &input[consumed..];
+        output.write_all(&buffer[..produced])?;
+        *ended = status == flate2::Status::StreamEnd;
+        if produced < buffer.len() && (input.is_empty() || consumed == 0) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The opposite of [`Compressor`].
+pub(crate) enum Decompressor {
+    None {
+        limit: usize,
+    },
+    // The raw inflater, since the writer doesn't report a stream that ends early.
+    #[cfg(feature = "deflate")]
+    Deflate {
+        inflater: flate2::Decompress,
+        output: BoundedOutput,
+        ended: bool,
+    },
+    // The lower-level writer, since only it reports a stream that ends mid-frame.
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::zio::Writer<BoundedOutput, zstd::stream::raw::Decoder<'static>>),
+}
+
+impl Decompressor {
+    /// A decompressor that fails with [`OutOfMemory`](io::ErrorKind::OutOfMemory) instead of
+    /// returning more than `limit` bytes from one call, however small the input: a few
+    /// kilobytes of deflate or zstd can claim to expand to gigabytes.
+    pub(crate) fn new(compression: Compression, limit: usize) -> Self {
+        #[cfg(any(feature = "deflate", feature = "zstd"))]
+        let output = BoundedOutput {
+            data: Vec::new(),
+            limit,
+        };
+        match compression {
+            Compression::None => Decompressor::None { limit },
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => Decompressor::Deflate {
+                inflater: flate2::Decompress::new(false),
+                output,
+                ended: false,
+            },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Decompressor::Zstd(zstd::stream::zio::Writer::new(
+                output,
+                zstd::stream::raw::Decoder::new().expect("creating a zstd decoder can't fail"),
+            )),
+        }
+    }
+
+    /// Fails if the data doesn't decompress, which can only happen if whoever held the key
+    /// wrote garbage: anyone else's changes are caught by authentication first.
+    pub(crate) fn decompress(&mut self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Decompressor::None { limit } if data.len() > *limit => Err(over_limit()),
+            Decompressor::None { .. } => Ok(data),
+            #[cfg(feature = "deflate")]
+            Decompressor::Deflate {
+                inflater,
+                output,
+                ended,
+            } => {
+                inflate(inflater, output, ended, &data)?;
+                Ok(output.take())
+            }
+            #[cfg(feature = "zstd")]
+            Decompressor::Zstd(decoder) => {
+                decoder.write_all(&data)?;
+                decoder.flush()?;
+                Ok(decoder.writer_mut().take())
+            }
+        }
+    }
+
+    /// The rest of the output. Fails if the compressed stream ended early.
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Decompressor::None { .. } => Ok(Vec::new()),
+            #[cfg(feature = "deflate")]
+            Decompressor::Deflate {
+                mut inflater,
+                mut output,
+                mut ended,
+            } => {
+                inflate(&mut inflater, &mut output, &mut ended, &[])?;
+                if !ended {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "compressed stream ended early",
+                    ));
+                }
+                Ok(output.take())
+            }
+            #[cfg(feature = "zstd")]
+            Decompressor::Zstd(mut decoder) => {
+                decoder.finish()?;
+                Ok(decoder.into_inner().0.take())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn algorithms() -> Vec<Compression> {
+        vec![
+            Compression::None,
+            #[cfg(feature = "deflate")]
+            Compression::Deflate,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+    }
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut compressor = Compressor::new(compression);
+        let mut compressed = compressor.compress(data).into_owned();
+        compressed.extend(compressor.finish());
+        compressed
+    }
+
+    fn decompress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressor = Decompressor::new(compression, usize::MAX);
+        let mut decompressed = Vec::new();
+        for piece in data.chunks(100) {
+            decompressed.extend(decompressor.decompress(piece.to_vec())?);
+        }
+        decompressed.extend(decompressor.finish()?);
+        Ok(decompressed)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 7) as u8).collect();
+        for compression in algorithms() {
+            assert_eq!(Compression::from_id(compression.id()), Some(compression));
+            let compressed = compress(compression, &data);
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 10);
+            }
+            assert_eq!(decompress(compression, &compressed).unwrap(), data);
+            assert_eq!(
+                decompress(compression, &compress(compression, &[])).unwrap(),
+                []
+            );
+        }
+        assert_eq!(Compression::from_id(3), None);
+        assert_eq!(Compression::from_id(u8::MAX), None);
+    }
+
+    #[test]
+    fn test_truncated_stream_fails() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i * i % 251) as u8).collect();
+        for compression in algorithms()
+            .into_iter()
+            .filter(|&compression| compression != Compression::None)
+        {
+            let compressed = compress(compression, &data);
+            for len in [compressed.len() / 2, compressed.len() - 1] {
+                assert!(decompress(compression, &compressed[..len]).is_err());
+            }
+        }
+    }
+}
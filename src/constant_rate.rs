@@ -0,0 +1,178 @@
+//! Encryption that takes the same time and size for every message in a range of lengths.
+//!
+//! [`LengthPadding`](crate::length_padding::LengthPadding) hides a message's exact length, but
+//! an observer who can time the encryption, such as a process on the same host or a peer
+//! measuring response times, still sees how much work it took, and so roughly how long the
+//! message was. A [`ConstantRate`] closes both channels up to a quantum:
+//!
+//! - the plaintext is padded to a multiple of the size quantum with
+//!   [`LengthPadding::Multiple`], so every message in a quantum encrypts to the same size and
+//!   the same number of block operations;
+//! - after the real work, dummy AES block operations run until the elapsed time reaches the
+//!   next multiple of the time quantum.
+//!
+//! The dummy operations keep the CPU as busy as real ones would, where a sleep would show as an
+//! idle core, and they run on [`open`](ConstantRate::open) whether or not the envelope
+//! authenticates, so a failure takes as long as a success.
+//!
+//! The guarantee is only as good as the quanta. A message that takes longer than the time
+//! quantum takes two, so it should cover the largest message of the size quantum with room for
+//! a slow moment, and the cost is that every message takes as long as the slowest. The example
+//! `constant_rate` measures the spread of timings with and without it.
+
+use std::{
+    hint::black_box,
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{
+    envelope::{Envelope, EnvelopeError, EnvelopeMode},
+    length_padding::LengthPadding,
+    BLOCK_SIZE,
+};
+
+/// How many dummy blocks to encrypt between looks at the clock.
+const DUMMY_BATCH: usize = 16;
+
+/// Pads messages to a size quantum and their processing to a time quantum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConstantRate {
+    padding: LengthPadding,
+    time_quantum: Duration,
+}
+
+impl ConstantRate {
+    /// Pads to multiples of `size_quantum` bytes and `time_quantum`.
+    ///
+    /// # Panics
+    ///
+    /// If `size_quantum` is zero, or doesn't fit in a `u32`.
+    pub fn new(size_quantum: usize, time_quantum: Duration) -> Self {
+        let size_quantum = u32::try_from(size_quantum)
+            .ok()
+            .and_then(NonZeroU32::new)
+            .expect("the size quantum must be from 1 byte to 4 GiB");
+        ConstantRate {
+            padding: LengthPadding::Multiple(size_quantum),
+            time_quantum,
+        }
+    }
+
+    pub fn size_quantum(&self) -> usize {
+        self.padding.quantum() as usize
+    }
+
+    pub fn time_quantum(&self) -> Duration {
+        self.time_quantum
+    }
+
+    /// The length a `len`-byte plaintext is padded to, marker included.
+    pub fn padded_len(&self, len: usize) -> usize {
+        self.padding.padded_len(len)
+    }
+
+    /// Seals `plain_text` into a GCM envelope, padded to the size quantum, in a multiple of the
+    /// time quantum.
+    pub fn seal(&self, key_id: u32, key: [u8; BLOCK_SIZE], plain_text: Vec<u8>) -> Envelope {
+        let start = Instant::now();
+        let envelope =
+            Envelope::seal_with_padding(self.padding, EnvelopeMode::Gcm, key_id, key, plain_text);
+        self.wait_out(start);
+        envelope
+    }
+
+    /// Opens what [`seal`](Self::seal) sealed, in a multiple of the time quantum whatever the
+    /// outcome. An envelope that isn't padded to the same quantum is
+    /// [`Malformed`](EnvelopeError::Malformed).
+    pub fn open(
+        &self,
+        envelope: &Envelope,
+        key: [u8; BLOCK_SIZE],
+    ) -> Result<Vec<u8>, EnvelopeError> {
+        let start = Instant::now();
+        let result = if envelope.padding == self.padding {
+            envelope.open(key)
+        } else {
+            Err(EnvelopeError::Malformed)
+        };
+        self.wait_out(start);
+        result
+    }
+
+    /// Runs dummy block operations until the next multiple of the time quantum since `start`.
+    fn wait_out(&self, start: Instant) {
+        if self.time_quantum.is_zero() {
+            return;
+        }
+        let elapsed = start.elapsed();
+        let quanta = (elapsed.as_nanos() / self.time_quantum.as_nanos() + 1) as u32;
+        let deadline = start + self.time_quantum * quanta;
+        let cipher = Aes128::new(&GenericArray::default());
+        let mut block = GenericArray::default();
+        while Instant::now() < deadline {
+            for _ in 0..DUMMY_BATCH {
+                cipher.encrypt_block(&mut block);
+            }
+            black_box(&mut block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [3; BLOCK_SIZE];
+
+    #[test]
+    fn test_sizes_are_quantized() {
+        let rate = ConstantRate::new(256, Duration::ZERO);
+        let sizes: Vec<usize> = [0, 1, 100, 255]
+            .into_iter()
+            .map(|len| rate.seal(1, KEY, vec![b'x'; len]).to_bytes().len())
+            .collect();
+        assert!(sizes.iter().all(|&size| size == sizes[0]));
+        assert!(rate.seal(1, KEY, vec![b'x'; 256]).to_bytes().len() > sizes[0]);
+
+        for len in [0, 1, 255, 256, 1000] {
+            let envelope = rate.seal(1, KEY, vec![0; len]);
+            assert_eq!(rate.open(&envelope, KEY), Ok(vec![0; len]));
+        }
+        let decoded = Envelope::from_bytes(&rate.seal(1, KEY, vec![1; 5]).to_bytes()).unwrap();
+        assert_eq!(
+            decoded.padding,
+            LengthPadding::Multiple(NonZeroU32::new(256).unwrap())
+        );
+        assert_eq!(rate.open(&decoded, KEY), Ok(vec![1; 5]));
+
+        let unpadded = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, vec![0; 16]);
+        assert_eq!(rate.open(&unpadded, KEY), Err(EnvelopeError::Malformed));
+        let other_quantum = ConstantRate::new(64, Duration::ZERO).seal(1, KEY, vec![0; 16]);
+        assert_eq!(
+            rate.open(&other_quantum, KEY),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_time_is_quantized() {
+        let quantum = Duration::from_millis(20);
+        let rate = ConstantRate::new(64, quantum);
+        let start = Instant::now();
+        let envelope = rate.seal(1, KEY, b"short".to_vec());
+        assert!(start.elapsed() >= quantum);
+
+        let start = Instant::now();
+        assert_eq!(
+            rate.open(&envelope, [4; BLOCK_SIZE]),
+            Err(EnvelopeError::Authentication)
+        );
+        assert!(start.elapsed() >= quantum);
+    }
+}
@@ -0,0 +1,271 @@
+//! A corpus of ciphertexts for checking other implementations against this one.
+//!
+//! [`generate`] encrypts a fixed matrix of cases: every mode, at each of its key sizes, with
+//! each [`LengthPadding`] scheme that has no parameter, at lengths around the block boundaries
+//! where implementations tend to go wrong. Keys, IVs and plaintexts are derived from the
+//! case's name, so the corpus is the same on every run and every platform, and a port or
+//! binding that reproduces every ciphertext agrees with this crate byte for byte. [`manifest_json`] lists the inputs.
+//!
+//! Each ciphertext is exactly what the crate outputs for its algorithm:
+//!
+//! ```text
+//! aes-128-ecb    blocks
+//! aes-128-cbc    IV | blocks                      (also sm4 and camellia)
+//! aes-128-ctr    nonce | ciphertext               counter block = nonce | little-endian counter
+//! aes-128-gcm    ciphertext | tag                 the nonce is only in the manifest
+//! aes-siv        SIV | ciphertext
+//! ```
+//!
+//! ECB and CBC add PKCS#7 padding on top of any length padding, which is applied to the
+//! plaintext first. The authenticated ciphers authenticate the case's associated data.
+
+use std::fmt::Write;
+
+use aes::{cipher::KeyInit, Aes128};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ctr::CtrParams, ecb_encrypt, gcm::gcm_encrypt, generic, length_padding::LengthPadding,
+    siv::siv_encrypt, BLOCK_SIZE,
+};
+
+/// The manifest format written by [`manifest_json`].
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// The plaintext lengths, before length padding.
+pub const LENGTHS: &[usize] = &[0, 1, 15, 16, 17, 31, 32, 33, 255, 256, 1000];
+
+const PADDINGS: [LengthPadding; 3] = [
+    LengthPadding::None,
+    LengthPadding::Padme,
+    LengthPadding::PowerOfTwo,
+];
+
+/// One ciphertext of the corpus, with everything needed to reproduce it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorpusCase {
+    /// Unique within the corpus, and usable as a file name.
+    pub name: String,
+    /// The algorithm's name in the [registry](crate::registry).
+    pub algorithm: &'static str,
+    pub key: Vec<u8>,
+    /// The IV or nonce, or empty if there is none.
+    pub nonce: Vec<u8>,
+    /// Empty unless the cipher is authenticated.
+    pub associated_data: Vec<u8>,
+    pub padding: LengthPadding,
+    /// Before length padding.
+    pub plain_text: Vec<u8>,
+    pub cipher_text: Vec<u8>,
+}
+
+/// `len` bytes derived from `label`, the same on every run.
+fn derive(label: &str, len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len);
+    for counter in 0u32.. {
+        if bytes.len() >= len {
+            break;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(label);
+        hasher.update(counter.to_be_bytes());
+        bytes.extend_from_slice(&hasher.finalize());
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+/// The algorithms with their key and nonce sizes, and whether they are authenticated.
+fn algorithms() -> Vec<(&'static str, usize, usize, bool)> {
+    vec![
+        ("aes-128-ecb", BLOCK_SIZE, 0, false),
+        ("aes-128-cbc", BLOCK_SIZE, BLOCK_SIZE, false),
+        ("aes-128-ctr", BLOCK_SIZE, 8, false),
+        ("aes-128-ctr", BLOCK_SIZE, 12, false),
+        ("aes-128-gcm", BLOCK_SIZE, 12, true),
+        ("aes-siv", 2 * BLOCK_SIZE, 0, true),
+        #[cfg(feature = "sm4")]
+        ("sm4", 16, 16, false),
+        #[cfg(feature = "camellia")]
+        ("camellia", 16, 16, false),
+        #[cfg(feature = "camellia")]
+        ("camellia", 24, 16, false),
+        #[cfg(feature = "camellia")]
+        ("camellia", 32, 16, false),
+    ]
+}
+
+fn encrypt(algorithm: &str, key: &[u8], nonce: &[u8], aad: &[u8], padded: Vec<u8>) -> Vec<u8> {
+    let aes_key = || key.try_into().unwrap();
+    match (algorithm, key.len()) {
+        ("aes-128-ecb", _) => ecb_encrypt(padded, aes_key()),
+        ("aes-128-cbc", _) => {
+            generic::cbc_encrypt_with_iv(&Aes128::new_from_slice(key).unwrap(), nonce, padded)
+        }
+        ("aes-128-ctr", _) => CtrParams::new()
+            .with_nonce_size(nonce.len())
+            .encrypt_with_nonce(padded, aes_key(), nonce),
+        ("aes-128-gcm", _) => gcm_encrypt(padded, aes_key(), nonce.try_into().unwrap(), aad),
+        ("aes-siv", _) => siv_encrypt(&padded, key.try_into().unwrap(), &[aad]),
+        #[cfg(feature = "sm4")]
+        ("sm4", _) => {
+            generic::cbc_encrypt_with_iv(&generic::Sm4::new_from_slice(key).unwrap(), nonce, padded)
+        }
+        #[cfg(feature = "camellia")]
+        ("camellia", 16) => generic::cbc_encrypt_with_iv(
+            &generic::Camellia128::new_from_slice(key).unwrap(),
+            nonce,
+            padded,
+        ),
+        #[cfg(feature = "camellia")]
+        ("camellia", 24) => generic::cbc_encrypt_with_iv(
+            &generic::Camellia192::new_from_slice(key).unwrap(),
+            nonce,
+            padded,
+        ),
+        #[cfg(feature = "camellia")]
+        ("camellia", 32) => generic::cbc_encrypt_with_iv(
+            &generic::Camellia256::new_from_slice(key).unwrap(),
+            nonce,
+            padded,
+        ),
+        _ => unreachable!("no corpus encryption for {}", algorithm),
+    }
+}
+
+/// Every case of the corpus, in a fixed order.
+pub fn generate() -> Vec<CorpusCase> {
+    let mut cases = Vec::new();
+    for (algorithm, key_size, nonce_size, authenticated) in algorithms() {
+        for padding in PADDINGS {
+            for &len in LENGTHS {
+                let mut name = format!("{}-k{}", algorithm, 8 * key_size);
+                if nonce_size > 0 {
+                    write!(name, "-n{}", nonce_size).unwrap();
+                }
+                write!(name, "-{}-{}", padding.name(), len).unwrap();
+
+                let key = derive(&format!("{} key", name), key_size);
+                let nonce = derive(&format!("{} nonce", name), nonce_size);
+                let associated_data = if authenticated {
+                    derive(&format!("{} associated data", name), 13)
+                } else {
+                    Vec::new()
+                };
+                let plain_text = derive(&format!("{} plaintext", name), len);
+                let cipher_text = encrypt(
+                    algorithm,
+                    &key,
+                    &nonce,
+                    &associated_data,
+                    padding.pad(plain_text.clone()),
+                );
+                cases.push(CorpusCase {
+                    name,
+                    algorithm,
+                    key,
+                    nonce,
+                    associated_data,
+                    padding,
+                    plain_text,
+                    cipher_text,
+                });
+            }
+        }
+    }
+    cases
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The manifest of `cases`, in JSON. Each case names its ciphertext file `<name>.bin` and
+/// gives every input in hex, with the ciphertext's SHA-256 to check the file against.
+pub fn manifest_json(cases: &[CorpusCase]) -> String {
+    let mut json = format!("{{\"version\":{},\"cases\":[", MANIFEST_VERSION);
+    for (i, case) in cases.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write!(
+            json,
+            "\n{{\"name\":\"{}\",\"file\":\"{}.bin\",\"algorithm\":\"{}\",\"key\":\"{}\",\
+             \"nonce\":\"{}\",\"associated_data\":\"{}\",\"padding\":\"{}\",\
+             \"plain_text\":\"{}\",\"cipher_text_sha256\":\"{}\"}}",
+            case.name,
+            case.name,
+            case.algorithm,
+            hex(&case.key),
+            hex(&case.nonce),
+            hex(&case.associated_data),
+            case.padding.name(),
+            hex(&case.plain_text),
+            hex(&Sha256::digest(&case.cipher_text)),
+        )
+        .unwrap();
+    }
+    json.push_str("\n]}\n");
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ecb_decrypt, gcm::gcm_decrypt, siv::siv_decrypt};
+
+    #[test]
+    fn test_cases_decrypt() {
+        let cases = generate();
+        assert_eq!(cases, generate());
+        let mut names: Vec<&str> = cases.iter().map(|case| case.name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), cases.len());
+
+        for case in &cases {
+            let key = || case.key.clone().try_into().unwrap();
+            let padded = match case.algorithm {
+                "aes-128-ecb" => ecb_decrypt(case.cipher_text.clone(), key()),
+                "aes-128-cbc" => crate::cbc_decrypt(case.cipher_text.clone(), key()),
+                "aes-128-ctr" => CtrParams::new()
+                    .with_nonce_size(case.nonce.len())
+                    .decrypt(case.cipher_text.clone(), key())
+                    .unwrap(),
+                "aes-128-gcm" => gcm_decrypt(
+                    case.cipher_text.clone(),
+                    key(),
+                    case.nonce.clone().try_into().unwrap(),
+                    &case.associated_data,
+                )
+                .unwrap(),
+                "aes-siv" => siv_decrypt(
+                    &case.cipher_text,
+                    &case.key.clone().try_into().unwrap(),
+                    &[&case.associated_data],
+                )
+                .unwrap(),
+                _ => continue,
+            };
+            assert_eq!(
+                case.padding.unpad(padded).unwrap(),
+                case.plain_text,
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let cases = generate();
+        let json = manifest_json(&cases[..2]);
+        assert!(
+            json.starts_with("{\"version\":1,\"cases\":[\n{\"name\":\"aes-128-ecb-k128-none-0\"")
+        );
+        assert!(json.contains("\"file\":\"aes-128-ecb-k128-none-1.bin\""));
+        assert_eq!(json.matches("\"name\"").count(), 2);
+        // Without padding, a zero-length ECB plaintext is a single PKCS#7 block.
+        assert_eq!(cases[0].cipher_text.len(), BLOCK_SIZE);
+    }
+}
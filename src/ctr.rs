@@ -0,0 +1,310 @@
+//! CTR mode with a configurable split between the nonce and the counter.
+//!
+//! [`ctr_encrypt`](crate::ctr_encrypt) fills the counter block with an 8-byte nonce followed by
+//! an 8-byte little-endian counter. Other systems split the block differently: a 12-byte nonce
+//! with a 4-byte counter is the most common, as in GCM and RFC 3686, and most of them count in
+//! big-endian. [`CtrParams`] describes the split, and encrypts and decrypts with it:
+//!
+//! ```text
+//! counter block = nonce (nonce_size bytes) | counter (16 - nonce_size bytes)
+//! ciphertext    = nonce | plaintext XOR keystream
+//! ```
+//!
+//! The default parameters give exactly the output of `ctr_encrypt`.
+//!
+//! The counter normally starts at zero. [`CtrParams::with_initial_counter`] starts it
+//! elsewhere: RFC 3686 starts at one, and resuming a message at block `n` means starting at
+//! `n` and keystreaming from there.
+//!
+//! A shorter nonce means fewer messages per key when the nonces are random: by the birthday
+//! bound, random 4-byte nonces are likely to repeat after about 2^16 messages, and a repeated
+//! nonce reuses the keystream. [`CtrParams::encrypt`] therefore only picks nonces of at least
+//! [`MIN_RANDOM_NONCE_SIZE`] bytes, and 12 are better where the counter allows it. Shorter
+//! nonces, from a counter say, go through [`CtrParams::encrypt_with_nonce`].
+//!
+//! A shorter counter means a shorter maximum message: with a 4-byte counter, one nonce covers
+//! at most 2^32 blocks (64 GiB). Encrypting more would reuse the keystream, so it panics
+//! instead.
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{generic::MalformedCiphertext, utils, BLOCK_SIZE, NONCE_SIZE};
+
+/// The shortest nonce [`CtrParams`] accepts.
+pub const MIN_NONCE_SIZE: usize = 4;
+/// The shortest nonce [`CtrParams::encrypt`] generates at random, which keeps collisions
+/// unlikely for about 2^32 messages.
+pub const MIN_RANDOM_NONCE_SIZE: usize = 8;
+/// The longest nonce [`CtrParams`] accepts, which leaves a 4-byte counter.
+pub const MAX_NONCE_SIZE: usize = 12;
+
+/// How a CTR counter block is laid out. See the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CtrParams {
+    nonce_size: usize,
+    big_endian: bool,
+    initial_counter: u128,
+}
+
+impl Default for CtrParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CtrParams {
+    /// The layout [`ctr_encrypt`](crate::ctr_encrypt) uses: an 8-byte nonce and an 8-byte
+    /// little-endian counter.
+    pub fn new() -> Self {
+        CtrParams {
+            nonce_size: NONCE_SIZE,
+            big_endian: false,
+            initial_counter: 0,
+        }
+    }
+
+    /// Sets the nonce size. The counter takes the rest of the block.
+    ///
+    /// # Panics
+    ///
+    /// If `nonce_size` is outside [`MIN_NONCE_SIZE`]..=[`MAX_NONCE_SIZE`], or leaves a counter
+    /// too short for the initial counter.
+    pub fn with_nonce_size(mut self, nonce_size: usize) -> Self {
+        assert!(
+            (MIN_NONCE_SIZE..=MAX_NONCE_SIZE).contains(&nonce_size),
+            "unsupported CTR nonce size {}",
+            nonce_size
+        );
+        self.nonce_size = nonce_size;
+        assert!(
+            self.initial_counter < self.max_blocks(),
+            "initial counter doesn't fit the counter"
+        );
+        self
+    }
+
+    /// Starts counting at `counter` instead of zero.
+    ///
+    /// # Panics
+    ///
+    /// If `counter` doesn't fit the counter.
+    pub fn with_initial_counter(mut self, counter: u128) -> Self {
+        assert!(
+            counter < self.max_blocks(),
+            "initial counter doesn't fit the counter"
+        );
+        self.initial_counter = counter;
+        self
+    }
+
+    /// Counts in big-endian order, as NIST SP 800-38A and most other implementations do.
+    pub fn with_big_endian_counter(mut self) -> Self {
+        self.big_endian = true;
+        self
+    }
+
+    pub fn nonce_size(&self) -> usize {
+        self.nonce_size
+    }
+
+    pub fn initial_counter(&self) -> u128 {
+        self.initial_counter
+    }
+
+    pub fn counter_size(&self) -> usize {
+        BLOCK_SIZE - self.nonce_size
+    }
+
+    /// The number of counter values, and so the most blocks one nonce can encrypt from a zero
+    /// initial counter.
+    pub fn max_blocks(&self) -> u128 {
+        1 << (8 * self.counter_size())
+    }
+
+    /// The counter block for block number `counter` under `nonce`.
+    ///
+    /// # Panics
+    ///
+    /// If `nonce` has the wrong length, or `counter` doesn't fit the counter.
+    pub fn counter_block(&self, nonce: &[u8], counter: u128) -> [u8; BLOCK_SIZE] {
+        assert_eq!(nonce.len(), self.nonce_size, "wrong CTR nonce length");
+        assert!(counter < self.max_blocks(), "CTR counter overflow");
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..self.nonce_size].copy_from_slice(nonce);
+        if self.big_endian {
+            block[self.nonce_size..]
+                .copy_from_slice(&counter.to_be_bytes()[BLOCK_SIZE - self.counter_size()..]);
+        } else {
+            block[self.nonce_size..].copy_from_slice(&counter.to_le_bytes()[..self.counter_size()]);
+        }
+        block
+    }
+
+    /// XORs `data` with the keystream for `nonce`. This is its own inverse.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is longer than the counter values left after the initial counter.
+    pub fn apply_keystream(&self, key: [u8; BLOCK_SIZE], nonce: &[u8], data: &mut [u8]) {
+        let blocks = data.len().div_ceil(BLOCK_SIZE) as u128;
+        assert!(
+            blocks <= self.max_blocks() - self.initial_counter,
+            "message too long for a {}-byte CTR counter",
+            self.counter_size()
+        );
+        let cipher = Aes128::new(&GenericArray::from(key));
+        for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let counter = self.initial_counter + i as u128;
+            let mut keystream = GenericArray::from(self.counter_block(nonce, counter));
+            cipher.encrypt_block(&mut keystream);
+            for (byte, key_byte) in chunk.iter_mut().zip(keystream) {
+                *byte ^= key_byte;
+            }
+        }
+    }
+
+    /// Encrypts under a random nonce, which is the start of the output.
+    ///
+    /// # Panics
+    ///
+    /// If the nonce size is below [`MIN_RANDOM_NONCE_SIZE`].
+    pub fn encrypt(&self, plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+        assert!(
+            self.nonce_size >= MIN_RANDOM_NONCE_SIZE,
+            "random {}-byte CTR nonces are too likely to repeat; use encrypt_with_nonce",
+            self.nonce_size
+        );
+        let mut nonce = vec![0u8; self.nonce_size];
+        utils::fill_random(&mut nonce);
+        self.encrypt_with_nonce(plain_text, key, &nonce)
+    }
+
+    /// Like [`encrypt`](Self::encrypt), with a nonce chosen by the caller. The nonce must
+    /// never repeat under one key.
+    pub fn encrypt_with_nonce(
+        &self,
+        mut plain_text: Vec<u8>,
+        key: [u8; BLOCK_SIZE],
+        nonce: &[u8],
+    ) -> Vec<u8> {
+        self.apply_keystream(key, nonce, &mut plain_text);
+        [nonce, &plain_text].concat()
+    }
+
+    pub fn decrypt(
+        &self,
+        mut cipher_text: Vec<u8>,
+        key: [u8; BLOCK_SIZE],
+    ) -> Result<Vec<u8>, MalformedCiphertext> {
+        if cipher_text.len() < self.nonce_size {
+            return Err(MalformedCiphertext);
+        }
+        let mut body = cipher_text.split_off(self.nonce_size);
+        self.apply_keystream(key, &cipher_text, &mut body);
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [4u8; BLOCK_SIZE];
+
+    #[test]
+    fn test_default_matches_ctr_encrypt() {
+        let nonce = [9u8; NONCE_SIZE];
+        let plain_text = b"the crate's own counter layout".to_vec();
+        let cipher_text = CtrParams::new().encrypt_with_nonce(plain_text.clone(), KEY, &nonce);
+        assert_eq!(
+            cipher_text,
+            crate::ctr_encrypt_with_nonce(plain_text.clone(), KEY, nonce)
+        );
+        assert_eq!(CtrParams::new().decrypt(cipher_text, KEY), Ok(plain_text));
+    }
+
+    #[test]
+    #[should_panic(expected = "too likely to repeat")]
+    fn test_short_random_nonce_is_refused() {
+        CtrParams::new()
+            .with_nonce_size(MIN_NONCE_SIZE)
+            .encrypt(b"birthday".to_vec(), KEY);
+    }
+
+    #[test]
+    fn test_twelve_byte_nonce() {
+        // Cross-checked with Python's `cryptography`, whose CTR mode counts the whole block in
+        // big-endian: with a 4-byte counter starting at zero the two agree.
+        let params = CtrParams::new()
+            .with_nonce_size(12)
+            .with_big_endian_counter();
+        let cipher_text = params.encrypt_with_nonce(
+            b"twelve-byte nonce, four-byte counter".to_vec(),
+            KEY,
+            &[1u8; 12],
+        );
+        assert_eq!(
+            cipher_text[12..],
+            [
+                0x13, 0x7a, 0x9c, 0xcd, 0x80, 0xdb, 0xa2, 0x52, 0x1a, 0x85, 0x62, 0xb5, 0x62, 0xca,
+                0x8b, 0xa6, 0x48, 0x04, 0x2f, 0x45, 0xd1, 0x15, 0x61, 0xe2, 0x1e, 0x69, 0xed, 0x8d,
+                0x6e, 0x22, 0x12, 0x4e, 0xaf, 0x8f, 0xf6, 0xd2,
+            ]
+        );
+        assert_eq!(
+            params.decrypt(cipher_text, KEY).unwrap(),
+            b"twelve-byte nonce, four-byte counter"
+        );
+        assert_eq!(params.decrypt(vec![0u8; 11], KEY), Err(MalformedCiphertext));
+        assert_eq!(params.max_blocks(), 1 << 32);
+        assert_eq!(
+            params.counter_block(&[0u8; 12], 0x0102),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]
+        );
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_initial_counter_vectors() {
+        // RFC 3686 test vector #2: a 4-byte nonce and 8-byte IV, and a counter starting at 1.
+        let params = CtrParams::new()
+            .with_nonce_size(12)
+            .with_big_endian_counter()
+            .with_initial_counter(1);
+        let key = hex("7e24067817fae0d743d6ce1f32539163").try_into().unwrap();
+        let cipher_text =
+            params.encrypt_with_nonce((0..32).collect(), key, &hex("006cb6dbc0543b59da48d90b"));
+        assert_eq!(
+            cipher_text[12..],
+            hex("5104a106168a72d9790d41ee8edad388eb2e1efc46da57c8fce630df9141be28")
+        );
+
+        // NIST SP 800-38A F.5.1, whose initial counter block is f0f1...ff. Resuming at the
+        // second block gives the rest of the ciphertext.
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c").try_into().unwrap();
+        let nonce = hex("f0f1f2f3f4f5f6f7");
+        let params = CtrParams::new()
+            .with_big_endian_counter()
+            .with_initial_counter(0xf8f9fafbfcfdfeff);
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        params.apply_keystream(key, &nonce, &mut data);
+        assert_eq!(
+            data,
+            hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff")
+        );
+        let mut second = hex("ae2d8a571e03ac9c9eb76fac45af8e51");
+        params
+            .with_initial_counter(params.initial_counter() + 1)
+            .apply_keystream(key, &nonce, &mut second);
+        assert_eq!(second, data[16..]);
+    }
+}
@@ -0,0 +1,185 @@
+//! Key files protected by the Windows Data Protection API.
+//!
+//! [`KeyManager::seal`] needs a key-encryption key, which raises the question of where _that_
+//! key lives. On Windows the answer can be DPAPI: [`CryptProtectData`] encrypts under a key
+//! derived from the user's logon credentials, so a key file written with [`save_key_file`]
+//! can only be read back by the same user account, normally on the same machine, and is
+//! useless to anyone who copies the file elsewhere. DPAPI also authenticates what it protects,
+//! so a modified file fails to load.
+//!
+//! ```text
+//! magic "AMDP" | version | DPAPI blob of the keyring
+//! ```
+//!
+//! This only moves the problem to the account's password: anything running as the user can
+//! read the keys. It protects key files at rest, in backups, and on stolen disks.
+//!
+//! [`CryptProtectData`]: https://learn.microsoft.com/en-us/windows/win32/api/dpapi/nf-dpapi-cryptprotectdata
+
+use std::{ffi::c_void, fs, io, path::Path, ptr, slice};
+
+use zeroize::Zeroizing;
+
+use crate::keys::KeyManager;
+
+const MAGIC: &[u8; 4] = b"AMDP";
+/// The format version written by [`save_key_file`].
+pub const VERSION: u8 = 1;
+/// DPAPI must fail rather than show a prompt, since there may be no one to answer it.
+const CRYPTPROTECT_UI_FORBIDDEN: u32 = 0x1;
+
+#[repr(C)]
+struct DataBlob {
+    len: u32,
+    data: *mut u8,
+}
+
+impl DataBlob {
+    /// A blob pointing into `data`, which DPAPI only reads.
+    fn borrowing(data: &[u8]) -> Self {
+        DataBlob {
+            len: data.len() as u32,
+            data: data.as_ptr() as *mut u8,
+        }
+    }
+}
+
+#[link(name = "crypt32")]
+extern "system" {
+    fn CryptProtectData(
+        data_in: *const DataBlob,
+        description: *const u16,
+        entropy: *const DataBlob,
+        reserved: *mut c_void,
+        prompt: *mut c_void,
+        flags: u32,
+        data_out: *mut DataBlob,
+    ) -> i32;
+
+    fn CryptUnprotectData(
+        data_in: *const DataBlob,
+        description: *mut *mut u16,
+        entropy: *const DataBlob,
+        reserved: *mut c_void,
+        prompt: *mut c_void,
+        flags: u32,
+        data_out: *mut DataBlob,
+    ) -> i32;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn LocalFree(memory: *mut c_void) -> *mut c_void;
+}
+
+/// Runs a DPAPI call that fills in `output`, and copies the result out of the memory DPAPI
+/// allocated for it.
+fn call(f: impl FnOnce(*mut DataBlob) -> i32) -> io::Result<Vec<u8>> {
+    let mut output = DataBlob {
+        len: 0,
+        data: ptr::null_mut(),
+    };
+    if f(&mut output) == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: on success DPAPI points `output` at `len` bytes it allocated with LocalAlloc.
+    unsafe {
+        let data = slice::from_raw_parts(output.data, output.len as usize).to_vec();
+        LocalFree(output.data.cast());
+        Ok(data)
+    }
+}
+
+/// Encrypts `data` for the current user. `entropy`, if not empty, must be given again to
+/// [`unprotect`], so other programs running as the user can't simply ask DPAPI for the data.
+pub fn protect(data: &[u8], entropy: &[u8]) -> io::Result<Vec<u8>> {
+    let description: Vec<u16> = "aes-modes keyring\0".encode_utf16().collect();
+    let input = DataBlob::borrowing(data);
+    let entropy = DataBlob::borrowing(entropy);
+    call(|output| {
+        // SAFETY: every pointer is either null or valid for the duration of the call.
+        unsafe {
+            CryptProtectData(
+                &input,
+                description.as_ptr(),
+                &entropy,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        }
+    })
+}
+
+/// Decrypts what [`protect`] returned, failing unless the current user protected it with the
+/// same `entropy` and it wasn't modified.
+pub fn unprotect(protected: &[u8], entropy: &[u8]) -> io::Result<Vec<u8>> {
+    let input = DataBlob::borrowing(protected);
+    let entropy = DataBlob::borrowing(entropy);
+    call(|output| {
+        // SAFETY: as in `protect`; no description is asked for, so none needs freeing.
+        unsafe {
+            CryptUnprotectData(
+                &input,
+                ptr::null_mut(),
+                &entropy,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        }
+    })
+}
+
+/// Writes all of `keyring`'s keys, policies and usage counts to `path`, protected for the
+/// current user. See [`protect`] for `entropy`.
+pub fn save_key_file(path: &Path, keyring: &KeyManager, entropy: &[u8]) -> io::Result<()> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.extend(protect(&keyring.to_bytes(), entropy)?);
+    fs::write(path, bytes)
+}
+
+/// Reads a keyring written by [`save_key_file`]. Fails with
+/// [`InvalidData`](io::ErrorKind::InvalidData) if the file isn't a key file, and with the
+/// DPAPI error if another user wrote it, the entropy differs, or it was modified.
+pub fn load_key_file(path: &Path, entropy: &[u8]) -> io::Result<KeyManager> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not an aes-modes key file");
+    let bytes = fs::read(path)?;
+    let protected = bytes
+        .strip_prefix(MAGIC.as_slice())
+        .and_then(|rest| rest.strip_prefix(&[VERSION]))
+        .ok_or_else(invalid)?;
+    let keyring = Zeroizing::new(unprotect(protected, entropy)?);
+    KeyManager::from_bytes(&keyring).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyPolicy;
+
+    #[test]
+    fn test_round_trip() {
+        let protected = protect(b"secret", b"entropy").unwrap();
+        assert_eq!(unprotect(&protected, b"entropy").unwrap(), b"secret");
+        assert!(unprotect(&protected, b"other entropy").is_err());
+        let mut tampered = protected.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(unprotect(&tampered, b"entropy").is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let mut keyring = KeyManager::new();
+        keyring.generate(KeyPolicy::default());
+        let path = std::env::temp_dir().join("aes-modes-dpapi-test.key");
+        save_key_file(&path, &keyring, b"").unwrap();
+        let loaded = load_key_file(&path, b"");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), keyring);
+    }
+}
@@ -0,0 +1,302 @@
+//! Ciphers chosen at runtime, behind one object-safe trait.
+//!
+//! The modes in [`generic`] are generic over the cipher, which suits code that
+//! knows its algorithm when it is compiled. Plugin systems, FFI layers and services that read
+//! the algorithm from configuration don't, and need one type for all of them. [`new_cipher`]
+//! takes a [registry](crate::registry) name and a key of whatever size, checks both, and
+//! returns a `Box<dyn DynCipher>`:
+//!
+//! ```
+//! use aes_modes::dyn_cipher::new_cipher;
+//!
+//! let cipher = new_cipher("aes-128-gcm", &[7; 16]).unwrap();
+//! let cipher_text = cipher.encrypt(b"hello", b"header").unwrap();
+//! assert_eq!(cipher.decrypt(&cipher_text, b"header").unwrap(), b"hello");
+//! ```
+//!
+//! Every cipher picks its own IV or nonce and puts it in front of the ciphertext, in the
+//! formats of [`generic`] for the block modes and `nonce | ciphertext | tag`
+//! for GCM. SIV is deterministic and needs no nonce. Only the authenticated ciphers take
+//! associated data; the others return [`DynCipherError::AssociatedData`] rather than ignore
+//! it. The tweakable ciphers take a tweak per sector instead of a nonce, and aren't offered.
+
+use std::{error::Error, fmt};
+
+use aes::{
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE},
+    generic,
+    registry::{algorithm, Algorithm},
+    siv::{siv_decrypt, siv_encrypt, SIV_KEY_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+/// Why a cipher could not be made, or a message encrypted or decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynCipherError {
+    /// The name isn't an algorithm compiled in, or it is a tweakable cipher.
+    UnknownAlgorithm,
+    /// The key isn't one of the algorithm's key sizes.
+    KeySize,
+    /// Associated data was given to an unauthenticated cipher.
+    AssociatedData,
+    /// The ciphertext's length or padding doesn't fit the cipher.
+    Malformed,
+    /// The ciphertext or associated data was modified.
+    Authentication,
+}
+
+impl fmt::Display for DynCipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DynCipherError::UnknownAlgorithm => f.write_str("unknown algorithm"),
+            DynCipherError::KeySize => f.write_str("wrong key size for the algorithm"),
+            DynCipherError::AssociatedData => {
+                f.write_str("associated data given to an unauthenticated cipher")
+            }
+            DynCipherError::Malformed => f.write_str("malformed ciphertext"),
+            DynCipherError::Authentication => f.write_str("ciphertext failed authentication"),
+        }
+    }
+}
+
+impl Error for DynCipherError {}
+
+impl From<generic::MalformedCiphertext> for DynCipherError {
+    fn from(_: generic::MalformedCiphertext) -> Self {
+        DynCipherError::Malformed
+    }
+}
+
+/// A cipher with its key, whatever the algorithm. Shareable between threads.
+pub trait DynCipher: Send + Sync {
+    /// The algorithm's entry in the [registry](crate::registry).
+    fn algorithm(&self) -> Algorithm;
+
+    /// Encrypts under a fresh IV or nonce. `aad` must be empty unless the cipher is
+    /// authenticated.
+    fn encrypt(&self, plain_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError>;
+
+    fn decrypt(&self, cipher_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError>;
+}
+
+/// Makes the cipher named `name`, keyed with `key`.
+pub fn new_cipher(name: &str, key: &[u8]) -> Result<Box<dyn DynCipher>, DynCipherError> {
+    let algorithm = algorithm(name).ok_or(DynCipherError::UnknownAlgorithm)?;
+    if !algorithm.key_sizes.contains(&key.len()) {
+        return Err(DynCipherError::KeySize);
+    }
+    let block_mode = |mode| -> Box<dyn DynCipher> {
+        Box::new(BlockMode {
+            algorithm,
+            mode,
+            cipher: Aes128::new_from_slice(key).unwrap(),
+        })
+    };
+    Ok(match name {
+        "aes-128-ecb" => block_mode(Mode::Ecb),
+        "aes-128-cbc" => block_mode(Mode::Cbc),
+        "aes-128-ctr" => block_mode(Mode::Ctr),
+        "aes-128-gcm" => Box::new(Gcm(key.try_into().unwrap())),
+        "aes-siv" => Box::new(Siv(key.try_into().unwrap())),
+        #[cfg(feature = "sm4")]
+        "sm4" => Box::new(BlockMode {
+            algorithm,
+            mode: Mode::Cbc,
+            cipher: generic::Sm4::new_from_slice(key).unwrap(),
+        }),
+        #[cfg(feature = "camellia")]
+        "camellia" => match key.len() {
+            16 => Box::new(BlockMode {
+                algorithm,
+                mode: Mode::Cbc,
+                cipher: generic::Camellia128::new_from_slice(key).unwrap(),
+            }),
+            24 => Box::new(BlockMode {
+                algorithm,
+                mode: Mode::Cbc,
+                cipher: generic::Camellia192::new_from_slice(key).unwrap(),
+            }),
+            _ => Box::new(BlockMode {
+                algorithm,
+                mode: Mode::Cbc,
+                cipher: generic::Camellia256::new_from_slice(key).unwrap(),
+            }),
+        },
+        #[cfg(feature = "legacy")]
+        "3des" => Box::new(Tdes(key.try_into().unwrap())),
+        _ => return Err(DynCipherError::UnknownAlgorithm),
+    })
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
+/// A mode of any block cipher, through [`generic`](crate::generic).
+struct BlockMode<C> {
+    algorithm: Algorithm,
+    mode: Mode,
+    cipher: C,
+}
+
+impl<C: BlockEncrypt + BlockDecrypt + Send + Sync> DynCipher for BlockMode<C> {
+    fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    fn encrypt(&self, plain_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        if !aad.is_empty() {
+            return Err(DynCipherError::AssociatedData);
+        }
+        Ok(match self.mode {
+            Mode::Ecb => generic::ecb_encrypt(&self.cipher, plain_text.to_vec()),
+            Mode::Cbc => generic::cbc_encrypt(&self.cipher, plain_text.to_vec()),
+            Mode::Ctr => generic::ctr_encrypt(&self.cipher, plain_text),
+        })
+    }
+
+    fn decrypt(&self, cipher_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        if !aad.is_empty() {
+            return Err(DynCipherError::AssociatedData);
+        }
+        Ok(match self.mode {
+            Mode::Ecb => generic::ecb_decrypt(&self.cipher, cipher_text.to_vec())?,
+            Mode::Cbc => generic::cbc_decrypt(&self.cipher, cipher_text.to_vec())?,
+            Mode::Ctr => generic::ctr_decrypt(&self.cipher, cipher_text)?,
+        })
+    }
+}
+
+struct Gcm([u8; BLOCK_SIZE]);
+
+impl DynCipher for Gcm {
+    fn algorithm(&self) -> Algorithm {
+        algorithm("aes-128-gcm").unwrap()
+    }
+
+    fn encrypt(&self, plain_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        let mut nonce = [0u8; GCM_NONCE_SIZE];
+        utils::fill_random(&mut nonce);
+        let mut cipher_text = nonce.to_vec();
+        cipher_text.extend(gcm_encrypt(plain_text.to_vec(), self.0, nonce, aad));
+        Ok(cipher_text)
+    }
+
+    fn decrypt(&self, cipher_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        let (nonce, body) = cipher_text
+            .split_first_chunk::<GCM_NONCE_SIZE>()
+            .ok_or(DynCipherError::Malformed)?;
+        gcm_decrypt(body.to_vec(), self.0, *nonce, aad).map_err(|_| DynCipherError::Authentication)
+    }
+}
+
+struct Siv([u8; SIV_KEY_SIZE]);
+
+impl DynCipher for Siv {
+    fn algorithm(&self) -> Algorithm {
+        algorithm("aes-siv").unwrap()
+    }
+
+    fn encrypt(&self, plain_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        Ok(siv_encrypt(plain_text, &self.0, &[aad]))
+    }
+
+    fn decrypt(&self, cipher_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        siv_decrypt(cipher_text, &self.0, &[aad]).map_err(|_| DynCipherError::Authentication)
+    }
+}
+
+/// Through [`legacy`](crate::legacy), so every use is warned about.
+#[cfg(feature = "legacy")]
+struct Tdes([u8; crate::legacy::TDES_KEY_SIZE]);
+
+#[cfg(feature = "legacy")]
+#[allow(deprecated)]
+impl DynCipher for Tdes {
+    fn algorithm(&self) -> Algorithm {
+        algorithm("3des").unwrap()
+    }
+
+    fn encrypt(&self, plain_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        if !aad.is_empty() {
+            return Err(DynCipherError::AssociatedData);
+        }
+        Ok(crate::legacy::tdes_cbc_encrypt(
+            &self.0,
+            plain_text.to_vec(),
+        ))
+    }
+
+    fn decrypt(&self, cipher_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, DynCipherError> {
+        if !aad.is_empty() {
+            return Err(DynCipherError::AssociatedData);
+        }
+        Ok(crate::legacy::tdes_cbc_decrypt(
+            &self.0,
+            cipher_text.to_vec(),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{algorithms, Kind};
+
+    #[test]
+    fn test_every_algorithm_round_trips() {
+        // 3DES would add to the warnings the legacy tests count.
+        for algorithm in algorithms().into_iter().filter(|a| a.name != "3des") {
+            let key = vec![3; algorithm.key_sizes[0]];
+            let cipher = match new_cipher(algorithm.name, &key) {
+                Err(DynCipherError::UnknownAlgorithm) if algorithm.kind == Kind::Tweakable => {
+                    continue
+                }
+                cipher => cipher.unwrap(),
+            };
+            assert_eq!(cipher.algorithm(), algorithm);
+            let aad: &[u8] = if algorithm.authenticated { b"aad" } else { b"" };
+            let cipher_text = cipher.encrypt(b"runtime choice", aad).unwrap();
+            assert_eq!(
+                cipher.decrypt(&cipher_text, aad).unwrap(),
+                b"runtime choice",
+                "{}",
+                algorithm.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            new_cipher("rot13", &[0; 16]).err(),
+            Some(DynCipherError::UnknownAlgorithm)
+        );
+        assert_eq!(
+            new_cipher("aes-128-cbc", &[0; 32]).err(),
+            Some(DynCipherError::KeySize)
+        );
+        let cbc = new_cipher("aes-128-cbc", &[0; 16]).unwrap();
+        assert_eq!(
+            cbc.encrypt(b"", b"aad"),
+            Err(DynCipherError::AssociatedData)
+        );
+        assert_eq!(cbc.decrypt(&[0; 5], b""), Err(DynCipherError::Malformed));
+
+        let gcm = new_cipher("aes-128-gcm", &[0; 16]).unwrap();
+        let cipher_text = gcm.encrypt(b"message", b"one").unwrap();
+        assert_eq!(
+            gcm.decrypt(&cipher_text, b"two"),
+            Err(DynCipherError::Authentication)
+        );
+        assert_eq!(gcm.decrypt(&[0; 4], b""), Err(DynCipherError::Malformed));
+    }
+}
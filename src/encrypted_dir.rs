@@ -0,0 +1,399 @@
+//! A whole directory tree, encrypted file by file, as seen through its plaintext paths.
+//!
+//! This is the storage stack put together: file contents in the [`file_handle`] format, and
+//! names under a [`DirectoryCipher`]. On disk, every directory of the tree is a directory, and
+//! every file a file, like gocryptfs does it:
+//!
+//! ```text
+//! <root>/aes-modes.check             the root's encrypted name for ".", to spot a wrong secret
+//! <root>/aes-modes.dirid             16 random bytes, the directory's ID
+//! <root>/<encrypted name>            a file, in the file_handle format
+//! <root>/<encrypted name>/           a subdirectory, with its own aes-modes.dirid
+//! ```
+//!
+//! Names are encrypted under the ID of the directory that holds them, so moving an encrypted
+//! entry to another directory makes it undecryptable, while renaming a directory on disk
+//! keeps everything inside it readable. Each file derives its own key from the ID in its
+//! header, so files can be renamed without touching their contents. Entries whose names don't
+//! decrypt, such as files copied in from elsewhere, are left out of [`list`].
+//!
+//! What stays visible on disk: the shape of the tree, the number of entries in each
+//! directory, sizes to within a padding block for names and to the byte for contents, and the
+//! modification times and permissions. The `aes-fuse` binary, with the `fuse` feature, mounts
+//! an [`EncryptedDirectory`] as a plaintext view of the tree.
+//!
+//! [`file_handle`]: crate::file_handle
+//! [`list`]: EncryptedDirectory::list
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    file_handle::{EncryptedFileHandle, FILE_ID_SIZE},
+    files::{self, Durability},
+    names::DirectoryCipher,
+    utils,
+};
+
+/// The file in every directory of the tree that holds its ID.
+pub const DIRECTORY_ID_FILE: &str = "aes-modes.dirid";
+const CHECK_FILE: &str = "aes-modes.check";
+/// The longest encrypted name most file systems accept.
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A file or directory of the tree, with its plaintext name and size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub kind: EntryKind,
+    /// The plaintext length of a file, and 0 for a directory.
+    pub len: u64,
+    pub modified: SystemTime,
+    pub permissions: fs::Permissions,
+}
+
+/// A directory tree whose names and contents are encrypted under one master secret.
+pub struct EncryptedDirectory {
+    root: PathBuf,
+    secret: Zeroizing<Vec<u8>>,
+    cipher: DirectoryCipher,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_directory_id(directory: &Path) -> io::Result<[u8; FILE_ID_SIZE]> {
+    fs::read(directory.join(DIRECTORY_ID_FILE))?
+        .try_into()
+        .map_err(|_| invalid_data("malformed directory ID"))
+}
+
+/// The names along `path`, with `/` and `.` skipped.
+fn names(path: &Path) -> io::Result<Vec<&str>> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "file names must be UTF-8")
+            })),
+            Component::RootDir | Component::CurDir => None,
+            Component::ParentDir | Component::Prefix(_) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "paths must not leave the tree",
+            ))),
+        })
+        .collect()
+}
+
+/// Removes a stored directory, if it holds nothing but its ID.
+fn remove_empty_dir(backing: &Path) -> io::Result<()> {
+    for stored in fs::read_dir(backing)? {
+        if stored?.file_name() != DIRECTORY_ID_FILE {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                "the directory isn't empty",
+            ));
+        }
+    }
+    fs::remove_file(backing.join(DIRECTORY_ID_FILE))?;
+    fs::remove_dir(backing)
+}
+
+impl EncryptedDirectory {
+    /// Starts a new, empty tree at `root`, which must not exist yet or be empty.
+    pub fn create(root: impl Into<PathBuf>, master_secret: &[u8]) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        if fs::read_dir(&root)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the directory isn't empty",
+            ));
+        }
+        let directory = Self::from_parts(root, master_secret);
+        let root_id = utils::create_rand_key();
+        fs::write(directory.root.join(DIRECTORY_ID_FILE), root_id)?;
+        fs::write(
+            directory.root.join(CHECK_FILE),
+            directory.cipher.encrypt_name(&root_id, "."),
+        )?;
+        Ok(directory)
+    }
+
+    /// Opens the tree at `root`, failing with [`InvalidData`](io::ErrorKind::InvalidData) if
+    /// it was created under another secret.
+    pub fn open(root: impl Into<PathBuf>, master_secret: &[u8]) -> io::Result<Self> {
+        let directory = Self::from_parts(root.into(), master_secret);
+        let root_id = read_directory_id(&directory.root)?;
+        let check = fs::read(directory.root.join(CHECK_FILE))?;
+        if check != directory.cipher.encrypt_name(&root_id, ".").as_bytes() {
+            return Err(invalid_data("wrong secret for this encrypted directory"));
+        }
+        Ok(directory)
+    }
+
+    fn from_parts(root: PathBuf, master_secret: &[u8]) -> Self {
+        EncryptedDirectory {
+            root,
+            secret: Zeroizing::new(master_secret.to_vec()),
+            cipher: DirectoryCipher::new(master_secret),
+        }
+    }
+
+    /// Where the entry at the plaintext `path` is stored.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut backing = self.root.clone();
+        for name in names(path)? {
+            let encrypted_name = self
+                .cipher
+                .encrypt_name(&read_directory_id(&backing)?, name);
+            if encrypted_name.len() > MAX_NAME_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidFilename,
+                    "the file name is too long to encrypt",
+                ));
+            }
+            backing.push(encrypted_name);
+        }
+        Ok(backing)
+    }
+
+    fn entry(&self, name: String, backing: &Path) -> io::Result<Entry> {
+        let metadata = fs::symlink_metadata(backing)?;
+        let (kind, len) = if metadata.is_dir() {
+            (EntryKind::Directory, 0)
+        } else {
+            let file = EncryptedFileHandle::open(&self.secret, File::open(backing)?)?;
+            (EntryKind::File, file.len())
+        };
+        Ok(Entry {
+            name,
+            kind,
+            len,
+            modified: metadata.modified()?,
+            permissions: metadata.permissions(),
+        })
+    }
+
+    /// The entry at `path`. An empty path is the root.
+    pub fn metadata(&self, path: &Path) -> io::Result<Entry> {
+        let name = names(path)?.last().unwrap_or(&"").to_string();
+        self.entry(name, &self.resolve(path)?)
+    }
+
+    /// The entries of the directory at `path`, sorted by name.
+    pub fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        let backing = self.resolve(path)?;
+        let id = read_directory_id(&backing)?;
+        let mut entries = Vec::new();
+        for stored in fs::read_dir(&backing)? {
+            let stored = stored?;
+            let Some(name) = stored
+                .file_name()
+                .to_str()
+                .and_then(|encrypted| self.cipher.decrypt_name(&id, encrypted).ok())
+            else {
+                continue;
+            };
+            entries.push(self.entry(name, &stored.path())?);
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Creates a new, empty file at `path`, which must not exist yet.
+    pub fn create_file(&self, path: &Path) -> io::Result<EncryptedFileHandle<File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.resolve(path)?)?;
+        EncryptedFileHandle::create(&self.secret, file)
+    }
+
+    /// Opens the file at `path` for reading and writing.
+    pub fn open_file(&self, path: &Path) -> io::Result<EncryptedFileHandle<File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.resolve(path)?)?;
+        EncryptedFileHandle::open(&self.secret, file)
+    }
+
+    /// Truncates or extends the file at `path` to `len` bytes, extending it with zeros.
+    pub fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut file = self.open_file(path)?;
+        if len >= file.len() {
+            let gap = len - file.len();
+            file.seek(SeekFrom::End(0))?;
+            io::copy(&mut io::repeat(0).take(gap), &mut file)?;
+            return file.flush();
+        }
+        // Sectors can't be cut off in place, so the part that stays is copied to a new file,
+        // which then replaces the old one in a single rename.
+        let backing = self.resolve(path)?;
+        let permissions = fs::metadata(&backing)?.permissions();
+        files::write_atomically(&backing, Durability::Atomic, |temporary| {
+            temporary.set_permissions(permissions)?;
+            let mut truncated = EncryptedFileHandle::create(&self.secret, temporary)?;
+            io::copy(&mut (&mut file).take(len), &mut truncated)?;
+            truncated.flush()
+        })
+    }
+
+    pub fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        fs::set_permissions(self.resolve(path)?, permissions)
+    }
+
+    /// Creates a new, empty directory at `path`.
+    pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let backing = self.resolve(path)?;
+        fs::create_dir(&backing)?;
+        fs::write(backing.join(DIRECTORY_ID_FILE), utils::create_rand_key()).inspect_err(|_| {
+            let _ = fs::remove_dir_all(&backing);
+        })
+    }
+
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.resolve(path)?)
+    }
+
+    /// Removes the directory at `path`, which must be empty.
+    pub fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let backing = self.resolve(path)?;
+        remove_empty_dir(&backing)
+    }
+
+    /// Moves the entry at `from` to `to`, replacing a file there, or an empty directory if
+    /// `from` is a directory too.
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (self.resolve(from)?, self.resolve(to)?);
+        if from.is_dir() && to.is_dir() && from != to {
+            remove_empty_dir(&to)?;
+        }
+        fs::rename(from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    fn scratch(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "aes-modes-{}-{}",
+            name,
+            utils::base64url_encode(&utils::create_rand_key())
+        ))
+    }
+
+    #[test]
+    fn test_tree_round_trips_with_names_hidden() {
+        let root = scratch("dir");
+        let tree = EncryptedDirectory::create(&root, SECRET).unwrap();
+        tree.create_dir(Path::new("reports")).unwrap();
+        let mut file = tree
+            .create_file(Path::new("reports/q3 budget.txt"))
+            .unwrap();
+        file.write_all(b"confidential numbers").unwrap();
+        file.flush().unwrap();
+
+        let tree = EncryptedDirectory::open(&root, SECRET).unwrap();
+        let listed = tree.list(Path::new("/reports")).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            (listed[0].name.as_str(), listed[0].kind, listed[0].len),
+            ("q3 budget.txt", EntryKind::File, 20)
+        );
+        let mut read = String::new();
+        tree.open_file(Path::new("reports/q3 budget.txt"))
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, "confidential numbers");
+
+        // Nothing on disk carries a plaintext name or plaintext contents.
+        let mut pending = vec![root.clone()];
+        while let Some(directory) = pending.pop() {
+            for stored in fs::read_dir(directory).unwrap() {
+                let stored = stored.unwrap();
+                let name = stored.file_name().into_string().unwrap();
+                assert!(!name.contains("reports") && !name.contains("budget"));
+                if stored.path().is_dir() {
+                    pending.push(stored.path());
+                } else {
+                    let bytes = fs::read(stored.path()).unwrap();
+                    assert!(!bytes.windows(12).any(|window| window == b"confidential"));
+                }
+            }
+        }
+
+        assert_eq!(
+            EncryptedDirectory::open(&root, b"another secret")
+                .err()
+                .map(|error| error.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rename_and_set_len() {
+        let root = scratch("dir");
+        let tree = EncryptedDirectory::create(&root, SECRET).unwrap();
+        tree.create_dir(Path::new("a")).unwrap();
+        let mut file = tree.create_file(Path::new("a/notes")).unwrap();
+        file.write_all(&[5; 10_000]).unwrap();
+        file.flush().unwrap();
+
+        // Renaming a directory keeps the names inside it readable.
+        tree.rename(Path::new("a"), Path::new("b")).unwrap();
+        assert_eq!(tree.list(Path::new("b")).unwrap()[0].name, "notes");
+        assert_eq!(
+            tree.metadata(Path::new("a")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        tree.create_dir(Path::new("c")).unwrap();
+        tree.create_file(Path::new("c/x")).unwrap();
+        assert_eq!(
+            tree.rename(Path::new("b"), Path::new("c"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::DirectoryNotEmpty
+        );
+
+        let path = Path::new("b/notes");
+        tree.set_len(path, 5000).unwrap();
+        tree.set_len(path, 6000).unwrap();
+        let mut read = Vec::new();
+        tree.open_file(path)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        let expected = [vec![5; 5000], vec![0; 1000]].concat();
+        assert_eq!(read, expected);
+        // The truncated copy replaced the file, with no temporary left behind.
+        let stored = tree.resolve(Path::new("b")).unwrap();
+        assert_eq!(fs::read_dir(stored).unwrap().count(), 2);
+
+        tree.remove_file(path).unwrap();
+        tree.remove_dir(Path::new("b")).unwrap();
+        assert_eq!(tree.list(Path::new("")).unwrap().len(), 1);
+        assert!(tree.metadata(Path::new("../escape")).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,1458 @@
+//! A self-describing container for a ciphertext.
+//!
+//! The mode functions at the crate root return bare bytes, and whoever decrypts them has to
+//! know out of band which mode and key were used. An [`Envelope`] carries that along with the
+//! ciphertext: the mode, the ID of the key (its version in a [`KeyManager`](crate::keys)),
+//! the IV or nonce, the ciphertext itself and, for GCM, the tag.
+//!
+//! Envelopes have a compact binary encoding of their own:
+//!
+//! ```text
+//! "AMEV" | version | mode | key ID (u32) | nonce length (u8) | nonce | tag length (u8) | tag
+//!        | wrapped key length (u8) | wrapped key | check value length (u8) | check value
+//!        | length padding (u8) | padding quantum (u32) | validity flags (u8)
+//!        | not before (u64) | not after (u64) | chunk count (u32)
+//!        | for each chunk: length (u32) | chunk
+//! ```
+//!
+//! The padding quantum is only there for [`LengthPadding::Multiple`], and the validity flags
+//! say which of the two times follow: bit 0 for not before, bit 1 for not after. Version 1 had no wrapped key, version 2 no check value, version 3 no length padding
+//! and version 4 no validity; they are all still read.
+//!
+//! With the `protobuf` feature they can also be encoded as the `Envelope` message from
+//! `proto/envelope.proto`, so services that already speak protobuf can embed them in their own
+//! messages. The `cbor` and `msgpack` features add CBOR and MessagePack encodings through
+//! serde, for systems that prefer those. All the encodings carry the same fields, with the
+//! mode as the same small integer.
+//!
+//! CTR envelopes may use any nonce size [`CtrParams`] supports, with the crate's
+//! little-endian counter in the rest of the block. The nonce length field records the split,
+//! so [`Envelope::seal_ctr`] needs no new field and older readers still reject what they can't
+//! handle.
+//!
+//! The ciphertext may be split into several chunks, for transports that limit message sizes.
+//! They are simply concatenated before decryption.
+//!
+//! [`Envelope::split`] separates the header from the ciphertext, for systems that keep the
+//! metadata in a database and the bulk bytes in object storage, and [`Envelope::join`] puts
+//! them back together without copying the ciphertext. The header has the same fields as the
+//! envelope, up to the chunks, under a magic of its own:
+//!
+//! ```text
+//! "AMEH" | version | ... | not after (u64) | chunk count (u32) | for each chunk: length (u32)
+//! ```
+//!
+//! Only GCM authenticates the body against its header. The other modes decrypt whatever body
+//! of the right length they are joined with.
+//!
+//! [`seal_with_ephemeral_key`] encrypts every message under its own random data key, and
+//! stores that key in the envelope wrapped with AES-KW under a key-encryption key. A data key
+//! that leaks then exposes one message, and the KEK only ever touches 16-byte keys.
+//!
+//! [`Envelope::with_check_value`] records the key's [KCV](crate::keys::check_value), so
+//! [`Envelope::open`] can reject the wrong key with [`EnvelopeError::WrongKey`] before
+//! producing any output. That matters most for ECB, CBC and CTR, which would otherwise
+//! decrypt to garbage without complaint.
+//!
+//! [`Envelope::seal_with_padding`] pads the plaintext with a [`LengthPadding`] scheme first,
+//! so the ciphertext doesn't give away the exact length. The envelope records the scheme, and
+//! GCM authenticates it.
+//!
+//! [`Envelope::seal_with_validity`] adds a [`Validity`] period: the envelope opens only from
+//! `not_before` to `not_after`, as told by a [`Clock`], which is what bearer tokens and license
+//! blobs need. GCM authenticates the times, and they are only checked once the rest of the
+//! envelope has been, so an envelope that is reported as expired really was sealed to expire.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    analysis::KeyFingerprint,
+    clock::{Clock, SystemClock},
+    ctr::{CtrParams, MAX_NONCE_SIZE, MIN_NONCE_SIZE},
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE, TAG_SIZE},
+    keys::{check_value, KCV_SIZE},
+    keywrap::{unwrap_key, wrap_key, HardwareKey, KeyWrapError, WRAPPED_KEY_SIZE},
+    length_padding::LengthPadding,
+    limits::Limits,
+    metrics::{self, Direction},
+    policy::{Policy, PolicyViolation},
+    utils, Mode, BLOCK_SIZE, NONCE_SIZE,
+};
+
+pub(crate) const MAGIC: &[u8; 4] = b"AMEV";
+const HEADER_MAGIC: &[u8; 4] = b"AMEH";
+/// The first format version with detached headers.
+const FIRST_HEADER_VERSION: u8 = 5;
+/// The format version written by [`Envelope::to_bytes`]. Older versions are still read.
+pub const VERSION: u8 = 5;
+
+/// How the ciphertext in an envelope was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EnvelopeMode {
+    Ecb,
+    Cbc,
+    Ctr,
+    Gcm,
+}
+
+impl EnvelopeMode {
+    pub const ALL: [EnvelopeMode; 4] = [
+        EnvelopeMode::Ecb,
+        EnvelopeMode::Cbc,
+        EnvelopeMode::Ctr,
+        EnvelopeMode::Gcm,
+    ];
+
+    /// Short lowercase name, as used in logs and media type parameters.
+    pub fn name(self) -> &'static str {
+        match self {
+            EnvelopeMode::Ecb => "ecb",
+            EnvelopeMode::Cbc => "cbc",
+            EnvelopeMode::Ctr => "ctr",
+            EnvelopeMode::Gcm => "gcm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    /// The number used in both encodings. Zero is left unused, as protobuf wants.
+    fn id(self) -> u8 {
+        match self {
+            EnvelopeMode::Ecb => 1,
+            EnvelopeMode::Cbc => 2,
+            EnvelopeMode::Ctr => 3,
+            EnvelopeMode::Gcm => 4,
+        }
+    }
+
+    fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(EnvelopeMode::Ecb),
+            2 => Some(EnvelopeMode::Cbc),
+            3 => Some(EnvelopeMode::Ctr),
+            4 => Some(EnvelopeMode::Gcm),
+            _ => None,
+        }
+    }
+
+    fn nonce_size(self) -> usize {
+        match self {
+            EnvelopeMode::Ecb => 0,
+            EnvelopeMode::Cbc => BLOCK_SIZE,
+            EnvelopeMode::Ctr => NONCE_SIZE,
+            EnvelopeMode::Gcm => GCM_NONCE_SIZE,
+        }
+    }
+
+    /// The unauthenticated mode at the crate root that does the work, if any.
+    fn block_mode(self) -> Option<Mode> {
+        match self {
+            EnvelopeMode::Ecb => Some(Mode::Ecb),
+            EnvelopeMode::Cbc => Some(Mode::Cbc),
+            EnvelopeMode::Ctr => Some(Mode::Ctr),
+            EnvelopeMode::Gcm => None,
+        }
+    }
+
+    fn tag_size(self) -> usize {
+        match self {
+            EnvelopeMode::Gcm => TAG_SIZE,
+            _ => 0,
+        }
+    }
+}
+
+impl From<Mode> for EnvelopeMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Ecb => EnvelopeMode::Ecb,
+            Mode::Cbc => EnvelopeMode::Cbc,
+            Mode::Ctr => EnvelopeMode::Ctr,
+        }
+    }
+}
+
+/// Why an envelope could not be decoded or opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The encoding is invalid, or the fields don't fit the mode.
+    Malformed,
+    /// The envelope was written by a newer format version.
+    UnsupportedVersion(u8),
+    /// The GCM tag didn't match: wrong key, or the envelope was modified.
+    Authentication,
+    /// The key doesn't match the envelope's check value.
+    WrongKey,
+    /// The envelope's mode or parameters aren't allowed by the [`Policy`] it was opened under.
+    Policy(PolicyViolation),
+    /// The envelope is over the [`Limits`] it was parsed with, or too large to allocate.
+    TooLarge,
+    /// The envelope is authentic, but its validity period hasn't started yet.
+    NotYetValid { not_before: u64 },
+    /// The envelope is authentic, but its validity period is over.
+    Expired { not_after: u64 },
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed => f.write_str("malformed envelope"),
+            EnvelopeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {}", version)
+            }
+            EnvelopeError::Authentication => f.write_str("envelope failed authentication"),
+            EnvelopeError::WrongKey => f.write_str("key does not match the envelope"),
+            EnvelopeError::Policy(violation) => violation.fmt(f),
+            EnvelopeError::TooLarge => f.write_str("envelope exceeds the parsing limits"),
+            EnvelopeError::NotYetValid { not_before } => {
+                write!(f, "envelope is not valid before {}", not_before)
+            }
+            EnvelopeError::Expired { not_after } => {
+                write!(f, "envelope expired after {}", not_after)
+            }
+        }
+    }
+}
+
+impl Error for EnvelopeError {}
+
+/// When an envelope may be opened, in seconds since the Unix epoch, both ends included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Validity {
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+}
+
+const NOT_BEFORE_FLAG: u8 = 1;
+const NOT_AFTER_FLAG: u8 = 2;
+
+impl Validity {
+    /// Valid from `not_before` until `not_after`.
+    pub fn between(not_before: u64, not_after: u64) -> Self {
+        Validity {
+            not_before: Some(not_before),
+            not_after: Some(not_after),
+        }
+    }
+
+    /// Valid from now, as `clock` tells it, for `seconds`.
+    pub fn for_seconds(clock: &impl Clock, seconds: u64) -> Self {
+        let now = clock.now();
+        Self::between(now, now.saturating_add(seconds))
+    }
+
+    /// Whether neither end is set, as in envelopes sealed without a validity period.
+    pub fn is_unbounded(&self) -> bool {
+        *self == Validity::default()
+    }
+
+    pub fn check(&self, now: u64) -> Result<(), EnvelopeError> {
+        if let Some(not_before) = self.not_before.filter(|&not_before| now < not_before) {
+            return Err(EnvelopeError::NotYetValid { not_before });
+        }
+        if let Some(not_after) = self.not_after.filter(|&not_after| now > not_after) {
+            return Err(EnvelopeError::Expired { not_after });
+        }
+        Ok(())
+    }
+
+    /// The flags and the times that are set, as in the binary encoding and the GCM AAD.
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![0];
+        if let Some(not_before) = self.not_before {
+            bytes[0] |= NOT_BEFORE_FLAG;
+            bytes.extend_from_slice(&not_before.to_be_bytes());
+        }
+        if let Some(not_after) = self.not_after {
+            bytes[0] |= NOT_AFTER_FLAG;
+            bytes.extend_from_slice(&not_after.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+/// A ciphertext together with everything needed to decrypt it, except the key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        into = "serde_record::EnvelopeRecord",
+        try_from = "serde_record::EnvelopeRecord"
+    )
+)]
+pub struct Envelope {
+    pub mode: EnvelopeMode,
+    pub key_id: u32,
+    /// The IV for CBC, the nonce for CTR and GCM, and empty for ECB. CTR nonces are 8 bytes
+    /// unless sealed with [`Envelope::seal_ctr`].
+    pub nonce: Vec<u8>,
+    pub chunks: Vec<Vec<u8>>,
+    /// The GCM tag, and empty for the other modes.
+    pub tag: Vec<u8>,
+    /// The data key wrapped under the key `key_id` names, for envelopes made by
+    /// [`seal_with_ephemeral_key`]. Empty when `key_id` names the data key itself.
+    pub wrapped_key: Vec<u8>,
+    /// The [check value](crate::keys::check_value) of the key that opens the envelope, or
+    /// empty. It isn't authenticated: changing it can only make `open` refuse the right key.
+    pub check_value: Vec<u8>,
+    /// The padding added to the plaintext before encryption, which `open` removes.
+    pub padding: LengthPadding,
+    /// When `open` accepts the envelope. Unbounded unless it was sealed with
+    /// [`Envelope::seal_with_validity`].
+    pub validity: Validity,
+}
+
+impl Envelope {
+    /// Encrypts `plain_text` under `key`, recording `key_id` so the reader can find the key.
+    /// GCM also authenticates the mode and key ID.
+    pub fn seal(
+        mode: EnvelopeMode,
+        key_id: u32,
+        key: [u8; BLOCK_SIZE],
+        plain_text: Vec<u8>,
+    ) -> Self {
+        Self::seal_with_wrapped_key(
+            mode,
+            key_id,
+            Vec::new(),
+            LengthPadding::None,
+            Validity::default(),
+            key,
+            plain_text,
+        )
+    }
+
+    /// Like [`seal`](Self::seal), padding `plain_text` first to hide its length.
+    pub fn seal_with_padding(
+        padding: LengthPadding,
+        mode: EnvelopeMode,
+        key_id: u32,
+        key: [u8; BLOCK_SIZE],
+        plain_text: Vec<u8>,
+    ) -> Self {
+        Self::seal_with_wrapped_key(
+            mode,
+            key_id,
+            Vec::new(),
+            padding,
+            Validity::default(),
+            key,
+            plain_text,
+        )
+    }
+
+    /// Encrypts with GCM, which authenticates `validity` so [`open`](Self::open) can enforce
+    /// it. The other modes couldn't stop anyone from changing the times.
+    pub fn seal_with_validity(
+        validity: Validity,
+        key_id: u32,
+        key: [u8; BLOCK_SIZE],
+        plain_text: Vec<u8>,
+    ) -> Self {
+        Self::seal_with_wrapped_key(
+            EnvelopeMode::Gcm,
+            key_id,
+            Vec::new(),
+            LengthPadding::None,
+            validity,
+            key,
+            plain_text,
+        )
+    }
+
+    /// Like [`seal`](Self::seal), refusing a mode `policy` doesn't allow.
+    pub fn seal_with_policy(
+        policy: &Policy,
+        mode: EnvelopeMode,
+        key_id: u32,
+        key: [u8; BLOCK_SIZE],
+        plain_text: Vec<u8>,
+    ) -> Result<Self, PolicyViolation> {
+        policy.check_envelope_mode(mode)?;
+        Ok(Self::seal(mode, key_id, key, plain_text))
+    }
+
+    /// Encrypts with CTR under a random `nonce_size`-byte nonce, for readers that expect a split
+    /// other than the default 8 bytes of nonce and 8 of counter.
+    ///
+    /// # Panics
+    ///
+    /// If [`CtrParams`] doesn't support `nonce_size`, or it is below
+    /// [`MIN_RANDOM_NONCE_SIZE`](crate::ctr::MIN_RANDOM_NONCE_SIZE).
+    pub fn seal_ctr(
+        key_id: u32,
+        nonce_size: usize,
+        key: [u8; BLOCK_SIZE],
+        plain_text: Vec<u8>,
+    ) -> Self {
+        let mut nonce = CtrParams::new()
+            .with_nonce_size(nonce_size)
+            .encrypt(plain_text, key);
+        let cipher_text = nonce.split_off(nonce_size);
+        let envelope = Envelope {
+            mode: EnvelopeMode::Ctr,
+            key_id,
+            nonce,
+            chunks: vec![cipher_text],
+            tag: Vec::new(),
+            wrapped_key: Vec::new(),
+            check_value: Vec::new(),
+            padding: LengthPadding::None,
+            validity: Validity::default(),
+        };
+        envelope.record(Direction::Encrypt, &key, Ok(envelope.chunks[0].len()));
+        envelope
+    }
+
+    fn seal_with_wrapped_key(
+        mode: EnvelopeMode,
+        key_id: u32,
+        wrapped_key: Vec<u8>,
+        padding: LengthPadding,
+        validity: Validity,
+        key: [u8; BLOCK_SIZE],
+        plain_text: Vec<u8>,
+    ) -> Self {
+        let plain_text_len = plain_text.len();
+        let plain_text = padding.pad(plain_text);
+        let (nonce, mut cipher_text) = match mode.block_mode() {
+            Some(inner) => {
+                let mut cipher_text = inner.encrypt(plain_text, key);
+                let body = cipher_text.split_off(mode.nonce_size());
+                (cipher_text, body)
+            }
+            None => {
+                let nonce = utils::create_rand_gcm_nonce();
+                let aad = Self::aad(mode, key_id, &wrapped_key, padding, validity);
+                (nonce.to_vec(), gcm_encrypt(plain_text, key, nonce, &aad))
+            }
+        };
+        let tag = cipher_text.split_off(cipher_text.len() - mode.tag_size());
+        let envelope = Envelope {
+            mode,
+            key_id,
+            nonce,
+            chunks: vec![cipher_text],
+            tag,
+            wrapped_key,
+            check_value: Vec::new(),
+            padding,
+            validity,
+        };
+        envelope.record(Direction::Encrypt, &key, Ok(plain_text_len));
+        envelope
+    }
+
+    /// Reports a use of `key` on this envelope to the [`metrics`] sink.
+    fn record(
+        &self,
+        direction: Direction,
+        key: &[u8; BLOCK_SIZE],
+        result: Result<usize, EnvelopeError>,
+    ) {
+        metrics::record(|| metrics::Event {
+            direction,
+            mode: self.mode,
+            key: KeyFingerprint {
+                key_id: self.key_id,
+                check_value: if self.wrapped_key.is_empty() {
+                    check_value(key).to_vec()
+                } else {
+                    Vec::new()
+                },
+            },
+            bytes: *result.as_ref().unwrap_or(&0),
+            failure: result.err(),
+        });
+    }
+
+    /// Records the check value of `key`, which should be the key the envelope was sealed with.
+    pub fn with_check_value(mut self, key: &[u8; BLOCK_SIZE]) -> Self {
+        self.check_value = check_value(key).to_vec();
+        self
+    }
+
+    /// Unpadded envelopes leave the padding out, so they authenticate as before version 4, and
+    /// unbounded ones the validity, as before version 5.
+    fn aad(
+        mode: EnvelopeMode,
+        key_id: u32,
+        wrapped_key: &[u8],
+        padding: LengthPadding,
+        validity: Validity,
+    ) -> Vec<u8> {
+        let mut aad = MAGIC.to_vec();
+        aad.push(mode.id());
+        aad.extend_from_slice(&key_id.to_be_bytes());
+        aad.extend_from_slice(wrapped_key);
+        if padding != LengthPadding::None {
+            aad.push(padding.id());
+        }
+        if padding.quantum() != 0 {
+            aad.extend_from_slice(&padding.quantum().to_be_bytes());
+        }
+        if !validity.is_unbounded() {
+            aad.extend(validity.to_bytes());
+        }
+        aad
+    }
+
+    /// Checks that the nonce, tag and ciphertext have the sizes the mode needs.
+    fn check(&self) -> Result<(), EnvelopeError> {
+        let len: usize = self.chunks.iter().map(Vec::len).sum();
+        let blocks_ok = match self.mode {
+            EnvelopeMode::Ecb | EnvelopeMode::Cbc => len > 0 && len.is_multiple_of(BLOCK_SIZE),
+            EnvelopeMode::Ctr | EnvelopeMode::Gcm => true,
+        };
+        let nonce_ok = match self.mode {
+            EnvelopeMode::Ctr => (MIN_NONCE_SIZE..=MAX_NONCE_SIZE).contains(&self.nonce.len()),
+            _ => self.nonce.len() == self.mode.nonce_size(),
+        };
+        if !nonce_ok
+            || self.tag.len() != self.mode.tag_size()
+            || ![0, WRAPPED_KEY_SIZE].contains(&self.wrapped_key.len())
+            || ![0, KCV_SIZE].contains(&self.check_value.len())
+            || !blocks_ok
+            || (self.mode != EnvelopeMode::Gcm && !self.validity.is_unbounded())
+        {
+            return Err(EnvelopeError::Malformed);
+        }
+        Ok(())
+    }
+
+    /// Decrypts the envelope. Only GCM detects a modified ciphertext, but any mode detects a
+    /// wrong key if the envelope has a check value. The [`validity`](Self::validity) is
+    /// checked against the system clock.
+    pub fn open(&self, key: [u8; BLOCK_SIZE]) -> Result<Vec<u8>, EnvelopeError> {
+        self.open_with_clock(key, &SystemClock)
+    }
+
+    /// Like [`open`](Self::open), checking the validity against `clock`.
+    pub fn open_with_clock(
+        &self,
+        key: [u8; BLOCK_SIZE],
+        clock: &impl Clock,
+    ) -> Result<Vec<u8>, EnvelopeError> {
+        let result = self.decrypt(key, clock);
+        self.record(
+            Direction::Decrypt,
+            &key,
+            result.as_ref().map(Vec::len).map_err(|error| *error),
+        );
+        result
+    }
+
+    fn decrypt(&self, key: [u8; BLOCK_SIZE], clock: &impl Clock) -> Result<Vec<u8>, EnvelopeError> {
+        self.check()?;
+        if !self.check_value.is_empty() && self.check_value != check_value(&key) {
+            return Err(EnvelopeError::WrongKey);
+        }
+        let cipher_text = self.chunks.concat();
+        let plain_text = match self.mode.block_mode() {
+            Some(Mode::Ctr) => {
+                let params = CtrParams::new().with_nonce_size(self.nonce.len());
+                let mut plain_text = cipher_text;
+                params.apply_keystream(key, &self.nonce, &mut plain_text);
+                plain_text
+            }
+            Some(inner) => inner.decrypt([self.nonce.clone(), cipher_text].concat(), key),
+            None => gcm_decrypt(
+                [cipher_text, self.tag.clone()].concat(),
+                key,
+                self.nonce.as_slice().try_into().unwrap(),
+                &Self::aad(
+                    self.mode,
+                    self.key_id,
+                    &self.wrapped_key,
+                    self.padding,
+                    self.validity,
+                ),
+            )
+            .map_err(|_| EnvelopeError::Authentication)?,
+        };
+        let plain_text = self
+            .padding
+            .unpad(plain_text)
+            .map_err(|_| EnvelopeError::Malformed)?;
+        self.validity.check(clock.now())?;
+        Ok(plain_text)
+    }
+
+    /// Like [`open`](Self::open), refusing an envelope whose mode `policy` doesn't allow,
+    /// before decrypting anything.
+    pub fn open_with_policy(
+        &self,
+        key: [u8; BLOCK_SIZE],
+        policy: &Policy,
+    ) -> Result<Vec<u8>, EnvelopeError> {
+        policy
+            .check_envelope_mode(self.mode)
+            .map_err(EnvelopeError::Policy)?;
+        if self.mode == EnvelopeMode::Gcm {
+            policy
+                .check_tag_len(self.tag.len())
+                .map_err(EnvelopeError::Policy)?;
+        }
+        self.open(key)
+    }
+
+    /// Decodes the binary encoding, refusing a format version, mode or tag length `policy`
+    /// doesn't allow before decoding the rest. See [Downgrades](crate::policy#downgrades).
+    pub fn from_bytes_with_policy(bytes: &[u8], policy: &Policy) -> Result<Self, EnvelopeError> {
+        if let Some(&version) = bytes.get(MAGIC.len()).filter(|_| bytes.starts_with(MAGIC)) {
+            policy
+                .check_envelope_version(version)
+                .map_err(EnvelopeError::Policy)?;
+        }
+        let envelope = Self::from_bytes(bytes)?;
+        policy
+            .check_envelope_mode(envelope.mode)
+            .map_err(EnvelopeError::Policy)?;
+        if envelope.mode == EnvelopeMode::Gcm {
+            policy
+                .check_tag_len(envelope.tag.len())
+                .map_err(EnvelopeError::Policy)?;
+        }
+        Ok(envelope)
+    }
+
+    /// The binary encoding from the [module documentation](self).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.fields_to_bytes(MAGIC);
+        bytes.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(chunk);
+        }
+        bytes
+    }
+
+    /// Everything up to the chunks, which envelopes and detached headers share.
+    fn fields_to_bytes(&self, magic: &[u8; 4]) -> Vec<u8> {
+        let mut bytes = magic.to_vec();
+        bytes.push(VERSION);
+        bytes.push(self.mode.id());
+        bytes.extend_from_slice(&self.key_id.to_be_bytes());
+        bytes.push(self.nonce.len() as u8);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.push(self.tag.len() as u8);
+        bytes.extend_from_slice(&self.tag);
+        bytes.push(self.wrapped_key.len() as u8);
+        bytes.extend_from_slice(&self.wrapped_key);
+        bytes.push(self.check_value.len() as u8);
+        bytes.extend_from_slice(&self.check_value);
+        bytes.push(self.padding.id());
+        if self.padding.quantum() != 0 {
+            bytes.extend_from_slice(&self.padding.quantum().to_be_bytes());
+        }
+        bytes.extend(self.validity.to_bytes());
+        bytes
+    }
+
+    /// Decodes the binary encoding, within the default [`Limits`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        Self::from_bytes_with_limits(bytes, &Limits::default())
+    }
+
+    /// Like [`from_bytes`](Self::from_bytes), failing with [`EnvelopeError::TooLarge`] if the
+    /// envelope is larger or has more chunks than `limits` allow.
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &Limits) -> Result<Self, EnvelopeError> {
+        if bytes.len() > limits.max_message_size() {
+            return Err(EnvelopeError::TooLarge);
+        }
+        let mut reader = Reader { rest: bytes };
+        let mut envelope = Self::read_fields(&mut reader, MAGIC, 1)?;
+        let count = reader.u32()? as usize;
+        if count > limits.max_chunks() {
+            return Err(EnvelopeError::TooLarge);
+        }
+        for _ in 0..count {
+            let len = reader.u32()? as usize;
+            let chunk = reader.take(len)?;
+            let mut copy = Vec::new();
+            copy.try_reserve_exact(len)
+                .map_err(|_| EnvelopeError::TooLarge)?;
+            copy.extend_from_slice(chunk);
+            envelope.chunks.push(copy);
+        }
+        if !reader.rest.is_empty() {
+            return Err(EnvelopeError::Malformed);
+        }
+        envelope.check()?;
+        Ok(envelope)
+    }
+
+    /// Reads everything up to the chunks, from format `first_version` on, and returns an
+    /// envelope without any.
+    fn read_fields(
+        reader: &mut Reader,
+        magic: &[u8; 4],
+        first_version: u8,
+    ) -> Result<Self, EnvelopeError> {
+        if reader.take(4)? != magic {
+            return Err(EnvelopeError::Malformed);
+        }
+        let version = reader.byte()?;
+        if !(first_version..=VERSION).contains(&version) {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        let mode = EnvelopeMode::from_id(reader.byte()? as u32).ok_or(EnvelopeError::Malformed)?;
+        let key_id = reader.u32()?;
+        let nonce_len = reader.byte()? as usize;
+        let nonce = reader.take(nonce_len)?.to_vec();
+        let tag_len = reader.byte()? as usize;
+        let tag = reader.take(tag_len)?.to_vec();
+        let wrapped_key = if version >= 2 {
+            let wrapped_len = reader.byte()? as usize;
+            reader.take(wrapped_len)?.to_vec()
+        } else {
+            Vec::new()
+        };
+        let check_value = if version >= 3 {
+            let check_len = reader.byte()? as usize;
+            reader.take(check_len)?.to_vec()
+        } else {
+            Vec::new()
+        };
+        let padding = if version >= 4 {
+            let id = reader.byte()? as u32;
+            let quantum = if id == LengthPadding::MULTIPLE_ID {
+                reader.u32()?
+            } else {
+                0
+            };
+            LengthPadding::from_id(id, quantum).ok_or(EnvelopeError::Malformed)?
+        } else {
+            LengthPadding::None
+        };
+        let mut validity = Validity::default();
+        if version >= 5 {
+            let flags = reader.byte()?;
+            if flags & !(NOT_BEFORE_FLAG | NOT_AFTER_FLAG) != 0 {
+                return Err(EnvelopeError::Malformed);
+            }
+            if flags & NOT_BEFORE_FLAG != 0 {
+                validity.not_before = Some(reader.u64()?);
+            }
+            if flags & NOT_AFTER_FLAG != 0 {
+                validity.not_after = Some(reader.u64()?);
+            }
+        }
+        Ok(Envelope {
+            mode,
+            key_id,
+            nonce,
+            chunks: Vec::new(),
+            tag,
+            wrapped_key,
+            check_value,
+            padding,
+            validity,
+        })
+    }
+
+    /// Separates the envelope into a detached header and the ciphertext body, as described
+    /// in the [module documentation](self). A single chunk, as [`seal`](Self::seal) makes,
+    /// becomes the body without being copied.
+    pub fn split(self) -> (Vec<u8>, Vec<u8>) {
+        let mut header = self.fields_to_bytes(HEADER_MAGIC);
+        header.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+        for chunk in &self.chunks {
+            header.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        }
+        let body = if self.chunks.len() == 1 {
+            self.chunks.into_iter().next().unwrap()
+        } else {
+            self.chunks.concat()
+        };
+        (header, body)
+    }
+
+    /// Puts an envelope back together from what [`split`](Self::split) returned. The body
+    /// must be exactly as long as the header says, and only the chunks after the first are
+    /// copied out of it.
+    pub fn join(header: &[u8], mut body: Vec<u8>) -> Result<Self, EnvelopeError> {
+        let limits = Limits::default();
+        let mut reader = Reader { rest: header };
+        let mut envelope = Self::read_fields(&mut reader, HEADER_MAGIC, FIRST_HEADER_VERSION)?;
+        let count = reader.u32()? as usize;
+        if count > limits.max_chunks() {
+            return Err(EnvelopeError::TooLarge);
+        }
+        let lengths = (0..count)
+            .map(|_| reader.u32().map(|len| len as usize))
+            .collect::<Result<Vec<_>, _>>()?;
+        let len = lengths
+            .iter()
+            .try_fold(0usize, |sum, &len| sum.checked_add(len));
+        if !reader.rest.is_empty() || len != Some(body.len()) {
+            return Err(EnvelopeError::Malformed);
+        }
+        if let Some((_, later)) = lengths.split_first() {
+            for &len in later.iter().rev() {
+                envelope.chunks.push(body.split_off(body.len() - len));
+            }
+            envelope.chunks.push(body);
+            envelope.chunks.reverse();
+        }
+        envelope.check()?;
+        Ok(envelope)
+    }
+}
+
+/// Reads an encoding front to back.
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], EnvelopeError> {
+        if self.rest.len() < n {
+            return Err(EnvelopeError::Malformed);
+        }
+        let (taken, rest) = self.rest.split_at(n);
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, EnvelopeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, EnvelopeError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, EnvelopeError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// A human-readable summary of the header fields, then a [hex dump](utils::hexdump) of each
+/// chunk. The alternate form `{:#}` colors the dump for a terminal.
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex =
+            |bytes: &[u8]| -> String { bytes.iter().map(|byte| format!("{:02x}", byte)).collect() };
+        writeln!(f, "{:?} envelope under key {}", self.mode, self.key_id)?;
+        for (name, field) in [
+            ("nonce", &self.nonce),
+            ("tag", &self.tag),
+            ("wrapped key", &self.wrapped_key),
+            ("check value", &self.check_value),
+        ] {
+            if !field.is_empty() {
+                writeln!(f, "{}: {}", name, hex(field))?;
+            }
+        }
+        if self.padding != LengthPadding::None {
+            write!(f, "length padding: {}", self.padding.name())?;
+            if let LengthPadding::Multiple(quantum) = self.padding {
+                write!(f, " of {}", quantum)?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(not_before) = self.validity.not_before {
+            writeln!(f, "not before: {}", not_before)?;
+        }
+        if let Some(not_after) = self.validity.not_after {
+            writeln!(f, "not after: {}", not_after)?;
+        }
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            writeln!(f, "chunk {}, {} bytes:", index, chunk.len())?;
+            f.write_str(&utils::hexdump(chunk, f.alternate()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Encrypts `plain_text` with AES-GCM under a fresh random data key, and stores the data key
+/// in the envelope wrapped under `kek`. `kek_id` is recorded as the envelope's key ID.
+pub fn seal_with_ephemeral_key<K: HardwareKey>(
+    kek: &K,
+    kek_id: u32,
+    plain_text: Vec<u8>,
+) -> Result<Envelope, K::Error> {
+    let data_key = utils::create_rand_key();
+    let wrapped_key = wrap_key(kek, &data_key)?.to_vec();
+    Ok(Envelope::seal_with_wrapped_key(
+        EnvelopeMode::Gcm,
+        kek_id,
+        wrapped_key,
+        LengthPadding::None,
+        Validity::default(),
+        data_key,
+        plain_text,
+    ))
+}
+
+/// Unwraps the data key with `kek` and decrypts. A wrong KEK, or any change to the
+/// envelope, fails with [`KeyWrapError::Integrity`].
+pub fn open_with_ephemeral_key<K: HardwareKey>(
+    kek: &K,
+    envelope: &Envelope,
+) -> Result<Vec<u8>, KeyWrapError<K::Error>> {
+    let wrapped: &[u8; WRAPPED_KEY_SIZE] = envelope
+        .wrapped_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| KeyWrapError::Truncated)?;
+    let data_key = unwrap_key(kek, wrapped)?;
+    envelope.open(data_key).map_err(|error| match error {
+        // The data key unwrapped fine, so a check value that doesn't match it was tampered with.
+        EnvelopeError::Authentication | EnvelopeError::WrongKey => KeyWrapError::Integrity,
+        _ => KeyWrapError::Truncated,
+    })
+}
+
+#[cfg(feature = "protobuf")]
+mod protobuf {
+    use prost::Message;
+
+    use super::*;
+
+    /// The `Envelope` message from `proto/envelope.proto`.
+    #[derive(Clone, PartialEq, Message)]
+    struct EnvelopeMessage {
+        #[prost(int32, tag = "1")]
+        mode: i32,
+        #[prost(uint32, tag = "2")]
+        key_id: u32,
+        #[prost(bytes = "vec", tag = "3")]
+        nonce: Vec<u8>,
+        #[prost(bytes = "vec", repeated, tag = "4")]
+        chunks: Vec<Vec<u8>>,
+        #[prost(bytes = "vec", tag = "5")]
+        tag: Vec<u8>,
+        #[prost(bytes = "vec", tag = "6")]
+        wrapped_key: Vec<u8>,
+        #[prost(bytes = "vec", tag = "7")]
+        check_value: Vec<u8>,
+        #[prost(uint32, tag = "8")]
+        padding: u32,
+        #[prost(uint64, optional, tag = "9")]
+        not_before: Option<u64>,
+        #[prost(uint64, optional, tag = "10")]
+        not_after: Option<u64>,
+        #[prost(uint32, tag = "11")]
+        padding_quantum: u32,
+    }
+
+    impl Envelope {
+        /// Encodes the envelope as the protobuf `aes_modes.v1.Envelope` message.
+        pub fn to_protobuf(&self) -> Vec<u8> {
+            EnvelopeMessage {
+                mode: self.mode.id() as i32,
+                key_id: self.key_id,
+                nonce: self.nonce.clone(),
+                chunks: self.chunks.clone(),
+                tag: self.tag.clone(),
+                wrapped_key: self.wrapped_key.clone(),
+                check_value: self.check_value.clone(),
+                padding: self.padding.id() as u32,
+                not_before: self.validity.not_before,
+                not_after: self.validity.not_after,
+                padding_quantum: self.padding.quantum(),
+            }
+            .encode_to_vec()
+        }
+
+        pub fn from_protobuf(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+            let message = EnvelopeMessage::decode(bytes).map_err(|_| EnvelopeError::Malformed)?;
+            let envelope = Envelope {
+                mode: u32::try_from(message.mode)
+                    .ok()
+                    .and_then(EnvelopeMode::from_id)
+                    .ok_or(EnvelopeError::Malformed)?,
+                key_id: message.key_id,
+                nonce: message.nonce,
+                chunks: message.chunks,
+                tag: message.tag,
+                wrapped_key: message.wrapped_key,
+                check_value: message.check_value,
+                padding: LengthPadding::from_id(message.padding, message.padding_quantum)
+                    .ok_or(EnvelopeError::Malformed)?,
+                validity: Validity {
+                    not_before: message.not_before,
+                    not_after: message.not_after,
+                },
+            };
+            envelope.check()?;
+            Ok(envelope)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_record {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    /// What serde sees of an [`Envelope`]: the mode as its ID, and all byte fields as byte
+    /// strings rather than arrays of numbers.
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub(super) struct EnvelopeRecord {
+        mode: u8,
+        key_id: u32,
+        nonce: ByteBuf,
+        chunks: Vec<ByteBuf>,
+        tag: ByteBuf,
+        #[serde(default)]
+        wrapped_key: ByteBuf,
+        #[serde(default)]
+        check_value: ByteBuf,
+        #[serde(default)]
+        padding: u8,
+        #[serde(default)]
+        not_before: Option<u64>,
+        #[serde(default)]
+        not_after: Option<u64>,
+        #[serde(default)]
+        padding_quantum: u32,
+    }
+
+    impl From<Envelope> for EnvelopeRecord {
+        fn from(envelope: Envelope) -> Self {
+            EnvelopeRecord {
+                mode: envelope.mode.id(),
+                key_id: envelope.key_id,
+                nonce: ByteBuf::from(envelope.nonce),
+                chunks: envelope.chunks.into_iter().map(ByteBuf::from).collect(),
+                tag: ByteBuf::from(envelope.tag),
+                wrapped_key: ByteBuf::from(envelope.wrapped_key),
+                check_value: ByteBuf::from(envelope.check_value),
+                padding: envelope.padding.id(),
+                not_before: envelope.validity.not_before,
+                not_after: envelope.validity.not_after,
+                padding_quantum: envelope.padding.quantum(),
+            }
+        }
+    }
+
+    impl TryFrom<EnvelopeRecord> for Envelope {
+        type Error = EnvelopeError;
+
+        fn try_from(record: EnvelopeRecord) -> Result<Self, EnvelopeError> {
+            let envelope = Envelope {
+                mode: EnvelopeMode::from_id(record.mode as u32).ok_or(EnvelopeError::Malformed)?,
+                key_id: record.key_id,
+                nonce: record.nonce.into_vec(),
+                chunks: record.chunks.into_iter().map(ByteBuf::into_vec).collect(),
+                tag: record.tag.into_vec(),
+                wrapped_key: record.wrapped_key.into_vec(),
+                check_value: record.check_value.into_vec(),
+                padding: LengthPadding::from_id(record.padding as u32, record.padding_quantum)
+                    .ok_or(EnvelopeError::Malformed)?,
+                validity: Validity {
+                    not_before: record.not_before,
+                    not_after: record.not_after,
+                },
+            };
+            envelope.check()?;
+            Ok(envelope)
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl Envelope {
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).expect("writing to a Vec can't fail");
+        bytes
+    }
+
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        ciborium::from_reader(bytes).map_err(|_| EnvelopeError::Malformed)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl Envelope {
+    /// Encodes the envelope as a MessagePack map, with the field names as keys.
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("envelopes always serialize")
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        rmp_serde::from_slice(bytes).map_err(|_| EnvelopeError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [7u8; BLOCK_SIZE];
+    const MODES: [EnvelopeMode; 4] = [
+        EnvelopeMode::Ecb,
+        EnvelopeMode::Cbc,
+        EnvelopeMode::Ctr,
+        EnvelopeMode::Gcm,
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        for mode in MODES {
+            let envelope = Envelope::seal(mode, 3, KEY, b"inside the envelope".to_vec());
+            assert_eq!(envelope.nonce.len(), mode.nonce_size());
+            assert_eq!(envelope.open(KEY).unwrap(), b"inside the envelope");
+
+            let decoded = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+            assert_eq!(decoded, envelope);
+        }
+    }
+
+    #[test]
+    fn test_chunks_and_authentication() {
+        let mut envelope = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, vec![9u8; 100]);
+        let cipher_text = envelope.chunks.remove(0);
+        envelope.chunks = cipher_text.chunks(30).map(<[u8]>::to_vec).collect();
+        assert_eq!(envelope.open(KEY).unwrap(), vec![9u8; 100]);
+
+        // The key ID is authenticated, so the envelope can't be pointed at another key.
+        envelope.key_id = 2;
+        assert_eq!(envelope.open(KEY), Err(EnvelopeError::Authentication));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = Envelope::seal(EnvelopeMode::Cbc, 1, KEY, vec![1, 2, 3]).to_bytes();
+        assert_eq!(
+            Envelope::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(EnvelopeError::Malformed)
+        );
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(
+            Envelope::from_bytes(&newer),
+            Err(EnvelopeError::UnsupportedVersion(VERSION + 1))
+        );
+        // The nonce length must match the mode.
+        let mut wrong_mode = bytes;
+        wrong_mode[5] = EnvelopeMode::Ctr.id();
+        assert_eq!(
+            Envelope::from_bytes(&wrong_mode),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        for mode in MODES {
+            let envelope = Envelope::seal(mode, 3, KEY, vec![5u8; 100]).with_check_value(&KEY);
+            let (header, body) = envelope.clone().split();
+            assert_eq!(body, envelope.chunks[0]);
+            assert_eq!(Envelope::join(&header, body).unwrap(), envelope);
+        }
+
+        let mut envelope = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, vec![9u8; 100]);
+        let cipher_text = envelope.chunks.remove(0);
+        envelope.chunks = cipher_text.chunks(30).map(<[u8]>::to_vec).collect();
+        let (header, body) = envelope.clone().split();
+        assert_eq!(body, cipher_text);
+        let joined = Envelope::join(&header, body.clone()).unwrap();
+        assert_eq!(joined, envelope);
+        assert_eq!(joined.open(KEY).unwrap(), vec![9u8; 100]);
+
+        assert_eq!(
+            Envelope::join(&header, body[1..].to_vec()),
+            Err(EnvelopeError::Malformed)
+        );
+        // A header isn't an envelope, nor the other way around.
+        assert_eq!(Envelope::from_bytes(&header), Err(EnvelopeError::Malformed));
+        assert_eq!(
+            Envelope::join(&envelope.to_bytes(), Vec::new()),
+            Err(EnvelopeError::Malformed)
+        );
+        let mut tampered = body;
+        tampered[0] ^= 1;
+        let joined = Envelope::join(&header, tampered).unwrap();
+        assert_eq!(joined.open(KEY), Err(EnvelopeError::Authentication));
+    }
+
+    #[test]
+    fn test_ephemeral_keys() {
+        let kek = [8u8; BLOCK_SIZE];
+        let first = seal_with_ephemeral_key(&kek, 5, b"per-message key".to_vec()).unwrap();
+        let second = seal_with_ephemeral_key(&kek, 5, b"per-message key".to_vec()).unwrap();
+        assert_ne!(first.wrapped_key, second.wrapped_key);
+        assert_eq!(first.key_id, 5);
+
+        let decoded = Envelope::from_bytes(&first.to_bytes()).unwrap();
+        assert_eq!(
+            open_with_ephemeral_key(&kek, &decoded).unwrap(),
+            b"per-message key"
+        );
+        assert_eq!(
+            open_with_ephemeral_key(&[0u8; BLOCK_SIZE], &decoded),
+            Err(KeyWrapError::Integrity)
+        );
+
+        // The wrapped key is authenticated too, so it can't be swapped for another one.
+        let mut swapped = first.clone();
+        swapped.wrapped_key = second.wrapped_key.clone();
+        assert_eq!(
+            open_with_ephemeral_key(&kek, &swapped),
+            Err(KeyWrapError::Integrity)
+        );
+    }
+
+    #[test]
+    fn test_reads_older_versions() {
+        let envelope = Envelope::seal(EnvelopeMode::Ctr, 1, KEY, b"old format".to_vec());
+        let mut bytes = envelope.to_bytes();
+        // Version 4 had no validity flags after the padding, version 3 no padding after the
+        // check value, version 2 no check value length after the wrapped key, and version 1 no
+        // wrapped key length after the tag either.
+        let wrapped_len = 4 + 1 + 1 + 4 + 1 + NONCE_SIZE + 1;
+        bytes[4] = 4;
+        bytes.remove(wrapped_len + 3);
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), envelope);
+        bytes[4] = 3;
+        bytes.remove(wrapped_len + 2);
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), envelope);
+        bytes[4] = 2;
+        bytes.remove(wrapped_len + 1);
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), envelope);
+        bytes[4] = 1;
+        bytes.remove(wrapped_len);
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), envelope);
+    }
+
+    #[test]
+    fn test_ctr_nonce_sizes() {
+        for nonce_size in [8, 12] {
+            let envelope = Envelope::seal_ctr(1, nonce_size, KEY, b"any split".to_vec());
+            let decoded = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+            assert_eq!(decoded.nonce.len(), nonce_size);
+            assert_eq!(decoded.open(KEY).unwrap(), b"any split");
+        }
+        // Random 4-byte nonces are refused, but envelopes that have one still open.
+        assert!(std::panic::catch_unwind(|| Envelope::seal_ctr(1, 4, KEY, Vec::new())).is_err());
+        let mut short = Envelope::seal_ctr(1, 8, KEY, Vec::new());
+        short.nonce.truncate(4);
+        assert_eq!(short.open(KEY).unwrap(), b"");
+        let mut too_long = Envelope::seal_ctr(1, 12, KEY, vec![1]);
+        too_long.nonce.push(0);
+        assert_eq!(too_long.open(KEY), Err(EnvelopeError::Malformed));
+    }
+
+    #[test]
+    fn test_check_value() {
+        for mode in MODES {
+            let envelope = Envelope::seal(mode, 1, KEY, b"checked".to_vec()).with_check_value(&KEY);
+            let decoded = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+            assert_eq!(decoded.check_value, check_value(&KEY));
+            assert_eq!(decoded.open(KEY).unwrap(), b"checked");
+            assert_eq!(
+                decoded.open([0u8; BLOCK_SIZE]),
+                Err(EnvelopeError::WrongKey)
+            );
+        }
+
+        let mut short = Envelope::seal(EnvelopeMode::Ctr, 1, KEY, vec![1]).with_check_value(&KEY);
+        short.check_value.pop();
+        assert_eq!(
+            Envelope::from_bytes(&short.to_bytes()),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_length_padding() {
+        for mode in MODES {
+            let short =
+                Envelope::seal_with_padding(LengthPadding::Padme, mode, 1, KEY, vec![1; 97]);
+            let long =
+                Envelope::seal_with_padding(LengthPadding::Padme, mode, 1, KEY, vec![2; 100]);
+            assert_eq!(short.to_bytes().len(), long.to_bytes().len(), "{:?}", mode);
+
+            let decoded = Envelope::from_bytes(&long.to_bytes()).unwrap();
+            assert_eq!(decoded.padding, LengthPadding::Padme);
+            assert_eq!(decoded.open(KEY), Ok(vec![2; 100]));
+        }
+
+        // GCM authenticates the scheme along with the header.
+        let mut envelope = Envelope::seal_with_padding(
+            LengthPadding::PowerOfTwo,
+            EnvelopeMode::Gcm,
+            1,
+            KEY,
+            vec![3; 5],
+        );
+        envelope.padding = LengthPadding::Padme;
+        assert_eq!(envelope.open(KEY), Err(EnvelopeError::Authentication));
+
+        // So is the quantum of a multiple, which follows the scheme in the encoding.
+        let multiple =
+            |quantum| LengthPadding::Multiple(std::num::NonZeroU32::new(quantum).unwrap());
+        let mut envelope =
+            Envelope::seal_with_padding(multiple(64), EnvelopeMode::Gcm, 1, KEY, vec![4; 5]);
+        let decoded = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+        assert_eq!(decoded, envelope);
+        assert_eq!(decoded.chunks[0].len(), 64);
+        envelope.padding = multiple(32);
+        assert_eq!(envelope.open(KEY), Err(EnvelopeError::Authentication));
+    }
+
+    #[test]
+    fn test_validity() {
+        use crate::clock::FixedClock;
+
+        let validity = Validity::for_seconds(&FixedClock(1000), 60);
+        let envelope = Envelope::seal_with_validity(validity, 1, KEY, b"token".to_vec());
+        let decoded = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+        assert_eq!(decoded.validity, Validity::between(1000, 1060));
+        for now in [1000, 1060] {
+            assert_eq!(
+                decoded.open_with_clock(KEY, &FixedClock(now)).unwrap(),
+                b"token"
+            );
+        }
+        assert_eq!(
+            decoded.open_with_clock(KEY, &FixedClock(999)),
+            Err(EnvelopeError::NotYetValid { not_before: 1000 })
+        );
+        assert_eq!(
+            decoded.open(KEY),
+            Err(EnvelopeError::Expired { not_after: 1060 })
+        );
+
+        // Extending the period breaks the tag, and other modes can't carry one at all.
+        let mut extended = decoded.clone();
+        extended.validity.not_after = None;
+        assert_eq!(
+            extended.open_with_clock(KEY, &FixedClock(2000)),
+            Err(EnvelopeError::Authentication)
+        );
+        let mut ctr = Envelope::seal(EnvelopeMode::Ctr, 1, KEY, b"token".to_vec());
+        ctr.validity = validity;
+        assert_eq!(
+            Envelope::from_bytes(&ctr.to_bytes()),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let mut envelope = Envelope::seal(EnvelopeMode::Ecb, 7, KEY, [[b'A'; 16]; 2].concat());
+        envelope.chunks[0].truncate(32);
+        let text = envelope.to_string();
+        assert!(text.starts_with("Ecb envelope under key 7\nchunk 0, 32 bytes:\n00000000 "));
+        assert!(text.ends_with("= block 0\n"));
+
+        let envelope = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, Vec::new());
+        let text = envelope.to_string();
+        assert!(text.contains(&format!("tag: {:02x}", envelope.tag[0])));
+        assert!(text.ends_with("chunk 0, 0 bytes:\n"));
+    }
+
+    #[test]
+    fn test_policy() {
+        let strict = Policy::strict();
+        assert_eq!(
+            Envelope::seal_with_policy(&strict, EnvelopeMode::Cbc, 1, KEY, vec![1]),
+            Err(PolicyViolation::AlgorithmNotAllowed("aes-128-cbc"))
+        );
+        let envelope =
+            Envelope::seal_with_policy(&strict, EnvelopeMode::Gcm, 1, KEY, vec![1]).unwrap();
+        assert_eq!(envelope.open_with_policy(KEY, &strict), Ok(vec![1]));
+
+        let ecb = Envelope::seal(EnvelopeMode::Ecb, 1, KEY, vec![2]);
+        assert_eq!(
+            ecb.open_with_policy(KEY, &Policy::compat()),
+            Err(EnvelopeError::Policy(PolicyViolation::AlgorithmNotAllowed(
+                "aes-128-ecb"
+            )))
+        );
+        assert_eq!(ecb.open_with_policy(KEY, &Policy::legacy()), Ok(vec![2]));
+
+        // A version 4 envelope, once every writer is on version 5.
+        let current = Policy::strict().with_min_envelope_version(VERSION);
+        let mut old = envelope.to_bytes();
+        old[4] = 4;
+        old.remove(4 + 1 + 1 + 4 + 1 + GCM_NONCE_SIZE + 1 + TAG_SIZE + 1 + 1 + 1);
+        assert_eq!(
+            Envelope::from_bytes_with_policy(&old, &current),
+            Err(EnvelopeError::Policy(PolicyViolation::VersionTooOld {
+                version: 4,
+                min: VERSION
+            }))
+        );
+        assert_eq!(
+            Envelope::from_bytes_with_policy(&old, &current.allow_downgrade()),
+            Ok(envelope)
+        );
+        assert_eq!(
+            Envelope::from_bytes_with_policy(&ecb.to_bytes(), &Policy::compat()),
+            Err(EnvelopeError::Policy(PolicyViolation::AlgorithmNotAllowed(
+                "aes-128-ecb"
+            )))
+        );
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn test_protobuf() {
+        for mode in MODES {
+            let envelope =
+                Envelope::seal(mode, 42, KEY, b"over protobuf".to_vec()).with_check_value(&KEY);
+            let encoded = envelope.to_protobuf();
+            assert_eq!(Envelope::from_protobuf(&encoded).unwrap(), envelope);
+        }
+        let padded = Envelope::seal_with_padding(
+            LengthPadding::Multiple(std::num::NonZeroU32::new(100).unwrap()),
+            EnvelopeMode::Gcm,
+            1,
+            KEY,
+            b"quantized".to_vec(),
+        );
+        assert_eq!(
+            Envelope::from_protobuf(&padded.to_protobuf()).unwrap(),
+            padded
+        );
+        // A bound of 0 is a bound, not a missing one.
+        let validity = Validity::between(0, 1060);
+        let envelope = Envelope::seal_with_validity(validity, 42, KEY, b"from 0".to_vec());
+        let decoded = Envelope::from_protobuf(&envelope.to_protobuf()).unwrap();
+        assert_eq!(decoded.validity, validity);
+        assert_eq!(decoded, envelope);
+        // Field 1 (mode), varint 0: the unspecified mode is rejected.
+        assert_eq!(
+            Envelope::from_protobuf(&[0x08, 0x00]),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        for mode in MODES {
+            let envelope = Envelope::seal(mode, 42, KEY, b"over cbor".to_vec());
+            assert_eq!(Envelope::from_cbor(&envelope.to_cbor()).unwrap(), envelope);
+        }
+        let mut wrong_nonce = Envelope::seal(EnvelopeMode::Gcm, 1, KEY, vec![1]);
+        wrong_nonce.nonce.push(0);
+        assert_eq!(
+            Envelope::from_cbor(&wrong_nonce.to_cbor()),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack() {
+        for mode in MODES {
+            let envelope = Envelope::seal(mode, 42, KEY, b"over msgpack".to_vec());
+            assert_eq!(
+                Envelope::from_msgpack(&envelope.to_msgpack()).unwrap(),
+                envelope
+            );
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! An encrypted file that can be used like any other file.
+//!
+//! The [`chunked`](crate::chunked) format is written once, front to back. Code that expects a
+//! file wants to seek around and overwrite bytes in the middle, which that format can't offer:
+//! its chunk nonces are derived from the chunk index, so rewriting a chunk in place would reuse
+//! a nonce. Here the file is split into _sectors_ instead, and every sector carries its own
+//! random nonce that is replaced each time the sector is written:
+//!
+//! ```text
+//! header:  magic "AMCF" | version | file ID (16 random bytes)
+//! sectors: nonce | ciphertext | tag, nonce | ciphertext | tag, ...
+//! ```
+//!
+//! Every sector authenticates the file ID and its own index, so sectors can't be moved around
+//! or copied between files. What this layout can't detect is a file that was truncated at a
+//! sector boundary, or a sector rolled back to an older version of itself: both would need
+//! state stored outside the file.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    thread,
+};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::{
+    chunked::StreamError,
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE, TAG_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+/// How much plaintext each sector holds.
+pub const SECTOR_SIZE: usize = 4096;
+
+pub const FILE_ID_SIZE: usize = 16;
+pub const HEADER_SIZE: usize = 4 + 1 + FILE_ID_SIZE;
+
+const MAGIC: &[u8; 4] = b"AMCF";
+const VERSION: u8 = 1;
+const KEY_LABEL: &[u8] = b"aes-modes file key";
+const SECTOR_OVERHEAD: usize = GCM_NONCE_SIZE + TAG_SIZE;
+
+/// The sector currently being read or written.
+struct Sector {
+    index: u64,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+/// Encrypts on write and decrypts on read, on top of any seekable file.
+///
+/// Writes are buffered one sector at a time. Like a [`BufWriter`](io::BufWriter), the handle
+/// writes back what is still buffered when it is dropped, and ignores any error doing so.
+/// Call [`close`](Self::close), [`into_inner`](Self::into_inner) or [`flush`](Write::flush)
+/// when done to find out whether everything was written.
+pub struct EncryptedFileHandle<F: Read + Write + Seek> {
+    /// Only `None` once [`into_inner`](Self::into_inner) has taken it.
+    inner: Option<F>,
+    key: Zeroizing<[u8; BLOCK_SIZE]>,
+    file_id: [u8; FILE_ID_SIZE],
+    position: u64,
+    len: u64,
+    sector: Option<Sector>,
+}
+
+impl<F: Read + Write + Seek> EncryptedFileHandle<F> {
+    /// Starts a new, empty encrypted file, overwriting whatever `inner` contained.
+    pub fn create(master_secret: &[u8], mut inner: F) -> io::Result<Self> {
+        let file_id = utils::create_rand_key();
+        inner.seek(SeekFrom::Start(0))?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        inner.write_all(&file_id)?;
+        Ok(Self::from_parts(master_secret, inner, file_id, 0))
+    }
+
+    /// Opens an existing encrypted file.
+    pub fn open(master_secret: &[u8], mut inner: F) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner
+            .read_exact(&mut header)
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::UnexpectedEof => StreamError::BadHeader.into(),
+                _ => error,
+            })?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(StreamError::BadHeader.into());
+        }
+        let mut file_id = [0u8; FILE_ID_SIZE];
+        file_id.copy_from_slice(&header[5..]);
+
+        // Every sector but the last is full, and none of them may be smaller than its overhead.
+        let body_len = inner.seek(SeekFrom::End(0))? - HEADER_SIZE as u64;
+        let sector_len = (SECTOR_SIZE + SECTOR_OVERHEAD) as u64;
+        let sectors = body_len.div_ceil(sector_len);
+        let last_len = body_len - sectors.saturating_sub(1) * sector_len;
+        if sectors > 0 && last_len <= SECTOR_OVERHEAD as u64 {
+            return Err(StreamError::Truncated.into());
+        }
+        let len = body_len - sectors * SECTOR_OVERHEAD as u64;
+
+        Ok(Self::from_parts(master_secret, inner, file_id, len))
+    }
+
+    fn from_parts(master_secret: &[u8], inner: F, file_id: [u8; FILE_ID_SIZE], len: u64) -> Self {
+        let mut key = Zeroizing::new([0u8; BLOCK_SIZE]);
+        Hkdf::<Sha256>::new(Some(&file_id), master_secret)
+            .expand(KEY_LABEL, key.as_mut())
+            .expect("16 bytes is a valid HKDF-SHA256 output length");
+        EncryptedFileHandle {
+            inner: Some(inner),
+            key,
+            file_id,
+            position: 0,
+            len,
+            sector: None,
+        }
+    }
+
+    /// The length of the plaintext, including anything written but not yet flushed.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Flushes and hands back the underlying file.
+    pub fn into_inner(mut self) -> io::Result<F> {
+        self.flush()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    /// Flushes and closes the file, reporting the errors that dropping it would ignore.
+    pub fn close(self) -> io::Result<()> {
+        self.into_inner().map(drop)
+    }
+
+    fn inner(&mut self) -> &mut F {
+        self.inner
+            .as_mut()
+            .expect("only into_inner takes the file, and it consumes the handle")
+    }
+
+    fn aad(&self, index: u64) -> Vec<u8> {
+        let mut aad = self.file_id.to_vec();
+        aad.extend_from_slice(&index.to_be_bytes());
+        aad
+    }
+
+    fn sector_offset(index: u64) -> u64 {
+        HEADER_SIZE as u64 + index * (SECTOR_SIZE + SECTOR_OVERHEAD) as u64
+    }
+
+    /// Writes the current sector back to the file, under a fresh nonce.
+    fn write_back(&mut self) -> io::Result<()> {
+        let Some(sector) = self.sector.as_mut().filter(|sector| sector.dirty) else {
+            return Ok(());
+        };
+        sector.dirty = false;
+        let (index, data) = (sector.index, sector.data.clone());
+
+        let nonce = utils::create_rand_gcm_nonce();
+        let sealed = gcm_encrypt(data, *self.key, nonce, &self.aad(index));
+        let inner = self.inner();
+        inner.seek(SeekFrom::Start(Self::sector_offset(index)))?;
+        inner.write_all(&nonce)?;
+        inner.write_all(&sealed)
+    }
+
+    /// Makes sector `index` the current one, reading and authenticating it if it exists.
+    fn load(&mut self, index: u64) -> io::Result<&mut Sector> {
+        if self
+            .sector
+            .as_ref()
+            .is_none_or(|sector| sector.index != index)
+        {
+            self.write_back()?;
+
+            let start = index * SECTOR_SIZE as u64;
+            let data = if start < self.len {
+                let plain_len = (self.len - start).min(SECTOR_SIZE as u64) as usize;
+                let mut sealed = vec![0u8; plain_len + SECTOR_OVERHEAD];
+                let inner = self.inner();
+                inner.seek(SeekFrom::Start(Self::sector_offset(index)))?;
+                inner.read_exact(&mut sealed)?;
+
+                let nonce: [u8; GCM_NONCE_SIZE] = sealed[..GCM_NONCE_SIZE].try_into().unwrap();
+                gcm_decrypt(
+                    sealed[GCM_NONCE_SIZE..].to_vec(),
+                    *self.key,
+                    nonce,
+                    &self.aad(index),
+                )
+                .map_err(|_| StreamError::Authentication { index })?
+            } else {
+                Vec::new()
+            };
+            self.sector = Some(Sector {
+                index,
+                data,
+                dirty: false,
+            });
+        }
+        Ok(self.sector.as_mut().unwrap())
+    }
+
+    /// Writes as much of `buf` as fits into the sector at the current position.
+    fn write_in_sector(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let index = self.position / SECTOR_SIZE as u64;
+        let offset = (self.position % SECTOR_SIZE as u64) as usize;
+        let n = buf.len().min(SECTOR_SIZE - offset);
+
+        let sector = self.load(index)?;
+        if sector.data.len() < offset + n {
+            sector.data.resize(offset + n, 0);
+        }
+        sector.data[offset..offset + n].copy_from_slice(&buf[..n]);
+        sector.dirty = true;
+
+        self.position += n as u64;
+        self.len = self.len.max(self.position);
+        Ok(n)
+    }
+}
+
+impl<F: Read + Write + Seek> Read for EncryptedFileHandle<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / SECTOR_SIZE as u64;
+        let offset = (self.position % SECTOR_SIZE as u64) as usize;
+
+        let sector = self.load(index)?;
+        let n = buf.len().min(sector.data.len() - offset);
+        buf[..n].copy_from_slice(&sector.data[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<F: Read + Write + Seek> Write for EncryptedFileHandle<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Writing past the end leaves a gap, which is filled with zeros like a regular file.
+        while self.len < self.position {
+            let target = self.position;
+            self.position = self.len;
+            let gap = (target - self.len).min(SECTOR_SIZE as u64) as usize;
+            self.write_in_sector(&vec![0u8; gap])?;
+            self.position = target;
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.write_in_sector(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_back()?;
+        self.inner().flush()
+    }
+}
+
+impl<F: Read + Write + Seek> Drop for EncryptedFileHandle<F> {
+    fn drop(&mut self) {
+        // Not after a panic, which may have left the sector half written.
+        if self.inner.is_some() && !thread::panicking() {
+            let _ = self.write_back();
+        }
+    }
+}
+
+impl<F: Read + Write + Seek> Seek for EncryptedFileHandle<F> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    #[test]
+    fn test_drop_writes_back() {
+        let mut stored = Cursor::new(Vec::new());
+        let mut file = EncryptedFileHandle::create(SECRET, &mut stored).unwrap();
+        file.write_all(b"never flushed").unwrap();
+        drop(file);
+
+        let mut file = EncryptedFileHandle::open(SECRET, &mut stored).unwrap();
+        let mut read_back = String::new();
+        file.read_to_string(&mut read_back).unwrap();
+        assert_eq!(read_back, "never flushed");
+        file.write_all(b", then closed").unwrap();
+        file.close().unwrap();
+        assert_eq!(EncryptedFileHandle::open(SECRET, stored).unwrap().len(), 26);
+    }
+
+    #[test]
+    fn test_behaves_like_a_file() {
+        let mut file = EncryptedFileHandle::create(SECRET, Cursor::new(Vec::new())).unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data).unwrap();
+
+        // Overwrite a range that straddles a sector boundary, then extend past the end.
+        file.seek(SeekFrom::Start(4000)).unwrap();
+        file.write_all(&[0xFF; 200]).unwrap();
+        file.seek(SeekFrom::End(100)).unwrap();
+        file.write_all(b"tail").unwrap();
+
+        let mut expected = data;
+        expected[4000..4200].fill(0xFF);
+        expected.resize(10_100, 0);
+        expected.extend_from_slice(b"tail");
+
+        let inner = file.into_inner().unwrap();
+        let mut file = EncryptedFileHandle::open(SECRET, inner).unwrap();
+        assert_eq!(file.len(), expected.len() as u64);
+        let mut read_back = Vec::new();
+        file.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, expected);
+
+        file.seek(SeekFrom::Start(4090)).unwrap();
+        let mut middle = [0u8; 12];
+        file.read_exact(&mut middle).unwrap();
+        assert_eq!(middle, [0xFF; 12]);
+    }
+
+    #[test]
+    fn test_rewrites_use_fresh_nonces() {
+        let mut file = EncryptedFileHandle::create(SECRET, Cursor::new(Vec::new())).unwrap();
+        file.write_all(&[0u8; 100]).unwrap();
+        file.flush().unwrap();
+        let before = file.inner().get_ref().clone();
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0u8; 100]).unwrap();
+        let after = file.into_inner().unwrap().into_inner();
+
+        assert_eq!(before.len(), after.len());
+        assert_ne!(before[HEADER_SIZE..], after[HEADER_SIZE..]);
+        assert!(!after.windows(16).any(|window| window == [0u8; 16]));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let mut file = EncryptedFileHandle::create(SECRET, Cursor::new(Vec::new())).unwrap();
+        file.write_all(&[1u8; 3 * SECTOR_SIZE]).unwrap();
+        let mut stored = file.into_inner().unwrap().into_inner();
+
+        // Swap the first two sectors.
+        let sector_len = SECTOR_SIZE + SECTOR_OVERHEAD;
+        let (first, rest) = stored[HEADER_SIZE..].split_at_mut(sector_len);
+        first.swap_with_slice(&mut rest[..sector_len]);
+
+        let mut file = EncryptedFileHandle::open(SECRET, Cursor::new(stored)).unwrap();
+        let error = file.read(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(
+            *error
+                .into_inner()
+                .unwrap()
+                .downcast::<StreamError>()
+                .unwrap(),
+            StreamError::Authentication { index: 0 }
+        );
+
+        let mut file =
+            EncryptedFileHandle::open(b"wrong secret", file.into_inner().unwrap()).unwrap();
+        file.seek(SeekFrom::Start(2 * SECTOR_SIZE as u64)).unwrap();
+        assert!(file.read(&mut [0u8; 1]).is_err());
+    }
+}
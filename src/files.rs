@@ -0,0 +1,497 @@
+//! Encrypting and decrypting whole files on disk, without ever leaving half a file behind.
+//!
+//! Writing straight to the destination is dangerous: if the process crashes or the machine
+//! loses power halfway through, what's left is a truncated file under the final name. For
+//! plaintext that is merely annoying. For a ciphertext it is worse, because it looks like a
+//! complete backup right up until someone needs to restore it.
+//!
+//! So the output goes to a temporary file next to the destination first. Only once it is
+//! complete (and, depending on the [`Durability`], flushed to disk) is it renamed over the
+//! destination. Renaming within one directory is atomic, so the destination either keeps its
+//! old contents or gets the complete new ones. Decryption works the same way, and since a
+//! [`StreamDecryptor`] fails on any tampered or truncated chunk, a bad ciphertext never
+//! produces an output file at all.
+//!
+//! Backup workflows that must not leave plaintext behind can ask for the source to be
+//! [shredded](FileOptions::with_shredded_source) once it has been encrypted.
+//!
+//! Sparse files, such as VM images, can be mostly holes that read as zeros but take no space.
+//! Encrypting them as they read would turn every hole into ciphertext on disk.
+//! [`FileOptions::with_sparse`] finds the holes with `SEEK_DATA` and `SEEK_HOLE` instead, and
+//! encrypts a map of the data extents followed by only their bytes:
+//!
+//! ```text
+//! file:      magic "AMSF" | chunked stream
+//! plaintext: file length (u64) | extent count (u64) | (offset (u64), length (u64))* | data
+//! ```
+//!
+//! The map is encrypted and authenticated along with the data, and the stream header carries
+//! a [sparse flag](crate::chunked::StreamHeader::sparse) that must match the magic, so the
+//! magic can't be stripped to pass the map off as the plaintext. [`decrypt_file`] recognizes
+//! the magic, and restores the holes by writing only the extents into a file of the original
+//! length. Where `SEEK_DATA` isn't available (outside Linux), or the file system doesn't track
+//! holes, the whole file is one extent and nothing is saved.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    chunked::{StreamDecryptor, StreamEncryptor, StreamError, StreamHeader, DEFAULT_CHUNK_SIZE},
+    utils,
+};
+
+/// The magic in front of the stream of a [sparse](FileOptions::with_sparse) container.
+const SPARSE_MAGIC: &[u8; 4] = b"AMSF";
+
+/// How hard to try to make the output survive a crash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Atomic rename only. A crash can still lose the new file, but never leaves a partial one,
+    /// as long as the file system orders the rename after the data (most do).
+    Atomic,
+    /// Also fsync the file contents before renaming.
+    SyncData,
+    /// Also fsync the directory after renaming, so the rename itself is on disk when this
+    /// returns.
+    Full,
+}
+
+/// Options for [`encrypt_file`] and [`decrypt_file`].
+#[derive(Clone, Copy, Debug)]
+pub struct FileOptions {
+    durability: Durability,
+    shred_source: bool,
+    sparse: bool,
+}
+
+impl FileOptions {
+    /// Fully durable output.
+    pub fn new() -> Self {
+        FileOptions {
+            durability: Durability::Full,
+            shred_source: false,
+            sparse: false,
+        }
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Overwrites the plaintext with random bytes and deletes it once [`encrypt_file`] has
+    /// safely written the ciphertext.
+    ///
+    /// This is a best effort. It works on a simple file system on a spinning disk, but SSDs
+    /// remap writes to fresh flash cells and keep the old ones around until they get erased,
+    /// and copy-on-write file systems (Btrfs, ZFS, APFS), snapshots, journals and backups can
+    /// all keep copies the overwrite never reaches. Where that matters, encrypt the data before
+    /// it ever touches the disk, or use full-disk encryption and throw away the key.
+    pub fn with_shredded_source(mut self) -> Self {
+        self.shred_source = true;
+        self
+    }
+
+    /// Makes [`encrypt_file`] skip the holes of a sparse source, and record them so
+    /// [`decrypt_file`] can put them back. See the [module documentation](self).
+    pub fn with_sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypts `source` into `destination` as a [`chunked`](crate::chunked) stream.
+pub fn encrypt_file(
+    master_secret: &[u8],
+    source: &Path,
+    destination: &Path,
+    options: &FileOptions,
+) -> io::Result<()> {
+    let mut input = File::open(source)?;
+    write_atomically(destination, options.durability, |output| {
+        if options.sparse {
+            output.write_all(SPARSE_MAGIC)?;
+        }
+        let header = StreamHeader {
+            sparse: options.sparse,
+            ..StreamHeader::new(DEFAULT_CHUNK_SIZE)
+        };
+        let mut encryptor = StreamEncryptor::with_header(master_secret, header, output)?;
+        if options.sparse {
+            write_sparse(&mut input, &mut encryptor)?;
+        } else {
+            io::copy(&mut input, &mut encryptor)?;
+        }
+        encryptor.finish()?;
+        Ok(())
+    })?;
+
+    if options.shred_source {
+        drop(input);
+        shred(source)?;
+    }
+    Ok(())
+}
+
+/// Overwrites a file with random data, flushes it to disk, and removes it.
+fn shred(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut remaining = file.metadata()?.len();
+    let mut buffer = vec![0u8; 64 * 1024];
+    while remaining > 0 {
+        let n = remaining.min(buffer.len() as u64) as usize;
+        utils::fill_random(&mut buffer[..n]);
+        file.write_all(&buffer[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+/// Decrypts `source`, written by [`encrypt_file`], into `destination`, which is sparse if the
+/// source was.
+pub fn decrypt_file(
+    master_secret: &[u8],
+    source: &Path,
+    destination: &Path,
+    options: &FileOptions,
+) -> io::Result<()> {
+    let mut input = File::open(source)?;
+    let mut magic = [0u8; 4];
+    let sparse = match input.read_exact(&mut magic) {
+        Ok(()) => &magic == SPARSE_MAGIC,
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(error) => return Err(error),
+    };
+    if !sparse {
+        input.rewind()?;
+    }
+    write_atomically(destination, options.durability, |output| {
+        let mut decryptor = StreamDecryptor::new(master_secret, input)?;
+        if decryptor.header().sparse() != sparse {
+            return Err(StreamError::BadHeader.into());
+        }
+        if sparse {
+            read_sparse(&mut decryptor, output)
+        } else {
+            io::copy(&mut decryptor, output)?;
+            Ok(())
+        }
+    })
+}
+
+/// A run of data in a sparse file. Everything between extents is a hole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Extent {
+    offset: u64,
+    len: u64,
+}
+
+/// The data extents of `file`, which is `len` bytes long, in order.
+#[cfg(target_os = "linux")]
+fn data_extents(file: &File, len: u64) -> io::Result<Vec<Extent>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: lseek only moves the offset of a descriptor that `file` keeps open.
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let error = io::Error::last_os_error();
+            // ENXIO: there is no data after `offset`, only a hole up to the end.
+            if error.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(error);
+        }
+        // SAFETY: as above.
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (start, end) = (start as u64, (end as u64).min(len));
+        if start >= end {
+            break;
+        }
+        extents.push(Extent {
+            offset: start,
+            len: end - start,
+        });
+        offset = end;
+    }
+    Ok(extents)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_extents(_file: &File, len: u64) -> io::Result<Vec<Extent>> {
+    Ok(if len == 0 {
+        Vec::new()
+    } else {
+        vec![Extent { offset: 0, len }]
+    })
+}
+
+/// Writes the sparse plaintext of `input`: its length, its data extents, and their data.
+fn write_sparse(input: &mut File, output: &mut impl Write) -> io::Result<()> {
+    let len = input.metadata()?.len();
+    let extents = data_extents(input, len)?;
+    output.write_all(&len.to_be_bytes())?;
+    output.write_all(&(extents.len() as u64).to_be_bytes())?;
+    for extent in &extents {
+        output.write_all(&extent.offset.to_be_bytes())?;
+        output.write_all(&extent.len.to_be_bytes())?;
+    }
+    for extent in &extents {
+        input.seek(SeekFrom::Start(extent.offset))?;
+        if io::copy(&mut input.take(extent.len), output)? != extent.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file shrank while it was being encrypted",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Restores what [`write_sparse`] wrote into `output`, leaving the holes unwritten.
+fn read_sparse(input: &mut impl Read, output: &mut File) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed sparse file map");
+    let len = read_u64(input)?;
+    let count = read_u64(input)?;
+    let mut extents = Vec::new();
+    let mut end = 0;
+    for _ in 0..count {
+        let extent = Extent {
+            offset: read_u64(input)?,
+            len: read_u64(input)?,
+        };
+        if extent.offset < end {
+            return Err(invalid());
+        }
+        end = extent
+            .offset
+            .checked_add(extent.len)
+            .filter(|&end| end <= len)
+            .ok_or_else(invalid)?;
+        extents.push(extent);
+    }
+
+    output.set_len(len)?;
+    for extent in &extents {
+        output.seek(SeekFrom::Start(extent.offset))?;
+        if io::copy(&mut input.take(extent.len), output)? != extent.len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+    // Reading to the end checks the final chunk, and that nothing follows the extents.
+    if input.read(&mut [0])? != 0 {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Runs `write` against a temporary file in the destination's directory, then moves it into
+/// place. On any error the temporary file is removed and the destination is left untouched.
+///
+/// This is how [`encrypt_file`] and [`decrypt_file`] write, for outputs that aren't a plain
+/// copy of one file, such as a stream decrypted from standard input.
+pub fn write_atomically(
+    destination: &Path,
+    durability: Durability,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let directory = match destination.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = destination.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "destination has no file name")
+    })?;
+    let random = u64::from_be_bytes(utils::create_rand_nonce());
+    let temporary = directory.join(format!(
+        ".{}.{:016x}.tmp",
+        file_name.to_string_lossy(),
+        random
+    ));
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temporary)?;
+    let result = write(&mut file)
+        .and_then(|()| file.flush())
+        .and_then(|()| match durability {
+            Durability::Atomic => Ok(()),
+            Durability::SyncData | Durability::Full => file.sync_all(),
+        })
+        .and_then(|()| fs::rename(&temporary, destination));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+
+    if durability == Durability::Full {
+        sync_directory(&directory)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+/// Directories can't be opened as files on Windows, where renames are durable once they return.
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    fn scratch_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "aes-modes-{}-{:016x}",
+            name,
+            u64::from_be_bytes(utils::create_rand_nonce())
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let directory = scratch_directory("files");
+        let (plain, encrypted, decrypted) = (
+            directory.join("plain.txt"),
+            directory.join("plain.txt.enc"),
+            directory.join("decrypted.txt"),
+        );
+        let data = vec![42u8; 200_000];
+        fs::write(&plain, &data).unwrap();
+
+        for durability in [Durability::Atomic, Durability::SyncData, Durability::Full] {
+            let options = FileOptions::new().with_durability(durability);
+            encrypt_file(SECRET, &plain, &encrypted, &options).unwrap();
+            decrypt_file(SECRET, &encrypted, &decrypted, &options).unwrap();
+            assert_eq!(fs::read(&decrypted).unwrap(), data);
+        }
+
+        // No temporary files were left behind.
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 3);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_failed_decryption_leaves_destination_alone() {
+        let directory = scratch_directory("files");
+        let (plain, encrypted, decrypted) = (
+            directory.join("plain.txt"),
+            directory.join("plain.txt.enc"),
+            directory.join("decrypted.txt"),
+        );
+        fs::write(&plain, vec![1u8; 100_000]).unwrap();
+        fs::write(&decrypted, b"previous contents").unwrap();
+        encrypt_file(SECRET, &plain, &encrypted, &FileOptions::new()).unwrap();
+
+        // A ciphertext cut off by a crash must not decrypt to a shorter file.
+        let cipher_text = fs::read(&encrypted).unwrap();
+        fs::write(&encrypted, &cipher_text[..cipher_text.len() / 2]).unwrap();
+        assert!(decrypt_file(SECRET, &encrypted, &decrypted, &FileOptions::new()).is_err());
+        assert_eq!(fs::read(&decrypted).unwrap(), b"previous contents");
+
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 3);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_shredded_source() {
+        let directory = scratch_directory("files");
+        let (plain, encrypted, decrypted) = (
+            directory.join("plain.txt"),
+            directory.join("plain.txt.enc"),
+            directory.join("decrypted.txt"),
+        );
+        let data = b"nothing to see here".repeat(10_000);
+        fs::write(&plain, &data).unwrap();
+
+        // A failed encryption leaves the source alone.
+        let options = FileOptions::new().with_shredded_source();
+        let nowhere = directory.join("missing").join("out.enc");
+        assert!(encrypt_file(SECRET, &plain, &nowhere, &options).is_err());
+        assert_eq!(fs::read(&plain).unwrap(), data);
+
+        encrypt_file(SECRET, &plain, &encrypted, &options).unwrap();
+        assert!(!plain.exists());
+        decrypt_file(SECRET, &encrypted, &decrypted, &FileOptions::new()).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), data);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_sparse_round_trip() {
+        let directory = scratch_directory("files");
+        let (plain, encrypted, decrypted) = (
+            directory.join("disk.img"),
+            directory.join("disk.img.enc"),
+            directory.join("restored.img"),
+        );
+        let len = 64 << 20;
+        let mut file = File::create(&plain).unwrap();
+        file.set_len(len).unwrap();
+        for offset in [0, 20 << 20, len - 5] {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(b"data!").unwrap();
+        }
+        drop(file);
+
+        let options = FileOptions::new().with_sparse();
+        encrypt_file(SECRET, &plain, &encrypted, &options).unwrap();
+        decrypt_file(SECRET, &encrypted, &decrypted, &options).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), fs::read(&plain).unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Only holds where the file system keeps holes, as tmpfs and ext4 do.
+            if fs::metadata(&plain).unwrap().blocks() * 512 < len / 2 {
+                assert!(fs::metadata(&encrypted).unwrap().len() < len / 2);
+                assert!(fs::metadata(&decrypted).unwrap().blocks() * 512 < len / 2);
+            }
+        }
+
+        // A tampered map fails like any tampered chunk.
+        let mut cipher_text = fs::read(&encrypted).unwrap();
+        cipher_text[SPARSE_MAGIC.len() + crate::chunked::HEADER_SIZE] ^= 1;
+        fs::write(&encrypted, cipher_text).unwrap();
+        assert!(decrypt_file(SECRET, &encrypted, &decrypted, &options).is_err());
+
+        // Without its magic, the stream still says it is sparse.
+        encrypt_file(SECRET, &plain, &encrypted, &options).unwrap();
+        let cipher_text = fs::read(&encrypted).unwrap();
+        fs::write(&encrypted, &cipher_text[SPARSE_MAGIC.len()..]).unwrap();
+        assert!(decrypt_file(SECRET, &encrypted, &decrypted, &options).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Galois/Counter Mode, the most widely used authenticated encryption mode.
+//!
+//! GCM is counter mode for confidentiality plus a polynomial MAC called GHASH for integrity.
+//! Unlike the modes in the crate root, any change to the ciphertext (or to the associated data,
+//! which is authenticated but not encrypted) is detected, and decryption refuses to return
+//! anything at all.
+//!
+//! For the full specification see NIST SP 800-38D:
+//! https://csrc.nist.gov/publications/detail/sp/800-38d/final
+//!
+//! As with CTR, a nonce must NEVER be reused under the same key. Doing so leaks the XOR of the
+//! plaintexts _and_ lets an attacker recover the GHASH key and forge messages.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    backend::{Backend, CipherContext},
+    gf128,
+    util::{self, inc32},
+    BLOCK_SIZE,
+};
+
+/// GCM is defined for any nonce length, but 96 bits is the recommended (and fast) case.
+pub const GCM_NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+/// Returned when a ciphertext, its tag, or its associated data has been tampered with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuthenticationError;
+
+impl fmt::Display for AuthenticationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("authentication tag mismatch")
+    }
+}
+
+impl Error for AuthenticationError {}
+
+/// Encrypts and authenticates the plaintext, and authenticates the associated data.
+/// The output is the ciphertext followed by the 16-byte tag.
+pub fn gcm_encrypt(
+    plain_text: Vec<u8>,
+    key: [u8; BLOCK_SIZE],
+    nonce: [u8; GCM_NONCE_SIZE],
+    aad: &[u8],
+) -> Vec<u8> {
+    let mut cipher_text = Vec::with_capacity(plain_text.len() + TAG_SIZE);
+    GcmKey::new(key).seal_into(nonce, &plain_text, aad, &mut cipher_text);
+    cipher_text
+}
+
+/// Opposite of gcm_encrypt. The tag is checked before any decryption happens.
+pub fn gcm_decrypt(
+    cipher_text: Vec<u8>,
+    key: [u8; BLOCK_SIZE],
+    nonce: [u8; GCM_NONCE_SIZE],
+    aad: &[u8],
+) -> Result<Vec<u8>, AuthenticationError> {
+    GcmKey::new(key).open(nonce, &cipher_text, aad)
+}
+
+/// A GCM key with its AES key schedule and GHASH key worked out once, for callers that
+/// encrypt many messages under the same key.
+pub(crate) struct GcmKey {
+    cipher: CipherContext,
+    h: u128,
+}
+
+impl fmt::Debug for GcmKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("GcmKey(..)")
+    }
+}
+
+impl GcmKey {
+    pub(crate) fn new(key: [u8; BLOCK_SIZE]) -> Self {
+        Self::with_context(Backend::RustCryptoAes.context(key))
+    }
+
+    /// GCM on top of the block cipher of any [`Backend`].
+    pub(crate) fn with_context(cipher: CipherContext) -> Self {
+        let h = u128::from_be_bytes(cipher.encrypt_block([0u8; BLOCK_SIZE]));
+        GcmKey { cipher, h }
+    }
+
+    fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+        self.cipher.encrypt_block(block)
+    }
+
+    /// Appends the ciphertext and the tag to `output`.
+    pub(crate) fn seal_into(
+        &self,
+        nonce: [u8; GCM_NONCE_SIZE],
+        plain_text: &[u8],
+        aad: &[u8],
+        output: &mut Vec<u8>,
+    ) {
+        let j0 = initial_counter_block(nonce);
+        let start = output.len();
+        self.gctr(plain_text, inc32(j0), output);
+        let tag = self.compute_tag(j0, aad, &output[start..]);
+        output.extend_from_slice(&tag);
+    }
+
+    /// Checks the tag on `cipher_text | tag`, and only then decrypts.
+    pub(crate) fn open(
+        &self,
+        nonce: [u8; GCM_NONCE_SIZE],
+        cipher_text: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, AuthenticationError> {
+        self.open_truncated(nonce, cipher_text, aad, TAG_SIZE)
+    }
+
+    /// Like [`GcmKey::open`], for a tag truncated to its first `tag_len` bytes.
+    pub(crate) fn open_truncated(
+        &self,
+        nonce: [u8; GCM_NONCE_SIZE],
+        cipher_text: &[u8],
+        aad: &[u8],
+        tag_len: usize,
+    ) -> Result<Vec<u8>, AuthenticationError> {
+        if cipher_text.len() < tag_len {
+            return Err(AuthenticationError);
+        }
+        let (body, tag) = cipher_text.split_at(cipher_text.len() - tag_len);
+
+        let j0 = initial_counter_block(nonce);
+        let expected_tag = self.compute_tag(j0, aad, body);
+        if !constant_time_eq(&expected_tag[..tag_len], tag) {
+            return Err(AuthenticationError);
+        }
+
+        let mut plain_text = Vec::with_capacity(body.len());
+        self.gctr(body, inc32(j0), &mut plain_text);
+        Ok(plain_text)
+    }
+
+    /// The counter mode part of GCM. Works the same in both directions.
+    fn gctr(&self, data: &[u8], mut counter_block: [u8; BLOCK_SIZE], output: &mut Vec<u8>) {
+        for chunk in data.chunks(BLOCK_SIZE) {
+            let keystream = self.encrypt_block(counter_block);
+            output.extend(chunk.iter().zip(keystream.iter()).map(|(x, y)| x ^ y));
+            // The nonce part never changes, which is why a single message is limited to
+            // 2^32 - 2 blocks.
+            counter_block = inc32(counter_block);
+        }
+    }
+
+    /// GHASH over the padded associated data, the padded ciphertext, and their bit lengths,
+    /// masked with the encryption of the first counter block.
+    fn compute_tag(&self, j0: [u8; BLOCK_SIZE], aad: &[u8], cipher_text: &[u8]) -> [u8; TAG_SIZE] {
+        let mut y = 0u128;
+        for data in [aad, cipher_text] {
+            for chunk in data.chunks(BLOCK_SIZE) {
+                let mut block = [0u8; BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+                y = gf128::mul(y ^ u128::from_be_bytes(block), self.h);
+            }
+        }
+
+        let lengths = ((aad.len() as u128 * 8) << 64) | (cipher_text.len() as u128 * 8);
+        y = gf128::mul(y ^ lengths, self.h);
+
+        let mask = u128::from_be_bytes(self.encrypt_block(j0));
+        (y ^ mask).to_be_bytes()
+    }
+}
+
+/// For 96-bit nonces the first counter block is simply `nonce | 0x00000001`.
+fn initial_counter_block(nonce: [u8; GCM_NONCE_SIZE]) -> [u8; BLOCK_SIZE] {
+    util::gcm_counter_block(&nonce, 1)
+}
+
+/// Compares two tags without bailing out at the first difference, so the time taken
+/// doesn't tell an attacker how many leading bytes of a forged tag were right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_gcm_spec_vectors() {
+        // Test cases 1, 2 and 4 from the original GCM specification.
+        let key = [0u8; BLOCK_SIZE];
+        let nonce = [0u8; GCM_NONCE_SIZE];
+
+        let output = gcm_encrypt(Vec::new(), key, nonce, &[]);
+        assert_eq!(output, hex("58e2fccefa7e3061367f1d57a4e7455a"));
+
+        let output = gcm_encrypt(vec![0u8; 16], key, nonce, &[]);
+        assert_eq!(
+            output,
+            hex("0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf")
+        );
+
+        let key = hex("feffe9928665731c6d6a8f9467308308").try_into().unwrap();
+        let nonce = hex("cafebabefacedbaddecaf888").try_into().unwrap();
+        let plain_text = hex(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        );
+        let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let expected = hex(
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091\
+             5bc94fbc3221a5db94fae95ae7121a47",
+        );
+
+        let output = gcm_encrypt(plain_text.clone(), key, nonce, &aad);
+        assert_eq!(output, expected);
+        assert_eq!(gcm_decrypt(output, key, nonce, &aad), Ok(plain_text));
+    }
+
+    #[test]
+    fn test_gcm_rejects_tampering() {
+        let key = [7u8; BLOCK_SIZE];
+        let nonce = [9u8; GCM_NONCE_SIZE];
+        let cipher_text = gcm_encrypt(b"Attack at dawn".to_vec(), key, nonce, b"header");
+
+        let mut flipped = cipher_text.clone();
+        flipped[0] ^= 1;
+        assert_eq!(
+            gcm_decrypt(flipped, key, nonce, b"header"),
+            Err(AuthenticationError)
+        );
+        assert_eq!(
+            gcm_decrypt(cipher_text.clone(), key, nonce, b"HEADER"),
+            Err(AuthenticationError)
+        );
+        assert_eq!(
+            gcm_decrypt(cipher_text[..TAG_SIZE - 1].to_vec(), key, nonce, b"header"),
+            Err(AuthenticationError)
+        );
+    }
+}
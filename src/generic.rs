@@ -0,0 +1,440 @@
+//! ECB, CBC, CFB, OFB and CTR for any block cipher, not just AES-128.
+//!
+//! The functions at the crate root are written out for AES with 16-byte blocks, which keeps
+//! them easy to follow. The same modes work with any block cipher, though, and some users
+//! need other ones: national standards, or interop with older systems. The functions here take
+//! any cipher implementing the RustCrypto [`cipher`] traits, whatever its block size.
+//!
+//! The formats match the ones at the crate root where they can: CBC puts a random IV in front
+//! of the ciphertext, and both ECB and CBC use PKCS#7 padding. CTR is the standard
+//! big-endian counter from NIST SP 800-38A, with a random initial counter block in front.
+//! CFB (with full-block feedback) and OFB are as in SP 800-38A too, with a random IV in front,
+//! and like CTR need no padding. The crate root has neither, since CTR does everything they do
+//! and parallelizes; they are here for old protocols and for comparing the modes.
+//!
+//! Decryption returns [`MalformedCiphertext`] instead of panicking on input that can't have
+//! come from the matching encryption, since data from other systems is often the point.
+//!
+//! Some ciphers are re-exported here behind features, so callers don't need their own
+//! dependency on a matching version: `sm4` for SM4 (GB/T 32907), and `camellia` for Camellia
+//! (RFC 3713, as used by Japanese government systems and some TLS deployments).
+
+use std::{error::Error, fmt};
+
+pub use aes::cipher;
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, BlockSizeUser};
+
+#[cfg(feature = "camellia")]
+pub use camellia::{Camellia128, Camellia192, Camellia256};
+#[cfg(feature = "sm4")]
+pub use sm4::Sm4;
+
+use crate::warnings::{self, Warning};
+
+/// Returned when a ciphertext's length doesn't fit the mode, or its padding is invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MalformedCiphertext;
+
+impl fmt::Display for MalformedCiphertext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("malformed ciphertext")
+    }
+}
+
+impl Error for MalformedCiphertext {}
+
+fn block_size<C: BlockSizeUser>() -> usize {
+    C::block_size()
+}
+
+fn encrypt_block<C: BlockEncrypt>(cipher: &C, block: &mut [u8]) {
+    cipher.encrypt_block(GenericArray::from_mut_slice(block));
+}
+
+fn decrypt_block<C: BlockDecrypt>(cipher: &C, block: &mut [u8]) {
+    cipher.decrypt_block(GenericArray::from_mut_slice(block));
+}
+
+/// PKCS#7 padding for any block size up to 255.
+fn pad(mut data: Vec<u8>, block_size: usize) -> Vec<u8> {
+    let pad_len = block_size - data.len() % block_size;
+    data.resize(data.len() + pad_len, pad_len as u8);
+    data
+}
+
+fn un_pad(mut data: Vec<u8>, block_size: usize) -> Result<Vec<u8>, MalformedCiphertext> {
+    let pad_len = *data.last().ok_or(MalformedCiphertext)? as usize;
+    if pad_len == 0 || pad_len > block_size || pad_len > data.len() {
+        return Err(MalformedCiphertext);
+    }
+    if data[data.len() - pad_len..]
+        .iter()
+        .any(|&byte| byte as usize != pad_len)
+    {
+        return Err(MalformedCiphertext);
+    }
+    data.truncate(data.len() - pad_len);
+    Ok(data)
+}
+
+/// ECB. Just as insecure with any other cipher, and only here for decrypting old data.
+pub fn ecb_encrypt<C: BlockEncrypt>(cipher: &C, plain_text: Vec<u8>) -> Vec<u8> {
+    warnings::warn(Warning::Ecb);
+    let mut data = pad(plain_text, block_size::<C>());
+    for block in data.chunks_mut(block_size::<C>()) {
+        encrypt_block(cipher, block);
+    }
+    data
+}
+
+pub fn ecb_decrypt<C: BlockDecrypt>(
+    cipher: &C,
+    mut cipher_text: Vec<u8>,
+) -> Result<Vec<u8>, MalformedCiphertext> {
+    warnings::warn(Warning::Ecb);
+    let n = block_size::<C>();
+    if cipher_text.is_empty() || !cipher_text.len().is_multiple_of(n) {
+        return Err(MalformedCiphertext);
+    }
+    for block in cipher_text.chunks_mut(n) {
+        decrypt_block(cipher, block);
+    }
+    un_pad(cipher_text, n)
+}
+
+/// CBC with a random IV, which is the first block of the output.
+pub fn cbc_encrypt<C: BlockEncrypt>(cipher: &C, plain_text: Vec<u8>) -> Vec<u8> {
+    let mut iv = vec![0u8; block_size::<C>()];
+    crate::utils::fill_random(&mut iv);
+    cbc_encrypt_with_iv(cipher, &iv, plain_text)
+}
+
+/// CBC with a caller-chosen IV, which must be unpredictable. Mostly useful for test vectors.
+pub fn cbc_encrypt_with_iv<C: BlockEncrypt>(cipher: &C, iv: &[u8], plain_text: Vec<u8>) -> Vec<u8> {
+    warnings::warn(Warning::UnauthenticatedCbc);
+    let n = block_size::<C>();
+    assert_eq!(iv.len(), n, "the IV must be one block long");
+
+    let mut cipher_text = iv.to_vec();
+    let data = pad(plain_text, n);
+    for block in data.chunks(n) {
+        let previous = &cipher_text[cipher_text.len() - n..];
+        let mut block: Vec<u8> = block.iter().zip(previous).map(|(x, y)| x ^ y).collect();
+        encrypt_block(cipher, &mut block);
+        cipher_text.extend_from_slice(&block);
+    }
+    cipher_text
+}
+
+pub fn cbc_decrypt<C: BlockDecrypt>(
+    cipher: &C,
+    cipher_text: Vec<u8>,
+) -> Result<Vec<u8>, MalformedCiphertext> {
+    warnings::warn(Warning::UnauthenticatedCbc);
+    let n = block_size::<C>();
+    if cipher_text.len() < 2 * n || !cipher_text.len().is_multiple_of(n) {
+        return Err(MalformedCiphertext);
+    }
+
+    let mut plain_text = Vec::with_capacity(cipher_text.len() - n);
+    for pair in cipher_text.windows(2 * n).step_by(n) {
+        let (previous, block) = pair.split_at(n);
+        let mut block = block.to_vec();
+        decrypt_block(cipher, &mut block);
+        plain_text.extend(block.iter().zip(previous).map(|(x, y)| x ^ y));
+    }
+    un_pad(plain_text, n)
+}
+
+/// XORs `data` with the keystream starting at `counter_block`, incrementing the whole block
+/// as a big-endian integer. This is its own inverse.
+pub fn ctr_apply<C: BlockEncrypt>(cipher: &C, counter_block: &[u8], data: &[u8]) -> Vec<u8> {
+    let n = block_size::<C>();
+    assert_eq!(
+        counter_block.len(),
+        n,
+        "the counter block must be one block long"
+    );
+
+    let mut counter = counter_block.to_vec();
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(n) {
+        let mut keystream = counter.clone();
+        encrypt_block(cipher, &mut keystream);
+        output.extend(chunk.iter().zip(&keystream).map(|(x, y)| x ^ y));
+
+        for byte in counter.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+    output
+}
+
+/// CTR with a random initial counter block, which is the first block of the output.
+pub fn ctr_encrypt<C: BlockEncrypt>(cipher: &C, plain_text: &[u8]) -> Vec<u8> {
+    let mut counter_block = vec![0u8; block_size::<C>()];
+    crate::utils::fill_random(&mut counter_block);
+    let mut cipher_text = counter_block.clone();
+    cipher_text.extend(ctr_apply(cipher, &counter_block, plain_text));
+    cipher_text
+}
+
+pub fn ctr_decrypt<C: BlockEncrypt>(
+    cipher: &C,
+    cipher_text: &[u8],
+) -> Result<Vec<u8>, MalformedCiphertext> {
+    let n = block_size::<C>();
+    if cipher_text.len() < n {
+        return Err(MalformedCiphertext);
+    }
+    let (counter_block, body) = cipher_text.split_at(n);
+    Ok(ctr_apply(cipher, counter_block, body))
+}
+
+/// CFB with full-block feedback from `iv`: each keystream block is the encryption of the
+/// previous ciphertext block.
+fn cfb_apply<C: BlockEncrypt>(cipher: &C, iv: &[u8], data: &[u8], decrypt: bool) -> Vec<u8> {
+    let n = block_size::<C>();
+    assert_eq!(iv.len(), n, "the IV must be one block long");
+
+    let mut feedback = iv.to_vec();
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(n) {
+        encrypt_block(cipher, &mut feedback);
+        let out: Vec<u8> = chunk.iter().zip(&feedback).map(|(x, y)| x ^ y).collect();
+        feedback = if decrypt { chunk.to_vec() } else { out.clone() };
+        output.extend(out);
+    }
+    output
+}
+
+/// CFB with a random IV, which is the first block of the output.
+pub fn cfb_encrypt<C: BlockEncrypt>(cipher: &C, plain_text: &[u8]) -> Vec<u8> {
+    let mut iv = vec![0u8; block_size::<C>()];
+    crate::utils::fill_random(&mut iv);
+    cfb_encrypt_with_iv(cipher, &iv, plain_text)
+}
+
+/// CFB with a caller-chosen IV, which must be unpredictable.
+pub fn cfb_encrypt_with_iv<C: BlockEncrypt>(cipher: &C, iv: &[u8], plain_text: &[u8]) -> Vec<u8> {
+    let mut cipher_text = iv.to_vec();
+    cipher_text.extend(cfb_apply(cipher, iv, plain_text, false));
+    cipher_text
+}
+
+pub fn cfb_decrypt<C: BlockEncrypt>(
+    cipher: &C,
+    cipher_text: &[u8],
+) -> Result<Vec<u8>, MalformedCiphertext> {
+    let n = block_size::<C>();
+    if cipher_text.len() < n {
+        return Err(MalformedCiphertext);
+    }
+    let (iv, body) = cipher_text.split_at(n);
+    Ok(cfb_apply(cipher, iv, body, true))
+}
+
+/// XORs `data` with the OFB keystream from `iv`, the IV encrypted again and again. This is
+/// its own inverse.
+pub fn ofb_apply<C: BlockEncrypt>(cipher: &C, iv: &[u8], data: &[u8]) -> Vec<u8> {
+    let n = block_size::<C>();
+    assert_eq!(iv.len(), n, "the IV must be one block long");
+
+    let mut keystream = iv.to_vec();
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(n) {
+        encrypt_block(cipher, &mut keystream);
+        output.extend(chunk.iter().zip(&keystream).map(|(x, y)| x ^ y));
+    }
+    output
+}
+
+/// OFB with a random IV, which is the first block of the output.
+pub fn ofb_encrypt<C: BlockEncrypt>(cipher: &C, plain_text: &[u8]) -> Vec<u8> {
+    let mut iv = vec![0u8; block_size::<C>()];
+    crate::utils::fill_random(&mut iv);
+    let mut cipher_text = iv.clone();
+    cipher_text.extend(ofb_apply(cipher, &iv, plain_text));
+    cipher_text
+}
+
+pub fn ofb_decrypt<C: BlockEncrypt>(
+    cipher: &C,
+    cipher_text: &[u8],
+) -> Result<Vec<u8>, MalformedCiphertext> {
+    let n = block_size::<C>();
+    if cipher_text.len() < n {
+        return Err(MalformedCiphertext);
+    }
+    let (iv, body) = cipher_text.split_at(n);
+    Ok(ofb_apply(cipher, iv, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::{cipher::KeyInit, Aes128};
+
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_the_aes_modes() {
+        let key = [4u8; 16];
+        let aes = Aes128::new(&key.into());
+        let plain_text = b"the same modes, any cipher".to_vec();
+
+        // ECB is deterministic, so the output is identical to the crate root's.
+        assert_eq!(
+            ecb_encrypt(&aes, plain_text.clone()),
+            crate::ecb_encrypt(plain_text.clone(), key)
+        );
+        // CBC is compatible in both directions.
+        let cipher_text = crate::cbc_encrypt(plain_text.clone(), key);
+        assert_eq!(cbc_decrypt(&aes, cipher_text), Ok(plain_text.clone()));
+        let cipher_text = cbc_encrypt(&aes, plain_text.clone());
+        assert_eq!(crate::cbc_decrypt(cipher_text, key), plain_text);
+
+        let cipher_text = ctr_encrypt(&aes, &plain_text);
+        assert_eq!(ctr_decrypt(&aes, &cipher_text), Ok(plain_text));
+    }
+
+    #[test]
+    fn test_sp800_38a_ctr() {
+        // F.5.1 CTR-AES128.Encrypt, first two blocks.
+        let aes = Aes128::new_from_slice(&hex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        let counter = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let plain_text = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        assert_eq!(
+            ctr_apply(&aes, &counter, &plain_text),
+            hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff")
+        );
+    }
+
+    #[test]
+    fn test_sp800_38a_cfb_ofb() {
+        // F.3.13 CFB128-AES128.Encrypt and F.4.1 OFB-AES128.Encrypt, first three blocks.
+        let aes = Aes128::new_from_slice(&hex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        let iv = hex("000102030405060708090a0b0c0d0e0f");
+        let plain_text = hex(concat!(
+            "6bc1bee22e409f96e93d7e117393172a",
+            "ae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52ef"
+        ));
+        let cfb = cfb_encrypt_with_iv(&aes, &iv, &plain_text);
+        assert_eq!(
+            cfb[16..],
+            hex(concat!(
+                "3b3fd92eb72dad20333449f8e83cfb4a",
+                "c8a64537a0b3a93fcde3cdad9f1ce58b",
+                "26751f67a3cbb140b1808cf187a4f4df"
+            ))
+        );
+        assert_eq!(cfb_decrypt(&aes, &cfb), Ok(plain_text.clone()));
+        assert_eq!(
+            ofb_apply(&aes, &iv, &plain_text),
+            hex(concat!(
+                "3b3fd92eb72dad20333449f8e83cfb4a",
+                "7789508d16918f03f53c52dac54ed825",
+                "9740051e9c5fecf64344f7a82260edcc"
+            ))
+        );
+        let ofb = ofb_encrypt(&aes, &plain_text[..20]);
+        assert_eq!(ofb_decrypt(&aes, &ofb), Ok(plain_text[..20].to_vec()));
+    }
+
+    #[test]
+    fn test_malformed_ciphertexts() {
+        let aes = Aes128::new(&[4u8; 16].into());
+        assert_eq!(cbc_decrypt(&aes, vec![0u8; 16]), Err(MalformedCiphertext));
+        assert_eq!(ecb_decrypt(&aes, vec![0u8; 17]), Err(MalformedCiphertext));
+        assert_eq!(ctr_decrypt(&aes, &[0u8; 3]), Err(MalformedCiphertext));
+
+        let mut cipher_text = cbc_encrypt(&aes, b"padding".to_vec());
+        let last = cipher_text.len() - 17;
+        cipher_text[last] ^= 0xff;
+        assert_eq!(cbc_decrypt(&aes, cipher_text), Err(MalformedCiphertext));
+    }
+
+    #[cfg(feature = "camellia")]
+    #[test]
+    fn test_camellia_vectors() {
+        // RFC 3713, appendix A, for each key size.
+        let plain_text = hex("0123456789abcdeffedcba9876543210");
+        let check = |cipher_text: Vec<u8>, expected: &str| {
+            assert_eq!(cipher_text[..16], hex(expected));
+        };
+        let key = hex("0123456789abcdeffedcba9876543210");
+        let camellia = Camellia128::new_from_slice(&key).unwrap();
+        check(
+            ecb_encrypt(&camellia, plain_text.clone()),
+            "67673138549669730857065648eabe43",
+        );
+        let key = hex("0123456789abcdeffedcba98765432100011223344556677");
+        let camellia = Camellia192::new_from_slice(&key).unwrap();
+        check(
+            ecb_encrypt(&camellia, plain_text.clone()),
+            "b4993401b3e996f84ee5cee7d79b09b9",
+        );
+        let key = hex("0123456789abcdeffedcba987654321000112233445566778899aabbccddeeff");
+        let camellia = Camellia256::new_from_slice(&key).unwrap();
+        check(
+            ecb_encrypt(&camellia, plain_text.clone()),
+            "9acc237dff16d76c20ef7c919e3a7509",
+        );
+
+        // CBC, cross-checked against OpenSSL's camellia-128-cbc.
+        let camellia =
+            Camellia128::new_from_slice(&hex("0123456789abcdeffedcba9876543210")).unwrap();
+        let iv = hex("000102030405060708090a0b0c0d0e0f");
+        let plain_text = hex("aaaaaaaabbbbbbbbccccccccddddddddeeeeeeeeffffffffaaaaaaaabbbbbbbb");
+        let cipher_text = cbc_encrypt_with_iv(&camellia, &iv, plain_text.clone());
+        assert_eq!(
+            cipher_text[16..48],
+            hex("c6f2ea60c464b1b39b9d8d0725cd87ee7d7757430e965b133e75d915c5e133c6")
+        );
+        assert_eq!(cbc_decrypt(&camellia, cipher_text), Ok(plain_text));
+    }
+
+    #[cfg(feature = "sm4")]
+    #[test]
+    fn test_sm4_vectors() {
+        // The examples from GB/T 32907-2016 (one block) and draft-ribose-cfrg-sm4 (CBC, CTR).
+        let key = hex("0123456789abcdeffedcba9876543210");
+        let sm4 = Sm4::new_from_slice(&key).unwrap();
+        let mut block = key.clone();
+        encrypt_block(&sm4, &mut block);
+        assert_eq!(block, hex("681edf34d206965e86b3e94f536e4246"));
+
+        let iv = hex("000102030405060708090a0b0c0d0e0f");
+        let plain_text = hex("aaaaaaaabbbbbbbbccccccccddddddddeeeeeeeeffffffffaaaaaaaabbbbbbbb");
+        let cipher_text = cbc_encrypt_with_iv(&sm4, &iv, plain_text.clone());
+        assert_eq!(
+            cipher_text[16..48],
+            hex("78ebb11cc40b0a48312aaeb2040244cb4cb7016951909226979b0d15dc6a8f6d")
+        );
+        assert_eq!(cbc_decrypt(&sm4, cipher_text), Ok(plain_text));
+
+        let plain_text: Vec<u8> = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0xaa, 0xbb]
+            .iter()
+            .flat_map(|&byte| [byte; 16])
+            .collect();
+        assert_eq!(
+            ctr_apply(&sm4, &iv, &plain_text),
+            hex(
+                "ac3236cb970cc20780275d284b0253c0d4bcb6f0fb1847ba612aa85e3abb16a1\
+                 d0169e2c06e33ba2ce21b5023fd048a119758ba78dd7cd84ed4f55e37be42372\
+                 57322fc08e3922492178abbfc2c902dde479e61916f4c7fab889311c46a9b644\
+                 97d99dd267128349e7500d8521bbcf424d9943cf263c1fca0ed4a5979ffc98fd"
+            )
+        );
+    }
+}
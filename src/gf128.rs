@@ -0,0 +1,141 @@
+//! Arithmetic in GF(2^128), the field behind GHASH and so behind GCM's tag.
+//!
+//! The elements are polynomials of degree below 128 with coefficients in GF(2), that is, bits.
+//! Adding two of them is XOR. Multiplying them is polynomial multiplication with carry-less
+//! arithmetic (the partial products are XORed, not added), followed by reduction modulo the
+//! field polynomial
+//!
+//! ```text
+//! P(x) = x^128 + x^7 + x^2 + x + 1
+//! ```
+//!
+//! GCM stores elements in a "reflected" bit order: loading a block with
+//! [`u128::from_be_bytes`], the _most_ significant bit of the u128 is the coefficient of x^0
+//! and the least significant is that of x^127. Both multiplications here take and return
+//! elements in that order, so they plug straight into GHASH.
+//!
+//! [`mul_slow`] follows Algorithm 1 of NIST SP 800-38D bit by bit: 128 rounds of shift,
+//! conditional XOR and conditional reduction. It is easy to check against the standard. [`mul`]
+//! gives the same result the way fast implementations do: reverse the bits into the natural
+//! order, compute the full 255-bit carry-less product, and then reduce it in two folding steps.
+//! CPUs with a carry-less multiply instruction (PCLMULQDQ, PMULL) do the product in one go; here
+//! it is done in software from ordinary integer multiplications, in constant time.
+//!
+//! POLYVAL, used by AES-GCM-SIV and HCTR2, is the same field with the bits in the ordinary
+//! order, and [`mul`] serves it as well after converting representations.
+
+/// Multiplication in GF(2^128), bit by bit, as in Algorithm 1 of SP 800-38D.
+///
+/// GCM uses a "reflected" bit order, so the most significant bit of the u128 is the
+/// coefficient of x^0, and reducing by the field polynomial shifts _right_.
+pub fn mul_slow(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xE1 << 120;
+
+    let mut z = 0u128;
+    let mut v = y;
+    for i in 0..128 {
+        if (x >> (127 - i)) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ R } else { v >> 1 };
+    }
+    z
+}
+
+/// Multiplication in GF(2^128) through a carry-less product and a separate reduction. Gives
+/// exactly the same results as [`mul_slow`], with elements in the same reflected order.
+pub fn mul(x: u128, y: u128) -> u128 {
+    let (high, low) = clmul128(x.reverse_bits(), y.reverse_bits());
+    reduce(high, low).reverse_bits()
+}
+
+/// Reduces the 256-bit polynomial `high * x^128 + low` modulo P(x), in natural bit order.
+///
+/// Since x^128 = x^7 + x^2 + x + 1 modulo P(x), the high half folds into the low half as
+/// `high * (x^7 + x^2 + x + 1)`. That product spills up to 7 bits past x^127, which fold in
+/// the same way a second time, and are then small enough to stay below x^128.
+pub fn reduce(high: u128, low: u128) -> u128 {
+    let fold = |h: u128| h ^ (h << 1) ^ (h << 2) ^ (h << 7);
+    let spill = (high >> 127) ^ (high >> 126) ^ (high >> 121);
+    low ^ fold(high) ^ fold(spill)
+}
+
+/// The carry-less product of two 128-bit polynomials, as its high and low halves.
+pub fn clmul128(x: u128, y: u128) -> (u128, u128) {
+    let (x1, x0) = ((x >> 64) as u64, x as u64);
+    let (y1, y0) = ((y >> 64) as u64, y as u64);
+    let low = clmul64(x0, y0);
+    let high = clmul64(x1, y1);
+    let middle = clmul64(x0, y1) ^ clmul64(x1, y0);
+    (high ^ (middle >> 64), low ^ (middle << 64))
+}
+
+fn clmul64(x: u64, y: u64) -> u128 {
+    let (x1, x0) = ((x >> 32) as u32, x as u32);
+    let (y1, y0) = ((y >> 32) as u32, y as u32);
+    let low = clmul32(x0, y0) as u128;
+    let high = clmul32(x1, y1) as u128;
+    let middle = (clmul32(x0, y1) ^ clmul32(x1, y0)) as u128;
+    (high << 64) ^ (middle << 32) ^ low
+}
+
+/// The carry-less product of two 32-bit polynomials, from integer multiplications.
+///
+/// Keeping only every fourth bit of each operand leaves at most eight one-bits lined up in any
+/// column of the product, so the column sums fit in four bits and the carries between them
+/// never reach the next kept bit. Each kept bit then holds the XOR of its column.
+fn clmul32(x: u32, y: u32) -> u64 {
+    const MASKS: [u32; 4] = [0x1111_1111, 0x2222_2222, 0x4444_4444, 0x8888_8888];
+    let xs = MASKS.map(|mask| (x & mask) as u64);
+    let ys = MASKS.map(|mask| (y & mask) as u64);
+
+    let mut z = 0u64;
+    for (i, mask) in MASKS.iter().enumerate() {
+        let mut column = 0u64;
+        for (j, x) in xs.iter().enumerate() {
+            column ^= x * ys[(i + 4 - j) % 4];
+        }
+        z |= column & (*mask as u64 * 0x1_0000_0001);
+    }
+    z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fast_matches_slow() {
+        let mut state = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210u128;
+        let mut next = || {
+            state = state
+                .wrapping_mul(0x2360_ed05_1fc6_5da4_4385_df64_9fcc_f645)
+                .wrapping_add(0x5851_f42d_4c95_7f2d_1405_7b7e_f767_814f);
+            state
+        };
+        for _ in 0..200 {
+            let (x, y) = (next(), next());
+            assert_eq!(mul(x, y), mul_slow(x, y));
+        }
+        for (x, y) in [(0, u128::MAX), (u128::MAX, u128::MAX), (1, 1 << 127)] {
+            assert_eq!(mul(x, y), mul_slow(x, y));
+        }
+    }
+
+    #[test]
+    fn test_field_laws() {
+        // In GCM's order 1 << 127 is the polynomial 1, and 1 << 126 is x.
+        let one = 1u128 << 127;
+        let x = 1u128 << 126;
+        let a = 0x66e9_4bd4_ef8a_2c3b_884c_fa59_ca34_2b2eu128;
+        assert_eq!(mul(a, one), a);
+        assert_eq!(mul_slow(a, one), a);
+
+        // x^127 * x = x^128 = x^7 + x^2 + x + 1.
+        assert_eq!(mul(1, x), 0xE1 << 120);
+
+        let (b, c) = (0x1234u128 << 100, 0xdead_beefu128);
+        assert_eq!(mul(a, b ^ c), mul(a, b) ^ mul(a, c));
+        assert_eq!(mul(mul(a, b), c), mul(a, mul(b, c)));
+    }
+}
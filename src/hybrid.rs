@@ -0,0 +1,307 @@
+//! Public-key envelopes with a hybrid post-quantum key exchange.
+//!
+//! Everything else in this crate needs the sender and the recipient to share a key. Here the
+//! sender only needs the recipient's [`HybridPublicKey`]: [`seal`] establishes a fresh data key
+//! with both X25519 and ML-KEM-768 (FIPS 203), and seals the plaintext into an ordinary GCM
+//! [envelope](crate::envelope) under it:
+//!
+//! ```text
+//! "AMHY" | version (u8) | X25519 ephemeral public key (32) | ML-KEM-768 ciphertext (1088)
+//!        | envelope
+//! ```
+//!
+//! The data key is HKDF-SHA256 of both shared secrets, with the two key-exchange messages and
+//! the recipient's X25519 key in the info, so it stays secret as long as either exchange holds:
+//! X25519 against today's attacks, ML-KEM against a quantum computer that records the traffic
+//! now to break it later. [`open`] repeats the derivation with the [`HybridSecretKey`]. A wrong
+//! key, or a change to any byte, fails the envelope's tag.
+//!
+//! ML-KEM is young, and its implementation here is audited less than the rest of the stack,
+//! which is the reason to combine it with X25519 rather than use it alone.
+
+use std::{error::Error, fmt};
+
+use hkdf::Hkdf;
+use ml_kem::{
+    kem::{Decapsulate, DecapsulationKey, Encapsulate, EncapsulationKey},
+    EncodedSizeUser, KemCore, MlKem768, MlKem768Params,
+};
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    envelope::{Envelope, EnvelopeError, EnvelopeMode},
+    utils, BLOCK_SIZE,
+};
+
+pub const MAGIC: &[u8; 4] = b"AMHY";
+pub const VERSION: u8 = 1;
+
+const X25519_SIZE: usize = 32;
+const ML_KEM_CIPHERTEXT_SIZE: usize = 1088;
+const ML_KEM_PUBLIC_KEY_SIZE: usize = 1184;
+const ML_KEM_SECRET_KEY_SIZE: usize = 2400;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + X25519_SIZE + ML_KEM_CIPHERTEXT_SIZE;
+const DATA_KEY_INFO: &[u8] = b"aes-modes hybrid v1 data key";
+
+/// Where the key exchanges get their randomness: the crate's RNG, so that the `testing`
+/// feature seeds them as it seeds everything else.
+struct CrateRng;
+
+impl RngCore for CrateRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        utils::fill_random(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for CrateRng {}
+
+/// Why [`open`] failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HybridError {
+    /// The input is too short, or doesn't start with the magic.
+    Malformed,
+    /// It was written by a newer version of the format.
+    UnsupportedVersion(u8),
+    /// The envelope is malformed, or doesn't open under the derived key: the wrong secret key,
+    /// or a modified ciphertext.
+    Envelope(EnvelopeError),
+}
+
+impl fmt::Display for HybridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HybridError::Malformed => f.write_str("malformed hybrid envelope"),
+            HybridError::UnsupportedVersion(version) => {
+                write!(f, "unsupported hybrid envelope version {}", version)
+            }
+            HybridError::Envelope(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for HybridError {}
+
+impl From<EnvelopeError> for HybridError {
+    fn from(error: EnvelopeError) -> Self {
+        HybridError::Envelope(error)
+    }
+}
+
+/// What anyone may encrypt to: an X25519 and an ML-KEM-768 public key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HybridPublicKey {
+    x25519: PublicKey,
+    ml_kem: EncapsulationKey<MlKem768Params>,
+}
+
+impl HybridPublicKey {
+    pub const SIZE: usize = X25519_SIZE + ML_KEM_PUBLIC_KEY_SIZE;
+
+    /// The X25519 key followed by the ML-KEM key, [`SIZE`](Self::SIZE) bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.x25519.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.ml_kem.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (x25519, ml_kem) = bytes.split_first_chunk::<X25519_SIZE>()?;
+        Some(HybridPublicKey {
+            x25519: PublicKey::from(*x25519),
+            ml_kem: EncapsulationKey::from_bytes(ml_kem.try_into().ok()?),
+        })
+    }
+}
+
+/// What only the recipient has. Its bytes are as secret as any key.
+#[derive(Clone)]
+pub struct HybridSecretKey {
+    x25519: StaticSecret,
+    ml_kem: DecapsulationKey<MlKem768Params>,
+}
+
+impl HybridSecretKey {
+    pub const SIZE: usize = X25519_SIZE + ML_KEM_SECRET_KEY_SIZE;
+
+    pub fn generate() -> Self {
+        let (ml_kem, _) = MlKem768::generate(&mut CrateRng);
+        HybridSecretKey {
+            x25519: StaticSecret::random_from_rng(CrateRng),
+            ml_kem,
+        }
+    }
+
+    pub fn public_key(&self) -> HybridPublicKey {
+        HybridPublicKey {
+            x25519: PublicKey::from(&self.x25519),
+            ml_kem: self.ml_kem.encapsulation_key().clone(),
+        }
+    }
+
+    /// The X25519 key followed by the ML-KEM key, [`SIZE`](Self::SIZE) bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.x25519.to_bytes().to_vec();
+        bytes.extend_from_slice(&self.ml_kem.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (x25519, ml_kem) = bytes.split_first_chunk::<X25519_SIZE>()?;
+        Some(HybridSecretKey {
+            x25519: StaticSecret::from(*x25519),
+            ml_kem: DecapsulationKey::from_bytes(ml_kem.try_into().ok()?),
+        })
+    }
+}
+
+impl fmt::Debug for HybridSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HybridSecretKey(..)")
+    }
+}
+
+/// The data key from both shared secrets, bound to the messages that established them.
+fn data_key(
+    ml_kem_secret: &[u8],
+    x25519_secret: &[u8],
+    header: &[u8],
+    recipient: &PublicKey,
+) -> [u8; BLOCK_SIZE] {
+    let mut secrets = ml_kem_secret.to_vec();
+    secrets.extend_from_slice(x25519_secret);
+    let mut info = DATA_KEY_INFO.to_vec();
+    info.extend_from_slice(header);
+    info.extend_from_slice(recipient.as_bytes());
+    let mut key = [0; BLOCK_SIZE];
+    Hkdf::<Sha256>::new(None, &secrets)
+        .expand(&info, &mut key)
+        .expect("16 bytes is a valid HKDF-SHA256 length");
+    key
+}
+
+/// Encrypts `plain_text` to the holder of `recipient`'s secret key.
+pub fn seal(recipient: &HybridPublicKey, plain_text: Vec<u8>) -> Vec<u8> {
+    let ephemeral = StaticSecret::random_from_rng(CrateRng);
+    let x25519_secret = ephemeral.diffie_hellman(&recipient.x25519);
+    let (ml_kem_ciphertext, ml_kem_secret) = recipient
+        .ml_kem
+        .encapsulate(&mut CrateRng)
+        .expect("ML-KEM encapsulation can't fail");
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.extend_from_slice(PublicKey::from(&ephemeral).as_bytes());
+    bytes.extend_from_slice(&ml_kem_ciphertext);
+    let key = data_key(
+        &ml_kem_secret,
+        x25519_secret.as_bytes(),
+        &bytes,
+        &recipient.x25519,
+    );
+    bytes.extend(Envelope::seal(EnvelopeMode::Gcm, 0, key, plain_text).to_bytes());
+    bytes
+}
+
+/// Decrypts what [`seal`] encrypted to `secret_key`'s public key.
+pub fn open(secret_key: &HybridSecretKey, bytes: &[u8]) -> Result<Vec<u8>, HybridError> {
+    if bytes.len() < HEADER_SIZE || !bytes.starts_with(MAGIC) {
+        return Err(HybridError::Malformed);
+    }
+    let (header, envelope) = bytes.split_at(HEADER_SIZE);
+    if header[MAGIC.len()] != VERSION {
+        return Err(HybridError::UnsupportedVersion(header[MAGIC.len()]));
+    }
+    let (ephemeral, ml_kem_ciphertext) = header[MAGIC.len() + 1..]
+        .split_first_chunk::<X25519_SIZE>()
+        .expect("the header has room for the ephemeral key");
+    let envelope = Envelope::from_bytes(envelope)?;
+    if envelope.mode != EnvelopeMode::Gcm {
+        return Err(HybridError::Envelope(EnvelopeError::Malformed));
+    }
+
+    let x25519_secret = secret_key
+        .x25519
+        .diffie_hellman(&PublicKey::from(*ephemeral));
+    // ML-KEM rejects implicitly: a modified ciphertext gives an unrelated secret, which then
+    // fails the tag like any wrong key.
+    let ml_kem_secret = secret_key
+        .ml_kem
+        .decapsulate(ml_kem_ciphertext.try_into().unwrap())
+        .expect("ML-KEM decapsulation can't fail");
+    let key = data_key(
+        &ml_kem_secret,
+        x25519_secret.as_bytes(),
+        header,
+        &PublicKey::from(&secret_key.x25519),
+    );
+    Ok(envelope.open(key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let secret_key = HybridSecretKey::generate();
+        let public_key = HybridPublicKey::from_bytes(&secret_key.public_key().to_bytes()).unwrap();
+        assert_eq!(public_key.to_bytes().len(), HybridPublicKey::SIZE);
+        let sealed = seal(&public_key, b"for the recipient only".to_vec());
+        assert_ne!(
+            sealed,
+            seal(&public_key, b"for the recipient only".to_vec())
+        );
+
+        let restored = HybridSecretKey::from_bytes(&secret_key.to_bytes()).unwrap();
+        assert_eq!(restored.to_bytes().len(), HybridSecretKey::SIZE);
+        assert_eq!(open(&restored, &sealed).unwrap(), b"for the recipient only");
+        assert_eq!(
+            open(&HybridSecretKey::generate(), &sealed),
+            Err(HybridError::Envelope(EnvelopeError::Authentication))
+        );
+    }
+
+    #[test]
+    fn test_rejects_modified_exchanges() {
+        let secret_key = HybridSecretKey::generate();
+        let sealed = seal(&secret_key.public_key(), b"hybrid".to_vec());
+        // Either key exchange alone is enough to break the data key.
+        for index in [MAGIC.len() + 1, MAGIC.len() + 1 + X25519_SIZE + 100] {
+            let mut modified = sealed.clone();
+            modified[index] ^= 1;
+            assert_eq!(
+                open(&secret_key, &modified),
+                Err(HybridError::Envelope(EnvelopeError::Authentication))
+            );
+        }
+        let mut newer = sealed.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            open(&secret_key, &newer),
+            Err(HybridError::UnsupportedVersion(VERSION + 1))
+        );
+        assert_eq!(
+            open(&secret_key, &sealed[..HEADER_SIZE - 1]),
+            Err(HybridError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//! Counting GCM invocations per key, across restarts.
+//!
+//! NIST SP 800-38D (section 8.3) caps how many times one GCM key may be used: with random
+//! 96-bit nonces, at most 2^32 messages, because beyond that a nonce collision gets too likely.
+//! A service that restarts every few minutes can't keep that count in memory.
+//!
+//! A [`CountingGcm`] keeps the count in a [`CounterStore`] instead, and also uses it to build
+//! the nonces: `fixed field (4 bytes) | invocation counter (8 bytes)`. That is the deterministic
+//! construction from section 8.2.1. With a persistent counter the nonces can never repeat, not
+//! even across restarts, which random nonces only promise with high probability.
+//!
+//! Writing to the store for every message would be slow, so counters are reserved in blocks.
+//! The store is always updated _before_ a reserved value is used. After a crash, whatever was
+//! left of the last block is skipped rather than reused.
+//!
+//! The nonces themselves come from a [`NonceBuilder`], which can also be used on its own, for
+//! CTR or for GCM used elsewhere. Fleets of devices sharing a key give each device its own
+//! 4-byte prefix, for example its serial number.
+//!
+//! # Forking
+//!
+//! A forked child inherits its parent's builders, reserved block and all, and would hand out
+//! the same nonces as the parent. So a builder remembers which process made its reservation,
+//! and one used from another process reserves a fresh block before its first nonce. That only
+//! helps if the store is shared: a [`FileStore`] is, and on Unix it also locks each counter
+//! file while reserving, so forked workers take turns. A [`MemoryStore`] is copied by the fork
+//! like everything else, and must not be used across one.
+//!
+//! # Truncated tags
+//!
+//! Some protocols need tags shorter than 16 bytes. Down to 12 bytes that costs little, but with
+//! 8- or 4-byte tags a forger gets a real chance with every decryption attempt, so appendix C
+//! of SP 800-38D limits how many decryptions a key may do, depending on how long the packets
+//! are. [`CountingGcm::allow_truncated_tags`] is the only way to get short tags here: it looks
+//! up the limit with [`max_invocations`], applies it to encryptions, and keeps a second,
+//! equally persistent counter of decryptions. Every message sealed with a short tag is also
+//! reported as a [`Warning::ShortTag`].
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+};
+
+use crate::{
+    files::{write_atomically, Durability},
+    gcm::{gcm_encrypt, AuthenticationError, GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    warnings::{self, Warning},
+    BLOCK_SIZE, NONCE_SIZE,
+};
+
+pub const PREFIX_SIZE: usize = 4;
+
+/// The most invocations NIST allows per key, and the default limit.
+pub const MAX_INVOCATIONS: u64 = 1 << 32;
+
+/// The tag lengths SP 800-38D allows, in bytes.
+pub const ALLOWED_TAG_LENS: [u8; 7] = [16, 15, 14, 13, 12, 8, 4];
+
+/// SP 800-38D, table 8 (32-bit tags) and table 9 (64-bit tags): for packets (ciphertext plus
+/// associated data) of up to 2^a bytes, a key may be used for at most 2^b decryptions.
+const TAG_32_LIMITS: [(u32, u32); 10] = [
+    (1, 22),
+    (2, 20),
+    (3, 18),
+    (4, 16),
+    (5, 14),
+    (6, 12),
+    (7, 10),
+    (8, 8),
+    (9, 6),
+    (10, 4),
+];
+const TAG_64_LIMITS: [(u32, u32); 10] = [
+    (15, 32),
+    (17, 29),
+    (19, 26),
+    (21, 23),
+    (23, 20),
+    (25, 17),
+    (27, 14),
+    (29, 11),
+    (31, 8),
+    (32, 5),
+];
+
+/// How many times SP 800-38D lets one key be used with `tag_len`-byte tags, when no packet is
+/// longer than `max_packet_len` bytes. `None` if that combination isn't allowed at all.
+pub fn max_invocations(tag_len: u8, max_packet_len: u64) -> Option<u64> {
+    let table = match tag_len {
+        12..=16 => return Some(MAX_INVOCATIONS),
+        8 => &TAG_64_LIMITS,
+        4 => &TAG_32_LIMITS,
+        _ => return None,
+    };
+    table
+        .iter()
+        .find(|(length, _)| max_packet_len <= 1 << length)
+        .map(|(_, invocations)| 1 << invocations)
+}
+
+/// How many counter values are reserved from the store at a time.
+pub const DEFAULT_RESERVATION: u64 = 1024;
+
+/// The key has been used as often as it may be, and must be replaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvocationLimitReached;
+
+impl fmt::Display for InvocationLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the key has reached its invocation limit")
+    }
+}
+
+impl Error for InvocationLimitReached {}
+
+/// Somewhere to keep invocation counters, keyed by a key ID.
+pub trait CounterStore {
+    /// Advances the counter for `key_id` by `count`, and returns its value from before. The new
+    /// value must be durable before this returns.
+    fn reserve(&self, key_id: &str, count: u64) -> io::Result<u64>;
+}
+
+/// Counters in memory, for tests and for keys that never outlive the process, or cross a fork.
+pub struct MemoryStore {
+    counters: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CounterStore for MemoryStore {
+    fn reserve(&self, key_id: &str, count: u64) -> io::Result<u64> {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(key_id.to_string()).or_insert(0);
+        let start = *counter;
+        *counter = start.saturating_add(count);
+        Ok(start)
+    }
+}
+
+/// One small file per key in a directory, replaced atomically on every reservation. Processes
+/// may share the directory.
+pub struct FileStore {
+    directory: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(FileStore {
+            directory,
+            lock: Mutex::new(()),
+        })
+    }
+}
+
+impl CounterStore for FileStore {
+    fn reserve(&self, key_id: &str, count: u64) -> io::Result<u64> {
+        if key_id.is_empty()
+            || !key_id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key IDs may only contain letters, digits and dashes",
+            ));
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        let _file_lock = lock_file(&self.directory.join(format!("{}.lock", key_id)))?;
+        let path = self.directory.join(format!("{}.counter", key_id));
+        let start =
+            match fs::read(&path) {
+                Ok(bytes) => u64::from_be_bytes(bytes.try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "corrupt counter file")
+                })?),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+                Err(error) => return Err(error),
+            };
+
+        let next = start.saturating_add(count);
+        write_atomically(&path, Durability::Full, |file| {
+            file.write_all(&next.to_be_bytes())
+        })?;
+        Ok(start)
+    }
+}
+
+/// Holds an exclusive `flock` on `path` until the returned file is closed.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::fd::AsRawFd;
+
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    // SAFETY: the descriptor stays open for the duration of the call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Only the threads of one process are kept from racing elsewhere.
+#[cfg(not(unix))]
+fn lock_file(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Builds nonces as `device prefix | counter`, with the counter kept in a [`CounterStore`].
+pub struct NonceBuilder<'a, S: CounterStore> {
+    prefix: [u8; PREFIX_SIZE],
+    key_id: String,
+    store: &'a S,
+    limit: u64,
+    reservation: u64,
+    next: u64,
+    reserved_until: u64,
+    /// The process that reserved the block up to `reserved_until`.
+    reserved_by: u32,
+}
+
+impl<'a, S: CounterStore> NonceBuilder<'a, S> {
+    /// `prefix` tells apart the devices or processes sharing one key, and must be unique among
+    /// them. `key_id` names the counter in the store: one per key.
+    pub fn new(prefix: [u8; PREFIX_SIZE], key_id: &str, store: &'a S) -> Self {
+        NonceBuilder {
+            prefix,
+            key_id: key_id.to_string(),
+            store,
+            limit: MAX_INVOCATIONS,
+            reservation: DEFAULT_RESERVATION,
+            next: 0,
+            reserved_until: 0,
+            reserved_by: process::id(),
+        }
+    }
+
+    /// Lowers the number of nonces handed out, to rotate keys earlier than NIST requires.
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        assert!(
+            limit <= MAX_INVOCATIONS,
+            "the limit can't exceed {}",
+            MAX_INVOCATIONS
+        );
+        self.limit = limit;
+        self
+    }
+
+    /// Changes how many counter values are reserved per write to the store.
+    pub fn with_reservation(mut self, reservation: u64) -> Self {
+        assert!(reservation > 0, "the reservation can't be zero");
+        self.reservation = reservation;
+        self
+    }
+
+    /// How many more nonces there are, as far as this builder knows. Other builders sharing the
+    /// store may have used some of them already.
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.next)
+    }
+
+    fn next_counter(&mut self) -> io::Result<u64> {
+        let pid = process::id();
+        if self.next == self.reserved_until || self.reserved_by != pid {
+            let start = self.store.reserve(&self.key_id, self.reservation)?;
+            self.next = start;
+            self.reserved_until = start.saturating_add(self.reservation);
+            self.reserved_by = pid;
+        }
+        if self.next >= self.limit {
+            return Err(io::Error::other(InvocationLimitReached));
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+
+    /// A 96-bit GCM nonce: the prefix and a 64-bit counter.
+    pub fn next_gcm_nonce(&mut self) -> io::Result<[u8; GCM_NONCE_SIZE]> {
+        let counter = self.next_counter()?;
+        let mut nonce = [0u8; GCM_NONCE_SIZE];
+        nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[PREFIX_SIZE..].copy_from_slice(&counter.to_be_bytes());
+        Ok(nonce)
+    }
+
+    /// A 64-bit nonce for [`ctr_encrypt_with_nonce`](crate::ctr_encrypt_with_nonce): the
+    /// prefix and a 32-bit counter, which caps it at 2^32 nonces like GCM.
+    pub fn next_ctr_nonce(&mut self) -> io::Result<[u8; NONCE_SIZE]> {
+        let counter = self.next_counter()?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[PREFIX_SIZE..].copy_from_slice(&(counter as u32).to_be_bytes());
+        Ok(nonce)
+    }
+}
+
+/// GCM with deterministic nonces from a persistent invocation counter.
+pub struct CountingGcm<'a, S: CounterStore> {
+    key: [u8; BLOCK_SIZE],
+    nonces: NonceBuilder<'a, S>,
+    truncation: Option<Truncation<'a, S>>,
+}
+
+/// The tag length and packet size allowed by [`CountingGcm::allow_truncated_tags`], and the
+/// counter that limits decryptions.
+struct Truncation<'a, S: CounterStore> {
+    tag_len: u8,
+    max_packet_len: u64,
+    decryptions: NonceBuilder<'a, S>,
+}
+
+impl<'a, S: CounterStore> CountingGcm<'a, S> {
+    /// See [`NonceBuilder::new`] for `key_id` and `fixed_field`.
+    pub fn new(key: [u8; BLOCK_SIZE], key_id: &str, fixed_field: [u8; 4], store: &'a S) -> Self {
+        CountingGcm {
+            key,
+            nonces: NonceBuilder::new(fixed_field, key_id, store),
+            truncation: None,
+        }
+    }
+
+    /// Opts in to `tag_len`-byte tags, which must be one of [`ALLOWED_TAG_LENS`], for packets
+    /// (ciphertext plus associated data) of at most `max_packet_len` bytes.
+    ///
+    /// Both encryptions and decryptions are then limited as [`max_invocations`] says, with
+    /// decryptions counted in the store under `"<key_id>-decryptions"`. Packets that are too
+    /// long are refused in both directions.
+    ///
+    /// # Panics
+    ///
+    /// If SP 800-38D doesn't allow the combination at all.
+    pub fn allow_truncated_tags(mut self, tag_len: u8, max_packet_len: u64) -> Self {
+        assert!(
+            ALLOWED_TAG_LENS.contains(&tag_len),
+            "SP 800-38D doesn't allow {}-byte tags",
+            tag_len
+        );
+        let limit = max_invocations(tag_len, max_packet_len)
+            .expect("packets are too long for this tag length");
+        let decryptions = NonceBuilder::new(
+            [0; PREFIX_SIZE],
+            &format!("{}-decryptions", self.nonces.key_id),
+            self.nonces.store,
+        )
+        .with_limit(limit);
+        self.nonces.limit = self.nonces.limit.min(limit);
+        self.truncation = Some(Truncation {
+            tag_len,
+            max_packet_len,
+            decryptions,
+        });
+        self
+    }
+
+    fn tag_len(&self) -> usize {
+        self.truncation
+            .as_ref()
+            .map_or(TAG_SIZE, |truncation| truncation.tag_len as usize)
+    }
+
+    fn check_packet_len(&self, len: usize) -> io::Result<()> {
+        match &self.truncation {
+            Some(truncation) if len as u64 > truncation.max_packet_len => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet too long for the truncated tag policy",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lowers the number of messages the key may encrypt. A limit from
+    /// [`allow_truncated_tags`](Self::allow_truncated_tags) is never raised, whichever is
+    /// called first.
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        let limit = limit.min(self.nonces.limit);
+        self.nonces = self.nonces.with_limit(limit);
+        self
+    }
+
+    pub fn with_reservation(mut self, reservation: u64) -> Self {
+        self.nonces = self.nonces.with_reservation(reservation);
+        self
+    }
+
+    /// How many more messages the key may encrypt, as far as this instance knows.
+    pub fn remaining(&self) -> u64 {
+        self.nonces.remaining()
+    }
+
+    /// Encrypts the next message, returning `nonce | ciphertext | tag`.
+    pub fn encrypt(&mut self, plain_text: Vec<u8>, aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.check_packet_len(plain_text.len() + aad.len())?;
+        let nonce = self.nonces.next_gcm_nonce()?;
+        if self.tag_len() < TAG_SIZE {
+            warnings::warn(Warning::ShortTag {
+                len: self.tag_len(),
+            });
+        }
+        let mut output = nonce.to_vec();
+        output.extend(gcm_encrypt(plain_text, self.key, nonce, aad));
+        output.truncate(output.len() - TAG_SIZE + self.tag_len());
+        Ok(output)
+    }
+
+    /// Decrypts `nonce | ciphertext | tag` as written by [`CountingGcm::encrypt`]. With
+    /// truncated tags, every attempt counts against the decryption limit, successful or not.
+    pub fn decrypt(&mut self, message: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let tag_len = self.tag_len();
+        if message.len() < GCM_NONCE_SIZE + tag_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                AuthenticationError,
+            ));
+        }
+        self.check_packet_len(message.len() - GCM_NONCE_SIZE - tag_len + aad.len())?;
+        if let Some(truncation) = &mut self.truncation {
+            truncation.decryptions.next_counter()?;
+        }
+
+        let (nonce, rest) = message.split_at(GCM_NONCE_SIZE);
+        GcmKey::new(self.key)
+            .open_truncated(nonce.try_into().unwrap(), rest, aad, tag_len)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gcm::gcm_decrypt, utils};
+
+    const KEY: [u8; BLOCK_SIZE] = [5u8; BLOCK_SIZE];
+
+    fn nonce_counter(message: &[u8]) -> u64 {
+        u64::from_be_bytes(message[4..GCM_NONCE_SIZE].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_nonces_never_repeat_across_restarts() {
+        let directory = std::env::temp_dir().join(format!(
+            "aes-modes-counters-{:016x}",
+            u64::from_be_bytes(utils::create_rand_nonce())
+        ));
+        let store = FileStore::new(&directory).unwrap();
+
+        let mut gcm = CountingGcm::new(KEY, "key-1", [0, 0, 0, 1], &store).with_reservation(10);
+        let first: Vec<u64> = (0..3)
+            .map(|_| nonce_counter(&gcm.encrypt(b"hello".to_vec(), b"").unwrap()))
+            .collect();
+        assert_eq!(first, [0, 1, 2]);
+
+        // A restarted process skips the rest of the old reservation.
+        let store = FileStore::new(&directory).unwrap();
+        let mut gcm = CountingGcm::new(KEY, "key-1", [0, 0, 0, 1], &store).with_reservation(10);
+        let message = gcm.encrypt(b"hello".to_vec(), b"aad").unwrap();
+        assert_eq!(nonce_counter(&message), 10);
+        assert_eq!(
+            gcm_decrypt(
+                message[GCM_NONCE_SIZE..].to_vec(),
+                KEY,
+                message[..GCM_NONCE_SIZE].try_into().unwrap(),
+                b"aad"
+            ),
+            Ok(b"hello".to_vec())
+        );
+
+        // Other keys have their own counters.
+        let mut other = CountingGcm::new(KEY, "key-2", [0, 0, 0, 1], &store);
+        assert_eq!(nonce_counter(&other.encrypt(Vec::new(), b"").unwrap()), 0);
+        assert!(CountingGcm::new(KEY, "../escape", [0; 4], &store)
+            .encrypt(Vec::new(), b"")
+            .is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forked_child_reserves_its_own_block() {
+        let directory = std::env::temp_dir().join(format!(
+            "aes-modes-fork-{:016x}",
+            u64::from_be_bytes(utils::create_rand_nonce())
+        ));
+        let store = FileStore::new(&directory).unwrap();
+        let mut nonces = NonceBuilder::new(*b"fork", "key", &store).with_reservation(10);
+        nonces.next_gcm_nonce().unwrap();
+
+        let child = utils::in_forked_child(|| nonces.next_gcm_nonce().unwrap().to_vec());
+        assert_eq!(nonce_counter(&child), 10);
+        assert_eq!(nonce_counter(&nonces.next_gcm_nonce().unwrap()), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_limit_is_enforced() {
+        let store = MemoryStore::new();
+        let mut gcm = CountingGcm::new(KEY, "key", [0; 4], &store)
+            .with_limit(5)
+            .with_reservation(2);
+        for _ in 0..5 {
+            gcm.encrypt(Vec::new(), b"").unwrap();
+        }
+        assert_eq!(gcm.remaining(), 0);
+        let error = gcm.encrypt(Vec::new(), b"").unwrap_err();
+        assert_eq!(
+            *error
+                .into_inner()
+                .unwrap()
+                .downcast::<InvocationLimitReached>()
+                .unwrap(),
+            InvocationLimitReached
+        );
+
+        // The limit holds for every instance sharing the store.
+        let mut again = CountingGcm::new(KEY, "key", [0; 4], &store).with_limit(5);
+        assert!(again.encrypt(Vec::new(), b"").is_err());
+    }
+
+    #[test]
+    fn test_truncated_tags() {
+        assert_eq!(max_invocations(12, u64::MAX), Some(MAX_INVOCATIONS));
+        assert_eq!(max_invocations(8, 1 << 15), Some(1 << 32));
+        assert_eq!(max_invocations(8, 8_000_000), Some(1 << 20));
+        assert_eq!(max_invocations(4, 100), Some(1 << 10));
+        assert_eq!(max_invocations(4, 2000), None);
+        assert_eq!(max_invocations(10, 16), None);
+
+        let store = MemoryStore::new();
+        let mut gcm = CountingGcm::new(KEY, "short", [0; 4], &store).allow_truncated_tags(4, 64);
+        let message = gcm.encrypt(b"sensor reading".to_vec(), b"id").unwrap();
+        assert_eq!(message.len(), GCM_NONCE_SIZE + 14 + 4);
+        assert_eq!(gcm.decrypt(&message, b"id").unwrap(), b"sensor reading");
+        assert!(gcm.decrypt(&message, b"other").is_err());
+        assert!(gcm.encrypt(vec![0u8; 65], b"").is_err());
+
+        // 2^12 decryptions in all, counted in the store like the nonces.
+        for _ in 2..1 << 12 {
+            assert!(gcm.decrypt(&message, b"other").is_err());
+        }
+        let error = gcm.decrypt(&message, b"id").unwrap_err();
+        assert!(error.into_inner().unwrap().is::<InvocationLimitReached>());
+        let mut again = CountingGcm::new(KEY, "short", [0; 4], &store).allow_truncated_tags(4, 64);
+        assert!(again.decrypt(&message, b"id").is_err());
+
+        // A later limit can lower the truncated-tag limit, but not raise it.
+        let raised = CountingGcm::new(KEY, "raised", [0; 4], &store)
+            .allow_truncated_tags(4, 1024)
+            .with_limit(MAX_INVOCATIONS);
+        assert_eq!(raised.remaining(), 16);
+        let lowered = CountingGcm::new(KEY, "lowered", [0; 4], &store)
+            .allow_truncated_tags(4, 1024)
+            .with_limit(3);
+        assert_eq!(lowered.remaining(), 3);
+    }
+
+    #[test]
+    fn test_ctr_nonces_for_a_fleet() {
+        let store = MemoryStore::new();
+        let mut device_a = NonceBuilder::new(*b"dev1", "fleet-key", &store);
+        let mut device_b = NonceBuilder::new(*b"dev2", "fleet-key", &store);
+
+        let nonce = device_a.next_ctr_nonce().unwrap();
+        assert_eq!(nonce, *b"dev1\0\0\0\0");
+        assert_eq!(device_a.next_ctr_nonce().unwrap(), *b"dev1\0\0\0\x01");
+        assert_eq!(&device_b.next_ctr_nonce().unwrap()[..4], b"dev2");
+
+        let cipher_text = crate::ctr_encrypt_with_nonce(b"telemetry".to_vec(), KEY, nonce);
+        assert_eq!(&cipher_text[..NONCE_SIZE], &nonce);
+        assert_eq!(crate::ctr_decrypt(cipher_text, KEY), b"telemetry");
+    }
+}
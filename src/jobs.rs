@@ -0,0 +1,219 @@
+//! Parallel CTR and ECB without threads: the work is split into jobs for the caller to run.
+//!
+//! [`chunked::encrypt_parallel`](crate::chunked::encrypt_parallel) and
+//! [`batch::encrypt_batch_parallel`](crate::batch::encrypt_batch_parallel) spawn their own
+//! threads, which some hosts don't allow: browser plugins, WASM without threads, runtimes that
+//! count every thread against a quota. CTR and ECB need no threads of their own to run in
+//! parallel, since every block is independent of the others. [`Work`] describes an operation
+//! over a buffer, and [`Work::split_into_jobs`] cuts it into [`Job`]s over disjoint ranges of
+//! blocks, each a `Send` closure that the caller hands to whatever executor it has, in any
+//! order, on any number of threads, or one after another.
+//!
+//! The split depends only on the buffer's length and the number of jobs asked for, and every
+//! job writes only its own range, so the result is the same however the jobs are scheduled,
+//! and the same as the sequential functions give:
+//!
+//! ```text
+//! ctr_encrypt_with_nonce(plain_text, key, nonce) == nonce | Work::ctr(key, nonce, plain_text)
+//! ecb_encrypt(plain_text, key)                   == Work::ecb_encrypt(key, pad(plain_text))
+//! ```
+//!
+//! CTR is its own inverse, so [`Work::ctr`] also decrypts. CBC encryption can't be split this
+//! way, since every block needs the one before it.
+
+use std::{fmt, ops::Range};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{
+    util,
+    warnings::{self, Warning},
+    BLOCK_SIZE, NONCE_SIZE,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    CtrKeystream([u8; NONCE_SIZE]),
+    EcbEncrypt,
+    EcbDecrypt,
+}
+
+/// An operation on a buffer, in place, still to be done.
+pub struct Work<'a> {
+    key: [u8; BLOCK_SIZE],
+    operation: Operation,
+    data: &'a mut [u8],
+}
+
+impl<'a> Work<'a> {
+    /// XORs `data` with the CTR keystream for `nonce`, counting from zero as
+    /// [`ctr_encrypt_with_nonce`](crate::ctr_encrypt_with_nonce) does. This encrypts or
+    /// decrypts; the nonce is not part of `data`.
+    pub fn ctr(key: [u8; BLOCK_SIZE], nonce: [u8; NONCE_SIZE], data: &'a mut [u8]) -> Self {
+        Work {
+            key,
+            operation: Operation::CtrKeystream(nonce),
+            data,
+        }
+    }
+
+    /// Encrypts every block of `data` on its own. Pad it first with [`pad`](crate::pad).
+    ///
+    /// # Panics
+    ///
+    /// If `data` isn't a whole number of blocks.
+    pub fn ecb_encrypt(key: [u8; BLOCK_SIZE], data: &'a mut [u8]) -> Self {
+        Self::ecb(key, Operation::EcbEncrypt, data)
+    }
+
+    /// Decrypts every block of `data` on its own. Unpad it afterwards with
+    /// [`un_pad`](crate::un_pad).
+    ///
+    /// # Panics
+    ///
+    /// If `data` isn't a whole number of blocks.
+    pub fn ecb_decrypt(key: [u8; BLOCK_SIZE], data: &'a mut [u8]) -> Self {
+        Self::ecb(key, Operation::EcbDecrypt, data)
+    }
+
+    fn ecb(key: [u8; BLOCK_SIZE], operation: Operation, data: &'a mut [u8]) -> Self {
+        assert!(
+            data.len().is_multiple_of(BLOCK_SIZE),
+            "ECB works on whole blocks"
+        );
+        warnings::warn(Warning::Ecb);
+        Work {
+            key,
+            operation,
+            data,
+        }
+    }
+
+    /// Splits the work into at most `jobs` jobs of nearly equal size, in order of their
+    /// ranges. An empty buffer gives no jobs.
+    ///
+    /// # Panics
+    ///
+    /// If `jobs` is zero.
+    pub fn split_into_jobs(self, jobs: usize) -> Vec<Job<'a>> {
+        assert!(jobs > 0, "the work must be split into at least one job");
+        let blocks = self.data.len().div_ceil(BLOCK_SIZE);
+        let blocks_per_job = blocks.div_ceil(jobs).max(1);
+        let (key, operation) = (self.key, self.operation);
+        self.data
+            .chunks_mut(blocks_per_job * BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, data)| {
+                let first_block = i * blocks_per_job;
+                let start = first_block * BLOCK_SIZE;
+                Job {
+                    range: start..start + data.len(),
+                    work: Box::new(move || apply(key, operation, first_block as u64, data)),
+                }
+            })
+            .collect()
+    }
+
+    /// Does all of the work on the calling thread.
+    pub fn run(self) {
+        apply(self.key, self.operation, 0, self.data);
+    }
+}
+
+impl fmt::Debug for Work<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Work")
+            .field("len", &self.data.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs `operation` over `data`, whose first block is block `first_block` of the buffer.
+fn apply(key: [u8; BLOCK_SIZE], operation: Operation, first_block: u64, data: &mut [u8]) {
+    let cipher = Aes128::new(&GenericArray::from(key));
+    for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+        match operation {
+            Operation::CtrKeystream(nonce) => {
+                let counter = first_block + i as u64;
+                let mut keystream = GenericArray::from(util::ctr_counter_block(&nonce, counter));
+                cipher.encrypt_block(&mut keystream);
+                util::xor_in_place(chunk, &keystream[..chunk.len()]);
+            }
+            Operation::EcbEncrypt => cipher.encrypt_block(GenericArray::from_mut_slice(chunk)),
+            Operation::EcbDecrypt => cipher.decrypt_block(GenericArray::from_mut_slice(chunk)),
+        }
+    }
+}
+
+/// One piece of a [`Work`], over its own range of the buffer.
+pub struct Job<'a> {
+    range: Range<usize>,
+    work: Box<dyn FnOnce() + Send + 'a>,
+}
+
+impl Job<'_> {
+    /// The bytes of the buffer this job writes.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    pub fn run(self) {
+        (self.work)()
+    }
+}
+
+impl fmt::Debug for Job<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("range", &self.range)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ctr_encrypt_with_nonce, ecb_encrypt, pad, un_pad};
+
+    const KEY: [u8; BLOCK_SIZE] = [8; BLOCK_SIZE];
+    const NONCE: [u8; NONCE_SIZE] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn test_ctr_jobs_match_sequential() {
+        let plain_text: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let expected = ctr_encrypt_with_nonce(plain_text.clone(), KEY, NONCE);
+        for jobs in [1, 2, 3, 7, 100] {
+            let mut data = plain_text.clone();
+            let mut split = Work::ctr(KEY, NONCE, &mut data).split_into_jobs(jobs);
+            assert!(split.len() <= jobs);
+            assert_eq!(split.last().unwrap().range().end, plain_text.len());
+            // Out of order, as an executor might run them.
+            split.reverse();
+            split.into_iter().for_each(Job::run);
+            assert_eq!(data, expected[NONCE_SIZE..]);
+
+            Work::ctr(KEY, NONCE, &mut data).run();
+            assert_eq!(data, plain_text);
+        }
+        assert!(Work::ctr(KEY, NONCE, &mut []).split_into_jobs(4).is_empty());
+    }
+
+    #[test]
+    fn test_ecb_jobs_on_threads() {
+        let plain_text = b"independent blocks, scheduled by the caller".to_vec();
+        let mut data = pad(plain_text.clone());
+        let jobs = Work::ecb_encrypt(KEY, &mut data).split_into_jobs(3);
+        std::thread::scope(|scope| {
+            for job in jobs {
+                scope.spawn(|| job.run());
+            }
+        });
+        assert_eq!(data, ecb_encrypt(plain_text.clone(), KEY));
+
+        Work::ecb_decrypt(KEY, &mut data).run();
+        assert_eq!(un_pad(data), plain_text);
+    }
+}
@@ -0,0 +1,413 @@
+//! A small key server, so several local processes can share keys without any of them holding
+//! the key material.
+//!
+//! The server owns a [`KeyManager`] and speaks a minimal subset of HTTP/1.1 over a Unix socket.
+//! Every request needs an `Authorization: Bearer <token>` header with the shared token, and
+//! request and response bodies are raw bytes:
+//!
+//! | Request        | Body                       | Response                          |
+//! |----------------|----------------------------|-----------------------------------|
+//! | `POST /keys`   | empty                      | the new key version, as decimal   |
+//! | `POST /wrap`   | a 16-byte data key         | the version, then the wrapped key |
+//! | `POST /unwrap` | output of `/wrap`          | the data key                      |
+//! | `POST /encrypt`| plaintext                  | a [`KeyManager`] ciphertext (GCM) |
+//! | `POST /decrypt`| output of `/encrypt`       | plaintext                         |
+//!
+//! Wrapping uses AES-KW under the current key, so clients can do envelope encryption with
+//! their own data keys and only ever ask the server to wrap and unwrap them. Both wrapping and
+//! `/encrypt` go through the key's [`KeyPolicy`] and count towards its limits.
+//!
+//! `/encrypt` uses [`KeyManager::encrypt_aead`], so `/decrypt` refuses a ciphertext that was
+//! changed in transit or at rest, or relabelled with another key version. A server can be set
+//! to one of the unauthenticated [`Mode`]s instead with [`KeyServer::with_mode`], for clients
+//! that expect them.
+//!
+//! The socket's file permissions are the first line of defence; the token is the second. The
+//! `aes-keyd` binary, behind the `key-server` feature, runs this server.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{
+    files::{write_atomically, Durability},
+    gcm::constant_time_eq,
+    keys::{KeyManager, KeyPolicy, PolicyError},
+    Mode, BLOCK_SIZE,
+};
+
+/// Requests bigger than this are refused, so a client can't make the server allocate freely.
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// One parsed request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub token: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// The status code and body of a reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(body: Vec<u8>) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Response {
+            status,
+            body: message.to_string().into_bytes(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+fn policy_response(error: PolicyError) -> Response {
+    match error {
+        PolicyError::Malformed => Response::error(400, error),
+        _ => Response::error(403, error),
+    }
+}
+
+/// The shared state behind the socket.
+pub struct KeyServer {
+    manager: Mutex<KeyManager>,
+    token: Vec<u8>,
+    /// `None` for GCM.
+    mode: Option<Mode>,
+    policy: KeyPolicy,
+    keyring: Option<(PathBuf, [u8; BLOCK_SIZE])>,
+}
+
+impl KeyServer {
+    /// A server for `manager`, accepting requests that carry `token`. `/encrypt` uses GCM, and
+    /// new keys get no usage limits.
+    pub fn new(manager: KeyManager, token: &[u8]) -> Self {
+        KeyServer {
+            manager: Mutex::new(manager),
+            token: token.to_vec(),
+            mode: None,
+            policy: KeyPolicy::default(),
+            keyring: None,
+        }
+    }
+
+    /// Makes `/encrypt` use `mode`, which isn't authenticated: whoever can change its
+    /// ciphertexts changes the plaintext `/decrypt` returns, undetected.
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// The policy keys generated through `POST /keys` get.
+    pub fn with_key_policy(mut self, policy: KeyPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Saves the keyring, sealed under `kek`, to `path` after every change, including the usage
+    /// counts that policies depend on.
+    pub fn with_keyring_file(mut self, path: impl Into<PathBuf>, kek: [u8; BLOCK_SIZE]) -> Self {
+        self.keyring = Some((path.into(), kek));
+        self
+    }
+
+    fn save(&self, manager: &KeyManager) -> io::Result<()> {
+        match &self.keyring {
+            Some((path, kek)) => write_atomically(path, Durability::Full, |file| {
+                file.write_all(&manager.seal(*kek))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Answers one request.
+    pub fn handle(&self, request: &Request) -> Response {
+        let authorized = request
+            .token
+            .as_ref()
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), &self.token));
+        if !authorized {
+            return Response::error(401, "missing or wrong token");
+        }
+        if request.method != "POST" {
+            return Response::error(404, "not found");
+        }
+
+        let mut manager = self.manager.lock().unwrap();
+        let (response, changed) = match request.path.as_str() {
+            "/keys" => {
+                let version = manager.generate(self.policy.clone());
+                (Response::ok(version.to_string().into_bytes()), true)
+            }
+            "/wrap" => match <[u8; BLOCK_SIZE]>::try_from(&request.body[..]) {
+                Ok(data_key) => match manager.wrap_data_key(&data_key) {
+                    Ok(wrapped) => (Response::ok(wrapped), true),
+                    Err(error) => (policy_response(error), false),
+                },
+                Err(_) => (Response::error(400, "the data key must be 16 bytes"), false),
+            },
+            "/unwrap" => match manager.unwrap_data_key(&request.body) {
+                Ok(data_key) => (Response::ok(data_key.to_vec()), false),
+                Err(error) => (policy_response(error), false),
+            },
+            "/encrypt" => {
+                let result = match self.mode {
+                    Some(mode) => manager.encrypt(mode, request.body.clone()),
+                    None => manager.encrypt_aead(&request.body, b""),
+                };
+                match result {
+                    Ok(cipher_text) => (Response::ok(cipher_text), true),
+                    Err(error) => (policy_response(error), false),
+                }
+            }
+            "/decrypt" => match manager.decrypt(&request.body) {
+                Ok(plain_text) => (Response::ok(plain_text), false),
+                Err(error) => (policy_response(error), false),
+            },
+            _ => (Response::error(404, "not found"), false),
+        };
+        if changed {
+            if let Err(error) = self.save(&manager) {
+                return Response::error(500, format!("saving the keyring failed: {}", error));
+            }
+        }
+        response
+    }
+
+    /// Accepts connections forever, answering each on its own thread.
+    pub fn serve(self: Arc<Self>, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || server.serve_connection(stream));
+        }
+        Ok(())
+    }
+
+    /// Answers requests on one connection until the client closes it. Errors just drop the
+    /// connection.
+    fn serve_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        loop {
+            let response = match read_request(&mut reader) {
+                Ok(Some(request)) => self.handle(&request),
+                Ok(None) => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::OutOfMemory => {
+                    Response::error(413, "request body too large")
+                }
+                Err(error) => Response::error(400, error),
+            };
+            write_response(&mut writer, &response)?;
+            if response.status == 400 || response.status == 413 {
+                // The rest of the request can't be trusted to be framed properly.
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Reads one request. `None` means the client closed the connection between requests.
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("malformed request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut token = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("connection closed in the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(invalid("malformed header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| invalid("malformed content length"))?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            token = value.strip_prefix("Bearer ").map(str::to_string);
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "request body too large",
+        ));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(Request {
+        method,
+        path,
+        token,
+        body,
+    }))
+}
+
+pub fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.body.len()
+    )?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::utils;
+
+    use super::*;
+
+    const TOKEN: &[u8] = b"local token";
+
+    fn post(path: &str, body: &[u8]) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            token: Some(String::from_utf8(TOKEN.to_vec()).unwrap()),
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        let server = KeyServer::new(KeyManager::new(), TOKEN);
+        assert_eq!(server.handle(&post("/encrypt", b"x")).status, 403);
+        assert_eq!(
+            server.handle(&post("/keys", b"")),
+            Response::ok(b"1".to_vec())
+        );
+
+        let cipher_text = server.handle(&post("/encrypt", b"shared secret")).body;
+        assert_eq!(
+            server.handle(&post("/decrypt", &cipher_text)),
+            Response::ok(b"shared secret".to_vec())
+        );
+        let mut tampered = cipher_text;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(server.handle(&post("/decrypt", &tampered)).status, 400);
+
+        let data_key = utils::create_rand_key();
+        let wrapped = server.handle(&post("/wrap", &data_key)).body;
+        server.handle(&post("/keys", b""));
+        assert_eq!(
+            server.handle(&post("/unwrap", &wrapped)),
+            Response::ok(data_key.to_vec())
+        );
+        assert_eq!(server.handle(&post("/wrap", b"short")).status, 400);
+
+        // Wrapping is held to the key's policy like encrypting.
+        let limited = KeyServer::new(KeyManager::new(), TOKEN).with_key_policy(KeyPolicy {
+            max_invocations: Some(1),
+            ..KeyPolicy::default()
+        });
+        limited.handle(&post("/keys", b""));
+        assert_eq!(limited.handle(&post("/wrap", &data_key)).status, 200);
+        assert_eq!(limited.handle(&post("/wrap", &data_key)).status, 403);
+        assert_eq!(limited.handle(&post("/encrypt", b"x")).status, 403);
+
+        let mut stolen = post("/unwrap", &wrapped);
+        stolen.token = Some("guess".to_string());
+        assert_eq!(server.handle(&stolen).status, 401);
+        assert_eq!(server.handle(&post("/decrypt", b"short")).status, 400);
+        assert_eq!(server.handle(&post("/nowhere", b"")).status, 404);
+    }
+
+    #[test]
+    fn test_over_a_socket() {
+        let directory = std::env::temp_dir().join(format!(
+            "aes-modes-keyd-{:016x}",
+            u64::from_be_bytes(utils::create_rand_nonce())
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let socket = directory.join("keyd.sock");
+        let keyring = directory.join("keyring");
+        let kek = [5u8; BLOCK_SIZE];
+
+        let server =
+            Arc::new(KeyServer::new(KeyManager::new(), TOKEN).with_keyring_file(&keyring, kek));
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let mut client = UnixStream::connect(&socket).unwrap();
+        let mut responses = BufReader::new(client.try_clone().unwrap());
+        for (path, body) in [("/keys", &b""[..]), ("/encrypt", b"over the wire")] {
+            write!(
+                client,
+                "POST {} HTTP/1.1\r\nAuthorization: Bearer local token\r\nContent-Length: {}\r\n\r\n",
+                path,
+                body.len()
+            )
+            .unwrap();
+            client.write_all(body).unwrap();
+
+            let mut status = String::new();
+            responses.read_line(&mut status).unwrap();
+            assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                responses.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            responses.read_exact(&mut vec![0u8; length]).unwrap();
+        }
+
+        // The keyring on disk has the new key and its usage.
+        let saved = KeyManager::open(kek, &std::fs::read(&keyring).unwrap()).unwrap();
+        assert_eq!(saved.keys()[0].invocations, 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
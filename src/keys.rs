@@ -0,0 +1,692 @@
+//! Managing versioned keys, and the policies that say how long and how much each may be used.
+//!
+//! Keys wear out. A key that has encrypted too many messages, too much data, or that has simply
+//! been around for too long should be replaced, and the safest way to make sure that happens
+//! is for the encryption API itself to refuse to go on.
+//!
+//! A [`KeyManager`] holds a numbered list of keys, one of which is current. Every key carries
+//! a [`KeyPolicy`] with optional limits, and every ciphertext starts with the version of the
+//! key and the mode that produced it, so older data stays readable after the current key
+//! changes:
+//!
+//! ```text
+//! key version (u32) | mode | ciphertext
+//! ```
+//!
+//! [`KeyManager::encrypt_aead`] uses the same layout for GCM, with a mode byte of its own, and
+//! authenticates the key version and the mode along with the data. That makes it the only
+//! choice here that detects a modified ciphertext.
+//!
+//! Limits are enforced when encrypting. Decryption only checks that the mode is allowed: an
+//! expired key must still be able to read the data it protected, or the data is lost.
+
+use std::{
+    error::Error,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    aes_encrypt,
+    gcm::{gcm_decrypt, GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    keywrap::{unwrap_key, wrap_key, WRAPPED_KEY_SIZE},
+    locked::LockedBox,
+    utils, Mode, BLOCK_SIZE, NONCE_SIZE,
+};
+
+/// Every ciphertext starts with the key version and the mode.
+pub const HEADER_SIZE: usize = 4 + 1;
+
+const KEYRING_MAGIC: &[u8; 4] = b"AMKR";
+const KEYRING_VERSION: u8 = 1;
+const MODES: [Mode; 3] = [Mode::Ecb, Mode::Cbc, Mode::Ctr];
+/// The mode byte of [`KeyManager::encrypt_aead`] ciphertexts, after those of [`MODES`].
+const GCM_MODE: u8 = MODES.len() as u8;
+
+/// The length of a [`check_value`].
+pub const KCV_SIZE: usize = 3;
+
+/// The key check value (KCV) payment systems and HSMs print next to a key: the first three
+/// bytes of the key's encryption of the all-zero block. Two parties comparing KCVs can tell
+/// whether they loaded the same key without showing it to each other.
+///
+/// A KCV is 24 bits of known plaintext and ciphertext under the key, which doesn't help
+/// recover it, but is enough to rule out most wrong keys before decrypting anything.
+pub fn check_value(key: &[u8; BLOCK_SIZE]) -> [u8; KCV_SIZE] {
+    aes_encrypt([0u8; BLOCK_SIZE], key)[..KCV_SIZE]
+        .try_into()
+        .unwrap()
+}
+
+/// Why a key may not be used, or a ciphertext or keyring could not be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    /// There is no key with this version, or no key at all yet.
+    UnknownVersion { version: u32 },
+    /// The key is past its not-after date.
+    Expired { version: u32 },
+    /// Encrypting this much more data would exceed the key's byte limit.
+    ByteLimit { version: u32 },
+    /// The key has been used for as many operations as it may be.
+    InvocationLimit { version: u32 },
+    /// The key's policy doesn't allow this mode.
+    ModeNotAllowed { version: u32, mode: Mode },
+    /// The ciphertext, wrapped key or keyring is malformed, or failed authentication.
+    Malformed,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::UnknownVersion { version } => write!(f, "no key with version {}", version),
+            PolicyError::Expired { version } => write!(f, "key {} has expired", version),
+            PolicyError::ByteLimit { version } => {
+                write!(f, "key {} has reached its byte limit", version)
+            }
+            PolicyError::InvocationLimit { version } => {
+                write!(f, "key {} has reached its invocation limit", version)
+            }
+            PolicyError::ModeNotAllowed { version, mode } => {
+                write!(f, "key {} may not be used with {}", version, mode.name())
+            }
+            PolicyError::Malformed => f.write_str("malformed ciphertext or keyring"),
+        }
+    }
+}
+
+impl Error for PolicyError {}
+
+/// Limits on how a key may be used. Every limit is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// Seconds since the Unix epoch after which the key no longer encrypts.
+    pub not_after: Option<u64>,
+    /// The most plaintext bytes the key may encrypt in total.
+    pub max_bytes: Option<u64>,
+    /// The most encryptions the key may perform.
+    pub max_invocations: Option<u64>,
+    /// The modes the key may be used with. `None` allows all of them.
+    pub allowed_modes: Option<Vec<Mode>>,
+}
+
+impl KeyPolicy {
+    fn allows(&self, mode: Mode) -> bool {
+        self.allowed_modes
+            .as_ref()
+            .is_none_or(|modes| modes.contains(&mode))
+    }
+}
+
+/// One version of a key, with its policy and how much it has been used so far.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManagedKey {
+    pub version: u32,
+    /// Kept on [locked](crate::locked) pages, out of swap and core dumps.
+    pub key: LockedBox<[u8; BLOCK_SIZE]>,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub policy: KeyPolicy,
+    pub bytes_encrypted: u64,
+    pub invocations: u64,
+}
+
+/// Parses a key written as 32 hex digits or 22 base64url characters, as keys are given on
+/// command lines, in environment variables and in key files. Surrounding whitespace is
+/// ignored.
+///
+/// Ordinary decoders look characters up in tables or branch on their values, which can leak
+/// the key through timing or the cache. These don't: only the length of the input affects how
+/// long parsing takes.
+pub fn parse_key(encoded: &str) -> Option<[u8; BLOCK_SIZE]> {
+    let encoded = encoded.trim();
+    let key = match encoded.len() {
+        32 => utils::hex_decode(encoded)?,
+        22 => utils::base64url_decode(encoded)?,
+        _ => return None,
+    };
+    key.try_into().ok()
+}
+
+impl ManagedKey {
+    /// The key's [`check_value`].
+    pub fn check_value(&self) -> [u8; KCV_SIZE] {
+        check_value(&self.key)
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// A numbered set of keys, of which the newest is used for encryption.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyManager {
+    keys: Vec<ManagedKey>,
+}
+
+impl KeyManager {
+    pub fn new() -> Self {
+        KeyManager { keys: Vec::new() }
+    }
+
+    /// Adds a new random key with the given policy, makes it current, and returns its version.
+    pub fn generate(&mut self, policy: KeyPolicy) -> u32 {
+        self.add(utils::create_rand_key(), policy)
+    }
+
+    /// Adds an existing key, makes it current, and returns its version.
+    pub fn add(&mut self, key: [u8; BLOCK_SIZE], policy: KeyPolicy) -> u32 {
+        let version = self.current_version().map_or(1, |version| version + 1);
+        self.keys.push(ManagedKey {
+            version,
+            key: LockedBox::new(key),
+            created: now(),
+            policy,
+            bytes_encrypted: 0,
+            invocations: 0,
+        });
+        version
+    }
+
+    /// The version new data is encrypted under, if there are any keys.
+    pub fn current_version(&self) -> Option<u32> {
+        self.keys.last().map(|key| key.version)
+    }
+
+    pub fn get(&self, version: u32) -> Option<&ManagedKey> {
+        self.keys.iter().find(|key| key.version == version)
+    }
+
+    pub fn keys(&self) -> &[ManagedKey] {
+        &self.keys
+    }
+
+    /// Checks that the current key's policy allows encrypting `len` more bytes, with `mode` if
+    /// there is one, and counts the usage.
+    fn use_current(&mut self, mode: Option<Mode>, len: usize) -> Result<&ManagedKey, PolicyError> {
+        let managed = self
+            .keys
+            .last_mut()
+            .ok_or(PolicyError::UnknownVersion { version: 0 })?;
+        let version = managed.version;
+        let policy = &managed.policy;
+
+        if let Some(mode) = mode.filter(|&mode| !policy.allows(mode)) {
+            return Err(PolicyError::ModeNotAllowed { version, mode });
+        }
+        if policy.not_after.is_some_and(|not_after| now() > not_after) {
+            return Err(PolicyError::Expired { version });
+        }
+        if policy
+            .max_invocations
+            .is_some_and(|max| managed.invocations >= max)
+        {
+            return Err(PolicyError::InvocationLimit { version });
+        }
+        let bytes_encrypted = managed.bytes_encrypted + len as u64;
+        if policy.max_bytes.is_some_and(|max| bytes_encrypted > max) {
+            return Err(PolicyError::ByteLimit { version });
+        }
+
+        managed.bytes_encrypted = bytes_encrypted;
+        managed.invocations += 1;
+        Ok(managed)
+    }
+
+    /// Encrypts with the current key, if its policy allows it, and counts the usage.
+    pub fn encrypt(&mut self, mode: Mode, plain_text: Vec<u8>) -> Result<Vec<u8>, PolicyError> {
+        let managed = self.use_current(Some(mode), plain_text.len())?;
+        let mut output = managed.version.to_be_bytes().to_vec();
+        output.push(MODES.iter().position(|m| *m == mode).unwrap() as u8);
+        output.extend(mode.encrypt(plain_text, *managed.key));
+        Ok(output)
+    }
+
+    /// Encrypts and authenticates with GCM under the current key, if its policy allows it, and
+    /// counts the usage. The key version and mode at the front are authenticated as part of
+    /// the associated data, so the ciphertext can't be passed off as one from another key. The
+    /// policy's allowed modes don't apply, as for [`wrap_data_key`](Self::wrap_data_key).
+    pub fn encrypt_aead(&mut self, plain_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, PolicyError> {
+        let managed = self.use_current(None, plain_text.len())?;
+        let nonce = utils::create_rand_gcm_nonce();
+        let mut output =
+            Vec::with_capacity(HEADER_SIZE + GCM_NONCE_SIZE + plain_text.len() + TAG_SIZE);
+        output.extend_from_slice(&managed.version.to_be_bytes());
+        output.push(GCM_MODE);
+        let full_aad = [&output[..], aad].concat();
+        output.extend_from_slice(&nonce);
+        GcmKey::new(*managed.key).seal_into(nonce, plain_text, &full_aad, &mut output);
+        Ok(output)
+    }
+
+    /// Opposite of [`encrypt_aead`](Self::encrypt_aead). A ciphertext that fails authentication,
+    /// or was made with other associated data, is [`PolicyError::Malformed`].
+    pub fn decrypt_aead(&self, cipher_text: &[u8], aad: &[u8]) -> Result<Vec<u8>, PolicyError> {
+        if cipher_text.len() < HEADER_SIZE + GCM_NONCE_SIZE + TAG_SIZE || cipher_text[4] != GCM_MODE
+        {
+            return Err(PolicyError::Malformed);
+        }
+        let version = u32::from_be_bytes(cipher_text[..4].try_into().unwrap());
+        let managed = self
+            .get(version)
+            .ok_or(PolicyError::UnknownVersion { version })?;
+        let (header, rest) = cipher_text.split_at(HEADER_SIZE);
+        let (nonce, body) = rest.split_at(GCM_NONCE_SIZE);
+        GcmKey::new(*managed.key)
+            .open(nonce.try_into().unwrap(), body, &[header, aad].concat())
+            .map_err(|_| PolicyError::Malformed)
+    }
+
+    /// Wraps `data_key` with AES-KW under the current key, as the key version followed by the
+    /// wrapped key. The policy applies as to encrypting 16 bytes, except for its allowed
+    /// modes, which only name this crate's [`Mode`]s.
+    pub fn wrap_data_key(&mut self, data_key: &[u8; BLOCK_SIZE]) -> Result<Vec<u8>, PolicyError> {
+        let managed = self.use_current(None, BLOCK_SIZE)?;
+        let mut wrapped = managed.version.to_be_bytes().to_vec();
+        wrapped.extend_from_slice(&wrap_key(&managed.key, data_key).unwrap());
+        Ok(wrapped)
+    }
+
+    /// Unwraps the output of [`wrap_data_key`](Self::wrap_data_key) with whichever key version
+    /// it names. A wrapped key that fails its integrity check is [`PolicyError::Malformed`].
+    pub fn unwrap_data_key(&self, wrapped: &[u8]) -> Result<[u8; BLOCK_SIZE], PolicyError> {
+        let (version, wrapped) = wrapped
+            .split_first_chunk::<4>()
+            .filter(|(_, wrapped)| wrapped.len() == WRAPPED_KEY_SIZE)
+            .ok_or(PolicyError::Malformed)?;
+        let version = u32::from_be_bytes(*version);
+        let managed = self
+            .get(version)
+            .ok_or(PolicyError::UnknownVersion { version })?;
+        unwrap_key(&managed.key, wrapped.try_into().unwrap()).map_err(|_| PolicyError::Malformed)
+    }
+
+    /// Decrypts with whichever key version the ciphertext names. GCM ciphertexts are opened
+    /// like [`decrypt_aead`](Self::decrypt_aead) with no associated data.
+    pub fn decrypt(&self, cipher_text: &[u8]) -> Result<Vec<u8>, PolicyError> {
+        if cipher_text.len() < HEADER_SIZE {
+            return Err(PolicyError::Malformed);
+        }
+        if cipher_text[4] == GCM_MODE {
+            return self.decrypt_aead(cipher_text, b"");
+        }
+        let version = u32::from_be_bytes(cipher_text[..4].try_into().unwrap());
+        let mode = *MODES
+            .get(cipher_text[4] as usize)
+            .ok_or(PolicyError::Malformed)?;
+
+        let body = &cipher_text[HEADER_SIZE..];
+        let well_formed = match mode {
+            Mode::Ecb => body.len().is_multiple_of(BLOCK_SIZE),
+            Mode::Cbc => body.len() >= BLOCK_SIZE && body.len().is_multiple_of(BLOCK_SIZE),
+            Mode::Ctr => body.len() >= NONCE_SIZE,
+        };
+        if !well_formed {
+            return Err(PolicyError::Malformed);
+        }
+
+        let managed = self
+            .get(version)
+            .ok_or(PolicyError::UnknownVersion { version })?;
+        if !managed.policy.allows(mode) {
+            return Err(PolicyError::ModeNotAllowed { version, mode });
+        }
+        Ok(mode.decrypt(body.to_vec(), *managed.key))
+    }
+
+    /// Whether the ciphertext was encrypted under the current key.
+    pub fn is_current(&self, cipher_text: &[u8]) -> bool {
+        cipher_text.len() >= HEADER_SIZE
+            && Some(u32::from_be_bytes(cipher_text[..4].try_into().unwrap()))
+                == self.current_version()
+    }
+
+    /// Decrypts like [`decrypt`](Self::decrypt) and, if the ciphertext was made with an older
+    /// key, also re-encrypts it under the current one with the same mode.
+    ///
+    /// This is the hook for re-encrypting stored data lazily after a rotation: whoever reads a
+    /// record writes the refreshed ciphertext back, and old keys gradually fall out of use
+    /// without a bulk migration. A current key whose policy refuses the re-encryption is not
+    /// an error; the record just stays as it is for now.
+    pub fn decrypt_and_refresh(&mut self, cipher_text: &[u8]) -> Result<Refreshed, PolicyError> {
+        let plain_text = self.decrypt(cipher_text)?;
+        let refreshed = if self.is_current(cipher_text) {
+            None
+        } else if cipher_text[4] == GCM_MODE {
+            self.encrypt_aead(&plain_text, b"").ok()
+        } else {
+            let mode = MODES[cipher_text[4] as usize];
+            self.encrypt(mode, plain_text.clone()).ok()
+        };
+        Ok(Refreshed {
+            plain_text,
+            refreshed,
+        })
+    }
+
+    /// The keyring in the clear, in a buffer that is zeroed when dropped. It is allocated at
+    /// its final size up front, so no copy of the keys is left behind by growing it.
+    pub(crate) fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        fn optional(bytes: &mut Vec<u8>, value: Option<u64>) {
+            bytes.push(value.is_some() as u8);
+            bytes.extend_from_slice(&value.unwrap_or(0).to_be_bytes());
+        }
+
+        // Version, key, creation time, three optional limits, modes and two counters.
+        const ENTRY_SIZE: usize = 4 + BLOCK_SIZE + 8 + 3 * 9 + 1 + 8 + 8;
+        let mut bytes = Zeroizing::new(Vec::with_capacity(
+            KEYRING_MAGIC.len() + 1 + 4 + self.keys.len() * ENTRY_SIZE,
+        ));
+        bytes.extend_from_slice(KEYRING_MAGIC);
+        bytes.push(KEYRING_VERSION);
+        bytes.extend_from_slice(&(self.keys.len() as u32).to_be_bytes());
+        for managed in &self.keys {
+            bytes.extend_from_slice(&managed.version.to_be_bytes());
+            bytes.extend_from_slice(&managed.key[..]);
+            bytes.extend_from_slice(&managed.created.to_be_bytes());
+            optional(&mut bytes, managed.policy.not_after);
+            optional(&mut bytes, managed.policy.max_bytes);
+            optional(&mut bytes, managed.policy.max_invocations);
+            // One bit per allowed mode, with the top bit set when the list is present at all.
+            bytes.push(managed.policy.allowed_modes.as_ref().map_or(0, |modes| {
+                MODES
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, mode)| modes.contains(mode))
+                    .fold(0x80, |bits, (i, _)| bits | 1 << i)
+            }));
+            bytes.extend_from_slice(&managed.bytes_encrypted.to_be_bytes());
+            bytes.extend_from_slice(&managed.invocations.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != KEYRING_MAGIC || reader.take(1)? != [KEYRING_VERSION] {
+            return None;
+        }
+        let count = u32::from_be_bytes(reader.take(4)?.try_into().unwrap());
+        let mut keys = Vec::new();
+        for _ in 0..count {
+            let version = u32::from_be_bytes(reader.take(4)?.try_into().unwrap());
+            let key = LockedBox::new(reader.take(BLOCK_SIZE)?.try_into().unwrap());
+            let created = reader.u64()?;
+            let not_after = reader.optional()?;
+            let max_bytes = reader.optional()?;
+            let max_invocations = reader.optional()?;
+            let bits = reader.take(1)?[0];
+            let allowed_modes = (bits & 0x80 != 0).then(|| {
+                MODES
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| bits & 1 << i != 0)
+                    .map(|(_, mode)| *mode)
+                    .collect()
+            });
+            keys.push(ManagedKey {
+                version,
+                key,
+                created,
+                policy: KeyPolicy {
+                    not_after,
+                    max_bytes,
+                    max_invocations,
+                    allowed_modes,
+                },
+                bytes_encrypted: reader.u64()?,
+                invocations: reader.u64()?,
+            });
+        }
+        reader.0.is_empty().then_some(KeyManager { keys })
+    }
+
+    /// Serializes all keys, policies and usage counts, encrypted and authenticated under a
+    /// key-encryption key, as `nonce | ciphertext | tag`.
+    pub fn seal(&self, kek: [u8; BLOCK_SIZE]) -> Vec<u8> {
+        let nonce = utils::create_rand_gcm_nonce();
+        let mut sealed = nonce.to_vec();
+        GcmKey::new(kek).seal_into(nonce, &self.to_bytes(), KEYRING_MAGIC, &mut sealed);
+        sealed
+    }
+
+    pub fn open(kek: [u8; BLOCK_SIZE], sealed: &[u8]) -> Result<Self, PolicyError> {
+        if sealed.len() < GCM_NONCE_SIZE {
+            return Err(PolicyError::Malformed);
+        }
+        let (nonce, cipher_text) = sealed.split_at(GCM_NONCE_SIZE);
+        let bytes = Zeroizing::new(
+            gcm_decrypt(
+                cipher_text.to_vec(),
+                kek,
+                nonce.try_into().unwrap(),
+                KEYRING_MAGIC,
+            )
+            .map_err(|_| PolicyError::Malformed)?,
+        );
+        Self::from_bytes(&bytes).ok_or(PolicyError::Malformed)
+    }
+}
+
+/// The result of [`KeyManager::decrypt_and_refresh`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Refreshed {
+    pub plain_text: Vec<u8>,
+    /// The same plaintext under the current key, to be stored in place of the old ciphertext.
+    pub refreshed: Option<Vec<u8>>,
+}
+
+/// Reads big-endian fields off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn optional(&mut self) -> Option<Option<u64>> {
+        let present = self.take(1)?[0] != 0;
+        let value = self.u64()?;
+        Some(present.then_some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEK: [u8; BLOCK_SIZE] = [9u8; BLOCK_SIZE];
+
+    #[test]
+    fn test_check_value() {
+        assert_eq!(check_value(&[0u8; BLOCK_SIZE]), [0x66, 0xe9, 0x4b]);
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        assert_eq!(check_value(&key), [0x7d, 0xf7, 0x6b]);
+
+        let mut manager = KeyManager::new();
+        manager.generate(KeyPolicy::default());
+        let managed = manager.get(1).unwrap();
+        assert_eq!(managed.check_value(), check_value(&managed.key));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key: [u8; BLOCK_SIZE] = core::array::from_fn(|i| i as u8 * 17);
+        assert_eq!(parse_key("00112233445566778899AABBCCDDEEFF\n"), Some(key));
+        assert_eq!(parse_key("00112233445566778899aabbccddeeff"), Some(key));
+        assert_eq!(parse_key(&utils::base64url_encode(&key)), Some(key));
+        assert_eq!(parse_key("00112233445566778899aabbccddeefg"), None);
+        assert_eq!(parse_key("0011"), None);
+    }
+
+    #[test]
+    fn test_versions_and_rotation() {
+        let mut manager = KeyManager::new();
+        assert_eq!(
+            manager.encrypt(Mode::Cbc, b"too early".to_vec()),
+            Err(PolicyError::UnknownVersion { version: 0 })
+        );
+
+        assert_eq!(manager.generate(KeyPolicy::default()), 1);
+        let old = manager.encrypt(Mode::Cbc, b"old data".to_vec()).unwrap();
+        assert_eq!(manager.generate(KeyPolicy::default()), 2);
+        let new = manager.encrypt(Mode::Ctr, b"new data".to_vec()).unwrap();
+
+        assert_eq!(&old[..4], &1u32.to_be_bytes());
+        assert_eq!(&new[..4], &2u32.to_be_bytes());
+        assert_eq!(manager.decrypt(&old), Ok(b"old data".to_vec()));
+        assert_eq!(manager.decrypt(&new), Ok(b"new data".to_vec()));
+    }
+
+    #[test]
+    fn test_authenticated_encryption() {
+        let mut manager = KeyManager::new();
+        manager.generate(KeyPolicy::default());
+        let old = manager.encrypt_aead(b"old data", b"").unwrap();
+        manager.generate(KeyPolicy::default());
+        let cipher_text = manager.encrypt_aead(b"record", b"row 7").unwrap();
+        assert_eq!(&cipher_text[..4], &2u32.to_be_bytes());
+        assert_eq!(
+            manager.decrypt_aead(&cipher_text, b"row 7"),
+            Ok(b"record".to_vec())
+        );
+        assert_eq!(
+            manager.decrypt_aead(&cipher_text, b"row 8"),
+            Err(PolicyError::Malformed)
+        );
+
+        // Neither the data nor the key version in front of it can be changed.
+        let mut tampered = cipher_text.clone();
+        tampered[HEADER_SIZE + GCM_NONCE_SIZE] ^= 1;
+        assert_eq!(
+            manager.decrypt_aead(&tampered, b"row 7"),
+            Err(PolicyError::Malformed)
+        );
+        let mut relabelled = cipher_text;
+        relabelled[..4].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(
+            manager.decrypt_aead(&relabelled, b"row 7"),
+            Err(PolicyError::Malformed)
+        );
+
+        assert_eq!(manager.decrypt(&old), Ok(b"old data".to_vec()));
+        let refreshed = manager
+            .decrypt_and_refresh(&old)
+            .unwrap()
+            .refreshed
+            .unwrap();
+        assert_eq!(&refreshed[..HEADER_SIZE], &[0, 0, 0, 2, GCM_MODE]);
+        assert_eq!(manager.decrypt(&refreshed), Ok(b"old data".to_vec()));
+    }
+
+    #[test]
+    fn test_policies_are_enforced() {
+        let mut manager = KeyManager::new();
+        manager.generate(KeyPolicy {
+            max_invocations: Some(2),
+            allowed_modes: Some(vec![Mode::Cbc, Mode::Ctr]),
+            ..KeyPolicy::default()
+        });
+        assert_eq!(
+            manager.encrypt(Mode::Ecb, Vec::new()),
+            Err(PolicyError::ModeNotAllowed {
+                version: 1,
+                mode: Mode::Ecb
+            })
+        );
+        manager.encrypt(Mode::Cbc, Vec::new()).unwrap();
+        let last = manager.encrypt(Mode::Ctr, Vec::new()).unwrap();
+        assert_eq!(
+            manager.encrypt(Mode::Ctr, Vec::new()),
+            Err(PolicyError::InvocationLimit { version: 1 })
+        );
+        // Data encrypted before the limit was hit stays readable.
+        assert_eq!(manager.decrypt(&last), Ok(Vec::new()));
+
+        manager.generate(KeyPolicy {
+            max_bytes: Some(10),
+            ..KeyPolicy::default()
+        });
+        manager.encrypt(Mode::Ctr, vec![0; 6]).unwrap();
+        assert_eq!(
+            manager.encrypt(Mode::Ctr, vec![0; 6]),
+            Err(PolicyError::ByteLimit { version: 2 })
+        );
+
+        manager.generate(KeyPolicy {
+            not_after: Some(now() - 1),
+            ..KeyPolicy::default()
+        });
+        assert_eq!(
+            manager.encrypt(Mode::Ctr, Vec::new()),
+            Err(PolicyError::Expired { version: 3 })
+        );
+        assert_eq!(
+            manager.wrap_data_key(&[7; BLOCK_SIZE]),
+            Err(PolicyError::Expired { version: 3 })
+        );
+
+        // Wrapping counts against the same limits as encrypting.
+        manager.generate(KeyPolicy {
+            max_invocations: Some(1),
+            allowed_modes: Some(vec![Mode::Cbc]),
+            ..KeyPolicy::default()
+        });
+        let wrapped = manager.wrap_data_key(&[7; BLOCK_SIZE]).unwrap();
+        assert_eq!(manager.keys()[3].invocations, 1);
+        assert_eq!(
+            manager.wrap_data_key(&[7; BLOCK_SIZE]),
+            Err(PolicyError::InvocationLimit { version: 4 })
+        );
+        assert_eq!(manager.unwrap_data_key(&wrapped), Ok([7; BLOCK_SIZE]));
+        let mut tampered = wrapped;
+        tampered[10] ^= 1;
+        assert_eq!(
+            manager.unwrap_data_key(&tampered),
+            Err(PolicyError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_keyring_serialization() {
+        let mut manager = KeyManager::new();
+        manager.generate(KeyPolicy {
+            not_after: Some(4_000_000_000),
+            allowed_modes: Some(vec![Mode::Ctr]),
+            ..KeyPolicy::default()
+        });
+        manager.generate(KeyPolicy {
+            max_bytes: Some(1 << 30),
+            max_invocations: Some(1000),
+            allowed_modes: Some(Vec::new()),
+            ..KeyPolicy::default()
+        });
+        manager.keys[0].invocations = 7;
+        // Sized exactly, so it never reallocated and left a copy of the keys behind.
+        let bytes = manager.to_bytes();
+        assert_eq!(bytes.len(), bytes.capacity());
+
+        let sealed = manager.seal(KEK);
+        assert_eq!(KeyManager::open(KEK, &sealed), Ok(manager.clone()));
+        assert_eq!(
+            KeyManager::open([0u8; BLOCK_SIZE], &sealed),
+            Err(PolicyError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Key wrapping, with the key-encryption key (KEK) held wherever you like.
+//!
+//! A common way to protect data at rest is to encrypt it under a random _data key_, and then
+//! encrypt ("wrap") that data key under a long-lived _key-encryption key_. The KEK never has to
+//! touch the bulk data, so it can live somewhere slow but safe, like an HSM or a smartcard, while
+//! the data itself is still encrypted locally at full speed.
+//!
+//! The wrapping algorithm is AES Key Wrap from RFC 3394. It only ever needs single-block
+//! encryptions and decryptions under the KEK, which is exactly what the [`HardwareKey`] trait
+//! asks for. The data itself is encrypted with GCM under the data key, so a modified body is
+//! rejected before anything is decrypted.
+
+use std::convert::Infallible;
+
+use crate::{
+    aes_decrypt, aes_encrypt,
+    gcm::{GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    locked::LockedBox,
+    trace, utils, BLOCK_SIZE,
+};
+
+/// A data key wrapped with AES-KW is one 64-bit integrity block longer than the key itself.
+pub const WRAPPED_KEY_SIZE: usize = BLOCK_SIZE + 8;
+
+/// The default initial value from RFC 3394 section 2.2.3.1. Unwrapping must give this back,
+/// otherwise the wrapped key was tampered with or the wrong KEK was used.
+const DEFAULT_IV: [u8; 8] = [0xA6; 8];
+
+/// Anything that can run a single AES block operation under a key it holds.
+///
+/// The key itself never needs to leave the implementor, so this can be backed by an HSM,
+/// a smartcard, or simply a key in memory.
+pub trait HardwareKey {
+    /// Errors reported by the device. Local keys can't fail, so they use [`Infallible`].
+    type Error;
+
+    fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Self::Error>;
+
+    fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Self::Error>;
+}
+
+/// A plain key in memory is the simplest possible "hardware" key.
+impl HardwareKey for [u8; BLOCK_SIZE] {
+    type Error = Infallible;
+
+    fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Infallible> {
+        Ok(aes_encrypt(block, self))
+    }
+
+    fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Infallible> {
+        Ok(aes_decrypt(block, self))
+    }
+}
+
+impl HardwareKey for LockedBox<[u8; BLOCK_SIZE]> {
+    type Error = Infallible;
+
+    fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Infallible> {
+        Ok(aes_encrypt(block, self))
+    }
+
+    fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Infallible> {
+        Ok(aes_decrypt(block, self))
+    }
+}
+
+/// Why a wrapped key or a wrapped-key ciphertext could not be opened.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyWrapError<E> {
+    /// The key-encryption key's backend reported an error.
+    Backend(E),
+    /// The input is too short to even contain a wrapped key.
+    Truncated,
+    /// The integrity check failed: wrong KEK, or the wrapped key was modified.
+    Integrity,
+}
+
+/// Wraps a data key under the KEK using AES Key Wrap (RFC 3394).
+///
+/// The data key is split into two 64-bit halves, which are mixed with a running integrity
+/// value `A` over six rounds of single-block encryptions.
+pub fn wrap_key<K: HardwareKey>(
+    kek: &K,
+    key: &[u8; BLOCK_SIZE],
+) -> Result<[u8; WRAPPED_KEY_SIZE], K::Error> {
+    let mut a = DEFAULT_IV;
+    let mut r = [[0u8; 8]; BLOCK_SIZE / 8];
+    for (i, half) in r.iter_mut().enumerate() {
+        half.copy_from_slice(&key[i * 8..(i + 1) * 8]);
+    }
+
+    let n = r.len();
+    for j in 0..6 {
+        for (i, half) in r.iter_mut().enumerate() {
+            let mut block = [0u8; BLOCK_SIZE];
+            block[..8].copy_from_slice(&a);
+            block[8..].copy_from_slice(half);
+            let b = kek.encrypt_block(block)?;
+
+            let t = (n * j + i + 1) as u64;
+            a.copy_from_slice(&b[..8]);
+            a = xor_counter(a, t);
+            half.copy_from_slice(&b[8..]);
+        }
+    }
+
+    let mut wrapped = [0u8; WRAPPED_KEY_SIZE];
+    wrapped[..8].copy_from_slice(&a);
+    for (i, half) in r.iter().enumerate() {
+        wrapped[8 * (i + 1)..8 * (i + 2)].copy_from_slice(half);
+    }
+    Ok(wrapped)
+}
+
+/// Opposite of wrap_key. Fails if the integrity value doesn't come back out as expected.
+pub fn unwrap_key<K: HardwareKey>(
+    kek: &K,
+    wrapped: &[u8; WRAPPED_KEY_SIZE],
+) -> Result<[u8; BLOCK_SIZE], KeyWrapError<K::Error>> {
+    let mut a = [0u8; 8];
+    a.copy_from_slice(&wrapped[..8]);
+    let mut r = [[0u8; 8]; BLOCK_SIZE / 8];
+    for (i, half) in r.iter_mut().enumerate() {
+        half.copy_from_slice(&wrapped[8 * (i + 1)..8 * (i + 2)]);
+    }
+
+    let n = r.len();
+    for j in (0..6).rev() {
+        for (i, half) in r.iter_mut().enumerate().rev() {
+            let t = (n * j + i + 1) as u64;
+            let mut block = [0u8; BLOCK_SIZE];
+            block[..8].copy_from_slice(&xor_counter(a, t));
+            block[8..].copy_from_slice(half);
+            let b = kek.decrypt_block(block).map_err(KeyWrapError::Backend)?;
+
+            a.copy_from_slice(&b[..8]);
+            half.copy_from_slice(&b[8..]);
+        }
+    }
+
+    if a != DEFAULT_IV {
+        return Err(KeyWrapError::Integrity);
+    }
+
+    let mut key = [0u8; BLOCK_SIZE];
+    for (i, half) in r.iter().enumerate() {
+        key[i * 8..(i + 1) * 8].copy_from_slice(half);
+    }
+    Ok(key)
+}
+
+/// Encrypts the data locally under a fresh random data key, and only asks the KEK to wrap
+/// that data key. The output is the wrapped key, the GCM nonce, and the GCM ciphertext and
+/// tag. The wrapped key is the associated data, so it can't be swapped for another one.
+pub fn encrypt_with_wrapped_key<K: HardwareKey>(
+    kek: &K,
+    plain_text: Vec<u8>,
+) -> Result<Vec<u8>, K::Error> {
+    let op = trace::Operation::start("encrypt", "wrapped-key", plain_text.len());
+    let data_key = utils::create_rand_key();
+    let nonce = utils::create_rand_gcm_nonce();
+    let mut cipher_text =
+        Vec::with_capacity(WRAPPED_KEY_SIZE + GCM_NONCE_SIZE + plain_text.len() + TAG_SIZE);
+    cipher_text.extend_from_slice(&wrap_key(kek, &data_key)?);
+    cipher_text.extend_from_slice(&nonce);
+    let wrapped = cipher_text[..WRAPPED_KEY_SIZE].to_vec();
+    GcmKey::new(data_key).seal_into(nonce, &plain_text, &wrapped, &mut cipher_text);
+    op.finish(true);
+    Ok(cipher_text)
+}
+
+/// Opposite of encrypt_with_wrapped_key.
+pub fn decrypt_with_wrapped_key<K: HardwareKey>(
+    kek: &K,
+    cipher_text: Vec<u8>,
+) -> Result<Vec<u8>, KeyWrapError<K::Error>> {
+    let op = trace::Operation::start("decrypt", "wrapped-key", cipher_text.len());
+    if cipher_text.len() < WRAPPED_KEY_SIZE + GCM_NONCE_SIZE + TAG_SIZE {
+        return Err(KeyWrapError::Truncated);
+    }
+
+    let (wrapped, rest) = cipher_text.split_at(WRAPPED_KEY_SIZE);
+    let (nonce, body) = rest.split_at(GCM_NONCE_SIZE);
+    let data_key = unwrap_key(kek, wrapped.try_into().unwrap())?;
+
+    let result = GcmKey::new(data_key)
+        .open(nonce.try_into().unwrap(), body, wrapped)
+        .map_err(|_| KeyWrapError::Integrity);
+    op.finish(result.is_ok());
+    result
+}
+
+/// XORs the big-endian step counter `t` into the integrity value, as the spec requires.
+fn xor_counter(a: [u8; 8], t: u64) -> [u8; 8] {
+    (u64::from_be_bytes(a) ^ t).to_be_bytes()
+}
+
+#[cfg(feature = "pkcs11")]
+pub use self::pkcs11::Pkcs11Key;
+
+/// A KEK that lives on a PKCS#11 token (an HSM, a smartcard, SoftHSM, ...).
+///
+/// Only raw AES-ECB single-block operations are requested from the token, since that's all
+/// AES-KW needs. Opening the session and logging in is left to the caller.
+#[cfg(feature = "pkcs11")]
+mod pkcs11 {
+    use cryptoki::{mechanism::Mechanism, object::ObjectHandle, session::Session};
+
+    use super::HardwareKey;
+    use crate::BLOCK_SIZE;
+
+    pub struct Pkcs11Key {
+        session: Session,
+        handle: ObjectHandle,
+    }
+
+    impl Pkcs11Key {
+        /// Uses the AES key object `handle` through an already logged-in session.
+        pub fn new(session: Session, handle: ObjectHandle) -> Self {
+            Self { session, handle }
+        }
+
+        fn run(
+            &self,
+            encrypt: bool,
+            block: [u8; BLOCK_SIZE],
+        ) -> Result<[u8; BLOCK_SIZE], cryptoki::error::Error> {
+            let output = if encrypt {
+                self.session
+                    .encrypt(&Mechanism::AesEcb, self.handle, &block)?
+            } else {
+                self.session
+                    .decrypt(&Mechanism::AesEcb, self.handle, &block)?
+            };
+
+            // A token that returns anything but one block is broken, and says so as an error.
+            Ok(output.as_slice().try_into()?)
+        }
+    }
+
+    impl HardwareKey for Pkcs11Key {
+        type Error = cryptoki::error::Error;
+
+        fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Self::Error> {
+            self.run(true, block)
+        }
+
+        fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> Result<[u8; BLOCK_SIZE], Self::Error> {
+            self.run(false, block)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEK: [u8; BLOCK_SIZE] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F,
+    ];
+
+    #[test]
+    fn test_rfc3394_vector() {
+        // RFC 3394 section 4.1: wrap 128 bits of key data with a 128-bit KEK.
+        let key = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD,
+            0xEE, 0xFF,
+        ];
+        let expected = [
+            0x1F, 0xA6, 0x8B, 0x0A, 0x81, 0x12, 0xB4, 0x47, 0xAE, 0xF3, 0x4B, 0xD8, 0xFB, 0x5A,
+            0x7B, 0x82, 0x9D, 0x3E, 0x86, 0x23, 0x71, 0xD2, 0xCF, 0xE5,
+        ];
+
+        let wrapped = wrap_key(&KEK, &key).unwrap();
+        assert_eq!(wrapped, expected);
+        assert_eq!(unwrap_key(&KEK, &wrapped), Ok(key));
+    }
+
+    #[test]
+    fn test_unwrap_detects_tampering() {
+        let mut wrapped = wrap_key(&KEK, &[7u8; BLOCK_SIZE]).unwrap();
+        wrapped[10] ^= 1;
+        assert_eq!(unwrap_key(&KEK, &wrapped), Err(KeyWrapError::Integrity));
+
+        let wrapped = wrap_key(&KEK, &[7u8; BLOCK_SIZE]).unwrap();
+        assert_eq!(
+            unwrap_key(&[0u8; BLOCK_SIZE], &wrapped),
+            Err(KeyWrapError::Integrity)
+        );
+    }
+
+    #[test]
+    fn test_wrapped_key_encryption() {
+        let plain_text = b"Bulk data stays local".to_vec();
+
+        let cipher_text = encrypt_with_wrapped_key(&KEK, plain_text.clone()).unwrap();
+        assert_eq!(decrypt_with_wrapped_key(&KEK, cipher_text), Ok(plain_text));
+
+        assert_eq!(
+            decrypt_with_wrapped_key(&KEK, vec![0u8; WRAPPED_KEY_SIZE]),
+            Err(KeyWrapError::Truncated)
+        );
+
+        // Any change to the body, including its length, fails the tag.
+        let mut tampered = encrypt_with_wrapped_key(&KEK, b"some data".to_vec()).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(
+            decrypt_with_wrapped_key(&KEK, tampered),
+            Err(KeyWrapError::Integrity)
+        );
+        let mut extended = encrypt_with_wrapped_key(&KEK, b"some data".to_vec()).unwrap();
+        extended.push(0);
+        assert_eq!(
+            decrypt_with_wrapped_key(&KEK, extended),
+            Err(KeyWrapError::Integrity)
+        );
+    }
+}
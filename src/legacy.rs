@@ -0,0 +1,80 @@
+//! 3DES-CBC, for getting data _out_ of legacy systems. Do not encrypt anything new with it.
+//!
+//! **Triple DES is deprecated.** It has 64-bit blocks, so a single key can only safely process
+//! a few gigabytes before block collisions start leaking plaintext (the Sweet32 attack), and
+//! NIST withdrew it at the end of 2023. It is here, behind the `legacy` feature, only so data
+//! from old systems can be decrypted and re-encrypted under AES during a migration. Everything
+//! in this module is marked `#[deprecated]`, so each use shows up as a compiler warning.
+//!
+//! Every call also reports a [`Warning::Deprecated`] to the [warning sink](crate::warnings), so
+//! a migration can check at runtime that nothing still depends on 3DES once it is done: a
+//! [`StderrSink`](crate::warnings::StderrSink) logs each use, and a
+//! [`PanicSink`](crate::warnings::PanicSink) makes any left over fail.
+//!
+//! The format is the same as [`generic::cbc_encrypt`](crate::generic::cbc_encrypt): an 8-byte
+//! IV, then the PKCS#7 padded ciphertext. Keys are 24 bytes of three-key 3DES (EDE3).
+
+use aes::cipher::KeyInit;
+pub use des::TdesEde3;
+
+use crate::{
+    generic::{self, MalformedCiphertext},
+    warnings::{self, Warning},
+};
+
+/// The key size of three-key 3DES. The parity bits are ignored.
+pub const TDES_KEY_SIZE: usize = 24;
+
+fn warn() {
+    warnings::warn(Warning::Deprecated("3des"));
+}
+
+/// Encrypts with 3DES-CBC under a random IV. Only for producing test data for a migration.
+#[deprecated(note = "3DES is insecure; only use it to decrypt legacy data")]
+pub fn tdes_cbc_encrypt(key: &[u8; TDES_KEY_SIZE], plain_text: Vec<u8>) -> Vec<u8> {
+    warn();
+    generic::cbc_encrypt(&TdesEde3::new(key.into()), plain_text)
+}
+
+/// Decrypts `IV | ciphertext` written by a legacy system with 3DES-CBC.
+#[deprecated(note = "3DES is insecure; only use it to decrypt legacy data")]
+pub fn tdes_cbc_decrypt(
+    key: &[u8; TDES_KEY_SIZE],
+    cipher_text: Vec<u8>,
+) -> Result<Vec<u8>, MalformedCiphertext> {
+    warn();
+    generic::cbc_decrypt(&TdesEde3::new(key.into()), cipher_text)
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_decrypts_legacy_data() {
+        // Written by OpenSSL's des-ede3-cbc, with the padding block removed.
+        let key = hex("0123456789abcdeffedcba987654321089abcdef01234567");
+        let key: [u8; TDES_KEY_SIZE] = key.try_into().unwrap();
+        let iv = hex("0001020304050607");
+        let plain_text = hex("aaaaaaaabbbbbbbbccccccccdddddddd");
+        let cipher_text =
+            generic::cbc_encrypt_with_iv(&TdesEde3::new(&key.into()), &iv, plain_text.clone());
+        assert_eq!(cipher_text[8..24], hex("661ddb2d5733318996485cf035094a8d"));
+        assert_eq!(tdes_cbc_decrypt(&key, cipher_text), Ok(plain_text));
+
+        let round_trip = tdes_cbc_decrypt(&key, tdes_cbc_encrypt(&key, b"old".to_vec()));
+        assert_eq!(round_trip, Ok(b"old".to_vec()));
+        assert_eq!(
+            tdes_cbc_decrypt(&key, vec![0u8; 12]),
+            Err(MalformedCiphertext)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Padding that hides how long a message is.
+//!
+//! Encryption hides what a message says but not how long it is, and the length alone can give
+//! a lot away: which page was fetched, which of a few known documents was sent, whether a
+//! field was empty. Block padding doesn't help, since it only rounds up to the next block.
+//! A [`LengthPadding`] rounds the plaintext up much further before encryption, so that many
+//! lengths share one ciphertext size:
+//!
+//! - [`LengthPadding::Padme`] leaks only O(log log n) bits about a length n, for at most 12%
+//!   overhead ([Nikitin et al., PETS 2019](https://petsymposium.org/2019/files/papers/issue4/popets-2019-0056.pdf));
+//! - [`LengthPadding::PowerOfTwo`] leaks even less, only the length's order of magnitude, for
+//!   up to 100% overhead;
+//! - [`LengthPadding::Multiple`] rounds up to a fixed quantum, so every length in a quantum
+//!   looks the same, as a [`ConstantRate`](crate::constant_rate::ConstantRate) needs.
+//!
+//! The padding is a `0x80` byte followed by zeros, so it can be removed again without storing
+//! the original length. [`Envelope::seal_with_padding`](crate::envelope::Envelope::seal_with_padding)
+//! records the scheme, and [`Envelope::open`](crate::envelope::Envelope::open) removes it.
+
+use std::num::NonZeroU32;
+
+use crate::generic::MalformedCiphertext;
+
+/// How far to round a plaintext's length up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LengthPadding {
+    /// No padding: the ciphertext shows the exact length.
+    #[default]
+    None,
+    /// The Padmé scheme: keeps the top bits of the length and zeroes the rest.
+    Padme,
+    /// The next power of two.
+    PowerOfTwo,
+    /// The next multiple of the quantum.
+    Multiple(NonZeroU32),
+}
+
+impl LengthPadding {
+    /// The ID of [`Multiple`](Self::Multiple), which encodings follow with the quantum.
+    pub(crate) const MULTIPLE_ID: u32 = 3;
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LengthPadding::None => "none",
+            LengthPadding::Padme => "padme",
+            LengthPadding::PowerOfTwo => "power-of-two",
+            LengthPadding::Multiple(_) => "multiple",
+        }
+    }
+
+    /// The number used in the envelope encodings.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            LengthPadding::None => 0,
+            LengthPadding::Padme => 1,
+            LengthPadding::PowerOfTwo => 2,
+            LengthPadding::Multiple(_) => Self::MULTIPLE_ID as u8,
+        }
+    }
+
+    /// The quantum of [`Multiple`](Self::Multiple), and 0 for the other schemes.
+    pub(crate) fn quantum(self) -> u32 {
+        match self {
+            LengthPadding::Multiple(quantum) => quantum.get(),
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn from_id(id: u32, quantum: u32) -> Option<Self> {
+        match (id, quantum) {
+            (0, 0) => Some(LengthPadding::None),
+            (1, 0) => Some(LengthPadding::Padme),
+            (2, 0) => Some(LengthPadding::PowerOfTwo),
+            (Self::MULTIPLE_ID, _) => NonZeroU32::new(quantum).map(LengthPadding::Multiple),
+            _ => None,
+        }
+    }
+
+    /// The length a `len`-byte plaintext is padded to, marker included.
+    pub fn padded_len(self, len: usize) -> usize {
+        match self {
+            LengthPadding::None => len,
+            LengthPadding::Padme => padme(len + 1),
+            LengthPadding::PowerOfTwo => (len + 1).next_power_of_two(),
+            LengthPadding::Multiple(quantum) => (len + 1).next_multiple_of(quantum.get() as usize),
+        }
+    }
+
+    pub fn pad(self, mut plain_text: Vec<u8>) -> Vec<u8> {
+        if self != LengthPadding::None {
+            let padded_len = self.padded_len(plain_text.len());
+            plain_text.push(0x80);
+            plain_text.resize(padded_len, 0);
+        }
+        plain_text
+    }
+
+    /// Removes what [`pad`](Self::pad) added. Fails if there isn't a `0x80` byte after the
+    /// trailing zeros.
+    pub fn unpad(self, mut padded: Vec<u8>) -> Result<Vec<u8>, MalformedCiphertext> {
+        if self != LengthPadding::None {
+            let len = padded
+                .iter()
+                .rposition(|&byte| byte != 0)
+                .filter(|&marker| padded[marker] == 0x80)
+                .ok_or(MalformedCiphertext)?;
+            padded.truncate(len);
+        }
+        Ok(padded)
+    }
+}
+
+/// Rounds `len` up so that only its top ⌊log₂ log₂ len⌋ + 1 bits can be nonzero.
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let exponent = len.ilog2();
+    let kept_bits = exponent.ilog2() + 1;
+    let mask = (1 << (exponent - kept_bits)) - 1;
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padded_lengths() {
+        for (len, padded) in [
+            (1, 1),
+            (2, 2),
+            (9, 10),
+            (100, 104),
+            (1000, 1024),
+            (4097, 4352),
+        ] {
+            assert_eq!(padme(len), padded, "padme({})", len);
+        }
+        assert_eq!(LengthPadding::Padme.padded_len(99), 104);
+        assert_eq!(LengthPadding::PowerOfTwo.padded_len(64), 128);
+        assert_eq!(LengthPadding::None.padded_len(99), 99);
+        let multiple = LengthPadding::Multiple(NonZeroU32::new(64).unwrap());
+        assert_eq!(multiple.padded_len(0), 64);
+        assert_eq!(multiple.padded_len(63), 64);
+        assert_eq!(multiple.padded_len(64), 128);
+        for len in 1..5000 {
+            let padded = padme(len);
+            assert!(padded >= len && padded - len <= len / 8, "padme({})", len);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for padding in [
+            LengthPadding::None,
+            LengthPadding::Padme,
+            LengthPadding::PowerOfTwo,
+            LengthPadding::Multiple(NonZeroU32::new(16).unwrap()),
+        ] {
+            for len in [0, 1, 15, 16, 100] {
+                let message = vec![0u8; len];
+                let padded = padding.pad(message.clone());
+                assert_eq!(padded.len(), padding.padded_len(len));
+                assert_eq!(padding.unpad(padded), Ok(message));
+            }
+        }
+        assert_eq!(
+            LengthPadding::Padme.unpad(vec![1, 0, 0]),
+            Err(MalformedCiphertext)
+        );
+        assert_eq!(
+            LengthPadding::Padme.unpad(Vec::new()),
+            Err(MalformedCiphertext)
+        );
+    }
+}
@@ -12,22 +12,98 @@
 //! best, and can sometimes be trivially broken.
 
 use aes::{
-    cipher::{generic_array::GenericArray, BlockCipher, BlockDecrypt, BlockEncrypt, KeyInit},
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
     Aes128,
 };
+#[cfg(feature = "adiantum")]
+pub mod adiantum;
+pub mod analysis;
+pub mod armor_parts;
+pub mod audit;
+pub mod auto;
+pub mod backend;
+pub mod backup;
+pub mod batch;
+pub mod blocks;
+pub mod brute_force;
+pub mod cascade;
+pub mod ceremony;
+pub mod chunked;
+pub mod clock;
+pub mod cmac_prf;
+pub mod compression;
+pub mod constant_rate;
+pub mod corpus;
+pub mod ctr;
+#[cfg(windows)]
+pub mod dpapi;
+pub mod dyn_cipher;
+pub mod encrypted_dir;
+pub mod envelope;
+pub mod file_handle;
+pub mod files;
+pub mod gcm;
+pub mod generic;
+pub mod gf128;
+pub mod hctr2;
+#[cfg(feature = "hybrid-kem")]
+pub mod hybrid;
+pub mod invocations;
+pub mod jobs;
+#[cfg(unix)]
+pub mod key_server;
+pub mod keys;
+pub mod keywrap;
+#[cfg(feature = "legacy")]
+pub mod legacy;
+pub mod length_padding;
+#[cfg(feature = "cbor")]
+pub mod licensing;
+pub mod limits;
+pub mod locked;
+pub mod mac_reader;
+pub mod merkle;
+pub mod messages;
+pub mod metrics;
+pub mod migrate;
+pub mod mime;
+pub mod multipart;
+pub mod names;
+#[cfg(all(unix, feature = "oracle-server"))]
+pub mod oracle;
+pub mod pipeline;
+pub mod policy;
+pub mod ratchet;
+pub mod registry;
+pub mod rotation;
+pub mod secretbox;
+pub mod self_test;
+pub mod session;
+pub mod siv;
+pub mod spill;
+pub mod stream;
+pub mod tamper;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "textbook")]
+pub mod textbook;
+pub mod tls_record;
+mod trace;
+pub mod transcode;
+pub mod tweakable;
+pub mod util;
 mod utils;
+#[cfg(any(feature = "cbor", feature = "msgpack"))]
+pub mod values;
+pub mod warnings;
 
 ///We're using AES 128 which has 16-byte (128 bit) blocks.
-const BLOCK_SIZE: usize = 16;
-const NONCE_SIZE: usize = 8;
-
-fn main() {
-    todo!("Maybe this should be a library crate. TBD");
-}
+pub const BLOCK_SIZE: usize = 16;
+pub const NONCE_SIZE: usize = 8;
 
 /// Simple AES encryption
 /// Helper function to make the core AES block cipher easier to understand.
-fn aes_encrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+pub fn aes_encrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
     // Convert the inputs to the necessary data type
     let mut block = GenericArray::from(data);
     let key = GenericArray::from(*key);
@@ -41,7 +117,7 @@ fn aes_encrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZ
 
 /// Simple AES encryption
 /// Helper function to make the core AES block cipher easier to understand.
-fn aes_decrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+pub fn aes_decrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
     // Convert the inputs to the necessary data type
     let mut block = GenericArray::from(data);
     let key = GenericArray::from(*key);
@@ -68,7 +144,7 @@ fn aes_decrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZ
 /// to later look at the last byte and remove part of the data. Instead, in this case, we add
 /// another entire block containing the block length in each byte. In our case,
 /// [16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16]
-fn pad(mut data: Vec<u8>) -> Vec<u8> {
+pub fn pad(mut data: Vec<u8>) -> Vec<u8> {
     let number_bytes_to_pad = BLOCK_SIZE - (data.len() % BLOCK_SIZE);
 
     for _ in 0..number_bytes_to_pad {
@@ -78,29 +154,30 @@ fn pad(mut data: Vec<u8>) -> Vec<u8> {
     data
 }
 
-/// Groups the data into BLOCK_SIZE blocks. Assumes the data is already
-/// a multiple of the block size. If this is not the case, call `pad` first.
-fn group(data: Vec<u8>) -> Vec<[u8; BLOCK_SIZE]> {
+/// Groups the data into blocks of N bytes, which is BLOCK_SIZE for AES and 8 for 3DES.
+/// Assumes the data is already a multiple of the block size. If this is not the case,
+/// call `pad` first.
+pub fn group<const N: usize>(data: Vec<u8>) -> Vec<[u8; N]> {
     let mut blocks = Vec::new();
     let mut i = 0;
     while i < data.len() {
-        let mut block: [u8; BLOCK_SIZE] = Default::default();
-        block.copy_from_slice(&data[i..i + BLOCK_SIZE]);
+        let mut block = [0u8; N];
+        block.copy_from_slice(&data[i..i + N]);
         blocks.push(block);
 
-        i += BLOCK_SIZE;
+        i += N;
     }
 
     blocks
 }
 
 /// Does the opposite of the group function
-fn un_group(blocks: Vec<[u8; BLOCK_SIZE]>) -> Vec<u8> {
+pub fn un_group<const N: usize>(blocks: Vec<[u8; N]>) -> Vec<u8> {
     blocks.iter().flat_map(|&block| block.to_vec()).collect()
 }
 
 /// Does the opposite of the pad function.
-fn un_pad(mut data: Vec<u8>) -> Vec<u8> {
+pub fn un_pad(mut data: Vec<u8>) -> Vec<u8> {
     if let Some(&pad_len) = data.last() {
         let len = data.len();
         if (pad_len as usize) <= len && (pad_len as usize) <= BLOCK_SIZE {
@@ -117,7 +194,9 @@ fn un_pad(mut data: Vec<u8>) -> Vec<u8> {
 /// large data. In this mode we simply encrypt each block of data under the same key.
 /// One good thing about this mode is that it is parallelizable. But to see why it is
 /// insecure look at: https://www.ubiqsecurity.com/wp-content/uploads/2022/02/ECB2.png
-fn ecb_encrypt(plain_text: Vec<u8>, key: [u8; 16]) -> Vec<u8> {
+pub fn ecb_encrypt(plain_text: Vec<u8>, key: [u8; 16]) -> Vec<u8> {
+    let op = trace::Operation::start("encrypt", "ecb", plain_text.len());
+    warnings::warn(warnings::Warning::Ecb);
     let padded_text = pad(plain_text);
 
     // Group the padded text into 16-byte blocks
@@ -129,11 +208,16 @@ fn ecb_encrypt(plain_text: Vec<u8>, key: [u8; 16]) -> Vec<u8> {
         .map(|&block| aes_encrypt(block, &key))
         .collect();
 
-    un_group(encrypted_blocks)
+    let cipher_text = un_group(encrypted_blocks);
+    op.finish(true);
+    cipher_text
 }
 
 /// Opposite of ecb_encrypt.
-fn ecb_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+pub fn ecb_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+    let op = trace::Operation::start("decrypt", "ecb", cipher_text.len());
+    warnings::warn(warnings::Warning::Ecb);
+
     // Group the ciphertext into 16-byte blocks
     let blocks = group(cipher_text);
 
@@ -147,7 +231,9 @@ fn ecb_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
     let decrypted_data = un_group(decrypted_blocks);
 
     // Remove padding
-    un_pad(decrypted_data)
+    let plain_text = un_pad(decrypted_data);
+    op.finish(true);
+    plain_text
 }
 
 /// The next mode, which you can implement on your own is cipherblock chaining.
@@ -162,7 +248,10 @@ fn ecb_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
 /// You will need to generate a random initialization vector (IV) to encrypt the
 /// very first block because it doesn't have a previous block. Typically this IV
 /// is inserted as the first block of ciphertext.
-fn cbc_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+pub fn cbc_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+    let op = trace::Operation::start("encrypt", "cbc", plain_text.len());
+    warnings::warn(warnings::Warning::UnauthenticatedCbc);
+
     // Inputs
     let padded_text = pad(plain_text);
     let rand_init_vector = utils::create_rand_init_vector();
@@ -175,17 +264,22 @@ fn cbc_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
 
     for block in blocks {
         // XOR input
-        let xored_block = utils::xor_block_bytes(&block, &previous_block);
+        let xored_block = util::xor(&block, &previous_block);
         // Encrypt with key
         let encrypted_block = aes_encrypt(xored_block, &key);
         encrypted_blocks.push(encrypted_block);
         previous_block = encrypted_block;
     }
 
-    un_group(encrypted_blocks)
+    let cipher_text = un_group(encrypted_blocks);
+    op.finish(true);
+    cipher_text
 }
 
-fn cbc_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+pub fn cbc_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+    let op = trace::Operation::start("decrypt", "cbc", cipher_text.len());
+    warnings::warn(warnings::Warning::UnauthenticatedCbc);
+
     let blocks = group(cipher_text);
 
     // The first block is the initialization vector (IV)
@@ -199,13 +293,15 @@ fn cbc_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
         // Decrypt
         let decrypted_block = aes_decrypt(*block, &key);
         // Unxor
-        let xored_block = utils::xor_block_bytes(&decrypted_block, &previous_block);
+        let xored_block = util::xor(&decrypted_block, &previous_block);
         decrypted_blocks.push(xored_block);
         previous_block = *block;
     }
 
     let decrypted_data = un_group(decrypted_blocks);
-    un_pad(decrypted_data)
+    let plain_text = un_pad(decrypted_data);
+    op.finish(true);
+    plain_text
 }
 
 /// Another mode which you can implement on your own is counter mode.
@@ -224,37 +320,43 @@ fn cbc_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
 ///
 /// Once again, you will need to generate a random nonce which is 64 bits long. This should be
 /// inserted as the first block of the ciphertext.
-fn ctr_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
-    let nonce = utils::create_rand_nonce();
+pub fn ctr_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+    ctr_encrypt_with_nonce(plain_text, key, utils::create_rand_nonce())
+}
 
+/// Like ctr_encrypt, but with a nonce chosen by the caller, for example by a
+/// [`NonceBuilder`](invocations::NonceBuilder). The nonce must never repeat under one key.
+pub fn ctr_encrypt_with_nonce(
+    plain_text: Vec<u8>,
+    key: [u8; BLOCK_SIZE],
+    nonce: [u8; NONCE_SIZE],
+) -> Vec<u8> {
+    let op = trace::Operation::start("encrypt", "ctr", plain_text.len());
     let mut cipher_text = nonce.to_vec();
 
     for (i, block) in plain_text.chunks(BLOCK_SIZE).enumerate() {
         let counter = i as u64;
 
         // Construct the counter block (nonce | counter)
-        let mut counter_block = [0u8; BLOCK_SIZE];
-        counter_block[..NONCE_SIZE].copy_from_slice(&nonce);
-        counter_block[NONCE_SIZE..].copy_from_slice(&counter.to_le_bytes());
+        let counter_block = util::ctr_counter_block(&nonce, counter);
 
         // Encrypt with key
         let encrypted_counter = aes_encrypt(counter_block, &key);
 
         // XOR
-        let mut encrypted_block = vec![0u8; block.len()];
-        for j in 0..block.len() {
-            encrypted_block[j] = block[j] ^ encrypted_counter[j];
-        }
+        let mut encrypted_block = block.to_vec();
+        util::xor_in_place(&mut encrypted_block, &encrypted_counter[..block.len()]);
 
         cipher_text.extend_from_slice(&encrypted_block);
     }
 
+    op.finish(true);
     cipher_text
 }
 
-fn ctr_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
-    //
-    let nonce = &cipher_text[..NONCE_SIZE];
+pub fn ctr_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+    let op = trace::Operation::start("decrypt", "ctr", cipher_text.len());
+    let nonce: &[u8; NONCE_SIZE] = cipher_text[..NONCE_SIZE].try_into().unwrap();
 
     let mut plain_text = Vec::new();
 
@@ -262,25 +364,57 @@ fn ctr_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
         let counter = i as u64;
 
         // Construct the counter block (nonce | counter)
-        let mut counter_block = [0u8; BLOCK_SIZE];
-        counter_block[..NONCE_SIZE].copy_from_slice(nonce);
-        counter_block[NONCE_SIZE..].copy_from_slice(&counter.to_le_bytes());
+        let counter_block = util::ctr_counter_block(nonce, counter);
 
-        // Encrypt with key
-        let encrypted_counter = aes_decrypt(counter_block, &key);
+        // Encrypt with key. CTR only ever runs the cipher forwards, even when decrypting.
+        let encrypted_counter = aes_encrypt(counter_block, &key);
 
         // XOR the encrypted counter block with the ciphertext block
-        let mut decrypted_block = vec![0u8; block.len()];
-        for j in 0..block.len() {
-            decrypted_block[j] = block[j] ^ encrypted_counter[j];
-        }
+        let mut decrypted_block = block.to_vec();
+        util::xor_in_place(&mut decrypted_block, &encrypted_counter[..block.len()]);
 
         plain_text.extend_from_slice(&decrypted_block);
     }
 
+    op.finish(true);
     plain_text
 }
 
+/// The modes above, as a value that can be picked at runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
+impl Mode {
+    /// Short lowercase name, as used in logs and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Ecb => "ecb",
+            Mode::Cbc => "cbc",
+            Mode::Ctr => "ctr",
+        }
+    }
+
+    pub fn encrypt(self, plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+        match self {
+            Mode::Ecb => ecb_encrypt(plain_text, key),
+            Mode::Cbc => cbc_encrypt(plain_text, key),
+            Mode::Ctr => ctr_encrypt(plain_text, key),
+        }
+    }
+
+    pub fn decrypt(self, cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
+        match self {
+            Mode::Ecb => ecb_decrypt(cipher_text, key),
+            Mode::Cbc => cbc_decrypt(cipher_text, key),
+            Mode::Ctr => ctr_decrypt(cipher_text, key),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -332,4 +466,15 @@ mod tests {
         let decrypted_text = ctr_decrypt(encrypted_text, KEY);
         assert_eq!(decrypted_text, text_spans_multiple_blocks);
     }
+
+    #[test]
+    fn test_other_block_sizes() {
+        // 3DES blocks are 8 bytes.
+        let data: Vec<u8> = (0..24).collect();
+        let blocks: Vec<[u8; 8]> = group(data.clone());
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[1], [8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(util::xor(&blocks[0], &blocks[1]), [8, 8, 8, 8, 8, 8, 8, 8]);
+        assert_eq!(un_group(blocks), data);
+    }
 }
@@ -0,0 +1,199 @@
+//! License and activation blobs: claims sealed into an envelope, armored as text.
+//!
+//! A worked example of putting the layers together. The vendor [`issue`]s a license for a set
+//! of [`Claims`], which are encoded as CBOR and sealed into a GCM [envelope](crate::envelope)
+//! that expires with them, with the key's check value. The envelope is armored as base64url
+//! between marker lines, so it survives being pasted into an email or a config file:
+//!
+//! ```text
+//! -----BEGIN AES-MODES LICENSE-----
+//! QU1FVgUDAAAAAQxY...
+//! -----END AES-MODES LICENSE-----
+//! ```
+//!
+//! The application [`verify`]s it with the same key: the check value catches a license for
+//! another product, the GCM tag any edit to the claims or the expiry, and the
+//! [clock](crate::clock) an expired license.
+//!
+//! The tag is the signature here, and it is a symmetric one: whoever can verify a license can
+//! also issue one. That is enough to stop a user from changing "trial" to "pro" in a text
+//! editor, and no more. A key that ships in every copy of the application will be found, so
+//! licenses that must hold up against someone who has it need a public-key signature, which
+//! this crate doesn't provide.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    envelope::{Envelope, EnvelopeError, Validity},
+    utils, BLOCK_SIZE,
+};
+
+const BEGIN: &str = "-----BEGIN AES-MODES LICENSE-----";
+const END: &str = "-----END AES-MODES LICENSE-----";
+/// The length of the armor's lines, as in PEM.
+const LINE_LEN: usize = 64;
+
+/// What a license grants.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Claims {
+    pub customer: String,
+    pub features: Vec<String>,
+    /// When the license expires, in seconds since the Unix epoch.
+    pub expires: u64,
+}
+
+impl Claims {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|granted| granted == feature)
+    }
+}
+
+/// Why [`verify`] rejected a license.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LicenseError {
+    /// The text isn't an armored license.
+    Armor,
+    /// The envelope is damaged, under another key, modified or expired.
+    Envelope(EnvelopeError),
+    /// The envelope is authentic, but its contents aren't claims.
+    Claims,
+}
+
+impl fmt::Display for LicenseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LicenseError::Armor => f.write_str("not an armored license"),
+            LicenseError::Envelope(error) => error.fmt(f),
+            LicenseError::Claims => f.write_str("license contents are not valid claims"),
+        }
+    }
+}
+
+impl Error for LicenseError {}
+
+impl From<EnvelopeError> for LicenseError {
+    fn from(error: EnvelopeError) -> Self {
+        LicenseError::Envelope(error)
+    }
+}
+
+/// Seals `claims` under `key`, named `key_id` in the envelope, into an armored license that is
+/// valid until `claims.expires`.
+pub fn issue(claims: &Claims, key_id: u32, key: [u8; BLOCK_SIZE]) -> String {
+    let mut encoded = Vec::new();
+    ciborium::into_writer(claims, &mut encoded).expect("writing to a Vec can't fail");
+    let validity = Validity {
+        not_before: None,
+        not_after: Some(claims.expires),
+    };
+    let envelope =
+        Envelope::seal_with_validity(validity, key_id, key, encoded).with_check_value(&key);
+    armor(&envelope.to_bytes())
+}
+
+/// Checks an armored license against `key` and the system clock, and returns its claims.
+pub fn verify(armored: &str, key: [u8; BLOCK_SIZE]) -> Result<Claims, LicenseError> {
+    verify_with_clock(armored, key, &SystemClock)
+}
+
+/// Like [`verify`], checking the expiry against `clock`.
+pub fn verify_with_clock(
+    armored: &str,
+    key: [u8; BLOCK_SIZE],
+    clock: &impl Clock,
+) -> Result<Claims, LicenseError> {
+    let envelope = Envelope::from_bytes(&dearmor(armored).ok_or(LicenseError::Armor)?)?;
+    // Only a GCM envelope authenticates its expiry, so accept nothing else.
+    if envelope.validity.not_after.is_none() {
+        return Err(LicenseError::Envelope(EnvelopeError::Malformed));
+    }
+    let encoded = envelope.open_with_clock(key, clock)?;
+    let claims: Claims =
+        ciborium::from_reader(encoded.as_slice()).map_err(|_| LicenseError::Claims)?;
+    if Some(claims.expires) != envelope.validity.not_after {
+        return Err(LicenseError::Claims);
+    }
+    Ok(claims)
+}
+
+fn armor(bytes: &[u8]) -> String {
+    let encoded = utils::base64url_encode(bytes);
+    let mut armored = format!("{}\n", BEGIN);
+    for line in encoded.as_bytes().chunks(LINE_LEN) {
+        armored.push_str(std::str::from_utf8(line).expect("base64url is ASCII"));
+        armored.push('\n');
+    }
+    armored.push_str(END);
+    armored.push('\n');
+    armored
+}
+
+/// The bytes between the marker lines, ignoring surrounding text and line endings.
+fn dearmor(armored: &str) -> Option<Vec<u8>> {
+    let (_, rest) = armored.split_once(BEGIN)?;
+    let (body, _) = rest.split_once(END)?;
+    let encoded: String = body.split_whitespace().collect();
+    utils::base64url_decode(&encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+
+    const KEY: [u8; BLOCK_SIZE] = [5; BLOCK_SIZE];
+
+    fn claims() -> Claims {
+        Claims {
+            customer: "Example Ltd".to_string(),
+            features: vec!["export".to_string(), "sso".to_string()],
+            expires: 2_000_000_000,
+        }
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let license = issue(&claims(), 1, KEY);
+        assert!(license.starts_with(BEGIN));
+        assert!(license.lines().all(|line| line.len() <= LINE_LEN));
+
+        let pasted = format!(
+            "Your license:\r\n\r\n{}\r\nThanks!",
+            license.replace('\n', "\r\n")
+        );
+        let verified = verify_with_clock(&pasted, KEY, &FixedClock(1_900_000_000)).unwrap();
+        assert_eq!(verified, claims());
+        assert!(verified.has_feature("sso"));
+        assert!(!verified.has_feature("audit"));
+
+        assert_eq!(
+            verify_with_clock(&license, KEY, &FixedClock(2_000_000_001)),
+            Err(LicenseError::Envelope(EnvelopeError::Expired {
+                not_after: 2_000_000_000
+            }))
+        );
+        assert_eq!(
+            verify_with_clock(&license, [6; BLOCK_SIZE], &FixedClock(0)),
+            Err(LicenseError::Envelope(EnvelopeError::WrongKey))
+        );
+        assert_eq!(verify("not a license", KEY), Err(LicenseError::Armor));
+    }
+
+    #[test]
+    fn test_rejects_modified_licenses() {
+        let license = issue(&claims(), 1, KEY);
+        let mut envelope = Envelope::from_bytes(&dearmor(&license).unwrap()).unwrap();
+        envelope.validity.not_after = Some(u64::MAX);
+        assert_eq!(
+            verify_with_clock(&armor(&envelope.to_bytes()), KEY, &FixedClock(0)),
+            Err(LicenseError::Envelope(EnvelopeError::Authentication))
+        );
+
+        let unbounded = Envelope::seal_with_validity(Validity::default(), 1, KEY, vec![0xa0]);
+        assert_eq!(
+            verify_with_clock(&armor(&unbounded.to_bytes()), KEY, &FixedClock(0)),
+            Err(LicenseError::Envelope(EnvelopeError::Malformed))
+        );
+    }
+}
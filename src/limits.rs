@@ -0,0 +1,242 @@
+//! Bounds on what the parsers allocate for untrusted input.
+//!
+//! Every container format here has length fields, and a parser that believes them can be made
+//! to allocate whatever an attacker writes there: a few bytes of header claiming a 16 EiB
+//! payload abort the process before a single byte of it arrives. The parsers that take
+//! [`Limits`] check each length against them before allocating anything, and allocate with
+//! `try_reserve`, so a length that is within the limits but still more than the allocator can
+//! give fails with an error instead of an abort. A service sets the limits to what its
+//! legitimate inputs need:
+//!
+//! - [`Envelope::from_bytes_with_limits`](crate::envelope::Envelope::from_bytes_with_limits)
+//!   checks the envelope's size and chunk count;
+//! - [`StreamDecryptor::with_limits`](crate::chunked::StreamDecryptor::with_limits) and
+//!   [`MessageStreamReader::with_limits`](crate::messages::MessageStreamReader::with_limits)
+//!   check each chunk or record before reading it;
+//! - [`EncryptedFile::open_with_limits`](crate::chunked::EncryptedFile::open_with_limits)
+//!   checks the file's chunk size before reading any chunk.
+//!
+//! Compressed streams add a second length that no header states: what a chunk decompresses
+//! to, which for a few kilobytes of deflate or zstd can be gigabytes.
+//! [`with_max_decompressed_chunk_size`](Limits::with_max_decompressed_chunk_size) bounds it,
+//! and decompression past it fails with [`OutOfMemory`](io::ErrorKind::OutOfMemory).
+//!
+//! The defaults are the formats' own maximums, and are what the parsers without a `limits`
+//! argument use. Some parsers take no limits, because nothing they allocate depends on a
+//! length an attacker chooses:
+//!
+//! - the [backup](crate::backup) manifest is authenticated before it is parsed, and its
+//!   lengths are checked against the bytes actually there;
+//! - the [Merkle](crate::merkle) manifest has a fixed size;
+//! - [`tls_record`](crate::tls_record) lengths are 16 bits, and the caller hands over the
+//!   whole record;
+//! - [`file_handle`](crate::file_handle) sectors have a fixed size.
+
+use std::io;
+
+use crate::{chunked::MAX_CHUNK_SIZE, gcm::TAG_SIZE, session};
+
+/// The largest envelope accepted by default: 1 GiB.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 30;
+/// The most chunks an envelope may have by default.
+pub const DEFAULT_MAX_CHUNKS: usize = 1 << 16;
+/// The largest chunk or record accepted by default, which is the largest either stream format
+/// can write.
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = {
+    let chunk = MAX_CHUNK_SIZE as usize + TAG_SIZE;
+    let record = session::HEADER_SIZE + session::MAX_RECORD_SIZE + TAG_SIZE;
+    if chunk > record {
+        chunk
+    } else {
+        record
+    }
+};
+/// The most one chunk may decompress to by default: 1 GiB.
+pub const DEFAULT_MAX_DECOMPRESSED_CHUNK_SIZE: usize = 1 << 30;
+
+/// How much a parser may allocate. See the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    max_message_size: usize,
+    max_chunks: usize,
+    max_chunk_size: usize,
+    max_decompressed_chunk_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_chunks: DEFAULT_MAX_CHUNKS,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            max_decompressed_chunk_size: DEFAULT_MAX_DECOMPRESSED_CHUNK_SIZE,
+        }
+    }
+
+    /// Sets the largest whole envelope, in bytes.
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Sets the most chunks one envelope may have.
+    pub fn with_max_chunks(mut self, count: usize) -> Self {
+        self.max_chunks = count;
+        self
+    }
+
+    /// Sets the largest chunk or record a stream may have, in bytes, tag included.
+    pub fn with_max_chunk_size(mut self, size: usize) -> Self {
+        self.max_chunk_size = size;
+        self
+    }
+
+    /// Sets the most bytes one chunk of a compressed stream may decompress to.
+    pub fn with_max_decompressed_chunk_size(mut self, size: usize) -> Self {
+        self.max_decompressed_chunk_size = size;
+        self
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn max_chunks(&self) -> usize {
+        self.max_chunks
+    }
+
+    pub fn max_chunk_size(&self) -> usize {
+        self.max_chunk_size
+    }
+
+    pub fn max_decompressed_chunk_size(&self) -> usize {
+        self.max_decompressed_chunk_size
+    }
+}
+
+/// A zeroed buffer of `len` bytes, or an [`OutOfMemory`](io::ErrorKind::OutOfMemory) error if
+/// it is over `limit` or can't be allocated.
+pub(crate) fn try_zeroed(len: usize, limit: usize) -> io::Result<Vec<u8>> {
+    let too_large = || io::Error::new(io::ErrorKind::OutOfMemory, "length over the limit");
+    if len > limit {
+        return Err(too_large());
+    }
+    let mut buffer = Vec::new();
+    buffer.try_reserve_exact(len).map_err(|_| too_large())?;
+    buffer.resize(len, 0);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_zeroed() {
+        assert_eq!(try_zeroed(3, 3).unwrap(), [0; 3]);
+        assert_eq!(
+            try_zeroed(4, 3).unwrap_err().kind(),
+            io::ErrorKind::OutOfMemory
+        );
+        // Within the limit, but more than any allocator has.
+        assert_eq!(
+            try_zeroed(usize::MAX / 2, usize::MAX).unwrap_err().kind(),
+            io::ErrorKind::OutOfMemory
+        );
+    }
+
+    #[test]
+    fn test_parsers_enforce_limits() {
+        use crate::{
+            chunked::{EncryptedFile, StreamDecryptor, StreamEncryptor, StreamOptions},
+            envelope::{Envelope, EnvelopeError, EnvelopeMode},
+            messages::{MessageStreamReader, MessageStreamWriter},
+        };
+
+        let envelope = Envelope::seal(EnvelopeMode::Gcm, 1, [1; 16], vec![0; 100]);
+        let mut split = envelope.clone();
+        split.chunks = split.chunks[0].chunks(10).map(<[u8]>::to_vec).collect();
+        let limits = Limits::new().with_max_chunks(5);
+        assert_eq!(
+            Envelope::from_bytes_with_limits(&split.to_bytes(), &limits),
+            Err(EnvelopeError::TooLarge)
+        );
+        let bytes = envelope.to_bytes();
+        assert_eq!(
+            Envelope::from_bytes_with_limits(&bytes, &limits),
+            Ok(envelope)
+        );
+        let limits = Limits::new().with_max_message_size(bytes.len() - 1);
+        assert_eq!(
+            Envelope::from_bytes_with_limits(&bytes, &limits),
+            Err(EnvelopeError::TooLarge)
+        );
+
+        let secret = b"limits test secret";
+        let options = StreamOptions::new().with_chunk_size(1 << 20);
+        let stream = StreamEncryptor::with_options(secret, options, Vec::new())
+            .unwrap()
+            .finish()
+            .unwrap();
+        let limits = Limits::new().with_max_chunk_size(64 * 1024);
+        let error = StreamDecryptor::with_limits(secret, &limits, stream.as_slice())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+        let error = EncryptedFile::open_with_limits(secret, &limits, io::Cursor::new(&stream))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+
+        let mut writer = MessageStreamWriter::new(secret, Vec::new()).unwrap();
+        writer.write_message(&[0; 1000]).unwrap();
+        let stream = writer.finish().unwrap();
+        let mut reader =
+            MessageStreamReader::with_limits(secret, &limits, stream.as_slice()).unwrap();
+        assert_eq!(reader.read_message().unwrap().unwrap().len(), 1000);
+        let limits = Limits::new().with_max_chunk_size(100);
+        let mut reader =
+            MessageStreamReader::with_limits(secret, &limits, stream.as_slice()).unwrap();
+        assert_eq!(
+            reader.read_message().unwrap_err().kind(),
+            io::ErrorKind::OutOfMemory
+        );
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn test_decompression_bomb_is_refused() {
+        use std::io::{Read, Write};
+
+        use crate::{
+            chunked::{StreamDecryptor, StreamEncryptor, StreamOptions},
+            compression::{Compression, OracleRiskAcknowledged},
+        };
+
+        let secret = b"limits test secret";
+        let options = StreamOptions::new()
+            .with_chunk_size(1 << 20)
+            .with_compression(Compression::Deflate, OracleRiskAcknowledged);
+        let mut encryptor = StreamEncryptor::with_options(secret, options, Vec::new()).unwrap();
+        encryptor.write_all(&vec![0; 4 << 20]).unwrap();
+        let stream = encryptor.finish().unwrap();
+        assert!(stream.len() < 64 * 1024);
+
+        let limits = Limits::new().with_max_decompressed_chunk_size(64 * 1024);
+        let mut decryptor =
+            StreamDecryptor::with_limits(secret, &limits, stream.as_slice()).unwrap();
+        let error = decryptor.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+
+        let mut decryptor = StreamDecryptor::new(secret, stream.as_slice()).unwrap();
+        let mut plain_text = Vec::new();
+        decryptor.read_to_end(&mut plain_text).unwrap();
+        assert_eq!(plain_text.len(), 4 << 20);
+    }
+}
@@ -0,0 +1,176 @@
+//! Memory for keys that is kept out of swap and core dumps, and wiped when freed.
+//!
+//! A key in an ordinary allocation can outlive its use in several ways: the page holding it
+//! may be swapped to disk, a crash may write it into a core dump, and the allocator hands the
+//! freed bytes to the next caller as they are. A [`LockedBox`] closes all three:
+//!
+//! - its value sits on pages of its own, which are `mlock`ed so they are never swapped out;
+//! - on Linux, the pages are marked `MADV_DONTDUMP`, so core dumps leave them out;
+//! - the value is zeroized before the pages are unlocked and freed.
+//!
+//! Locking can fail, typically because the process is over `RLIMIT_MEMLOCK`, in which case
+//! the box still works and still zeroizes, and [`LockedBox::is_locked`] says so. On platforms
+//! other than Unix, nothing is locked. Every box takes at least one page, so this is for keys,
+//! not for bulk data.
+//!
+//! The value passed to [`LockedBox::new`] may leave copies behind on the stack on its way in.
+//! Keys should go into a box as soon as they are made and be used from there.
+
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use zeroize::Zeroize;
+
+/// A value on locked pages of its own, zeroized when dropped.
+pub struct LockedBox<T: Zeroize> {
+    ptr: NonNull<T>,
+    layout: Layout,
+    locked: bool,
+}
+
+// SAFETY: the box owns its value as a `Box` would.
+unsafe impl<T: Zeroize + Send> Send for LockedBox<T> {}
+unsafe impl<T: Zeroize + Sync> Sync for LockedBox<T> {}
+
+impl<T: Zeroize> LockedBox<T> {
+    pub fn new(value: T) -> Self {
+        let page_size = page_size();
+        let layout = Layout::from_size_align(
+            size_of::<T>().max(1).next_multiple_of(page_size),
+            page_size.max(align_of::<T>()),
+        )
+        .expect("a page-aligned layout is valid");
+        // SAFETY: the layout isn't zero-sized.
+        let ptr = NonNull::new(unsafe { alloc::alloc(layout) }.cast::<T>())
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        // The pages are locked before the value is written to them.
+        let locked = lock(ptr.as_ptr().cast(), layout.size());
+        // SAFETY: the allocation is big enough and aligned for a `T`.
+        unsafe { ptr.as_ptr().write(value) };
+        LockedBox {
+            ptr,
+            layout,
+            locked,
+        }
+    }
+
+    /// Whether the pages are locked in memory.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl<T: Zeroize> Drop for LockedBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the value was written in `new` and is dropped only here, before the memory
+        // it lives in is freed with the layout it was allocated with.
+        unsafe {
+            (*self.ptr.as_ptr()).zeroize();
+            ptr::drop_in_place(self.ptr.as_ptr());
+            unlock(self.ptr.as_ptr().cast(), self.layout.size());
+            alloc::dealloc(self.ptr.as_ptr().cast(), self.layout);
+        }
+    }
+}
+
+impl<T: Zeroize> Deref for LockedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the box owns an initialized value until it is dropped.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Zeroize> DerefMut for LockedBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: as in `deref`, and `&mut self` makes the access unique.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+/// Clones into pages of its own.
+impl<T: Zeroize + Clone> Clone for LockedBox<T> {
+    fn clone(&self) -> Self {
+        LockedBox::new(T::clone(self))
+    }
+}
+
+impl<T: Zeroize + PartialEq> PartialEq for LockedBox<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Zeroize + Eq> Eq for LockedBox<T> {}
+
+/// Never shows the value.
+impl<T: Zeroize> fmt::Debug for LockedBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LockedBox(..)")
+    }
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    page_size.try_into().unwrap_or(4096)
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+#[cfg(unix)]
+fn lock(ptr: *mut u8, len: usize) -> bool {
+    // SAFETY: `ptr` is the page-aligned start of an allocation of `len` bytes.
+    unsafe {
+        #[cfg(target_os = "linux")]
+        libc::madvise(ptr.cast(), len, libc::MADV_DONTDUMP);
+        libc::mlock(ptr.cast(), len) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_ptr: *mut u8, _len: usize) -> bool {
+    false
+}
+
+/// Undoes [`lock`], even if `mlock` failed, since the allocator may hand the pages to data
+/// that should be dumped.
+#[cfg(unix)]
+fn unlock(ptr: *mut u8, len: usize) {
+    // SAFETY: as in `lock`.
+    unsafe {
+        libc::munlock(ptr.cast(), len);
+        #[cfg(target_os = "linux")]
+        libc::madvise(ptr.cast(), len, libc::MADV_DODUMP);
+    }
+}
+
+#[cfg(not(unix))]
+fn unlock(_ptr: *mut u8, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_holds_the_value() {
+        let mut key = LockedBox::new([7u8; 16]);
+        assert_eq!(*key, [7; 16]);
+        key[0] = 1;
+        let copy = key.clone();
+        key[1] = 2;
+        assert_eq!(copy[..2], [1, 7]);
+        assert_ne!(copy, key);
+        assert_eq!(&*key as *const [u8; 16] as usize % page_size(), 0);
+        assert_eq!(format!("{:?}", key), "LockedBox(..)");
+    }
+}
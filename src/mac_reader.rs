@@ -0,0 +1,185 @@
+//! Checking an encrypt-then-MAC message's tag while it streams past.
+//!
+//! An encrypt-then-MAC message is `data | tag`, and the usual way to verify it is to buffer
+//! all of `data`, check the tag, and only then decrypt. That needs memory for the whole
+//! message. A [`MacVerifyingReader`] instead hands `data` to the caller as it arrives, feeding
+//! it to the MAC on the way, and holds back only the last bytes, which might be the tag. At
+//! the end of the input it checks the tag, and the final `read` fails with an
+//! [`InvalidData`](io::ErrorKind::InvalidData) error wrapping an [`AuthenticationError`] if
+//! it doesn't match. A tag that is cut short fails the same way.
+//!
+//! # Releasing unauthenticated data
+//!
+//! This is a trade-off, not a free lunch. Everything the reader returns before that final
+//! `read` is **unauthenticated**: an attacker may have changed it, and the caller only finds
+//! out at the end. Streaming is only safe if nothing irreversible happens to the data until
+//! then. Write it to a temporary file and rename it into place once the reader reports the
+//! end of the input, as [`files`](crate::files) does, or otherwise be ready to throw it away.
+//! Don't parse it, act on it, or send it on in the meantime. Decrypting an unauthenticated CBC
+//! ciphertext and reacting to its padding is exactly the padding oracle this construction
+//! exists to prevent.
+//!
+//! When that discipline can't be kept, buffer and verify first, or use a format whose every
+//! chunk is authenticated on its own, such as [`chunked`](crate::chunked).
+
+use std::{
+    io::{self, Read},
+    mem,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::gcm::AuthenticationError;
+
+/// Reads `data` from a `data | tag` message, and checks `tag` against a MAC of `data` at the
+/// end. See the [module documentation](self) for what may and may not be done with the data
+/// before then.
+pub struct MacVerifyingReader<R: Read, M: Mac = Hmac<Sha256>> {
+    inner: R,
+    /// Taken at the end of the input, when the tag is checked.
+    mac: Option<M>,
+    /// The last bytes read, which are the tag if the input ends here.
+    held_back: Vec<u8>,
+    verified: bool,
+}
+
+impl<R: Read> MacVerifyingReader<R> {
+    /// A reader of a message whose tag is an HMAC-SHA256 under `key`.
+    pub fn hmac_sha256(key: &[u8], inner: R) -> Self {
+        Self::new(
+            Hmac::new_from_slice(key).expect("HMAC takes any key size"),
+            inner,
+        )
+    }
+}
+
+impl<R: Read, M: Mac> MacVerifyingReader<R, M> {
+    /// A reader that checks the tag against `mac`, which may have absorbed a header already.
+    pub fn new(mac: M, inner: R) -> Self {
+        MacVerifyingReader {
+            inner,
+            mac: Some(mac),
+            held_back: Vec::with_capacity(M::output_size()),
+            verified: false,
+        }
+    }
+
+    /// Whether the tag has been checked and matched.
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn end(&self) -> io::Result<usize> {
+        if !self.verified {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                AuthenticationError,
+            ));
+        }
+        Ok(0)
+    }
+}
+
+impl<R: Read, M: Mac> Read for MacVerifyingReader<R, M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // Every read after the end reports the outcome again, so a failure can't be
+            // mistaken for a clean end by a caller that retries.
+            let Some(mac) = &mut self.mac else {
+                return self.end();
+            };
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                let mac = self.mac.take().unwrap();
+                self.verified = self.held_back.len() == M::output_size()
+                    && mac.verify_slice(&self.held_back).is_ok();
+                return self.end();
+            }
+            // The held-back bytes come first, then the new ones, of which the last tag-size
+            // bytes are held back again. What is released fits in `buf`, since at most the
+            // tag size was held back before.
+            let mut pending = mem::take(&mut self.held_back);
+            pending.extend_from_slice(&buf[..n]);
+            let released = pending.len().saturating_sub(M::output_size());
+            self.held_back = pending.split_off(released);
+            if released > 0 {
+                mac.update(&pending);
+                buf[..released].copy_from_slice(&pending);
+                return Ok(released);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"encrypt-then-MAC key";
+
+    fn message(data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY).unwrap();
+        mac.update(data);
+        [data, &mac.finalize().into_bytes()].concat()
+    }
+
+    /// Reads `reader` `step` bytes at a time, returning what was read and how it ended.
+    fn read_in_steps(mut reader: impl Read, step: usize) -> (Vec<u8>, io::Result<()>) {
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; step];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return (data, Ok(())),
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(error) => return (data, Err(error)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_streams_and_verifies() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let sent = message(&data);
+        for step in [1, 7, 32, 4096] {
+            let reader = MacVerifyingReader::hmac_sha256(KEY, &sent[..]);
+            let (read, result) = read_in_steps(reader, step);
+            assert!(result.is_ok(), "step {}", step);
+            assert_eq!(read, data);
+        }
+        let mut reader = MacVerifyingReader::hmac_sha256(KEY, &sent[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert!(reader.is_verified());
+
+        let empty = message(b"");
+        let (read, result) = read_in_steps(MacVerifyingReader::hmac_sha256(KEY, &empty[..]), 16);
+        assert!(read.is_empty() && result.is_ok());
+    }
+
+    #[test]
+    fn test_fails_at_the_end() {
+        let mut tampered = message(b"pay 100 to alice");
+        tampered[4] ^= b'1' ^ b'9';
+        let mut reader = MacVerifyingReader::hmac_sha256(KEY, &tampered[..]);
+        let mut read = Vec::new();
+        let error = reader.read_to_end(&mut read).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // The tampered data was released before the tag was checked.
+        assert_eq!(read, b"pay 900 to alice");
+        assert!(!reader.is_verified());
+        assert!(reader.read(&mut [0u8; 16]).is_err());
+
+        let full = message(b"cut short");
+        for len in [0, 10, full.len() - 1] {
+            let reader = MacVerifyingReader::hmac_sha256(KEY, &full[..len]);
+            assert!(read_in_steps(reader, 5).1.is_err(), "length {}", len);
+        }
+    }
+}
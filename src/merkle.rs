@@ -0,0 +1,356 @@
+//! A Merkle tree over the chunks of a [`chunked`](crate::chunked) stream.
+//!
+//! Every chunk of a stream is already authenticated on its own, but checking that a chunk sits
+//! at the right place in the _right_ stream means reading the stream from the start. A
+//! container that wants to verify chunk 7,000 of a large archive can't afford that.
+//!
+//! The tree is built over the chunks' GCM tags, which already commit to their contents:
+//!
+//! ```text
+//!                root
+//!           /          \
+//!       node            node
+//!      /    \          /    \
+//!   leaf    leaf    leaf    leaf
+//!    |       |       |       |
+//!  tag 0   tag 1   tag 2   tag 3
+//! ```
+//!
+//! The root, together with the number of chunks, goes into a [`Manifest`] that is authenticated
+//! with a key derived from the stream's master secret. The sealed manifest starts with the
+//! SHA-256 of the stream header, which the MAC covers too, so it commits to every parameter
+//! of the stream and can't be attached to another stream, or to this one with its header
+//! altered. A single chunk is then verified against the manifest with about log2(n) hashes,
+//! and a chunk that was substituted or moved to another position doesn't hash up to the root.
+//!
+//! Leaves and inner nodes are hashed with different prefixes, so a node can't be passed off as
+//! a leaf. A node without a sibling is carried up to the next level unchanged.
+
+use std::{error::Error, fmt};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    chunked::{ChunkKeys, StreamHeader},
+    gcm::{constant_time_eq, TAG_SIZE},
+};
+
+pub const HASH_SIZE: usize = 32;
+
+/// A sealed manifest is the header hash, the chunk count, the root, and an HMAC over all three.
+pub const MANIFEST_SIZE: usize = HASH_SIZE + 8 + HASH_SIZE + HASH_SIZE;
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+pub type Hash = [u8; HASH_SIZE];
+
+/// Why a manifest or a chunk was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManifestError {
+    /// The manifest has the wrong length.
+    Malformed,
+    /// The manifest belongs to a stream with a different header.
+    WrongStream,
+    /// The manifest was modified, or belongs to another stream.
+    BadMac,
+    /// The chunk with this index doesn't belong at this position of the stream.
+    BadProof { index: u64 },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Malformed => f.write_str("malformed manifest"),
+            ManifestError::WrongStream => f.write_str("manifest belongs to another stream"),
+            ManifestError::BadMac => f.write_str("manifest failed authentication"),
+            ManifestError::BadProof { index } => {
+                write!(f, "chunk {} does not match the manifest", index)
+            }
+        }
+    }
+}
+
+impl Error for ManifestError {}
+
+/// The leaf for an encrypted chunk is the hash of its last 16 bytes: its tag, plus the end of
+/// its ciphertext if the stream uses shorter tags.
+fn leaf_hash(chunk: &[u8]) -> Hash {
+    let tag = &chunk[chunk.len().saturating_sub(TAG_SIZE)..];
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(tag)
+        .finalize()
+        .into()
+}
+
+/// The SHA-256 of the header as it is stored at the start of the stream.
+pub fn header_hash(header: &StreamHeader) -> Hash {
+    Sha256::digest(header.to_bytes()).into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([NODE_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// All levels of the tree, from the leaves up to the root.
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree over a stream's encrypted chunks, as returned by
+    /// [`split_chunks`](crate::chunked::split_chunks).
+    pub fn new(chunks: &[&[u8]]) -> Self {
+        assert!(!chunks.is_empty(), "a stream has at least its final chunk");
+
+        let mut levels = vec![chunks
+            .iter()
+            .map(|chunk| leaf_hash(chunk))
+            .collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    pub fn leaf_count(&self) -> u64 {
+        self.levels[0].len() as u64
+    }
+
+    /// The sibling hashes on the way from chunk `index` up to the root.
+    pub fn proof(&self, index: u64) -> Vec<Hash> {
+        assert!(index < self.leaf_count(), "no chunk {} in the tree", index);
+
+        let mut position = index as usize;
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                proof.push(*sibling);
+            }
+            position /= 2;
+        }
+        proof
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        Manifest {
+            chunk_count: self.leaf_count(),
+            root: self.root(),
+        }
+    }
+}
+
+/// What a container stores to let readers verify any chunk on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub chunk_count: u64,
+    pub root: Hash,
+}
+
+impl Manifest {
+    /// The sealed manifest without its MAC, which is also what the MAC covers.
+    fn body(&self, keys: &ChunkKeys) -> [u8; MANIFEST_SIZE - HASH_SIZE] {
+        let mut body = [0u8; MANIFEST_SIZE - HASH_SIZE];
+        body[..HASH_SIZE].copy_from_slice(&header_hash(keys.header()));
+        body[HASH_SIZE..HASH_SIZE + 8].copy_from_slice(&self.chunk_count.to_be_bytes());
+        body[HASH_SIZE + 8..].copy_from_slice(&self.root);
+        body
+    }
+
+    fn mac(body: &[u8], keys: &ChunkKeys) -> Hash {
+        let mut mac =
+            HmacSha256::new_from_slice(&keys.manifest_key()).expect("HMAC takes any key size");
+        mac.update(body);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Serializes the manifest as `header hash | chunk count | root | HMAC`.
+    pub fn seal(&self, keys: &ChunkKeys) -> [u8; MANIFEST_SIZE] {
+        let body = self.body(keys);
+        let mut bytes = [0u8; MANIFEST_SIZE];
+        bytes[..body.len()].copy_from_slice(&body);
+        bytes[body.len()..].copy_from_slice(&Self::mac(&body, keys));
+        bytes
+    }
+
+    pub fn open(keys: &ChunkKeys, bytes: &[u8]) -> Result<Self, ManifestError> {
+        if bytes.len() != MANIFEST_SIZE {
+            return Err(ManifestError::Malformed);
+        }
+        let (body, mac) = bytes.split_at(MANIFEST_SIZE - HASH_SIZE);
+        if body[..HASH_SIZE] != header_hash(keys.header()) {
+            return Err(ManifestError::WrongStream);
+        }
+        let manifest = Manifest {
+            chunk_count: u64::from_be_bytes(body[HASH_SIZE..HASH_SIZE + 8].try_into().unwrap()),
+            root: body[HASH_SIZE + 8..].try_into().unwrap(),
+        };
+
+        if !constant_time_eq(&Self::mac(body, keys), mac) {
+            return Err(ManifestError::BadMac);
+        }
+        Ok(manifest)
+    }
+
+    /// Checks that `chunk` is chunk `index` of the stream, using its [`MerkleTree::proof`].
+    /// This proves the chunk's position, not its contents: those are still authenticated by
+    /// [`ChunkKeys::decrypt_chunk`].
+    pub fn verify_chunk(
+        &self,
+        index: u64,
+        chunk: &[u8],
+        proof: &[Hash],
+    ) -> Result<(), ManifestError> {
+        let bad_proof = ManifestError::BadProof { index };
+        if index >= self.chunk_count {
+            return Err(bad_proof);
+        }
+
+        // Replays the construction, tracking where the chunk is and how wide each level is.
+        let mut hash = leaf_hash(chunk);
+        let mut position = index;
+        let mut width = self.chunk_count;
+        let mut siblings = proof.iter();
+        while width > 1 {
+            if position ^ 1 < width {
+                let sibling = siblings.next().ok_or(bad_proof)?;
+                hash = if position.is_multiple_of(2) {
+                    node_hash(&hash, sibling)
+                } else {
+                    node_hash(sibling, &hash)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+
+        if siblings.next().is_some() || !constant_time_eq(&hash, &self.root) {
+            return Err(bad_proof);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunked::{encrypt_parallel, split_chunks, StreamHeader, HEADER_SIZE};
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    fn stream(chunk_count: usize) -> (ChunkKeys, Vec<u8>) {
+        let header = StreamHeader::new(32);
+        let data = vec![0xA5u8; 32 * (chunk_count - 1) + 5];
+        (
+            ChunkKeys::new(SECRET, header),
+            encrypt_parallel(SECRET, header, &data),
+        )
+    }
+
+    #[test]
+    fn test_every_chunk_verifies() {
+        for chunk_count in [1, 2, 3, 5, 8, 13] {
+            let (keys, stream) = stream(chunk_count);
+            let chunks = split_chunks(keys.header(), &stream[HEADER_SIZE..]);
+            assert_eq!(chunks.len(), chunk_count);
+
+            let tree = MerkleTree::new(&chunks);
+            let manifest = Manifest::open(&keys, &tree.manifest().seal(&keys)).unwrap();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let proof = tree.proof(i as u64);
+                assert!(proof.len() <= (chunk_count as f64).log2().ceil() as usize);
+                assert_eq!(manifest.verify_chunk(i as u64, chunk, &proof), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_substituted_and_reordered_chunks() {
+        let (keys, stream) = stream(6);
+        let chunks = split_chunks(keys.header(), &stream[HEADER_SIZE..]);
+        let tree = MerkleTree::new(&chunks);
+        let manifest = tree.manifest();
+
+        // Chunk 2 presented as chunk 3, with either chunk's proof.
+        for proof in [tree.proof(2), tree.proof(3)] {
+            assert_eq!(
+                manifest.verify_chunk(3, chunks[2], &proof),
+                Err(ManifestError::BadProof { index: 3 })
+            );
+        }
+
+        // A chunk from another stream with the same layout.
+        let (_, other) = self::stream(6);
+        let foreign = split_chunks(keys.header(), &other[HEADER_SIZE..])[1];
+        assert_eq!(
+            manifest.verify_chunk(1, foreign, &tree.proof(1)),
+            Err(ManifestError::BadProof { index: 1 })
+        );
+        assert_eq!(
+            manifest.verify_chunk(6, chunks[5], &tree.proof(5)),
+            Err(ManifestError::BadProof { index: 6 })
+        );
+    }
+
+    #[test]
+    fn test_manifest_is_authenticated() {
+        let (keys, stream) = stream(4);
+        let chunks = split_chunks(keys.header(), &stream[HEADER_SIZE..]);
+        let sealed = MerkleTree::new(&chunks).manifest().seal(&keys);
+
+        let mut tampered = sealed;
+        tampered[HASH_SIZE + 7] ^= 1;
+        assert_eq!(Manifest::open(&keys, &tampered), Err(ManifestError::BadMac));
+        assert_eq!(
+            Manifest::open(&keys, &sealed[1..]),
+            Err(ManifestError::Malformed)
+        );
+
+        let other_stream = ChunkKeys::new(SECRET, StreamHeader::new(32));
+        assert_eq!(
+            Manifest::open(&other_stream, &sealed),
+            Err(ManifestError::WrongStream)
+        );
+
+        // The same stream ID with other parameters, and a header hash edited to match them.
+        let altered = ChunkKeys::new(
+            SECRET,
+            StreamHeader {
+                plaintext_digest: true,
+                ..*keys.header()
+            },
+        );
+        assert_eq!(
+            Manifest::open(&altered, &sealed),
+            Err(ManifestError::WrongStream)
+        );
+        let mut rebound = sealed;
+        rebound[..HASH_SIZE].copy_from_slice(&header_hash(altered.header()));
+        assert_eq!(
+            Manifest::open(&altered, &rebound),
+            Err(ManifestError::BadMac)
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! A file or pipe holding a sequence of separately encrypted messages.
+//!
+//! [`chunked`](crate::chunked) streams encrypt one long byte stream; this encrypts a sequence
+//! of _messages_ whose boundaries matter, such as log lines shipped to a collector or entries
+//! in a message queue. A [`MessageStreamWriter`] writes them one at a time, and a reader gets
+//! back exactly the messages that were written, each as a whole:
+//!
+//! ```text
+//! "AMMS" | version | stream ID (16 bytes) | record | record | ... | end record
+//! ```
+//!
+//! Each record is a [`Session`] record, so it carries a sequence number the reader insists on:
+//! messages that are replayed, dropped or reordered are rejected. The session key is derived
+//! from the master secret and the random stream ID, so every stream has its own keys and
+//! records can't be moved between streams either. [`MessageStreamWriter::finish`] writes an
+//! end record, which is how the reader tells a complete stream from a truncated one.
+
+use std::io::{self, Read, Write};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    gcm::TAG_SIZE,
+    limits::{self, Limits},
+    session::{record_len, Role, Session, HEADER_SIZE as RECORD_HEADER_SIZE, MAX_RECORD_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+const MAGIC: &[u8; 4] = b"AMMS";
+/// The format version of the stream header.
+pub const VERSION: u8 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + BLOCK_SIZE;
+const STREAM_LABEL: &[u8] = b"aes-modes message stream";
+
+/// The largest message one record can carry, after the byte that marks the record type.
+pub const MAX_MESSAGE_SIZE: usize = MAX_RECORD_SIZE - 1;
+
+const MESSAGE: u8 = 0;
+const END: u8 = 1;
+
+fn session(master_secret: &[u8], stream_id: &[u8; BLOCK_SIZE], role: Role) -> Session {
+    let hkdf = Hkdf::<Sha256>::new(Some(stream_id), master_secret);
+    let mut secret = [0u8; 32];
+    hkdf.expand(STREAM_LABEL, &mut secret)
+        .expect("32 bytes is a valid output length");
+    Session::new(&secret, role)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes encrypted messages to an underlying writer.
+pub struct MessageStreamWriter<W: Write> {
+    inner: W,
+    session: Session,
+}
+
+impl<W: Write> MessageStreamWriter<W> {
+    /// Starts a new stream with a random ID, writing its header straight away.
+    pub fn new(master_secret: &[u8], mut inner: W) -> io::Result<Self> {
+        let stream_id = utils::create_rand_key();
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend_from_slice(&stream_id);
+        inner.write_all(&header)?;
+        Ok(MessageStreamWriter {
+            inner,
+            session: session(master_secret, &stream_id, Role::Initiator),
+        })
+    }
+
+    fn write_record(&mut self, kind: u8, message: &[u8]) -> io::Result<()> {
+        let mut plain_text = Vec::with_capacity(1 + message.len());
+        plain_text.push(kind);
+        plain_text.extend_from_slice(message);
+        let record = self.session.seal(plain_text);
+        self.inner.write_all(&record)
+    }
+
+    /// Encrypts and writes one message. Call `flush` to push it through buffered writers.
+    pub fn write_message(&mut self, message: &[u8]) -> io::Result<()> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message too large for one record",
+            ));
+        }
+        self.write_record(MESSAGE, message)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Writes the end record and hands back the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_record(END, &[])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads and checks the messages written by a [`MessageStreamWriter`].
+pub struct MessageStreamReader<R: Read> {
+    inner: R,
+    session: Session,
+    max_record_size: usize,
+    finished: bool,
+}
+
+impl<R: Read> MessageStreamReader<R> {
+    /// Reads the stream header, to read records within the default [`Limits`].
+    pub fn new(master_secret: &[u8], inner: R) -> io::Result<Self> {
+        Self::with_limits(master_secret, &Limits::default(), inner)
+    }
+
+    /// Like [`new`](Self::new), but a record larger than `limits` allow fails with
+    /// [`OutOfMemory`](io::ErrorKind::OutOfMemory) before any of it is read.
+    pub fn with_limits(master_secret: &[u8], limits: &Limits, mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != VERSION {
+            return Err(invalid_data("not a message stream"));
+        }
+        let stream_id = header[MAGIC.len() + 1..].try_into().unwrap();
+        Ok(MessageStreamReader {
+            inner,
+            session: session(master_secret, &stream_id, Role::Responder),
+            max_record_size: limits.max_chunk_size(),
+            finished: false,
+        })
+    }
+
+    /// The next message, or `None` after the end record. A stream that stops without an end
+    /// record fails with [`UnexpectedEof`](io::ErrorKind::UnexpectedEof), and one that was
+    /// tampered with, reordered or spliced fails with
+    /// [`InvalidData`](io::ErrorKind::InvalidData).
+    pub fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.inner.read_exact(&mut header)?;
+        let len = record_len(&header);
+        if len > RECORD_HEADER_SIZE + MAX_RECORD_SIZE + TAG_SIZE {
+            return Err(invalid_data("record too large"));
+        }
+        let mut record = limits::try_zeroed(len, self.max_record_size)?;
+        record[..RECORD_HEADER_SIZE].copy_from_slice(&header);
+        self.inner.read_exact(&mut record[RECORD_HEADER_SIZE..])?;
+
+        let mut plain_text = self
+            .session
+            .open(&record)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        match plain_text.first() {
+            Some(&MESSAGE) => Ok(Some(plain_text.split_off(1))),
+            Some(&END) if plain_text.len() == 1 => {
+                self.finished = true;
+                Ok(None)
+            }
+            _ => Err(invalid_data("unknown record type")),
+        }
+    }
+
+    /// Hands back the inner reader, positioned after the last record read.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for MessageStreamReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let message = self.read_message().transpose();
+        if matches!(message, Some(Err(_))) {
+            // Nothing after an error can be trusted.
+            self.finished = true;
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    fn write_stream(messages: &[&[u8]]) -> Vec<u8> {
+        let mut writer = MessageStreamWriter::new(SECRET, Vec::new()).unwrap();
+        for message in messages {
+            writer.write_message(message).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages: [&[u8]; 3] = [b"first line", b"", b"third line"];
+        let stream = write_stream(&messages);
+        let reader = MessageStreamReader::new(SECRET, stream.as_slice()).unwrap();
+        let read: Vec<Vec<u8>> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, messages);
+    }
+
+    #[test]
+    fn test_truncation_and_reordering() {
+        let stream = write_stream(&[b"one", b"two"]);
+        let record = RECORD_HEADER_SIZE + 1 + 3 + TAG_SIZE;
+
+        // Cut right before the end record: every message reads, but the stream is incomplete.
+        let truncated = &stream[..HEADER_SIZE + 2 * record];
+        let mut reader = MessageStreamReader::new(SECRET, truncated).unwrap();
+        assert_eq!(reader.read_message().unwrap(), Some(b"one".to_vec()));
+        assert_eq!(reader.read_message().unwrap(), Some(b"two".to_vec()));
+        let error = reader.read_message().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Swap the two messages.
+        let mut swapped = stream[..HEADER_SIZE].to_vec();
+        swapped.extend_from_slice(&stream[HEADER_SIZE + record..HEADER_SIZE + 2 * record]);
+        swapped.extend_from_slice(&stream[HEADER_SIZE..HEADER_SIZE + record]);
+        let mut reader = MessageStreamReader::new(SECRET, swapped.as_slice()).unwrap();
+        let error = reader.read_message().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_records_are_bound_to_their_stream() {
+        let first = write_stream(&[b"from the first stream"]);
+        let mut second = write_stream(&[b"from the other stream"]);
+        second.truncate(HEADER_SIZE);
+        second.extend_from_slice(&first[HEADER_SIZE..]);
+
+        let mut reader = MessageStreamReader::new(SECRET, second.as_slice()).unwrap();
+        assert_eq!(
+            reader.read_message().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
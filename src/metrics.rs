@@ -0,0 +1,195 @@
+//! A process-wide hook for counting what the crate encrypts and decrypts.
+//!
+//! Every [`Envelope::seal`](crate::envelope::Envelope::seal) and
+//! [`Envelope::open`](crate::envelope::Envelope::open) is reported to a [`MetricsSink`] as an
+//! [`Event`]: which way, which mode, which key, how many bytes, and how it failed if it did.
+//! A service can feed these to its metrics system and graph its crypto activity, or alert when
+//! something changes, such as a spike in [`Authentication`](EnvelopeError::Authentication)
+//! failures that suggests someone is tampering with ciphertexts or a key was mixed up.
+//!
+//! No sink is installed to begin with, so nothing is counted and no fingerprint is computed.
+//! [`set_metrics_sink`] installs one for the whole process: a [`KeyUsage`] to count in memory,
+//! or any closure taking an `&Event`.
+//!
+//! A key is identified by its [`KeyFingerprint`]: its ID and its
+//! [check value](crate::keys::check_value), so keys never appear in events. Envelopes with a
+//! [wrapped key](crate::envelope::seal_with_ephemeral_key) have a key of their own each, and
+//! are counted under their ID alone.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::{
+    analysis::KeyFingerprint,
+    envelope::{EnvelopeError, EnvelopeMode},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+/// One encryption or decryption.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub direction: Direction,
+    pub mode: EnvelopeMode,
+    pub key: KeyFingerprint,
+    /// The plaintext length, or zero if the decryption failed.
+    pub bytes: usize,
+    pub failure: Option<EnvelopeError>,
+}
+
+/// Receives every [`Event`]. Closures taking an `&Event` are sinks.
+pub trait MetricsSink: Send + Sync {
+    fn record(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> MetricsSink for F {
+    fn record(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// The counts for one key and mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsageStats {
+    pub encryptions: u64,
+    pub decryptions: u64,
+    pub bytes_encrypted: u64,
+    pub bytes_decrypted: u64,
+    /// Decryptions that failed for any reason, authentication included.
+    pub failures: u64,
+    pub authentication_failures: u64,
+}
+
+/// Counts events in memory, per key and mode. Clones share the counts, so one clone can be
+/// installed as the sink while another is read.
+#[derive(Clone, Debug, Default)]
+pub struct KeyUsage(Arc<Mutex<HashMap<(KeyFingerprint, EnvelopeMode), UsageStats>>>);
+
+impl KeyUsage {
+    /// The counts for `key` under `mode`, all zero if it wasn't used.
+    pub fn get(&self, key: &KeyFingerprint, mode: EnvelopeMode) -> UsageStats {
+        let counts = self.0.lock().unwrap_or_else(|error| error.into_inner());
+        counts
+            .get(&(key.clone(), mode))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// All the counts so far.
+    pub fn snapshot(&self) -> HashMap<(KeyFingerprint, EnvelopeMode), UsageStats> {
+        self.0
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .clone()
+    }
+}
+
+impl MetricsSink for KeyUsage {
+    fn record(&self, event: &Event) {
+        let mut counts = self.0.lock().unwrap_or_else(|error| error.into_inner());
+        let stats = counts.entry((event.key.clone(), event.mode)).or_default();
+        let bytes = event.bytes as u64;
+        match event.direction {
+            Direction::Encrypt => {
+                stats.encryptions += 1;
+                stats.bytes_encrypted += bytes;
+            }
+            Direction::Decrypt => {
+                stats.decryptions += 1;
+                stats.bytes_decrypted += bytes;
+            }
+        }
+        if let Some(failure) = event.failure {
+            stats.failures += 1;
+            if failure == EnvelopeError::Authentication {
+                stats.authentication_failures += 1;
+            }
+        }
+    }
+}
+
+static SINK: RwLock<Option<Box<dyn MetricsSink>>> = RwLock::new(None);
+
+/// Replaces the sink that receives every event, for the whole process.
+pub fn set_metrics_sink(sink: impl MetricsSink + 'static) {
+    *SINK.write().unwrap_or_else(|error| error.into_inner()) = Some(Box::new(sink));
+}
+
+/// Removes the sink, so nothing is counted again.
+pub fn clear_metrics_sink() {
+    *SINK.write().unwrap_or_else(|error| error.into_inner()) = None;
+}
+
+/// Reports the event `event` makes, which is only called if a sink is installed.
+pub(crate) fn record(event: impl FnOnce() -> Event) {
+    if let Some(sink) = &*SINK.read().unwrap_or_else(|error| error.into_inner()) {
+        sink.record(&event());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{envelope::Envelope, keys::check_value, BLOCK_SIZE};
+
+    const KEY: [u8; BLOCK_SIZE] = [9; BLOCK_SIZE];
+
+    #[test]
+    fn test_counts_envelopes() {
+        // Other tests seal envelopes in parallel, so only this key ID is looked at.
+        let usage = KeyUsage::default();
+        set_metrics_sink(usage.clone());
+        let key = KeyFingerprint {
+            key_id: 0x3e7a,
+            check_value: check_value(&KEY).to_vec(),
+        };
+
+        let envelope = Envelope::seal(EnvelopeMode::Gcm, key.key_id, KEY, b"counted".to_vec());
+        envelope.open(KEY).unwrap();
+        let mut tampered = envelope.clone();
+        tampered.chunks[0][0] ^= 1;
+        tampered.open(KEY).unwrap_err();
+        Envelope::seal(EnvelopeMode::Ctr, key.key_id, KEY, vec![0; 100]);
+        clear_metrics_sink();
+        envelope.open(KEY).unwrap();
+
+        let gcm = usage.get(&key, EnvelopeMode::Gcm);
+        assert_eq!(
+            gcm,
+            UsageStats {
+                encryptions: 1,
+                decryptions: 2,
+                bytes_encrypted: 7,
+                bytes_decrypted: 7,
+                failures: 1,
+                authentication_failures: 1,
+            }
+        );
+        assert_eq!(usage.get(&key, EnvelopeMode::Ctr).bytes_encrypted, 100);
+        assert_eq!(usage.get(&key, EnvelopeMode::Cbc), UsageStats::default());
+    }
+
+    #[test]
+    fn test_failures_other_than_authentication() {
+        let usage = KeyUsage::default();
+        let key = KeyFingerprint {
+            key_id: 1,
+            check_value: Vec::new(),
+        };
+        usage.record(&Event {
+            direction: Direction::Decrypt,
+            mode: EnvelopeMode::Cbc,
+            key: key.clone(),
+            bytes: 0,
+            failure: Some(EnvelopeError::WrongKey),
+        });
+        let stats = usage.snapshot()[&(key, EnvelopeMode::Cbc)];
+        assert_eq!((stats.failures, stats.authentication_failures), (1, 0));
+    }
+}
@@ -0,0 +1,263 @@
+//! Re-encrypting old data under a new key, in the current authenticated format.
+//!
+//! Before [envelopes](crate::envelope), what this crate wrote was the bare output of its
+//! crate-root functions: `IV | blocks` from [`cbc_encrypt`](crate::cbc_encrypt), and only the
+//! blocks from [`ecb_encrypt`](crate::ecb_encrypt). These "version 0" blobs record neither
+//! their mode nor their key, and nothing authenticates them. A [`Migrator`] decrypts them, and
+//! envelopes of any mode, with the old key and seals the plaintext into a GCM envelope under
+//! the new key, with the new key's [check value](crate::keys::check_value):
+//!
+//! - an envelope names its own mode, and is opened as any envelope is;
+//! - a bare blob is read as the [`LegacyMode`] the migrator is given, CBC unless told
+//!   otherwise, since its bytes can't say which mode wrote it;
+//! - a GCM envelope whose check value is already the new key's is left alone, so running a
+//!   migration twice does no harm.
+//!
+//! A bare blob's padding is checked strictly, which catches a wrong key or mode for all but
+//! about one blob in 256. Those few decrypt to garbage that the new envelope then
+//! authenticates, so old blobs should be migrated with the key and mode that wrote them, and
+//! spot-checked after. [`Migrator::migrate_files`] replaces each file
+//! [atomically](crate::files), or only reports what it would do.
+
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use aes::{cipher::KeyInit, Aes128};
+
+use crate::{
+    envelope::{self, open_with_ephemeral_key, Envelope, EnvelopeError, EnvelopeMode},
+    files::{write_atomically, Durability},
+    generic,
+    keys::check_value,
+    keywrap::KeyWrapError,
+    BLOCK_SIZE,
+};
+
+/// The mode a bare blob was written with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyMode {
+    Ecb,
+    Cbc,
+}
+
+/// What a blob was before it was migrated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// A bare blob, read as this mode.
+    Legacy(LegacyMode),
+    /// An envelope of this mode, under the old key.
+    Envelope(EnvelopeMode),
+}
+
+/// Why a blob couldn't be migrated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrateError {
+    /// A bare blob whose length or padding doesn't fit the mode: wrong key, wrong mode, or not
+    /// a ciphertext at all.
+    Malformed,
+    /// An envelope that doesn't open under the old key.
+    Envelope(EnvelopeError),
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrateError::Malformed => {
+                f.write_str("bad length or padding for the legacy mode and key")
+            }
+            MigrateError::Envelope(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for MigrateError {}
+
+impl From<EnvelopeError> for MigrateError {
+    fn from(error: EnvelopeError) -> Self {
+        MigrateError::Envelope(error)
+    }
+}
+
+/// A blob migrated by [`Migrator::migrate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Migration {
+    pub from: Source,
+    pub envelope: Envelope,
+}
+
+/// What [`Migrator::migrate_files`] did with each file, in the order given.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated: Vec<(PathBuf, Source)>,
+    /// Files already sealed under the new key.
+    pub current: Vec<PathBuf>,
+    /// Files that couldn't be read, migrated or written, and are unchanged.
+    pub failed: Vec<(PathBuf, io::Error)>,
+}
+
+impl MigrationReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Moves blobs from an old key to a new one. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct Migrator {
+    from_key: [u8; BLOCK_SIZE],
+    legacy_mode: LegacyMode,
+    key_id: u32,
+    to_key: [u8; BLOCK_SIZE],
+}
+
+impl Migrator {
+    /// Migrates from `from_key` to `to_key`, which the new envelopes name as `key_id`.
+    pub fn new(from_key: [u8; BLOCK_SIZE], key_id: u32, to_key: [u8; BLOCK_SIZE]) -> Self {
+        Migrator {
+            from_key,
+            legacy_mode: LegacyMode::Cbc,
+            key_id,
+            to_key,
+        }
+    }
+
+    /// Sets the mode bare blobs are read as.
+    pub fn with_legacy_mode(mut self, mode: LegacyMode) -> Self {
+        self.legacy_mode = mode;
+        self
+    }
+
+    /// Decrypts `blob` and seals it under the new key, or returns `None` if it already is.
+    pub fn migrate(&self, blob: &[u8]) -> Result<Option<Migration>, MigrateError> {
+        let (from, plain_text) = if blob.starts_with(envelope::MAGIC) {
+            let old = Envelope::from_bytes(blob)?;
+            if old.mode == EnvelopeMode::Gcm && old.check_value == check_value(&self.to_key) {
+                return Ok(None);
+            }
+            let plain_text = if old.wrapped_key.is_empty() {
+                old.open(self.from_key)?
+            } else {
+                open_with_ephemeral_key(&self.from_key, &old).map_err(|error| match error {
+                    KeyWrapError::Truncated => EnvelopeError::Malformed,
+                    KeyWrapError::Integrity => EnvelopeError::Authentication,
+                    KeyWrapError::Backend(never) => match never {},
+                })?
+            };
+            (Source::Envelope(old.mode), plain_text)
+        } else {
+            let cipher = Aes128::new(&self.from_key.into());
+            let plain_text = match self.legacy_mode {
+                LegacyMode::Ecb => generic::ecb_decrypt(&cipher, blob.to_vec()),
+                LegacyMode::Cbc => generic::cbc_decrypt(&cipher, blob.to_vec()),
+            }
+            .map_err(|_| MigrateError::Malformed)?;
+            (Source::Legacy(self.legacy_mode), plain_text)
+        };
+        let envelope = Envelope::seal(EnvelopeMode::Gcm, self.key_id, self.to_key, plain_text)
+            .with_check_value(&self.to_key);
+        Ok(Some(Migration { from, envelope }))
+    }
+
+    /// Migrates each file in place, or with `dry_run` only decrypts it and reports what would
+    /// be done.
+    pub fn migrate_files(&self, paths: &[PathBuf], dry_run: bool) -> MigrationReport {
+        let mut report = MigrationReport::default();
+        for path in paths {
+            match self.migrate_file(path, dry_run) {
+                Ok(Some(from)) => report.migrated.push((path.clone(), from)),
+                Ok(None) => report.current.push(path.clone()),
+                Err(error) => report.failed.push((path.clone(), error)),
+            }
+        }
+        report
+    }
+
+    fn migrate_file(&self, path: &Path, dry_run: bool) -> io::Result<Option<Source>> {
+        let migration = self
+            .migrate(&fs::read(path)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let Some(migration) = migration else {
+            return Ok(None);
+        };
+        if !dry_run {
+            write_atomically(path, Durability::SyncData, |file| {
+                file.write_all(&migration.envelope.to_bytes())
+            })?;
+        }
+        Ok(Some(migration.from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cbc_encrypt, ecb_encrypt};
+
+    const OLD: [u8; BLOCK_SIZE] = [1; BLOCK_SIZE];
+    const NEW: [u8; BLOCK_SIZE] = [2; BLOCK_SIZE];
+
+    #[test]
+    fn test_migrate() {
+        let message = b"written before envelopes".to_vec();
+        let migrator = Migrator::new(OLD, 7, NEW);
+
+        let cbc = cbc_encrypt(message.clone(), OLD);
+        let migration = migrator.migrate(&cbc).unwrap().unwrap();
+        assert_eq!(migration.from, Source::Legacy(LegacyMode::Cbc));
+        assert_eq!(migration.envelope.mode, EnvelopeMode::Gcm);
+        assert_eq!(migration.envelope.key_id, 7);
+        assert_eq!(migration.envelope.open(NEW).unwrap(), message);
+        assert_eq!(migrator.migrate(&migration.envelope.to_bytes()), Ok(None));
+
+        let ecb = ecb_encrypt(message.clone(), OLD);
+        let migration = migrator
+            .clone()
+            .with_legacy_mode(LegacyMode::Ecb)
+            .migrate(&ecb)
+            .unwrap()
+            .unwrap();
+        assert_eq!(migration.envelope.open(NEW).unwrap(), message);
+
+        let old = Envelope::seal(EnvelopeMode::Ctr, 1, OLD, message.clone());
+        let migration = migrator.migrate(&old.to_bytes()).unwrap().unwrap();
+        assert_eq!(migration.from, Source::Envelope(EnvelopeMode::Ctr));
+        assert_eq!(migration.envelope.open(NEW).unwrap(), message);
+
+        assert_eq!(migrator.migrate(&cbc[..20]), Err(MigrateError::Malformed));
+        let other = Envelope::seal(EnvelopeMode::Gcm, 1, NEW, message).with_check_value(&OLD);
+        assert_eq!(
+            migrator.migrate(&other.to_bytes()),
+            Err(MigrateError::Envelope(EnvelopeError::Authentication))
+        );
+    }
+
+    #[test]
+    fn test_migrate_files() {
+        let dir = std::env::temp_dir().join(format!("aes-modes-migrate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let legacy = dir.join("legacy.bin");
+        let broken = dir.join("broken.bin");
+        fs::write(&legacy, cbc_encrypt(b"old".to_vec(), OLD)).unwrap();
+        fs::write(&broken, b"short").unwrap();
+        let paths = [legacy.clone(), broken.clone()];
+        let migrator = Migrator::new(OLD, 1, NEW);
+
+        let report = migrator.migrate_files(&paths, true);
+        assert_eq!(report.migrated.len(), 1);
+        assert_eq!(report.failed[0].0, broken);
+        assert!(!fs::read(&legacy).unwrap().starts_with(envelope::MAGIC));
+
+        let report = migrator.migrate_files(&paths, false);
+        assert!(!report.is_complete());
+        let envelope = Envelope::from_bytes(&fs::read(&legacy).unwrap()).unwrap();
+        assert_eq!(envelope.open(NEW).unwrap(), b"old");
+        let report = migrator.migrate_files(&paths[..1], false);
+        assert_eq!(report.current, [legacy]);
+        assert!(report.is_complete());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Envelopes as `data:` URLs and MIME entities, for embedding them in web pages and mail.
+//!
+//! An envelope's media type is [`MEDIA_TYPE`] with its mode and format version as
+//! parameters, so a page or a proxy can tell what it holds without parsing it:
+//!
+//! ```text
+//! application/vnd.aes-modes.envelope; mode=gcm; version=5
+//! ```
+//!
+//! [`to_data_url`] puts the envelope in a `data:` URL (RFC 2397), which fits in an `href`, a
+//! `data-` attribute or a QR code, and [`to_mime`] in a MIME entity with `Content-Type` and
+//! `Content-Transfer-Encoding` headers for mail and multipart bodies. Both use standard base64,
+//! which is what browsers and mail readers decode, rather than the base64url used elsewhere in
+//! the crate. Applications with a media type of their own can pass it to the `_with_type`
+//! variants; the parameters are added to it either way, and checked against the envelope when
+//! it is parsed back.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    envelope::{Envelope, EnvelopeError, EnvelopeMode, VERSION},
+    utils,
+};
+
+/// The media type of an envelope, before its parameters.
+pub const MEDIA_TYPE: &str = "application/vnd.aes-modes.envelope";
+
+/// Why a data URL or MIME entity couldn't be parsed back into an envelope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MimeError {
+    /// It isn't a base64 `data:` URL, or a MIME entity with base64 content.
+    Syntax,
+    /// The media type isn't the expected one.
+    MediaType,
+    /// The `mode` or `version` parameter doesn't match the envelope.
+    Parameters,
+    Envelope(EnvelopeError),
+}
+
+impl fmt::Display for MimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MimeError::Syntax => f.write_str("not a base64 data URL or MIME entity"),
+            MimeError::MediaType => f.write_str("unexpected media type"),
+            MimeError::Parameters => f.write_str("media type parameters don't match the envelope"),
+            MimeError::Envelope(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for MimeError {}
+
+impl From<EnvelopeError> for MimeError {
+    fn from(error: EnvelopeError) -> Self {
+        MimeError::Envelope(error)
+    }
+}
+
+/// A media type with its parameters, as in `Content-Type`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaType {
+    /// The type and subtype, in lowercase.
+    pub essence: String,
+    /// The parameters in order, with lowercase names.
+    pub parameters: Vec<(String, String)>,
+}
+
+impl MediaType {
+    /// The media type of `envelope` under `essence`.
+    pub fn of(envelope: &Envelope, essence: &str) -> Self {
+        MediaType {
+            essence: essence.to_ascii_lowercase(),
+            parameters: vec![
+                ("mode".to_string(), envelope.mode.name().to_string()),
+                ("version".to_string(), VERSION.to_string()),
+            ],
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.split(';').map(str::trim);
+        let essence = parts.next()?.to_ascii_lowercase();
+        if essence.split('/').count() != 2 || essence.contains(char::is_whitespace) {
+            return None;
+        }
+        let parameters = parts
+            .map(|parameter| {
+                let (name, value) = parameter.split_once('=')?;
+                let value = value.trim().trim_matches('"');
+                Some((name.trim().to_ascii_lowercase(), value.to_string()))
+            })
+            .collect::<Option<_>>()?;
+        Some(MediaType {
+            essence,
+            parameters,
+        })
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the `mode` and `version` parameters, where present, describe `envelope`. The
+    /// version can only be checked as no newer than this build writes, since older versions
+    /// are read into the same [`Envelope`].
+    fn describes(&self, envelope: &Envelope) -> bool {
+        let mode_ok = self
+            .parameter("mode")
+            .is_none_or(|mode| EnvelopeMode::from_name(mode) == Some(envelope.mode));
+        let version_ok = self.parameter("version").is_none_or(|version| {
+            version
+                .parse::<u8>()
+                .is_ok_and(|version| version <= VERSION)
+        });
+        mode_ok && version_ok
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.essence)?;
+        for (name, value) in &self.parameters {
+            write!(f, "; {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Standard padded base64, from the crate's base64url.
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded: String = utils::base64url_encode(data)
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    while !encoded.len().is_multiple_of(4) {
+        encoded.push('=');
+    }
+    encoded
+}
+
+/// Standard base64, padded or not, ignoring whitespace.
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut url_safe = String::with_capacity(encoded.len());
+    for c in encoded.chars().filter(|c| !c.is_ascii_whitespace()) {
+        url_safe.push(match c {
+            '+' => '-',
+            '/' => '_',
+            '-' | '_' => return None,
+            c => c,
+        });
+    }
+    utils::base64url_decode(url_safe.trim_end_matches('='))
+}
+
+fn parse_envelope(
+    media_type: &MediaType,
+    essence: &str,
+    encoded: &str,
+) -> Result<Envelope, MimeError> {
+    if !media_type.essence.eq_ignore_ascii_case(essence) {
+        return Err(MimeError::MediaType);
+    }
+    let envelope = Envelope::from_bytes(&base64_decode(encoded).ok_or(MimeError::Syntax)?)?;
+    if !media_type.describes(&envelope) {
+        return Err(MimeError::Parameters);
+    }
+    Ok(envelope)
+}
+
+/// `envelope` as a `data:` URL of type [`MEDIA_TYPE`].
+pub fn to_data_url(envelope: &Envelope) -> String {
+    to_data_url_with_type(envelope, MEDIA_TYPE)
+}
+
+/// `envelope` as a `data:` URL of type `essence`, with the mode and version parameters.
+pub fn to_data_url_with_type(envelope: &Envelope, essence: &str) -> String {
+    let media_type = MediaType::of(envelope, essence)
+        .to_string()
+        .replace(' ', "");
+    format!(
+        "data:{};base64,{}",
+        media_type,
+        base64_encode(&envelope.to_bytes())
+    )
+}
+
+pub fn from_data_url(url: &str) -> Result<Envelope, MimeError> {
+    from_data_url_with_type(url, MEDIA_TYPE)
+}
+
+/// Parses a `data:` URL, which must be of type `essence` and base64.
+pub fn from_data_url_with_type(url: &str, essence: &str) -> Result<Envelope, MimeError> {
+    let rest = url
+        .get(..5)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("data:"))
+        .map(|_| &url[5..])
+        .ok_or(MimeError::Syntax)?;
+    let (header, encoded) = rest.split_once(',').ok_or(MimeError::Syntax)?;
+    let header = header.strip_suffix(";base64").ok_or(MimeError::Syntax)?;
+    let media_type = MediaType::parse(header).ok_or(MimeError::Syntax)?;
+    parse_envelope(&media_type, essence, encoded)
+}
+
+/// `envelope` as a MIME entity of type [`MEDIA_TYPE`]: headers, a blank line, and the base64
+/// content in lines of 76 characters, all with CRLF line endings.
+pub fn to_mime(envelope: &Envelope) -> String {
+    to_mime_with_type(envelope, MEDIA_TYPE)
+}
+
+pub fn to_mime_with_type(envelope: &Envelope, essence: &str) -> String {
+    let mut entity = format!(
+        "Content-Type: {}\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+        MediaType::of(envelope, essence)
+    );
+    let encoded = base64_encode(&envelope.to_bytes());
+    for line in encoded.as_bytes().chunks(76) {
+        entity.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        entity.push_str("\r\n");
+    }
+    entity
+}
+
+pub fn from_mime(entity: &str) -> Result<Envelope, MimeError> {
+    from_mime_with_type(entity, MEDIA_TYPE)
+}
+
+/// Parses a MIME entity of type `essence` with base64 content. Header names are matched
+/// without regard to case, and either line ending is accepted.
+pub fn from_mime_with_type(entity: &str, essence: &str) -> Result<Envelope, MimeError> {
+    let (headers, body) = entity
+        .split_once("\r\n\r\n")
+        .or_else(|| entity.split_once("\n\n"))
+        .ok_or(MimeError::Syntax)?;
+    let mut media_type = None;
+    let mut base64 = false;
+    for line in headers.lines() {
+        let (name, value) = line.split_once(':').ok_or(MimeError::Syntax)?;
+        if name.trim().eq_ignore_ascii_case("content-type") {
+            media_type = Some(MediaType::parse(value.trim()).ok_or(MimeError::Syntax)?);
+        } else if name
+            .trim()
+            .eq_ignore_ascii_case("content-transfer-encoding")
+        {
+            base64 = value.trim().eq_ignore_ascii_case("base64");
+        }
+    }
+    match media_type {
+        Some(media_type) if base64 => parse_envelope(&media_type, essence, body),
+        _ => Err(MimeError::Syntax),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BLOCK_SIZE;
+
+    const KEY: [u8; BLOCK_SIZE] = [9; BLOCK_SIZE];
+
+    #[test]
+    fn test_data_url() {
+        let envelope = Envelope::seal(EnvelopeMode::Gcm, 3, KEY, b"in an attribute".to_vec());
+        let url = to_data_url(&envelope);
+        assert!(url.starts_with(&format!(
+            "data:application/vnd.aes-modes.envelope;mode=gcm;version={};base64,",
+            VERSION
+        )));
+        assert_eq!(from_data_url(&url), Ok(envelope.clone()));
+
+        let custom = to_data_url_with_type(&envelope, "application/x-example");
+        assert_eq!(from_data_url(&custom), Err(MimeError::MediaType));
+        assert_eq!(
+            from_data_url_with_type(&custom, "application/x-example"),
+            Ok(envelope.clone())
+        );
+
+        let relabelled = url.replace("mode=gcm", "mode=ctr");
+        assert_eq!(from_data_url(&relabelled), Err(MimeError::Parameters));
+        let unencoded = url.replace(";base64", "");
+        assert_eq!(from_data_url(&unencoded), Err(MimeError::Syntax));
+        assert_eq!(from_data_url("https://example.com"), Err(MimeError::Syntax));
+    }
+
+    #[test]
+    fn test_mime() {
+        let envelope = Envelope::seal(EnvelopeMode::Cbc, 1, KEY, vec![7; 200]);
+        let entity = to_mime(&envelope);
+        assert!(entity.starts_with(&format!(
+            "Content-Type: application/vnd.aes-modes.envelope; mode=cbc; version={}\r\n",
+            VERSION
+        )));
+        assert!(entity.lines().all(|line| line.len() <= 76));
+        assert_eq!(from_mime(&entity), Ok(envelope.clone()));
+
+        let lowercase = entity
+            .replace("Content-Type", "content-type")
+            .replace("\r\n", "\n");
+        assert_eq!(from_mime(&lowercase), Ok(envelope));
+        let quoted_printable = entity.replace("base64", "quoted-printable");
+        assert_eq!(from_mime(&quoted_printable), Err(MimeError::Syntax));
+    }
+}
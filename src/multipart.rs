@@ -0,0 +1,253 @@
+//! [Chunked](crate::chunked) streams laid out for multipart uploads.
+//!
+//! S3 and the stores compatible with it upload a large object in parts of a few MiB, each sent,
+//! retried and checksummed on its own. A stream whose chunks straddle the part boundaries makes
+//! a part meaningless by itself, so
+//! [`StreamOptions::with_part_size`](crate::chunked::StreamOptions::with_part_size) picks the
+//! chunk size for every part to hold a whole number of encrypted chunks:
+//!
+//! ```text
+//! part 0:   header | chunk 0 | ... | chunk k-1
+//! part 1:   chunk k | ... | chunk 2k-1
+//! ...
+//! last:     the remaining chunks, ending with the final one
+//! ```
+//!
+//! The first part is longer than the part size by the [`HEADER_SIZE`] bytes of the header, and
+//! the last is shorter; S3 only requires parts other than the last to be at least 5 MiB. The
+//! stream is an ordinary chunked stream, read as any other.
+//!
+//! A [`PartWriter`] takes the stream as [`StreamEncryptor`](crate::chunked::StreamEncryptor)
+//! writes it, and hands over each part as soon as it is complete, so the object is uploaded
+//! while the rest of it is still being encrypted. [`PartWriter::finish`] returns a [`Part`] for
+//! each: its byte range in the object, and an HMAC-SHA256 over its index and bytes under a key
+//! derived from the master secret and the stream ID. [`verify_part`] checks one part,
+//! downloaded on its own with a ranged GET say, without reading the rest of the object.
+
+use std::{
+    io::{self, Write},
+    ops::Range,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::chunked::{ChunkKeys, StreamHeader, DEFAULT_CHUNK_SIZE, HEADER_SIZE};
+
+/// The length of a part's MAC.
+pub const MAC_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The largest chunk size, up to [`DEFAULT_CHUNK_SIZE`], that fits a whole number of encrypted
+/// chunks with `tag_len`-byte tags into a part of `part_size` bytes, if there is one.
+pub fn chunk_size_for_part(part_size: u64, tag_len: u8) -> Option<u32> {
+    let tag_len = u64::from(tag_len);
+    if part_size == 0 {
+        return None;
+    }
+    (tag_len + 1..=u64::from(DEFAULT_CHUNK_SIZE) + tag_len)
+        .rev()
+        .find(|&encrypted| part_size.is_multiple_of(encrypted))
+        .map(|encrypted| (encrypted - tag_len) as u32)
+}
+
+/// The bytes of part `index` in a stream of `stream_len` bytes, or `None` past the end.
+pub fn part_range(part_size: u64, index: u64, stream_len: u64) -> Option<Range<u64>> {
+    let boundary = |index: u64| {
+        index
+            .checked_mul(part_size)
+            .and_then(|offset| offset.checked_add(HEADER_SIZE as u64))
+    };
+    let start = if index == 0 { 0 } else { boundary(index)? };
+    let end = boundary(index + 1).map_or(stream_len, |end| end.min(stream_len));
+    (start < end).then_some(start..end)
+}
+
+/// One part of an uploaded stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub index: u64,
+    /// Where the part is in the whole stream.
+    pub range: Range<u64>,
+    pub mac: [u8; MAC_SIZE],
+}
+
+fn part_mac(key: &[u8], index: u64, bytes: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key size");
+    mac.update(&index.to_be_bytes());
+    mac.update(bytes);
+    mac
+}
+
+/// Cuts the stream written to it into parts, and calls `on_part` with each one.
+pub struct PartWriter<F: FnMut(&Part, &[u8]) -> io::Result<()>> {
+    mac_key: [u8; 32],
+    part_size: u64,
+    on_part: F,
+    buffer: Vec<u8>,
+    offset: u64,
+    parts: Vec<Part>,
+}
+
+impl<F: FnMut(&Part, &[u8]) -> io::Result<()>> PartWriter<F> {
+    /// Takes the stream with `header`, encrypted under `master_secret`.
+    ///
+    /// # Panics
+    ///
+    /// If a part of `part_size` bytes doesn't hold a whole number of the header's encrypted
+    /// chunks. [`StreamOptions::with_part_size`](crate::chunked::StreamOptions::with_part_size)
+    /// makes headers that fit.
+    pub fn new(master_secret: &[u8], header: &StreamHeader, part_size: u64, on_part: F) -> Self {
+        let encrypted = u64::from(header.chunk_size) + u64::from(header.tag_len);
+        assert!(
+            part_size > 0 && part_size.is_multiple_of(encrypted),
+            "parts of {} bytes don't hold whole encrypted chunks of {} bytes",
+            part_size,
+            encrypted
+        );
+        PartWriter {
+            mac_key: ChunkKeys::new(master_secret, *header).part_key(),
+            part_size,
+            on_part,
+            buffer: Vec::new(),
+            offset: 0,
+            parts: Vec::new(),
+        }
+    }
+
+    fn current_part_len(&self) -> usize {
+        let header = if self.parts.is_empty() {
+            HEADER_SIZE
+        } else {
+            0
+        };
+        header + self.part_size as usize
+    }
+
+    fn emit_part(&mut self) -> io::Result<()> {
+        let index = self.parts.len() as u64;
+        let end = self.offset + self.buffer.len() as u64;
+        let part = Part {
+            index,
+            range: self.offset..end,
+            mac: part_mac(&self.mac_key, index, &self.buffer)
+                .finalize()
+                .into_bytes()
+                .into(),
+        };
+        (self.on_part)(&part, &self.buffer)?;
+        self.offset = end;
+        self.buffer.clear();
+        self.parts.push(part);
+        Ok(())
+    }
+
+    /// Hands over the last part, and returns every part. Call it once the
+    /// [`StreamEncryptor`](crate::chunked::StreamEncryptor) has finished.
+    pub fn finish(mut self) -> io::Result<Vec<Part>> {
+        if !self.buffer.is_empty() {
+            self.emit_part()?;
+        }
+        Ok(self.parts)
+    }
+}
+
+impl<F: FnMut(&Part, &[u8]) -> io::Result<()>> Write for PartWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.current_part_len() - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.current_part_len() {
+            self.emit_part()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `bytes` are the part `part` describes, of the stream with `header` encrypted under
+/// `master_secret`.
+pub fn verify_part(master_secret: &[u8], header: &StreamHeader, part: &Part, bytes: &[u8]) -> bool {
+    let key = ChunkKeys::new(master_secret, *header).part_key();
+    bytes.len() as u64 == part.range.end - part.range.start
+        && part_mac(&key, part.index, bytes)
+            .verify_slice(&part.mac)
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunked::{StreamDecryptor, StreamEncryptor, StreamOptions, StreamOptionsError};
+    use std::io::Read;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    #[test]
+    fn test_chunk_size_for_part() {
+        assert_eq!(
+            chunk_size_for_part(8 << 20, 16),
+            Some(DEFAULT_CHUNK_SIZE - 16)
+        );
+        assert_eq!(chunk_size_for_part(5_000_000, 16), Some(62_500 - 16));
+        assert_eq!(chunk_size_for_part(0, 16), None);
+        assert_eq!(
+            StreamOptions::new().with_part_size(7).to_header(),
+            Err(StreamOptionsError::PartSize(7))
+        );
+    }
+
+    #[test]
+    fn test_parts() {
+        let part_size = 256 * 1024;
+        let header = StreamOptions::new()
+            .with_part_size(part_size)
+            .to_header()
+            .unwrap();
+        let data = vec![9u8; 1_000_000];
+        let mut uploaded = Vec::new();
+        let writer = PartWriter::new(SECRET, &header, part_size, |part, bytes| {
+            uploaded.push((part.clone(), bytes.to_vec()));
+            Ok(())
+        });
+        let mut encryptor = StreamEncryptor::with_header(SECRET, header, writer).unwrap();
+        encryptor.write_all(&data).unwrap();
+        let parts = encryptor.finish().unwrap().finish().unwrap();
+
+        let stream: Vec<u8> = uploaded
+            .iter()
+            .flat_map(|(_, bytes)| bytes.clone())
+            .collect();
+        let mut decrypted = Vec::new();
+        StreamDecryptor::new(SECRET, stream.as_slice())
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, data);
+
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0].range, 0..HEADER_SIZE as u64 + part_size);
+        for (part, bytes) in &uploaded {
+            assert_eq!(
+                part_range(part_size, part.index, stream.len() as u64),
+                Some(part.range.clone())
+            );
+            assert_eq!(
+                &stream[part.range.start as usize..part.range.end as usize],
+                bytes
+            );
+            assert!(verify_part(SECRET, &header, part, bytes));
+        }
+        assert_eq!(part_range(part_size, 4, stream.len() as u64), None);
+
+        let (part, bytes) = &uploaded[2];
+        let mut tampered = bytes.clone();
+        tampered[100] ^= 1;
+        assert!(!verify_part(SECRET, &header, part, &tampered));
+        assert!(!verify_part(SECRET, &header, &uploaded[1].0, bytes));
+        assert!(!verify_part(b"another secret", &header, part, bytes));
+    }
+}
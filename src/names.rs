@@ -0,0 +1,268 @@
+//! Encrypting file names and metadata, for encrypted directory trees.
+//!
+//! Encrypting the contents of every file still leaves a lot in plain sight: the names, the
+//! sizes, the modification times. A [`DirectoryCipher`] hides those too.
+//!
+//! - Names are encrypted with [`siv`](crate::siv), which is deterministic. The same name in the
+//!   same directory always encrypts to the same string, so a file can still be opened by name.
+//!   The directory's ID is authenticated along with the name, so equal names in different
+//!   directories don't look equal, and an encrypted name can't be moved to another directory.
+//! - Names are padded to a multiple of 16 bytes before encryption, which hides their exact
+//!   length. The result is encoded as base64url so it is a valid file name everywhere.
+//! - Metadata (size, modification time, mode) is sealed with GCM under a random nonce and bound
+//!   to the encrypted name, so it can't be swapped between files.
+//!
+//! Keeping the extension in the clear (`report.pdf` becomes `<encrypted>.pdf`) helps tools that
+//! go by extension, at the cost of revealing it. It is off by default.
+//!
+//! Most file systems limit names to 255 bytes. With padding, the SIV and base64, that leaves
+//! room for plaintext names of up to about 175 bytes.
+
+use std::{error::Error, fmt};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE},
+    pad,
+    siv::{siv_decrypt, siv_encrypt, SIV_KEY_SIZE},
+    un_pad, utils, BLOCK_SIZE,
+};
+
+/// Size, modification time and mode, each as a big-endian integer.
+pub const METADATA_SIZE: usize = 8 + 8 + 4;
+
+const NAME_KEY_LABEL: &[u8] = b"aes-modes filename key";
+const METADATA_KEY_LABEL: &[u8] = b"aes-modes metadata key";
+
+/// Why an encrypted name or metadata record could not be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameError {
+    /// The input isn't valid base64url, or isn't the output of this cipher at all.
+    Malformed,
+    /// The name or metadata was modified, or belongs to another directory or file.
+    Authentication,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Malformed => f.write_str("malformed encrypted name"),
+            NameError::Authentication => f.write_str("encrypted name failed authentication"),
+        }
+    }
+}
+
+impl Error for NameError {}
+
+/// What a directory listing would otherwise reveal about a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub modified: u64,
+    /// The Unix permission bits.
+    pub mode: u32,
+}
+
+/// Encrypts names and metadata for a whole tree, under keys derived from one master secret.
+pub struct DirectoryCipher {
+    name_key: [u8; SIV_KEY_SIZE],
+    metadata_key: [u8; BLOCK_SIZE],
+    preserve_extensions: bool,
+}
+
+impl DirectoryCipher {
+    pub fn new(master_secret: &[u8]) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, master_secret);
+        let mut name_key = [0u8; SIV_KEY_SIZE];
+        let mut metadata_key = [0u8; BLOCK_SIZE];
+        hkdf.expand(NAME_KEY_LABEL, &mut name_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        hkdf.expand(METADATA_KEY_LABEL, &mut metadata_key)
+            .expect("16 bytes is a valid HKDF-SHA256 output length");
+
+        DirectoryCipher {
+            name_key,
+            metadata_key,
+            preserve_extensions: false,
+        }
+    }
+
+    /// Leaves the extension of each name unencrypted.
+    pub fn with_preserved_extensions(mut self) -> Self {
+        self.preserve_extensions = true;
+        self
+    }
+
+    /// Splits off the extension, if extensions are preserved and the name has one. A leading
+    /// dot (as in `.bashrc`) doesn't count.
+    fn split_extension<'a>(&self, name: &'a str) -> (&'a str, Option<&'a str>) {
+        match name.rfind('.') {
+            Some(dot) if self.preserve_extensions && dot > 0 => {
+                (&name[..dot], Some(&name[dot + 1..]))
+            }
+            _ => (name, None),
+        }
+    }
+
+    /// Encrypts `name`, a single path component, for the directory with the given ID.
+    pub fn encrypt_name(&self, directory_id: &[u8], name: &str) -> String {
+        let (stem, extension) = self.split_extension(name);
+        let padded = pad(stem.as_bytes().to_vec());
+        let encrypted = siv_encrypt(&padded, &self.name_key, &[directory_id]);
+
+        let mut encrypted_name = utils::base64url_encode(&encrypted);
+        if let Some(extension) = extension {
+            encrypted_name.push('.');
+            encrypted_name.push_str(extension);
+        }
+        encrypted_name
+    }
+
+    pub fn decrypt_name(
+        &self,
+        directory_id: &[u8],
+        encrypted_name: &str,
+    ) -> Result<String, NameError> {
+        let (stem, extension) = self.split_extension(encrypted_name);
+        let encrypted = utils::base64url_decode(stem).ok_or(NameError::Malformed)?;
+        if encrypted.len() < 2 * BLOCK_SIZE || encrypted.len() % BLOCK_SIZE != 0 {
+            return Err(NameError::Malformed);
+        }
+
+        let padded = siv_decrypt(&encrypted, &self.name_key, &[directory_id])
+            .map_err(|_| NameError::Authentication)?;
+        let mut name = String::from_utf8(un_pad(padded)).map_err(|_| NameError::Malformed)?;
+        if let Some(extension) = extension {
+            name.push('.');
+            name.push_str(extension);
+        }
+        Ok(name)
+    }
+
+    /// Seals the metadata of the file stored under `encrypted_name`.
+    pub fn encrypt_metadata(&self, encrypted_name: &str, metadata: &FileMetadata) -> Vec<u8> {
+        let mut plain_text = Vec::with_capacity(METADATA_SIZE);
+        plain_text.extend_from_slice(&metadata.size.to_be_bytes());
+        plain_text.extend_from_slice(&metadata.modified.to_be_bytes());
+        plain_text.extend_from_slice(&metadata.mode.to_be_bytes());
+
+        let nonce = utils::create_rand_gcm_nonce();
+        let mut output = nonce.to_vec();
+        output.extend(gcm_encrypt(
+            plain_text,
+            self.metadata_key,
+            nonce,
+            encrypted_name.as_bytes(),
+        ));
+        output
+    }
+
+    pub fn decrypt_metadata(
+        &self,
+        encrypted_name: &str,
+        sealed: &[u8],
+    ) -> Result<FileMetadata, NameError> {
+        if sealed.len() < GCM_NONCE_SIZE {
+            return Err(NameError::Malformed);
+        }
+        let (nonce, cipher_text) = sealed.split_at(GCM_NONCE_SIZE);
+        let plain_text = gcm_decrypt(
+            cipher_text.to_vec(),
+            self.metadata_key,
+            nonce.try_into().unwrap(),
+            encrypted_name.as_bytes(),
+        )
+        .map_err(|_| NameError::Authentication)?;
+        if plain_text.len() != METADATA_SIZE {
+            return Err(NameError::Malformed);
+        }
+
+        Ok(FileMetadata {
+            size: u64::from_be_bytes(plain_text[..8].try_into().unwrap()),
+            modified: u64::from_be_bytes(plain_text[8..16].try_into().unwrap()),
+            mode: u32::from_be_bytes(plain_text[16..].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"master secret for the tests";
+
+    #[test]
+    fn test_names_round_trip_deterministically() {
+        let cipher = DirectoryCipher::new(SECRET);
+        for name in ["a", "report.pdf", ".bashrc", "exactly sixteen!", "ünïcödé"] {
+            let encrypted = cipher.encrypt_name(b"root", name);
+            assert!(encrypted
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+            assert_eq!(encrypted, cipher.encrypt_name(b"root", name));
+            assert_eq!(
+                cipher.decrypt_name(b"root", &encrypted),
+                Ok(name.to_string())
+            );
+        }
+
+        // Padding hides the exact length.
+        assert_eq!(
+            cipher.encrypt_name(b"root", "a").len(),
+            cipher.encrypt_name(b"root", "fifteen letters").len()
+        );
+        assert_ne!(
+            cipher.encrypt_name(b"root", "notes.txt"),
+            cipher.encrypt_name(b"home", "notes.txt")
+        );
+    }
+
+    #[test]
+    fn test_preserved_extensions() {
+        let cipher = DirectoryCipher::new(SECRET).with_preserved_extensions();
+        let encrypted = cipher.encrypt_name(b"root", "archive.tar.gz");
+        assert!(encrypted.ends_with(".gz"));
+        assert_eq!(
+            cipher.decrypt_name(b"root", &encrypted),
+            Ok("archive.tar.gz".to_string())
+        );
+
+        let hidden = cipher.encrypt_name(b"root", ".profile");
+        assert!(!hidden.contains('.'));
+        assert_eq!(
+            cipher.decrypt_name(b"root", &hidden),
+            Ok(".profile".to_string())
+        );
+    }
+
+    #[test]
+    fn test_names_and_metadata_are_authenticated() {
+        let cipher = DirectoryCipher::new(SECRET);
+        let name = cipher.encrypt_name(b"root", "secret plans");
+        assert_eq!(
+            cipher.decrypt_name(b"home", &name),
+            Err(NameError::Authentication)
+        );
+        assert_eq!(
+            cipher.decrypt_name(b"root", "not base64!"),
+            Err(NameError::Malformed)
+        );
+
+        let metadata = FileMetadata {
+            size: 1234,
+            modified: 1_700_000_000,
+            mode: 0o644,
+        };
+        let sealed = cipher.encrypt_metadata(&name, &metadata);
+        assert_eq!(cipher.decrypt_metadata(&name, &sealed), Ok(metadata));
+
+        let other = cipher.encrypt_name(b"root", "shopping list");
+        assert_eq!(
+            cipher.decrypt_metadata(&other, &sealed),
+            Err(NameError::Authentication)
+        );
+    }
+}
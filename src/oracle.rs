@@ -0,0 +1,219 @@
+//! Deliberately vulnerable oracles over HTTP, for practising attacks against a real service.
+//!
+//! The attacks on ECB and unauthenticated CBC need no key, only a service that answers one
+//! question too many. An [`OracleServer`] is such a service, on a local TCP port, speaking the
+//! same minimal HTTP as the [key server](crate::key_server):
+//!
+//! | Request                  | Body                  | Response                                 |
+//! |--------------------------|-----------------------|------------------------------------------|
+//! | `POST /ecb`              | any bytes             | ECB of the body followed by the secret   |
+//! | `GET /padding/challenge` | empty                 | `IV \| ciphertext` of the secret, in CBC |
+//! | `POST /padding`          | `IV \| ciphertext`    | 200 if its padding is valid, 400 if not  |
+//! | `POST /check`            | a guess of the secret | 200 if it is right, 403 if not           |
+//!
+//! `/ecb` gives the byte-at-a-time ECB decryption, and `/padding` the CBC padding oracle.
+//! Real services rarely say "bad padding" so plainly, and leak it through their timing
+//! instead: [`OracleServer::with_delay`] makes valid padding take longer to answer, and
+//! [`OracleServer::with_timing_only`] answers 200 either way, leaving only the delay.
+//!
+//! Everything, the keys, the secret and the challenge's IV, is derived from a seed, so a
+//! class gets the same exercise on every machine and students can compare their answers. None
+//! of this is for anything but exercises, which is why it sits behind the `oracle-server`
+//! feature.
+
+use std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use aes::{cipher::KeyInit, Aes128};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    gcm::constant_time_eq,
+    generic,
+    key_server::{read_request, write_response, Request, Response},
+    BLOCK_SIZE,
+};
+
+/// The secrets a seed picks from. Readable, so students know when they are done.
+const SECRETS: [&str; 4] = [
+    "A mode is only as strong as what it refuses to tell you.",
+    "ECB leaks patterns; CBC without a MAC leaks everything else.",
+    "The padding was valid. That was all the attacker needed to know.",
+    "Authenticate, then decrypt. Never the other way around.",
+];
+
+/// A bundle of vulnerable oracles. See the [module documentation](self).
+pub struct OracleServer {
+    ecb: Aes128,
+    cbc: Aes128,
+    iv: [u8; BLOCK_SIZE],
+    secret: Vec<u8>,
+    delay: Duration,
+    timing_only: bool,
+}
+
+fn derive(seed: &[u8], label: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(label);
+    hasher.finalize().into()
+}
+
+fn response(status: u16, body: impl Into<Vec<u8>>) -> Response {
+    Response {
+        status,
+        body: body.into(),
+    }
+}
+
+impl OracleServer {
+    /// Oracles whose keys and secret are derived from `seed`, answering without delay.
+    pub fn new(seed: &[u8]) -> Self {
+        let key = |label| Aes128::new_from_slice(&derive(seed, label)[..BLOCK_SIZE]).unwrap();
+        let mut iv = [0u8; BLOCK_SIZE];
+        iv.copy_from_slice(&derive(seed, "iv")[..BLOCK_SIZE]);
+        let secret = SECRETS[derive(seed, "secret")[0] as usize % SECRETS.len()];
+        OracleServer {
+            ecb: key("ecb key"),
+            cbc: key("cbc key"),
+            iv,
+            secret: secret.as_bytes().to_vec(),
+            delay: Duration::ZERO,
+            timing_only: false,
+        }
+    }
+
+    /// Makes `/padding` take `delay` longer when the padding is valid.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Makes `/padding` answer 200 whatever the padding, so only the delay tells.
+    pub fn with_timing_only(mut self) -> Self {
+        self.timing_only = true;
+        self
+    }
+
+    /// The secret the exercises recover, for whoever runs the class.
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// Answers one request.
+    pub fn handle(&self, request: &Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/ecb") => {
+                let mut plain_text = request.body.clone();
+                plain_text.extend_from_slice(&self.secret);
+                response(200, generic::ecb_encrypt(&self.ecb, plain_text))
+            }
+            ("GET", "/padding/challenge") => response(
+                200,
+                generic::cbc_encrypt_with_iv(&self.cbc, &self.iv, self.secret.clone()),
+            ),
+            ("POST", "/padding") => {
+                let valid = generic::cbc_decrypt(&self.cbc, request.body.clone()).is_ok();
+                if valid {
+                    thread::sleep(self.delay);
+                }
+                if valid || self.timing_only {
+                    response(200, "ok")
+                } else {
+                    response(400, "invalid padding")
+                }
+            }
+            ("POST", "/check") => {
+                if constant_time_eq(&request.body, &self.secret) {
+                    response(200, "correct")
+                } else {
+                    response(403, "wrong")
+                }
+            }
+            _ => response(404, "not found"),
+        }
+    }
+
+    /// Accepts connections forever, answering each on its own thread.
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || server.serve_connection(stream));
+        }
+        Ok(())
+    }
+
+    /// Answers requests on one connection until the client closes it or sends garbage.
+    fn serve_connection(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        while let Some(request) = read_request(&mut reader)? {
+            write_response(&mut writer, &self.handle(&request))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &[u8]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            token: None,
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_oracles() {
+        let server = OracleServer::new(b"class of 2026");
+        let challenge = server.handle(&request("GET", "/padding/challenge", b""));
+        assert_eq!(
+            challenge,
+            OracleServer::new(b"class of 2026").handle(&request("GET", "/padding/challenge", b""))
+        );
+        assert_eq!(
+            server
+                .handle(&request("POST", "/padding", &challenge.body))
+                .status,
+            200
+        );
+        let mut tampered = challenge.body.clone();
+        let last = tampered.len() - BLOCK_SIZE - 1;
+        tampered[last] ^= 0x80;
+        assert_eq!(
+            server
+                .handle(&request("POST", "/padding", &tampered))
+                .status,
+            400
+        );
+        let timing_only = OracleServer::new(b"class of 2026").with_timing_only();
+        assert_eq!(
+            timing_only
+                .handle(&request("POST", "/padding", &tampered))
+                .status,
+            200
+        );
+
+        // Two equal blocks of chosen plaintext come back as two equal blocks.
+        let ecb = server.handle(&request("POST", "/ecb", &[b'A'; 2 * BLOCK_SIZE]));
+        assert_eq!(ecb.body[..BLOCK_SIZE], ecb.body[BLOCK_SIZE..2 * BLOCK_SIZE]);
+
+        let secret = server.secret().to_vec();
+        assert_eq!(
+            server.handle(&request("POST", "/check", &secret)).status,
+            200
+        );
+        assert_eq!(server.handle(&request("POST", "/check", b"no")).status, 403);
+        assert_eq!(server.handle(&request("GET", "/ecb", b"")).status, 404);
+    }
+}
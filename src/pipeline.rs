@@ -0,0 +1,615 @@
+//! One builder for the stream wrappers: compression, encryption, a MAC and armor.
+//!
+//! The crate has a stream adapter for each job: [`chunked`](crate::chunked) encrypts,
+//! [`compression`](crate::compression) shrinks the plaintext first, a
+//! [`MacVerifyingReader`](crate::mac_reader::MacVerifyingReader) checks an encrypt-then-MAC
+//! tag, and [`transcode`](crate::transcode) turns the result into text. Stacking them by hand
+//! means getting the order right at every call site. A [`Pipeline`] takes the stages in the
+//! only order that is safe,
+//!
+//! ```text
+//! compress -> encrypt -> MAC -> armor
+//! ```
+//!
+//! and builds a single [`PipelineWriter`] for the sending end and a [`PipelineReader`] for the
+//! receiving one. The order is checked by the compiler: each stage returns a pipeline in a new
+//! state, and only the states where the next stage makes sense have a method for it. So
+//! compressing after encrypting, which gains nothing, doesn't compile; nor does a MAC over the
+//! plaintext, which would leave the ciphertext open to tampering; nor does building a pipeline
+//! whose CTR ciphertext has no MAC at all. The stages are:
+//!
+//! - [`compress`](Pipeline::compress), optional, and only with the oracle risk acknowledged;
+//! - [`encrypt`](Pipeline::encrypt), a chunked AEAD stream that needs no MAC, or
+//!   [`encrypt_unauthenticated`](Pipeline::encrypt_unauthenticated), CTR with a random nonce,
+//!   which must be followed by [`mac`](Pipeline::mac), an HMAC-SHA256 over the nonce and
+//!   ciphertext;
+//! - [`armor`](Pipeline::armor), optional.
+//!
+//! Both ends must build the same pipeline. The chunked stream records its own options in its
+//! header, but nothing records which stages there were.
+//!
+//! The CTR and MAC stages release data before the tag at the end of the stream has been
+//! checked, as the [`MacVerifyingReader`](crate::mac_reader) they use does, and the last read
+//! fails if it doesn't match. Prefer [`encrypt`](Pipeline::encrypt) unless the other end
+//! expects encrypt-then-MAC.
+
+use std::{
+    io::{self, Read, Write},
+    marker::PhantomData,
+    mem,
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    chunked::{StreamDecryptor, StreamEncryptor, StreamOptions},
+    compression::{Compression, Compressor, Decompressor, OracleRiskAcknowledged},
+    ctr::CtrParams,
+    limits,
+    mac_reader::MacVerifyingReader,
+    transcode::{DecodingReader, Encoding, EncodingWriter},
+    utils, BLOCK_SIZE, NONCE_SIZE,
+};
+
+/// A pipeline with no stages yet.
+#[derive(Clone, Copy, Debug)]
+pub struct Start;
+/// A pipeline that compresses, and must encrypt next.
+#[derive(Clone, Copy, Debug)]
+pub struct Compressed;
+/// A pipeline whose ciphertext has no tag yet, and must be given a MAC.
+#[derive(Clone, Copy, Debug)]
+pub struct Unauthenticated;
+/// A pipeline whose ciphertext is authenticated, which can be armored or built.
+#[derive(Clone, Copy, Debug)]
+pub struct Authenticated;
+/// A pipeline that writes text, which can only be built.
+#[derive(Clone, Copy, Debug)]
+pub struct Armored;
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for super::Start {}
+    impl Sealed for super::Compressed {}
+    impl Sealed for super::Authenticated {}
+    impl Sealed for super::Armored {}
+}
+
+/// The states before encryption.
+pub trait Plaintext: private::Sealed {}
+impl Plaintext for Start {}
+impl Plaintext for Compressed {}
+
+/// The states a pipeline can be built in.
+pub trait Complete: private::Sealed {}
+impl Complete for Authenticated {}
+impl Complete for Armored {}
+
+#[derive(Clone)]
+enum Cipher {
+    None,
+    Chunked {
+        master_secret: Vec<u8>,
+        options: StreamOptions,
+    },
+    Ctr {
+        key: [u8; BLOCK_SIZE],
+        mac_key: Vec<u8>,
+    },
+}
+
+/// A stack of stream stages. See the [module documentation](self) for the order they go in.
+#[derive(Clone)]
+pub struct Pipeline<S> {
+    compression: Compression,
+    cipher: Cipher,
+    armor: Option<(Encoding, Option<usize>)>,
+    state: PhantomData<S>,
+}
+
+impl Pipeline<Start> {
+    pub fn new() -> Self {
+        Pipeline {
+            compression: Compression::None,
+            cipher: Cipher::None,
+            armor: None,
+            state: PhantomData,
+        }
+    }
+
+    /// Compresses the plaintext before it is encrypted. See [`compression`](crate::compression)
+    /// for when that is safe.
+    pub fn compress(
+        self,
+        compression: Compression,
+        _acknowledged: OracleRiskAcknowledged,
+    ) -> Pipeline<Compressed> {
+        Pipeline {
+            compression,
+            ..self.into_state()
+        }
+    }
+}
+
+impl Default for Pipeline<Start> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Pipeline<S> {
+    fn into_state<T>(self) -> Pipeline<T> {
+        Pipeline {
+            compression: self.compression,
+            cipher: self.cipher,
+            armor: self.armor,
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S: Plaintext> Pipeline<S> {
+    /// Encrypts into a [chunked](crate::chunked) stream under `master_secret`, with the default
+    /// options.
+    pub fn encrypt(self, master_secret: &[u8]) -> Pipeline<Authenticated> {
+        self.encrypt_with_options(master_secret, StreamOptions::new())
+    }
+
+    /// Like [`encrypt`](Self::encrypt), with `options`. The pipeline's compression replaces
+    /// theirs.
+    pub fn encrypt_with_options(
+        self,
+        master_secret: &[u8],
+        options: StreamOptions,
+    ) -> Pipeline<Authenticated> {
+        let options = options.with_compression(self.compression, OracleRiskAcknowledged);
+        Pipeline {
+            cipher: Cipher::Chunked {
+                master_secret: master_secret.to_vec(),
+                options,
+            },
+            ..self.into_state()
+        }
+    }
+
+    /// Encrypts with CTR under `key`, which leaves the ciphertext malleable until
+    /// [`mac`](Pipeline::mac) adds a tag.
+    pub fn encrypt_unauthenticated(self, key: [u8; BLOCK_SIZE]) -> Pipeline<Unauthenticated> {
+        Pipeline {
+            cipher: Cipher::Ctr {
+                key,
+                mac_key: Vec::new(),
+            },
+            ..self.into_state()
+        }
+    }
+}
+
+impl Pipeline<Unauthenticated> {
+    /// Appends an HMAC-SHA256 of the nonce and ciphertext under `key`, which must be
+    /// independent of the encryption key.
+    pub fn mac(mut self, key: &[u8]) -> Pipeline<Authenticated> {
+        if let Cipher::Ctr { mac_key, .. } = &mut self.cipher {
+            *mac_key = key.to_vec();
+        }
+        self.into_state()
+    }
+}
+
+impl Pipeline<Authenticated> {
+    /// Writes the output as text in `encoding`, on a single line.
+    pub fn armor(self, encoding: Encoding) -> Pipeline<Armored> {
+        Pipeline {
+            armor: Some((encoding, None)),
+            ..self.into_state()
+        }
+    }
+
+    /// Like [`armor`](Self::armor), in lines of `line_length` characters.
+    ///
+    /// # Panics
+    ///
+    /// If `line_length` is zero.
+    pub fn armor_lines(self, encoding: Encoding, line_length: usize) -> Pipeline<Armored> {
+        assert!(line_length > 0, "lines must hold at least one character");
+        Pipeline {
+            armor: Some((encoding, Some(line_length))),
+            ..self.into_state()
+        }
+    }
+}
+
+impl<S: Complete> Pipeline<S> {
+    /// Starts writing through the pipeline into `writer`. Call
+    /// [`finish`](PipelineWriter::finish) at the end.
+    pub fn writer<W: Write>(&self, writer: W) -> io::Result<PipelineWriter<W>> {
+        let sink = match self.armor {
+            None => Sink::Binary(writer),
+            Some((encoding, line_length)) => {
+                let encoder = EncodingWriter::new(encoding, writer);
+                Sink::Armored(match line_length {
+                    Some(line_length) => encoder.with_line_length(line_length),
+                    None => encoder,
+                })
+            }
+        };
+        let stage = match &self.cipher {
+            Cipher::Chunked {
+                master_secret,
+                options,
+            } => WriteStage::Chunked(Box::new(StreamEncryptor::with_options(
+                master_secret,
+                *options,
+                sink,
+            )?)),
+            Cipher::Ctr { key, mac_key } => WriteStage::Ctr(Box::new(CtrWriter::new(
+                *key,
+                mac_key,
+                self.compression,
+                sink,
+            )?)),
+            Cipher::None => unreachable!("every complete pipeline encrypts"),
+        };
+        Ok(PipelineWriter { stage })
+    }
+
+    /// Starts reading what a [`PipelineWriter`] of the same pipeline wrote, from `reader`.
+    pub fn reader<R: Read>(&self, reader: R) -> io::Result<PipelineReader<R>> {
+        let source = match self.armor {
+            None => Source::Binary(reader),
+            Some((encoding, _)) => Source::Armored(DecodingReader::new(encoding, reader)),
+        };
+        let stage = match &self.cipher {
+            Cipher::Chunked { master_secret, .. } => {
+                ReadStage::Chunked(Box::new(StreamDecryptor::new(master_secret, source)?))
+            }
+            Cipher::Ctr { key, mac_key } => ReadStage::Ctr(Box::new(CtrReader::new(
+                *key,
+                mac_key,
+                self.compression,
+                source,
+            )?)),
+            Cipher::None => unreachable!("every complete pipeline decrypts"),
+        };
+        Ok(PipelineReader { stage })
+    }
+}
+
+/// Where the writer's output goes: straight to the caller's writer, or through an encoder.
+enum Sink<W: Write> {
+    Binary(W),
+    Armored(EncodingWriter<W>),
+}
+
+impl<W: Write> Sink<W> {
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Sink::Binary(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            Sink::Armored(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Binary(writer) => writer.write(buf),
+            Sink::Armored(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Binary(writer) => writer.flush(),
+            Sink::Armored(encoder) => encoder.flush(),
+        }
+    }
+}
+
+enum Source<R: Read> {
+    Binary(R),
+    Armored(DecodingReader<R>),
+}
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Binary(reader) => reader.read(buf),
+            Source::Armored(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// A CTR keystream that picks up where the last call left off.
+struct Keystream {
+    cipher: Aes128,
+    nonce: [u8; NONCE_SIZE],
+    counter: u128,
+    block: [u8; BLOCK_SIZE],
+    used: usize,
+}
+
+impl Keystream {
+    fn new(key: [u8; BLOCK_SIZE], nonce: [u8; NONCE_SIZE]) -> Self {
+        Keystream {
+            cipher: Aes128::new(&GenericArray::from(key)),
+            nonce,
+            counter: 0,
+            block: [0; BLOCK_SIZE],
+            used: BLOCK_SIZE,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == BLOCK_SIZE {
+                let mut block =
+                    GenericArray::from(CtrParams::new().counter_block(&self.nonce, self.counter));
+                self.cipher.encrypt_block(&mut block);
+                self.block = block.into();
+                self.counter += 1;
+                self.used = 0;
+            }
+            *byte ^= self.block[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// Compresses, encrypts with CTR and MACs, writing `nonce | ciphertext | tag`.
+struct CtrWriter<W: Write> {
+    compressor: Compressor,
+    keystream: Keystream,
+    mac: Hmac<Sha256>,
+    sink: Sink<W>,
+}
+
+impl<W: Write> CtrWriter<W> {
+    fn new(
+        key: [u8; BLOCK_SIZE],
+        mac_key: &[u8],
+        compression: Compression,
+        mut sink: Sink<W>,
+    ) -> io::Result<Self> {
+        let mut nonce = [0; NONCE_SIZE];
+        utils::fill_random(&mut nonce);
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC takes any key size");
+        mac.update(&nonce);
+        sink.write_all(&nonce)?;
+        Ok(CtrWriter {
+            compressor: Compressor::new(compression),
+            keystream: Keystream::new(key, nonce),
+            mac,
+            sink,
+        })
+    }
+
+    fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut cipher_text = data.to_vec();
+        self.keystream.apply(&mut cipher_text);
+        self.mac.update(&cipher_text);
+        self.sink.write_all(&cipher_text)
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        let compressor = mem::replace(&mut self.compressor, Compressor::None);
+        self.push(&compressor.finish())?;
+        let tag = self.mac.finalize().into_bytes();
+        self.sink.write_all(&tag)?;
+        self.sink.finish()
+    }
+}
+
+enum WriteStage<W: Write> {
+    Chunked(Box<StreamEncryptor<Sink<W>>>),
+    Ctr(Box<CtrWriter<W>>),
+}
+
+/// Writes plaintext through every stage of a [`Pipeline`].
+pub struct PipelineWriter<W: Write> {
+    stage: WriteStage<W>,
+}
+
+impl<W: Write> PipelineWriter<W> {
+    /// Ends the stream, writing out whatever every stage still holds, and hands back the
+    /// underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.stage {
+            WriteStage::Chunked(encryptor) => encryptor.finish()?.finish(),
+            WriteStage::Ctr(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for PipelineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stage {
+            WriteStage::Chunked(encryptor) => encryptor.write(buf),
+            WriteStage::Ctr(writer) => {
+                let data = writer.compressor.compress(buf).into_owned();
+                writer.push(&data)?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stage {
+            WriteStage::Chunked(encryptor) => encryptor.flush(),
+            WriteStage::Ctr(writer) => writer.sink.flush(),
+        }
+    }
+}
+
+/// Checks the MAC, decrypts with CTR and decompresses.
+struct CtrReader<R: Read> {
+    reader: MacVerifyingReader<Source<R>>,
+    keystream: Keystream,
+    decompressor: Option<Decompressor>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> CtrReader<R> {
+    fn new(
+        key: [u8; BLOCK_SIZE],
+        mac_key: &[u8],
+        compression: Compression,
+        source: Source<R>,
+    ) -> io::Result<Self> {
+        let mut reader = MacVerifyingReader::hmac_sha256(mac_key, source);
+        let mut nonce = [0; NONCE_SIZE];
+        reader.read_exact(&mut nonce)?;
+        Ok(CtrReader {
+            reader,
+            keystream: Keystream::new(key, nonce),
+            decompressor: Some(Decompressor::new(
+                compression,
+                limits::DEFAULT_MAX_DECOMPRESSED_CHUNK_SIZE,
+            )),
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for CtrReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            let Some(decompressor) = &mut self.decompressor else {
+                return Ok(0);
+            };
+            let mut data = vec![0; 8192];
+            let n = self.reader.read(&mut data)?;
+            self.buffer = if n == 0 {
+                self.decompressor.take().unwrap().finish()?
+            } else {
+                data.truncate(n);
+                self.keystream.apply(&mut data);
+                decompressor.decompress(data)?
+            };
+            self.position = 0;
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+enum ReadStage<R: Read> {
+    Chunked(Box<StreamDecryptor<Source<R>>>),
+    Ctr(Box<CtrReader<R>>),
+}
+
+/// Reads plaintext back through every stage of a [`Pipeline`].
+pub struct PipelineReader<R: Read> {
+    stage: ReadStage<R>,
+}
+
+impl<R: Read> Read for PipelineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.stage {
+            ReadStage::Chunked(decryptor) => decryptor.read(buf),
+            ReadStage::Ctr(reader) => reader.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"pipeline test master secret";
+    const KEY: [u8; BLOCK_SIZE] = [5; BLOCK_SIZE];
+
+    fn round_trip<S: Complete>(pipeline: &Pipeline<S>, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut writer = pipeline.writer(Vec::new()).unwrap();
+        writer.write_all(data).unwrap();
+        let output = writer.finish().unwrap();
+        let mut decrypted = Vec::new();
+        pipeline
+            .reader(output.as_slice())
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        (output, decrypted)
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let data: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        let chunked = Pipeline::new().encrypt(SECRET);
+        assert_eq!(round_trip(&chunked, &data).1, data);
+
+        let armored = Pipeline::new()
+            .encrypt(SECRET)
+            .armor_lines(Encoding::Base64Url, 76);
+        let (text, decrypted) = round_trip(&armored, &data);
+        assert!(text.is_ascii());
+        assert_eq!(decrypted, data);
+
+        let ctr = Pipeline::new()
+            .encrypt_unauthenticated(KEY)
+            .mac(b"an independent MAC key")
+            .armor(Encoding::Hex);
+        let (text, decrypted) = round_trip(&ctr, &data);
+        assert_eq!(text.len(), 2 * (NONCE_SIZE + data.len() + 32));
+        assert_eq!(decrypted, data);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn test_compression() {
+        let data = vec![b'a'; 100_000];
+        for pipeline in [
+            Pipeline::new()
+                .compress(Compression::Deflate, OracleRiskAcknowledged)
+                .encrypt(SECRET)
+                .armor(Encoding::Base64Url),
+            Pipeline::new()
+                .compress(Compression::Deflate, OracleRiskAcknowledged)
+                .encrypt_unauthenticated(KEY)
+                .mac(b"mac key")
+                .armor(Encoding::Base64Url),
+        ] {
+            let (text, decrypted) = round_trip(&pipeline, &data);
+            assert!(text.len() < data.len() / 10);
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        let pipeline = Pipeline::new().encrypt_unauthenticated(KEY).mac(b"mac key");
+        let mut writer = pipeline.writer(Vec::new()).unwrap();
+        writer.write_all(b"pay 100 to alice").unwrap();
+        let mut output = writer.finish().unwrap();
+        output[NONCE_SIZE + 4] ^= 1;
+        let error = pipeline
+            .reader(output.as_slice())
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let other_key = Pipeline::new().encrypt(b"another master secret");
+        let mut writer = Pipeline::new().encrypt(SECRET).writer(Vec::new()).unwrap();
+        writer.write_all(b"chunked").unwrap();
+        let output = writer.finish().unwrap();
+        assert!(other_key
+            .reader(output.as_slice())
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .is_err());
+    }
+}
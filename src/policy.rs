@@ -0,0 +1,384 @@
+//! Organization-wide rules on which algorithms and parameters are acceptable.
+//!
+//! Security standards usually come down to a short list: no ECB, authenticated encryption for
+//! anything new, full-length tags, master secrets of at least so many bytes. A [`Policy`]
+//! writes such a list down once, and the high-level APIs that take one refuse anything it
+//! forbids with a [`PolicyViolation`] that says exactly what was wrong:
+//!
+//! - [`Envelope::seal_with_policy`](crate::envelope::Envelope::seal_with_policy),
+//!   [`Envelope::open_with_policy`](crate::envelope::Envelope::open_with_policy), and
+//!   [`Envelope::from_bytes_with_policy`](crate::envelope::Envelope::from_bytes_with_policy);
+//! - [`StreamEncryptor::with_policy`](crate::chunked::StreamEncryptor::with_policy) and
+//!   [`StreamDecryptor::with_policy`](crate::chunked::StreamDecryptor::with_policy).
+//!
+//! Three profiles come ready-made:
+//!
+//! | profile               | ECB | CBC, CTR | deprecated | tags      | master secrets |
+//! |-----------------------|-----|----------|------------|-----------|----------------|
+//! | [`Policy::strict`]    | no  | no       | no         | 16 bytes  | 32 bytes       |
+//! | [`Policy::compat`]    | no  | yes      | no         | 12 and up | 16 bytes       |
+//! | [`Policy::legacy`]    | yes | yes      | yes        | 12 and up | any            |
+//!
+//! `legacy` is for reading old data during a migration, not for writing new data. Algorithms
+//! are named as in the [registry](crate::registry).
+//!
+//! # Downgrades
+//!
+//! In a fleet that runs several releases at once, an attacker who can replace stored blobs
+//! may try to substitute ones in older formats or weaker modes, hoping some reader still
+//! accepts them. Once every writer has moved on, a policy can record where they moved to:
+//! [`Policy::with_min_envelope_version`] refuses envelopes in older formats, and
+//! [`Policy::with_allowed_algorithms`] refuses every algorithm not on an explicit list, even
+//! ones the profile would allow.
+//! [`Envelope::from_bytes_with_policy`](crate::envelope::Envelope::from_bytes_with_policy)
+//! checks both before decoding anything else.
+//!
+//! Old data still has to be read while it is re-encrypted. [`Policy::allow_downgrade`] lets
+//! older envelopes through again, but reports each as a [`Warning::Downgrade`], so the override
+//! is visible and can be removed once the warnings stop.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    chunked::{StreamHeader, MIN_TAG_LEN},
+    envelope::EnvelopeMode,
+    gcm::TAG_SIZE,
+    registry::{self, Algorithm},
+    warnings::{self, Warning},
+    Mode,
+};
+
+/// Something a [`Policy`] doesn't allow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// No algorithm has this name, so none can be allowed.
+    UnknownAlgorithm,
+    /// The policy forbids the algorithm: insecure, deprecated or unauthenticated.
+    AlgorithmNotAllowed(&'static str),
+    /// The algorithm doesn't take this key size, or the policy wants a bigger key.
+    KeySize {
+        algorithm: &'static str,
+        size: usize,
+    },
+    /// Tags must be at least `min` bytes long.
+    TagTooShort { len: usize, min: usize },
+    /// Master secrets must be at least `min` bytes long.
+    SecretTooShort { len: usize, min: usize },
+    /// The format version is older than the policy's minimum `min`.
+    VersionTooOld { version: u8, min: u8 },
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::UnknownAlgorithm => f.write_str("unknown algorithm"),
+            PolicyViolation::AlgorithmNotAllowed(algorithm) => {
+                write!(f, "policy does not allow {}", algorithm)
+            }
+            PolicyViolation::KeySize { algorithm, size } => {
+                write!(
+                    f,
+                    "policy does not allow {}-byte keys for {}",
+                    size, algorithm
+                )
+            }
+            PolicyViolation::TagTooShort { len, min } => {
+                write!(
+                    f,
+                    "{}-byte tag is shorter than the policy's {} bytes",
+                    len, min
+                )
+            }
+            PolicyViolation::SecretTooShort { len, min } => write!(
+                f,
+                "{}-byte master secret is shorter than the policy's {} bytes",
+                len, min
+            ),
+            PolicyViolation::VersionTooOld { version, min } => write!(
+                f,
+                "format version {} is older than the policy's minimum version {}",
+                version, min
+            ),
+        }
+    }
+}
+
+impl Error for PolicyViolation {}
+
+/// Which algorithms and parameters are acceptable. See the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    allow_unauthenticated: bool,
+    allow_deprecated: bool,
+    /// Algorithms forbidden by name, whatever the other rules say.
+    forbidden: Vec<&'static str>,
+    /// If set, the only algorithms that may be allowed.
+    allowed: Option<Vec<&'static str>>,
+    min_key_size: usize,
+    min_tag_len: usize,
+    min_secret_len: usize,
+    min_envelope_version: u8,
+    allow_downgrade: bool,
+}
+
+impl Policy {
+    /// Authenticated encryption only, with full-length tags and 32-byte master secrets.
+    pub fn strict() -> Self {
+        Policy {
+            allow_unauthenticated: false,
+            allow_deprecated: false,
+            forbidden: Vec::new(),
+            allowed: None,
+            min_key_size: 16,
+            min_tag_len: TAG_SIZE,
+            min_secret_len: 32,
+            min_envelope_version: 1,
+            allow_downgrade: false,
+        }
+    }
+
+    /// Also allows CBC and CTR, and tags truncated to 12 bytes, for interoperating with
+    /// existing systems. ECB and deprecated algorithms are still refused.
+    pub fn compat() -> Self {
+        Policy {
+            allow_unauthenticated: true,
+            allow_deprecated: false,
+            forbidden: Vec::new(),
+            allowed: None,
+            min_key_size: 16,
+            min_tag_len: MIN_TAG_LEN as usize,
+            min_secret_len: 16,
+            min_envelope_version: 1,
+            allow_downgrade: false,
+        }
+    }
+
+    /// Allows everything the crate can do, for decrypting old data.
+    pub fn legacy() -> Self {
+        Policy {
+            allow_unauthenticated: true,
+            allow_deprecated: true,
+            forbidden: Vec::new(),
+            allowed: None,
+            min_key_size: 0,
+            min_tag_len: MIN_TAG_LEN as usize,
+            min_secret_len: 0,
+            min_envelope_version: 1,
+            allow_downgrade: false,
+        }
+    }
+
+    /// Forbids one more algorithm by its registry name.
+    ///
+    /// # Panics
+    ///
+    /// If no algorithm has that name, which is most likely a typo.
+    pub fn forbid(mut self, name: &str) -> Self {
+        let algorithm = registry::algorithm(name).expect("unknown algorithm");
+        self.forbidden.push(algorithm.name);
+        self
+    }
+
+    /// Allows no algorithm but these, by their registry names, on top of the other rules.
+    ///
+    /// # Panics
+    ///
+    /// If no algorithm has one of the names.
+    pub fn with_allowed_algorithms(mut self, names: &[&str]) -> Self {
+        let allowed = names
+            .iter()
+            .map(|name| registry::algorithm(name).expect("unknown algorithm").name)
+            .collect();
+        self.allowed = Some(allowed);
+        self
+    }
+
+    /// Refuses envelopes in formats older than `version`. See [Downgrades](self#downgrades).
+    pub fn with_min_envelope_version(mut self, version: u8) -> Self {
+        self.min_envelope_version = version;
+        self
+    }
+
+    /// Accepts envelopes older than the minimum version after all, warning about each.
+    pub fn allow_downgrade(mut self) -> Self {
+        self.allow_downgrade = true;
+        self
+    }
+
+    /// Requires keys of at least `size` bytes.
+    pub fn with_min_key_size(mut self, size: usize) -> Self {
+        self.min_key_size = size;
+        self
+    }
+
+    /// Checks the algorithm with this registry name, and returns its description.
+    pub fn check_algorithm(&self, name: &str) -> Result<Algorithm, PolicyViolation> {
+        let algorithm = registry::algorithm(name).ok_or(PolicyViolation::UnknownAlgorithm)?;
+        if (algorithm.deprecated && !self.allow_deprecated)
+            || (!algorithm.authenticated && !self.allow_unauthenticated)
+            || self.forbidden.contains(&algorithm.name)
+            || self
+                .allowed
+                .as_ref()
+                .is_some_and(|allowed| !allowed.contains(&algorithm.name))
+        {
+            return Err(PolicyViolation::AlgorithmNotAllowed(algorithm.name));
+        }
+        Ok(algorithm)
+    }
+
+    /// Checks the algorithm, and that it is used with a `size`-byte key.
+    pub fn check_key(&self, name: &str, size: usize) -> Result<(), PolicyViolation> {
+        let algorithm = self.check_algorithm(name)?;
+        if !algorithm.key_sizes.contains(&size) || size < self.min_key_size {
+            return Err(PolicyViolation::KeySize {
+                algorithm: algorithm.name,
+                size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_mode(&self, mode: Mode) -> Result<(), PolicyViolation> {
+        self.check_envelope_mode(mode.into())
+    }
+
+    pub fn check_envelope_mode(&self, mode: EnvelopeMode) -> Result<(), PolicyViolation> {
+        let name = match mode {
+            EnvelopeMode::Ecb => "aes-128-ecb",
+            EnvelopeMode::Cbc => "aes-128-cbc",
+            EnvelopeMode::Ctr => "aes-128-ctr",
+            EnvelopeMode::Gcm => "aes-128-gcm",
+        };
+        self.check_key(name, 16)
+    }
+
+    /// Checks an envelope's format version.
+    pub fn check_envelope_version(&self, version: u8) -> Result<(), PolicyViolation> {
+        if version >= self.min_envelope_version {
+            return Ok(());
+        }
+        let min = self.min_envelope_version;
+        if self.allow_downgrade {
+            warnings::warn(Warning::Downgrade { version, min });
+            return Ok(());
+        }
+        Err(PolicyViolation::VersionTooOld { version, min })
+    }
+
+    pub fn check_tag_len(&self, len: usize) -> Result<(), PolicyViolation> {
+        if len < self.min_tag_len {
+            return Err(PolicyViolation::TagTooShort {
+                len,
+                min: self.min_tag_len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the input to a key derivation, such as a stream's master secret.
+    pub fn check_secret(&self, secret: &[u8]) -> Result<(), PolicyViolation> {
+        if secret.len() < self.min_secret_len {
+            return Err(PolicyViolation::SecretTooShort {
+                len: secret.len(),
+                min: self.min_secret_len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks a chunked stream: AES-GCM chunks with the header's tag length, keyed from
+    /// `master_secret`.
+    pub fn check_stream(
+        &self,
+        master_secret: &[u8],
+        header: &StreamHeader,
+    ) -> Result<(), PolicyViolation> {
+        self.check_algorithm("aes-128-gcm")?;
+        self.check_tag_len(header.tag_len as usize)?;
+        self.check_secret(master_secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let strict = Policy::strict();
+        assert_eq!(
+            strict.check_mode(Mode::Ecb),
+            Err(PolicyViolation::AlgorithmNotAllowed("aes-128-ecb"))
+        );
+        assert_eq!(
+            strict.check_mode(Mode::Cbc),
+            Err(PolicyViolation::AlgorithmNotAllowed("aes-128-cbc"))
+        );
+        assert_eq!(strict.check_envelope_mode(EnvelopeMode::Gcm), Ok(()));
+        assert_eq!(
+            strict.check_tag_len(12),
+            Err(PolicyViolation::TagTooShort { len: 12, min: 16 })
+        );
+        assert_eq!(
+            strict.check_secret(&[0u8; 16]).unwrap_err().to_string(),
+            "16-byte master secret is shorter than the policy's 32 bytes"
+        );
+
+        let compat = Policy::compat();
+        assert_eq!(compat.check_mode(Mode::Cbc), Ok(()));
+        assert!(compat.check_mode(Mode::Ecb).is_err());
+        assert_eq!(compat.check_tag_len(12), Ok(()));
+
+        let legacy = Policy::legacy();
+        for mode in [Mode::Ecb, Mode::Cbc, Mode::Ctr] {
+            assert_eq!(legacy.check_mode(mode), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let policy = Policy::compat().forbid("aes-128-ctr");
+        assert_eq!(
+            policy.check_mode(Mode::Ctr),
+            Err(PolicyViolation::AlgorithmNotAllowed("aes-128-ctr"))
+        );
+        assert_eq!(
+            policy.check_key("aes-siv", 16),
+            Err(PolicyViolation::KeySize {
+                algorithm: "aes-siv",
+                size: 16
+            })
+        );
+        assert_eq!(policy.check_key("aes-siv", 32), Ok(()));
+        assert_eq!(
+            policy.clone().with_min_key_size(32).check_key("hctr2", 16),
+            Err(PolicyViolation::KeySize {
+                algorithm: "hctr2",
+                size: 16
+            })
+        );
+        assert_eq!(
+            policy.check_algorithm("rot13"),
+            Err(PolicyViolation::UnknownAlgorithm)
+        );
+    }
+
+    #[test]
+    fn test_downgrades() {
+        let policy = Policy::compat()
+            .with_allowed_algorithms(&["aes-128-gcm", "aes-128-ctr"])
+            .with_min_envelope_version(4);
+        assert_eq!(policy.check_mode(Mode::Ctr), Ok(()));
+        assert_eq!(
+            policy.check_mode(Mode::Cbc),
+            Err(PolicyViolation::AlgorithmNotAllowed("aes-128-cbc"))
+        );
+        assert_eq!(policy.check_envelope_version(4), Ok(()));
+        assert_eq!(
+            policy.check_envelope_version(3),
+            Err(PolicyViolation::VersionTooOld { version: 3, min: 4 })
+        );
+        assert_eq!(policy.allow_downgrade().check_envelope_version(3), Ok(()));
+    }
+}
@@ -0,0 +1,291 @@
+//! Forward-secure encryption for append-only logs.
+//!
+//! If an audit log is encrypted under one long-lived key, whoever steals that key can read the
+//! whole history. Here every entry gets its own key instead, and the keys form a one-way chain:
+//!
+//! ```text
+//! chain key 0 --HKDF--> chain key 1 --HKDF--> chain key 2 --> ...
+//!      |                     |                     |
+//!   entry key 0          entry key 1          entry key 2
+//! ```
+//!
+//! As soon as an entry has been written, its keys are derived forward and the old ones are
+//! zeroized. Someone who compromises the writer later only finds the _current_ chain key, and
+//! HKDF can't be run backwards, so all entries written before the compromise stay private.
+//!
+//! The flip side is that a reader can only move forwards too. Readers that need the whole log
+//! start from the initial chain key, which should be kept offline.
+
+use std::{error::Error, fmt};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::{
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE},
+    BLOCK_SIZE,
+};
+
+pub const CHAIN_KEY_SIZE: usize = 32;
+
+const RATCHET_LABEL: &[u8] = b"aes-modes log ratchet";
+
+/// How many entries a reader is willing to skip at once. Without a limit, a forged index near
+/// `u64::MAX` would keep the reader busy deriving keys forever.
+pub const MAX_SKIP: u64 = 1 << 20;
+
+/// Why a log entry could not be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RatchetError {
+    /// The entry is too short to contain its index and tag.
+    Truncated,
+    /// The entry comes before the reader's position, and its key has already been erased.
+    AlreadyErased { index: u64 },
+    /// The entry is more than [`MAX_SKIP`] entries ahead of the reader.
+    TooFarAhead { index: u64 },
+    /// The entry was modified, or doesn't belong to this log.
+    Authentication,
+}
+
+impl fmt::Display for RatchetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatchetError::Truncated => f.write_str("truncated log entry"),
+            RatchetError::AlreadyErased { index } => {
+                write!(f, "the key for entry {} has already been erased", index)
+            }
+            RatchetError::TooFarAhead { index } => {
+                write!(f, "entry {} is too far ahead of the reader", index)
+            }
+            RatchetError::Authentication => f.write_str("log entry failed authentication"),
+        }
+    }
+}
+
+impl Error for RatchetError {}
+
+/// The current position in the key chain. Zeroized whenever it moves on, and when dropped.
+struct Chain {
+    key: [u8; CHAIN_KEY_SIZE],
+    index: u64,
+}
+
+impl Chain {
+    /// Returns the key for the current entry and moves the chain one step forward.
+    fn step(&mut self) -> [u8; BLOCK_SIZE] {
+        let hkdf =
+            Hkdf::<Sha256>::from_prk(&self.key).expect("the chain key is exactly one hash long");
+        let mut output = [0u8; CHAIN_KEY_SIZE + BLOCK_SIZE];
+        hkdf.expand(RATCHET_LABEL, &mut output)
+            .expect("48 bytes is a valid HKDF-SHA256 output length");
+
+        self.key.zeroize();
+        self.key.copy_from_slice(&output[..CHAIN_KEY_SIZE]);
+        self.index += 1;
+
+        let mut entry_key = [0u8; BLOCK_SIZE];
+        entry_key.copy_from_slice(&output[CHAIN_KEY_SIZE..]);
+        output.zeroize();
+        entry_key
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// The nonce can be fixed, since every entry key is only ever used once. The index is used
+/// anyway, as a second line of defence.
+fn nonce(index: u64) -> [u8; GCM_NONCE_SIZE] {
+    let mut nonce = [0u8; GCM_NONCE_SIZE];
+    nonce[GCM_NONCE_SIZE - 8..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+/// Appends entries to a log, destroying each entry's key right after use.
+pub struct LogWriter {
+    chain: Chain,
+}
+
+impl LogWriter {
+    /// Starts a new log. Keep a copy of `initial_key` somewhere safe if the log must be readable
+    /// later, because the writer forgets it immediately.
+    pub fn new(initial_key: [u8; CHAIN_KEY_SIZE]) -> Self {
+        Self::resume(initial_key, 0)
+    }
+
+    /// Picks up a log where a previous writer left off, from its [`state`](Self::state).
+    pub fn resume(chain_key: [u8; CHAIN_KEY_SIZE], index: u64) -> Self {
+        LogWriter {
+            chain: Chain {
+                key: chain_key,
+                index,
+            },
+        }
+    }
+
+    /// The current chain key and index, to persist across restarts. This only unlocks entries
+    /// written _from now on_.
+    pub fn state(&self) -> ([u8; CHAIN_KEY_SIZE], u64) {
+        (self.chain.key, self.chain.index)
+    }
+
+    /// Encrypts the next entry as `index | ciphertext | tag`.
+    pub fn encrypt_entry(&mut self, entry: &[u8]) -> Vec<u8> {
+        let index = self.chain.index;
+        let mut entry_key = self.chain.step();
+
+        let mut output = index.to_be_bytes().to_vec();
+        output.extend(gcm_encrypt(
+            entry.to_vec(),
+            entry_key,
+            nonce(index),
+            &output,
+        ));
+        entry_key.zeroize();
+        output
+    }
+}
+
+/// Reads a log in order. Entries may be skipped, but never revisited.
+pub struct LogReader {
+    chain: Chain,
+}
+
+impl LogReader {
+    pub fn new(initial_key: [u8; CHAIN_KEY_SIZE]) -> Self {
+        Self::resume(initial_key, 0)
+    }
+
+    pub fn resume(chain_key: [u8; CHAIN_KEY_SIZE], index: u64) -> Self {
+        LogReader {
+            chain: Chain {
+                key: chain_key,
+                index,
+            },
+        }
+    }
+
+    pub fn decrypt_entry(&mut self, entry: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        if entry.len() < 8 {
+            return Err(RatchetError::Truncated);
+        }
+        let (header, body) = entry.split_at(8);
+        let index = u64::from_be_bytes(header.try_into().unwrap());
+        if index < self.chain.index {
+            return Err(RatchetError::AlreadyErased { index });
+        }
+        if index - self.chain.index > MAX_SKIP {
+            return Err(RatchetError::TooFarAhead { index });
+        }
+
+        // Work on a copy, so that a bad entry doesn't move the reader forward.
+        let mut chain = Chain {
+            key: self.chain.key,
+            index: self.chain.index,
+        };
+        let mut entry_key = chain.step();
+        while chain.index <= index {
+            entry_key.zeroize();
+            entry_key = chain.step();
+        }
+
+        let result = gcm_decrypt(body.to_vec(), entry_key, nonce(index), header)
+            .map_err(|_| RatchetError::Authentication);
+        entry_key.zeroize();
+
+        if result.is_ok() {
+            self.chain = chain;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIAL_KEY: [u8; CHAIN_KEY_SIZE] = [0x11; CHAIN_KEY_SIZE];
+
+    #[test]
+    fn test_read_back_log() {
+        let mut writer = LogWriter::new(INITIAL_KEY);
+        let entries: Vec<Vec<u8>> = (0..5)
+            .map(|i| writer.encrypt_entry(format!("event {}", i).as_bytes()))
+            .collect();
+
+        let mut reader = LogReader::new(INITIAL_KEY);
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(
+                reader.decrypt_entry(entry),
+                Ok(format!("event {}", i).into_bytes())
+            );
+        }
+
+        // Skipping ahead works, going back doesn't.
+        let mut reader = LogReader::new(INITIAL_KEY);
+        assert_eq!(reader.decrypt_entry(&entries[3]), Ok(b"event 3".to_vec()));
+        assert_eq!(
+            reader.decrypt_entry(&entries[1]),
+            Err(RatchetError::AlreadyErased { index: 1 })
+        );
+        assert_eq!(reader.decrypt_entry(&entries[4]), Ok(b"event 4".to_vec()));
+
+        let mut forged = entries[4].clone();
+        forged[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            reader.decrypt_entry(&forged),
+            Err(RatchetError::TooFarAhead { index: u64::MAX })
+        );
+    }
+
+    #[test]
+    fn test_compromised_writer_cannot_read_the_past() {
+        let mut writer = LogWriter::new(INITIAL_KEY);
+        let old_entry = writer.encrypt_entry(b"before the break-in");
+
+        let (stolen_key, stolen_index) = writer.state();
+        let new_entry = writer.encrypt_entry(b"after the break-in");
+
+        let mut attacker = LogReader::resume(stolen_key, stolen_index);
+        assert_eq!(
+            attacker.decrypt_entry(&old_entry),
+            Err(RatchetError::AlreadyErased { index: 0 })
+        );
+
+        // Pretending the old entry is a future one just gives a wrong key.
+        let mut relabelled = old_entry.clone();
+        relabelled[..8].copy_from_slice(&1u64.to_be_bytes());
+        assert_eq!(
+            attacker.decrypt_entry(&relabelled),
+            Err(RatchetError::Authentication)
+        );
+        assert_eq!(
+            attacker.decrypt_entry(&new_entry),
+            Ok(b"after the break-in".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_resumed_writer_continues_the_chain() {
+        let mut writer = LogWriter::new(INITIAL_KEY);
+        let first = writer.encrypt_entry(b"first");
+        let (key, index) = writer.state();
+        drop(writer);
+
+        let second = LogWriter::resume(key, index).encrypt_entry(b"second");
+
+        let mut reader = LogReader::new(INITIAL_KEY);
+        let mut tampered = first.clone();
+        tampered[10] ^= 1;
+        assert_eq!(
+            reader.decrypt_entry(&tampered),
+            Err(RatchetError::Authentication)
+        );
+        assert_eq!(reader.decrypt_entry(&first), Ok(b"first".to_vec()));
+        assert_eq!(reader.decrypt_entry(&second), Ok(b"second".to_vec()));
+    }
+}
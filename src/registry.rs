@@ -0,0 +1,161 @@
+//! What the crate can do, as data.
+//!
+//! [`algorithms`] lists every encryption algorithm compiled in, with its key and nonce sizes
+//! and whether it is authenticated or deprecated. CLIs and services can build their menus and
+//! validate configuration from it, instead of keeping their own list in step with the crate's
+//! features, and can reject deprecated choices by filtering on [`Algorithm::deprecated`].
+//!
+//! The names are the same ones [`self_test`](crate::self_test) reports.
+
+use crate::{
+    ctr::{MAX_NONCE_SIZE, MIN_NONCE_SIZE},
+    gcm::GCM_NONCE_SIZE,
+    siv::SIV_KEY_SIZE,
+    BLOCK_SIZE,
+};
+
+/// The broad kind of an algorithm, which decides how it is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// A mode of a block cipher, taking a message of any length.
+    BlockMode,
+    /// Authenticated encryption with associated data.
+    Aead,
+    /// A length-preserving cipher for disk sectors, taking a tweak instead of a nonce.
+    Tweakable,
+}
+
+/// A description of one algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Algorithm {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// The key sizes accepted, in bytes.
+    pub key_sizes: &'static [usize],
+    /// The IV or nonce sizes accepted, in bytes. Empty if there is none.
+    pub nonce_sizes: &'static [usize],
+    /// Whether tampering with the ciphertext is detected.
+    pub authenticated: bool,
+    /// Whether the algorithm is only kept to read old data, or is insecure.
+    pub deprecated: bool,
+}
+
+const CTR_NONCE_SIZES: [usize; MAX_NONCE_SIZE - MIN_NONCE_SIZE + 1] = {
+    let mut sizes = [0; MAX_NONCE_SIZE - MIN_NONCE_SIZE + 1];
+    let mut i = 0;
+    while i < sizes.len() {
+        sizes[i] = MIN_NONCE_SIZE + i;
+        i += 1;
+    }
+    sizes
+};
+
+/// Every algorithm compiled in.
+pub fn algorithms() -> Vec<Algorithm> {
+    let block_mode = |name, key_sizes, nonce_sizes| Algorithm {
+        name,
+        kind: Kind::BlockMode,
+        key_sizes,
+        nonce_sizes,
+        authenticated: false,
+        deprecated: false,
+    };
+    let tweakable = |name, key_sizes| Algorithm {
+        name,
+        kind: Kind::Tweakable,
+        key_sizes,
+        nonce_sizes: &[],
+        authenticated: false,
+        deprecated: false,
+    };
+    vec![
+        Algorithm {
+            deprecated: true,
+            ..block_mode("aes-128-ecb", &[BLOCK_SIZE], &[])
+        },
+        block_mode("aes-128-cbc", &[BLOCK_SIZE], &[BLOCK_SIZE]),
+        block_mode("aes-128-ctr", &[BLOCK_SIZE], &CTR_NONCE_SIZES),
+        Algorithm {
+            name: "aes-128-gcm",
+            kind: Kind::Aead,
+            key_sizes: &[BLOCK_SIZE],
+            nonce_sizes: &[GCM_NONCE_SIZE],
+            authenticated: true,
+            deprecated: false,
+        },
+        // A nonce is optional: SIV takes it as one more piece of associated data.
+        Algorithm {
+            name: "aes-siv",
+            kind: Kind::Aead,
+            key_sizes: &[SIV_KEY_SIZE],
+            nonce_sizes: &[],
+            authenticated: true,
+            deprecated: false,
+        },
+        // The data key and the tweak key.
+        tweakable("aes-xts", &[2 * BLOCK_SIZE]),
+        tweakable("hctr2", &[BLOCK_SIZE]),
+        #[cfg(feature = "adiantum")]
+        tweakable("adiantum", &[crate::adiantum::ADIANTUM_KEY_SIZE]),
+        // Through the generic modes, whose IV or initial counter block is one block.
+        #[cfg(feature = "sm4")]
+        block_mode("sm4", &[16], &[16]),
+        #[cfg(feature = "camellia")]
+        block_mode("camellia", &[16, 24, 32], &[16]),
+        #[cfg(feature = "legacy")]
+        Algorithm {
+            deprecated: true,
+            ..block_mode("3des", &[crate::legacy::TDES_KEY_SIZE], &[8])
+        },
+    ]
+}
+
+/// Looks an algorithm up by name.
+pub fn algorithm(name: &str) -> Option<Algorithm> {
+    algorithms()
+        .into_iter()
+        .find(|algorithm| algorithm.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let gcm = algorithm("aes-128-gcm").unwrap();
+        assert!(gcm.authenticated && !gcm.deprecated);
+        assert_eq!(gcm.nonce_sizes, [12]);
+        assert_eq!(
+            algorithm("aes-128-ctr").unwrap().nonce_sizes,
+            [4, 5, 6, 7, 8, 9, 10, 11, 12]
+        );
+        assert_eq!(algorithm("rot13"), None);
+
+        let allowed: Vec<&str> = algorithms()
+            .into_iter()
+            .filter(|algorithm| !algorithm.deprecated)
+            .map(|algorithm| algorithm.name)
+            .collect();
+        assert!(!allowed.contains(&"aes-128-ecb"));
+        assert!(allowed.contains(&"aes-128-cbc"));
+    }
+
+    #[test]
+    fn test_everything_has_a_known_answer_test() {
+        let report = crate::self_test::self_test();
+        for algorithm in algorithms() {
+            // ECB is the bare block cipher, which the "aes-128" tests cover.
+            if algorithm.name != "aes-128-ecb" {
+                assert!(
+                    report
+                        .results
+                        .iter()
+                        .any(|result| result.algorithm == algorithm.name),
+                    "no known-answer test for {}",
+                    algorithm.name
+                );
+            }
+        }
+    }
+}
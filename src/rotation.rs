@@ -0,0 +1,204 @@
+//! Rotating keys on a schedule.
+//!
+//! A [`RotationPolicy`] says how old the current key in a [`KeyManager`] may get before a new
+//! version replaces it. [`spawn_rotation`] runs a background thread that checks the policy
+//! periodically, and with the `tokio` feature [`rotate_periodically`] does the same as an async
+//! task.
+//!
+//! Rotation only changes which key _new_ data is encrypted under. Data already stored stays
+//! readable under its old version, and can be moved to the new key whenever it is next read
+//! with [`KeyManager::decrypt_and_refresh`].
+
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::keys::{self, KeyManager, KeyPolicy};
+
+/// When to replace the current key, and what policy to give its replacement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RotationPolicy {
+    interval: Duration,
+    key_policy: KeyPolicy,
+}
+
+impl RotationPolicy {
+    /// A new key every `interval`, without any usage limits.
+    pub fn new(interval: Duration) -> Self {
+        RotationPolicy {
+            interval,
+            key_policy: KeyPolicy::default(),
+        }
+    }
+
+    /// The policy every newly generated key gets.
+    pub fn with_key_policy(mut self, key_policy: KeyPolicy) -> Self {
+        self.key_policy = key_policy;
+        self
+    }
+
+    /// Whether the current key is old enough to be replaced. A manager without keys is
+    /// always due.
+    pub fn is_due(&self, manager: &KeyManager) -> bool {
+        manager.keys().last().is_none_or(|current| {
+            keys::now().saturating_sub(current.created) >= self.interval.as_secs()
+        })
+    }
+
+    /// Generates a new key version if one is due, and returns it.
+    pub fn rotate_if_due(&self, manager: &mut KeyManager) -> Option<u32> {
+        self.is_due(manager)
+            .then(|| manager.generate(self.key_policy.clone()))
+    }
+}
+
+/// The shortest time between two checks, so that a `check_every` of zero neither spins nor,
+/// in [`rotate_periodically`], panics.
+pub const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A running [`spawn_rotation`] thread. Dropping the handle stops it too, without waiting.
+pub struct RotationHandle {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl RotationHandle {
+    /// Stops the thread and waits for it to finish.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Checks the policy every `check_every` on a background thread, rotating the shared manager's
+/// key when it is due. `on_rotate` is called with each new version, after the lock is
+/// released, for example to persist the keyring. Checks are at least [`MIN_CHECK_INTERVAL`]
+/// apart, however short `check_every` is.
+pub fn spawn_rotation(
+    manager: Arc<Mutex<KeyManager>>,
+    policy: RotationPolicy,
+    check_every: Duration,
+    on_rotate: impl Fn(u32) + Send + 'static,
+) -> RotationHandle {
+    let check_every = check_every.max(MIN_CHECK_INTERVAL);
+    let (stop, stopped) = mpsc::channel();
+    let thread = thread::spawn(move || loop {
+        let rotated = policy.rotate_if_due(&mut manager.lock().unwrap());
+        if let Some(version) = rotated {
+            on_rotate(version);
+        }
+        match stopped.recv_timeout(check_every) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    });
+    RotationHandle { stop, thread }
+}
+
+#[cfg(feature = "tokio")]
+pub use self::asynchronous::rotate_periodically;
+
+#[cfg(feature = "tokio")]
+mod asynchronous {
+    use super::*;
+
+    /// The async version of [`spawn_rotation`]. It runs until the task is dropped or aborted,
+    /// so it is meant to be given to `tokio::spawn`.
+    pub async fn rotate_periodically(
+        manager: Arc<Mutex<KeyManager>>,
+        policy: RotationPolicy,
+        check_every: Duration,
+        on_rotate: impl Fn(u32),
+    ) {
+        let mut interval = tokio::time::interval(check_every.max(MIN_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            let rotated = policy.rotate_if_due(&mut manager.lock().unwrap());
+            if let Some(version) = rotated {
+                on_rotate(version);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mode;
+
+    use super::*;
+
+    #[test]
+    fn test_rotate_if_due() {
+        let mut manager = KeyManager::new();
+        let daily = RotationPolicy::new(Duration::from_secs(24 * 60 * 60));
+        assert_eq!(daily.rotate_if_due(&mut manager), Some(1));
+        assert_eq!(daily.rotate_if_due(&mut manager), None);
+
+        let always = RotationPolicy::new(Duration::ZERO).with_key_policy(KeyPolicy {
+            max_invocations: Some(1),
+            ..KeyPolicy::default()
+        });
+        assert_eq!(always.rotate_if_due(&mut manager), Some(2));
+        assert_eq!(manager.get(2).unwrap().policy.max_invocations, Some(1));
+    }
+
+    #[test]
+    fn test_background_rotation_and_lazy_refresh() {
+        let manager = Arc::new(Mutex::new(KeyManager::new()));
+        manager.lock().unwrap().generate(KeyPolicy::default());
+        let old = manager
+            .lock()
+            .unwrap()
+            .encrypt(Mode::Cbc, b"stored record".to_vec())
+            .unwrap();
+
+        let (rotated, rotations) = mpsc::channel();
+        let handle = spawn_rotation(
+            manager.clone(),
+            RotationPolicy::new(Duration::ZERO),
+            Duration::from_millis(5),
+            move |version| rotated.send(version).unwrap(),
+        );
+        assert_eq!(rotations.recv().unwrap(), 2);
+        handle.stop();
+
+        let mut manager = manager.lock().unwrap();
+        let result = manager.decrypt_and_refresh(&old).unwrap();
+        assert_eq!(result.plain_text, b"stored record");
+        let refreshed = result.refreshed.unwrap();
+        assert!(manager.is_current(&refreshed));
+        assert_eq!(manager.decrypt(&refreshed).unwrap(), b"stored record");
+        assert_eq!(
+            manager.decrypt_and_refresh(&refreshed).unwrap().refreshed,
+            None
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_rotation() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let manager = Arc::new(Mutex::new(KeyManager::new()));
+            let (rotated, mut rotations) = tokio::sync::mpsc::unbounded_channel();
+            let task = tokio::spawn(rotate_periodically(
+                manager.clone(),
+                RotationPolicy::new(Duration::from_secs(60 * 60)),
+                Duration::ZERO,
+                move |version| rotated.send(version).unwrap(),
+            ));
+            assert_eq!(rotations.recv().await, Some(1));
+            task.abort();
+            assert_eq!(manager.lock().unwrap().current_version(), Some(1));
+        });
+    }
+}
@@ -0,0 +1,78 @@
+//! The simplest way to encrypt something safely: [`seal`] and [`open`], and nothing else.
+//!
+//! This is modelled on NaCl's `crypto_secretbox`. There are no modes, nonces, or associated
+//! data to get wrong: [`seal`] picks a random nonce, encrypts with AES-128-GCM, and puts the
+//! nonce in front of the output; [`open`] either returns exactly the plaintext that was sealed
+//! or fails. Keys come from [`generate_key`], or from a key manager.
+//!
+//! ```text
+//! nonce (12 bytes) | ciphertext | tag (16 bytes)
+//! ```
+//!
+//! Random 96-bit nonces are safe for up to about 2^32 messages under one key. Beyond that, rotate
+//! the key, or use [`batch`](crate::batch), whose nonces can't collide within a batch.
+
+use crate::{
+    gcm::{AuthenticationError, GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+pub const KEY_SIZE: usize = BLOCK_SIZE;
+
+/// How much longer a sealed box is than its plaintext.
+pub const OVERHEAD: usize = GCM_NONCE_SIZE + TAG_SIZE;
+
+/// A fresh random key.
+pub fn generate_key() -> [u8; KEY_SIZE] {
+    utils::create_rand_key()
+}
+
+/// Encrypts and authenticates `plain_text`.
+pub fn seal(key: &[u8; KEY_SIZE], plain_text: &[u8]) -> Vec<u8> {
+    let nonce = utils::create_rand_gcm_nonce();
+    let mut sealed = Vec::with_capacity(plain_text.len() + OVERHEAD);
+    sealed.extend_from_slice(&nonce);
+    GcmKey::new(*key).seal_into(nonce, plain_text, &[], &mut sealed);
+    sealed
+}
+
+/// Checks and decrypts a box made by [`seal`]. Fails if it was modified in any way, truncated,
+/// or sealed under a different key.
+pub fn open(key: &[u8; KEY_SIZE], sealed: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+    if sealed.len() < OVERHEAD {
+        return Err(AuthenticationError);
+    }
+    let (nonce, rest) = sealed.split_at(GCM_NONCE_SIZE);
+    GcmKey::new(*key).open(nonce.try_into().unwrap(), rest, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = generate_key();
+        let sealed = seal(&key, b"just keep it safe");
+        assert_eq!(sealed.len(), 17 + OVERHEAD);
+        assert_eq!(open(&key, &sealed), Ok(b"just keep it safe".to_vec()));
+        assert_ne!(seal(&key, b"just keep it safe"), sealed);
+        assert_eq!(open(&key, &seal(&key, b"")), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_open_rejects_anything_else() {
+        let key = generate_key();
+        let sealed = seal(&key, b"just keep it safe");
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert_eq!(open(&key, &tampered), Err(AuthenticationError));
+        }
+        assert_eq!(open(&generate_key(), &sealed), Err(AuthenticationError));
+        assert_eq!(
+            open(&key, &sealed[..OVERHEAD - 1]),
+            Err(AuthenticationError)
+        );
+    }
+}
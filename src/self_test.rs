@@ -0,0 +1,280 @@
+//! Known-answer tests for startup health checks.
+//!
+//! Many compliance regimes (FIPS 140 among them) require a cryptographic module to check at
+//! startup that its algorithms still give the published answers, and to refuse to run if not.
+//! [`self_test`] does that for everything compiled into this crate: it runs a published test
+//! vector through every algorithm, and every [`Backend`] for the ones that have backends, and
+//! reports each result:
+//!
+//! ```
+//! let report = aes_modes::self_test::self_test();
+//! assert!(report.passed(), "{}", report);
+//! ```
+//!
+//! A test that panics counts as failed, so a backend that refuses to work (for example OpenSSL
+//! with a misconfigured provider) shows up in the report instead of taking the service down.
+
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{
+    backend::Backend, cmac_prf::aes_cmac_prf_128, ctr::CtrParams, hctr2::Hctr2, siv,
+    tweakable::Xex, utils, warnings, BLOCK_SIZE,
+};
+
+/// The outcome of one known-answer test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KnownAnswer {
+    pub algorithm: &'static str,
+    /// The backend tested, for the algorithms that run on one.
+    pub backend: Option<Backend>,
+    pub passed: bool,
+}
+
+/// The outcomes of all the known-answer tests [`self_test`] ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub results: Vec<KnownAnswer>,
+}
+
+impl SelfTestReport {
+    /// Whether every test passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &KnownAnswer> {
+        self.results.iter().filter(|result| !result.passed)
+    }
+
+    /// Runs `test`, recording a panic as a failure.
+    fn run(
+        &mut self,
+        algorithm: &'static str,
+        backend: Option<Backend>,
+        test: impl FnOnce() -> bool,
+    ) {
+        let passed =
+            panic::catch_unwind(AssertUnwindSafe(|| warnings::quietly(test))).unwrap_or(false);
+        self.results.push(KnownAnswer {
+            algorithm,
+            backend,
+            passed,
+        });
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failures: Vec<String> = self
+            .failures()
+            .map(|failure| match failure.backend {
+                Some(backend) => format!("{} ({})", failure.algorithm, backend.name()),
+                None => failure.algorithm.to_string(),
+            })
+            .collect();
+        if failures.is_empty() {
+            write!(f, "all {} known-answer tests passed", self.results.len())
+        } else {
+            write!(
+                f,
+                "{} of {} known-answer tests failed: {}",
+                failures.len(),
+                self.results.len(),
+                failures.join(", ")
+            )
+        }
+    }
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    utils::hex_decode(s).unwrap()
+}
+
+fn key(s: &str) -> [u8; BLOCK_SIZE] {
+    hex(s).try_into().unwrap()
+}
+
+/// Runs the known-answer tests for every algorithm and backend compiled in. Each is a single
+/// short message, so this is cheap enough to run at every startup.
+pub fn self_test() -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+
+    for backend in Backend::available() {
+        let some = Some(backend);
+
+        // FIPS-197 Appendix C.1.
+        report.run("aes-128", some, || {
+            let context = backend.context(key("000102030405060708090a0b0c0d0e0f"));
+            let plain_text = key("00112233445566778899aabbccddeeff");
+            let cipher_text = context.encrypt_block(plain_text);
+            cipher_text == key("69c4e0d86a7b0430d8cdb78070b4c55a")
+                && context.decrypt_block(cipher_text) == plain_text
+        });
+
+        // NIST SP 800-38A F.2.1, the first block. Padding adds a second one.
+        report.run("aes-128-cbc", some, || {
+            let context = backend.context(key("2b7e151628aed2a6abf7158809cf4f3c"));
+            let plain_text = hex("6bc1bee22e409f96e93d7e117393172a");
+            let cipher_text = context
+                .cbc_encrypt_with_iv(plain_text.clone(), key("000102030405060708090a0b0c0d0e0f"));
+            cipher_text[16..32] == hex("7649abac8119b246cee98e9b12e9197d")
+                && context.cbc_decrypt(cipher_text) == Ok(plain_text)
+        });
+
+        // Test case 2 of the GCM specification.
+        report.run("aes-128-gcm", some, || {
+            let context = backend.context([0u8; BLOCK_SIZE]);
+            let cipher_text = context.gcm_encrypt(vec![0u8; 16], [0u8; 12], &[]);
+            cipher_text == hex("0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf")
+                && context.gcm_decrypt(cipher_text, [0u8; 12], &[]) == Ok(vec![0u8; 16])
+        });
+    }
+
+    // NIST SP 800-38A F.5.1, the first block.
+    report.run("aes-128-ctr", None, || {
+        let params = CtrParams::new()
+            .with_big_endian_counter()
+            .with_initial_counter(0xf8f9fafbfcfdfeff);
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172a");
+        params.apply_keystream(
+            key("2b7e151628aed2a6abf7158809cf4f3c"),
+            &hex("f0f1f2f3f4f5f6f7"),
+            &mut data,
+        );
+        data == hex("874d6191b620e3261bef6864990db6ce")
+    });
+
+    // RFC 4493 example 2, through the RFC 4615 PRF, which is CMAC for 16-byte keys.
+    report.run("aes-cmac", None, || {
+        aes_cmac_prf_128(
+            &hex("2b7e151628aed2a6abf7158809cf4f3c"),
+            &hex("6bc1bee22e409f96e93d7e117393172a"),
+        ) == key("070a16b46b4d4144f79bdd9dd04a287c")
+    });
+
+    // RFC 5297 A.1.
+    report.run("aes-siv", None, || {
+        let siv_key = hex("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff")
+            .try_into()
+            .unwrap();
+        let ad = hex("101112131415161718191a1b1c1d1e1f2021222324252627");
+        let plain_text = hex("112233445566778899aabbccddee");
+        let cipher_text = siv::siv_encrypt(&plain_text, &siv_key, &[&ad]);
+        cipher_text == hex("85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c")
+            && siv::siv_decrypt(&cipher_text, &siv_key, &[&ad]) == Ok(plain_text)
+    });
+
+    // IEEE 1619 XTS-AES-128 vector 2.
+    report.run("aes-xts", None, || {
+        let xex = Xex::new([0x11; BLOCK_SIZE], [0x22; BLOCK_SIZE]);
+        let mut data_unit = [0u8; BLOCK_SIZE];
+        data_unit[..5].copy_from_slice(&[0x33; 5]);
+        let mut data = [0x44u8; 32];
+        xex.encrypt_unit(&data_unit, &mut data);
+        let passed =
+            data[..] == hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0");
+        xex.decrypt_unit(&data_unit, &mut data);
+        passed && data == [0x44u8; 32]
+    });
+
+    // The first HCTR2_AES128 vector of the reference implementation.
+    report.run("hctr2", None, || {
+        let hctr2 = Hctr2::new(key("8171c4d67e21d6a250235be986da8e3f"));
+        let plain_text = hex("5477797774f15a78b691d443b14be3560b88f5b95977549508e85ebd7f31bb");
+        let mut data = plain_text.clone();
+        hctr2.encrypt(&hex("10"), &mut data);
+        let passed = data == hex("0a999af4e64b89473ae7ce0c1e06a03275e74f5640af8f15af291e04a097cd");
+        hctr2.decrypt(&hex("10"), &mut data);
+        passed && data == plain_text
+    });
+
+    // The first Adiantum_XChaCha12_32_AES256 vector of the reference implementation.
+    #[cfg(feature = "adiantum")]
+    report.run("adiantum", None, || {
+        let adiantum = crate::adiantum::Adiantum::new(
+            hex("7fc7152ae1f5fda4176769aec92bba82a314e7cfadfd8540da7b7d24bdf17d07")
+                .try_into()
+                .unwrap(),
+        );
+        let mut data = hex("9be382c65ac19fad4659b80bacc857a0");
+        adiantum.encrypt(&[], &mut data);
+        data == hex("820ae44477dd9a186f80288b25070e85")
+    });
+
+    // GB/T 32907 example 1.
+    #[cfg(feature = "sm4")]
+    report.run("sm4", None, || {
+        use crate::generic::{self, cipher::KeyInit};
+        let sm4_key = key("0123456789abcdeffedcba9876543210");
+        let cipher_text =
+            generic::ecb_encrypt(&generic::Sm4::new(&sm4_key.into()), sm4_key.to_vec());
+        cipher_text[..16] == hex("681edf34d206965e86b3e94f536e4246")
+    });
+
+    // RFC 3713 Appendix A, the 128-bit key.
+    #[cfg(feature = "camellia")]
+    report.run("camellia", None, || {
+        use crate::generic::{self, cipher::KeyInit};
+        let camellia_key = key("0123456789abcdeffedcba9876543210");
+        let cipher = generic::Camellia128::new(&camellia_key.into());
+        generic::ecb_encrypt(&cipher, camellia_key.to_vec())[..16]
+            == hex("67673138549669730857065648eabe43")
+    });
+
+    // NIST SP 800-67 Appendix B. Run directly on the cipher, since the legacy functions warn.
+    #[cfg(feature = "legacy")]
+    report.run("3des", None, || {
+        use crate::generic::{self, cipher::KeyInit};
+        let tdes_key = hex("0123456789abcdef23456789abcdef01456789abcdef0123");
+        let cipher = des::TdesEde3::new_from_slice(&tdes_key).unwrap();
+        generic::ecb_encrypt(&cipher, b"The qufc".to_vec())[..8] == hex("a826fd8ce53b855f")
+    });
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test_passes() {
+        let report = self_test();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(
+            report.results.len(),
+            5 + 3 * Backend::available().len()
+                + cfg!(feature = "adiantum") as usize
+                + cfg!(feature = "sm4") as usize
+                + cfg!(feature = "camellia") as usize
+                + cfg!(feature = "legacy") as usize
+        );
+        assert_eq!(
+            report.to_string(),
+            format!("all {} known-answer tests passed", report.results.len())
+        );
+    }
+
+    #[test]
+    fn test_reports_failures() {
+        let mut report = SelfTestReport::default();
+        report.run("good", None, || true);
+        report.run("wrong", Some(Backend::RustCryptoAes), || false);
+        report.run("panics", None, || panic!("provider unavailable"));
+        assert!(!report.passed());
+        assert_eq!(
+            report
+                .failures()
+                .map(|failure| failure.algorithm)
+                .collect::<Vec<_>>(),
+            ["wrong", "panics"]
+        );
+        assert_eq!(
+            report.to_string(),
+            "2 of 3 known-answer tests failed: wrong (rustcrypto), panics"
+        );
+    }
+}
@@ -0,0 +1,621 @@
+//! Encrypting a conversation rather than a single message.
+//!
+//! When people ask "how do I encrypt a TCP stream", GCM on its own is not quite the answer.
+//! Something still has to choose a fresh nonce for every message, stop an attacker from
+//! replaying, dropping or reordering messages, and stop one key from being used forever.
+//! A [`Session`] does all of that on top of [`gcm`](crate::gcm):
+//!
+//! - Data is split into _records_. Each record carries an explicit 64-bit sequence number,
+//!   which doubles as the GCM nonce, so nonces can never repeat under one key.
+//! - The record header (sequence number and length) is authenticated as associated data, and
+//!   the receiver only accepts the next sequence number it expects.
+//! - Each direction has its own keys, derived from the shared secret with HKDF.
+//! - After every `rekey_interval` records, both sides ratchet their keys forward through HKDF.
+//!   The old keys are overwritten, so a key compromised later can't decrypt older records.
+//!
+//! That suits a reliable, ordered transport such as TCP. Over UDP, records get lost,
+//! duplicated and reordered on the way, and insisting on the next sequence number would throw
+//! away everything after the first lost one. A [`DatagramSession`] uses the same records, under
+//! keys of its own, but accepts them in any order, as DTLS does: its [`ReplayWindow`] remembers
+//! which of the last [`REPLAY_WINDOW_SIZE`] sequence numbers have been seen, so that each record
+//! is accepted at most once, and anything older than the window is dropped. As each record's
+//! sequence number is its nonce, every record can be decrypted on its own.
+
+use std::{error::Error, fmt};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE, TAG_SIZE},
+    BLOCK_SIZE,
+};
+
+/// How many records are protected by one key before both sides ratchet to the next.
+pub const DEFAULT_REKEY_INTERVAL: u64 = 1 << 16;
+
+/// Every record starts with an 8-byte sequence number and a 4-byte body length.
+pub const HEADER_SIZE: usize = 12;
+
+/// The largest plaintext a single record may carry.
+pub const MAX_RECORD_SIZE: usize = 1 << 24;
+
+/// How many sequence numbers, counting back from the highest accepted, a [`ReplayWindow`]
+/// keeps track of.
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// How many key epochs a datagram record may jump ahead of the receiver, so that a forged
+/// sequence number can't make it ratchet its keys for ever before authentication fails.
+const MAX_EPOCH_SKIP: u64 = 16;
+
+const CHAIN_KEY_SIZE: usize = 32;
+const RATCHET_LABEL: &[u8] = b"aes-modes session ratchet";
+
+/// Which end of the conversation we are. The two ends must pick different roles, so that they
+/// don't encrypt under the same keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Why a record was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionError {
+    /// The record is shorter than its header claims, or has trailing bytes.
+    Truncated,
+    /// A record with this sequence number was already accepted.
+    Replayed { sequence: u64 },
+    /// A datagram record too far behind the newest one for the replay window to tell whether
+    /// it was already accepted.
+    Stale { sequence: u64 },
+    /// A record was skipped, or records arrived out of order.
+    OutOfOrder { expected: u64, received: u64 },
+    /// The record was modified, or was not encrypted by the other end of this session.
+    Authentication,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Truncated => f.write_str("truncated record"),
+            SessionError::Replayed { sequence } => write!(f, "record {} was replayed", sequence),
+            SessionError::Stale { sequence } => {
+                write!(f, "record {} is older than the replay window", sequence)
+            }
+            SessionError::OutOfOrder { expected, received } => {
+                write!(f, "expected record {}, received {}", expected, received)
+            }
+            SessionError::Authentication => f.write_str("record failed authentication"),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+/// The key schedule and sequence number for traffic going one way.
+#[derive(Clone)]
+struct Direction {
+    chain_key: [u8; CHAIN_KEY_SIZE],
+    record_key: [u8; BLOCK_SIZE],
+    sequence: u64,
+}
+
+impl Direction {
+    fn new(hkdf: &Hkdf<Sha256>, label: &[u8]) -> Self {
+        let mut chain_key = [0u8; CHAIN_KEY_SIZE];
+        hkdf.expand(label, &mut chain_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        let mut direction = Direction {
+            chain_key,
+            record_key: [0u8; BLOCK_SIZE],
+            sequence: 0,
+        };
+        direction.ratchet();
+        direction
+    }
+
+    /// Derives the next chain key and record key from the current chain key, replacing both.
+    /// HKDF is one-way, so there is no getting back to the keys that were just overwritten.
+    fn ratchet(&mut self) {
+        let hkdf = Hkdf::<Sha256>::from_prk(&self.chain_key)
+            .expect("the chain key is exactly one hash long");
+        let mut output = [0u8; CHAIN_KEY_SIZE + BLOCK_SIZE];
+        hkdf.expand(RATCHET_LABEL, &mut output)
+            .expect("48 bytes is a valid HKDF-SHA256 output length");
+
+        self.chain_key.copy_from_slice(&output[..CHAIN_KEY_SIZE]);
+        self.record_key.copy_from_slice(&output[CHAIN_KEY_SIZE..]);
+    }
+
+    fn advance(&mut self, rekey_interval: u64) {
+        self.sequence += 1;
+        if self.sequence.is_multiple_of(rekey_interval) {
+            self.ratchet();
+        }
+    }
+}
+
+/// The nonce for a record is just its sequence number, padded out to 96 bits.
+fn nonce(sequence: u64) -> [u8; GCM_NONCE_SIZE] {
+    let mut nonce = [0u8; GCM_NONCE_SIZE];
+    nonce[GCM_NONCE_SIZE - 8..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+/// Reads the total length of a record (header included) from its header, so that readers of a
+/// byte stream know how much more to read before calling [`Session::open`].
+pub fn record_len(header: &[u8; HEADER_SIZE]) -> usize {
+    let body_len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    HEADER_SIZE + body_len as usize
+}
+
+/// One end of an encrypted conversation.
+pub struct Session {
+    send: Direction,
+    receive: Direction,
+    rekey_interval: u64,
+}
+
+/// Shows the sequence numbers only, never the keys.
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("send_sequence", &self.send.sequence)
+            .field("receive_sequence", &self.receive.sequence)
+            .field("rekey_interval", &self.rekey_interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Session {
+    /// Starts a session from a secret shared with the other end (at least 16 random bytes).
+    pub fn new(shared_secret: &[u8], role: Role) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, shared_secret);
+        let initiator_to_responder = Direction::new(&hkdf, b"aes-modes session i2r");
+        let responder_to_initiator = Direction::new(&hkdf, b"aes-modes session r2i");
+
+        let (send, receive) = match role {
+            Role::Initiator => (initiator_to_responder, responder_to_initiator),
+            Role::Responder => (responder_to_initiator, initiator_to_responder),
+        };
+
+        Session {
+            send,
+            receive,
+            rekey_interval: DEFAULT_REKEY_INTERVAL,
+        }
+    }
+
+    /// Changes how many records each key protects. Both ends must use the same interval.
+    pub fn with_rekey_interval(mut self, rekey_interval: u64) -> Self {
+        assert!(rekey_interval > 0, "the rekey interval can't be zero");
+        self.rekey_interval = rekey_interval;
+        self
+    }
+
+    /// Encrypts the next outgoing record: `sequence | length | ciphertext | tag`.
+    pub fn seal(&mut self, plain_text: Vec<u8>) -> Vec<u8> {
+        seal_record(&mut self.send, self.rekey_interval, plain_text)
+    }
+
+    /// Decrypts the next incoming record. Anything except exactly the next record in sequence
+    /// is rejected, and a rejected record leaves the session untouched.
+    pub fn open(&mut self, record: &[u8]) -> Result<Vec<u8>, SessionError> {
+        let (sequence, header, body) = parse_record(record)?;
+        let expected = self.receive.sequence;
+        if sequence < expected {
+            return Err(SessionError::Replayed { sequence });
+        }
+        if sequence > expected {
+            return Err(SessionError::OutOfOrder {
+                expected,
+                received: sequence,
+            });
+        }
+
+        let plain_text = gcm_decrypt(
+            body.to_vec(),
+            self.receive.record_key,
+            nonce(sequence),
+            header,
+        )
+        .map_err(|_| SessionError::Authentication)?;
+
+        self.receive.advance(self.rekey_interval);
+        Ok(plain_text)
+    }
+}
+
+fn seal_record(direction: &mut Direction, rekey_interval: u64, plain_text: Vec<u8>) -> Vec<u8> {
+    assert!(
+        plain_text.len() <= MAX_RECORD_SIZE,
+        "records are limited to {} bytes",
+        MAX_RECORD_SIZE
+    );
+
+    let sequence = direction.sequence;
+    let body_len = (plain_text.len() + TAG_SIZE) as u32;
+    let mut record = sequence.to_be_bytes().to_vec();
+    record.extend_from_slice(&body_len.to_be_bytes());
+
+    let body = gcm_encrypt(plain_text, direction.record_key, nonce(sequence), &record);
+    record.extend(body);
+
+    direction.advance(rekey_interval);
+    record
+}
+
+/// Splits a record into its sequence number, header and body.
+fn parse_record(record: &[u8]) -> Result<(u64, &[u8; HEADER_SIZE], &[u8]), SessionError> {
+    let (header, body) = record
+        .split_first_chunk::<HEADER_SIZE>()
+        .ok_or(SessionError::Truncated)?;
+    if record_len(header) != record.len() {
+        return Err(SessionError::Truncated);
+    }
+    let mut sequence_bytes = [0u8; 8];
+    sequence_bytes.copy_from_slice(&header[..8]);
+    Ok((u64::from_be_bytes(sequence_bytes), header, body))
+}
+
+/// Which of the recent sequence numbers have been accepted, as in DTLS (RFC 6347, 4.1.2.6).
+///
+/// The window covers the highest sequence number accepted so far and the
+/// [`REPLAY_WINDOW_SIZE`] - 1 before it. Anything newer is fresh, anything inside is fresh
+/// unless its bit is set, and anything older is stale, since there is no telling any more.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - i` was accepted.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The highest sequence number accepted so far.
+    pub fn highest(&self) -> Option<u64> {
+        self.highest
+    }
+
+    /// Whether a record with this sequence number may still be accepted. Check before
+    /// authenticating the record, and only [`accept`](Self::accept) it after.
+    pub fn check(&self, sequence: u64) -> Result<(), SessionError> {
+        let Some(highest) = self.highest else {
+            return Ok(());
+        };
+        if sequence > highest {
+            return Ok(());
+        }
+        let age = highest - sequence;
+        if age >= REPLAY_WINDOW_SIZE {
+            Err(SessionError::Stale { sequence })
+        } else if self.seen & (1 << age) != 0 {
+            Err(SessionError::Replayed { sequence })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks a sequence number as accepted, sliding the window forward if it is the newest.
+    pub fn accept(&mut self, sequence: u64) {
+        match self.highest {
+            Some(highest) if sequence <= highest => {
+                let age = highest - sequence;
+                if age < REPLAY_WINDOW_SIZE {
+                    self.seen |= 1 << age;
+                }
+            }
+            highest => {
+                let shift = highest.map_or(REPLAY_WINDOW_SIZE, |highest| sequence - highest);
+                self.seen = if shift < REPLAY_WINDOW_SIZE {
+                    (self.seen << shift) | 1
+                } else {
+                    1
+                };
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+/// One end of an encrypted conversation over a transport that may lose, duplicate and reorder
+/// records. See the [module documentation](self).
+pub struct DatagramSession {
+    send: Direction,
+    /// The receive keys for epoch `receive.sequence / rekey_interval`.
+    receive: Direction,
+    /// The record key of the epoch before, for records that arrive after the receiver has
+    /// moved on.
+    previous_key: Option<[u8; BLOCK_SIZE]>,
+    window: ReplayWindow,
+    rekey_interval: u64,
+}
+
+impl DatagramSession {
+    /// Starts a session from a secret shared with the other end (at least 16 random bytes).
+    /// Its keys differ from those of a [`Session`] on the same secret.
+    pub fn new(shared_secret: &[u8], role: Role) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, shared_secret);
+        let initiator_to_responder = Direction::new(&hkdf, b"aes-modes datagram i2r");
+        let responder_to_initiator = Direction::new(&hkdf, b"aes-modes datagram r2i");
+
+        let (send, receive) = match role {
+            Role::Initiator => (initiator_to_responder, responder_to_initiator),
+            Role::Responder => (responder_to_initiator, initiator_to_responder),
+        };
+
+        DatagramSession {
+            send,
+            receive,
+            previous_key: None,
+            window: ReplayWindow::new(),
+            rekey_interval: DEFAULT_REKEY_INTERVAL,
+        }
+    }
+
+    /// Changes how many records each key protects. Both ends must use the same interval.
+    ///
+    /// # Panics
+    ///
+    /// If `rekey_interval` is less than [`REPLAY_WINDOW_SIZE`], since the receiver keeps the
+    /// keys of only two epochs for the records in its window.
+    pub fn with_rekey_interval(mut self, rekey_interval: u64) -> Self {
+        assert!(
+            rekey_interval >= REPLAY_WINDOW_SIZE,
+            "the rekey interval must cover the replay window"
+        );
+        self.rekey_interval = rekey_interval;
+        self
+    }
+
+    /// Encrypts the next outgoing record, in the same format as [`Session::seal`]. Each record
+    /// goes in a datagram of its own.
+    pub fn seal(&mut self, plain_text: Vec<u8>) -> Vec<u8> {
+        seal_record(&mut self.send, self.rekey_interval, plain_text)
+    }
+
+    /// Decrypts a record that may have arrived out of order. A record already accepted, or too
+    /// old for the replay window, is rejected, and a rejected record leaves the session
+    /// untouched.
+    pub fn open(&mut self, record: &[u8]) -> Result<Vec<u8>, SessionError> {
+        let (sequence, header, body) = parse_record(record)?;
+        self.window.check(sequence)?;
+
+        let current_epoch = self.receive.sequence / self.rekey_interval;
+        let epoch = sequence / self.rekey_interval;
+        let mut ahead = None;
+        let key = if epoch == current_epoch {
+            self.receive.record_key
+        } else if epoch + 1 == current_epoch {
+            self.previous_key.ok_or(SessionError::Stale { sequence })?
+        } else if epoch < current_epoch {
+            return Err(SessionError::Stale { sequence });
+        } else if epoch - current_epoch > MAX_EPOCH_SKIP {
+            return Err(SessionError::OutOfOrder {
+                expected: self.window.highest().map_or(0, |highest| highest + 1),
+                received: sequence,
+            });
+        } else {
+            // The keys only move forward once the record proves genuine.
+            let mut direction = self.receive.clone();
+            let mut previous_key = direction.record_key;
+            for _ in current_epoch..epoch {
+                previous_key = direction.record_key;
+                direction.ratchet();
+            }
+            direction.sequence = epoch * self.rekey_interval;
+            let key = direction.record_key;
+            ahead = Some((direction, previous_key));
+            key
+        };
+
+        let plain_text = gcm_decrypt(body.to_vec(), key, nonce(sequence), header)
+            .map_err(|_| SessionError::Authentication)?;
+
+        if let Some((direction, previous_key)) = ahead {
+            self.receive = direction;
+            self.previous_key = Some(previous_key);
+        }
+        self.window.accept(sequence);
+        Ok(plain_text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"correct horse battery staple";
+
+    fn pair() -> (Session, Session) {
+        (
+            Session::new(SECRET, Role::Initiator),
+            Session::new(SECRET, Role::Responder),
+        )
+    }
+
+    #[test]
+    fn test_debug_hides_the_keys() {
+        let (mut alice, _) = pair();
+        alice.seal(b"hello".to_vec());
+        let debug = format!("{:?}", alice);
+        assert!(debug.starts_with("Session { send_sequence: 1, receive_sequence: 0, "));
+        for key in [alice.send.record_key, alice.receive.record_key] {
+            assert!(!debug.contains(&format!("{:?}", key)));
+        }
+    }
+
+    #[test]
+    fn test_conversation() {
+        let (mut alice, mut bob) = pair();
+
+        let record = alice.seal(b"Hello, Bob".to_vec());
+        assert_eq!(
+            record_len(record[..HEADER_SIZE].try_into().unwrap()),
+            record.len()
+        );
+        assert_eq!(bob.open(&record), Ok(b"Hello, Bob".to_vec()));
+
+        let record = bob.seal(b"Hello, Alice".to_vec());
+        assert_eq!(alice.open(&record), Ok(b"Hello, Alice".to_vec()));
+
+        // Both ends start at sequence number zero, but under different keys.
+        let from_alice = alice.seal(b"same".to_vec());
+        let from_bob = bob.seal(b"same".to_vec());
+        assert_ne!(from_alice[HEADER_SIZE..], from_bob[HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn test_replay_and_reordering() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.seal(b"first".to_vec());
+        let second = alice.seal(b"second".to_vec());
+
+        assert_eq!(
+            bob.open(&second),
+            Err(SessionError::OutOfOrder {
+                expected: 0,
+                received: 1
+            })
+        );
+        assert_eq!(bob.open(&first), Ok(b"first".to_vec()));
+        assert_eq!(
+            bob.open(&first),
+            Err(SessionError::Replayed { sequence: 0 })
+        );
+        assert_eq!(bob.open(&second), Ok(b"second".to_vec()));
+    }
+
+    #[test]
+    fn test_tampering() {
+        let (mut alice, mut bob) = pair();
+        let record = alice.seal(b"Transfer $10".to_vec());
+
+        let mut tampered = record.clone();
+        tampered[HEADER_SIZE] ^= 1;
+        assert_eq!(bob.open(&tampered), Err(SessionError::Authentication));
+        assert_eq!(
+            bob.open(&record[..record.len() - 1]),
+            Err(SessionError::Truncated)
+        );
+
+        // Nothing was accepted, so the genuine record still opens.
+        assert_eq!(bob.open(&record), Ok(b"Transfer $10".to_vec()));
+
+        let mut stranger = Session::new(b"some other secret", Role::Responder);
+        assert_eq!(stranger.open(&record), Err(SessionError::Authentication));
+    }
+
+    #[test]
+    fn test_rekeying() {
+        let mut alice = Session::new(SECRET, Role::Initiator).with_rekey_interval(3);
+        let mut bob = Session::new(SECRET, Role::Responder).with_rekey_interval(3);
+
+        let first_key = alice.send.record_key;
+        for i in 0..10u8 {
+            let record = alice.seal(vec![i; 20]);
+            assert_eq!(bob.open(&record), Ok(vec![i; 20]));
+        }
+        assert_ne!(alice.send.record_key, first_key);
+        assert_eq!(alice.send.record_key, bob.receive.record_key);
+
+        // A receiver that doesn't rekey at the same points can't follow along.
+        let mut alice = Session::new(SECRET, Role::Initiator).with_rekey_interval(3);
+        let mut bob = Session::new(SECRET, Role::Responder);
+        for _ in 0..3 {
+            bob.open(&alice.seal(Vec::new())).unwrap();
+        }
+        assert_eq!(
+            bob.open(&alice.seal(Vec::new())),
+            Err(SessionError::Authentication)
+        );
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        for sequence in [5, 3, 4, 70, 7] {
+            window.check(sequence).unwrap();
+            window.accept(sequence);
+        }
+        assert_eq!(window.highest(), Some(70));
+        assert_eq!(window.check(4), Err(SessionError::Stale { sequence: 4 }));
+        assert_eq!(window.check(7), Err(SessionError::Replayed { sequence: 7 }));
+        assert_eq!(
+            window.check(70),
+            Err(SessionError::Replayed { sequence: 70 })
+        );
+        assert_eq!(window.check(6), Err(SessionError::Stale { sequence: 6 }));
+        assert_eq!(window.check(8), Ok(()));
+        assert_eq!(window.check(71), Ok(()));
+
+        // A jump past the whole window forgets everything in it.
+        window.accept(1000);
+        assert_eq!(window.check(70), Err(SessionError::Stale { sequence: 70 }));
+        assert_eq!(window.check(999), Ok(()));
+    }
+
+    #[test]
+    fn test_datagrams_lost_duplicated_and_reordered() {
+        let mut alice = DatagramSession::new(SECRET, Role::Initiator);
+        let mut bob = DatagramSession::new(SECRET, Role::Responder);
+        let records: Vec<Vec<u8>> = (0..5u8).map(|i| alice.seal(vec![i; 10])).collect();
+
+        for i in [2, 0, 4, 1] {
+            assert_eq!(bob.open(&records[i]), Ok(vec![i as u8; 10]));
+        }
+        assert_eq!(
+            bob.open(&records[0]),
+            Err(SessionError::Replayed { sequence: 0 })
+        );
+        // Record 3 was lost, and nothing waits for it.
+        assert_eq!(
+            bob.open(&alice.seal(b"later".to_vec())),
+            Ok(b"later".to_vec())
+        );
+
+        let mut tampered = records[3].clone();
+        tampered[HEADER_SIZE] ^= 1;
+        assert_eq!(bob.open(&tampered), Err(SessionError::Authentication));
+        assert_eq!(bob.open(&records[3]), Ok(vec![3; 10]));
+
+        // Stream and datagram sessions don't share keys.
+        let mut stream = Session::new(SECRET, Role::Responder);
+        assert_eq!(stream.open(&records[0]), Err(SessionError::Authentication));
+    }
+
+    #[test]
+    fn test_datagram_rekeying_with_reordering() {
+        let interval = REPLAY_WINDOW_SIZE;
+        let mut alice = DatagramSession::new(SECRET, Role::Initiator).with_rekey_interval(interval);
+        let mut bob = DatagramSession::new(SECRET, Role::Responder).with_rekey_interval(interval);
+        let records: Vec<Vec<u8>> = (0..4 * interval).map(|_| alice.seal(Vec::new())).collect();
+
+        // Into the next epoch, then back for a late record from the one before.
+        bob.open(&records[70]).unwrap();
+        bob.open(&records[20]).unwrap();
+        // Skipping a whole epoch, after which the records of the first are out of the window.
+        bob.open(&records[200]).unwrap();
+        assert_eq!(
+            bob.open(&records[100]),
+            Err(SessionError::Stale { sequence: 100 })
+        );
+        bob.open(&records[150]).unwrap();
+
+        let mut forged = records[199].clone();
+        forged[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            bob.open(&forged),
+            Err(SessionError::OutOfOrder { .. })
+        ));
+        let mut forged = records[199].clone();
+        forged[..8].copy_from_slice(&(5 * interval).to_be_bytes());
+        assert_eq!(bob.open(&forged), Err(SessionError::Authentication));
+        bob.open(&records[199]).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+//! AES-SIV, deterministic authenticated encryption (RFC 5297).
+//!
+//! Every other mode in this crate needs a nonce, and encrypts the same plaintext differently
+//! each time. Sometimes that is exactly what you don't want: an encrypted file name has to come
+//! out the same every time, or the file can't be looked up by name.
+//!
+//! SIV gets there without giving up integrity. It first computes a MAC (CMAC-based "S2V") over
+//! the associated data and the plaintext, and then uses that MAC as the initial counter for CTR
+//! mode. Equal inputs give equal ciphertexts, which is all that leaks; any other change gives a
+//! completely different ciphertext, and decryption checks the MAC as usual.
+//!
+//! https://www.rfc-editor.org/rfc/rfc5297
+
+use crate::{
+    aes_encrypt,
+    gcm::{constant_time_eq, AuthenticationError},
+    util::{self, gf128_double},
+    BLOCK_SIZE,
+};
+
+/// SIV takes two AES-128 keys, one for S2V and one for CTR.
+pub const SIV_KEY_SIZE: usize = 2 * BLOCK_SIZE;
+
+/// CMAC (NIST SP 800-38B) over AES-128.
+pub(crate) fn cmac(key: &[u8; BLOCK_SIZE], message: &[u8]) -> [u8; BLOCK_SIZE] {
+    let k1 = gf128_double(aes_encrypt([0u8; BLOCK_SIZE], key));
+    let k2 = gf128_double(k1);
+
+    let full_blocks = message.len().div_ceil(BLOCK_SIZE).max(1) - 1;
+    let mut state = [0u8; BLOCK_SIZE];
+    for block in message.chunks(BLOCK_SIZE).take(full_blocks) {
+        state = aes_encrypt(util::xor(&state, block.try_into().unwrap()), key);
+    }
+
+    // The last block is XORed with K1 if it is complete, or padded and XORed with K2 if not.
+    let rest = &message[full_blocks * BLOCK_SIZE..];
+    let mut last = [0u8; BLOCK_SIZE];
+    last[..rest.len()].copy_from_slice(rest);
+    let last = if rest.len() == BLOCK_SIZE {
+        util::xor(&last, &k1)
+    } else {
+        last[rest.len()] = 0x80;
+        util::xor(&last, &k2)
+    };
+    aes_encrypt(util::xor(&state, &last), key)
+}
+
+/// S2V turns a vector of strings (the associated data, then the plaintext) into one MAC.
+fn s2v(key: &[u8; BLOCK_SIZE], associated_data: &[&[u8]], plain_text: &[u8]) -> [u8; BLOCK_SIZE] {
+    let mut d = cmac(key, &[0u8; BLOCK_SIZE]);
+    for data in associated_data {
+        d = util::xor(&gf128_double(d), &cmac(key, data));
+    }
+
+    let t = if plain_text.len() >= BLOCK_SIZE {
+        let mut t = plain_text.to_vec();
+        let end = t.len() - BLOCK_SIZE;
+        for (byte, d) in t[end..].iter_mut().zip(d) {
+            *byte ^= d;
+        }
+        t
+    } else {
+        let mut padded = [0u8; BLOCK_SIZE];
+        padded[..plain_text.len()].copy_from_slice(plain_text);
+        padded[plain_text.len()] = 0x80;
+        util::xor(&gf128_double(d), &padded).to_vec()
+    };
+    cmac(key, &t)
+}
+
+/// CTR with the SIV as the initial counter. Two bits are cleared first so that implementations
+/// can use 64- or 32-bit counter arithmetic.
+fn ctr(key: &[u8; BLOCK_SIZE], siv: [u8; BLOCK_SIZE], data: &[u8]) -> Vec<u8> {
+    let mut counter = u128::from_be_bytes(siv) & !(1 << 63 | 1 << 31);
+    let mut output = Vec::with_capacity(data.len());
+    for block in data.chunks(BLOCK_SIZE) {
+        let keystream = aes_encrypt(counter.to_be_bytes(), key);
+        let start = output.len();
+        output.extend_from_slice(block);
+        util::xor_in_place(&mut output[start..], &keystream[..block.len()]);
+        counter = counter.wrapping_add(1);
+    }
+    output
+}
+
+fn split_key(key: &[u8; SIV_KEY_SIZE]) -> ([u8; BLOCK_SIZE], [u8; BLOCK_SIZE]) {
+    let mut mac_key = [0u8; BLOCK_SIZE];
+    let mut ctr_key = [0u8; BLOCK_SIZE];
+    mac_key.copy_from_slice(&key[..BLOCK_SIZE]);
+    ctr_key.copy_from_slice(&key[BLOCK_SIZE..]);
+    (mac_key, ctr_key)
+}
+
+/// Encrypts deterministically. The output is the 16-byte SIV followed by the ciphertext.
+pub fn siv_encrypt(
+    plain_text: &[u8],
+    key: &[u8; SIV_KEY_SIZE],
+    associated_data: &[&[u8]],
+) -> Vec<u8> {
+    let (mac_key, ctr_key) = split_key(key);
+    let siv = s2v(&mac_key, associated_data, plain_text);
+
+    let mut output = siv.to_vec();
+    output.extend(ctr(&ctr_key, siv, plain_text));
+    output
+}
+
+/// Opposite of siv_encrypt. The plaintext is only returned if the SIV matches.
+pub fn siv_decrypt(
+    cipher_text: &[u8],
+    key: &[u8; SIV_KEY_SIZE],
+    associated_data: &[&[u8]],
+) -> Result<Vec<u8>, AuthenticationError> {
+    if cipher_text.len() < BLOCK_SIZE {
+        return Err(AuthenticationError);
+    }
+    let (mac_key, ctr_key) = split_key(key);
+    let siv: [u8; BLOCK_SIZE] = cipher_text[..BLOCK_SIZE].try_into().unwrap();
+
+    let plain_text = ctr(&ctr_key, siv, &cipher_text[BLOCK_SIZE..]);
+    if !constant_time_eq(&s2v(&mac_key, associated_data, &plain_text), &siv) {
+        return Err(AuthenticationError);
+    }
+    Ok(plain_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_cmac_vectors() {
+        // From NIST SP 800-38B, appendix D.1.
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c").try_into().unwrap();
+        assert_eq!(
+            cmac(&key, &[]).to_vec(),
+            hex("bb1d6929e95937287fa37d129b756746")
+        );
+        assert_eq!(
+            cmac(&key, &hex("6bc1bee22e409f96e93d7e117393172a")).to_vec(),
+            hex("070a16b46b4d4144f79bdd9dd04a287c")
+        );
+    }
+
+    #[test]
+    fn test_rfc_5297_vector() {
+        // Deterministic authenticated encryption example, appendix A.1.
+        let key = hex("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff")
+            .try_into()
+            .unwrap();
+        let ad = hex("101112131415161718191a1b1c1d1e1f2021222324252627");
+        let plain_text = hex("112233445566778899aabbccddee");
+
+        let output = siv_encrypt(&plain_text, &key, &[&ad]);
+        assert_eq!(
+            output,
+            hex("85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c")
+        );
+        assert_eq!(siv_decrypt(&output, &key, &[&ad]), Ok(plain_text));
+    }
+
+    #[test]
+    fn test_siv_rejects_tampering() {
+        let key = [3u8; SIV_KEY_SIZE];
+        let output = siv_encrypt(b"report.pdf", &key, &[b"directory"]);
+        assert_eq!(output, siv_encrypt(b"report.pdf", &key, &[b"directory"]));
+        assert_ne!(output, siv_encrypt(b"report.pdf", &key, &[b"elsewhere"]));
+
+        let mut flipped = output.clone();
+        flipped[BLOCK_SIZE] ^= 1;
+        assert_eq!(
+            siv_decrypt(&flipped, &key, &[b"directory"]),
+            Err(AuthenticationError)
+        );
+        assert_eq!(
+            siv_decrypt(&output, &key, &[b"elsewhere"]),
+            Err(AuthenticationError)
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! A buffer that moves to an encrypted temporary file once it outgrows memory.
+//!
+//! Verifying data before acting on it means holding all of it somewhere first: a whole
+//! [chunked](crate::chunked) stream before [`decrypt_verified`](crate::chunked::decrypt_verified)
+//! releases it, a download before its signature has been checked. Memory runs out for large
+//! inputs, and a plain temporary file would put plaintext, or unauthenticated data, on disk,
+//! where it outlives the process in backups and in freed blocks.
+//!
+//! A [`SpillBuffer`] keeps what is written to it in memory up to a threshold. Past that it
+//! creates a temporary file, readable only by its owner, and continues as a chunked stream
+//! into it, under an ephemeral key that lives on [locked](crate::locked) pages and never
+//! leaves the process. [`SpillBuffer::into_reader`] reads the data back, authenticating the
+//! file as it goes, so someone who can write to the temporary directory can make reading fail
+//! but can't change what is read. The file is deleted when the reader, or the buffer, is
+//! dropped, and at worst, after a crash, what stays behind is ciphertext under a forgotten key.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use zeroize::Zeroize;
+
+use crate::{
+    chunked::{StreamDecryptor, StreamEncryptor},
+    locked::LockedBox,
+    utils, BLOCK_SIZE,
+};
+
+/// A temporary file, deleted when dropped.
+struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    /// Creates a new file with a random name in `directory`, readable only by its owner.
+    fn create(directory: &Path) -> io::Result<Self> {
+        let path = directory.join(format!(
+            "aes-modes-spool-{}",
+            utils::base64url_encode(&utils::create_rand_key())
+        ));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        Ok(TempFile { path, file })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+enum Buffer {
+    Memory(Vec<u8>),
+    Spilled {
+        encryptor: Box<StreamEncryptor<File>>,
+        // Declared after the encryptor, so that the file is deleted after its last write.
+        spool: TempFile,
+    },
+}
+
+/// Holds what is written to it in memory up to a threshold, and in an encrypted temporary
+/// file beyond it. See the [module documentation](self).
+pub struct SpillBuffer {
+    threshold: usize,
+    directory: PathBuf,
+    secret: LockedBox<[u8; BLOCK_SIZE]>,
+    buffer: Buffer,
+    len: u64,
+}
+
+impl SpillBuffer {
+    /// A buffer that spills once it would hold more than `threshold` bytes, into the system's
+    /// temporary directory. A threshold of 0 spills on the first write, and `usize::MAX`
+    /// never does.
+    pub fn new(threshold: usize) -> Self {
+        SpillBuffer {
+            threshold,
+            directory: std::env::temp_dir(),
+            secret: LockedBox::new([0; BLOCK_SIZE]),
+            buffer: Buffer::Memory(Vec::new()),
+            len: 0,
+        }
+    }
+
+    /// Spills into `directory` instead, such as one on an encrypted or RAM-backed volume.
+    pub fn with_directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.directory = directory.into();
+        self
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.buffer, Buffer::Spilled { .. })
+    }
+
+    /// Moves the buffer into a new temporary file, encrypting it on the way.
+    fn spill(&mut self) -> io::Result<()> {
+        let Buffer::Memory(memory) = &mut self.buffer else {
+            return Ok(());
+        };
+        let spool = TempFile::create(&self.directory)?;
+        utils::fill_random(&mut *self.secret);
+        let mut encryptor = Box::new(StreamEncryptor::new(
+            &*self.secret,
+            spool.file.try_clone()?,
+        )?);
+        encryptor.write_all(memory)?;
+        memory.zeroize();
+        self.buffer = Buffer::Spilled { encryptor, spool };
+        Ok(())
+    }
+
+    /// Finishes writing, and reads everything written from the start.
+    pub fn into_reader(self) -> io::Result<SpillReader> {
+        let source = match self.buffer {
+            Buffer::Memory(memory) => Source::Memory(Cursor::new(memory)),
+            Buffer::Spilled { encryptor, spool } => {
+                let mut file = encryptor.finish()?;
+                file.seek(SeekFrom::Start(0))?;
+                Source::File {
+                    decryptor: Box::new(StreamDecryptor::new(&*self.secret, file)?),
+                    _spool: spool,
+                }
+            }
+        };
+        Ok(SpillReader { source })
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.len as usize).saturating_add(buf.len()) > self.threshold {
+            self.spill()?;
+        }
+        match &mut self.buffer {
+            Buffer::Memory(memory) => memory.extend_from_slice(buf),
+            Buffer::Spilled { encryptor, .. } => encryptor.write_all(buf)?,
+        }
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.buffer {
+            Buffer::Memory(_) => Ok(()),
+            Buffer::Spilled { encryptor, .. } => encryptor.flush(),
+        }
+    }
+}
+
+enum Source {
+    Memory(Cursor<Vec<u8>>),
+    File {
+        decryptor: Box<StreamDecryptor<File>>,
+        _spool: TempFile,
+    },
+}
+
+/// Reads back what was written to a [`SpillBuffer`]. A spill file that was changed on disk
+/// fails with [`InvalidData`](io::ErrorKind::InvalidData).
+pub struct SpillReader {
+    source: Source,
+}
+
+impl SpillReader {
+    /// The spill file, if there is one.
+    #[cfg(test)]
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match &self.source {
+            Source::Memory(_) => None,
+            Source::File { _spool, .. } => Some(&_spool.path),
+        }
+    }
+}
+
+impl Read for SpillReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.source {
+            Source::Memory(cursor) => cursor.read(buf),
+            Source::File { decryptor, .. } => decryptor.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spills_past_threshold() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 200) as u8).collect();
+        let mut memory = SpillBuffer::new(data.len());
+        memory.write_all(&data).unwrap();
+        assert!(!memory.is_spilled());
+        let mut read = Vec::new();
+        memory
+            .into_reader()
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        let mut spilled = SpillBuffer::new(1000);
+        for piece in data.chunks(300) {
+            spilled.write_all(piece).unwrap();
+        }
+        assert!(spilled.is_spilled());
+        assert_eq!(spilled.len(), data.len() as u64);
+        let reader = spilled.into_reader().unwrap();
+        let path = reader.path().unwrap().to_path_buf();
+        let on_disk = fs::read(&path).unwrap();
+        assert!(!on_disk.windows(16).any(|window| window == &data[..16]));
+
+        let mut read = Vec::new();
+        let mut reader = reader;
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        drop(reader);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_detects_changed_spill_file() {
+        let mut buffer = SpillBuffer::new(0);
+        buffer.write_all(&[7; 5000]).unwrap();
+        let reader = buffer.into_reader().unwrap();
+        let path = reader.path().unwrap().to_path_buf();
+        let mut on_disk = fs::read(&path).unwrap();
+        on_disk[100] ^= 1;
+        fs::write(&path, on_disk).unwrap();
+
+        let error = { reader }.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,511 @@
+//! An encrypted wrapper around any byte stream, such as a TCP socket.
+//!
+//! [`EncryptedStream`] implements `Read` and `Write` itself, so it can be dropped in wherever
+//! the wrapped stream was used before. Under the hood, every `write` becomes one
+//! [`Session`] record and `read` hands back the plaintext of records as they arrive.
+//!
+//! Before any data flows, the two ends run a tiny handshake based on a pre-shared key (PSK):
+//!
+//! 1. Each side sends 16 random bytes.
+//! 2. Both derive the session secret as HKDF(PSK, salt = client random | server random). Since
+//!    both randoms are fresh, every connection gets its own keys, even with the same PSK.
+//! 3. Each side sends a record containing a fixed "finished" message. If the other side used a
+//!    different PSK, this record fails authentication and the handshake is aborted.
+//!
+//! Either side ends the connection with a close record: an authenticated record with no
+//! plaintext, which no write produces. A reader that sees the transport end without one
+//! fails with [`UnexpectedEof`](io::ErrorKind::UnexpectedEof), so an attacker who cuts the
+//! connection between two records can't pass off what arrived so far as everything that was
+//! sent.
+//!
+//! This is deliberately simple: there's no forward secrecy if the PSK leaks, and no way to
+//! negotiate anything. It's an example of the session layer in use, not a TLS replacement.
+
+use std::io::{self, Read, Write};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    gcm::TAG_SIZE,
+    session::{record_len, Role, Session, SessionError, HEADER_SIZE, MAX_RECORD_SIZE},
+    utils, BLOCK_SIZE,
+};
+
+const HANDSHAKE_LABEL: &[u8] = b"aes-modes stream handshake";
+const FINISHED: &[u8] = b"aes-modes stream finished";
+
+/// Builds the session for this connection from the PSK and both sides' random values.
+fn derive_session(
+    psk: &[u8],
+    client_random: &[u8; BLOCK_SIZE],
+    server_random: &[u8; BLOCK_SIZE],
+    role: Role,
+) -> Session {
+    let mut salt = client_random.to_vec();
+    salt.extend_from_slice(server_random);
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), psk)
+        .expand(HANDSHAKE_LABEL, &mut secret)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Session::new(&secret, role)
+}
+
+/// Reads the length of the record starting with `header`, refusing absurd lengths before any
+/// memory is allocated for them.
+fn checked_record_len(header: &[u8; HEADER_SIZE]) -> io::Result<usize> {
+    let len = record_len(header);
+    if len > HEADER_SIZE + MAX_RECORD_SIZE + TAG_SIZE {
+        return Err(invalid_data("record too large"));
+    }
+    Ok(len)
+}
+
+fn check_finished(message: Result<Vec<u8>, SessionError>) -> io::Result<()> {
+    match message {
+        Ok(message) if message == FINISHED => Ok(()),
+        _ => Err(invalid_data(
+            "handshake failed, the peer uses a different key",
+        )),
+    }
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the encrypted stream was shut down",
+    )
+}
+
+/// A stream whose traffic is transparently encrypted and authenticated.
+///
+/// Dropping it sends the close record, ignoring errors; call [`shutdown`](Self::shutdown) to
+/// see them.
+pub struct EncryptedStream<T: Read + Write> {
+    /// `None` once handed back by [`into_inner`](Self::into_inner).
+    inner: Option<T>,
+    session: Session,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Whether the peer's close record has arrived.
+    peer_closed: bool,
+    /// Whether our close record has been sent.
+    closed: bool,
+}
+
+impl<T: Read + Write> EncryptedStream<T> {
+    /// Runs the client side of the handshake over `inner`.
+    pub fn connect(mut inner: T, psk: &[u8]) -> io::Result<Self> {
+        let client_random = utils::create_rand_key();
+        inner.write_all(&client_random)?;
+        inner.flush()?;
+        let mut server_random = [0u8; BLOCK_SIZE];
+        inner.read_exact(&mut server_random)?;
+
+        let session = derive_session(psk, &client_random, &server_random, Role::Initiator);
+        let mut stream = Self::new(inner, session);
+        stream.send_finished()?;
+        stream.receive_finished()?;
+        Ok(stream)
+    }
+
+    /// Runs the server side of the handshake over `inner`.
+    pub fn accept(mut inner: T, psk: &[u8]) -> io::Result<Self> {
+        let mut client_random = [0u8; BLOCK_SIZE];
+        inner.read_exact(&mut client_random)?;
+        let server_random = utils::create_rand_key();
+        inner.write_all(&server_random)?;
+        inner.flush()?;
+
+        let session = derive_session(psk, &client_random, &server_random, Role::Responder);
+        let mut stream = Self::new(inner, session);
+        stream.receive_finished()?;
+        stream.send_finished()?;
+        Ok(stream)
+    }
+
+    /// Gives back the wrapped stream, without sending the close record. Anything already
+    /// received but not yet read is lost.
+    pub fn into_inner(mut self) -> T {
+        self.inner.take().expect("the stream is only taken once")
+    }
+
+    /// Sends the close record, after which nothing more can be written.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        let record = self.session.seal(Vec::new());
+        self.inner().write_all(&record)?;
+        self.inner().flush()
+    }
+
+    fn new(inner: T, session: Session) -> Self {
+        EncryptedStream {
+            inner: Some(inner),
+            session,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            peer_closed: false,
+            closed: false,
+        }
+    }
+
+    fn inner(&mut self) -> &mut T {
+        self.inner
+            .as_mut()
+            .expect("the stream is only taken by into_inner")
+    }
+
+    fn send_finished(&mut self) -> io::Result<()> {
+        let record = self.session.seal(FINISHED.to_vec());
+        self.inner().write_all(&record)?;
+        self.inner().flush()
+    }
+
+    fn receive_finished(&mut self) -> io::Result<()> {
+        let record = self.read_record()?;
+        check_finished(self.session.open(&record))
+    }
+
+    /// Reads one whole record off the wire.
+    fn read_record(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.inner().read_exact(&mut header)?;
+        let mut record = vec![0u8; checked_record_len(&header)?];
+        record[..HEADER_SIZE].copy_from_slice(&header);
+        self.inner().read_exact(&mut record[HEADER_SIZE..])?;
+        Ok(record)
+    }
+}
+
+impl<T: Read + Write> Drop for EncryptedStream<T> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+impl<T: Read + Write> Read for EncryptedStream<T> {
+    /// Returns 0 once the peer's close record has arrived, and fails with
+    /// [`UnexpectedEof`](io::ErrorKind::UnexpectedEof) if the transport ends before it.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos == self.read_buf.len() {
+            if self.peer_closed {
+                return Ok(0);
+            }
+            let record = self.read_record()?;
+            self.read_buf = self.session.open(&record).map_err(invalid_data)?;
+            self.read_pos = 0;
+            self.peer_closed = self.read_buf.is_empty();
+        }
+
+        let n = buf.len().min(self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl<T: Read + Write> Write for EncryptedStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(closed());
+        }
+        // An empty record would be the close record.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min(MAX_RECORD_SIZE);
+        let record = self.session.seal(buf[..n].to_vec());
+        self.inner().write_all(&record)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use self::asynchronous::AsyncEncryptedStream;
+
+/// The same protocol for tokio streams.
+///
+/// Rather than implementing `AsyncRead`/`AsyncWrite`, this variant exposes whole messages
+/// through [`send`](AsyncEncryptedStream::send) and [`recv`](AsyncEncryptedStream::recv),
+/// which is usually what async protocol code wants anyway. It interoperates with the
+/// blocking [`EncryptedStream`] on the other end. Since nothing can be sent from `drop` here,
+/// call [`close`](AsyncEncryptedStream::close) when done.
+#[cfg(feature = "tokio")]
+mod asynchronous {
+    use std::io;
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use super::{
+        check_finished, checked_record_len, closed, derive_session, invalid_data, FINISHED,
+    };
+    use crate::{
+        session::{Role, Session, HEADER_SIZE, MAX_RECORD_SIZE},
+        utils, BLOCK_SIZE,
+    };
+
+    pub struct AsyncEncryptedStream<T> {
+        inner: T,
+        session: Session,
+        peer_closed: bool,
+        closed: bool,
+    }
+
+    impl<T: AsyncRead + AsyncWrite + Unpin> AsyncEncryptedStream<T> {
+        pub async fn connect(mut inner: T, psk: &[u8]) -> io::Result<Self> {
+            let client_random = utils::create_rand_key();
+            inner.write_all(&client_random).await?;
+            inner.flush().await?;
+            let mut server_random = [0u8; BLOCK_SIZE];
+            inner.read_exact(&mut server_random).await?;
+
+            let session = derive_session(psk, &client_random, &server_random, Role::Initiator);
+            let mut stream = AsyncEncryptedStream::new(inner, session);
+            stream.send(FINISHED).await?;
+            stream.receive_finished().await?;
+            Ok(stream)
+        }
+
+        pub async fn accept(mut inner: T, psk: &[u8]) -> io::Result<Self> {
+            let mut client_random = [0u8; BLOCK_SIZE];
+            inner.read_exact(&mut client_random).await?;
+            let server_random = utils::create_rand_key();
+            inner.write_all(&server_random).await?;
+            inner.flush().await?;
+
+            let session = derive_session(psk, &client_random, &server_random, Role::Responder);
+            let mut stream = AsyncEncryptedStream::new(inner, session);
+            stream.receive_finished().await?;
+            stream.send(FINISHED).await?;
+            Ok(stream)
+        }
+
+        fn new(inner: T, session: Session) -> Self {
+            AsyncEncryptedStream {
+                inner,
+                session,
+                peer_closed: false,
+                closed: false,
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+
+        /// Sends one message, split over several records if it is very large.
+        pub async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            if self.closed {
+                return Err(closed());
+            }
+            for chunk in message.chunks(MAX_RECORD_SIZE) {
+                let record = self.session.seal(chunk.to_vec());
+                self.inner.write_all(&record).await?;
+            }
+            self.inner.flush().await
+        }
+
+        /// Sends the close record, after which nothing more can be sent.
+        pub async fn close(&mut self) -> io::Result<()> {
+            if self.closed {
+                return Ok(());
+            }
+            self.closed = true;
+            let record = self.session.seal(Vec::new());
+            self.inner.write_all(&record).await?;
+            self.inner.flush().await
+        }
+
+        /// Receives the next record's plaintext, or `None` once the peer's close record has
+        /// arrived. A transport that ends before it fails with
+        /// [`UnexpectedEof`](io::ErrorKind::UnexpectedEof).
+        pub async fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+            if self.peer_closed {
+                return Ok(None);
+            }
+            let record = self.read_record().await?;
+            let message = self.session.open(&record).map_err(invalid_data)?;
+            self.peer_closed = message.is_empty();
+            Ok(Some(message).filter(|message| !message.is_empty()))
+        }
+
+        async fn receive_finished(&mut self) -> io::Result<()> {
+            let record = self.read_record().await?;
+            check_finished(self.session.open(&record))
+        }
+
+        async fn read_record(&mut self) -> io::Result<Vec<u8>> {
+            let mut header = [0u8; HEADER_SIZE];
+            self.inner.read_exact(&mut header).await?;
+            let mut record = vec![0u8; checked_record_len(&header)?];
+            record[..HEADER_SIZE].copy_from_slice(&header);
+            self.inner.read_exact(&mut record[HEADER_SIZE..]).await?;
+            Ok(record)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+
+    const PSK: &[u8] = b"a pre-shared key for testing";
+
+    #[test]
+    fn test_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = EncryptedStream::accept(socket, PSK).unwrap();
+
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").unwrap();
+
+            // Echo everything else back until the client hangs up.
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            rest
+        });
+
+        let socket = TcpStream::connect(address).unwrap();
+        let mut stream = EncryptedStream::connect(socket, PSK).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut response = [0u8; 4];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"pong");
+
+        let big = vec![0xABu8; 100_000];
+        stream.write_all(&big).unwrap();
+        drop(stream);
+
+        assert_eq!(server.join().unwrap(), big);
+    }
+
+    #[test]
+    fn test_wrong_psk_fails_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            EncryptedStream::accept(socket, b"the server's key").map(|_| ())
+        });
+
+        let socket = TcpStream::connect(address).unwrap();
+        let client = EncryptedStream::connect(socket, PSK);
+
+        let error = server.join().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(client.is_err());
+    }
+
+    /// One direction of a connection: reads from `incoming`, and collects what is written.
+    struct Wire {
+        incoming: io::Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+
+    impl Read for Wire {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_truncation_between_records_is_detected() {
+        let randoms = ([1; BLOCK_SIZE], [2; BLOCK_SIZE]);
+        let wire = |incoming: Vec<u8>| Wire {
+            incoming: io::Cursor::new(incoming),
+            outgoing: Vec::new(),
+        };
+        let mut sender = EncryptedStream::new(
+            wire(Vec::new()),
+            derive_session(PSK, &randoms.0, &randoms.1, Role::Initiator),
+        );
+        sender.write_all(b"transfer $10").unwrap();
+        sender.write_all(b" to bob").unwrap();
+        let first = sender.inner().outgoing.len();
+        sender.write_all(b" and $1000 to mallory").unwrap();
+        sender.shutdown().unwrap();
+        assert!(sender.write_all(b"more").is_err());
+        let sent = sender.into_inner().outgoing;
+
+        let receive = |bytes: &[u8]| {
+            let mut receiver = EncryptedStream::new(
+                wire(bytes.to_vec()),
+                derive_session(PSK, &randoms.0, &randoms.1, Role::Responder),
+            );
+            let mut received = Vec::new();
+            receiver.read_to_end(&mut received).map(|_| received)
+        };
+        assert_eq!(
+            receive(&sent).unwrap(),
+            b"transfer $10 to bob and $1000 to mallory"
+        );
+        // Cut after the second record, and before the close record.
+        for cut in [first, sent.len() - (HEADER_SIZE + TAG_SIZE)] {
+            assert_eq!(
+                receive(&sent[..cut]).unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_variant() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let (client, server) = tokio::io::duplex(1024);
+            let server = tokio::spawn(async move {
+                let mut stream = AsyncEncryptedStream::accept(server, PSK).await.unwrap();
+                let message = stream.recv().await.unwrap().unwrap();
+                stream.send(&message).await.unwrap();
+                stream.close().await.unwrap();
+            });
+
+            let mut stream = AsyncEncryptedStream::connect(client, PSK).await.unwrap();
+            stream.send(b"hello async").await.unwrap();
+            assert_eq!(stream.recv().await.unwrap(), Some(b"hello async".to_vec()));
+            assert_eq!(stream.recv().await.unwrap(), None);
+            server.await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,158 @@
+//! What flipping one ciphertext bit does to each mode, for teaching malleability.
+//!
+//! [`tamper_all_modes`] seals the same plaintext under ECB, CBC, CTR and GCM, flips the same
+//! bit of each ciphertext, and decrypts again. The results are the lesson:
+//!
+//! - ECB garbles the block holding the bit, and nothing else;
+//! - CBC garbles that block too, but flips exactly the same bit in the _next_ block, which is
+//!   how an attacker edits CBC plaintext at will;
+//! - CTR flips exactly that bit of the plaintext and nothing else;
+//! - GCM rejects the ciphertext outright.
+//!
+//! The `aes-modes tamper` command prints them side by side.
+
+use crate::{
+    envelope::{Envelope, EnvelopeError, EnvelopeMode},
+    BLOCK_SIZE,
+};
+
+pub const MODES: [EnvelopeMode; 4] = [
+    EnvelopeMode::Ecb,
+    EnvelopeMode::Cbc,
+    EnvelopeMode::Ctr,
+    EnvelopeMode::Gcm,
+];
+
+/// Flips bit `bit` of the envelope's ciphertext, counting from the least significant bit of
+/// its first byte. The IV or nonce isn't part of the count.
+///
+/// # Panics
+///
+/// If the ciphertext has fewer bits.
+pub fn flip_bit(envelope: &mut Envelope, bit: usize) {
+    let mut offset = bit / 8;
+    for chunk in &mut envelope.chunks {
+        if let Some(byte) = chunk.get_mut(offset) {
+            *byte ^= 1 << (bit % 8);
+            return;
+        }
+        offset -= chunk.len();
+    }
+    panic!("bit {} is past the end of the ciphertext", bit);
+}
+
+/// How one mode fared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tampered {
+    pub mode: EnvelopeMode,
+    /// The decryption of the tampered ciphertext.
+    pub result: Result<Vec<u8>, EnvelopeError>,
+}
+
+/// One way a decrypted plaintext differs from the original.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Damage {
+    /// More than one bit of this block changed.
+    GarbledBlock(usize),
+    /// Exactly one bit changed, in this byte.
+    FlippedBit { byte: usize, bit: u32 },
+    /// The plaintext came out with a different length, because the padding was garbled.
+    Length(usize),
+}
+
+impl Tampered {
+    /// What changed, block by block, with the original `plain_text` to compare against.
+    /// Empty if the tampered ciphertext was rejected.
+    pub fn damage(&self, plain_text: &[u8]) -> Vec<Damage> {
+        let Ok(decrypted) = &self.result else {
+            return Vec::new();
+        };
+        let mut damage = Vec::new();
+        for (index, (original, tampered)) in plain_text
+            .chunks(BLOCK_SIZE)
+            .zip(decrypted.chunks(BLOCK_SIZE))
+            .enumerate()
+        {
+            let changed: Vec<(usize, u8)> = original
+                .iter()
+                .zip(tampered)
+                .enumerate()
+                .map(|(i, (a, b))| (i, a ^ b))
+                .filter(|&(_, difference)| difference != 0)
+                .collect();
+            match changed[..] {
+                [] => {}
+                [(i, difference)] if difference.is_power_of_two() => {
+                    damage.push(Damage::FlippedBit {
+                        byte: index * BLOCK_SIZE + i,
+                        bit: difference.trailing_zeros(),
+                    })
+                }
+                _ => damage.push(Damage::GarbledBlock(index)),
+            }
+        }
+        if decrypted.len() != plain_text.len() {
+            damage.push(Damage::Length(decrypted.len()));
+        }
+        damage
+    }
+}
+
+/// Seals `plain_text` under each of the [`MODES`], flips `bit` of each ciphertext, and
+/// decrypts.
+///
+/// # Panics
+///
+/// If `bit` isn't within `plain_text`, the shortest of the ciphertexts.
+pub fn tamper_all_modes(key: [u8; BLOCK_SIZE], plain_text: &[u8], bit: usize) -> Vec<Tampered> {
+    assert!(
+        bit < 8 * plain_text.len(),
+        "bit is past the end of the plaintext"
+    );
+    MODES
+        .into_iter()
+        .map(|mode| {
+            let mut envelope = Envelope::seal(mode, 0, key, plain_text.to_vec());
+            flip_bit(&mut envelope, bit);
+            Tampered {
+                mode,
+                result: envelope.open(key),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [3; BLOCK_SIZE];
+    const MESSAGE: &[u8] = b"Transfer $100 from Alice to Bob, please.";
+
+    #[test]
+    fn test_each_mode_fails_its_own_way() {
+        // Bit 2 of byte 10, the "1" of "$100", is in block 0.
+        let results = tamper_all_modes(KEY, MESSAGE, 10 * 8 + 2);
+        let damage: Vec<Vec<Damage>> = results.iter().map(|t| t.damage(MESSAGE)).collect();
+        assert_eq!(damage[0], [Damage::GarbledBlock(0)]);
+        assert_eq!(
+            damage[1],
+            [
+                Damage::GarbledBlock(0),
+                Damage::FlippedBit { byte: 26, bit: 2 }
+            ]
+        );
+        assert_eq!(damage[2], [Damage::FlippedBit { byte: 10, bit: 2 }]);
+        assert_eq!(results[2].result.as_ref().unwrap()[10], b'5');
+        assert_eq!(results[3].result, Err(EnvelopeError::Authentication));
+        assert!(damage[3].is_empty());
+    }
+
+    #[test]
+    fn test_flip_bit_across_chunks() {
+        let mut envelope = Envelope::seal(EnvelopeMode::Ctr, 0, KEY, vec![0; 8]);
+        envelope.chunks = vec![vec![0; 3], vec![0; 5]];
+        flip_bit(&mut envelope, 8 * 4 + 7);
+        assert_eq!(envelope.chunks, [vec![0; 3], vec![0, 0x80, 0, 0, 0]]);
+    }
+}
@@ -0,0 +1,626 @@
+//! AES written out step by step, following FIPS-197, for studying the cipher itself.
+//!
+//! **This is for reading, not for encrypting.** Everything else in the crate uses the audited
+//! `aes` crate, and so should you. This version looks bytes up in the S-box by index, so its
+//! timing depends on the key and the data, and it makes no attempt at speed.
+//!
+//! AES works on a 4x4 grid of bytes called the _state_. The 16 input bytes fill it column by
+//! column, so byte `r + 4c` of a block is in row `r` and column `c`:
+//!
+//! ```text
+//! in[0] in[4] in[8]  in[12]
+//! in[1] in[5] in[9]  in[13]
+//! in[2] in[6] in[10] in[14]
+//! in[3] in[7] in[11] in[15]
+//! ```
+//!
+//! The functions here keep the state as those same 16 bytes. Encryption XORs in the first
+//! round key and then runs 10, 12 or 14 rounds (for 128, 192 or 256-bit keys) of
+//!
+//! 1. [`sub_bytes`]: replace every byte through the S-box, the only non-linear step;
+//! 2. [`shift_rows`]: rotate row `r` left by `r` places, so each column mixes into four;
+//! 3. [`mix_columns`]: multiply each column by a fixed matrix over GF(2^8), spreading every
+//!    byte over its whole column (skipped in the last round);
+//! 4. [`add_round_key`]: XOR in the round key.
+//!
+//! After two rounds every output byte depends on every input byte. Decryption runs the inverse
+//! steps in reverse order. Each step is public, so you can call them one at a time and watch
+//! the state change; [`TextbookAes`] puts them together.
+
+/// Multiplication by x (that is, by 2) in GF(2^8), the field AES does its arithmetic in.
+///
+/// A byte is a polynomial of degree below 8 with bits as coefficients. Multiplying by x shifts
+/// every coefficient up one place; if that makes a term x^8, it is reduced away with the AES
+/// polynomial x^8 + x^4 + x^3 + x + 1, which means XORing in 0x1b.
+pub const fn xtime(b: u8) -> u8 {
+    let shifted = b << 1;
+    if b & 0x80 != 0 {
+        shifted ^ 0x1b
+    } else {
+        shifted
+    }
+}
+
+/// Multiplication in GF(2^8): add up (XOR) `a * x^i` for every bit `i` set in `b`.
+pub const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+/// The S-box, built the way FIPS-197 section 5.1.1 defines it rather than copied from a table.
+///
+/// Each byte is replaced by its multiplicative inverse in GF(2^8) (zero stays zero), which
+/// makes the step highly non-linear. An affine transformation follows, so the S-box has no
+/// fixed points and isn't simply the inverse, which has algebraic structure of its own.
+const fn build_sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        // In a field with 256 elements, a^255 = 1 for a != 0, so a^254 is the inverse of a.
+        let a = i as u8;
+        let mut inverse = 1u8;
+        let mut power = 0;
+        while power < 254 {
+            inverse = gf_mul(inverse, a);
+            power += 1;
+        }
+        if a == 0 {
+            inverse = 0;
+        }
+        // The affine transformation: each output bit is the XOR of five input bits (the bit
+        // itself and the four after it, cyclically), then XOR with the constant 0x63.
+        sbox[i] = inverse
+            ^ inverse.rotate_left(1)
+            ^ inverse.rotate_left(2)
+            ^ inverse.rotate_left(3)
+            ^ inverse.rotate_left(4)
+            ^ 0x63;
+        i += 1;
+    }
+    sbox
+}
+
+const fn invert(sbox: &[u8; 256]) -> [u8; 256] {
+    let mut inverse = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        inverse[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    inverse
+}
+
+/// The AES S-box.
+pub const SBOX: [u8; 256] = build_sbox();
+/// Its inverse, for decryption.
+pub const INV_SBOX: [u8; 256] = invert(&SBOX);
+
+/// SubBytes: every byte of the state goes through the S-box on its own.
+pub fn sub_bytes(state: &mut [u8; 16]) {
+    for byte in state.iter_mut() {
+        *byte = SBOX[*byte as usize];
+    }
+}
+
+pub fn inv_sub_bytes(state: &mut [u8; 16]) {
+    for byte in state.iter_mut() {
+        *byte = INV_SBOX[*byte as usize];
+    }
+}
+
+/// ShiftRows: row `r` is rotated left by `r` places. Row 0 stays put.
+pub fn shift_rows(state: &mut [u8; 16]) {
+    let old = *state;
+    for row in 0..4 {
+        for column in 0..4 {
+            // The byte that ends up in column c came from column c + r.
+            state[row + 4 * column] = old[row + 4 * ((column + row) % 4)];
+        }
+    }
+}
+
+pub fn inv_shift_rows(state: &mut [u8; 16]) {
+    let old = *state;
+    for row in 0..4 {
+        for column in 0..4 {
+            state[row + 4 * ((column + row) % 4)] = old[row + 4 * column];
+        }
+    }
+}
+
+/// Multiplies one column by a circulant matrix over GF(2^8), whose first row is `coefficients`.
+fn mix_column(column: &mut [u8], coefficients: [u8; 4]) {
+    let old = [column[0], column[1], column[2], column[3]];
+    for (row, byte) in column.iter_mut().enumerate() {
+        *byte = (0..4).fold(0, |sum, i| {
+            sum ^ gf_mul(coefficients[(4 + i - row) % 4], old[i])
+        });
+    }
+}
+
+/// MixColumns: each column is multiplied by the matrix
+///
+/// ```text
+/// 2 3 1 1
+/// 1 2 3 1
+/// 1 1 2 3
+/// 3 1 1 2
+/// ```
+///
+/// so every output byte of a column depends on all four input bytes. The matrix is chosen to
+/// be invertible and to spread differences as widely as possible.
+pub fn mix_columns(state: &mut [u8; 16]) {
+    for column in state.chunks_mut(4) {
+        mix_column(column, [2, 3, 1, 1]);
+    }
+}
+
+/// InvMixColumns, with the inverse matrix, whose first row is 14, 11, 13, 9.
+pub fn inv_mix_columns(state: &mut [u8; 16]) {
+    for column in state.chunks_mut(4) {
+        mix_column(column, [14, 11, 13, 9]);
+    }
+}
+
+/// AddRoundKey: XOR the round key into the state. It is its own inverse.
+pub fn add_round_key(state: &mut [u8; 16], round_key: &[u8; 16]) {
+    for (byte, key_byte) in state.iter_mut().zip(round_key) {
+        *byte ^= key_byte;
+    }
+}
+
+/// KeyExpansion (FIPS-197 section 5.2): stretches the key into one 16-byte round key per
+/// round, plus one for the initial AddRoundKey.
+///
+/// The expansion works in 4-byte words. The key supplies the first `Nk` words (4, 6 or 8); each
+/// later word is the word before it XORed with the word `Nk` places back. Every `Nk`th word
+/// first goes through RotWord (a one-byte rotation), SubWord (the S-box on each byte) and an
+/// XOR with a round constant, a power of x in GF(2^8), so the rounds don't all look alike.
+/// AES-256 also applies SubWord half way between those.
+///
+/// # Panics
+///
+/// If the key isn't 16, 24 or 32 bytes long.
+pub fn expand_key(key: &[u8]) -> Vec<[u8; 16]> {
+    assert!(
+        [16, 24, 32].contains(&key.len()),
+        "AES keys are 16, 24 or 32 bytes"
+    );
+    let nk = key.len() / 4;
+    let rounds = nk + 6;
+
+    let mut words: Vec<[u8; 4]> = key.chunks(4).map(|word| word.try_into().unwrap()).collect();
+    let mut round_constant = 1u8;
+    for i in nk..4 * (rounds + 1) {
+        let mut word = words[i - 1];
+        if i % nk == 0 {
+            word.rotate_left(1);
+            word = word.map(|byte| SBOX[byte as usize]);
+            word[0] ^= round_constant;
+            round_constant = xtime(round_constant);
+        } else if nk > 6 && i % nk == 4 {
+            word = word.map(|byte| SBOX[byte as usize]);
+        }
+        let back = words[i - nk];
+        words.push(core::array::from_fn(|j| word[j] ^ back[j]));
+    }
+
+    words
+        .chunks(4)
+        .map(|round| round.concat().try_into().unwrap())
+        .collect()
+}
+
+/// A point in encryption that [`TextbookAes::encrypt_block_traced`] reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// The plaintext, before round 0.
+    Input,
+    /// The state at the start of a round.
+    Start,
+    SubBytes,
+    ShiftRows,
+    MixColumns,
+    /// The round key about to be added.
+    RoundKey,
+    /// The ciphertext, after the last round.
+    Output,
+}
+
+impl Step {
+    /// The name FIPS-197 Appendix C gives the step, as in `round[ 1].s_box`.
+    pub fn label(self) -> &'static str {
+        match self {
+            Step::Input => "input",
+            Step::Start => "start",
+            Step::SubBytes => "s_box",
+            Step::ShiftRows => "s_row",
+            Step::MixColumns => "m_col",
+            Step::RoundKey => "k_sch",
+            Step::Output => "output",
+        }
+    }
+}
+
+/// AES put together from the steps above.
+#[derive(Clone)]
+pub struct TextbookAes {
+    round_keys: Vec<[u8; 16]>,
+}
+
+impl TextbookAes {
+    /// # Panics
+    ///
+    /// If the key isn't 16, 24 or 32 bytes long.
+    pub fn new(key: &[u8]) -> Self {
+        TextbookAes {
+            round_keys: expand_key(key),
+        }
+    }
+
+    /// 10, 12 or 14, depending on the key size.
+    pub fn rounds(&self) -> usize {
+        self.round_keys.len() - 1
+    }
+
+    /// The key XORed in after `round`, where round 0 is the initial AddRoundKey.
+    pub fn round_key(&self, round: usize) -> &[u8; 16] {
+        &self.round_keys[round]
+    }
+
+    pub fn encrypt_block(&self, block: [u8; 16]) -> [u8; 16] {
+        self.encrypt_block_traced(block, |_, _, _| {})
+    }
+
+    /// Encrypts like [`encrypt_block`](Self::encrypt_block), calling `observe` with the round
+    /// number, the step and the 16 bytes after every step, in the order of the FIPS-197
+    /// Appendix C tables. For [`Step::RoundKey`] the bytes are the round key, not the state.
+    pub fn encrypt_block_traced(
+        &self,
+        block: [u8; 16],
+        mut observe: impl FnMut(usize, Step, &[u8; 16]),
+    ) -> [u8; 16] {
+        let mut state = block;
+        observe(0, Step::Input, &state);
+        observe(0, Step::RoundKey, self.round_key(0));
+        add_round_key(&mut state, self.round_key(0));
+        for round in 1..=self.rounds() {
+            observe(round, Step::Start, &state);
+            sub_bytes(&mut state);
+            observe(round, Step::SubBytes, &state);
+            shift_rows(&mut state);
+            observe(round, Step::ShiftRows, &state);
+            if round != self.rounds() {
+                mix_columns(&mut state);
+                observe(round, Step::MixColumns, &state);
+            }
+            observe(round, Step::RoundKey, self.round_key(round));
+            add_round_key(&mut state, self.round_key(round));
+        }
+        observe(self.rounds(), Step::Output, &state);
+        state
+    }
+
+    /// The steps of encryption undone, last first.
+    pub fn decrypt_block(&self, block: [u8; 16]) -> [u8; 16] {
+        let mut state = block;
+        for round in (1..=self.rounds()).rev() {
+            add_round_key(&mut state, self.round_key(round));
+            if round != self.rounds() {
+                inv_mix_columns(&mut state);
+            }
+            inv_shift_rows(&mut state);
+            inv_sub_bytes(&mut state);
+        }
+        add_round_key(&mut state, self.round_key(0));
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::{
+        cipher::{consts::U16, generic_array::GenericArray, BlockEncrypt, BlockSizeUser, KeyInit},
+        Aes128, Aes192, Aes256,
+    };
+
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_fips_197_examples() {
+        assert_eq!((SBOX[0x00], SBOX[0x53], INV_SBOX[0x63]), (0x63, 0xed, 0x00));
+        assert_eq!(gf_mul(0x57, 0x13), 0xfe);
+
+        // Appendix B, and the last round key of the Appendix A.1 key expansion.
+        let aes = TextbookAes::new(&hex("2b7e151628aed2a6abf7158809cf4f3c"));
+        assert_eq!(
+            aes.round_key(10).to_vec(),
+            hex("d014f9a8c9ee2589e13f0cc8b6630ca6")
+        );
+        let block = hex("3243f6a8885a308d313198a2e0370734").try_into().unwrap();
+        let cipher_text = aes.encrypt_block(block);
+        assert_eq!(
+            cipher_text.to_vec(),
+            hex("3925841d02dc09fbdc118597196a0b32")
+        );
+        assert_eq!(aes.decrypt_block(cipher_text), block);
+    }
+
+    /// FIPS-197 Appendix C.1, AES-128 with key 000102...0f.
+    const APPENDIX_C1: &str = "
+    round[ 0].input  00112233445566778899aabbccddeeff
+    round[ 0].k_sch  000102030405060708090a0b0c0d0e0f
+    round[ 1].start  00102030405060708090a0b0c0d0e0f0
+    round[ 1].s_box  63cab7040953d051cd60e0e7ba70e18c
+    round[ 1].s_row  6353e08c0960e104cd70b751bacad0e7
+    round[ 1].m_col  5f72641557f5bc92f7be3b291db9f91a
+    round[ 1].k_sch  d6aa74fdd2af72fadaa678f1d6ab76fe
+    round[ 2].start  89d810e8855ace682d1843d8cb128fe4
+    round[ 2].s_box  a761ca9b97be8b45d8ad1a611fc97369
+    round[ 2].s_row  a7be1a6997ad739bd8c9ca451f618b61
+    round[ 2].m_col  ff87968431d86a51645151fa773ad009
+    round[ 2].k_sch  b692cf0b643dbdf1be9bc5006830b3fe
+    round[ 3].start  4915598f55e5d7a0daca94fa1f0a63f7
+    round[ 3].s_box  3b59cb73fcd90ee05774222dc067fb68
+    round[ 3].s_row  3bd92268fc74fb735767cbe0c0590e2d
+    round[ 3].m_col  4c9c1e66f771f0762c3f868e534df256
+    round[ 3].k_sch  b6ff744ed2c2c9bf6c590cbf0469bf41
+    round[ 4].start  fa636a2825b339c940668a3157244d17
+    round[ 4].s_box  2dfb02343f6d12dd09337ec75b36e3f0
+    round[ 4].s_row  2d6d7ef03f33e334093602dd5bfb12c7
+    round[ 4].m_col  6385b79ffc538df997be478e7547d691
+    round[ 4].k_sch  47f7f7bc95353e03f96c32bcfd058dfd
+    round[ 5].start  247240236966b3fa6ed2753288425b6c
+    round[ 5].s_box  36400926f9336d2d9fb59d23c42c3950
+    round[ 5].s_row  36339d50f9b539269f2c092dc4406d23
+    round[ 5].m_col  f4bcd45432e554d075f1d6c51dd03b3c
+    round[ 5].k_sch  3caaa3e8a99f9deb50f3af57adf622aa
+    round[ 6].start  c81677bc9b7ac93b25027992b0261996
+    round[ 6].s_box  e847f56514dadde23f77b64fe7f7d490
+    round[ 6].s_row  e8dab6901477d4653ff7f5e2e747dd4f
+    round[ 6].m_col  9816ee7400f87f556b2c049c8e5ad036
+    round[ 6].k_sch  5e390f7df7a69296a7553dc10aa31f6b
+    round[ 7].start  c62fe109f75eedc3cc79395d84f9cf5d
+    round[ 7].s_box  b415f8016858552e4bb6124c5f998a4c
+    round[ 7].s_row  b458124c68b68a014b99f82e5f15554c
+    round[ 7].m_col  c57e1c159a9bd286f05f4be098c63439
+    round[ 7].k_sch  14f9701ae35fe28c440adf4d4ea9c026
+    round[ 8].start  d1876c0f79c4300ab45594add66ff41f
+    round[ 8].s_box  3e175076b61c04678dfc2295f6a8bfc0
+    round[ 8].s_row  3e1c22c0b6fcbf768da85067f6170495
+    round[ 8].m_col  baa03de7a1f9b56ed5512cba5f414d23
+    round[ 8].k_sch  47438735a41c65b9e016baf4aebf7ad2
+    round[ 9].start  fde3bad205e5d0d73547964ef1fe37f1
+    round[ 9].s_box  5411f4b56bd9700e96a0902fa1bb9aa1
+    round[ 9].s_row  54d990a16ba09ab596bbf40ea111702f
+    round[ 9].m_col  e9f74eec023020f61bf2ccf2353c21c7
+    round[ 9].k_sch  549932d1f08557681093ed9cbe2c974e
+    round[10].start  bd6e7c3df2b5779e0b61216e8b10b689
+    round[10].s_box  7a9f102789d5f50b2beffd9f3dca4ea7
+    round[10].s_row  7ad5fda789ef4e272bca100b3d9ff59f
+    round[10].k_sch  13111d7fe3944a17f307a78b4d2b30c5
+    round[10].output 69c4e0d86a7b0430d8cdb78070b4c55a
+";
+
+    /// Appendix C.2, AES-192 with key 000102...17.
+    const APPENDIX_C2: &str = "
+    round[ 0].input  00112233445566778899aabbccddeeff
+    round[ 0].k_sch  000102030405060708090a0b0c0d0e0f
+    round[ 1].start  00102030405060708090a0b0c0d0e0f0
+    round[ 1].s_box  63cab7040953d051cd60e0e7ba70e18c
+    round[ 1].s_row  6353e08c0960e104cd70b751bacad0e7
+    round[ 1].m_col  5f72641557f5bc92f7be3b291db9f91a
+    round[ 1].k_sch  10111213141516175846f2f95c43f4fe
+    round[ 2].start  4f63760643e0aa85aff8c9d041fa0de4
+    round[ 2].s_box  84fb386f1ae1ac977941dd70832dd769
+    round[ 2].s_row  84e1dd691a41d76f792d389783fbac70
+    round[ 2].m_col  9f487f794f955f662afc86abd7f1ab29
+    round[ 2].k_sch  544afef55847f0fa4856e2e95c43f4fe
+    round[ 3].start  cb02818c17d2af9c62aa64428bb25fd7
+    round[ 3].s_box  1f770c64f0b579deaaac432c3d37cf0e
+    round[ 3].s_row  1fb5430ef0accf64aa370cde3d77792c
+    round[ 3].m_col  b7a53ecbbf9d75a0c40efc79b674cc11
+    round[ 3].k_sch  40f949b31cbabd4d48f043b810b7b342
+    round[ 4].start  f75c7778a327c8ed8cfebfc1a6c37f53
+    round[ 4].s_box  684af5bc0acce85564bb0878242ed2ed
+    round[ 4].s_row  68cc08ed0abbd2bc642ef555244ae878
+    round[ 4].m_col  7a1e98bdacb6d1141a6944dd06eb2d3e
+    round[ 4].k_sch  58e151ab04a2a5557effb5416245080c
+    round[ 5].start  22ffc916a81474416496f19c64ae2532
+    round[ 5].s_box  9316dd47c2fa92834390a1de43e43f23
+    round[ 5].s_row  93faa123c2903f4743e4dd83431692de
+    round[ 5].m_col  aaa755b34cffe57cef6f98e1f01c13e6
+    round[ 5].k_sch  2ab54bb43a02f8f662e3a95d66410c08
+    round[ 6].start  80121e0776fd1d8a8d8c31bc965d1fee
+    round[ 6].s_box  cdc972c53854a47e5d64c765904cc028
+    round[ 6].s_row  cd54c7283864c0c55d4c727e90c9a465
+    round[ 6].m_col  921f748fd96e937d622d7725ba8ba50c
+    round[ 6].k_sch  f501857297448d7ebdf1c6ca87f33e3c
+    round[ 7].start  671ef1fd4e2a1e03dfdcb1ef3d789b30
+    round[ 7].s_box  8572a1542fe5727b9e86c8df27bc1404
+    round[ 7].s_row  85e5c8042f8614549ebca17b277272df
+    round[ 7].m_col  e913e7b18f507d4b227ef652758acbcc
+    round[ 7].k_sch  e510976183519b6934157c9ea351f1e0
+    round[ 8].start  0c0370d00c01e622166b8accd6db3a2c
+    round[ 8].s_box  fe7b5170fe7c8e93477f7e4bf6b98071
+    round[ 8].s_row  fe7c7e71fe7f807047b95193f67b8e4b
+    round[ 8].m_col  6cf5edf996eb0a069c4ef21cbfc25762
+    round[ 8].k_sch  1ea0372a995309167c439e77ff12051e
+    round[ 9].start  7255dad30fb80310e00d6c6b40d0527c
+    round[ 9].s_box  40fc5766766c7bcae1d7507f09700010
+    round[ 9].s_row  406c501076d70066e17057ca09fc7b7f
+    round[ 9].m_col  7478bcdce8a50b81d4327a9009188262
+    round[ 9].k_sch  dd7e0e887e2fff68608fc842f9dcc154
+    round[10].start  a906b254968af4e9b4bdb2d2f0c44336
+    round[10].s_box  d36f3720907ebf1e8d7a37b58c1c1a05
+    round[10].s_row  d37e3705907a1a208d1c371e8c6fbfb5
+    round[10].m_col  0d73cc2d8f6abe8b0cf2dd9bb83d422e
+    round[10].k_sch  859f5f237a8d5a3dc0c02952beefd63a
+    round[11].start  88ec930ef5e7e4b6cc32f4c906d29414
+    round[11].s_box  c4cedcabe694694e4b23bfdd6fb522fa
+    round[11].s_row  c494bffae62322ab4bb5dc4e6fce69dd
+    round[11].m_col  71d720933b6d677dc00b8f28238e0fb7
+    round[11].k_sch  de601e7827bcdf2ca223800fd8aeda32
+    round[12].start  afb73eeb1cd1b85162280f27fb20d585
+    round[12].s_box  79a9b2e99c3e6cd1aa3476cc0fb70397
+    round[12].s_row  793e76979c3403e9aab7b2d10fa96ccc
+    round[12].k_sch  a4970a331a78dc09c418c271e3a41d5d
+    round[12].output dda97ca4864cdfe06eaf70a0ec0d7191
+";
+
+    /// Appendix C.3, AES-256 with key 000102...1f.
+    const APPENDIX_C3: &str = "
+    round[ 0].input  00112233445566778899aabbccddeeff
+    round[ 0].k_sch  000102030405060708090a0b0c0d0e0f
+    round[ 1].start  00102030405060708090a0b0c0d0e0f0
+    round[ 1].s_box  63cab7040953d051cd60e0e7ba70e18c
+    round[ 1].s_row  6353e08c0960e104cd70b751bacad0e7
+    round[ 1].m_col  5f72641557f5bc92f7be3b291db9f91a
+    round[ 1].k_sch  101112131415161718191a1b1c1d1e1f
+    round[ 2].start  4f63760643e0aa85efa7213201a4e705
+    round[ 2].s_box  84fb386f1ae1ac97df5cfd237c49946b
+    round[ 2].s_row  84e1fd6b1a5c946fdf4938977cfbac23
+    round[ 2].m_col  bd2a395d2b6ac438d192443e615da195
+    round[ 2].k_sch  a573c29fa176c498a97fce93a572c09c
+    round[ 3].start  1859fbc28a1c00a078ed8aadc42f6109
+    round[ 3].s_box  adcb0f257e9c63e0bc557e951c15ef01
+    round[ 3].s_row  ad9c7e017e55ef25bc150fe01ccb6395
+    round[ 3].m_col  810dce0cc9db8172b3678c1e88a1b5bd
+    round[ 3].k_sch  1651a8cd0244beda1a5da4c10640bade
+    round[ 4].start  975c66c1cb9f3fa8a93a28df8ee10f63
+    round[ 4].s_box  884a33781fdb75c2d380349e19f876fb
+    round[ 4].s_row  88db34fb1f807678d3f833c2194a759e
+    round[ 4].m_col  b2822d81abe6fb275faf103a078c0033
+    round[ 4].k_sch  ae87dff00ff11b68a68ed5fb03fc1567
+    round[ 5].start  1c05f271a417e04ff921c5c104701554
+    round[ 5].s_box  9c6b89a349f0e18499fda678f2515920
+    round[ 5].s_row  9cf0a62049fd59a399518984f26be178
+    round[ 5].m_col  aeb65ba974e0f822d73f567bdb64c877
+    round[ 5].k_sch  6de1f1486fa54f9275f8eb5373b8518d
+    round[ 6].start  c357aae11b45b7b0a2c7bd28a8dc99fa
+    round[ 6].s_box  2e5bacf8af6ea9e73ac67a34c286ee2d
+    round[ 6].s_row  2e6e7a2dafc6eef83a86ace7c25ba934
+    round[ 6].m_col  b951c33c02e9bd29ae25cdb1efa08cc7
+    round[ 6].k_sch  c656827fc9a799176f294cec6cd5598b
+    round[ 7].start  7f074143cb4e243ec10c815d8375d54c
+    round[ 7].s_box  d2c5831a1f2f36b278fe0c4cec9d0329
+    round[ 7].s_row  d22f0c291ffe031a789d83b2ecc5364c
+    round[ 7].m_col  ebb19e1c3ee7c9e87d7535e9ed6b9144
+    round[ 7].k_sch  3de23a75524775e727bf9eb45407cf39
+    round[ 8].start  d653a4696ca0bc0f5acaab5db96c5e7d
+    round[ 8].s_box  f6ed49f950e06576be74624c565058ff
+    round[ 8].s_row  f6e062ff507458f9be50497656ed654c
+    round[ 8].m_col  5174c8669da98435a8b3e62ca974a5ea
+    round[ 8].k_sch  0bdc905fc27b0948ad5245a4c1871c2f
+    round[ 9].start  5aa858395fd28d7d05e1a38868f3b9c5
+    round[ 9].s_box  bec26a12cfb55dff6bf80ac4450d56a6
+    round[ 9].s_row  beb50aa6cff856126b0d6aff45c25dc4
+    round[ 9].m_col  0f77ee31d2ccadc05430a83f4ef96ac3
+    round[ 9].k_sch  45f5a66017b2d387300d4d33640a820a
+    round[10].start  4a824851c57e7e47643de50c2af3e8c9
+    round[10].s_box  d61352d1a6f3f3a04327d9fee50d9bdd
+    round[10].s_row  d6f3d9dda6279bd1430d52a0e513f3fe
+    round[10].m_col  bd86f0ea748fc4f4630f11c1e9331233
+    round[10].k_sch  7ccff71cbeb4fe5413e6bbf0d261a7df
+    round[11].start  c14907f6ca3b3aa070e9aa313b52b5ec
+    round[11].s_box  783bc54274e280e0511eacc7e200d5ce
+    round[11].s_row  78e2acce741ed5425100c5e0e23b80c7
+    round[11].m_col  af8690415d6e1dd387e5fbedd5c89013
+    round[11].k_sch  f01afafee7a82979d7a5644ab3afe640
+    round[12].start  5f9c6abfbac634aa50409fa766677653
+    round[12].s_box  cfde0208f4b418ac5309db5c338538ed
+    round[12].s_row  cfb4dbedf4093808538502ac33de185c
+    round[12].m_col  7427fae4d8a695269ce83d315be0392b
+    round[12].k_sch  2541fe719bf500258813bbd55a721c0a
+    round[13].start  516604954353950314fb86e401922521
+    round[13].s_box  d133f22a1aed2a7bfa0f44697c4f3ffd
+    round[13].s_row  d1ed44fd1a0f3f2afa4ff27b7c332a69
+    round[13].m_col  2c21a820306f154ab712c75eee0da04f
+    round[13].k_sch  4e5a6699a9f24fe07e572baacdf8cdea
+    round[14].start  627bceb9999d5aaac945ecf423f56da5
+    round[14].s_box  aa218b56ee5ebeacdd6ecebf26e63c06
+    round[14].s_row  aa5ece06ee6e3c56dde68bac2621bebf
+    round[14].k_sch  24fc79ccbf0979e9371ac23c6d68de36
+    round[14].output 8ea2b7ca516745bfeafc49904b496089
+";
+
+    /// Encrypts the Appendix C plaintext and checks every line of `table` in order, so a
+    /// mistake in one step fails at the first line it changes, as in "round[ 3].m_col".
+    fn check_intermediate_values(key_size: usize, table: &str) {
+        let mut trace = Vec::new();
+        let aes = TextbookAes::new(&(0..key_size as u8).collect::<Vec<_>>());
+        let block = hex("00112233445566778899aabbccddeeff").try_into().unwrap();
+        aes.encrypt_block_traced(block, |round, step, bytes| {
+            trace.push((
+                format!("round[{:2}].{}", round, step.label()),
+                bytes.to_vec(),
+            ))
+        });
+
+        let expected: Vec<(&str, &str)> = table
+            .lines()
+            .filter_map(|line| line.trim().rsplit_once(char::is_whitespace))
+            .collect();
+        assert_eq!(trace.len(), expected.len(), "AES-{}", 8 * key_size);
+        for ((name, bytes), (expected_name, expected_bytes)) in trace.iter().zip(expected) {
+            assert_eq!(name, expected_name.trim());
+            assert_eq!(
+                *bytes,
+                hex(expected_bytes.trim()),
+                "AES-{} {}",
+                8 * key_size,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_appendix_c_intermediate_values() {
+        check_intermediate_values(16, APPENDIX_C1);
+        check_intermediate_values(24, APPENDIX_C2);
+        check_intermediate_values(32, APPENDIX_C3);
+    }
+
+    #[test]
+    fn test_matches_the_aes_crate() {
+        type Encrypt = fn(&[u8], [u8; 16]) -> [u8; 16];
+        fn with<C>(key: &[u8], block: [u8; 16]) -> [u8; 16]
+        where
+            C: KeyInit + BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+        {
+            let mut block = GenericArray::from(block);
+            C::new_from_slice(key).unwrap().encrypt_block(&mut block);
+            block.into()
+        }
+        let ciphers: [(usize, Encrypt); 3] = [
+            (16, with::<Aes128>),
+            (24, with::<Aes192>),
+            (32, with::<Aes256>),
+        ];
+        for seed in 0..20 {
+            let block: [u8; 16] = core::array::from_fn(|i| (i * 7 + seed * 31) as u8);
+            let key: Vec<u8> = (0..32).map(|i| (i * 13 + seed * 5) as u8).collect();
+            for (key_len, reference) in ciphers {
+                let aes = TextbookAes::new(&key[..key_len]);
+                assert_eq!(aes.rounds(), key_len / 4 + 6);
+                let cipher_text = aes.encrypt_block(block);
+                assert_eq!(cipher_text, reference(&key[..key_len], block));
+                assert_eq!(aes.decrypt_block(cipher_text), block);
+            }
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! A simplified TLS 1.2 record layer, to bridge the gap between modes and real protocols.
+//!
+//! TLS sends everything as _records_: a 5-byte header (content type, protocol version, length)
+//! followed by a protected fragment. TLS 1.2 supports two ways of protecting it, and both are
+//! built here from this crate's primitives.
+//!
+//! **CBC + HMAC (MAC-then-encrypt).** The sender computes an HMAC over the sequence number,
+//! header, and plaintext, appends it, pads, and CBC-encrypts under a fresh explicit IV.
+//! This ordering is a classic pitfall. The receiver has to decrypt and check the padding
+//! _before_ it can check the MAC, so it acts on unauthenticated data. If it reveals in any way
+//! (different alerts, different timing) whether the padding or the MAC was wrong, it becomes a
+//! padding oracle, and [`padding_oracle_decrypt_block`] recovers plaintext without the key.
+//! This is the root of Vaudenay's attack, POODLE, and Lucky Thirteen. [`CbcHmacRecordLayer`]
+//! reports the two failures differently on purpose, so that the attack can be tested.
+//!
+//! **AEAD.** With GCM there is nothing to check before authentication, and every kind of
+//! tampering gives the same single error. This is why TLS 1.3 only allows AEADs.
+//!
+//! Handshakes, alerts, and everything else are left out. Unlike real TLS, a failed record
+//! doesn't tear down the connection either, which makes the attack easier to demonstrate.
+
+use std::{error::Error, fmt};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    aes_decrypt, aes_encrypt,
+    gcm::{gcm_decrypt, gcm_encrypt, GCM_NONCE_SIZE, TAG_SIZE},
+    group, un_group, util, utils, BLOCK_SIZE,
+};
+
+/// The content type TLS uses for application data.
+pub const APPLICATION_DATA: u8 = 23;
+
+/// TLS 1.2 is version 3.3 on the wire, for historical reasons.
+pub const TLS_1_2: [u8; 2] = [3, 3];
+
+pub const RECORD_HEADER_SIZE: usize = 5;
+
+/// The largest plaintext one record may carry: 2^14 bytes.
+pub const MAX_PLAINTEXT_SIZE: usize = 1 << 14;
+
+/// The largest protected fragment, which TLS 1.2 allows 2048 bytes above the plaintext.
+pub const MAX_FRAGMENT_SIZE: usize = MAX_PLAINTEXT_SIZE + 2048;
+
+const MAC_SIZE: usize = 32;
+const EXPLICIT_NONCE_SIZE: usize = 8;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a record was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordError {
+    /// The header is malformed, or the fragment has an impossible length.
+    Malformed,
+    /// The plaintext is over [`MAX_PLAINTEXT_SIZE`], or the fragment over
+    /// [`MAX_FRAGMENT_SIZE`], so it has to be split across records. A received record over
+    /// either is what TLS answers with a `record_overflow` alert.
+    TooLarge,
+    /// CBC only: the decrypted padding was invalid. Telling this apart from `BadMac` is
+    /// exactly the mistake that enables padding oracle attacks.
+    BadPadding,
+    /// CBC only: the padding was fine but the MAC didn't match.
+    BadMac,
+    /// AEAD only: the record failed authentication.
+    Authentication,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RecordError::Malformed => "malformed record",
+            RecordError::TooLarge => "record too large",
+            RecordError::BadPadding => "bad record padding",
+            RecordError::BadMac => "bad record MAC",
+            RecordError::Authentication => "record failed authentication",
+        })
+    }
+}
+
+impl Error for RecordError {}
+
+/// Puts a header in front of a protected fragment of at most [`MAX_FRAGMENT_SIZE`] bytes.
+pub fn encode_record(content_type: u8, fragment: &[u8]) -> Result<Vec<u8>, RecordError> {
+    if fragment.len() > MAX_FRAGMENT_SIZE {
+        return Err(RecordError::TooLarge);
+    }
+    let mut record = vec![content_type, TLS_1_2[0], TLS_1_2[1]];
+    record.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+    record.extend_from_slice(fragment);
+    Ok(record)
+}
+
+/// Splits a record into its content type and fragment, checking the header. A fragment over
+/// [`MAX_FRAGMENT_SIZE`] is rejected before anyone spends time decrypting it.
+fn decode_record(record: &[u8]) -> Result<(u8, &[u8]), RecordError> {
+    if record.len() < RECORD_HEADER_SIZE || record[1..3] != TLS_1_2 {
+        return Err(RecordError::Malformed);
+    }
+    let length = u16::from_be_bytes([record[3], record[4]]) as usize;
+    if record.len() != RECORD_HEADER_SIZE + length {
+        return Err(RecordError::Malformed);
+    }
+    if length > MAX_FRAGMENT_SIZE {
+        return Err(RecordError::TooLarge);
+    }
+    Ok((record[0], &record[RECORD_HEADER_SIZE..]))
+}
+
+/// The bytes TLS authenticates besides the plaintext: `seq | type | version | length`. The
+/// callers have checked that `length` fits in 16 bits.
+fn pseudo_header(sequence: u64, content_type: u8, length: usize) -> Vec<u8> {
+    let mut header = sequence.to_be_bytes().to_vec();
+    header.extend_from_slice(&[content_type, TLS_1_2[0], TLS_1_2[1]]);
+    header.extend_from_slice(&(length as u16).to_be_bytes());
+    header
+}
+
+/// One direction of a TLS 1.2 connection using an AES-CBC + HMAC-SHA256 cipher suite.
+pub struct CbcHmacRecordLayer {
+    key: [u8; BLOCK_SIZE],
+    mac_key: [u8; MAC_SIZE],
+    sequence: u64,
+}
+
+impl CbcHmacRecordLayer {
+    pub fn new(key: [u8; BLOCK_SIZE], mac_key: [u8; MAC_SIZE]) -> Self {
+        CbcHmacRecordLayer {
+            key,
+            mac_key,
+            sequence: 0,
+        }
+    }
+
+    fn mac(&self, content_type: u8, plain_text: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.mac_key).expect("HMAC takes any key size");
+        mac.update(&pseudo_header(
+            self.sequence,
+            content_type,
+            plain_text.len(),
+        ));
+        mac.update(plain_text);
+        mac
+    }
+
+    /// MAC, then pad, then encrypt under a fresh random IV which is sent in the clear. Fails
+    /// with [`RecordError::TooLarge`] for plaintext over [`MAX_PLAINTEXT_SIZE`].
+    pub fn seal(&mut self, content_type: u8, plain_text: &[u8]) -> Result<Vec<u8>, RecordError> {
+        if plain_text.len() > MAX_PLAINTEXT_SIZE {
+            return Err(RecordError::TooLarge);
+        }
+        let mut data = plain_text.to_vec();
+        data.extend(self.mac(content_type, plain_text).finalize().into_bytes());
+
+        // TLS padding: `p + 1` bytes, each with the value `p`. Unlike PKCS#7, the bytes
+        // count the padding _excluding_ the length byte itself.
+        let pad_len = BLOCK_SIZE - 1 - data.len() % BLOCK_SIZE;
+        data.extend(std::iter::repeat_n(pad_len as u8, pad_len + 1));
+
+        let iv = utils::create_rand_init_vector();
+        let mut previous_block = iv;
+        let mut encrypted_blocks = vec![iv];
+        for block in group(data) {
+            let encrypted_block = aes_encrypt(util::xor(&block, &previous_block), &self.key);
+            encrypted_blocks.push(encrypted_block);
+            previous_block = encrypted_block;
+        }
+
+        self.sequence += 1;
+        encode_record(content_type, &un_group(encrypted_blocks))
+    }
+
+    /// Decrypts, checks the padding, and only then checks the MAC, returning the content type
+    /// and plaintext.
+    pub fn open(&mut self, record: &[u8]) -> Result<(u8, Vec<u8>), RecordError> {
+        let (content_type, fragment) = decode_record(record)?;
+        if fragment.len() % BLOCK_SIZE != 0 || fragment.len() < 2 * BLOCK_SIZE {
+            return Err(RecordError::Malformed);
+        }
+
+        let blocks = group(fragment.to_vec());
+        let mut decrypted_blocks = Vec::new();
+        for pair in blocks.windows(2) {
+            let decrypted_block = aes_decrypt(pair[1], &self.key);
+            decrypted_blocks.push(util::xor(&decrypted_block, &pair[0]));
+        }
+        let mut data = un_group(decrypted_blocks);
+
+        // The pitfall: this check runs on data nobody has authenticated yet.
+        let pad_len = *data.last().unwrap() as usize;
+        if pad_len + 1 > data.len()
+            || data[data.len() - pad_len - 1..]
+                .iter()
+                .any(|&b| b as usize != pad_len)
+        {
+            return Err(RecordError::BadPadding);
+        }
+        data.truncate(data.len() - pad_len - 1);
+
+        if data.len() < MAC_SIZE {
+            return Err(RecordError::BadMac);
+        }
+        let received_mac = data.split_off(data.len() - MAC_SIZE);
+        self.mac(content_type, &data)
+            .verify_slice(&received_mac)
+            .map_err(|_| RecordError::BadMac)?;
+        if data.len() > MAX_PLAINTEXT_SIZE {
+            return Err(RecordError::TooLarge);
+        }
+
+        self.sequence += 1;
+        Ok((content_type, data))
+    }
+}
+
+/// One direction of a TLS 1.2 connection using an AES-GCM cipher suite.
+///
+/// The nonce is a 4-byte implicit salt (agreed during the handshake) followed by an 8-byte
+/// explicit part sent with each record. Here the explicit part is the sequence number.
+pub struct GcmRecordLayer {
+    key: [u8; BLOCK_SIZE],
+    salt: [u8; 4],
+    sequence: u64,
+}
+
+impl GcmRecordLayer {
+    pub fn new(key: [u8; BLOCK_SIZE], salt: [u8; 4]) -> Self {
+        GcmRecordLayer {
+            key,
+            salt,
+            sequence: 0,
+        }
+    }
+
+    fn nonce(&self, explicit: &[u8]) -> [u8; GCM_NONCE_SIZE] {
+        let mut nonce = [0u8; GCM_NONCE_SIZE];
+        nonce[..4].copy_from_slice(&self.salt);
+        nonce[4..].copy_from_slice(explicit);
+        nonce
+    }
+
+    /// Fails with [`RecordError::TooLarge`] for plaintext over [`MAX_PLAINTEXT_SIZE`].
+    pub fn seal(&mut self, content_type: u8, plain_text: &[u8]) -> Result<Vec<u8>, RecordError> {
+        if plain_text.len() > MAX_PLAINTEXT_SIZE {
+            return Err(RecordError::TooLarge);
+        }
+        let explicit = self.sequence.to_be_bytes();
+        let aad = pseudo_header(self.sequence, content_type, plain_text.len());
+
+        let mut fragment = explicit.to_vec();
+        fragment.extend(gcm_encrypt(
+            plain_text.to_vec(),
+            self.key,
+            self.nonce(&explicit),
+            &aad,
+        ));
+
+        self.sequence += 1;
+        encode_record(content_type, &fragment)
+    }
+
+    pub fn open(&mut self, record: &[u8]) -> Result<(u8, Vec<u8>), RecordError> {
+        let (content_type, fragment) = decode_record(record)?;
+        if fragment.len() < EXPLICIT_NONCE_SIZE + TAG_SIZE {
+            return Err(RecordError::Malformed);
+        }
+
+        let (explicit, body) = fragment.split_at(EXPLICIT_NONCE_SIZE);
+        if body.len() - TAG_SIZE > MAX_PLAINTEXT_SIZE {
+            return Err(RecordError::TooLarge);
+        }
+        let aad = pseudo_header(self.sequence, content_type, body.len() - TAG_SIZE);
+        let plain_text = gcm_decrypt(body.to_vec(), self.key, self.nonce(explicit), &aad)
+            .map_err(|_| RecordError::Authentication)?;
+
+        self.sequence += 1;
+        Ok((content_type, plain_text))
+    }
+}
+
+/// Recovers the plaintext of one CBC block without the key, given an oracle that says whether
+/// a fragment decrypts to valid TLS padding.
+///
+/// `previous` is the ciphertext block (or IV) before `target`. The attack sends fragments that
+/// end in `forged | target`. Their last plaintext block is `D(target) ^ forged`, and `forged`
+/// is chosen one byte at a time until the padding comes out valid. At that point we know the
+/// last bytes of `D(target)`, and XORing with the real `previous` gives the plaintext. Two
+/// junk blocks are put in front so the fragment is always long enough to contain a MAC.
+pub fn padding_oracle_decrypt_block(
+    previous: [u8; BLOCK_SIZE],
+    target: [u8; BLOCK_SIZE],
+    mut padding_is_valid: impl FnMut(&[u8]) -> bool,
+) -> [u8; BLOCK_SIZE] {
+    // D(target), learned from the last byte backwards.
+    let mut intermediate = [0u8; BLOCK_SIZE];
+
+    for position in (0..BLOCK_SIZE).rev() {
+        let pad_value = (BLOCK_SIZE - 1 - position) as u8;
+
+        let mut forged = [0u8; BLOCK_SIZE];
+        for i in position + 1..BLOCK_SIZE {
+            forged[i] = intermediate[i] ^ pad_value;
+        }
+
+        let guess = (0..=255u8).find(|&guess| {
+            forged[position] = guess;
+            let mut fragment = vec![0u8; 2 * BLOCK_SIZE];
+            fragment.extend_from_slice(&forged);
+            fragment.extend_from_slice(&target);
+            if !padding_is_valid(&fragment) {
+                return false;
+            }
+
+            // For the last byte, a guess might accidentally produce longer valid padding
+            // like `.. 01 01`. Changing the byte before it rules that out.
+            if position == BLOCK_SIZE - 1 {
+                fragment[3 * BLOCK_SIZE - 2] ^= 0xFF;
+                return padding_is_valid(&fragment);
+            }
+            true
+        });
+
+        let guess = guess.expect("the oracle never accepted any padding");
+        intermediate[position] = guess ^ pad_value;
+    }
+
+    util::xor(&intermediate, &previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [0x42; BLOCK_SIZE];
+    const MAC_KEY: [u8; MAC_SIZE] = [0x24; MAC_SIZE];
+
+    #[test]
+    fn test_cbc_hmac_records() {
+        let mut sender = CbcHmacRecordLayer::new(KEY, MAC_KEY);
+        let mut receiver = CbcHmacRecordLayer::new(KEY, MAC_KEY);
+
+        for message in [&b""[..], b"GET / HTTP/1.1", &[7u8; 100]] {
+            let record = sender.seal(APPLICATION_DATA, message).unwrap();
+            assert_eq!(record[..3], [APPLICATION_DATA, 3, 3]);
+            assert_eq!(
+                receiver.open(&record),
+                Ok((APPLICATION_DATA, message.to_vec()))
+            );
+        }
+
+        // Replaying a record fails, because the sequence number is part of the MAC.
+        let record = sender.seal(APPLICATION_DATA, b"once").unwrap();
+        assert!(receiver.open(&record).is_ok());
+        assert_eq!(receiver.open(&record), Err(RecordError::BadMac));
+    }
+
+    #[test]
+    fn test_gcm_records() {
+        let mut sender = GcmRecordLayer::new(KEY, [1, 2, 3, 4]);
+        let mut receiver = GcmRecordLayer::new(KEY, [1, 2, 3, 4]);
+
+        let record = sender.seal(APPLICATION_DATA, b"GET / HTTP/1.1").unwrap();
+        assert_eq!(
+            receiver.open(&record),
+            Ok((APPLICATION_DATA, b"GET / HTTP/1.1".to_vec()))
+        );
+
+        // Whatever gets tampered with, there is only one kind of failure to observe.
+        let record = sender.seal(APPLICATION_DATA, b"secret").unwrap();
+        for i in RECORD_HEADER_SIZE..record.len() {
+            let mut tampered = record.clone();
+            tampered[i] ^= 1;
+            assert_eq!(receiver.open(&tampered), Err(RecordError::Authentication));
+        }
+        assert!(receiver.open(&record).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_oversized_records() {
+        let mut cbc = CbcHmacRecordLayer::new(KEY, MAC_KEY);
+        let mut gcm = GcmRecordLayer::new(KEY, [1, 2, 3, 4]);
+        let largest = vec![0; MAX_PLAINTEXT_SIZE];
+        let record = cbc.seal(APPLICATION_DATA, &largest).unwrap();
+        assert!(record.len() <= RECORD_HEADER_SIZE + MAX_FRAGMENT_SIZE);
+        gcm.seal(APPLICATION_DATA, &largest).unwrap();
+
+        // 65,552 bytes would have wrapped around to a 16-byte length in the header.
+        let too_large = vec![0; (1 << 16) + 16];
+        assert_eq!(
+            cbc.seal(APPLICATION_DATA, &too_large),
+            Err(RecordError::TooLarge)
+        );
+        assert_eq!(
+            gcm.seal(APPLICATION_DATA, &too_large),
+            Err(RecordError::TooLarge)
+        );
+        assert_eq!(
+            encode_record(APPLICATION_DATA, &too_large),
+            Err(RecordError::TooLarge)
+        );
+
+        // Received records over the limits are refused before decryption: the GCM one would
+        // otherwise fail authentication.
+        let oversized = |fragment_len: usize| {
+            let mut record = vec![APPLICATION_DATA, TLS_1_2[0], TLS_1_2[1]];
+            record.extend_from_slice(&(fragment_len as u16).to_be_bytes());
+            record.resize(RECORD_HEADER_SIZE + fragment_len, 0);
+            record
+        };
+        for fragment_len in [MAX_FRAGMENT_SIZE + BLOCK_SIZE, u16::MAX as usize] {
+            let record = oversized(fragment_len);
+            assert_eq!(cbc.open(&record), Err(RecordError::TooLarge));
+            assert_eq!(gcm.open(&record), Err(RecordError::TooLarge));
+        }
+        let record = oversized(EXPLICIT_NONCE_SIZE + MAX_PLAINTEXT_SIZE + 1 + TAG_SIZE);
+        assert_eq!(gcm.open(&record), Err(RecordError::TooLarge));
+    }
+
+    #[test]
+    fn test_padding_oracle_attack() {
+        let secret = b"session=8f2a61d0c33e41b7; admin=true";
+        let record = CbcHmacRecordLayer::new(KEY, MAC_KEY)
+            .seal(APPLICATION_DATA, secret)
+            .unwrap();
+
+        // The attacker only gets to see whether the receiver complained about the padding.
+        let mut receiver = CbcHmacRecordLayer::new(KEY, MAC_KEY);
+        let mut queries = 0;
+        let mut oracle = |fragment: &[u8]| {
+            queries += 1;
+            let forged = encode_record(APPLICATION_DATA, fragment).unwrap();
+            receiver.open(&forged) != Err(RecordError::BadPadding)
+        };
+
+        let blocks = group(record[RECORD_HEADER_SIZE..].to_vec());
+        let mut recovered = Vec::new();
+        for pair in blocks.windows(2) {
+            recovered.extend(padding_oracle_decrypt_block(pair[0], pair[1], &mut oracle));
+        }
+
+        assert_eq!(&recovered[..secret.len()], secret);
+        assert!(queries < blocks.len() * BLOCK_SIZE * 256 * 2);
+    }
+}
@@ -0,0 +1,141 @@
+//! Optional `tracing` instrumentation of the public APIs.
+//!
+//! Every instrumented call opens a span and emits a single event when it finishes, carrying the
+//! operation, the mode, the input length, how long it took, and whether it succeeded.
+//!
+//! Nothing else is ever recorded. [`Operation::start`] only accepts `&'static str` names and a
+//! length, so there is simply no way to hand it a key, an IV, keystream, or plaintext bytes.
+//! Without the `tracing` feature, all of this compiles down to nothing.
+//!
+//! Callers' own spans are the other way keys end up in logs, through `?value` fields. The
+//! types that hold keys or key schedules, such as
+//! [`CipherContext`](crate::backend::CipherContext), [`Session`](crate::session::Session) and
+//! the [chunked](crate::chunked) streams, have `Debug` implementations that leave them out.
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// A single instrumented call. Call [`Operation::finish`] with the outcome; an operation that is
+/// dropped without finishing (early return, panic) is reported as a failure.
+pub(crate) struct Operation {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(feature = "tracing")]
+    start: Instant,
+    #[cfg(feature = "tracing")]
+    finished: bool,
+}
+
+impl Operation {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn start(operation: &'static str, mode: &'static str, input_len: usize) -> Self {
+        Operation {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("aes_modes", operation, mode, input_len),
+            #[cfg(feature = "tracing")]
+            start: Instant::now(),
+            #[cfg(feature = "tracing")]
+            finished: false,
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_mut, unused_variables))]
+    pub(crate) fn finish(mut self, success: bool) {
+        #[cfg(feature = "tracing")]
+        {
+            self.emit(success);
+            self.finished = true;
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn emit(&self, success: bool) {
+        let elapsed_us = self.start.elapsed().as_micros() as u64;
+        self.span
+            .in_scope(|| tracing::debug!(elapsed_us, success, "finished"));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Operation {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.emit(false);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    use crate::{cbc_encrypt, ctr_decrypt, ctr_encrypt, ecb_encrypt, BLOCK_SIZE};
+
+    /// Collects the Debug rendering of every field of every span and event.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Capture {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let line = format!("{}={:?}", field.name(), value);
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            attrs.record(&mut self.clone());
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, values: &span::Record<'_>) {
+            values.record(&mut self.clone());
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            event.record(&mut self.clone());
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn test_no_secrets_in_events() {
+        let key = [0x5Au8; BLOCK_SIZE];
+        let plain_text = b"TOP SECRET TOP SECRET".to_vec();
+
+        let capture = Capture::default();
+        tracing::subscriber::with_default(capture.clone(), || {
+            ecb_encrypt(plain_text.clone(), key);
+            cbc_encrypt(plain_text.clone(), key);
+            ctr_decrypt(ctr_encrypt(plain_text.clone(), key), key);
+        });
+
+        let lines = capture.0.lock().unwrap();
+        assert!(lines.contains(&"mode=\"ctr\"".to_string()));
+        assert!(lines.contains(&format!("input_len={}", plain_text.len())));
+        assert!(lines.contains(&"success=true".to_string()));
+
+        let everything = lines.join("\n");
+        assert!(!everything.contains("SECRET"));
+        assert!(!everything.contains(&format!("{:?}", &plain_text[..4])));
+        assert!(!everything.contains(&format!("{:?}", &key[..4])));
+        assert!(!everything.contains("90, 90"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Base64url and hex as streaming `Read` and `Write` adapters.
+//!
+//! The encrypting adapters, [`StreamEncryptor`](crate::chunked::StreamEncryptor) and the rest,
+//! produce binary. An [`EncodingWriter`] sits between one of them and its destination and
+//! writes the text form as the ciphertext arrives; a [`DecodingReader`] sits in front of a
+//! decryptor and turns text back into bytes as they are read. Neither holds more than a few
+//! bytes of the payload, so a pipeline such as
+//!
+//! ```text
+//! file -> StreamEncryptor -> EncodingWriter -> file
+//! ```
+//!
+//! runs in constant memory however large the file. This is the `--armor` option of the
+//! `aes-modes` command.
+//!
+//! Base64url is unpadded, as everywhere in the crate, and the decoder accepts hex in either
+//! case. The decoder skips whitespace, so line-wrapped output from
+//! [`EncodingWriter::with_line_length`] reads back as it is.
+
+use std::io::{self, Read, Write};
+
+use crate::utils;
+
+/// A text encoding of binary data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Base64Url,
+    Hex,
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+impl Encoding {
+    /// How many bytes of data encode to a whole group of characters.
+    fn bytes_per_group(self) -> usize {
+        match self {
+            Encoding::Base64Url => 3,
+            Encoding::Hex => 1,
+        }
+    }
+
+    fn chars_per_group(self) -> usize {
+        match self {
+            Encoding::Base64Url => 4,
+            Encoding::Hex => 2,
+        }
+    }
+
+    /// Whether text may end with `chars` characters of a partial group.
+    fn decodes_partial(self, chars: usize) -> bool {
+        match self {
+            Encoding::Base64Url => chars != 1,
+            Encoding::Hex => chars == 0,
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Base64Url => utils::base64url_encode(data).into_bytes(),
+            Encoding::Hex => data
+                .iter()
+                .flat_map(|byte| {
+                    [
+                        HEX_DIGITS[(byte >> 4) as usize],
+                        HEX_DIGITS[(byte & 0xF) as usize],
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    fn decode(self, text: &[u8]) -> Option<Vec<u8>> {
+        let text = std::str::from_utf8(text).ok()?;
+        match self {
+            Encoding::Base64Url => utils::base64url_decode(text),
+            Encoding::Hex => utils::hex_decode(text),
+        }
+    }
+}
+
+fn invalid_data(encoding: Encoding) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid {:?} input", encoding),
+    )
+}
+
+/// Writes the encoding of everything written to it to `writer`.
+///
+/// The last partial group is only written by [`finish`](Self::finish), which must be called.
+pub struct EncodingWriter<W: Write> {
+    writer: W,
+    encoding: Encoding,
+    /// Data that doesn't fill a group yet.
+    pending: Vec<u8>,
+    line_length: Option<usize>,
+    column: usize,
+}
+
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(encoding: Encoding, writer: W) -> Self {
+        EncodingWriter {
+            writer,
+            encoding,
+            pending: Vec::new(),
+            line_length: None,
+            column: 0,
+        }
+    }
+
+    /// Breaks the output into lines of `line_length` characters, each ending with a newline.
+    ///
+    /// # Panics
+    ///
+    /// If `line_length` is zero.
+    pub fn with_line_length(mut self, line_length: usize) -> Self {
+        assert!(line_length > 0, "lines must hold at least one character");
+        self.line_length = Some(line_length);
+        self
+    }
+
+    fn write_text(&mut self, text: &[u8]) -> io::Result<()> {
+        let Some(line_length) = self.line_length else {
+            return self.writer.write_all(text);
+        };
+        let mut text = text;
+        while !text.is_empty() {
+            let n = text.len().min(line_length - self.column);
+            self.writer.write_all(&text[..n])?;
+            self.column += n;
+            text = &text[n..];
+            if self.column == line_length {
+                self.writer.write_all(b"\n")?;
+                self.column = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the last partial group, and ends the last line. Returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let text = self.encoding.encode(&self.pending);
+        self.write_text(&text)?;
+        if self.column > 0 {
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let group = self.encoding.bytes_per_group();
+        let whole = self.pending.len() - self.pending.len() % group;
+        let text = self.encoding.encode(&self.pending[..whole]);
+        self.pending.drain(..whole);
+        self.write_text(&text)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the data encoded in what `reader` gives, skipping whitespace.
+///
+/// Invalid characters, or text that ends partway through a group, fail with
+/// [`io::ErrorKind::InvalidData`].
+pub struct DecodingReader<R: Read> {
+    reader: R,
+    encoding: Encoding,
+    /// Characters that don't fill a group yet.
+    pending: Vec<u8>,
+    decoded: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn new(encoding: Encoding, reader: R) -> Self {
+        DecodingReader {
+            reader,
+            encoding,
+            pending: Vec::new(),
+            decoded: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Decodes the next run of input into `decoded`, or returns false at the end.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut text = [0u8; 8192];
+        while !self.finished {
+            let n = self.reader.read(&mut text)?;
+            if n == 0 {
+                self.finished = true;
+            }
+            self.pending
+                .extend(text[..n].iter().filter(|byte| !byte.is_ascii_whitespace()));
+            let group = self.encoding.chars_per_group();
+            let whole = if self.finished {
+                self.pending.len()
+            } else {
+                self.pending.len() - self.pending.len() % group
+            };
+            if whole == 0 {
+                continue;
+            }
+            if self.finished && !self.encoding.decodes_partial(whole % group) {
+                return Err(invalid_data(self.encoding));
+            }
+            self.decoded = self
+                .encoding
+                .decode(&self.pending[..whole])
+                .ok_or_else(|| invalid_data(self.encoding))?;
+            self.pending.drain(..whole);
+            self.position = 0;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.decoded.len() && !self.fill()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.decoded.len() - self.position);
+        buf[..n].copy_from_slice(&self.decoded[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunked::{StreamDecryptor, StreamEncryptor};
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        for encoding in [Encoding::Base64Url, Encoding::Hex] {
+            for len in [0, 1, 2, 3, 4, 999, 1000] {
+                let mut writer = EncodingWriter::new(encoding, Vec::new()).with_line_length(64);
+                // Uneven writes, so groups straddle them.
+                for piece in data[..len].chunks(7) {
+                    writer.write_all(piece).unwrap();
+                }
+                let text = writer.finish().unwrap();
+                assert!(text.split(|&c| c == b'\n').all(|line| line.len() <= 64));
+
+                let mut decoded = Vec::new();
+                DecodingReader::new(encoding, text.as_slice())
+                    .read_to_end(&mut decoded)
+                    .unwrap();
+                assert_eq!(decoded, data[..len]);
+            }
+        }
+        let mut writer = EncodingWriter::new(Encoding::Hex, Vec::new());
+        writer.write_all(&[0xab, 0x01]).unwrap();
+        assert_eq!(writer.finish().unwrap(), b"ab01");
+    }
+
+    #[test]
+    fn test_rejects_invalid_text() {
+        for (encoding, text) in [
+            (Encoding::Base64Url, &b"QUJD+A"[..]),
+            (Encoding::Base64Url, b"QUJDR"),
+            (Encoding::Hex, b"abc"),
+            (Encoding::Hex, b"0g"),
+        ] {
+            let error = DecodingReader::new(encoding, text)
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_armored_stream() {
+        let secret = b"master secret for the armor test";
+        let data = vec![42u8; 100_000];
+        let mut encryptor = StreamEncryptor::new(
+            secret,
+            EncodingWriter::new(Encoding::Base64Url, Vec::new()).with_line_length(76),
+        )
+        .unwrap();
+        encryptor.write_all(&data).unwrap();
+        let armored = encryptor.finish().unwrap().finish().unwrap();
+        assert!(armored.is_ascii());
+
+        let mut decrypted = Vec::new();
+        StreamDecryptor::new(
+            secret,
+            DecodingReader::new(Encoding::Base64Url, armored.as_slice()),
+        )
+        .unwrap()
+        .read_to_end(&mut decrypted)
+        .unwrap();
+        assert_eq!(decrypted, data);
+    }
+}
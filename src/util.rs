@@ -0,0 +1,142 @@
+//! The small pieces the modes in this crate are built from, for attack exercises and
+//! experiments that need them on their own.
+//!
+//! This module is part of the stable API: the modes use exactly these functions, so anything
+//! built on them sees the same block splits, counter layouts and field arithmetic the crate
+//! does.
+//!
+//! GF(2^128) "doubling" (multiplying by x) comes in two conventions, which differ only in
+//! byte order: [`gf128_double`] is the big-endian one of CMAC and SIV, and [`gf128_double_le`]
+//! the little-endian one of XTS.
+
+use crate::{gcm::GCM_NONCE_SIZE, BLOCK_SIZE, NONCE_SIZE};
+
+pub use crate::utils::hexdump;
+
+/// XORs two equal-length arrays.
+pub fn xor<const N: usize>(a: &[u8; N], b: &[u8; N]) -> [u8; N] {
+    core::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// XORs `other` into `target`.
+///
+/// # Panics
+///
+/// If the two have different lengths.
+pub fn xor_in_place(target: &mut [u8], other: &[u8]) {
+    assert_eq!(target.len(), other.len(), "XOR of different lengths");
+    for (byte, other) in target.iter_mut().zip(other) {
+        *byte ^= other;
+    }
+}
+
+/// Splits `data` into whole blocks and whatever is left over.
+pub fn split_blocks(data: &[u8]) -> (&[[u8; BLOCK_SIZE]], &[u8]) {
+    data.as_chunks()
+}
+
+/// Like [`split_blocks`], for modifying the blocks in place.
+pub fn split_blocks_mut(data: &mut [u8]) -> (&mut [[u8; BLOCK_SIZE]], &mut [u8]) {
+    data.as_chunks_mut()
+}
+
+/// The whole blocks of `data`, in order. A partial block at the end is skipped: use
+/// [`split_blocks`] to get at it.
+pub fn blocks(data: &[u8]) -> impl ExactSizeIterator<Item = &[u8; BLOCK_SIZE]> {
+    split_blocks(data).0.iter()
+}
+
+/// Like [`blocks`], for modifying the blocks in place.
+pub fn blocks_mut(data: &mut [u8]) -> impl ExactSizeIterator<Item = &mut [u8; BLOCK_SIZE]> {
+    split_blocks_mut(data).0.iter_mut()
+}
+
+/// The counter block [`ctr_encrypt`](crate::ctr_encrypt) encrypts for block number `counter`:
+/// the nonce followed by the counter in little-endian order.
+pub fn ctr_counter_block(nonce: &[u8; NONCE_SIZE], counter: u64) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..NONCE_SIZE].copy_from_slice(nonce);
+    block[NONCE_SIZE..].copy_from_slice(&counter.to_le_bytes());
+    block
+}
+
+/// A GCM counter block: the 96-bit nonce followed by a 32-bit big-endian counter. Counter 1 is
+/// the block that masks the tag, and the keystream starts at counter 2.
+pub fn gcm_counter_block(nonce: &[u8; GCM_NONCE_SIZE], counter: u32) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..GCM_NONCE_SIZE].copy_from_slice(nonce);
+    block[GCM_NONCE_SIZE..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+/// Increments the last 32 bits of a counter block, big-endian and wrapping around, as GCM
+/// does. The rest of the block never changes.
+pub fn inc32(block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let mut out = block;
+    let counter = u32::from_be_bytes(block[12..].try_into().unwrap());
+    out[12..].copy_from_slice(&counter.wrapping_add(1).to_be_bytes());
+    out
+}
+
+/// Multiplication by x in GF(2^128), as CMAC and S2V define it.
+pub fn gf128_double(block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let value = u128::from_be_bytes(block);
+    let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+    ((value << 1) ^ carry).to_be_bytes()
+}
+
+/// Multiplication by α (that is, x) in GF(2^128), in the little-endian convention of XTS.
+pub fn gf128_double_le(block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let value = u128::from_le_bytes(block);
+    let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+    ((value << 1) ^ carry).to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{aes_encrypt, ctr_encrypt_with_nonce};
+
+    use super::*;
+
+    #[test]
+    fn test_blocks_and_counters() {
+        let data = [7u8; 40];
+        let (whole, rest) = split_blocks(&data);
+        assert_eq!((whole.len(), rest.len()), (2, 8));
+        assert_eq!(blocks(&data).len(), 2);
+
+        // Encrypting zeros in CTR mode gives back the encrypted counter blocks.
+        let key = [1u8; BLOCK_SIZE];
+        let nonce = [2u8; NONCE_SIZE];
+        let mut keystream = ctr_encrypt_with_nonce(vec![0u8; 32], key, nonce).split_off(8);
+        for (i, block) in blocks_mut(&mut keystream).enumerate() {
+            xor_in_place(
+                block,
+                &aes_encrypt(ctr_counter_block(&nonce, i as u64), &key),
+            );
+        }
+        assert_eq!(keystream, [0u8; 32]);
+
+        let j0 = gcm_counter_block(&[0u8; GCM_NONCE_SIZE], u32::MAX);
+        assert_eq!(inc32(j0), [0u8; BLOCK_SIZE]);
+        assert_eq!(xor(&[1, 2], &[3, 3]), [2, 1]);
+    }
+
+    #[test]
+    fn test_doubling() {
+        // The CMAC subkey K1 of the RFC 4493 key, from L = AES(K, 0).
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let k1 = gf128_double(aes_encrypt([0u8; BLOCK_SIZE], &key));
+        assert_eq!(u128::from_be_bytes(k1), 0xfbeed618357133667c85e08f7236a8de);
+
+        // The two conventions are the same operation on byte-reversed blocks.
+        let mut reversed = k1;
+        reversed.reverse();
+        let mut doubled = gf128_double_le(reversed);
+        doubled.reverse();
+        assert_eq!(doubled, gf128_double(k1));
+    }
+}
@@ -1,30 +1,285 @@
-use rand::Rng;
+use std::{cell::RefCell, collections::HashMap, process};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 const BLOCK_SIZE: usize = 16;
 const NONCE_SIZE: usize = 8;
-pub fn xor_bytes(a: &[u8], b: &[u8]) -> Vec<u8> {
-    a.iter()
-        .zip(b.iter())
-        .map(|(x, y)| x ^ y)
-        .collect()
-}
-
-pub fn xor_block_bytes(block1: &[u8; BLOCK_SIZE], block2: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
-    let mut xored = [0u8; BLOCK_SIZE];
-    for i in 0..BLOCK_SIZE {
-        xored[i] = block1[i] ^ block2[i];
-    }
-    xored
-}
-
 pub fn create_rand_init_vector() -> [u8; BLOCK_SIZE] {
     let mut rand_init_vector = [0u8; BLOCK_SIZE];
-    rand::thread_rng().fill(&mut rand_init_vector);
+    fill_random(&mut rand_init_vector);
     rand_init_vector
 }
 
 pub fn create_rand_nonce() -> [u8; NONCE_SIZE] {
     let mut nonce = [0u8; NONCE_SIZE];
-    rand::thread_rng().fill(&mut nonce);
+    fill_random(&mut nonce);
     nonce
 }
+
+pub fn create_rand_key() -> [u8; BLOCK_SIZE] {
+    let mut key = [0u8; BLOCK_SIZE];
+    fill_random(&mut key);
+    key
+}
+
+pub fn create_rand_gcm_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    fill_random(&mut nonce);
+    nonce
+}
+
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Unpadded base64url, which is safe to use in file names and URLs.
+pub fn base64url_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..=group.len() {
+            encoded.push(BASE64_URL[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// The value of a base64url character, and a mask that is 0xFF if it is one. The lookup is done
+/// with arithmetic rather than a table or a search, so its timing doesn't depend on the
+/// character, and decoding key material doesn't leak it through the cache.
+fn base64url_value(c: u8) -> (u8, u8) {
+    let c = c as i32;
+    // -1 if lo <= c <= hi, and 0 otherwise.
+    let in_range = |lo: i32, hi: i32| ((lo - 1 - c) & (c - hi - 1)) >> 8;
+    let upper = in_range(0x41, 0x5A);
+    let lower = in_range(0x61, 0x7A);
+    let digit = in_range(0x30, 0x39);
+    let dash = in_range(0x2D, 0x2D);
+    let underscore = in_range(0x5F, 0x5F);
+    let value = (upper & (c - 0x41))
+        | (lower & (c - 0x61 + 26))
+        | (digit & (c - 0x30 + 52))
+        | (dash & 62)
+        | (underscore & 63);
+    (
+        value as u8,
+        (upper | lower | digit | dash | underscore) as u8,
+    )
+}
+
+/// Decodes unpadded base64url, in constant time for a given length.
+pub fn base64url_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut valid = 0xFFu8;
+    for group in encoded.as_bytes().chunks(4) {
+        if group.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, c) in group.iter().enumerate() {
+            let (value, is_valid) = base64url_value(*c);
+            valid &= is_valid;
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..group.len() - 1 {
+            decoded.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    (valid == 0xFF).then_some(decoded)
+}
+
+/// The value of a hex digit in either case, and a mask that is 0xFF if it is one, computed
+/// without branches or tables like [`base64url_value`].
+fn hex_value(c: u8) -> (u8, u8) {
+    let c = c as u32;
+    let digit = c ^ 0x30;
+    let is_digit = (digit.wrapping_sub(10) >> 8) & 0xFF;
+    let letter = (c & !0x20).wrapping_sub(55);
+    let is_letter = ((letter.wrapping_sub(10) ^ letter.wrapping_sub(16)) >> 8) & 0xFF;
+    (
+        ((is_digit & digit) | (is_letter & letter)) as u8,
+        (is_digit | is_letter) as u8,
+    )
+}
+
+/// Decodes hex, in constant time for a given length.
+pub fn hex_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 2);
+    let mut valid = 0xFFu8;
+    for pair in encoded.chunks(2) {
+        let (high, high_valid) = hex_value(pair[0]);
+        let (low, low_valid) = hex_value(pair[1]);
+        valid &= high_valid & low_valid;
+        decoded.push(high << 4 | low);
+    }
+    (valid == 0xFF).then_some(decoded)
+}
+
+const RED: &str = "\x1b[31m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// A hex dump with one 16-byte block per line: the offset, the bytes in two groups of eight,
+/// and the printable ones as ASCII. A block that repeats an earlier one is marked with the
+/// index of the first, which in an ECB ciphertext shows where the plaintext repeats. With
+/// `color`, repeated blocks are highlighted in red for a terminal.
+pub fn hexdump(data: &[u8], color: bool) -> String {
+    let (red, dim, reset) = if color {
+        (RED, DIM, RESET)
+    } else {
+        ("", "", "")
+    };
+    let mut dump = String::new();
+    let mut first_seen: HashMap<&[u8], usize> = HashMap::new();
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let first = if block.len() == BLOCK_SIZE {
+            *first_seen.entry(block).or_insert(index)
+        } else {
+            index
+        };
+        let mut hex = String::new();
+        for i in 0..BLOCK_SIZE {
+            if i == BLOCK_SIZE / 2 {
+                hex.push(' ');
+            }
+            match block.get(i) {
+                Some(byte) => hex.push_str(&format!(" {:02x}", byte)),
+                None => hex.push_str("   "),
+            }
+        }
+        let ascii: String = block
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!("{}{:08x}{} ", dim, index * BLOCK_SIZE, reset));
+        if first == index {
+            dump.push_str(&format!("{}  |{}|\n", hex, ascii));
+        } else {
+            dump.push_str(&format!(
+                "{}{}{}  |{}|  = block {}\n",
+                red, hex, reset, ascii, first
+            ));
+        }
+    }
+    dump
+}
+
+thread_local! {
+    /// This thread's RNG, and the process that seeded it.
+    static RNG: RefCell<Option<(u32, StdRng)>> = const { RefCell::new(None) };
+}
+
+/// Where all the crate's randomness comes from, so the `testing` feature can seed it.
+///
+/// A forked child starts with a copy of its parent's RNG state, and would repeat the parent's
+/// next IVs and nonces. `rand`'s `ThreadRng` only reseeds after a fork once its buffered
+/// output runs out, so the crate keeps an RNG of its own per thread, seeded from the
+/// operating system, and seeds a fresh one whenever the process ID has changed since.
+pub fn fill_random(buf: &mut [u8]) {
+    #[cfg(feature = "testing")]
+    if crate::testing::fill_seeded(buf) {
+        return;
+    }
+    RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        let pid = process::id();
+        if rng.as_ref().is_none_or(|(seeded_by, _)| *seeded_by != pid) {
+            *rng = Some((pid, StdRng::from_entropy()));
+        }
+        rng.as_mut().unwrap().1.fill(buf);
+    });
+}
+
+/// Runs `f` in a forked child and returns what it made, for testing what survives a fork.
+#[cfg(all(test, unix))]
+pub(crate) fn in_forked_child(f: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+    use std::{fs::File, io::Read, io::Write, os::fd::FromRawFd};
+
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends of the pipe. The child only runs `f`, writes to
+    // the pipe, and exits without unwinding or running destructors.
+    unsafe {
+        assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+        let pid = libc::fork();
+        assert!(pid >= 0, "fork failed");
+        if pid == 0 {
+            libc::close(fds[0]);
+            let mut pipe = File::from_raw_fd(fds[1]);
+            let _ = pipe.write_all(&f());
+            libc::_exit(0);
+        }
+        libc::close(fds[1]);
+        let mut output = Vec::new();
+        File::from_raw_fd(fds[0]).read_to_end(&mut output).unwrap();
+        libc::waitpid(pid, std::ptr::null_mut(), 0);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        let block = *b"YELLOW SUBMARINE";
+        let data = [&block[..], &[0u8; 16], &block, b"\x01end"].concat();
+        assert_eq!(
+            hexdump(&data, false),
+            "00000000  59 45 4c 4c 4f 57 20 53  55 42 4d 41 52 49 4e 45  |YELLOW SUBMARINE|\n\
+             00000010  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|\n\
+             00000020  59 45 4c 4c 4f 57 20 53  55 42 4d 41 52 49 4e 45  |YELLOW SUBMARINE|  = block 0\n\
+             00000030  01 65 6e 64                                       |.end|\n"
+        );
+        assert!(hexdump(&data, true).contains("\x1b[31m 59 45"));
+        assert_eq!(hexdump(&[], true), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forked_child_reseeds() {
+        // The parent's RNG is in use before the fork, so the child inherits its state.
+        create_rand_key();
+        let child = in_forked_child(|| create_rand_key().to_vec());
+        assert_eq!(child.len(), BLOCK_SIZE);
+        assert_ne!(child, create_rand_key());
+    }
+
+    #[test]
+    fn test_hex_decode() {
+        assert_eq!(hex_decode("00ff7Fa9"), Some(vec![0x00, 0xFF, 0x7F, 0xA9]));
+        assert_eq!(hex_decode(""), Some(Vec::new()));
+        for bad in ["0", "0g", "g0", "/0", ":0", "@0", "G0", "`0", " 0"] {
+            assert_eq!(hex_decode(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_base64url_matches_the_alphabet() {
+        for c in 0..=255u8 {
+            let (value, valid) = base64url_value(c);
+            match BASE64_URL.iter().position(|b| *b == c) {
+                Some(position) => assert_eq!((value, valid), (position as u8, 0xFF)),
+                None => assert_eq!(valid, 0, "{:?}", c as char),
+            }
+        }
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..10 {
+            assert_eq!(
+                base64url_decode(&base64url_encode(&data[..len])).unwrap(),
+                &data[..len]
+            );
+        }
+        assert_eq!(base64url_decode("AA+A"), None);
+    }
+}
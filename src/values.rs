@@ -0,0 +1,332 @@
+//! Encrypting any serde value in one call: [`seal_value`] and [`open_value`].
+//!
+//! Session state, cached tokens and settings are structs, and turning them into bytes for
+//! [`secretbox`](crate::secretbox) is the same few lines of plumbing every time. [`seal_value`]
+//! serializes the value, optionally compresses it, and encrypts and authenticates it with
+//! AES-128-GCM under a random nonce; [`open_value`] reverses all three. The blob records how
+//! it was made, so [`open_value`] needs no options:
+//!
+//! ```text
+//! "AMSV" | version | format | compression | nonce (12 bytes) | ciphertext | tag (16 bytes)
+//! ```
+//!
+//! The first seven bytes are authenticated as associated data, so a blob can't be made to
+//! decode differently by editing its header.
+//!
+//! The serialization [`Format`] is CBOR with the `cbor` feature and MessagePack with
+//! `msgpack`; this module needs at least one. Compression comes from
+//! [`compression`](crate::compression), and carries the same oracle risk: don't compress
+//! values that mix secrets with data an attacker chooses, if the attacker can see the size of
+//! the blob.
+
+use std::{error::Error, fmt};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    compression::{Compression, Compressor, Decompressor, OracleRiskAcknowledged},
+    gcm::{GcmKey, GCM_NONCE_SIZE, TAG_SIZE},
+    limits, utils, BLOCK_SIZE,
+};
+
+const MAGIC: &[u8; 4] = b"AMSV";
+/// The format version written by [`seal_value`].
+pub const VERSION: u8 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 3;
+
+/// How a value is turned into bytes before it is encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+#[cfg(feature = "cbor")]
+const DEFAULT_FORMAT: Format = Format::Cbor;
+#[cfg(not(feature = "cbor"))]
+const DEFAULT_FORMAT: Format = Format::MessagePack;
+
+impl Format {
+    fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "cbor")]
+            Format::Cbor => 1,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => 2,
+        }
+    }
+
+    /// `None` for unknown IDs, and for formats this build was compiled without.
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            #[cfg(feature = "cbor")]
+            1 => Some(Format::Cbor),
+            #[cfg(feature = "msgpack")]
+            2 => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+
+    fn serialize<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, ValueError> {
+        match self {
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|_| ValueError::Serialize)?;
+                Ok(bytes)
+            }
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|_| ValueError::Serialize)
+            }
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, ValueError> {
+        match self {
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|_| ValueError::Deserialize),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|_| ValueError::Deserialize)
+            }
+        }
+    }
+}
+
+/// Why a value couldn't be sealed or opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueError {
+    /// The value's `Serialize` implementation failed, or the format can't represent it.
+    Serialize,
+    /// The blob is too short, or isn't a sealed value at all.
+    Malformed,
+    /// The blob was written by a newer format version.
+    UnsupportedVersion(u8),
+    /// The blob's serialization format is unknown, or this build was compiled without it.
+    UnsupportedFormat(u8),
+    /// The blob's compression is unknown, or this build was compiled without it.
+    UnsupportedCompression(u8),
+    /// The tag didn't match: wrong key, or the blob was modified.
+    Authentication,
+    /// The blob is authentic, but doesn't hold a value of the type asked for.
+    Deserialize,
+}
+
+impl fmt::Display for ValueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueError::Serialize => f.write_str("the value can't be serialized"),
+            ValueError::Malformed => f.write_str("malformed sealed value"),
+            ValueError::UnsupportedVersion(version) => {
+                write!(f, "unsupported sealed value version {}", version)
+            }
+            ValueError::UnsupportedFormat(id) => write!(f, "unsupported serialization {}", id),
+            ValueError::UnsupportedCompression(id) => write!(f, "unsupported compression {}", id),
+            ValueError::Authentication => f.write_str("sealed value failed authentication"),
+            ValueError::Deserialize => f.write_str("the value doesn't have the expected type"),
+        }
+    }
+}
+
+impl Error for ValueError {}
+
+/// How [`seal_value_with_options`] encodes the value: in the default [`Format`], uncompressed,
+/// unless changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueOptions {
+    format: Format,
+    compression: Compression,
+}
+
+impl Default for ValueOptions {
+    fn default() -> Self {
+        ValueOptions {
+            format: DEFAULT_FORMAT,
+            compression: Compression::None,
+        }
+    }
+}
+
+impl ValueOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Compresses the serialized value before encrypting it. See the
+    /// [`compression`](crate::compression) module for when that is safe.
+    pub fn with_compression(
+        mut self,
+        compression: Compression,
+        _acknowledged: OracleRiskAcknowledged,
+    ) -> Self {
+        self.compression = compression;
+        self
+    }
+}
+
+/// Serializes `value` in the default format and encrypts it under `key`.
+pub fn seal_value<T: Serialize + ?Sized>(
+    key: &[u8; BLOCK_SIZE],
+    value: &T,
+) -> Result<Vec<u8>, ValueError> {
+    seal_value_with_options(key, value, ValueOptions::default())
+}
+
+/// Like [`seal_value`], in the format and with the compression of `options`.
+pub fn seal_value_with_options<T: Serialize + ?Sized>(
+    key: &[u8; BLOCK_SIZE],
+    value: &T,
+    options: ValueOptions,
+) -> Result<Vec<u8>, ValueError> {
+    let serialized = options.format.serialize(value)?;
+    let mut compressor = Compressor::new(options.compression);
+    let mut plain_text = compressor.compress(&serialized).into_owned();
+    plain_text.extend(compressor.finish());
+
+    let mut sealed = MAGIC.to_vec();
+    sealed.extend_from_slice(&[VERSION, options.format.id(), options.compression.id()]);
+    let nonce = utils::create_rand_gcm_nonce();
+    sealed.extend_from_slice(&nonce);
+    let aad = sealed[..HEADER_SIZE].to_vec();
+    GcmKey::new(*key).seal_into(nonce, &plain_text, &aad, &mut sealed);
+    Ok(sealed)
+}
+
+/// Decrypts a blob made by [`seal_value`] or [`seal_value_with_options`], and deserializes
+/// the value in it.
+pub fn open_value<T: DeserializeOwned>(
+    key: &[u8; BLOCK_SIZE],
+    blob: &[u8],
+) -> Result<T, ValueError> {
+    if blob.len() < HEADER_SIZE + GCM_NONCE_SIZE + TAG_SIZE || !blob.starts_with(MAGIC) {
+        return Err(ValueError::Malformed);
+    }
+    let (header, rest) = blob.split_at(HEADER_SIZE);
+    let version = header[4];
+    if !(1..=VERSION).contains(&version) {
+        return Err(ValueError::UnsupportedVersion(version));
+    }
+    let format = Format::from_id(header[5]).ok_or(ValueError::UnsupportedFormat(header[5]))?;
+    let compression =
+        Compression::from_id(header[6]).ok_or(ValueError::UnsupportedCompression(header[6]))?;
+
+    let (nonce, cipher_text) = rest.split_at(GCM_NONCE_SIZE);
+    let plain_text = GcmKey::new(*key)
+        .open(nonce.try_into().unwrap(), cipher_text, header)
+        .map_err(|_| ValueError::Authentication)?;
+
+    let mut decompressor =
+        Decompressor::new(compression, limits::DEFAULT_MAX_DECOMPRESSED_CHUNK_SIZE);
+    let mut serialized = decompressor
+        .decompress(plain_text)
+        .map_err(|_| ValueError::Malformed)?;
+    serialized.extend(decompressor.finish().map_err(|_| ValueError::Malformed)?);
+    format.deserialize(&serialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    const KEY: [u8; BLOCK_SIZE] = [3; BLOCK_SIZE];
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct SessionState {
+        user: String,
+        roles: Vec<String>,
+        expires: u64,
+        preferences: BTreeMap<String, bool>,
+    }
+
+    fn state() -> SessionState {
+        SessionState {
+            user: "ada".to_string(),
+            roles: vec!["admin".to_string(); 20],
+            expires: 1_900_000_000,
+            preferences: BTreeMap::from([("dark mode".to_string(), true)]),
+        }
+    }
+
+    fn formats() -> Vec<Format> {
+        vec![
+            #[cfg(feature = "cbor")]
+            Format::Cbor,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack,
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let blob = seal_value(&KEY, &state()).unwrap();
+        assert_eq!(open_value::<SessionState>(&KEY, &blob), Ok(state()));
+        assert_ne!(seal_value(&KEY, &state()).unwrap(), blob);
+
+        for format in formats() {
+            let options = ValueOptions::new().with_format(format);
+            let blob = seal_value_with_options(&KEY, &state(), options).unwrap();
+            assert_eq!(blob[5], format.id());
+            assert_eq!(open_value::<SessionState>(&KEY, &blob), Ok(state()));
+        }
+
+        #[cfg(feature = "deflate")]
+        {
+            let options =
+                ValueOptions::new().with_compression(Compression::Deflate, OracleRiskAcknowledged);
+            let compressed = seal_value_with_options(&KEY, &state(), options).unwrap();
+            assert!(compressed.len() < blob.len());
+            assert_eq!(open_value::<SessionState>(&KEY, &compressed), Ok(state()));
+        }
+    }
+
+    #[test]
+    fn test_open_rejects() {
+        let blob = seal_value(&KEY, &state()).unwrap();
+        assert_eq!(
+            open_value::<SessionState>(&[4; BLOCK_SIZE], &blob),
+            Err(ValueError::Authentication)
+        );
+        assert_eq!(open_value::<u64>(&KEY, &blob), Err(ValueError::Deserialize));
+        assert_eq!(
+            open_value::<SessionState>(&KEY, &blob[..20]),
+            Err(ValueError::Malformed)
+        );
+
+        let mut newer = blob.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(
+            open_value::<SessionState>(&KEY, &newer),
+            Err(ValueError::UnsupportedVersion(VERSION + 1))
+        );
+        let mut unknown = blob.clone();
+        unknown[6] = 9;
+        assert_eq!(
+            open_value::<SessionState>(&KEY, &unknown),
+            Err(ValueError::UnsupportedCompression(9))
+        );
+        // The header is authenticated, so it can't be switched to another known format.
+        for format in formats()
+            .into_iter()
+            .filter(|&format| format.id() != blob[5])
+        {
+            let mut switched = blob.clone();
+            switched[5] = format.id();
+            assert_eq!(
+                open_value::<SessionState>(&KEY, &switched),
+                Err(ValueError::Authentication)
+            );
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! A process-wide hook for risky constructions.
+//!
+//! The crate keeps ECB, unauthenticated CBC and truncated tags because old data and old peers
+//! need them, but an application may want to know when it still uses them. Every such use is
+//! reported to a [`WarningSink`] as a [`Warning`]:
+//!
+//! - ECB, through [`ecb_encrypt`](crate::ecb_encrypt), [`Mode::Ecb`](crate::Mode::Ecb), an
+//!   [`Envelope`](crate::envelope::Envelope), a [`CipherContext`](crate::backend::CipherContext),
+//!   the [`generic`](crate::generic) modes, the [`blocks`](crate::blocks) iterators or a
+//!   [`jobs`](crate::jobs) work item;
+//! - CBC the same ways, jobs aside, since nothing there authenticates the ciphertext;
+//! - chunked streams and [`CountingGcm`](crate::invocations::CountingGcm) with tags shorter
+//!   than [`TAG_SIZE`](crate::gcm::TAG_SIZE);
+//! - the deprecated algorithms of the [`legacy`](crate::legacy) module;
+//! - envelopes older than a [`Policy`](crate::policy::Policy)'s minimum version, opened
+//!   because the policy allows the downgrade.
+//!
+//! No sink is installed to begin with, so nothing happens. [`set_warning_sink`] installs one
+//! for the whole process: [`StderrSink`] to log, [`PanicSink`] to hard-fail in tests or CI, or
+//! any closure taking a `&Warning`.
+//!
+//! The crate's own known-answer tests run these constructions on purpose and don't report them.
+//!
+//! `cargo run --example misuse_gallery` shows each of them next to its correct use, with
+//! what the [`analysis`](crate::analysis) module finds in the output.
+
+use std::{cell::Cell, fmt, sync::RwLock};
+
+/// One use of a risky construction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// ECB, which shows which blocks of the plaintext are equal.
+    Ecb,
+    /// CBC with nothing to detect a modified ciphertext, which opens the door to padding oracles.
+    UnauthenticatedCbc,
+    /// An authentication tag of only `len` bytes.
+    ShortTag { len: usize },
+    /// A deprecated algorithm, by its registry name.
+    Deprecated(&'static str),
+    /// An envelope of format `version`, older than the policy's minimum `min`.
+    Downgrade { version: u8, min: u8 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Ecb => f.write_str("ECB mode used; it leaks repeated plaintext blocks"),
+            Warning::UnauthenticatedCbc => {
+                f.write_str("CBC mode used without a MAC; tampering goes undetected")
+            }
+            Warning::ShortTag { len } => write!(f, "{}-byte authentication tag used", len),
+            Warning::Deprecated(algorithm) => {
+                write!(f, "deprecated algorithm {} used", algorithm)
+            }
+            Warning::Downgrade { version, min } => write!(
+                f,
+                "envelope version {} accepted below the minimum version {}",
+                version, min
+            ),
+        }
+    }
+}
+
+/// Receives every [`Warning`]. Closures taking a `&Warning` are sinks.
+pub trait WarningSink: Send + Sync {
+    fn warn(&self, warning: &Warning);
+}
+
+impl<F: Fn(&Warning) + Send + Sync> WarningSink for F {
+    fn warn(&self, warning: &Warning) {
+        self(warning)
+    }
+}
+
+/// Prints each warning to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StderrSink;
+
+impl WarningSink for StderrSink {
+    fn warn(&self, warning: &Warning) {
+        eprintln!("aes-modes: warning: {}", warning);
+    }
+}
+
+/// Panics on the first warning, for making sure nothing risky is left.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PanicSink;
+
+impl WarningSink for PanicSink {
+    fn warn(&self, warning: &Warning) {
+        panic!("aes-modes: {}", warning);
+    }
+}
+
+static SINK: RwLock<Option<Box<dyn WarningSink>>> = RwLock::new(None);
+
+thread_local! {
+    static QUIET: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Replaces the sink that receives every warning, for the whole process.
+pub fn set_warning_sink(sink: impl WarningSink + 'static) {
+    *SINK.write().unwrap_or_else(|error| error.into_inner()) = Some(Box::new(sink));
+}
+
+/// Removes the sink, so warnings are ignored again.
+pub fn clear_warning_sink() {
+    *SINK.write().unwrap_or_else(|error| error.into_inner()) = None;
+}
+
+pub(crate) fn warn(warning: Warning) {
+    if QUIET.with(Cell::get) > 0 {
+        return;
+    }
+    if let Some(sink) = &*SINK.read().unwrap_or_else(|error| error.into_inner()) {
+        sink.warn(&warning);
+    }
+}
+
+/// Runs `f` without reporting warnings on this thread.
+pub(crate) fn quietly<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            QUIET.with(|quiet| quiet.set(quiet.get() - 1));
+        }
+    }
+    QUIET.with(|quiet| quiet.set(quiet.get() + 1));
+    let _restore = Restore;
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Mutex, Once},
+        thread::{self, ThreadId},
+    };
+
+    use aes::{cipher::KeyInit, Aes128};
+
+    use super::*;
+    use crate::{
+        blocks::EncryptIter,
+        chunked::{ChunkKeys, StreamOptions},
+        envelope::{Envelope, EnvelopeMode},
+        generic,
+        invocations::{CountingGcm, MemoryStore},
+        jobs::Work,
+        Mode,
+    };
+
+    const KEY: [u8; 16] = [7; 16];
+
+    /// Runs `f` and returns the warnings it reported. Tests run in parallel and warn too, so
+    /// one sink, installed once, records every thread's warnings, and each test only takes
+    /// those of its own thread.
+    fn capture(f: impl FnOnce()) -> Vec<Warning> {
+        static RECORDED: Mutex<Vec<(ThreadId, Warning)>> = Mutex::new(Vec::new());
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_warning_sink(|warning: &Warning| {
+                RECORDED
+                    .lock()
+                    .unwrap()
+                    .push((thread::current().id(), *warning))
+            })
+        });
+
+        f();
+        let mut recorded = RECORDED.lock().unwrap();
+        let id = thread::current().id();
+        let warnings = recorded
+            .iter()
+            .filter(|(thread, _)| *thread == id)
+            .map(|(_, warning)| *warning)
+            .collect();
+        recorded.retain(|(thread, _)| *thread != id);
+        warnings
+    }
+
+    #[test]
+    fn test_reports_risky_constructions() {
+        let warnings = capture(|| {
+            crate::ecb_decrypt(crate::ecb_encrypt(b"yellow".to_vec(), KEY), KEY);
+        });
+        assert_eq!(warnings, [Warning::Ecb, Warning::Ecb]);
+
+        let warnings = capture(|| {
+            Envelope::seal(EnvelopeMode::Cbc, 1, KEY, b"submarine".to_vec());
+            Mode::Ctr.encrypt(b"fine".to_vec(), KEY);
+            Envelope::seal(EnvelopeMode::Gcm, 1, KEY, b"also fine".to_vec());
+        });
+        assert_eq!(warnings, [Warning::UnauthenticatedCbc]);
+
+        let warnings = capture(|| {
+            let header = StreamOptions::new().with_tag_len(12).to_header().unwrap();
+            ChunkKeys::new(b"master secret", header);
+        });
+        assert_eq!(warnings, [Warning::ShortTag { len: 12 }]);
+
+        let warnings = capture(|| {
+            let store = MemoryStore::new();
+            CountingGcm::new(KEY, "key", [0; 4], &store)
+                .allow_truncated_tags(8, 1 << 15)
+                .encrypt(b"short".to_vec(), b"")
+                .unwrap();
+        });
+        assert_eq!(warnings, [Warning::ShortTag { len: 8 }]);
+
+        #[cfg(feature = "legacy")]
+        #[allow(deprecated)]
+        {
+            let warnings = capture(|| {
+                let key = [1; crate::legacy::TDES_KEY_SIZE];
+                let cipher_text = crate::legacy::tdes_cbc_encrypt(&key, b"old".to_vec());
+                crate::legacy::tdes_cbc_decrypt(&key, cipher_text).unwrap();
+            });
+            assert_eq!(
+                warnings,
+                [Warning::Deprecated("3des"), Warning::UnauthenticatedCbc].repeat(2)
+            );
+        }
+
+        let warnings = capture(|| {
+            quietly(|| crate::ecb_encrypt(Vec::new(), KEY));
+            crate::self_test::self_test();
+        });
+        assert_eq!(warnings, []);
+    }
+
+    #[test]
+    fn test_reports_generic_iterator_and_job_modes() {
+        let cipher = Aes128::new(&KEY.into());
+        let warnings = capture(|| {
+            generic::ecb_decrypt(&cipher, generic::ecb_encrypt(&cipher, b"any".to_vec())).unwrap();
+            generic::cbc_decrypt(&cipher, generic::cbc_encrypt(&cipher, b"cipher".to_vec()))
+                .unwrap();
+        });
+        assert_eq!(
+            warnings,
+            [
+                Warning::Ecb,
+                Warning::Ecb,
+                Warning::UnauthenticatedCbc,
+                Warning::UnauthenticatedCbc
+            ]
+        );
+
+        let warnings = capture(|| {
+            EncryptIter::new(Mode::Ecb, KEY, b"bytes".iter().copied()).for_each(drop);
+            EncryptIter::new(Mode::Cbc, KEY, b"bytes".iter().copied()).for_each(drop);
+            EncryptIter::new(Mode::Ctr, KEY, b"bytes".iter().copied()).for_each(drop);
+            Work::ecb_encrypt(KEY, &mut [0; 32]).run();
+        });
+        assert_eq!(
+            warnings,
+            [Warning::Ecb, Warning::UnauthenticatedCbc, Warning::Ecb]
+        );
+    }
+
+    #[test]
+    fn test_sinks() {
+        assert_eq!(
+            Warning::ShortTag { len: 8 }.to_string(),
+            "8-byte authentication tag used"
+        );
+        let panicked = std::panic::catch_unwind(|| PanicSink.warn(&Warning::Ecb));
+        assert!(panicked.is_err());
+    }
+}
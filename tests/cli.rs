@@ -0,0 +1,25 @@
+//! The `aes-modes` binary, run as a user would run it.
+
+use std::{env, fs, process::Command};
+
+#[test]
+fn test_inspect_marks_repeated_blocks() {
+    let path = env::temp_dir().join(format!("aes-modes-inspect-{}", std::process::id()));
+    fs::write(&path, [[0x41; 16], [0x42; 16], [0x41; 16]].concat()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_aes-modes"))
+        .arg("inspect")
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(path).unwrap();
+
+    assert!(output.status.success());
+    let dump = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("00000000") && lines[0].ends_with("|AAAAAAAAAAAAAAAA|"));
+    assert!(lines[2].starts_with("00000020") && lines[2].ends_with("= block 0"));
+    // Not a terminal, so no colors.
+    assert!(!dump.contains('\x1b'));
+}
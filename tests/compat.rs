@@ -0,0 +1,116 @@
+//! Every release's ciphertext must still decrypt.
+//!
+//! `tests/compat/<version>/` holds a fixture of each stored format as that release wrote it,
+//! made by `cargo xtask gen-fixtures` when it was released. Unlike the golden files, which are
+//! tied to this build's test cases, a fixture describes itself, with its format, key,
+//! plaintext and ciphertext:
+//!
+//! ```text
+//! format: envelope
+//! format-version: 5
+//! key: <hex>
+//! plaintext: <hex>
+//! ciphertext:
+//! <hex, over any number of lines>
+//! ```
+//!
+//! so it keeps being checked after the code that wrote it is gone. The test fails if any
+//! fixture doesn't decrypt, if its format is unknown, and if there are no fixtures for the
+//! current version, which is the reminder to run `cargo xtask gen-fixtures` before a release.
+
+use std::{fs, io::Read, panic, path::Path};
+
+use aes_modes::{chunked, envelope::Envelope, messages, secretbox};
+
+struct Fixture {
+    format: String,
+    key: Vec<u8>,
+    plain_text: Vec<u8>,
+    cipher_text: Vec<u8>,
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    let digits: String = text.split_whitespace().collect();
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn parse(text: &str) -> Result<Fixture, String> {
+    let (fields, cipher_text) = text.split_once("ciphertext:").ok_or("no ciphertext")?;
+    let field = |name: &str| {
+        fields
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(str::trim)
+            .ok_or(format!("no {}", name))
+    };
+    Ok(Fixture {
+        format: field("format")?.to_string(),
+        key: from_hex(field("key")?),
+        plain_text: from_hex(field("plaintext")?),
+        cipher_text: from_hex(cipher_text),
+    })
+}
+
+fn decrypt(fixture: &Fixture) -> Result<Vec<u8>, String> {
+    let key = &fixture.key;
+    let bytes = fixture.cipher_text.as_slice();
+    match fixture.format.as_str() {
+        "envelope" => Envelope::from_bytes(bytes)
+            .and_then(|envelope| envelope.open(key.as_slice().try_into().unwrap()))
+            .map_err(|error| error.to_string()),
+        "chunked" => {
+            let mut plain_text = Vec::new();
+            chunked::StreamDecryptor::new(key, bytes)
+                .and_then(|mut decryptor| decryptor.read_to_end(&mut plain_text))
+                .map_err(|error| error.to_string())?;
+            Ok(plain_text)
+        }
+        "messages" => messages::MessageStreamReader::new(key, bytes)
+            .and_then(|reader| reader.collect::<Result<Vec<_>, _>>())
+            .map(|messages| messages.concat())
+            .map_err(|error| error.to_string()),
+        "secretbox" => secretbox::open(key.as_slice().try_into().unwrap(), bytes)
+            .map_err(|error| error.to_string()),
+        format => Err(format!("unknown format {}", format)),
+    }
+}
+
+#[test]
+fn test_past_releases_decrypt() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat");
+    let mut failures = Vec::new();
+    let mut releases = Vec::new();
+
+    for release in fs::read_dir(&dir).unwrap() {
+        let release = release.unwrap();
+        releases.push(release.file_name().into_string().unwrap());
+        for file in fs::read_dir(release.path()).unwrap() {
+            let path = file.unwrap().path();
+            let name = path.strip_prefix(&dir).unwrap().display().to_string();
+            let outcome = parse(&fs::read_to_string(&path).unwrap()).and_then(|fixture| {
+                match panic::catch_unwind(|| decrypt(&fixture)) {
+                    Ok(Ok(plain_text)) if plain_text == fixture.plain_text => Ok(()),
+                    Ok(Ok(_)) => Err("decrypts to the wrong plaintext".to_string()),
+                    Ok(Err(error)) => Err(error),
+                    Err(_) => Err("panicked".to_string()),
+                }
+            });
+            if let Err(error) = outcome {
+                failures.push(format!("{}: {}", name, error));
+            }
+        }
+    }
+    if !releases
+        .iter()
+        .any(|release| release == env!("CARGO_PKG_VERSION"))
+    {
+        failures.push(format!(
+            "no fixtures for {}; run `cargo xtask gen-fixtures`",
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,309 @@
+//! Golden-file regression tests for every stored format.
+//!
+//! Each case encrypts the same plaintext under the seeded test RNG, so its output is the same
+//! on every run, and compares it with `tests/golden/<case>.v<version>.hex`. Formats without a
+//! version byte of their own, the bare modes, are stored as `<case>.hex`. The test fails:
+//!
+//! - if the output no longer matches the file for the current version. An intended format
+//!   change has to bump the version, which starts a new file;
+//! - if there is no file for the current version. Run with `UPDATE_GOLDEN=1` to write it, and
+//!   commit it next to the old versions;
+//! - if any stored file, of any version, no longer decrypts to the plaintext. Old files are
+//!   never deleted, so data written by any release stays readable.
+
+use std::{
+    env, fs,
+    io::{Read, Write},
+    panic,
+    path::Path,
+};
+
+use aes_modes::{
+    cbc_decrypt, cbc_encrypt, chunked,
+    clock::FixedClock,
+    ctr_decrypt, ctr_encrypt, ecb_decrypt, ecb_encrypt,
+    envelope::{self, Envelope, EnvelopeMode, Validity},
+    gcm::{gcm_decrypt, gcm_encrypt},
+    length_padding::LengthPadding,
+    messages, secretbox,
+    siv::{siv_decrypt, siv_encrypt},
+    testing::with_seeded_rng,
+};
+
+const SEED: u64 = 0x601d;
+const KEY: [u8; 16] = *b"golden file key!";
+const SIV_KEY: [u8; 32] = *b"golden file key for AES-SIV, 32B";
+const MASTER_SECRET: &[u8] = b"golden file master secret, 32 B!";
+const PLAIN_TEXT: &[u8] = b"Stored ciphertexts must stay readable across releases. 64 bytes.";
+
+struct Case {
+    name: &'static str,
+    version: Option<u8>,
+    seal: fn() -> Vec<u8>,
+    open: fn(&[u8]) -> Vec<u8>,
+}
+
+impl Case {
+    fn file_name(&self) -> String {
+        match self.version {
+            Some(version) => format!("{}.v{}.hex", self.name, version),
+            None => format!("{}.hex", self.name),
+        }
+    }
+
+    /// Whether `file_name` is a golden file of this case, of any version.
+    fn owns(&self, file_name: &str) -> bool {
+        let versioned = file_name
+            .strip_prefix(self.name)
+            .and_then(|rest| rest.strip_prefix(".v"))
+            .and_then(|rest| rest.strip_suffix(".hex"))
+            .is_some_and(|version| version.parse::<u8>().is_ok());
+        versioned || file_name == format!("{}.hex", self.name)
+    }
+}
+
+fn seal_envelope(mode: EnvelopeMode) -> Vec<u8> {
+    Envelope::seal(mode, 1, KEY, PLAIN_TEXT.to_vec()).to_bytes()
+}
+
+fn open_envelope(bytes: &[u8]) -> Vec<u8> {
+    Envelope::from_bytes(bytes).unwrap().open(KEY).unwrap()
+}
+
+fn seal_chunked(options: chunked::StreamOptions) -> Vec<u8> {
+    let mut encryptor =
+        chunked::StreamEncryptor::with_options(MASTER_SECRET, options, Vec::new()).unwrap();
+    encryptor.write_all(PLAIN_TEXT).unwrap();
+    encryptor.finish().unwrap()
+}
+
+fn open_chunked(bytes: &[u8]) -> Vec<u8> {
+    let mut plain_text = Vec::new();
+    chunked::StreamDecryptor::new(MASTER_SECRET, bytes)
+        .unwrap()
+        .read_to_end(&mut plain_text)
+        .unwrap();
+    plain_text
+}
+
+fn cases() -> Vec<Case> {
+    let envelope_version = Some(envelope::VERSION);
+    let chunked_version = Some(chunked::VERSION);
+    vec![
+        Case {
+            name: "ecb",
+            version: None,
+            seal: || ecb_encrypt(PLAIN_TEXT.to_vec(), KEY),
+            open: |bytes| ecb_decrypt(bytes.to_vec(), KEY),
+        },
+        Case {
+            name: "cbc",
+            version: None,
+            seal: || cbc_encrypt(PLAIN_TEXT.to_vec(), KEY),
+            open: |bytes| cbc_decrypt(bytes.to_vec(), KEY),
+        },
+        Case {
+            name: "ctr",
+            version: None,
+            seal: || ctr_encrypt(PLAIN_TEXT.to_vec(), KEY),
+            open: |bytes| ctr_decrypt(bytes.to_vec(), KEY),
+        },
+        Case {
+            name: "gcm",
+            version: None,
+            seal: || gcm_encrypt(PLAIN_TEXT.to_vec(), KEY, [7; 12], b"golden"),
+            open: |bytes| gcm_decrypt(bytes.to_vec(), KEY, [7; 12], b"golden").unwrap(),
+        },
+        Case {
+            name: "siv",
+            version: None,
+            seal: || siv_encrypt(PLAIN_TEXT, &SIV_KEY, &[b"golden"]),
+            open: |bytes| siv_decrypt(bytes, &SIV_KEY, &[b"golden"]).unwrap(),
+        },
+        Case {
+            name: "secretbox",
+            version: None,
+            seal: || secretbox::seal(&KEY, PLAIN_TEXT),
+            open: |bytes| secretbox::open(&KEY, bytes).unwrap(),
+        },
+        Case {
+            name: "envelope-ecb",
+            version: envelope_version,
+            seal: || seal_envelope(EnvelopeMode::Ecb),
+            open: open_envelope,
+        },
+        Case {
+            name: "envelope-cbc",
+            version: envelope_version,
+            seal: || seal_envelope(EnvelopeMode::Cbc),
+            open: open_envelope,
+        },
+        Case {
+            name: "envelope-ctr",
+            version: envelope_version,
+            seal: || seal_envelope(EnvelopeMode::Ctr),
+            open: open_envelope,
+        },
+        Case {
+            name: "envelope-ctr-12",
+            version: envelope_version,
+            seal: || Envelope::seal_ctr(1, 12, KEY, PLAIN_TEXT.to_vec()).to_bytes(),
+            open: open_envelope,
+        },
+        Case {
+            name: "envelope-gcm",
+            version: envelope_version,
+            seal: || seal_envelope(EnvelopeMode::Gcm),
+            open: open_envelope,
+        },
+        Case {
+            name: "envelope-gcm-padme",
+            version: envelope_version,
+            seal: || {
+                Envelope::seal_with_padding(
+                    LengthPadding::Padme,
+                    EnvelopeMode::Gcm,
+                    1,
+                    KEY,
+                    PLAIN_TEXT.to_vec(),
+                )
+                .to_bytes()
+            },
+            open: open_envelope,
+        },
+        Case {
+            name: "envelope-gcm-validity",
+            version: envelope_version,
+            seal: || {
+                Envelope::seal_with_validity(
+                    Validity::between(1_700_000_000, 1_800_000_000),
+                    1,
+                    KEY,
+                    PLAIN_TEXT.to_vec(),
+                )
+                .to_bytes()
+            },
+            open: |bytes| {
+                Envelope::from_bytes(bytes)
+                    .unwrap()
+                    .open_with_clock(KEY, &FixedClock(1_750_000_000))
+                    .unwrap()
+            },
+        },
+        Case {
+            name: "chunked",
+            version: chunked_version,
+            seal: || seal_chunked(chunked::StreamOptions::new().with_chunk_size(16)),
+            open: open_chunked,
+        },
+        Case {
+            name: "chunked-short-tag",
+            version: chunked_version,
+            seal: || {
+                seal_chunked(
+                    chunked::StreamOptions::new()
+                        .with_chunk_size(16)
+                        .with_tag_len(12),
+                )
+            },
+            open: open_chunked,
+        },
+        Case {
+            name: "chunked-digest",
+            version: chunked_version,
+            seal: || {
+                seal_chunked(
+                    chunked::StreamOptions::new()
+                        .with_chunk_size(16)
+                        .with_plaintext_digest(true),
+                )
+            },
+            open: open_chunked,
+        },
+        Case {
+            name: "messages",
+            version: Some(messages::VERSION),
+            seal: || {
+                let mut writer =
+                    messages::MessageStreamWriter::new(MASTER_SECRET, Vec::new()).unwrap();
+                for message in PLAIN_TEXT.chunks(40) {
+                    writer.write_message(message).unwrap();
+                }
+                writer.finish().unwrap()
+            },
+            open: |bytes| {
+                messages::MessageStreamReader::new(MASTER_SECRET, bytes)
+                    .unwrap()
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>()
+                    .concat()
+            },
+        },
+    ]
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .chunks(32)
+        .map(|line| {
+            let mut line: String = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    let digits: String = text.split_whitespace().collect();
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_golden_files() {
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let stored: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    let mut failures = Vec::new();
+
+    for case in cases() {
+        let output = with_seeded_rng(SEED, case.seal);
+        assert_eq!(
+            with_seeded_rng(SEED, case.seal),
+            output,
+            "{} isn't deterministic under the seeded RNG",
+            case.name
+        );
+        let file_name = case.file_name();
+        let path = dir.join(&file_name);
+        match fs::read_to_string(&path) {
+            Ok(text) if from_hex(&text) == output => {}
+            Ok(_) => failures.push(match case.version {
+                Some(_) => format!("{}: output changed without a version bump", file_name),
+                None => format!(
+                    "{}: output changed, and this format has no version",
+                    file_name
+                ),
+            }),
+            Err(_) if update => fs::write(&path, to_hex(&output)).unwrap(),
+            Err(_) => failures.push(format!(
+                "{}: missing; run with UPDATE_GOLDEN=1 to write it",
+                file_name
+            )),
+        }
+
+        for file_name in stored.iter().filter(|file_name| case.owns(file_name)) {
+            let bytes = from_hex(&fs::read_to_string(dir.join(file_name)).unwrap());
+            let opened = panic::catch_unwind(|| (case.open)(&bytes));
+            if opened.ok().as_deref() != Some(PLAIN_TEXT) {
+                failures.push(format!("{}: no longer decrypts", file_name));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
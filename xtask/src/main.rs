@@ -0,0 +1,219 @@
+//! Maintenance tasks for the repository, run as `cargo xtask <task>`.
+//!
+//! ```text
+//! cargo xtask gen-fixtures [--force]
+//! ```
+//!
+//! `gen-fixtures` writes a ciphertext fixture of every stored format, as this release writes
+//! it, to `tests/compat/<version>/`, for `tests/compat.rs` to decrypt in every later release.
+//! Run it once per release and commit the result. The fixtures of a release that has shipped
+//! must never change, so it refuses to touch an existing directory without `--force`.
+
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use aes_modes::{
+    chunked,
+    envelope::{self, Envelope, EnvelopeMode, Validity},
+    length_padding::LengthPadding,
+    messages, secretbox,
+};
+
+const PLAIN_TEXT: &[u8] = b"Ciphertext written by one release must open in every later one.";
+
+const USAGE: &str = "usage: cargo xtask gen-fixtures [--force]";
+
+/// A fixture: what was encrypted, how, and what came out.
+struct Fixture {
+    name: &'static str,
+    format: &'static str,
+    format_version: Option<u8>,
+    key: Vec<u8>,
+    cipher_text: Vec<u8>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Fixture {
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "# Written by aes-modes {}. Never edit: later releases must still open it.\n",
+            crate_version()
+        );
+        text.push_str(&format!("format: {}\n", self.format));
+        if let Some(version) = self.format_version {
+            text.push_str(&format!("format-version: {}\n", version));
+        }
+        text.push_str(&format!("key: {}\n", hex(&self.key)));
+        text.push_str(&format!("plaintext: {}\n", hex(PLAIN_TEXT)));
+        text.push_str("ciphertext:\n");
+        for line in self.cipher_text.chunks(32) {
+            text.push_str(&hex(line));
+            text.push('\n');
+        }
+        text
+    }
+}
+
+fn master_secret() -> Vec<u8> {
+    [secretbox::generate_key(), secretbox::generate_key()].concat()
+}
+
+fn envelope_fixture(name: &'static str, seal: impl FnOnce([u8; 16]) -> Envelope) -> Fixture {
+    let key = secretbox::generate_key();
+    Fixture {
+        name,
+        format: "envelope",
+        format_version: Some(envelope::VERSION),
+        key: key.to_vec(),
+        cipher_text: seal(key).to_bytes(),
+    }
+}
+
+fn chunked_fixture(name: &'static str, options: chunked::StreamOptions) -> Fixture {
+    let secret = master_secret();
+    let mut encryptor =
+        chunked::StreamEncryptor::with_options(&secret, options, Vec::new()).unwrap();
+    encryptor.write_all(PLAIN_TEXT).unwrap();
+    Fixture {
+        name,
+        format: "chunked",
+        format_version: Some(chunked::VERSION),
+        key: secret,
+        cipher_text: encryptor.finish().unwrap(),
+    }
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let mut fixtures: Vec<Fixture> = EnvelopeMode::ALL
+        .into_iter()
+        .map(|mode| {
+            let name = match mode {
+                EnvelopeMode::Ecb => "envelope-ecb",
+                EnvelopeMode::Cbc => "envelope-cbc",
+                EnvelopeMode::Ctr => "envelope-ctr",
+                EnvelopeMode::Gcm => "envelope-gcm",
+            };
+            envelope_fixture(name, |key| {
+                Envelope::seal(mode, 1, key, PLAIN_TEXT.to_vec())
+            })
+        })
+        .collect();
+    fixtures.push(envelope_fixture("envelope-ctr-12", |key| {
+        Envelope::seal_ctr(1, 12, key, PLAIN_TEXT.to_vec())
+    }));
+    fixtures.push(envelope_fixture("envelope-gcm-padme", |key| {
+        Envelope::seal_with_padding(
+            LengthPadding::Padme,
+            EnvelopeMode::Gcm,
+            1,
+            key,
+            PLAIN_TEXT.to_vec(),
+        )
+    }));
+    // Valid until 2096, so that it opens under the real clock for decades of releases.
+    fixtures.push(envelope_fixture("envelope-gcm-validity", |key| {
+        Envelope::seal_with_validity(
+            Validity::between(1_700_000_000, 4_000_000_000),
+            1,
+            key,
+            PLAIN_TEXT.to_vec(),
+        )
+    }));
+
+    fixtures.push(chunked_fixture(
+        "chunked",
+        chunked::StreamOptions::new().with_chunk_size(16),
+    ));
+    fixtures.push(chunked_fixture(
+        "chunked-short-tag",
+        chunked::StreamOptions::new()
+            .with_chunk_size(16)
+            .with_tag_len(12),
+    ));
+    fixtures.push(chunked_fixture(
+        "chunked-digest",
+        chunked::StreamOptions::new()
+            .with_chunk_size(16)
+            .with_plaintext_digest(true),
+    ));
+
+    let secret = master_secret();
+    let mut writer = messages::MessageStreamWriter::new(&secret, Vec::new()).unwrap();
+    for message in PLAIN_TEXT.chunks(40) {
+        writer.write_message(message).unwrap();
+    }
+    fixtures.push(Fixture {
+        name: "messages",
+        format: "messages",
+        format_version: Some(messages::VERSION),
+        key: secret,
+        cipher_text: writer.finish().unwrap(),
+    });
+
+    let key = secretbox::generate_key();
+    fixtures.push(Fixture {
+        name: "secretbox",
+        format: "secretbox",
+        format_version: None,
+        key: key.to_vec(),
+        cipher_text: secretbox::seal(&key, PLAIN_TEXT),
+    });
+    fixtures
+}
+
+fn repository() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask lives in the repository")
+        .to_path_buf()
+}
+
+/// The library's version, from its manifest.
+fn crate_version() -> String {
+    let manifest = fs::read_to_string(repository().join("Cargo.toml")).unwrap();
+    manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("version = "))
+        .map(|version| version.trim_matches('"').to_string())
+        .expect("the manifest has a version")
+}
+
+fn gen_fixtures(force: bool) -> Result<(), String> {
+    let dir = repository().join("tests/compat").join(crate_version());
+    if dir.exists() && !force {
+        return Err(format!(
+            "{} exists; the fixtures of a release must not change (--force to overwrite)",
+            dir.display()
+        ));
+    }
+    fs::create_dir_all(&dir).map_err(|error| error.to_string())?;
+    for fixture in fixtures() {
+        let path = dir.join(format!("{}.fixture", fixture.name));
+        fs::write(&path, fixture.to_text()).map_err(|error| error.to_string())?;
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["gen-fixtures"] => gen_fixtures(false),
+        ["gen-fixtures", "--force"] => gen_fixtures(true),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}